@@ -0,0 +1,468 @@
+//! Bit sets that can be changed by many threads without a lock.  Used to keep track of the message
+//! ids that have been acknowledged or seen.
+//!
+//! `ConcurrentBitSet` has a fixed capacity.  `SlidingBitWindow` keeps a window of bits after the
+//! ids that have all been set and slides forward as the front of the window is completed.
+//!```text
+//! word in the window  | generation (32 bits) | bits (32 bits) |
+//!
+//! base block    -> the first block that isn't complete
+//! block b       -> word b % blocks with the generation b / blocks
+//! complete word -> moved to the next generation with no bits set, then the base moves forward
+//!```
+//! A word is only reused once its block is complete so a set racing the slide either lands in the
+//! right generation or sees that the block was already completed.
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The number of bits in a word of the bit set.
+const WORD_BITS: usize = 64;
+/// The number of bits in a word of the window.  The upper half of the word is the generation.
+const BLOCK_BITS: u64 = 32;
+/// The mask for the bits in a window word.
+const BLOCK_MASK: u64 = 0xFFFF_FFFF;
+
+/// The errors for the bit sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitSetError {
+    /// The index is past the capacity of the bit set.
+    OutOfRange { index: usize, capacity: usize },
+    /// The offset is past the end of the window.  Try again once the window slides forward.
+    WindowFull { offset: u64, end: u64 },
+}
+
+impl fmt::Display for BitSetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitSetError::OutOfRange { index, capacity } => write!(
+                f,
+                "Index {} is out of range for a capacity of {}.",
+                index, capacity
+            ),
+            BitSetError::WindowFull { offset, end } => write!(
+                f,
+                "Offset {} is past the end of the window at {}.",
+                offset, end
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BitSetError {}
+
+pub type Result<T> = std::result::Result<T, BitSetError>;
+
+/// A bit set with a fixed capacity.
+pub struct ConcurrentBitSet {
+    words: Box<[AtomicU64]>,
+    capacity: usize,
+}
+
+impl ConcurrentBitSet {
+    /// Creates a bit set with all of the bits clear.
+    /// # Arguments
+    /// `capacity` - The number of bits in the set.
+    pub fn new(capacity: usize) -> Self {
+        let words = capacity.div_ceil(WORD_BITS);
+        ConcurrentBitSet {
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            capacity,
+        }
+    }
+
+    /// The number of bits in the set.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Gets the word and the mask for a bit.
+    /// # Arguments
+    /// `index` - The index of the bit.
+    fn locate(&self, index: usize) -> Result<(&AtomicU64, u64)> {
+        if index >= self.capacity {
+            Err(BitSetError::OutOfRange {
+                index,
+                capacity: self.capacity,
+            })
+        } else {
+            Ok((&self.words[index / WORD_BITS], 1 << (index % WORD_BITS)))
+        }
+    }
+
+    /// Sets a bit.
+    /// # Arguments
+    /// `index` - The index of the bit to set.
+    /// # Returns
+    /// True if the bit was already set.
+    pub fn set(&self, index: usize) -> Result<bool> {
+        let (word, mask) = self.locate(index)?;
+        Ok(word.fetch_or(mask, Ordering::AcqRel) & mask != 0)
+    }
+
+    /// Clears a bit.
+    /// # Arguments
+    /// `index` - The index of the bit to clear.
+    /// # Returns
+    /// True if the bit was set.
+    pub fn clear(&self, index: usize) -> Result<bool> {
+        let (word, mask) = self.locate(index)?;
+        Ok(word.fetch_and(!mask, Ordering::AcqRel) & mask != 0)
+    }
+
+    /// Checks to see if a bit is set.
+    /// # Arguments
+    /// `index` - The index of the bit.
+    pub fn get(&self, index: usize) -> Result<bool> {
+        let (word, mask) = self.locate(index)?;
+        Ok(word.load(Ordering::Acquire) & mask != 0)
+    }
+
+    /// Finds the first bit that isn't set.
+    /// # Arguments
+    /// `index` - The index to start looking from.
+    /// # Returns
+    /// The index of the bit or `None` if all of the bits from the index are set.
+    pub fn first_clear_from(&self, index: usize) -> Option<usize> {
+        let mut i = index / WORD_BITS;
+        // Treat the bits before the index as set.
+        let mut skip = (1u64 << (index % WORD_BITS)) - 1;
+        while i < self.words.len() {
+            let clear = !(self.words[i].load(Ordering::Acquire) | skip);
+            if clear != 0 {
+                let found = i * WORD_BITS + clear.trailing_zeros() as usize;
+                return if found < self.capacity {
+                    Some(found)
+                } else {
+                    None
+                };
+            }
+            skip = 0;
+            i += 1;
+        }
+        None
+    }
+
+    /// Counts the bits that are set.
+    /// # Arguments
+    /// `range` - The indexes of the bits to count.
+    pub fn count_set_in(&self, range: Range<usize>) -> Result<usize> {
+        if range.end > self.capacity {
+            return Err(BitSetError::OutOfRange {
+                index: range.end - 1,
+                capacity: self.capacity,
+            });
+        }
+        let mut count = 0;
+        let mut index = range.start;
+        while index < range.end {
+            let offset = index % WORD_BITS;
+            let bits = (WORD_BITS - offset).min(range.end - index);
+            let mask = if bits == WORD_BITS {
+                u64::MAX
+            } else {
+                ((1u64 << bits) - 1) << offset
+            };
+            count += (self.words[index / WORD_BITS].load(Ordering::Acquire) & mask).count_ones()
+                as usize;
+            index += bits;
+        }
+        Ok(count)
+    }
+}
+
+/// A window of bits for the offsets after the ones that have all been set.  The window slides
+/// forward as the offsets at the front of it are set.
+pub struct SlidingBitWindow {
+    words: Box<[AtomicU64]>,
+    /// The first block that hasn't been completed.
+    base_block: AtomicU64,
+}
+
+impl SlidingBitWindow {
+    /// Creates a window starting at offset 0.
+    /// # Arguments
+    /// `window` - The number of offsets in the window.  Rounded up to a multiple of 32.
+    pub fn new(window: usize) -> Self {
+        let blocks = (window as u64).div_ceil(BLOCK_BITS).max(1);
+        SlidingBitWindow {
+            words: (0..blocks).map(|_| AtomicU64::new(0)).collect(),
+            base_block: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of offsets in the window.
+    pub fn window(&self) -> u64 {
+        self.words.len() as u64 * BLOCK_BITS
+    }
+
+    /// The number of blocks in the window.
+    fn blocks(&self) -> u64 {
+        self.words.len() as u64
+    }
+
+    /// The offset all of the offsets before have been set.
+    pub fn base(&self) -> u64 {
+        loop {
+            let block = self.base_block.load(Ordering::Acquire);
+            let value = self.words[(block % self.blocks()) as usize].load(Ordering::Acquire);
+            if self.base_block.load(Ordering::Acquire) != block {
+                continue;
+            }
+            return if value >> BLOCK_BITS == generation(block, self.blocks()) {
+                block * BLOCK_BITS + (value & BLOCK_MASK).trailing_ones() as u64
+            } else {
+                // Completed but the base hasn't been moved yet.
+                (block + 1) * BLOCK_BITS
+            };
+        }
+    }
+
+    /// Checks an offset is in the window.
+    /// # Arguments
+    /// `offset` - The offset to check.
+    /// # Returns
+    /// `None` if the offset is before the window.
+    fn check(&self, offset: u64) -> Result<Option<(&AtomicU64, u64, u64)>> {
+        let start = self.base_block.load(Ordering::Acquire) * BLOCK_BITS;
+        if offset < start {
+            return Ok(None);
+        }
+        let end = start + self.window();
+        if offset >= end {
+            return Err(BitSetError::WindowFull { offset, end });
+        }
+        let block = offset / BLOCK_BITS;
+        Ok(Some((
+            &self.words[(block % self.blocks()) as usize],
+            generation(block, self.blocks()),
+            1 << (offset % BLOCK_BITS),
+        )))
+    }
+
+    /// Sets the bit for an offset.
+    /// # Arguments
+    /// `offset` - The offset to set.
+    /// # Returns
+    /// True if the offset was already set.
+    pub fn set(&self, offset: u64) -> Result<bool> {
+        let (word, gen, mask) = match self.check(offset)? {
+            Some(found) => found,
+            None => return Ok(true),
+        };
+        let mut value = word.load(Ordering::Acquire);
+        loop {
+            let current = value >> BLOCK_BITS;
+            if current != gen {
+                // The block was completed after we checked the window.
+                return if current == next_generation(gen) {
+                    Ok(true)
+                } else {
+                    Err(BitSetError::WindowFull {
+                        offset,
+                        end: self.base_block.load(Ordering::Acquire) * BLOCK_BITS
+                            + self.window(),
+                    })
+                };
+            }
+            if value & mask != 0 {
+                return Ok(true);
+            }
+            match word.compare_exchange_weak(
+                value,
+                value | mask,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    if (value | mask) & BLOCK_MASK == BLOCK_MASK {
+                        self.advance();
+                    }
+                    return Ok(false);
+                }
+                Err(v) => value = v,
+            }
+        }
+    }
+
+    /// Checks to see if the bit for an offset is set.
+    /// # Arguments
+    /// `offset` - The offset to check.
+    pub fn get(&self, offset: u64) -> Result<bool> {
+        match self.check(offset)? {
+            Some((word, gen, mask)) => {
+                let value = word.load(Ordering::Acquire);
+                Ok(value >> BLOCK_BITS != gen || value & mask != 0)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Slides the window past the blocks at the front that are complete.  Any thread can move it
+    /// forward so a thread stopped in the middle doesn't hold up the others.
+    fn advance(&self) {
+        loop {
+            let block = self.base_block.load(Ordering::Acquire);
+            let word = &self.words[(block % self.blocks()) as usize];
+            let gen = generation(block, self.blocks());
+            let value = word.load(Ordering::Acquire);
+            if value >> BLOCK_BITS == gen {
+                if value & BLOCK_MASK != BLOCK_MASK {
+                    return;
+                }
+                // Free the word for the block a window ahead before moving the base.
+                let _ = word.compare_exchange(
+                    value,
+                    next_generation(gen) << BLOCK_BITS,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+            } else if value >> BLOCK_BITS != next_generation(gen) {
+                // The base was moved by another thread.
+                continue;
+            }
+            let _ = self.base_block.compare_exchange(
+                block,
+                block + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+        }
+    }
+}
+
+/// The generation of the word for a block.
+/// # Arguments
+/// `block` - The block to get the generation for.
+/// `blocks` - The number of blocks in the window.
+#[inline]
+fn generation(block: u64, blocks: u64) -> u64 {
+    (block / blocks) & BLOCK_MASK
+}
+
+/// The generation after a generation.
+#[inline]
+fn next_generation(gen: u64) -> u64 {
+    (gen + 1) & BLOCK_MASK
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::bitset::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    pub fn bit_set_test() {
+        let bits = ConcurrentBitSet::new(200);
+        assert_eq!(Ok(false), bits.set(0));
+        assert_eq!(Ok(true), bits.set(0));
+        assert_eq!(Ok(true), bits.get(0));
+        assert_eq!(Ok(false), bits.get(1));
+        assert_eq!(
+            Err(BitSetError::OutOfRange {
+                index: 200,
+                capacity: 200
+            }),
+            bits.set(200)
+        );
+        assert!(bits.get(1000).is_err());
+
+        for i in 1..130 {
+            bits.set(i).unwrap();
+        }
+        assert_eq!(Some(130), bits.first_clear_from(0));
+        assert_eq!(Some(130), bits.first_clear_from(64));
+        assert_eq!(Some(150), bits.first_clear_from(150));
+        assert_eq!(Ok(130), bits.count_set_in(0..200));
+        assert_eq!(Ok(6), bits.count_set_in(60..66));
+        assert_eq!(Ok(0), bits.count_set_in(130..200));
+        assert!(bits.count_set_in(0..201).is_err());
+        assert_eq!(Ok(true), bits.clear(64));
+        assert_eq!(Some(64), bits.first_clear_from(0));
+
+        for i in 0..200 {
+            bits.set(i).unwrap();
+        }
+        assert_eq!(None, bits.first_clear_from(0));
+        assert_eq!(None, bits.first_clear_from(500));
+    }
+
+    #[test]
+    pub fn concurrent_set_test() {
+        let bits = Arc::new(ConcurrentBitSet::new(10_000));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let bits = bits.clone();
+                thread::spawn(move || {
+                    // Every thread sets every bit so only one of them should see it clear.
+                    (0..10_000).filter(|i| !bits.set(*i).unwrap()).count()
+                })
+            })
+            .collect();
+        let first: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(10_000, first);
+        assert_eq!(Ok(10_000), bits.count_set_in(0..10_000));
+        assert_eq!(None, bits.first_clear_from(0));
+    }
+
+    #[test]
+    pub fn window_test() {
+        let window = SlidingBitWindow::new(40);
+        assert_eq!(64, window.window());
+        assert_eq!(0, window.base());
+        assert_eq!(Ok(false), window.set(1));
+        assert_eq!(0, window.base());
+        assert_eq!(Ok(false), window.set(0));
+        assert_eq!(2, window.base());
+        assert_eq!(Ok(true), window.set(1));
+        assert_eq!(
+            Err(BitSetError::WindowFull {
+                offset: 64,
+                end: 64
+            }),
+            window.set(64)
+        );
+        for i in 2..40 {
+            window.set(i).unwrap();
+        }
+        // The first block is complete so the window moved forward.
+        assert_eq!(40, window.base());
+        assert_eq!(Ok(false), window.set(64));
+        assert_eq!(Ok(true), window.get(64));
+        assert_eq!(Ok(false), window.get(65));
+        // Before the window is always set.
+        assert_eq!(Ok(true), window.get(5));
+        assert_eq!(Ok(true), window.set(5));
+        assert!(window.set(96).is_err());
+    }
+
+    #[test]
+    pub fn concurrent_window_test() {
+        let window = Arc::new(SlidingBitWindow::new(256));
+        let threads: Vec<_> = (0..8u64)
+            .map(|t| {
+                let window = window.clone();
+                thread::spawn(move || {
+                    let mut offset = t;
+                    while offset < 100_000 {
+                        match window.set(offset) {
+                            Ok(previous) => {
+                                assert!(!previous);
+                                offset += 8;
+                            }
+                            Err(BitSetError::WindowFull { .. }) => thread::yield_now(),
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(100_000, window.base());
+        assert_eq!(Ok(false), window.get(100_000));
+    }
+}
@@ -0,0 +1,385 @@
+//! A bloom filter that can be added to by many threads without a lock.  Used in front of a slower
+//! lookup so the common case of a key that has never been seen is answered right away.
+//!
+//! The bits are kept in atomic words and the k positions for a key come from double hashing.
+//!```text
+//! h1, h2 = hash(key)
+//! bit(i) = (h1 + i * h2) % bits    for i in 0..k
+//!```
+//! The hash is computed here instead of using the std hasher so the positions are the same after a
+//! restart and the filter can be saved to a sidecar file.
+//!```text
+//! +----------+---------+-----+------+----------+--------------------+
+//! | "A19BLM" | version | k   | bits | capacity | false positive     |
+//! | 6 bytes  | u16     | u32 | u64  | u64      | rate f64           |
+//! +----------+---------+-----+------+----------+--------------------+
+//! | words as little endian u64s                                     |
+//! +-----------------------------------------------------------------+
+//!```
+use std::fs::{rename, File};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The magic bytes at the start of a saved filter.
+const MAGIC: &[u8; 6] = b"A19BLM";
+/// The version of the saved filter.
+const VERSION: u16 = 1;
+/// The size of the header of a saved filter.
+const HEADER_SIZE: usize = 36;
+
+/// FNV-1a with a final mix so the hashes are stable between runs.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        mix(self.0)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+}
+
+/// The splitmix64 finalizer.
+#[inline]
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A bloom filter.
+pub struct BloomFilter {
+    words: Box<[AtomicU64]>,
+    /// The number of bits in the filter.
+    bits: u64,
+    /// The number of bits set for each key.
+    hashes: u32,
+    /// The number of keys the filter was sized for.
+    capacity: u64,
+    /// The false positive rate the filter was sized for.
+    false_positive_rate: f64,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized to stay under the false positive rate.
+    /// # Arguments
+    /// `capacity` - The number of keys expected to be added.
+    /// `false_positive_rate` - The chance a key that wasn't added is reported as added once the
+    /// filter is at capacity.
+    pub fn new(capacity: u64, false_positive_rate: f64) -> Self {
+        let n = capacity.max(1) as f64;
+        let p = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = ((-n * p.ln() / (ln2 * ln2)).ceil() as u64).max(64);
+        let bits = bits.div_ceil(64) * 64;
+        let hashes = ((bits as f64 / n * ln2).round() as u32).max(1);
+        BloomFilter::with_size(bits, hashes, capacity, false_positive_rate)
+    }
+
+    /// Creates an empty filter.
+    /// # Arguments
+    /// `bits` - The number of bits.  Must be a multiple of 64.
+    /// `hashes` - The number of bits set for each key.
+    fn with_size(bits: u64, hashes: u32, capacity: u64, false_positive_rate: f64) -> Self {
+        BloomFilter {
+            words: (0..bits / 64).map(|_| AtomicU64::new(0)).collect(),
+            bits,
+            hashes,
+            capacity,
+            false_positive_rate,
+        }
+    }
+
+    /// The number of bits in the filter.
+    pub fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    /// The number of bits set for each key.
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    /// The number of keys the filter was sized for.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// The false positive rate the filter was sized for.
+    pub fn false_positive_rate(&self) -> f64 {
+        self.false_positive_rate
+    }
+
+    /// Gets the two hashes for a key.
+    fn hash_key<T: Hash + ?Sized>(key: &T) -> (u64, u64) {
+        let mut hasher = StableHasher::new();
+        key.hash(&mut hasher);
+        let h1 = hasher.finish();
+        // Odd so the positions don't repeat early.
+        (h1, mix(h1 ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    /// Calls the function with the word and the mask for each of the bits for a key.
+    #[inline]
+    fn for_each_bit<T: Hash + ?Sized, F: FnMut(&AtomicU64, u64) -> bool>(&self, key: &T, mut f: F) {
+        let (h1, h2) = BloomFilter::hash_key(key);
+        for i in 0..self.hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.bits;
+            if !f(&self.words[(bit / 64) as usize], 1 << (bit % 64)) {
+                break;
+            }
+        }
+    }
+
+    /// Adds a key to the filter.
+    /// # Arguments
+    /// `key` - The key to add.
+    /// # Returns
+    /// True if the key might have already been added.
+    pub fn insert<T: Hash + ?Sized>(&self, key: &T) -> bool {
+        let mut present = true;
+        self.for_each_bit(key, |word, mask| {
+            if word.fetch_or(mask, Ordering::AcqRel) & mask == 0 {
+                present = false;
+            }
+            true
+        });
+        present
+    }
+
+    /// Checks to see if a key might have been added.
+    /// # Arguments
+    /// `key` - The key to check.
+    /// # Returns
+    /// False if the key has never been added.  True if it might have been.
+    pub fn maybe_contains<T: Hash + ?Sized>(&self, key: &T) -> bool {
+        let mut present = true;
+        self.for_each_bit(key, |word, mask| {
+            present = word.load(Ordering::Acquire) & mask != 0;
+            present
+        });
+        present
+    }
+
+    /// The fraction of the bits that are set.  The false positive rate is about the fill ratio to
+    /// the power of the number of hashes.
+    pub fn fill_ratio(&self) -> f64 {
+        let set: u64 = self
+            .words
+            .iter()
+            .map(|w| w.load(Ordering::Relaxed).count_ones() as u64)
+            .sum();
+        set as f64 / self.bits as f64
+    }
+
+    /// Writes the filter.
+    /// # Arguments
+    /// `to` - Where to write the filter.
+    pub fn write_to<W: Write>(&self, to: &mut W) -> io::Result<()> {
+        let mut header = [0; HEADER_SIZE];
+        header[0..6].copy_from_slice(MAGIC);
+        header[6..8].copy_from_slice(&VERSION.to_le_bytes());
+        header[8..12].copy_from_slice(&self.hashes.to_le_bytes());
+        header[12..20].copy_from_slice(&self.bits.to_le_bytes());
+        header[20..28].copy_from_slice(&self.capacity.to_le_bytes());
+        header[28..36].copy_from_slice(&self.false_positive_rate.to_bits().to_le_bytes());
+        to.write_all(&header)?;
+        for word in self.words.iter() {
+            to.write_all(&word.load(Ordering::Acquire).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads in a filter that was written with `write_to`.
+    /// # Arguments
+    /// `from` - Where to read the filter from.
+    pub fn read_from<R: Read>(from: &mut R) -> io::Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        from.read_exact(&mut header)?;
+        let mut u16_bytes = [0; 2];
+        let mut u32_bytes = [0; 4];
+        let mut u64_bytes = [0; 8];
+        u16_bytes.copy_from_slice(&header[6..8]);
+        if &header[0..6] != MAGIC || u16::from_le_bytes(u16_bytes) != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a bloom filter file.",
+            ));
+        }
+        u32_bytes.copy_from_slice(&header[8..12]);
+        let hashes = u32::from_le_bytes(u32_bytes);
+        u64_bytes.copy_from_slice(&header[12..20]);
+        let bits = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&header[20..28]);
+        let capacity = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&header[28..36]);
+        let false_positive_rate = f64::from_bits(u64::from_le_bytes(u64_bytes));
+        if bits == 0 || bits % 64 != 0 || hashes == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The size of the bloom filter is invalid.",
+            ));
+        }
+        let filter = BloomFilter::with_size(bits, hashes, capacity, false_positive_rate);
+        for word in filter.words.iter() {
+            from.read_exact(&mut u64_bytes)?;
+            word.store(u64::from_le_bytes(u64_bytes), Ordering::Relaxed);
+        }
+        Ok(filter)
+    }
+}
+
+/// A bloom filter that is saved to a file so it doesn't start empty after a restart.
+pub struct PersistedBloomFilter {
+    filter: BloomFilter,
+    path: PathBuf,
+}
+
+impl PersistedBloomFilter {
+    /// Opens the filter saved in a file or creates an empty one if there isn't a file or it was
+    /// sized differently.
+    /// # Arguments
+    /// `path` - The path to the file to save the filter to.
+    /// `capacity` - The number of keys expected to be added.
+    /// `false_positive_rate` - The false positive rate at capacity.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        capacity: u64,
+        false_positive_rate: f64,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let filter = if path.exists() {
+            let saved = BloomFilter::read_from(&mut BufReader::new(File::open(&path)?))?;
+            if saved.capacity == capacity && saved.false_positive_rate == false_positive_rate {
+                saved
+            } else {
+                BloomFilter::new(capacity, false_positive_rate)
+            }
+        } else {
+            BloomFilter::new(capacity, false_positive_rate)
+        };
+        Ok(PersistedBloomFilter { filter, path })
+    }
+
+    /// The path the filter is saved to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Saves the filter.  Written to a temporary file first so a crash doesn't leave a partial
+    /// filter behind.
+    pub fn save(&self) -> io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            self.filter.write_to(&mut writer)?;
+            writer.into_inner()?.sync_all()?;
+        }
+        rename(&tmp, &self.path)
+    }
+}
+
+impl Deref for PersistedBloomFilter {
+    type Target = BloomFilter;
+
+    fn deref(&self) -> &BloomFilter {
+        &self.filter
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::bloom::*;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::sync::Arc;
+    use std::thread;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_concurrent_bloom";
+
+    #[test]
+    pub fn false_positive_test() {
+        let filter = BloomFilter::new(100_000, 0.01);
+        assert_eq!(7, filter.hashes());
+        for i in 0..100_000u64 {
+            filter.insert(&(7u64, i));
+        }
+        // Never a false negative.
+        for i in 0..100_000u64 {
+            assert!(filter.maybe_contains(&(7u64, i)));
+        }
+        let false_positives = (100_000..200_000u64)
+            .filter(|i| filter.maybe_contains(&(7u64, *i)))
+            .count();
+        let rate = false_positives as f64 / 100_000.0;
+        assert!(rate > 0.005 && rate < 0.015, "Rate {}.", rate);
+        let fill = filter.fill_ratio();
+        assert!(fill > 0.45 && fill < 0.55, "Fill {}.", fill);
+    }
+
+    #[test]
+    pub fn concurrent_insert_test() {
+        let filter = Arc::new(BloomFilter::new(80_000, 0.01));
+        let threads: Vec<_> = (0..8u64)
+            .map(|t| {
+                let filter = filter.clone();
+                thread::spawn(move || {
+                    for i in 0..10_000u64 {
+                        filter.insert(&(t, i));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        for t in 0..8u64 {
+            for i in 0..10_000u64 {
+                assert!(filter.maybe_contains(&(t, i)));
+            }
+        }
+    }
+
+    #[test]
+    pub fn persisted_test() {
+        if std::path::Path::new(TEST_DIR).exists() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        create_dir_all(TEST_DIR).unwrap();
+        let path = format!("{}/dedup.bloom", TEST_DIR);
+        {
+            let filter = PersistedBloomFilter::open(&path, 1000, 0.01).unwrap();
+            assert!(!filter.insert(&(1u64, 2u64)));
+            assert!(filter.insert(&(1u64, 2u64)));
+            filter.save().unwrap();
+        }
+        let filter = PersistedBloomFilter::open(&path, 1000, 0.01).unwrap();
+        assert!(filter.maybe_contains(&(1u64, 2u64)));
+        assert!(!filter.maybe_contains(&(1u64, 3u64)));
+        // Sized differently so it starts over.
+        let filter = PersistedBloomFilter::open(&path, 2000, 0.01).unwrap();
+        assert!(!filter.maybe_contains(&(1u64, 2u64)));
+
+        std::fs::write(&path, b"not a filter").unwrap();
+        assert!(PersistedBloomFilter::open(&path, 1000, 0.01).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! A heap buffer whose start is aligned to a power of 2.  Needed for direct I/O where the memory,
+//! the offset and the length all have to be multiples of the block size of the device.
+use a19_core::pow2::PowOf2;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::slice;
+
+/// A zeroed buffer aligned in memory.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocates a zeroed buffer.
+    /// # Arguments
+    /// `capacity` - The size of the buffer.  Rounded up to a multiple of the alignment.
+    /// `alignment` - The alignment of the start of the buffer.  Must be a power of 2.
+    pub fn new(capacity: usize, alignment: usize) -> Self {
+        assert!(alignment.is_power_of_two(), "The alignment must be a power of 2.");
+        let size = capacity.max(1).align_up(alignment);
+        let layout = Layout::from_size_align(size, alignment).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+        AlignedBuffer {
+            ptr: NonNull::new(ptr).expect("Unable to allocate the aligned buffer."),
+            layout,
+        }
+    }
+
+    /// The size of the buffer.
+    pub fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// The alignment of the start of the buffer.
+    pub fn alignment(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+impl std::fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlignedBuffer")
+            .field("capacity", &self.capacity())
+            .field("alignment", &self.alignment())
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::buffer::aligned::*;
+
+    #[test]
+    pub fn aligned_test() {
+        let mut buffer = AlignedBuffer::new(5000, 4096);
+        assert_eq!(8192, buffer.capacity());
+        assert_eq!(0, buffer.as_ptr() as usize % 4096);
+        assert!(buffer.iter().all(|b| *b == 0));
+        buffer[4095] = 7;
+        assert_eq!(7, buffer[4095]);
+    }
+}
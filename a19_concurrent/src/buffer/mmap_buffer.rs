@@ -69,6 +69,20 @@ impl MemoryMappedInt {
     pub fn flush(&self) -> Result<()> {
         self.mmap.flush()
     }
+
+    /// Forces a flush of part of the memory mapped file to storage.  The range is widened to the
+    /// pages it is in.
+    /// # Arguments
+    /// `offset` - The offset of the first byte to flush.
+    /// `len` - The number of bytes to flush.
+    pub fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
+        self.mmap.flush_range(offset, len)
+    }
+
+    /// The file the buffer is mapped to.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
 }
 
 impl DirectByteBuffer for MemoryMappedInt {
@@ -1,7 +1,11 @@
+pub mod aligned;
 pub mod atomic_buffer;
 pub mod mmap_buffer;
+pub mod pool;
 pub mod ring_buffer;
 
+use a19_core::pow2::PowOf2;
+
 /// Used to find the complement of power of 2.
 /// # Arguments
 /// `value` - The value to find the complement of.
@@ -17,7 +21,7 @@ pub fn complement(value: usize) -> usize {
 /// `alignment` - The amount of the alignment.  Must be a power of 2.
 #[inline]
 pub fn align(value: usize, alignment: usize) -> usize {
-    (value + (alignment - 1)) & !(alignment - 1)
+    value.align_up(alignment)
 }
 
 /// Used to find the next value.  This useful for appending on buffers.
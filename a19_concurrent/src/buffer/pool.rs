@@ -0,0 +1,327 @@
+//! A pool of byte buffers so the hot paths can reuse the buffers for the message bodies instead of
+//! allocating one per message.  The buffers are grouped into size classes and each class keeps its
+//! free buffers in a lock free queue.
+//!```text
+//! checkout(len) -> smallest class >= len -> free buffer (hit) or a new one (miss)
+//! drop(PooledBuf) -> back to its class if the class isn't full otherwise freed
+//!```
+//! A buffer bigger than the largest class isn't pooled.
+use crate::queue::mpmc_queue::MpmcQueueWrap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The size classes used by default.
+const DEFAULT_CLASSES: [usize; 6] = [64, 256, 1024, 4096, 0x4000, 0x10000];
+
+/// The free buffers for a size.
+struct SizeClass {
+    /// The capacity of the buffers in the class.
+    size: usize,
+    free: MpmcQueueWrap<Vec<u8>>,
+    /// The number of buffers in the free queue.  The queue size is a power of 2 so this keeps
+    /// the class to its cap.
+    pooled: AtomicUsize,
+}
+
+/// The counts of how the pool has been used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The number of checkouts that reused a buffer.
+    pub hits: u64,
+    /// The number of checkouts that allocated a buffer.
+    pub misses: u64,
+    /// The number of buffers put back into the pool.
+    pub returned: u64,
+    /// The number of buffers freed since their class was full.
+    pub discarded: u64,
+}
+
+/// A pool of byte buffers.
+pub struct BufferPool {
+    /// The classes ordered by their size.
+    classes: Vec<SizeClass>,
+    /// The max number of free buffers kept for a class.
+    max_per_class: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    returned: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl BufferPool {
+    /// Creates a pool.
+    /// # Arguments
+    /// `sizes` - The capacity of the buffers for each class.
+    /// `max_per_class` - The max number of free buffers kept for a class.
+    pub fn new(sizes: &[usize], max_per_class: usize) -> Arc<Self> {
+        let mut sizes = sizes.to_vec();
+        sizes.sort_unstable();
+        sizes.dedup();
+        Arc::new(BufferPool {
+            classes: sizes
+                .into_iter()
+                .map(|size| SizeClass {
+                    size,
+                    free: MpmcQueueWrap::new(max_per_class.max(1)),
+                    pooled: AtomicUsize::new(0),
+                })
+                .collect(),
+            max_per_class,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            returned: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        })
+    }
+
+    /// Creates a pool with the size classes from 64 bytes to 64 KiB.
+    /// # Arguments
+    /// `max_per_class` - The max number of free buffers kept for a class.
+    pub fn with_default_classes(max_per_class: usize) -> Arc<Self> {
+        BufferPool::new(&DEFAULT_CLASSES, max_per_class)
+    }
+
+    /// Gets an empty buffer.
+    /// # Arguments
+    /// `capacity` - The capacity the buffer needs.
+    /// # Returns
+    /// The buffer that goes back to the pool when it is dropped.
+    pub fn checkout(self: &Arc<Self>, capacity: usize) -> PooledBuf {
+        match self.classes.iter().position(|c| c.size >= capacity) {
+            Some(index) => {
+                let class = &self.classes[index];
+                let buf = match class.free.poll() {
+                    Some(buf) => {
+                        class.pooled.fetch_sub(1, Ordering::AcqRel);
+                        self.hits.fetch_add(1, Ordering::Relaxed);
+                        buf
+                    }
+                    None => {
+                        self.misses.fetch_add(1, Ordering::Relaxed);
+                        Vec::with_capacity(class.size)
+                    }
+                };
+                PooledBuf {
+                    buf,
+                    home: Some((self.clone(), index)),
+                }
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                PooledBuf::unpooled(Vec::with_capacity(capacity))
+            }
+        }
+    }
+
+    /// Gets a buffer with a copy of the bytes.
+    /// # Arguments
+    /// `bytes` - The bytes to copy into the buffer.
+    pub fn copy_of(self: &Arc<Self>, bytes: &[u8]) -> PooledBuf {
+        let mut buf = self.checkout(bytes.len());
+        buf.extend_from_slice(bytes);
+        buf
+    }
+
+    /// Puts a buffer back into its class.
+    /// # Arguments
+    /// `index` - The index of the class.
+    /// `buf` - The buffer to put back.
+    fn give_back(&self, index: usize, mut buf: Vec<u8>) {
+        let class = &self.classes[index];
+        if buf.capacity() < class.size {
+            // Was shrunk so it doesn't belong in the class anymore.
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if class.pooled.fetch_add(1, Ordering::AcqRel) >= self.max_per_class {
+            class.pooled.fetch_sub(1, Ordering::AcqRel);
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        buf.clear();
+        if class.free.offer(buf) {
+            self.returned.fetch_add(1, Ordering::Relaxed);
+        } else {
+            class.pooled.fetch_sub(1, Ordering::AcqRel);
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The counts of how the pool has been used.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            returned: self.returned.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The number of free buffers in each class.
+    pub fn free_buffers(&self) -> Vec<(usize, usize)> {
+        self.classes
+            .iter()
+            .map(|c| (c.size, c.pooled.load(Ordering::Acquire)))
+            .collect()
+    }
+}
+
+/// A buffer checked out of a pool.  It is put back into the pool when it is dropped.
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    /// The pool and the index of the class the buffer came from.
+    home: Option<(Arc<BufferPool>, usize)>,
+}
+
+impl PooledBuf {
+    /// Wraps a buffer that doesn't belong to a pool.
+    /// # Arguments
+    /// `buf` - The buffer to wrap.
+    pub fn unpooled(buf: Vec<u8>) -> Self {
+        PooledBuf { buf, home: None }
+    }
+
+    /// True if the buffer goes back to a pool when it is dropped.
+    pub fn is_pooled(&self) -> bool {
+        self.home.is_some()
+    }
+
+    /// Takes the buffer out of the pool.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.home = None;
+        std::mem::take(&mut self.buf)
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl fmt::Debug for PooledBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuf")
+            .field("buf", &self.buf)
+            .field("pooled", &self.is_pooled())
+            .finish()
+    }
+}
+
+impl PartialEq for PooledBuf {
+    fn eq(&self, other: &Self) -> bool {
+        self.buf == other.buf
+    }
+}
+
+impl Eq for PooledBuf {}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some((pool, index)) = self.home.take() {
+            pool.give_back(index, std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::buffer::pool::*;
+    use std::thread;
+
+    #[test]
+    pub fn reuse_test() {
+        let pool = BufferPool::new(&[256, 64], 4);
+        let mut buf = pool.checkout(100);
+        assert_eq!(256, buf.capacity());
+        buf.extend_from_slice(&[1, 2, 3]);
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        // Gets the same buffer back empty.
+        let buf = pool.checkout(200);
+        assert_eq!(ptr, buf.as_ptr());
+        assert_eq!(256, buf.capacity());
+        assert!(buf.is_empty());
+        drop(buf);
+        let buf = pool.copy_of(&[4; 10]);
+        assert_eq!(64, buf.capacity());
+        assert_eq!(&[4; 10][..], &buf[..]);
+        drop(buf);
+
+        // Too big to pool.
+        let big = pool.checkout(1000);
+        assert!(!big.is_pooled());
+        drop(big);
+        let vec = pool.checkout(10).into_vec();
+        assert_eq!(64, vec.capacity());
+
+        assert_eq!(
+            PoolStats {
+                hits: 2,
+                misses: 3,
+                returned: 3,
+                discarded: 0,
+            },
+            pool.stats()
+        );
+        assert_eq!(vec![(64, 0), (256, 1)], pool.free_buffers());
+    }
+
+    #[test]
+    pub fn cap_test() {
+        let pool = BufferPool::new(&[64], 3);
+        let bufs: Vec<PooledBuf> = (0..5).map(|_| pool.checkout(64)).collect();
+        drop(bufs);
+        assert_eq!(vec![(64, 3)], pool.free_buffers());
+        let stats = pool.stats();
+        assert_eq!(3, stats.returned);
+        assert_eq!(2, stats.discarded);
+
+        let bufs: Vec<PooledBuf> = (0..5).map(|_| pool.checkout(64)).collect();
+        assert_eq!(vec![(64, 0)], pool.free_buffers());
+        assert_eq!(3, pool.stats().hits);
+        assert_eq!(10, pool.stats().misses + pool.stats().hits);
+        drop(bufs);
+    }
+
+    #[test]
+    pub fn concurrent_test() {
+        let pool = BufferPool::new(&[64, 256], 16);
+        let threads: Vec<_> = (0..8u8)
+            .map(|t| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    for i in 0..5000usize {
+                        let mut buf = pool.checkout(if i % 2 == 0 { 64 } else { 200 });
+                        assert!(buf.is_empty());
+                        buf.resize(48, t);
+                        thread::yield_now();
+                        // No one else has the buffer.
+                        assert!(buf.iter().all(|b| *b == t));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let stats = pool.stats();
+        assert_eq!(40000, stats.hits + stats.misses);
+        assert_eq!(40000, stats.returned + stats.discarded);
+        for (_, free) in pool.free_buffers() {
+            assert!(free <= 16);
+        }
+    }
+}
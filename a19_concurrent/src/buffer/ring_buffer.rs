@@ -1,8 +1,9 @@
 use crate::buffer::atomic_buffer::{AtomicByteBuffer, AtomicByteBufferInt};
-use crate::buffer::{next_pos, DirectByteBuffer};
-use crate::queue::PaddedUsize;
+use crate::buffer::DirectByteBuffer;
+use a19_core::pow2::PowOf2;
+use a19_core::cache_padded::CachePadded;
 use std::cell::UnsafeCell;
-use std::sync::atomic::{fence, Ordering};
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 pub struct BytesReadInfo {
@@ -31,6 +32,19 @@ impl ManyToOneBufferReader {
         buffer.read(act, limit)
     }
 
+    /// Used to read in a series of messages along with the index they were written to.  The index
+    /// is the value returned by the writer.
+    /// # Arguments
+    /// `act` - The function to call to read in the message.
+    /// `limit` - The maximum number of messages to process.
+    pub fn read_indexed<F>(&'_ self, act: F, limit: u32) -> BytesReadInfo
+    where
+        F: FnMut(usize, i32, &[u8]),
+    {
+        let buffer = unsafe { &mut *self.buffer.get() };
+        buffer.read_indexed(act, limit)
+    }
+
     /// Called when we are done reading the bytes in.
     /// # Arguments
     /// `read_info` - The info we are reading.
@@ -79,8 +93,8 @@ pub fn create_many_to_one(size: usize) -> (ManyToOneBufferReader, ManyToOneBuffe
 }
 
 struct ManyToOneBufferInt {
-    producer: PaddedUsize,
-    consumer: PaddedUsize,
+    producer: CachePadded<AtomicUsize>,
+    consumer: CachePadded<AtomicUsize>,
     buffer: AtomicByteBufferInt,
     mask: usize,
 }
@@ -144,6 +158,14 @@ pub trait RingBuffer {
     where
         F: FnMut(i32, &'a [u8]);
 
+    /// Used to read in a series of messages with the index of the message.
+    /// # Arguments
+    /// `act` - The function to call with the index of the message.
+    /// `limit` - The maximum number of messages to process.
+    fn read_indexed<'a, F>(&'a mut self, act: F, limit: u32) -> BytesReadInfo
+    where
+        F: FnMut(usize, i32, &'a [u8]);
+
     /// Called when we are done reading the bytes in.
     fn read_completed(&mut self, read_info: &BytesReadInfo);
 
@@ -166,8 +188,8 @@ impl ManyToOneBufferInt {
         let buffer = AtomicByteBufferInt::new(size);
         let mask = buffer.capacity() - 1;
         ManyToOneBufferInt {
-            producer: PaddedUsize::new(0),
-            consumer: PaddedUsize::new(0),
+            producer: CachePadded::new(AtomicUsize::new(0)),
+            consumer: CachePadded::new(AtomicUsize::new(0)),
             buffer,
             mask,
         }
@@ -189,7 +211,7 @@ impl RingBuffer for ManyToOneBufferInt {
             None
         } else {
             let record_length: usize = buffer.len() + HEADER_SIZE;
-            let required_capacity = next_pos(record_length, ALIGNMENT);
+            let required_capacity = record_length.align_up(ALIGNMENT);
             let index = self.claim_capacity(required_capacity);
             match index {
                 Some(i) => {
@@ -215,9 +237,20 @@ impl RingBuffer for ManyToOneBufferInt {
     fn read<'a, F>(&'a mut self, mut act: F, limit: u32) -> BytesReadInfo
     where
         F: FnMut(i32, &'a [u8]),
+    {
+        self.read_indexed(|_, msg_type, bytes| act(msg_type, bytes), limit)
+    }
+
+    /// Used to read the next message with the index it was written at.
+    /// # Arguments
+    /// `act` - The function to call to read in the message.
+    /// `limit` - The maximum number of messages to process.
+    fn read_indexed<'a, F>(&'a mut self, mut act: F, limit: u32) -> BytesReadInfo
+    where
+        F: FnMut(usize, i32, &'a [u8]),
     {
         let capcity = self.capacity();
-        let head = self.consumer.counter.load(Ordering::Relaxed);
+        let head = self.consumer.load(Ordering::Relaxed);
         let head_index = head & self.mask;
         let max_block_length = capcity - head_index;
         let mut bytes_read: usize = 0;
@@ -231,7 +264,7 @@ impl RingBuffer for ManyToOneBufferInt {
                 if record_length <= 0 {
                     break;
                 } else {
-                    bytes_read += next_pos(record_length as usize, ALIGNMENT);
+                    bytes_read += (record_length as usize).align_up(ALIGNMENT);
 
                     let message_type = self.buffer.get_i32(message_type_offset(record_index));
                     if message_type == PADDING_MESSAGE_TYPE {
@@ -241,7 +274,7 @@ impl RingBuffer for ManyToOneBufferInt {
                         let byte_arrays = self
                             .buffer
                             .get_bytes(record_index + HEADER_SIZE, record_length_u - HEADER_SIZE);
-                        act(message_type, byte_arrays);
+                        act(record_index, message_type, byte_arrays);
                         messages_read += 1;
                         if messages_read >= limit {
                             break;
@@ -261,9 +294,7 @@ impl RingBuffer for ManyToOneBufferInt {
     fn read_completed(&mut self, read_info: &BytesReadInfo) {
         self.buffer
             .set_bytes(read_info.start, read_info.bytes_read, 0);
-        self.consumer
-            .counter
-            .store(read_info.start + read_info.bytes_read, Ordering::Release);
+        self.consumer.store(read_info.start + read_info.bytes_read, Ordering::Release);
     }
 
     /// The maximum length of a message.
@@ -273,12 +304,12 @@ impl RingBuffer for ManyToOneBufferInt {
 
     /// The current producer position.
     fn producer_position(&self) -> usize {
-        self.producer.counter.load(Ordering::Acquire)
+        self.producer.load(Ordering::Acquire)
     }
 
     /// The current consumer position.
     fn consumer_position(&self) -> usize {
-        self.consumer.counter.load(Ordering::Acquire)
+        self.consumer.load(Ordering::Acquire)
     }
 
     /// The current size of the buffer.
@@ -294,8 +325,8 @@ impl ManyToOneBufferInt {
     fn claim_capacity(&mut self, required_capacity: usize) -> Option<usize> {
         let capacity = self.capacity();
         loop {
-            let head = self.consumer.counter.load(Ordering::Acquire);
-            let tail = self.producer.counter.load(Ordering::Acquire);
+            let head = self.consumer.load(Ordering::Acquire);
+            let tail = self.producer.load(Ordering::Acquire);
 
             let available_capacity = capacity - (tail - head);
             if required_capacity > available_capacity {
@@ -309,7 +340,7 @@ impl ManyToOneBufferInt {
                         break None;
                     } else {
                         let padding = buffer_end_length as i32;
-                        if self.producer.counter.compare_exchange_weak(
+                        if self.producer.compare_exchange_weak(
                             tail,
                             0,
                             Ordering::SeqCst,
@@ -325,7 +356,7 @@ impl ManyToOneBufferInt {
                             break Some(0);
                         }
                     }
-                } else if self.producer.counter.compare_exchange_weak(
+                } else if self.producer.compare_exchange_weak(
                     tail,
                     tail + required_capacity,
                     Ordering::SeqCst,
@@ -359,6 +390,23 @@ mod tests {
         buffer.read_completed(&result);
     }
 
+    #[test]
+    pub fn read_indexed_test() {
+        let mut buffer = ManyToOneBufferInt::new(0x100);
+        let bytes: Vec<u8> = vec![10, 11, 12, 13, 14, 14, 16];
+        let first = buffer.write(1, &bytes).unwrap();
+        let second = buffer.write(2, &bytes[0..2]).unwrap();
+        let mut indexes = Vec::new();
+        let result = buffer.read_indexed(
+            |index, msg_type_id, _| {
+                indexes.push((index, msg_type_id));
+            },
+            1000,
+        );
+        buffer.read_completed(&result);
+        assert_eq!(vec![(first, 1), (second, 2)], indexes);
+    }
+
     #[test]
     pub fn fill_to_end_test() {
         let mut buffer = ManyToOneBufferInt::new(0x40);
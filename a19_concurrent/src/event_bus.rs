@@ -0,0 +1,293 @@
+//! Passes events between the components in a process without them knowing about each other.  Each
+//! subscription has its own bounded queue so a slow subscriber only loses its own events instead
+//! of holding up the publisher.
+//!```text
+//!                      +--> filter --> [ queue ] --> subscription 1
+//! publish(event) ------+--> filter --> [ queue ] --> subscription 2
+//!                      +--> filter --> [ queue ] --> subscription 3
+//!```
+//! The topics are the variants of the event enum.  A subscription can take a filter to only get
+//! the variants it cares about.  When a queue is full the overflow policy of the subscription
+//! decides if the new event or the oldest event is dropped and the drop is counted.
+use crate::queue::mpmc_queue::MpmcQueueWrap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+
+/// The default number of events a subscription can hold.
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// What to do when a subscription's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the event being published.
+    DropNewest,
+    /// Drops the oldest event in the queue to make room.
+    DropOldest,
+}
+
+/// Decides if a subscription gets an event.
+type Filter<E> = Box<dyn Fn(&E) -> bool + Send + Sync>;
+
+/// The queue for a subscription.
+struct Subscriber<E> {
+    queue: MpmcQueueWrap<E>,
+    overflow: OverflowPolicy,
+    filter: Option<Filter<E>>,
+    /// The number of events dropped since the queue was full.
+    dropped: AtomicU64,
+}
+
+impl<E: Clone> Subscriber<E> {
+    /// Adds an event to the queue.
+    /// # Arguments
+    /// `event` - The event to add.
+    fn offer(&self, event: &E) {
+        if let Some(filter) = &self.filter {
+            if !filter(event) {
+                return;
+            }
+        }
+        match self.overflow {
+            OverflowPolicy::DropNewest => {
+                if !self.queue.offer(event.clone()) {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                while !self.queue.offer(event.clone()) {
+                    // The subscriber could have made room before we got to it.
+                    if self.queue.poll().is_some() {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The subscribers shared by the handles.
+struct Subscribers<E> {
+    subscribers: RwLock<Vec<Arc<Subscriber<E>>>>,
+}
+
+/// Publishes the events to all of the subscriptions.  Cloning the bus gives another handle to the
+/// same subscriptions.
+pub struct EventBus<E> {
+    inner: Arc<Subscribers<E>>,
+}
+
+impl<E> Clone for EventBus<E> {
+    fn clone(&self) -> Self {
+        EventBus {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<E: Clone + Send + 'static> Default for EventBus<E> {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
+
+impl<E: Clone + Send + 'static> EventBus<E> {
+    /// Creates a bus without any subscriptions.
+    pub fn new() -> Self {
+        EventBus {
+            inner: Arc::new(Subscribers {
+                subscribers: RwLock::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Sends an event to all of the subscriptions.  Never waits on a subscriber.
+    /// # Arguments
+    /// `event` - The event to publish.
+    pub fn publish(&self, event: E) {
+        let subscribers = self.inner.subscribers.read().unwrap();
+        for subscriber in subscribers.iter() {
+            subscriber.offer(&event);
+        }
+    }
+
+    /// The number of subscriptions.
+    pub fn subscribers(&self) -> usize {
+        self.inner.subscribers.read().unwrap().len()
+    }
+
+    /// Subscribes to all of the events with the default capacity that drops the newest events
+    /// when full.
+    pub fn subscribe(&self) -> Subscription<E> {
+        self.subscribe_with(DEFAULT_CAPACITY, OverflowPolicy::DropNewest)
+    }
+
+    /// Subscribes to all of the events.
+    /// # Arguments
+    /// `capacity` - The number of events the subscription can hold.  Rounded up to a power of 2.
+    /// `overflow` - What to do when the subscription is full.
+    pub fn subscribe_with(&self, capacity: usize, overflow: OverflowPolicy) -> Subscription<E> {
+        self.add(capacity, overflow, None)
+    }
+
+    /// Subscribes to the events that match a filter.  Used to only get some of the variants.
+    /// # Arguments
+    /// `capacity` - The number of events the subscription can hold.  Rounded up to a power of 2.
+    /// `overflow` - What to do when the subscription is full.
+    /// `filter` - Returns true for the events to get.
+    pub fn subscribe_where<F>(
+        &self,
+        capacity: usize,
+        overflow: OverflowPolicy,
+        filter: F,
+    ) -> Subscription<E>
+    where
+        F: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.add(capacity, overflow, Some(Box::new(filter)))
+    }
+
+    fn add(
+        &self,
+        capacity: usize,
+        overflow: OverflowPolicy,
+        filter: Option<Filter<E>>,
+    ) -> Subscription<E> {
+        let subscriber = Arc::new(Subscriber {
+            queue: MpmcQueueWrap::new(capacity),
+            overflow,
+            filter,
+            dropped: AtomicU64::new(0),
+        });
+        self.inner
+            .subscribers
+            .write()
+            .unwrap()
+            .push(subscriber.clone());
+        Subscription {
+            subscriber,
+            bus: Arc::downgrade(&self.inner),
+        }
+    }
+}
+
+/// Receives the events published after it subscribed.  Unsubscribes when dropped.
+pub struct Subscription<E> {
+    subscriber: Arc<Subscriber<E>>,
+    bus: Weak<Subscribers<E>>,
+}
+
+impl<E> Subscription<E> {
+    /// Gets the next event.
+    pub fn poll(&self) -> Option<E> {
+        self.subscriber.queue.poll()
+    }
+
+    /// The number of events dropped since the subscription was full.
+    pub fn dropped(&self) -> u64 {
+        self.subscriber.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<E> Drop for Subscription<E> {
+    fn drop(&mut self) {
+        if let Some(bus) = self.bus.upgrade() {
+            bus.subscribers
+                .write()
+                .unwrap()
+                .retain(|s| !Arc::ptr_eq(s, &self.subscriber));
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::event_bus::*;
+    use std::thread;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum TestEvent {
+        Started,
+        Value(u64),
+    }
+
+    #[test]
+    pub fn subscribers_test() {
+        let bus = EventBus::new();
+        let subscriptions: Vec<_> = (0..3).map(|_| bus.subscribe()).collect();
+        let values = bus.subscribe_where(16, OverflowPolicy::DropNewest, |e| {
+            matches!(e, TestEvent::Value(v) if v % 2 == 0)
+        });
+        assert_eq!(4, bus.subscribers());
+        let publishers: Vec<_> = (0..2u64)
+            .map(|p| {
+                let bus = bus.clone();
+                thread::spawn(move || {
+                    for i in 0..100u64 {
+                        bus.publish(TestEvent::Value(p * 1000 + i));
+                    }
+                })
+            })
+            .collect();
+        for publisher in publishers {
+            publisher.join().unwrap();
+        }
+        bus.publish(TestEvent::Started);
+        for subscription in subscriptions.iter() {
+            let mut next = [0u64, 1000];
+            let mut count = 0;
+            while let Some(event) = subscription.poll() {
+                match event {
+                    TestEvent::Value(v) => {
+                        // Each publisher's events are in the order they were published.
+                        let p = (v / 1000) as usize;
+                        assert_eq!(next[p], v);
+                        next[p] += 1;
+                    }
+                    TestEvent::Started => assert_eq!(200, count),
+                }
+                count += 1;
+            }
+            assert_eq!(201, count);
+            assert_eq!(0, subscription.dropped());
+        }
+        // Only the even values and drops the rest once full.
+        let mut taken = 0;
+        while let Some(event) = values.poll() {
+            assert!(matches!(event, TestEvent::Value(v) if v % 2 == 0));
+            taken += 1;
+        }
+        assert_eq!(16, taken);
+        assert_eq!(84, values.dropped());
+
+        drop(subscriptions);
+        assert_eq!(1, bus.subscribers());
+    }
+
+    #[test]
+    pub fn overflow_test() {
+        let bus = EventBus::new();
+        let newest = bus.subscribe_with(4, OverflowPolicy::DropNewest);
+        let oldest = bus.subscribe_with(4, OverflowPolicy::DropOldest);
+        for i in 0..10u64 {
+            bus.publish(TestEvent::Value(i));
+        }
+        let drain = |s: &Subscription<TestEvent>| {
+            let mut events = Vec::new();
+            while let Some(TestEvent::Value(v)) = s.poll() {
+                events.push(v);
+            }
+            events
+        };
+        assert_eq!(vec![0, 1, 2, 3], drain(&newest));
+        assert_eq!(6, newest.dropped());
+        assert_eq!(vec![6, 7, 8, 9], drain(&oldest));
+        assert_eq!(6, oldest.dropped());
+
+        // Keeps working once there is room again.
+        bus.publish(TestEvent::Value(10));
+        assert_eq!(vec![10], drain(&newest));
+        assert_eq!(vec![10], drain(&oldest));
+        assert_eq!(6, newest.dropped());
+    }
+}
@@ -0,0 +1,391 @@
+//! A consistent hash ring for spreading keys across shards.  Each shard is placed on the ring many
+//! times as virtual nodes so the keys are spread evenly, and a key belongs to the first virtual node
+//! at or after its hash.
+//!```text
+//!            0
+//!        B ----- A          key -> hash -> first point clockwise
+//!      /           \
+//!     C             B       adding a shard only takes the ranges in front of its points
+//!      \           /        so the other keys stay where they are.
+//!        A ----- C
+//!```
+//! The hash is xxHash64 so the assignment is the same between runs and processes.
+use std::collections::BTreeSet;
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// The seed used to place the virtual nodes.  Different from the key seed so a key that looks like
+/// a node doesn't land on it.
+const NODE_SEED: u64 = 0x5EED_0A19;
+
+/// The number of points for each shard that keeps the shards within a few percent of each other.
+pub const DEFAULT_VIRTUAL_NODES: u32 = 1024;
+
+#[inline]
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(b)
+}
+
+#[inline]
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(b)
+}
+
+#[inline]
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+#[inline]
+fn merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ round(0, val))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+/// Hashes bytes with xxHash64.
+/// # Arguments
+/// `bytes` - The bytes to hash.
+/// `seed` - The seed for the hash.
+pub fn xxhash64(bytes: &[u8], seed: u64) -> u64 {
+    let len = bytes.len();
+    let mut rest = bytes;
+    let mut h = if len >= 32 {
+        let mut v1 = seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2);
+        let mut v2 = seed.wrapping_add(PRIME64_2);
+        let mut v3 = seed;
+        let mut v4 = seed.wrapping_sub(PRIME64_1);
+        while rest.len() >= 32 {
+            v1 = round(v1, read_u64(rest));
+            v2 = round(v2, read_u64(&rest[8..]));
+            v3 = round(v3, read_u64(&rest[16..]));
+            v4 = round(v4, read_u64(&rest[24..]));
+            rest = &rest[32..];
+        }
+        let mut h = v1
+            .rotate_left(1)
+            .wrapping_add(v2.rotate_left(7))
+            .wrapping_add(v3.rotate_left(12))
+            .wrapping_add(v4.rotate_left(18));
+        h = merge_round(h, v1);
+        h = merge_round(h, v2);
+        h = merge_round(h, v3);
+        merge_round(h, v4)
+    } else {
+        seed.wrapping_add(PRIME64_5)
+    };
+    h = h.wrapping_add(len as u64);
+    while rest.len() >= 8 {
+        h ^= round(0, read_u64(rest));
+        h = h
+            .rotate_left(27)
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        h ^= (read_u32(rest) as u64).wrapping_mul(PRIME64_1);
+        h = h
+            .rotate_left(23)
+            .wrapping_mul(PRIME64_2)
+            .wrapping_add(PRIME64_3);
+        rest = &rest[4..];
+    }
+    for b in rest {
+        h ^= (*b as u64).wrapping_mul(PRIME64_5);
+        h = h.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+/// A range of hashes that moved to a different shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MovedRange {
+    /// The first hash in the range.
+    pub start: u64,
+    /// The last hash in the range.  Inclusive.
+    pub end: u64,
+    /// The shard that had the range.  `None` if the ring was empty.
+    pub from: Option<u32>,
+    /// The shard that has the range now.  `None` if the ring is empty.
+    pub to: Option<u32>,
+}
+
+impl MovedRange {
+    /// The fraction of the hashes in the range.
+    pub fn fraction(&self) -> f64 {
+        ((self.end - self.start) as f64 + 1.0) / 2f64.powi(64)
+    }
+}
+
+/// The ranges of hashes that changed shards between two rings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RebalanceReport {
+    /// The ranges that moved sorted by the start of the range.
+    pub moved: Vec<MovedRange>,
+}
+
+impl RebalanceReport {
+    /// The fraction of all of the keys that moved.
+    pub fn moved_fraction(&self) -> f64 {
+        self.moved.iter().map(MovedRange::fraction).sum()
+    }
+
+    /// Checks to see if a hash is in one of the moved ranges.
+    /// # Arguments
+    /// `hash` - The hash of the key.
+    pub fn contains(&self, hash: u64) -> bool {
+        self.moved.iter().any(|r| r.start <= hash && hash <= r.end)
+    }
+}
+
+/// A consistent hash ring of shards.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    /// The number of points each shard has on the ring.
+    virtual_nodes: u32,
+    /// The points on the ring sorted by the hash.
+    points: Vec<(u64, u32)>,
+    /// The shards on the ring.
+    nodes: BTreeSet<u32>,
+}
+
+impl HashRing {
+    /// Creates an empty ring.
+    /// # Arguments
+    /// `virtual_nodes` - The number of points for each shard.  More points spreads the keys more
+    /// evenly but makes the ring bigger.
+    pub fn new(virtual_nodes: u32) -> Self {
+        assert!(virtual_nodes > 0, "Need at least 1 virtual node.");
+        HashRing {
+            virtual_nodes,
+            points: Vec::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    /// Creates a ring with the shards on it.
+    /// # Arguments
+    /// `virtual_nodes` - The number of points for each shard.
+    /// `nodes` - The ids of the shards.
+    pub fn with_nodes<I: IntoIterator<Item = u32>>(virtual_nodes: u32, nodes: I) -> Self {
+        let mut ring = HashRing::new(virtual_nodes);
+        for node in nodes {
+            ring.add_node(node);
+        }
+        ring
+    }
+
+    /// The hash of a key.
+    /// # Arguments
+    /// `key` - The key to hash.
+    pub fn hash_key<K: AsRef<[u8]> + ?Sized>(key: &K) -> u64 {
+        xxhash64(key.as_ref(), 0)
+    }
+
+    /// The number of points each shard has on the ring.
+    pub fn virtual_nodes(&self) -> u32 {
+        self.virtual_nodes
+    }
+
+    /// The ids of the shards on the ring in order.
+    pub fn nodes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.nodes.iter().copied()
+    }
+
+    /// The number of shards on the ring.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// True if there aren't any shards on the ring.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Adds a shard to the ring.  Only the keys that now belong to the shard move.
+    /// # Arguments
+    /// `node` - The id of the shard.
+    /// # Returns
+    /// False if the shard was already on the ring.
+    pub fn add_node(&mut self, node: u32) -> bool {
+        if !self.nodes.insert(node) {
+            return false;
+        }
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&node.to_le_bytes());
+        for replica in 0..self.virtual_nodes {
+            bytes[4..].copy_from_slice(&replica.to_le_bytes());
+            self.points.push((xxhash64(&bytes, NODE_SEED), node));
+        }
+        // Ties are broken by the shard id so the ring is the same whatever order they are added in.
+        self.points.sort_unstable();
+        true
+    }
+
+    /// Removes a shard from the ring.  Its keys move to the shards after its points.
+    /// # Arguments
+    /// `node` - The id of the shard.
+    /// # Returns
+    /// False if the shard wasn't on the ring.
+    pub fn remove_node(&mut self, node: u32) -> bool {
+        if self.nodes.remove(&node) {
+            self.points.retain(|(_, n)| *n != node);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Gets the shard a key belongs to.
+    /// # Arguments
+    /// `key` - The key to look up.
+    /// # Returns
+    /// The id of the shard or `None` if the ring is empty.
+    pub fn node_for<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Option<u32> {
+        self.node_for_hash(HashRing::hash_key(key))
+    }
+
+    /// Gets the shard a hash belongs to.
+    /// # Arguments
+    /// `hash` - The hash of the key.
+    pub fn node_for_hash(&self, hash: u64) -> Option<u32> {
+        if self.points.is_empty() {
+            None
+        } else {
+            let pos = self.points.partition_point(|(h, _)| *h < hash);
+            Some(self.points[pos % self.points.len()].1)
+        }
+    }
+
+    /// Gets the ranges of hashes that belong to a different shard in this ring than in another.
+    /// Used to find out which keys need to be moved after adding or removing a shard.
+    /// # Arguments
+    /// `previous` - The ring before the change.
+    pub fn rebalance_report(&self, previous: &HashRing) -> RebalanceReport {
+        let mut bounds: Vec<u64> = self
+            .points
+            .iter()
+            .chain(previous.points.iter())
+            .map(|(h, _)| *h)
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+        let mut report = RebalanceReport::default();
+        let (first, last) = match (bounds.first(), bounds.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return report,
+        };
+        // A range ends at a point so the owner is the same for the whole range.
+        let mut add = |start: u64, end: u64| {
+            let from = previous.node_for_hash(end);
+            let to = self.node_for_hash(end);
+            if from != to {
+                match report.moved.last_mut() {
+                    Some(r) if r.end.wrapping_add(1) == start && r.from == from && r.to == to => {
+                        r.end = end;
+                    }
+                    _ => report.moved.push(MovedRange {
+                        start,
+                        end,
+                        from,
+                        to,
+                    }),
+                }
+            }
+        };
+        add(0, first);
+        for pair in bounds.windows(2) {
+            add(pair[0] + 1, pair[1]);
+        }
+        if last < u64::MAX {
+            // Wraps around to the first point.
+            let from = previous.node_for_hash(first);
+            let to = self.node_for_hash(first);
+            if from != to {
+                report.moved.push(MovedRange {
+                    start: last + 1,
+                    end: u64::MAX,
+                    from,
+                    to,
+                });
+            }
+        }
+        report
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::hash_ring::*;
+
+    #[test]
+    pub fn xxhash64_test() {
+        assert_eq!(0xEF46_DB37_51D8_E999, xxhash64(b"", 0));
+        assert_eq!(0x44BC_2CF5_AD77_0999, xxhash64(b"abc", 0));
+        let long = b"The quick brown fox jumps over the lazy dog";
+        assert_eq!(0x0B24_2D36_1FDA_71BC, xxhash64(long, 0));
+    }
+
+    #[test]
+    pub fn distribution_test() {
+        let ring = HashRing::with_nodes(DEFAULT_VIRTUAL_NODES, 0..4);
+        let mut counts = [0u32; 4];
+        for key in 0..10_000u64 {
+            counts[ring.node_for(&key.to_le_bytes()).unwrap() as usize] += 1;
+        }
+        for count in counts.iter() {
+            // Each shard should have 25% give or take a few percent.
+            assert!((2_350..=2_650).contains(count), "{:?}", counts);
+        }
+        assert_eq!(None, HashRing::new(16).node_for("a"));
+    }
+
+    #[test]
+    pub fn add_node_test() {
+        let before = HashRing::with_nodes(256, 0..4);
+        let mut after = before.clone();
+        assert!(after.add_node(4));
+        assert!(!after.add_node(4));
+        let report = after.rebalance_report(&before);
+        let fraction = report.moved_fraction();
+        assert!(fraction > 0.15 && fraction < 0.25, "{}", fraction);
+        assert!(report.moved.iter().all(|r| r.to == Some(4)));
+
+        let mut moved = 0;
+        for key in 0..10_000u64 {
+            let key = key.to_le_bytes();
+            let old = before.node_for(&key).unwrap();
+            let new = after.node_for(&key).unwrap();
+            if old != new {
+                // Only the keys that went to the new shard moved.
+                assert_eq!(4, new);
+                assert!(report.contains(HashRing::hash_key(&key)));
+                moved += 1;
+            } else {
+                assert!(!report.contains(HashRing::hash_key(&key)));
+            }
+        }
+        assert!(moved > 1_500 && moved < 2_500, "{}", moved);
+
+        assert!(after.remove_node(4));
+        assert!(!after.remove_node(4));
+        let back = after.rebalance_report(&before);
+        assert!(back.moved.is_empty());
+    }
+}
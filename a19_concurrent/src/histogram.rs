@@ -0,0 +1,304 @@
+//! A histogram to record latencies in nanoseconds without a lock.  The values are put into log
+//! buckets like HDR histograms.  Each power of 2 is split into 32 sub buckets so a percentile is
+//! within about 3% of the value recorded.
+//!```text
+//! value < 32       -> bucket = value
+//! value >= 32      -> shift = highest bit - 5, top = value >> shift (32..63)
+//!                     bucket = (shift + 1) * 32 + top - 32
+//!
+//! record  -> shard for the thread -> counts[bucket] += 1
+//! read    -> sum of the counts for each bucket across the shards
+//!```
+//! The threads are spread over a fixed number of shards so the threads recording don't fight over
+//! the same cache lines.
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The number of bits for the sub buckets in a power of 2.
+const SUB_BUCKET_BITS: u32 = 5;
+/// The number of sub buckets in a power of 2.
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// The total number of buckets to cover a u64.
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+/// The number of shards the threads record into.
+const SHARDS: usize = 8;
+
+/// The shard to give to the next thread.
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The shard the current thread records into.
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+}
+
+/// Gets the bucket for a value.
+/// # Arguments
+/// `value` - The value to get the bucket for.
+fn bucket_of(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        value as usize
+    } else {
+        let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+        let top = (value >> shift) as usize;
+        (shift as usize + 1) * SUB_BUCKETS + top - SUB_BUCKETS
+    }
+}
+
+/// Gets the highest value that goes into a bucket.
+/// # Arguments
+/// `bucket` - The bucket to get the value for.
+fn highest_in(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        bucket as u64
+    } else {
+        let shift = (bucket / SUB_BUCKETS - 1) as u32;
+        let top = (bucket % SUB_BUCKETS + SUB_BUCKETS) as u64;
+        (top << shift) + ((1u64 << shift) - 1)
+    }
+}
+
+/// The counts recorded by a group of threads.
+struct Shard {
+    counts: Box<[AtomicU64]>,
+    max: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Shard {
+    fn new() -> Self {
+        Shard {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Records the values to get the percentiles.
+pub struct Histogram {
+    shards: Vec<Shard>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    /// Creates an empty histogram.
+    pub fn new() -> Self {
+        Histogram {
+            shards: (0..SHARDS).map(|_| Shard::new()).collect(),
+        }
+    }
+
+    /// Records a value.
+    /// # Arguments
+    /// `ns` - The value in nanoseconds.
+    pub fn record(&self, ns: u64) {
+        let shard = &self.shards[SHARD.with(|s| *s)];
+        shard.counts[bucket_of(ns)].fetch_add(1, Ordering::Relaxed);
+        shard.sum.fetch_add(ns, Ordering::Relaxed);
+        shard.max.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    /// Gets a percentile of the values recorded.
+    /// # Arguments
+    /// `p` - The percentile to get from 0 to 100.
+    /// # Returns
+    /// The highest value in the bucket the percentile is in or 0 if nothing has been recorded.
+    pub fn percentile(&self, p: f64) -> u64 {
+        self.snapshot().percentile(p)
+    }
+
+    /// Gets a copy of the values recorded.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        self.read(|v| v.load(Ordering::Relaxed))
+    }
+
+    /// Gets a copy of the values recorded and starts over.  A value recorded while taking the
+    /// snapshot ends up in this snapshot or the next one.
+    pub fn snapshot_and_reset(&self) -> HistogramSnapshot {
+        self.read(|v| v.swap(0, Ordering::Relaxed))
+    }
+
+    /// Merges the shards.
+    /// # Arguments
+    /// `take` - How to get a value out of the shard.
+    fn read<F: Fn(&AtomicU64) -> u64>(&self, take: F) -> HistogramSnapshot {
+        let mut snapshot = HistogramSnapshot::default();
+        for shard in self.shards.iter() {
+            for (total, count) in snapshot.counts.iter_mut().zip(shard.counts.iter()) {
+                *total += take(count);
+            }
+            snapshot.sum = snapshot.sum.wrapping_add(take(&shard.sum));
+            snapshot.max = snapshot.max.max(take(&shard.max));
+        }
+        snapshot
+    }
+}
+
+/// A copy of the values recorded by a histogram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    counts: Vec<u64>,
+    max: u64,
+    sum: u64,
+}
+
+impl Default for HistogramSnapshot {
+    fn default() -> Self {
+        HistogramSnapshot {
+            counts: vec![0; BUCKETS],
+            max: 0,
+            sum: 0,
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    /// The number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The largest value recorded.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The average of the values recorded or 0 if there aren't any.
+    pub fn mean(&self) -> u64 {
+        match self.count() {
+            0 => 0,
+            count => self.sum / count,
+        }
+    }
+
+    /// Gets a percentile of the values.
+    /// # Arguments
+    /// `p` - The percentile to get from 0 to 100.
+    /// # Returns
+    /// The highest value in the bucket the percentile is in or 0 if nothing has been recorded.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let count = self.count();
+        if count == 0 {
+            return 0;
+        }
+        let rank = ((p.clamp(0.0, 100.0) * count as f64 / 100.0).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, c) in self.counts.iter().enumerate() {
+            seen += c;
+            if seen >= rank {
+                return highest_in(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Adds the values from another snapshot.
+    /// # Arguments
+    /// `other` - The snapshot to add.
+    pub fn merge(&mut self, other: &HistogramSnapshot) {
+        for (total, count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *total += count;
+        }
+        self.sum = self.sum.wrapping_add(other.sum);
+        self.max = self.max.max(other.max);
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::histogram::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Checks a value is within the error of a bucket.
+    fn assert_close(expected: u64, actual: u64) {
+        let error = expected / SUB_BUCKETS as u64 + 1;
+        assert!(
+            actual >= expected && actual <= expected + error,
+            "Expected {} got {}.",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    pub fn bucket_test() {
+        for value in (0..100_000u64).chain(vec![u64::MAX - 1, u64::MAX]) {
+            let bucket = bucket_of(value);
+            assert!(bucket < BUCKETS);
+            assert!(highest_in(bucket) >= value);
+            if bucket > 0 {
+                assert!(highest_in(bucket - 1) < value);
+            }
+        }
+        assert_eq!(BUCKETS - 1, bucket_of(u64::MAX));
+        assert_eq!(u64::MAX, highest_in(BUCKETS - 1));
+    }
+
+    #[test]
+    pub fn percentile_test() {
+        let histogram = Histogram::new();
+        assert_eq!(0, histogram.percentile(50.0));
+        // Uniform from 1us to 100ms.
+        for i in 1..=100_000u64 {
+            histogram.record(i * 1000);
+        }
+        assert_close(50_000_000, histogram.percentile(50.0));
+        assert_close(99_000_000, histogram.percentile(99.0));
+        assert_close(99_900_000, histogram.percentile(99.9));
+        assert_eq!(100_000_000, histogram.percentile(100.0));
+        assert_close(1000, histogram.percentile(0.0));
+
+        // A long tail is kept out of the median.
+        let snapshot = histogram.snapshot_and_reset();
+        assert_eq!(100_000, snapshot.count());
+        assert_eq!(50_000_500, snapshot.mean());
+        assert_eq!(0, histogram.snapshot().count());
+        for _ in 0..990 {
+            histogram.record(20);
+        }
+        for _ in 0..10 {
+            histogram.record(5_000_000);
+        }
+        assert_eq!(20, histogram.percentile(50.0));
+        assert_eq!(20, histogram.percentile(99.0));
+        assert_close(5_000_000, histogram.percentile(99.5));
+        assert_eq!(5_000_000, histogram.snapshot().max());
+
+        let mut merged = histogram.snapshot();
+        merged.merge(&snapshot);
+        assert_eq!(101_000, merged.count());
+        assert_eq!(100_000_000, merged.max());
+    }
+
+    #[test]
+    pub fn concurrent_test() {
+        let histogram = Arc::new(Histogram::new());
+        let threads: Vec<_> = (0..8u64)
+            .map(|t| {
+                let histogram = histogram.clone();
+                thread::spawn(move || {
+                    for i in 0..100_000u64 {
+                        histogram.record(t * 1000 + i % 1000);
+                    }
+                })
+            })
+            .collect();
+        let mut taken = HistogramSnapshot::default();
+        for _ in 0..10 {
+            taken.merge(&histogram.snapshot_and_reset());
+            thread::yield_now();
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        taken.merge(&histogram.snapshot_and_reset());
+        assert_eq!(800_000, taken.count());
+        assert_eq!(7999, taken.max());
+    }
+}
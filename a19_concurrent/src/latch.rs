@@ -0,0 +1,462 @@
+//! Counters to wait on while the other threads get ready or shut down.  A `Latch` starts with a
+//! count and is released once it is counted down to 0.  A `WaitGroup` has work added and done and
+//! releases the waiters each time everything that was added is done.
+//!```text
+//! Latch::new(3)    count_down   count_down   count_down
+//!      3 ------------> 2 ---------> 1 ---------> 0  => waiters released, stays released
+//!
+//! WaitGroup        add(2)       done         done         add(1)
+//!      0 ------------> 2 ---------> 1 ---------> 0 ---------> 1
+//!                                                \-> waiters released
+//!```
+//! Both are handles that are cloned and given to the threads.  If every handle left is waiting
+//! while the count is above 0 nothing can ever count it down, usually since a thread panicked or
+//! forgot to call `done`.  The waiters get `LatchError::Abandoned` instead of waiting forever.
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// The reasons a wait can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatchError {
+    /// The count didn't get to 0 before the timeout.
+    TimedOut,
+    /// All of the handles that could count down have been dropped.
+    Abandoned,
+}
+
+impl fmt::Display for LatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LatchError::TimedOut => write!(f, "Timed out waiting for the count to get to 0."),
+            LatchError::Abandoned => write!(
+                f,
+                "All of the handles were dropped before the count got to 0."
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LatchError {}
+
+pub type Result<T> = std::result::Result<T, LatchError>;
+
+/// The state shared by the handles.
+struct State {
+    count: usize,
+    /// The number of handles.
+    handles: usize,
+    /// The number of handles waiting.
+    waiting: usize,
+    /// The async waits to wake once the count changes.
+    wakers: Vec<Waker>,
+}
+
+impl State {
+    /// Checks to see if a waiter can stop waiting.
+    /// # Returns
+    /// `None` if the waiter needs to keep waiting.
+    fn check(&self) -> Option<Result<()>> {
+        if self.count == 0 {
+            Some(Ok(()))
+        } else if self.handles <= self.waiting {
+            Some(Err(LatchError::Abandoned))
+        } else {
+            None
+        }
+    }
+}
+
+/// The counter behind the latch and the wait group.
+struct Counter {
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+/// A handle to the counter.  Keeps track of the number of handles so an abandoned counter can be
+/// detected.
+struct Handle {
+    counter: Arc<Counter>,
+}
+
+impl Handle {
+    fn new(count: usize) -> Self {
+        Handle {
+            counter: Arc::new(Counter {
+                state: Mutex::new(State {
+                    count,
+                    handles: 1,
+                    waiting: 0,
+                    wakers: Vec::new(),
+                }),
+                condvar: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.counter.state.lock().unwrap()
+    }
+
+    /// Wakes up everyone waiting so they check the count again.
+    /// # Arguments
+    /// `state` - The locked state.
+    fn wake_all(&self, mut state: MutexGuard<'_, State>) {
+        let wakers: Vec<Waker> = state.wakers.drain(..).collect();
+        drop(state);
+        self.counter.condvar.notify_all();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Changes the count and wakes the waiters if it got to 0.
+    /// # Arguments
+    /// `change` - Changes the count.
+    fn update<F: FnOnce(&mut usize)>(&self, change: F) {
+        let mut state = self.lock();
+        change(&mut state.count);
+        if state.count == 0 {
+            self.wake_all(state);
+        }
+    }
+
+    /// Waits for the count to get to 0.
+    /// # Arguments
+    /// `timeout` - The longest to wait.  `None` to wait forever.
+    fn wait(&self, timeout: Option<Duration>) -> Result<()> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut state = self.lock();
+        state.waiting += 1;
+        let result = loop {
+            if let Some(result) = state.check() {
+                break result;
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Err(LatchError::TimedOut);
+                    }
+                    self.counter
+                        .condvar
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.counter.condvar.wait(state).unwrap(),
+            };
+        };
+        state.waiting -= 1;
+        result
+    }
+
+    fn count(&self) -> usize {
+        self.lock().count
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Self {
+        self.lock().handles += 1;
+        Handle {
+            counter: self.counter.clone(),
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let mut state = self.lock();
+        state.handles -= 1;
+        if state.count > 0 {
+            // The waiters check if they have been abandoned.
+            self.wake_all(state);
+        }
+    }
+}
+
+/// The future returned by the async waits.  Has its own handle so it can be moved to a task.
+pub struct LatchWait {
+    handle: Handle,
+    /// True once we have been counted as waiting.
+    registered: bool,
+}
+
+impl LatchWait {
+    fn new(handle: &Handle) -> Self {
+        LatchWait {
+            handle: handle.clone(),
+            registered: false,
+        }
+    }
+}
+
+impl Future for LatchWait {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = &mut *self;
+        let mut state = this.handle.lock();
+        if !this.registered {
+            this.registered = true;
+            state.waiting += 1;
+        }
+        match state.check() {
+            Some(result) => {
+                state.waiting -= 1;
+                drop(state);
+                this.registered = false;
+                Poll::Ready(result)
+            }
+            None => {
+                state.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for LatchWait {
+    fn drop(&mut self) {
+        if self.registered {
+            self.handle.lock().waiting -= 1;
+        }
+    }
+}
+
+/// Released once it has been counted down to 0.  Once released it stays released.
+#[derive(Clone)]
+pub struct Latch {
+    handle: Handle,
+}
+
+impl Latch {
+    /// Creates a latch.
+    /// # Arguments
+    /// `count` - The number of times it needs to be counted down.
+    pub fn new(count: usize) -> Self {
+        Latch {
+            handle: Handle::new(count),
+        }
+    }
+
+    /// Counts down the latch.  Does nothing once the latch has been released.
+    pub fn count_down(&self) {
+        self.handle.update(|count| *count = count.saturating_sub(1));
+    }
+
+    /// The number of count downs left.
+    pub fn count(&self) -> usize {
+        self.handle.count()
+    }
+
+    /// Blocks until the latch is released.
+    /// # Returns
+    /// `LatchError::Abandoned` if all of the other handles are dropped first.
+    pub fn wait(&self) -> Result<()> {
+        self.handle.wait(None)
+    }
+
+    /// Blocks until the latch is released or the timeout.
+    /// # Arguments
+    /// `timeout` - The longest to wait.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<()> {
+        self.handle.wait(Some(timeout))
+    }
+
+    /// Waits for the latch to be released without blocking the thread.
+    pub fn wait_async(&self) -> LatchWait {
+        LatchWait::new(&self.handle)
+    }
+}
+
+/// Waits for the work that has been added to be done.
+#[derive(Clone)]
+pub struct WaitGroup {
+    handle: Handle,
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        WaitGroup::new()
+    }
+}
+
+impl WaitGroup {
+    /// Creates a wait group without any work.
+    pub fn new() -> Self {
+        WaitGroup {
+            handle: Handle::new(0),
+        }
+    }
+
+    /// Adds work to wait for.
+    /// # Arguments
+    /// `count` - The amount of work to add.
+    pub fn add(&self, count: usize) {
+        self.handle.update(|c| *c += count);
+    }
+
+    /// Marks a piece of work as done.
+    /// # Panics
+    /// If there isn't any work left.
+    pub fn done(&self) {
+        self.handle.update(|count| {
+            assert!(*count > 0, "done was called more times than the work added.");
+            *count -= 1;
+        });
+    }
+
+    /// The amount of work that isn't done.
+    pub fn count(&self) -> usize {
+        self.handle.count()
+    }
+
+    /// Blocks until all of the work is done.
+    /// # Returns
+    /// `LatchError::Abandoned` if all of the other handles are dropped first.
+    pub fn wait(&self) -> Result<()> {
+        self.handle.wait(None)
+    }
+
+    /// Blocks until all of the work is done or the timeout.
+    /// # Arguments
+    /// `timeout` - The longest to wait.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<()> {
+        self.handle.wait(Some(timeout))
+    }
+
+    /// Waits for the work to be done without blocking the thread.
+    pub fn wait_async(&self) -> LatchWait {
+        LatchWait::new(&self.handle)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::latch::*;
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    pub fn latch_test() {
+        let latch = Latch::new(4);
+        let counted = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let latch = latch.clone();
+                let counted = counted.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    latch.count_down();
+                })
+            })
+            .collect();
+        latch.wait().unwrap();
+        // Only released once everyone has counted down.
+        assert_eq!(4, counted.load(Ordering::SeqCst));
+        assert_eq!(0, latch.count());
+        latch.count_down();
+        assert_eq!(0, latch.count());
+        assert_eq!(Ok(()), latch.wait_timeout(Duration::from_millis(1)));
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    pub fn timeout_test() {
+        let latch = Latch::new(1);
+        let _other = latch.clone();
+        let started = Instant::now();
+        assert_eq!(
+            Err(LatchError::TimedOut),
+            latch.wait_timeout(Duration::from_millis(20))
+        );
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        let group = WaitGroup::new();
+        assert_eq!(Ok(()), group.wait_timeout(Duration::from_millis(1)));
+        group.add(2);
+        let worker = group.clone();
+        let t = thread::spawn(move || {
+            worker.done();
+            thread::sleep(Duration::from_millis(20));
+            worker.done();
+        });
+        assert_eq!(
+            Err(LatchError::TimedOut),
+            group.wait_timeout(Duration::from_millis(1))
+        );
+        assert_eq!(Ok(()), group.wait_timeout(Duration::from_secs(5)));
+        assert_eq!(0, group.count());
+        t.join().unwrap();
+    }
+
+    #[test]
+    pub fn async_wait_test() {
+        let latch = Latch::new(2);
+        let wait = latch.wait_async();
+        let counter = latch.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.count_down();
+            thread::sleep(Duration::from_millis(10));
+            counter.count_down();
+        });
+        assert_eq!(Ok(()), block_on(wait));
+        assert_eq!(0, latch.count());
+        t.join().unwrap();
+
+        let group = WaitGroup::new();
+        group.add(1);
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let wait = group.wait_async();
+                thread::spawn(move || block_on(wait))
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(10));
+        group.done();
+        for waiter in waiters {
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        }
+    }
+
+    #[test]
+    pub fn abandoned_test() {
+        // A worker that panics before calling done.
+        let group = WaitGroup::new();
+        group.add(2);
+        let workers: Vec<_> = (0..2)
+            .map(|i| {
+                let group = group.clone();
+                thread::spawn(move || {
+                    if i == 1 {
+                        panic!("Worker failed.");
+                    }
+                    group.done();
+                })
+            })
+            .collect();
+        assert_eq!(Err(LatchError::Abandoned), group.wait());
+        assert_eq!(1, group.count());
+        for worker in workers {
+            let _ = worker.join();
+        }
+
+        // The only handle left is the one waiting.
+        let latch = Latch::new(1);
+        assert_eq!(Err(LatchError::Abandoned), latch.wait());
+        let wait = latch.wait_async();
+        drop(latch);
+        assert_eq!(Err(LatchError::Abandoned), block_on(wait));
+    }
+}
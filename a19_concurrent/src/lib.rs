@@ -3,11 +3,21 @@ extern crate time_test;
 use std::sync::atomic::AtomicU64;
 
 pub mod actor;
+pub mod bitset;
+pub mod bloom;
 pub mod buffer;
 pub mod event;
+pub mod event_bus;
+pub mod hash_ring;
+pub mod histogram;
+pub mod latch;
 pub mod map;
 pub mod queue;
+pub mod rate_limiter;
+pub(crate) mod sync;
+pub mod threads;
 pub mod timeout;
+pub mod wait;
 
 pub struct PaddedU64 {
     // Make sure we are on one cache line.
@@ -1 +1,2 @@
 pub mod mrsw_map;
+pub mod skip_list;
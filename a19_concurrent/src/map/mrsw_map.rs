@@ -1,10 +1,9 @@
+use crate::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use crate::sync::{thread, Arc};
 use std::cell::UnsafeCell;
 use std::cmp::Eq;
 use std::collections::{HashMap, VecDeque};
 use std::hash::Hash;
-use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
-use std::sync::Arc;
-use std::thread;
 
 const READER: u32 = 1;
 const WRITER_PENDING: u32 = 2;
@@ -19,8 +18,8 @@ pub trait ApplyChanges<K: Hash + Eq, V, E> {
 /// The container for the map.
 /// K - Is the key in the hash map.
 struct MapContainer<K: Hash + Eq, V, E> {
-    /// The hash map.
-    map: HashMap<K, V>,
+    /// The hash map.  Only changed once all of the readers have left.
+    map: crate::sync::UnsafeCell<HashMap<K, V>>,
     /// The the number of reads currently on the map.
     reader_count: AtomicU32,
     /// If this map is currently a reader or writer.
@@ -37,7 +36,7 @@ impl<K: Hash + Eq, V, E> MapContainer<K, V, E> {
     /// `event_stream_size` - The event stream size.
     fn new(starting_map: HashMap<K, V>, initial_state: u32, event_stream_size: usize) -> Self {
         MapContainer {
-            map: starting_map,
+            map: crate::sync::UnsafeCell::new(starting_map),
             reader_count: AtomicU32::new(0),
             state: AtomicU32::new(initial_state),
             event_stream: VecDeque::with_capacity(event_stream_size),
@@ -59,6 +58,16 @@ unsafe impl<K: Hash + Eq, V, E, TApplyChange: ApplyChanges<K, V, E>> Send
 {
 }
 
+impl<K: Hash + Eq, V, E, TApplyChange: ApplyChanges<K, V, E>> Clone
+    for MrswMapReader<K, V, E, TApplyChange>
+{
+    fn clone(&self) -> Self {
+        MrswMapReader {
+            map: self.map.clone(),
+        }
+    }
+}
+
 impl<K: Hash + Eq, V, E, TApplyChange: ApplyChanges<K, V, E>> MrswMapReader<K, V, E, TApplyChange> {
     pub fn get<R>(&self, key: K, act: fn(Option<&V>) -> R) -> R {
         unsafe {
@@ -135,7 +144,7 @@ impl<K: Hash + Eq, V, E, TApplyChange: ApplyChanges<K, V, E>> MrswMap<K, V, E, T
     /// `map` - The map to apply the change to.
     /// `event` - The event to apply to the map.
     fn apply_int(apply_change: &TApplyChange, map: &mut MapContainer<K, V, E>, event: &E) {
-        apply_change.apply(&mut map.map, event);
+        map.map.with_mut(|m| apply_change.apply(unsafe { &mut *m }, event));
     }
 
     /// Used to add an event to the reader that will be processed when it is commited.
@@ -205,13 +214,15 @@ impl<K: Hash + Eq, V, E, TApplyChange: ApplyChanges<K, V, E>> WriterMap<K, V, E>
         // Full memory barrier hear so we don't accidently have a thread read the wrong writer
         // value.  Need to do this immediately so we
         writer.state.store(READER, Ordering::SeqCst);
-        reader.state.store(WRITER_PENDING, Ordering::Relaxed);
-        // Need to do an atomic store of the current writer.
-        self.current_reader.store(writer, Ordering::Relaxed);
+        reader.state.store(WRITER_PENDING, Ordering::SeqCst);
+        // Release so the readers see the events applied to the new reader.
+        self.current_reader.store(writer, Ordering::Release);
         self.current_writer.store(reader, Ordering::Relaxed);
         loop {
-            // Wait for the reader count to go to zero.
-            if reader.reader_count.load(Ordering::Relaxed) == 0 {
+            // Wait for the reader count to go to zero.  Has to be a read modify write so a reader
+            // that increments the count after us reads our value and is guaranteed to see the
+            // pending state.  A plain load can miss a reader that is just starting.
+            if reader.reader_count.fetch_add(0, Ordering::AcqRel) == 0 {
                 reader.state.store(WRITER, Ordering::Relaxed);
                 break;
             } else {
@@ -254,17 +265,16 @@ impl<K: Hash + Eq, V, E, TApplyChange: ApplyChanges<K, V, E>> ReaderMap<K, V>
 {
     fn get<R>(&mut self, key: K, act: fn(Option<&V>) -> R) -> R {
         loop {
-            let reader = self.current_reader.load(Ordering::Relaxed);
-            unsafe { (*reader).reader_count.fetch_add(1, Ordering::Relaxed) };
+            let reader = self.current_reader.load(Ordering::Acquire);
+            unsafe { (*reader).reader_count.fetch_add(1, Ordering::AcqRel) };
             // Need to verify it's still the reader before moving on.  Needs to be a LoadStore
             // barrier.
-            if unsafe { (*reader).state.load(Ordering::SeqCst) } == READER {
-                let elem = unsafe { (*reader).map.get(&key) };
-                let r = act(elem);
-                unsafe { (*reader).reader_count.fetch_sub(1, Ordering::Relaxed) };
+            if unsafe { (*reader).state.load(Ordering::Acquire) } == READER {
+                let r = unsafe { (*reader).map.with(|m| act((*m).get(&key))) };
+                unsafe { (*reader).reader_count.fetch_sub(1, Ordering::Release) };
                 break r;
             } else {
-                unsafe { (*reader).reader_count.fetch_sub(1, Ordering::Relaxed) };
+                unsafe { (*reader).reader_count.fetch_sub(1, Ordering::Release) };
                 thread::yield_now();
             }
         }
@@ -275,17 +285,16 @@ impl<K: Hash + Eq, V, E, TApplyChange: ApplyChanges<K, V, E>> ReaderMap<K, V>
         F: FnOnce(&HashMap<K, V>) -> R,
     {
         loop {
-            let reader = self.current_reader.load(Ordering::Relaxed);
-            unsafe { (*reader).reader_count.fetch_add(1, Ordering::Relaxed) };
+            let reader = self.current_reader.load(Ordering::Acquire);
+            unsafe { (*reader).reader_count.fetch_add(1, Ordering::AcqRel) };
             // Need to verify it's still the reader before moving on.  Needs to be a load store
             // barrier.
-            if unsafe { (*reader).state.load(Ordering::SeqCst) } == READER {
-                let map = unsafe { &(*reader).map };
-                let r = act(map);
-                unsafe { (*reader).reader_count.fetch_sub(1, Ordering::Relaxed) };
+            if unsafe { (*reader).state.load(Ordering::Acquire) } == READER {
+                let r = unsafe { (*reader).map.with(|m| act(&*m)) };
+                unsafe { (*reader).reader_count.fetch_sub(1, Ordering::Release) };
                 break r;
             } else {
-                unsafe { (*reader).reader_count.fetch_sub(1, Ordering::Relaxed) };
+                unsafe { (*reader).reader_count.fetch_sub(1, Ordering::Release) };
                 thread::yield_now();
             }
         }
@@ -300,7 +309,7 @@ unsafe impl<K: Hash + Eq, V, E, TApplyChange: ApplyChanges<K, V, E>> Send
 {
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
     use crate::map::mrsw_map::{ApplyChanges, MrswMap, ReaderMap, WriterMap};
     use std::collections::HashMap;
@@ -341,3 +350,38 @@ mod tests {
         assert_eq!("Hi", &r);
     }
 }
+
+#[cfg(loom)]
+mod loom_tests {
+    use crate::map::mrsw_map::{ApplyChanges, MrswMap};
+    use loom::thread;
+    use std::collections::HashMap;
+
+    struct Insert;
+
+    impl ApplyChanges<u64, u64, (u64, u64)> for Insert {
+        fn apply(&self, map: &mut HashMap<u64, u64>, event: &(u64, u64)) {
+            map.insert(event.0, event.1);
+        }
+    }
+
+    #[test]
+    pub fn loom_commit_test() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let (reader, writer) = MrswMap::new(HashMap::new(), HashMap::new(), Insert);
+            let writer = thread::spawn(move || {
+                writer.add_event((1, 1));
+                writer.commit();
+                writer.add_event((1, 2));
+                writer.commit();
+            });
+            // Only ever sees a committed value.
+            let value = reader.get(1, |v| v.copied());
+            assert!(matches!(value, None | Some(1) | Some(2)));
+            writer.join().unwrap();
+            assert_eq!(Some(2), reader.get(1, |v| v.copied()));
+        });
+    }
+}
@@ -0,0 +1,563 @@
+//! An ordered map that many threads can read without a lock while a few threads change it.  The
+//! writers take a lock between themselves and link the nodes in with atomic pointers so the
+//! readers never wait.
+//!```text
+//! level 2  head ------------------------> 30 -----------------------> null
+//! level 1  head ---------> 10 ----------> 30 ---------> 50 ---------> null
+//! level 0  head -> 5 ----> 10 -> 20 ----> 30 -> 40 ---> 50 -> 60 ---> null
+//!```
+//! A node is linked in from the bottom level up and unlinked from the top level down so a reader
+//! always finds a node on level 0 if it can see it on a higher level.  The removed nodes and the
+//! replaced values are kept until no reader is in the map and then freed by the next writer.  A
+//! long running iterator holds off freeing them until it is dropped.
+//!
+//! The iterators are weakly consistent.  They return the keys in order without any repeats.  A key
+//! that is in the map the whole time the iterator runs is always returned.  A key that is added or
+//! removed while it runs may or may not be returned.
+use std::borrow::Borrow;
+use std::ops::{Bound, RangeBounds};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The max number of levels in the list.
+const MAX_LEVEL: usize = 24;
+
+struct Node<K, V> {
+    key: K,
+    value: AtomicPtr<V>,
+    next: Box<[AtomicPtr<Node<K, V>>]>,
+}
+
+impl<K, V> Node<K, V> {
+    fn new(key: K, value: V, height: usize) -> *mut Self {
+        Box::into_raw(Box::new(Node {
+            key,
+            value: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            next: (0..height).map(|_| AtomicPtr::new(ptr::null_mut())).collect(),
+        }))
+    }
+}
+
+/// The state only the writers change.
+struct WriterState<K, V> {
+    /// The state of the random number generator used for the heights.
+    seed: u64,
+    /// The nodes that have been unlinked but could still be read.
+    retired_nodes: Vec<*mut Node<K, V>>,
+    /// The values that have been replaced but could still be read.
+    retired_values: Vec<*mut V>,
+}
+
+impl<K, V> WriterState<K, V> {
+    /// Picks the height of a new node.  Each level is half as likely as the one below it.
+    fn random_height(&mut self) -> usize {
+        // xorshift64
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed = x;
+        ((x.trailing_ones() as usize) + 1).min(MAX_LEVEL)
+    }
+
+    /// Frees the nodes and values that have been retired.  Only safe to call once all of the
+    /// readers that could have seen them have left.
+    unsafe fn free_retired(&mut self) {
+        for node in self.retired_nodes.drain(..) {
+            let node = Box::from_raw(node);
+            drop(Box::from_raw(node.value.load(Ordering::Relaxed)));
+        }
+        for value in self.retired_values.drain(..) {
+            drop(Box::from_raw(value));
+        }
+    }
+}
+
+/// Keeps track of a reader being in the map so the nodes it could see aren't freed.
+struct ReadGuard<'a> {
+    readers: &'a AtomicUsize,
+}
+
+impl<'a> ReadGuard<'a> {
+    fn new(readers: &'a AtomicUsize) -> Self {
+        readers.fetch_add(1, Ordering::SeqCst);
+        // Pairs with the fence in reclaim so either the writer sees the reader or the reader sees
+        // the nodes unlinked.
+        fence(Ordering::SeqCst);
+        ReadGuard { readers }
+    }
+}
+
+impl<'a> Drop for ReadGuard<'a> {
+    fn drop(&mut self) {
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An ordered map for many readers and a few writers.
+pub struct ConcurrentSkipListMap<K, V> {
+    head: Box<[AtomicPtr<Node<K, V>>]>,
+    /// The number of readers currently in the map.
+    readers: AtomicUsize,
+    len: AtomicUsize,
+    writer: Mutex<WriterState<K, V>>,
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for ConcurrentSkipListMap<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for ConcurrentSkipListMap<K, V> {}
+
+impl<K: Ord, V> Default for ConcurrentSkipListMap<K, V> {
+    fn default() -> Self {
+        ConcurrentSkipListMap::new()
+    }
+}
+
+impl<K: Ord, V> ConcurrentSkipListMap<K, V> {
+    /// Creates an empty map.
+    pub fn new() -> Self {
+        ConcurrentSkipListMap {
+            head: (0..MAX_LEVEL)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            readers: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            writer: Mutex::new(WriterState {
+                seed: 0x2545_f491_4f6c_dd1d,
+                retired_nodes: Vec::new(),
+                retired_values: Vec::new(),
+            }),
+        }
+    }
+
+    /// The number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// True if there aren't any entries in the map.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the next pointer at a level.  A null node is the head.
+    #[inline]
+    fn next_of(&self, node: *mut Node<K, V>, level: usize) -> &AtomicPtr<Node<K, V>> {
+        if node.is_null() {
+            &self.head[level]
+        } else {
+            unsafe { &(*node).next[level] }
+        }
+    }
+
+    /// Finds the last node before the key on each level.  A null node is the head.
+    /// # Arguments
+    /// `key` - The key to search for.
+    fn find_preds<Q>(&self, key: &Q) -> [*mut Node<K, V>; MAX_LEVEL]
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut preds = [ptr::null_mut(); MAX_LEVEL];
+        let mut pred: *mut Node<K, V> = ptr::null_mut();
+        for level in (0..MAX_LEVEL).rev() {
+            loop {
+                let next = self.next_of(pred, level).load(Ordering::Acquire);
+                if !next.is_null() && unsafe { (*next).key.borrow() } < key {
+                    pred = next;
+                } else {
+                    break;
+                }
+            }
+            preds[level] = pred;
+        }
+        preds
+    }
+
+    /// Finds the last node with a key less than or equal to the key.
+    /// # Returns
+    /// Null if there isn't one.
+    fn find_floor<Q>(&self, key: &Q) -> *mut Node<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut pred: *mut Node<K, V> = ptr::null_mut();
+        for level in (0..MAX_LEVEL).rev() {
+            loop {
+                let next = self.next_of(pred, level).load(Ordering::Acquire);
+                if !next.is_null() && unsafe { (*next).key.borrow() } <= key {
+                    pred = next;
+                } else {
+                    break;
+                }
+            }
+        }
+        pred
+    }
+
+    /// Frees the retired nodes if there aren't any readers.
+    /// # Arguments
+    /// `state` - The state of the writers.
+    fn reclaim(&self, state: &mut WriterState<K, V>) {
+        if state.retired_nodes.is_empty() && state.retired_values.is_empty() {
+            return;
+        }
+        fence(Ordering::SeqCst);
+        if self.readers.load(Ordering::SeqCst) == 0 {
+            // Any reader that comes in now can't reach the retired nodes.
+            unsafe { state.free_retired() };
+        }
+    }
+
+    /// Adds an entry or replaces the value of an entry.
+    /// # Arguments
+    /// `key` - The key of the entry.
+    /// `value` - The value for the key.
+    /// # Returns
+    /// True if the key was already in the map.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let mut state = self.writer.lock().unwrap();
+        let preds = self.find_preds(&key);
+        let found = self.next_of(preds[0], 0).load(Ordering::Acquire);
+        let replaced = if !found.is_null() && unsafe { &(*found).key } == &key {
+            let old = unsafe {
+                (*found)
+                    .value
+                    .swap(Box::into_raw(Box::new(value)), Ordering::SeqCst)
+            };
+            state.retired_values.push(old);
+            true
+        } else {
+            let height = state.random_height();
+            let node = Node::new(key, value, height);
+            for (level, pred) in preds.iter().enumerate().take(height) {
+                let next = self.next_of(*pred, level).load(Ordering::Acquire);
+                unsafe { (*node).next[level].store(next, Ordering::Relaxed) };
+            }
+            // Bottom up so a node seen on a level is always on the levels below.
+            for (level, pred) in preds.iter().enumerate().take(height) {
+                self.next_of(*pred, level).store(node, Ordering::SeqCst);
+            }
+            self.len.fetch_add(1, Ordering::AcqRel);
+            false
+        };
+        self.reclaim(&mut state);
+        replaced
+    }
+
+    /// Removes an entry.
+    /// # Arguments
+    /// `key` - The key of the entry to remove.
+    /// # Returns
+    /// True if the key was in the map.
+    pub fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut state = self.writer.lock().unwrap();
+        let preds = self.find_preds(key);
+        let found = self.next_of(preds[0], 0).load(Ordering::Acquire);
+        let removed = if !found.is_null() && unsafe { (*found).key.borrow() } == key {
+            let node = unsafe { &*found };
+            // Top down so a reader on the node can still follow its pointers.
+            for level in (0..node.next.len()).rev() {
+                let next = node.next[level].load(Ordering::Acquire);
+                self.next_of(preds[level], level).store(next, Ordering::SeqCst);
+            }
+            state.retired_nodes.push(found);
+            self.len.fetch_sub(1, Ordering::AcqRel);
+            true
+        } else {
+            false
+        };
+        self.reclaim(&mut state);
+        removed
+    }
+
+    /// Checks to see if a key is in the map.
+    /// # Arguments
+    /// `key` - The key to look for.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let _guard = ReadGuard::new(&self.readers);
+        let node = self.find_floor(key);
+        !node.is_null() && unsafe { (*node).key.borrow() } == key
+    }
+
+    /// Gets a copy of the value for a key.
+    /// # Arguments
+    /// `key` - The key to look for.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        let _guard = ReadGuard::new(&self.readers);
+        let node = self.find_floor(key);
+        if !node.is_null() && unsafe { (*node).key.borrow() } == key {
+            Some(unsafe { (*(*node).value.load(Ordering::Acquire)).clone() })
+        } else {
+            None
+        }
+    }
+
+    /// Gets the entry with the greatest key less than or equal to the key.
+    /// # Arguments
+    /// `key` - The key to search for.
+    pub fn floor_entry<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        let _guard = ReadGuard::new(&self.readers);
+        let node = self.find_floor(key);
+        if node.is_null() {
+            None
+        } else {
+            unsafe {
+                Some((
+                    (*node).key.clone(),
+                    (*(*node).value.load(Ordering::Acquire)).clone(),
+                ))
+            }
+        }
+    }
+
+    /// Iterates through the entries with the keys in the range.
+    /// # Arguments
+    /// `range` - The range of the keys to return.
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V, Q, R>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let guard = ReadGuard::new(&self.readers);
+        let next = match range.start_bound() {
+            Bound::Included(start) => self.next_of(self.find_preds(start)[0], 0),
+            Bound::Excluded(start) => self.next_of(self.find_floor(start), 0),
+            Bound::Unbounded => &self.head[0],
+        }
+        .load(Ordering::Acquire);
+        Range {
+            _guard: guard,
+            next,
+            range,
+            _key: std::marker::PhantomData,
+        }
+    }
+
+    /// Iterates through all of the entries.
+    pub fn iter(&self) -> Range<'_, K, V, K, (Bound<K>, Bound<K>)> {
+        self.range((Bound::Unbounded, Bound::Unbounded))
+    }
+}
+
+impl<K, V> Drop for ConcurrentSkipListMap<K, V> {
+    fn drop(&mut self) {
+        let state = self.writer.get_mut().unwrap();
+        unsafe {
+            state.free_retired();
+            let mut node = self.head[0].load(Ordering::Relaxed);
+            while !node.is_null() {
+                let next = (*node).next[0].load(Ordering::Relaxed);
+                let owned = Box::from_raw(node);
+                drop(Box::from_raw(owned.value.load(Ordering::Relaxed)));
+                node = next;
+            }
+        }
+    }
+}
+
+/// Iterates through a range of the entries in the map.  The removed entries aren't freed until it
+/// is dropped.
+pub struct Range<'a, K, V, Q: ?Sized, R> {
+    _guard: ReadGuard<'a>,
+    next: *mut Node<K, V>,
+    range: R,
+    _key: std::marker::PhantomData<&'a Q>,
+}
+
+impl<'a, K, V, Q, R> Iterator for Range<'a, K, V, Q, R>
+where
+    K: Borrow<Q> + Clone,
+    V: Clone,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        if self.next.is_null() {
+            return None;
+        }
+        let node = unsafe { &*self.next };
+        let in_range = match self.range.end_bound() {
+            Bound::Included(end) => node.key.borrow() <= end,
+            Bound::Excluded(end) => node.key.borrow() < end,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            self.next = ptr::null_mut();
+            return None;
+        }
+        self.next = node.next[0].load(Ordering::Acquire);
+        Some((node.key.clone(), unsafe {
+            (*node.value.load(Ordering::Acquire)).clone()
+        }))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::map::skip_list::*;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    pub fn map_test() {
+        let map = ConcurrentSkipListMap::new();
+        assert!(map.is_empty());
+        assert_eq!(None, map.floor_entry(&10u64));
+        for key in (10..=100u64).step_by(10) {
+            assert!(!map.insert(key, key * 2));
+        }
+        assert_eq!(10, map.len());
+        assert!(map.insert(50, 0));
+        assert_eq!(Some(0), map.get(&50));
+        assert_eq!(None, map.get(&55));
+        assert_eq!(Some((50, 0)), map.floor_entry(&55));
+        assert_eq!(Some((100, 200)), map.floor_entry(&1000));
+        assert_eq!(None, map.floor_entry(&9));
+        assert_eq!(
+            vec![(20, 40), (30, 60), (40, 80)],
+            map.range(20..50).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(90, 180), (100, 200)],
+            map.range((Bound::Excluded(80), Bound::Unbounded))
+                .collect::<Vec<_>>()
+        );
+        assert!(map.remove(&50));
+        assert!(!map.remove(&50));
+        assert!(!map.contains_key(&50));
+        assert_eq!(Some((40, 80)), map.floor_entry(&55));
+        assert_eq!(9, map.iter().count());
+    }
+
+    #[test]
+    pub fn concurrent_floor_test() {
+        let map = Arc::new(ConcurrentSkipListMap::new());
+        let done = Arc::new(AtomicBool::new(false));
+        // Only multiples of 3 are added so the floor of any key is known.
+        let writers: Vec<_> = (0..2u64)
+            .map(|w| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..20_000u64 {
+                        let key = (i * 2 + w) * 3;
+                        map.insert(key, key);
+                        // Remove some of them again.
+                        if i % 5 == 0 {
+                            map.remove(&key);
+                        }
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4u64)
+            .map(|r| {
+                let map = map.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut key = r;
+                    while !done.load(Ordering::Acquire) {
+                        key = (key * 7919 + 13) % 120_000;
+                        if let Some((floor, value)) = map.floor_entry(&key) {
+                            assert!(floor <= key);
+                            assert_eq!(0, floor % 3);
+                            assert_eq!(floor, value);
+                        }
+                        let mut last = None;
+                        for (k, v) in map.range(key..key + 300) {
+                            assert_eq!(k, v);
+                            assert!(last.map(|l| l < k).unwrap_or(true));
+                            last = Some(k);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // Matches what a BTreeMap would have.
+        let mut expected = BTreeMap::new();
+        for w in 0..2u64 {
+            for i in 0..20_000u64 {
+                if i % 5 != 0 {
+                    let key = (i * 2 + w) * 3;
+                    expected.insert(key, key);
+                }
+            }
+        }
+        assert_eq!(expected.len(), map.len());
+        assert_eq!(
+            expected.clone().into_iter().collect::<Vec<_>>(),
+            map.iter().collect::<Vec<_>>()
+        );
+        for key in (0..120_000u64).step_by(7) {
+            assert_eq!(
+                expected.range(..=key).next_back().map(|(k, v)| (*k, *v)),
+                map.floor_entry(&key)
+            );
+        }
+    }
+
+    #[test]
+    pub fn iterator_consistency_test() {
+        let map = Arc::new(ConcurrentSkipListMap::new());
+        // The even keys stay the whole time.
+        for key in (0..10_000u64).step_by(2) {
+            map.insert(key, key);
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let map = map.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut i = 0u64;
+                while !done.load(Ordering::Acquire) {
+                    let key = (i % 5000) * 2 + 1;
+                    if !map.insert(key, key) {
+                        map.remove(&key);
+                    }
+                    i += 1;
+                }
+            })
+        };
+        for _ in 0..50 {
+            let keys: Vec<u64> = map.iter().map(|(k, _)| k).collect();
+            assert!(keys.windows(2).all(|w| w[0] < w[1]));
+            let even: Vec<u64> = keys.into_iter().filter(|k| k % 2 == 0).collect();
+            assert_eq!((0..10_000u64).step_by(2).collect::<Vec<_>>(), even);
+        }
+        done.store(true, Ordering::Release);
+        writer.join().unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use a19_core::cache_padded::CachePadded;
 use std::sync::atomic::AtomicUsize;
 
 pub mod mpmc_queue;
@@ -5,21 +6,9 @@ pub mod mpsc_queue;
 pub mod skip_queue;
 pub mod spsc_queue;
 
-pub struct PaddedUsize {
-    // Make sure we are on one cache line.
-    #[allow(dead_code)]
-    padding: [usize; 15],
-    pub counter: AtomicUsize,
-}
-
-impl PaddedUsize {
-    pub fn new(initial_value: usize) -> Self {
-        PaddedUsize {
-            padding: [0; 15],
-            counter: AtomicUsize::new(initial_value),
-        }
-    }
-}
+/// A counter on its own cache line.
+#[deprecated(note = "Use CachePadded<AtomicUsize> instead.")]
+pub type PaddedUsize = CachePadded<AtomicUsize>;
 
 pub trait ConcurrentQueue<T> {
     /// Used to poll the queue and moves the value to the option if there is a value.
@@ -1,9 +1,11 @@
-use crate::queue::{ConcurrentQueue, PaddedUsize};
+use crate::queue::ConcurrentQueue;
+use a19_core::cache_padded::CachePadded;
 use a19_core::pow2::PowOf2;
 use std::cell::UnsafeCell;
 use std::mem::replace;
+use crate::wait::{yielding, WaitStrategy};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::thread;
+use std::sync::Arc;
 use std::vec::Vec;
 
 struct MpmcNode<T> {
@@ -20,7 +22,16 @@ unsafe impl<T> Send for MpmcQueueWrap<T> {}
 
 impl<T> MpmcQueueWrap<T> {
     pub fn new(queue_size: usize) -> Self {
-        let queue = UnsafeCell::new(MpmcQueue::new(queue_size));
+        MpmcQueueWrap::with_wait_strategy(queue_size, yielding())
+    }
+
+    /// Creates a queue that uses a wait strategy while waiting on the other threads.  The strategy
+    /// is signaled each time a value is added so a parked consumer wakes up.
+    /// # Arguments
+    /// `queue_size` - The size of the queue.  Gets rounded up to a power of 2.
+    /// `wait` - How to wait for a slot to be ready.
+    pub fn with_wait_strategy(queue_size: usize, wait: Arc<dyn WaitStrategy>) -> Self {
+        let queue = UnsafeCell::new(MpmcQueue::new(queue_size, wait));
         MpmcQueueWrap { queue }
     }
 
@@ -50,25 +61,22 @@ struct MpmcQueue<T> {
     mask: usize,
     ring_buffer: Vec<MpmcNode<T>>,
     capacity: usize,
-    sequence_number: PaddedUsize,
-    producer: PaddedUsize,
+    sequence_number: CachePadded<AtomicUsize>,
+    producer: CachePadded<AtomicUsize>,
+    /// How to wait on the other threads.
+    wait: Arc<dyn WaitStrategy>,
 }
 
 impl<T> MpmcQueue<T> {
-    fn new(queue_size: usize) -> Self {
+    fn new(queue_size: usize, wait: Arc<dyn WaitStrategy>) -> Self {
         let power_of_2 = queue_size.round_to_power_of_two();
         let mut queue = MpmcQueue {
             ring_buffer: Vec::with_capacity(power_of_2),
             capacity: power_of_2,
             mask: power_of_2 - 1,
-            sequence_number: PaddedUsize {
-                padding: [0; 15],
-                counter: AtomicUsize::new(1),
-            },
-            producer: PaddedUsize {
-                padding: [0; 15],
-                counter: AtomicUsize::new(1),
-            },
+            sequence_number: CachePadded::new(AtomicUsize::new(1)),
+            producer: CachePadded::new(AtomicUsize::new(1)),
+            wait,
         };
         for _ in 0..power_of_2 {
             let node = MpmcNode {
@@ -92,9 +100,10 @@ unsafe impl<T> Send for MpmcQueue<T> {}
 impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
     /// Used to poll the queue and moves the value to the option if there is a value.
     fn poll(&mut self) -> Option<T> {
+        let mut attempt = 0;
         loop {
-            let s_index = self.sequence_number.counter.load(Ordering::Relaxed);
-            let p_index = self.producer.counter.load(Ordering::Relaxed);
+            let s_index = self.sequence_number.load(Ordering::Relaxed);
+            let p_index = self.producer.load(Ordering::Relaxed);
             if p_index > s_index {
                 unsafe {
                     let last_pos = self.pos(s_index);
@@ -103,7 +112,7 @@ impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
                     // Verify the node id matches the index id.
                     if node_id == s_index {
                         // Try and claim the slot.
-                        if self.sequence_number.counter.compare_exchange_weak(
+                        if self.sequence_number.compare_exchange_weak(
                             s_index,
                             s_index + 1,
                             Ordering::Acquire,
@@ -114,7 +123,8 @@ impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
                             break v;
                         }
                     } else {
-                        thread::yield_now()
+                        self.wait.wait(attempt);
+                        attempt += 1;
                     }
                 }
             } else {
@@ -130,15 +140,15 @@ impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
     /// The number of items that where returned.
     fn drain(&mut self, act: fn(T), limit: usize) -> usize {
         loop {
-            let p_index = self.producer.counter.load(Ordering::Relaxed);
-            let s_index = self.sequence_number.counter.load(Ordering::Relaxed);
+            let p_index = self.producer.load(Ordering::Relaxed);
+            let s_index = self.sequence_number.load(Ordering::Relaxed);
             if p_index <= s_index {
                 break 0;
             } else {
                 let elements_left = p_index - s_index;
                 let request = limit.min(elements_left);
                 // Have to do this a little bit different.
-                if self.sequence_number.counter.compare_exchange_weak(
+                if self.sequence_number.compare_exchange_weak(
                     s_index,
                     s_index + request,
                     Ordering::Acquire,
@@ -147,6 +157,7 @@ impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
                     for i in 0..request {
                         let pos = self.pos(s_index + i);
                         let node = unsafe { self.ring_buffer.get_unchecked_mut(pos) };
+                        let mut attempt = 0;
                         loop {
                             let node_id = node.id.load(Ordering::Acquire);
                             if node_id == s_index + i {
@@ -159,7 +170,8 @@ impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
                                 }
                                 break;
                             } else {
-                                thread::yield_now();
+                                self.wait.wait(attempt);
+                                attempt += 1;
                             }
                         }
                     }
@@ -174,14 +186,15 @@ impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
     /// `value` - The vale to add to the queue.
     fn offer(&mut self, value: T) -> bool {
         let capacity = self.capacity;
+        let mut attempt = 0;
         loop {
-            let p_index = self.producer.counter.load(Ordering::Relaxed);
-            let c_index = self.sequence_number.counter.load(Ordering::Relaxed);
+            let p_index = self.producer.load(Ordering::Relaxed);
+            let c_index = self.sequence_number.load(Ordering::Relaxed);
             if p_index < capacity || p_index - capacity < c_index {
                 let pos = self.pos(p_index);
                 let mut node = unsafe { self.ring_buffer.get_unchecked_mut(pos) };
                 if node.id.load(Ordering::Acquire) == 0 {
-                    if self.producer.counter.compare_exchange_weak(
+                    if self.producer.compare_exchange_weak(
                         p_index,
                         p_index + 1,
                         Ordering::Acquire,
@@ -190,10 +203,12 @@ impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
                         node.value = Some(value);
                         // Need a Store/Store barrier to make sure this is done last.
                         node.id.store(p_index, Ordering::Release);
+                        self.wait.signal();
                         break true;
                     }
                 } else {
-                    thread::yield_now();
+                    self.wait.wait(attempt);
+                    attempt += 1;
                 }
             } else {
                 break false;
@@ -202,11 +217,12 @@ impl<T> ConcurrentQueue<T> for MpmcQueue<T> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
 
     use crate::queue::mpmc_queue::{MpmcQueue, MpmcQueueWrap};
     use crate::queue::ConcurrentQueue;
+    use crate::wait::yielding;
     use std::sync::Arc;
     use std::thread;
     use std::vec::Vec;
@@ -214,7 +230,7 @@ mod tests {
 
     #[test]
     pub fn create_queue_test() {
-        let mut queue: MpmcQueue<u64> = MpmcQueue::new(128);
+        let mut queue: MpmcQueue<u64> = MpmcQueue::new(128, yielding());
         assert_eq!(128, queue.ring_buffer.len());
 
         queue.offer(1);
@@ -1,15 +1,15 @@
-use crate::queue::{ConcurrentQueue, PaddedUsize};
+use crate::queue::ConcurrentQueue;
+use a19_core::cache_padded::CachePadded;
 use a19_core::pow2::PowOf2;
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::Arc;
+use crate::wait::{yielding, WaitStrategy};
 use std::cell::UnsafeCell;
-use std::mem::replace;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::thread;
 use std::vec::Vec;
 
 pub struct MpscNode<T> {
     id: AtomicUsize,
-    value: Option<T>,
+    value: crate::sync::UnsafeCell<Option<T>>,
 }
 
 pub struct MpscQueueWrap<T> {
@@ -26,7 +26,19 @@ unsafe impl<T> Send for MpscQueueWrap<T> {}
 
 impl<T> MpscQueueWrap<T> {
     pub fn new(queue_size: usize) -> (MpscQueueWrap<T>, MpscQueueReceive<T>) {
-        let queue = Arc::new(UnsafeCell::new(MpscQueue::new(queue_size)));
+        MpscQueueWrap::with_wait_strategy(queue_size, yielding())
+    }
+
+    /// Creates a queue that uses a wait strategy while waiting on the other side.  The strategy is
+    /// signaled each time a value is added so a parked consumer wakes up.
+    /// # Arguments
+    /// `queue_size` - The size of the queue.  Gets rounded up to a power of 2.
+    /// `wait` - How to wait for a slot to be ready.
+    pub fn with_wait_strategy(
+        queue_size: usize,
+        wait: std::sync::Arc<dyn WaitStrategy>,
+    ) -> (MpscQueueWrap<T>, MpscQueueReceive<T>) {
+        let queue = Arc::new(UnsafeCell::new(MpscQueue::new(queue_size, wait)));
         let send_queue = queue.clone();
         (
             MpscQueueWrap { queue },
@@ -40,6 +52,18 @@ impl<T> MpscQueueWrap<T> {
             queue.offer(v)
         }
     }
+
+    /// Offers a value to the queue without dropping it when the queue is full.
+    /// # Arguments
+    /// `v` - The value to add.
+    /// # Returns
+    /// The value back if there wasn't room for it.
+    pub fn try_offer(&self, v: T) -> Result<(), T> {
+        unsafe {
+            let queue = &mut *self.queue.get();
+            queue.try_offer(v)
+        }
+    }
 }
 
 unsafe impl<T> Send for MpscQueueReceive<T> {}
@@ -71,33 +95,30 @@ struct MpscQueue<T> {
     mask: usize,
     ring_buffer: Vec<MpscNode<T>>,
     capacity: usize,
-    sequence_number: PaddedUsize,
-    producer: PaddedUsize,
+    sequence_number: CachePadded<AtomicUsize>,
+    producer: CachePadded<AtomicUsize>,
+    /// How to wait on the other threads.
+    wait: std::sync::Arc<dyn WaitStrategy>,
 }
 
 unsafe impl<T> Send for MpscQueue<T> {}
 unsafe impl<T> Sync for MpscQueue<T> {}
 
 impl<T> MpscQueue<T> {
-    fn new(queue_size: usize) -> Self {
+    fn new(queue_size: usize, wait: std::sync::Arc<dyn WaitStrategy>) -> Self {
         let power_of_2 = queue_size.round_to_power_of_two();
         let mut queue = MpscQueue {
             ring_buffer: Vec::with_capacity(power_of_2),
             capacity: power_of_2,
             mask: power_of_2 - 1,
-            sequence_number: PaddedUsize {
-                padding: [0; 15],
-                counter: AtomicUsize::new(1),
-            },
-            producer: PaddedUsize {
-                padding: [0; 15],
-                counter: AtomicUsize::new(1),
-            },
+            sequence_number: CachePadded::new(AtomicUsize::new(1)),
+            producer: CachePadded::new(AtomicUsize::new(1)),
+            wait,
         };
         for _ in 0..power_of_2 {
             let node = MpscNode {
                 id: AtomicUsize::new(0),
-                value: None,
+                value: crate::sync::UnsafeCell::new(None),
             };
             queue.ring_buffer.push(node);
         }
@@ -113,50 +134,88 @@ impl<T> MpscQueue<T> {
     /// # Returns
     /// Either None if no value i there or some with a reference to the value.
     fn peek(&'_ self) -> Option<&'_ T> {
-        let s_index = self.sequence_number.counter.load(Ordering::Relaxed);
-        let p_index = self.producer.counter.load(Ordering::Relaxed);
+        let s_index = self.sequence_number.load(Ordering::Relaxed);
+        let p_index = self.producer.load(Ordering::Relaxed);
         if p_index > s_index {
             let last_pos = self.pos(s_index);
             let node = unsafe { self.ring_buffer.get_unchecked(last_pos) };
+            let mut attempt = 0;
             loop {
                 // Since we are looping can use relaxed ordering.
                 let node_id = node.id.load(Ordering::Acquire);
                 // Verify the node id matches the index id.
                 if node_id == s_index {
-                    if let Some(value) = &node.value {
+                    if let Some(value) = node.value.with(|v| unsafe { (*v).as_ref() }) {
                         break Some(value)
                     }
                 } else {
-                    thread::yield_now()
+                    self.wait.wait(attempt);
+                    attempt += 1;
                 }
             }
         } else {
             None
         }
     }
+
+    /// Offers a value to the queue.  Gives the value back when the queue is full.
+    /// # Arguments
+    /// `value` - The vale to add to the queue.
+    fn try_offer(&mut self, value: T) -> Result<(), T> {
+        let capacity = self.capacity;
+        let mut attempt = 0;
+        loop {
+            let p_index = self.producer.load(Ordering::Relaxed);
+            let c_index = self.sequence_number.load(Ordering::Relaxed);
+            if p_index < capacity || p_index - capacity < c_index {
+                let pos = self.pos(p_index);
+                let node = unsafe { self.ring_buffer.get_unchecked(pos) };
+                // since we are looping don't care if the value is stale since we will eventually get the correct value.
+                if node.id.load(Ordering::Acquire) == 0 {
+                    if self.producer.compare_exchange_weak(
+                        p_index,
+                        p_index + 1,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ).is_ok() {
+                        node.value.with_mut(|v| unsafe { *v = Some(value) });
+                        // Need a StoreStore barrier to prevent reordering of the op above.
+                        node.id.store(p_index, Ordering::Release);
+                        self.wait.signal();
+                        break Ok(());
+                    }
+                } else {
+                    self.wait.wait(attempt);
+                    attempt += 1;
+                }
+            } else {
+                break Err(value);
+            }
+        }
+    }
 }
 
 impl<T> ConcurrentQueue<T> for MpscQueue<T> {
     /// Used to poll the queue and moves the value to the option if there is a value.
     fn poll(&mut self) -> Option<T> {
-        let s_index = self.sequence_number.counter.load(Ordering::Relaxed);
-        let p_index = self.producer.counter.load(Ordering::Relaxed);
+        let s_index = self.sequence_number.load(Ordering::Relaxed);
+        let p_index = self.producer.load(Ordering::Relaxed);
         if p_index > s_index {
             let last_pos = self.pos(s_index);
             let node = unsafe { self.ring_buffer.get_unchecked_mut(last_pos) };
+            let mut attempt = 0;
             loop {
                 let node_id = node.id.load(Ordering::Acquire);
                 // Verify the node id matches the index id.
                 if node_id == s_index {
-                    self.sequence_number
-                        .counter
-                        .store(s_index + 1, Ordering::Relaxed);
-                    let v = replace(&mut node.value, Option::None);
+                    self.sequence_number.store(s_index + 1, Ordering::Relaxed);
+                    let v = node.value.with_mut(|v| unsafe { (*v).take() });
                     // Need a StoreStore barrier so this operation goes last.
                     node.id.store(0, Ordering::Release);
                     break v;
                 } else {
-                    thread::yield_now();
+                    self.wait.wait(attempt);
+                    attempt += 1;
                     /*
                     i = i + 1;
                     if i > 1_000_000_000 {
@@ -179,24 +238,23 @@ impl<T> ConcurrentQueue<T> for MpscQueue<T> {
     /// # Returns
     /// The number of items that where returned.
     fn drain(&mut self, act: fn(T), limit: usize) -> usize {
-        let p_index = self.producer.counter.load(Ordering::Relaxed);
-        let s_index = self.sequence_number.counter.load(Ordering::Relaxed);
+        let p_index = self.producer.load(Ordering::Relaxed);
+        let s_index = self.sequence_number.load(Ordering::Relaxed);
         if p_index <= s_index {
             0
         } else {
             let elements_left = p_index - s_index;
             let request = limit.min(elements_left);
             // Have to do this a little bit different.
-            self.sequence_number
-                .counter
-                .store(s_index + request, Ordering::Relaxed);
+            self.sequence_number.store(s_index + request, Ordering::Relaxed);
             for i in 0..request {
+                let mut attempt = 0;
                 loop {
                     let pos = self.pos(s_index + i);
                     let node = unsafe { self.ring_buffer.get_unchecked_mut(pos) };
                     let node_id = node.id.load(Ordering::Acquire);
                     if node_id == s_index + i {
-                        let v = replace(&mut node.value, Option::None);
+                        let v = node.value.with_mut(|v| unsafe { (*v).take() });
                         // Need a StoreStore barrier since we need this done last.
                         node.id.store(0, Ordering::Release);
                         match v {
@@ -205,7 +263,8 @@ impl<T> ConcurrentQueue<T> for MpscQueue<T> {
                         }
                         break;
                     } else {
-                        thread::yield_now();
+                        self.wait.wait(attempt);
+                        attempt += 1;
                     }
                 }
             }
@@ -217,41 +276,16 @@ impl<T> ConcurrentQueue<T> for MpscQueue<T> {
     /// # Arguments
     /// `value` - The vale to add to the queue.
     fn offer(&mut self, value: T) -> bool {
-        let capacity = self.capacity;
-        loop {
-            let p_index = self.producer.counter.load(Ordering::Relaxed);
-            let c_index = self.sequence_number.counter.load(Ordering::Relaxed);
-            if p_index < capacity || p_index - capacity < c_index {
-                let pos = self.pos(p_index);
-                let mut node = unsafe { self.ring_buffer.get_unchecked_mut(pos) };
-                // since we are looping don't care if the value is stale since we will eventually get the correct value.
-                if node.id.load(Ordering::Acquire) == 0 {
-                    if self.producer.counter.compare_exchange_weak(
-                        p_index,
-                        p_index + 1,
-                        Ordering::Relaxed,
-                        Ordering::Relaxed,
-                    ).is_ok() {
-                        node.value = Some(value);
-                        // Need a StoreStore barrier to prevent reordering of the op above.
-                        node.id.store(p_index, Ordering::Release);
-                        break true;
-                    }
-                } else {
-                    thread::yield_now();
-                }
-            } else {
-                break false;
-            }
-        }
+        self.try_offer(value).is_ok()
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
 
     use crate::queue::mpsc_queue::{MpscQueue, MpscQueueWrap};
     use crate::queue::ConcurrentQueue;
+    use crate::wait::yielding;
     use std::sync::Arc;
     use std::thread;
     use std::vec::Vec;
@@ -259,7 +293,7 @@ mod tests {
 
     #[test]
     pub fn create_queue_test() {
-        let mut queue: MpscQueue<u64> = MpscQueue::new(128);
+        let mut queue: MpscQueue<u64> = MpscQueue::new(128, yielding());
         assert_eq!(128, queue.ring_buffer.len());
 
         queue.offer(1);
@@ -359,3 +393,41 @@ mod tests {
         }
     }
 }
+
+#[cfg(loom)]
+mod loom_tests {
+
+    use crate::queue::mpsc_queue::MpscQueueWrap;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    pub fn loom_offer_poll_test() {
+        // Two producers and a spinning consumer has too many interleavings to check all of them.
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let (write, read) = MpscQueueWrap::<usize>::new(2);
+            let write = Arc::new(write);
+            let producers: Vec<_> = (1..3)
+                .map(|i| {
+                    let write = write.clone();
+                    thread::spawn(move || assert!(write.offer(i)))
+                })
+                .collect();
+            let mut values = Vec::with_capacity(2);
+            while values.len() < 2 {
+                match read.poll() {
+                    Some(v) => values.push(v),
+                    None => thread::yield_now(),
+                }
+            }
+            for p in producers {
+                p.join().unwrap();
+            }
+            values.sort_unstable();
+            assert_eq!(vec![1, 2], values);
+            assert_eq!(None, read.poll());
+        });
+    }
+}
@@ -1,15 +1,15 @@
-use crate::queue::{ConcurrentQueue, PaddedUsize};
+use crate::queue::ConcurrentQueue;
+use a19_core::cache_padded::CachePadded;
 use a19_core::pow2::PowOf2;
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::Arc;
+use crate::wait::{yielding, WaitStrategy};
 use std::cell::UnsafeCell;
-use std::mem::replace;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::thread;
 use std::vec::Vec;
 
 pub struct SpscNode<T> {
     id: AtomicUsize,
-    value: Option<T>,
+    value: crate::sync::UnsafeCell<Option<T>>,
 }
 
 pub struct SpscQueueSendWrap<T> {
@@ -20,7 +20,19 @@ unsafe impl<T> Send for SpscQueueSendWrap<T> {}
 
 impl<T> SpscQueueSendWrap<T> {
     pub fn new(queue_size: usize) -> (SpscQueueSendWrap<T>, SpscQueueReceiveWrap<T>) {
-        let queue = Arc::new(UnsafeCell::new(SpscQueue::new(queue_size)));
+        SpscQueueSendWrap::with_wait_strategy(queue_size, yielding())
+    }
+
+    /// Creates a queue that uses a wait strategy while waiting on the other side.  The strategy is
+    /// signaled each time a value is added so a parked consumer wakes up.
+    /// # Arguments
+    /// `queue_size` - The size of the queue.  Gets rounded up to a power of 2.
+    /// `wait` - How to wait for a slot to be ready.
+    pub fn with_wait_strategy(
+        queue_size: usize,
+        wait: std::sync::Arc<dyn WaitStrategy>,
+    ) -> (SpscQueueSendWrap<T>, SpscQueueReceiveWrap<T>) {
+        let queue = Arc::new(UnsafeCell::new(SpscQueue::new(queue_size, wait)));
         let send_queue = queue.clone();
         (
             SpscQueueSendWrap { queue: send_queue },
@@ -67,33 +79,30 @@ struct SpscQueue<T> {
     mask: usize,
     ring_buffer: Vec<SpscNode<T>>,
     capacity: usize,
-    sequence_number: PaddedUsize,
-    producer: PaddedUsize,
+    sequence_number: CachePadded<AtomicUsize>,
+    producer: CachePadded<AtomicUsize>,
+    /// How to wait on the other threads.
+    wait: std::sync::Arc<dyn WaitStrategy>,
 }
 
 unsafe impl<T> Send for SpscQueue<T> {}
 unsafe impl<T> Sync for SpscQueue<T> {}
 
 impl<T> SpscQueue<T> {
-    fn new(queue_size: usize) -> Self {
+    fn new(queue_size: usize, wait: std::sync::Arc<dyn WaitStrategy>) -> Self {
         let power_of_2 = queue_size.round_to_power_of_two();
         let mut queue = SpscQueue {
             ring_buffer: Vec::with_capacity(power_of_2),
             capacity: power_of_2,
             mask: power_of_2 - 1,
-            sequence_number: PaddedUsize {
-                padding: [0; 15],
-                counter: AtomicUsize::new(1),
-            },
-            producer: PaddedUsize {
-                padding: [0; 15],
-                counter: AtomicUsize::new(1),
-            },
+            sequence_number: CachePadded::new(AtomicUsize::new(1)),
+            producer: CachePadded::new(AtomicUsize::new(1)),
+            wait,
         };
         for _ in 0..power_of_2 {
             let node = SpscNode {
                 id: AtomicUsize::new(0),
-                value: None,
+                value: crate::sync::UnsafeCell::new(None),
             };
             queue.ring_buffer.push(node);
         }
@@ -107,18 +116,15 @@ impl<T> SpscQueue<T> {
     }
 
     fn peek(&'_ self) -> Option<&'_ T> {
-        let s_index = self.sequence_number.counter.load(Ordering::Relaxed);
-        let p_index = self.producer.counter.load(Ordering::Relaxed);
+        let s_index = self.sequence_number.load(Ordering::Relaxed);
+        let p_index = self.producer.load(Ordering::Relaxed);
         if p_index > s_index {
             let last_pos = self.pos(s_index);
             let node = unsafe { self.ring_buffer.get_unchecked(last_pos) };
             let node_id = node.id.load(Ordering::Acquire);
             // Verify the node id matches the index id.
             if node_id == s_index {
-                match &node.value {
-                    Some(value) => Some(value),
-                    None => None,
-                }
+                node.value.with(|v| unsafe { (*v).as_ref() })
             } else {
                 None
             }
@@ -131,9 +137,10 @@ impl<T> SpscQueue<T> {
 impl<T> ConcurrentQueue<T> for SpscQueue<T> {
     /// Used to poll the queue and moves the value to the option if there is a value.
     fn poll(&mut self) -> Option<T> {
+        let mut attempt = 0;
         loop {
-            let s_index = self.sequence_number.counter.load(Ordering::Relaxed);
-            let p_index = self.producer.counter.load(Ordering::Relaxed);
+            let s_index = self.sequence_number.load(Ordering::Relaxed);
+            let p_index = self.producer.load(Ordering::Relaxed);
             if p_index > s_index {
                 unsafe {
                     let last_pos = self.pos(s_index);
@@ -142,14 +149,15 @@ impl<T> ConcurrentQueue<T> for SpscQueue<T> {
                     // Verify the node id matches the index id.
                     if node_id == s_index {
                         // Try and claim the slot.
-                        self.sequence_number
-                            .counter
-                            .store(s_index + 1, Ordering::Relaxed);
-                        let v = replace(&mut node.value, Option::None);
-                        node.id.store(0, Ordering::Relaxed);
+                        self.sequence_number.store(s_index + 1, Ordering::Relaxed);
+                        let v = node.value.with_mut(|v| (*v).take());
+                        // Release so the producer doesn't write the slot until we are done with it.
+                        node.id.store(0, Ordering::Release);
                         break v;
                     } else {
                         // Go around again.
+                        self.wait.wait(attempt);
+                        attempt += 1;
                     }
                 }
             } else {
@@ -164,32 +172,32 @@ impl<T> ConcurrentQueue<T> for SpscQueue<T> {
     /// # Returns
     /// The number of items that where returned.
     fn drain(&mut self, act: fn(T), limit: usize) -> usize {
-        let p_index = self.producer.counter.load(Ordering::Relaxed);
-        let s_index = self.sequence_number.counter.load(Ordering::Relaxed);
+        let p_index = self.producer.load(Ordering::Relaxed);
+        let s_index = self.sequence_number.load(Ordering::Relaxed);
         if p_index <= s_index {
             0
         } else {
             let elements_left = p_index - s_index;
             let request = limit.min(elements_left);
             // Have to do this a little bit different.
-            self.sequence_number
-                .counter
-                .store(s_index + request, Ordering::Relaxed);
+            self.sequence_number.store(s_index + request, Ordering::Relaxed);
             for i in 0..request {
+                let mut attempt = 0;
                 loop {
                     let pos = self.pos(s_index + i);
                     let node = unsafe { self.ring_buffer.get_unchecked_mut(pos) };
                     let node_id = node.id.load(Ordering::Acquire);
                     if node_id == s_index + i {
-                        let v = replace(&mut node.value, Option::None);
-                        node.id.store(0, Ordering::Relaxed);
+                        let v = node.value.with_mut(|v| unsafe { (*v).take() });
+                        node.id.store(0, Ordering::Release);
                         match v {
                             None => panic!("Found a None!"),
                             Some(t_value) => act(t_value),
                         }
                         break;
                     } else {
-                        thread::yield_now();
+                        self.wait.wait(attempt);
+                        attempt += 1;
                     }
                 }
             }
@@ -202,19 +210,23 @@ impl<T> ConcurrentQueue<T> for SpscQueue<T> {
     /// `value` - The vale to add to the queue.
     fn offer(&mut self, value: T) -> bool {
         let capacity = self.capacity;
+        let mut attempt = 0;
         loop {
-            let p_index = self.producer.counter.load(Ordering::Relaxed);
-            let c_index = self.sequence_number.counter.load(Ordering::Relaxed);
+            let p_index = self.producer.load(Ordering::Relaxed);
+            let c_index = self.sequence_number.load(Ordering::Relaxed);
             if p_index < capacity || p_index - capacity < c_index {
                 let pos = self.pos(p_index);
-                let mut node = unsafe { self.ring_buffer.get_unchecked_mut(pos) };
+                let node = unsafe { self.ring_buffer.get_unchecked(pos) };
                 if node.id.load(Ordering::Acquire) == 0 {
-                    self.producer.counter.store(p_index + 1, Ordering::Relaxed);
-                    node.value = Some(value);
-                    node.id.store(p_index, Ordering::Relaxed);
+                    self.producer.store(p_index + 1, Ordering::Relaxed);
+                    node.value.with_mut(|v| unsafe { *v = Some(value) });
+                    // Release so the consumer sees the value once it sees the id.
+                    node.id.store(p_index, Ordering::Release);
+                    self.wait.signal();
                     break true;
                 } else {
-                    thread::yield_now();
+                    self.wait.wait(attempt);
+                    attempt += 1;
                 }
             } else {
                 break false;
@@ -223,11 +235,12 @@ impl<T> ConcurrentQueue<T> for SpscQueue<T> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
 
     use crate::queue::spsc_queue::{SpscQueue, SpscQueueReceiveWrap, SpscQueueSendWrap};
     use crate::queue::ConcurrentQueue;
+    use crate::wait::yielding;
     use std::sync::Arc;
     use std::thread;
     use std::vec::Vec;
@@ -235,7 +248,7 @@ mod tests {
 
     #[test]
     pub fn create_queue_test() {
-        let mut queue: SpscQueue<u64> = SpscQueue::new(128);
+        let mut queue: SpscQueue<u64> = SpscQueue::new(128, yielding());
         assert_eq!(128, queue.ring_buffer.len());
 
         queue.offer(1);
@@ -321,3 +334,34 @@ mod tests {
         }
     }
 }
+
+#[cfg(loom)]
+mod loom_tests {
+
+    use crate::queue::spsc_queue::SpscQueueSendWrap;
+    use loom::thread;
+
+    #[test]
+    pub fn loom_offer_poll_test() {
+        loom::model(|| {
+            let (write, read) = SpscQueueSendWrap::<usize>::new(2);
+            // Wraps around the ring buffer so the slots get reused.
+            let producer = thread::spawn(move || {
+                for i in 1..4 {
+                    while !write.offer(i) {
+                        thread::yield_now();
+                    }
+                }
+            });
+            let mut values = Vec::with_capacity(3);
+            while values.len() < 3 {
+                match read.poll() {
+                    Some(v) => values.push(v),
+                    None => thread::yield_now(),
+                }
+            }
+            producer.join().unwrap();
+            assert_eq!(vec![1, 2, 3], values);
+        });
+    }
+}
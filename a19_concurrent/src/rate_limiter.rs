@@ -0,0 +1,318 @@
+//! A token bucket to limit the rate of something like the appends to a topic or the bytes sent to
+//! a follower.  The tokens are kept as micro tokens in an atomic and are refilled from the clock
+//! each time the bucket is used so there isn't a thread refilling it.
+//!```text
+//!            refill = elapsed * rate, capped at the burst
+//!                          |
+//!                          V
+//! burst  +------------------------------+
+//!        |  tokens                      | <- try_acquire only takes them if there are enough
+//!    0   +------------------------------+
+//!        |  debt                        | <- acquire takes them anyway and waits for the debt
+//!        +------------------------------+    to be paid back
+//!```
+//! Since a waiting acquire has already taken its tokens the waiters are served in the order they
+//! asked and a `try_acquire` can't take the tokens out from under them.
+use crate::wait::{SleepBackoff, WaitStrategy};
+use a19_core::clock::{system_clock, Clock};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The number of micro tokens in a token.
+const MICROS_PER_TOKEN: i64 = 1_000_000;
+/// The number of nanoseconds it takes to add a micro token at a rate of 1 token per second.
+const NANOS_PER_MICRO: u128 = 1_000;
+/// How long the blocking acquire sleeps between checks by default.
+const DEFAULT_WAIT: Duration = Duration::from_millis(1);
+/// The longest the async acquire sleeps before checking the clock again.
+const MAX_ASYNC_SLEEP: Duration = Duration::from_millis(10);
+
+/// Limits the rate things can happen at.
+pub struct RateLimiter {
+    /// The number of tokens added each second.
+    rate_per_sec: u64,
+    /// The max number of micro tokens in the bucket.
+    burst: i64,
+    /// The micro tokens in the bucket.  Goes negative when the blocking acquires take more than
+    /// there is.
+    tokens: AtomicI64,
+    /// The monotonic time of the last refill.
+    last_refill_ns: AtomicU64,
+    clock: Arc<dyn Clock>,
+    /// How the blocking acquire waits.
+    wait: Arc<dyn WaitStrategy>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter that starts with a full bucket.
+    /// # Arguments
+    /// `rate_per_sec` - The number of tokens added each second.
+    /// `burst` - The max number of tokens that can be taken at once.
+    pub fn new(rate_per_sec: u64, burst: u64) -> Self {
+        RateLimiter::with_clock(rate_per_sec, burst, system_clock())
+    }
+
+    /// Creates a rate limiter that gets the time from a clock.
+    /// # Arguments
+    /// `rate_per_sec` - The number of tokens added each second.
+    /// `burst` - The max number of tokens that can be taken at once.
+    /// `clock` - The clock to get the time from.
+    pub fn with_clock(rate_per_sec: u64, burst: u64, clock: Arc<dyn Clock>) -> Self {
+        RateLimiter::with_wait_strategy(
+            rate_per_sec,
+            burst,
+            clock,
+            Arc::new(SleepBackoff(DEFAULT_WAIT)),
+        )
+    }
+
+    /// Creates a rate limiter with the strategy the blocking acquire waits with.
+    /// # Arguments
+    /// `rate_per_sec` - The number of tokens added each second.
+    /// `burst` - The max number of tokens that can be taken at once.
+    /// `clock` - The clock to get the time from.
+    /// `wait` - How to wait for the tokens.
+    pub fn with_wait_strategy(
+        rate_per_sec: u64,
+        burst: u64,
+        clock: Arc<dyn Clock>,
+        wait: Arc<dyn WaitStrategy>,
+    ) -> Self {
+        let burst = (burst as i64).saturating_mul(MICROS_PER_TOKEN);
+        RateLimiter {
+            rate_per_sec,
+            burst,
+            tokens: AtomicI64::new(burst),
+            last_refill_ns: AtomicU64::new(clock.monotonic_ns()),
+            clock,
+            wait,
+        }
+    }
+
+    /// The number of tokens added each second.
+    pub fn rate_per_sec(&self) -> u64 {
+        self.rate_per_sec
+    }
+
+    /// The number of whole tokens in the bucket.  Negative if the waiting acquires have taken
+    /// more than there is.
+    pub fn available(&self) -> i64 {
+        self.refill();
+        self.tokens.load(Ordering::Acquire) / MICROS_PER_TOKEN
+    }
+
+    /// Adds the tokens for the time since the last refill.
+    fn refill(&self) {
+        let now = self.clock.monotonic_ns();
+        let last = self.last_refill_ns.load(Ordering::Acquire);
+        if now <= last {
+            return;
+        }
+        let add = (now - last) as u128 * self.rate_per_sec as u128 / NANOS_PER_MICRO;
+        // Wait for enough time to pass to add a micro token so the fractions aren't lost.
+        if add == 0 {
+            return;
+        }
+        if self
+            .last_refill_ns
+            .compare_exchange(last, now, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            let add = add.min(i64::MAX as u128) as i64;
+            let burst = self.burst;
+            let _ = self
+                .tokens
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| {
+                    Some(tokens.saturating_add(add).min(burst))
+                });
+        }
+    }
+
+    /// Takes the tokens if there are enough in the bucket.
+    /// # Arguments
+    /// `n` - The number of tokens to take.
+    /// # Returns
+    /// True if the tokens were taken.  Always false if more than the burst is asked for.
+    pub fn try_acquire(&self, n: u64) -> bool {
+        self.refill();
+        let needed = (n as i64).saturating_mul(MICROS_PER_TOKEN);
+        self.tokens
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| {
+                if tokens >= needed {
+                    Some(tokens - needed)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// Takes the tokens even if there aren't enough.
+    /// # Arguments
+    /// `n` - The number of tokens to take.
+    /// # Returns
+    /// The monotonic time in nanoseconds when the tokens have been paid back.
+    fn reserve(&self, n: u64) -> u64 {
+        self.refill();
+        let needed = (n as i64).saturating_mul(MICROS_PER_TOKEN);
+        let after = self.tokens.fetch_sub(needed, Ordering::AcqRel) - needed;
+        let now = self.clock.monotonic_ns();
+        if after >= 0 {
+            now
+        } else if self.rate_per_sec == 0 {
+            u64::MAX
+        } else {
+            let wait = (-after) as u128 * NANOS_PER_MICRO / self.rate_per_sec as u128;
+            now.saturating_add(wait.min(u64::MAX as u128) as u64)
+        }
+    }
+
+    /// Takes the tokens waiting for them if there aren't enough.  The callers are served in the
+    /// order they call.
+    /// # Arguments
+    /// `n` - The number of tokens to take.  Can be more than the burst.
+    pub fn acquire(&self, n: u64) {
+        let ready_at = self.reserve(n);
+        let mut attempt = 0;
+        while self.clock.monotonic_ns() < ready_at {
+            self.wait.wait(attempt);
+            attempt += 1;
+        }
+    }
+
+    /// Takes the tokens waiting for them if there aren't enough.  Sleeps on the tokio timer when
+    /// called on a tokio runtime, otherwise blocks like `acquire`.  If the future is dropped
+    /// before it completes the tokens are still taken.
+    /// # Arguments
+    /// `n` - The number of tokens to take.  Can be more than the burst.
+    pub async fn acquire_async(&self, n: u64) {
+        let ready_at = self.reserve(n);
+        if tokio::runtime::Handle::try_current().is_err() {
+            let mut attempt = 0;
+            while self.clock.monotonic_ns() < ready_at {
+                self.wait.wait(attempt);
+                attempt += 1;
+            }
+            return;
+        }
+        loop {
+            let now = self.clock.monotonic_ns();
+            if now >= ready_at {
+                break;
+            }
+            let sleep = Duration::from_nanos(ready_at - now).min(MAX_ASYNC_SLEEP);
+            tokio::time::delay_for(sleep).await;
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::rate_limiter::*;
+    use crate::wait::Yielding;
+    use a19_core::clock::ManualClock;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    pub fn sustained_rate_test() {
+        let clock = Arc::new(ManualClock::new(0));
+        let limiter = RateLimiter::with_clock(1000, 10, clock.clone());
+        let mut acquired = 0;
+        // Starts with a full bucket.
+        while limiter.try_acquire(1) {
+            acquired += 1;
+        }
+        assert_eq!(10, acquired);
+
+        // A second in small steps.
+        let mut acquired = 0;
+        for _ in 0..10_000 {
+            clock.advance(Duration::from_micros(100));
+            while limiter.try_acquire(1) {
+                acquired += 1;
+            }
+        }
+        assert!((990..=1010).contains(&acquired), "Acquired {}.", acquired);
+    }
+
+    #[test]
+    pub fn burst_test() {
+        let clock = Arc::new(ManualClock::new(0));
+        let limiter = RateLimiter::with_clock(100, 50, clock.clone());
+        assert!(limiter.try_acquire(50));
+        assert!(!limiter.try_acquire(1));
+        // Idle time only fills the bucket up to the burst.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(50, limiter.available());
+        assert!(!limiter.try_acquire(51));
+        assert!(limiter.try_acquire(50));
+
+        // The blocking acquire goes into debt and waits for it to be paid back.
+        let waiting = Arc::new(limiter);
+        let limiter = waiting.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let acquired = done.clone();
+        let waiter = thread::spawn(move || {
+            limiter.acquire(20);
+            acquired.store(true, Ordering::Release);
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(!done.load(Ordering::Acquire));
+        assert_eq!(-20, waiting.available());
+        // Can't take the tokens out from under the waiter.
+        clock.advance(Duration::from_millis(100));
+        assert!(!waiting.try_acquire(1));
+        clock.advance(Duration::from_millis(100));
+        waiter.join().unwrap();
+        assert!(done.load(Ordering::Acquire));
+    }
+
+    #[test]
+    pub fn fairness_test() {
+        let limiter = Arc::new(RateLimiter::with_wait_strategy(
+            2000,
+            10,
+            system_clock(),
+            Arc::new(Yielding),
+        ));
+        let started = Instant::now();
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    // One thread asks for more at a time.
+                    let n = if t == 0 { 8 } else { 1 };
+                    let mut acquired = 0;
+                    while acquired < 100 {
+                        limiter.acquire(n);
+                        acquired += n;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // 400 tokens at 2000 a second with 10 to start with.
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "Took {:?}.", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "Took {:?}.", elapsed);
+    }
+
+    #[tokio::test]
+    pub async fn acquire_async_test() {
+        let limiter = RateLimiter::new(1000, 10);
+        let started = Instant::now();
+        for _ in 0..6 {
+            limiter.acquire_async(10).await;
+        }
+        // The first 10 are in the bucket and the rest take 50ms to refill.
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(45), "Took {:?}.", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "Took {:?}.", elapsed);
+    }
+}
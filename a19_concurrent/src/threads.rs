@@ -0,0 +1,213 @@
+//! Settings for the threads the crates start.  Latency sensitive deployments pin the threads to
+//! isolated cores and give them names so they can be found in the tools.  The settings that can't
+//! be applied are logged as warnings so a bad setting never stops a thread from starting.
+//!```text
+//! name         - Set with the thread builder, limited to 15 bytes on Linux.
+//! pin_to_core  - sched_setaffinity on Linux.
+//! priority     - The nice value of the thread on Linux.  Lower values need privileges.
+//!```
+//! Pinning and the priority aren't supported on the other platforms and are ignored with a
+//! warning.
+use std::io;
+use std::thread::{Builder, JoinHandle};
+
+/// The settings for a thread.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadConfig {
+    /// The name of the thread.
+    pub name: String,
+    /// The core to run the thread on.
+    pub pin_to_core: Option<usize>,
+    /// The priority of the thread.  On Linux this is the nice value.
+    pub priority: Option<i32>,
+}
+
+impl ThreadConfig {
+    /// Creates the settings for a thread that is only named.
+    /// # Arguments
+    /// `name` - The name of the thread.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        ThreadConfig {
+            name: name.into(),
+            pin_to_core: None,
+            priority: None,
+        }
+    }
+
+    /// Pins the thread to a core.
+    /// # Arguments
+    /// `core` - The core to run the thread on.
+    pub fn pin_to_core(mut self, core: usize) -> Self {
+        self.pin_to_core = Some(core);
+        self
+    }
+
+    /// Sets the priority of the thread.
+    /// # Arguments
+    /// `priority` - The priority to run the thread at.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Gets the settings to use for a thread.
+    /// # Arguments
+    /// `config` - The settings that were passed in.
+    /// `name` - The name to use if no settings were passed in.
+    pub fn or_named(config: Option<ThreadConfig>, name: &str) -> ThreadConfig {
+        config.unwrap_or_else(|| ThreadConfig::new(name))
+    }
+
+    /// Applies the pinning and priority to the current thread.  The ones that fail are logged as
+    /// warnings.
+    /// # Returns
+    /// True if all of the settings were applied.
+    pub fn apply_to_current(&self) -> bool {
+        let mut applied = true;
+        if let Some(core) = self.pin_to_core {
+            if let Err(e) = pin_current(core) {
+                log::warn!("Unable to pin thread {} to core {}: {}", self.name, core, e);
+                applied = false;
+            }
+        }
+        if let Some(priority) = self.priority {
+            if let Err(e) = set_current_priority(priority) {
+                log::warn!(
+                    "Unable to set the priority of thread {} to {}: {}",
+                    self.name,
+                    priority,
+                    e
+                );
+                applied = false;
+            }
+        }
+        applied
+    }
+}
+
+/// Starts a thread with the settings.
+/// # Arguments
+/// `config` - The settings for the thread.
+/// `f` - The function to run on the thread.
+/// # Returns
+/// The handle to join the thread.  Only fails if the thread couldn't be started.
+pub fn spawn_configured<F, T>(config: ThreadConfig, f: F) -> io::Result<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let mut builder = Builder::new();
+    if !config.name.is_empty() {
+        builder = builder.name(config.name.clone());
+    }
+    builder.spawn(move || {
+        config.apply_to_current();
+        f()
+    })
+}
+
+/// Pins the current thread to a core.
+/// # Arguments
+/// `core` - The core to run the thread on.
+#[cfg(target_os = "linux")]
+pub fn pin_current(core: usize) -> io::Result<()> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Core {} is out of range.", core),
+        ));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Pins the current thread to a core.
+/// # Arguments
+/// `core` - The core to run the thread on.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current(_core: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Pinning a thread isn't supported on this platform.",
+    ))
+}
+
+/// Sets the priority of the current thread.
+/// # Arguments
+/// `priority` - The nice value for the thread.
+#[cfg(target_os = "linux")]
+pub fn set_current_priority(priority: i32) -> io::Result<()> {
+    unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        if libc::setpriority(libc::PRIO_PROCESS, tid, priority) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Sets the priority of the current thread.
+/// # Arguments
+/// `priority` - The priority for the thread.
+#[cfg(not(target_os = "linux"))]
+pub fn set_current_priority(_priority: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Setting the priority of a thread isn't supported on this platform.",
+    ))
+}
+
+#[cfg(all(test, not(loom), target_os = "linux"))]
+mod tests {
+
+    use crate::threads::*;
+    use std::fs::read_to_string;
+
+    /// Reads a field from the status of the current thread.
+    fn thread_status(field: &str) -> String {
+        read_to_string("/proc/thread-self/status")
+            .unwrap()
+            .lines()
+            .find(|l| l.starts_with(field))
+            .map(|l| l[field.len()..].trim().to_owned())
+            .unwrap()
+    }
+
+    #[test]
+    pub fn spawn_configured_test() {
+        let handle = spawn_configured(ThreadConfig::new("a19-test"), || {
+            read_to_string("/proc/thread-self/comm").unwrap()
+        })
+        .unwrap();
+        assert_eq!("a19-test", handle.join().unwrap().trim());
+
+        let handle = spawn_configured(ThreadConfig::new("a19-pinned").pin_to_core(0), || {
+            thread_status("Cpus_allowed_list:")
+        })
+        .unwrap();
+        assert_eq!("0", handle.join().unwrap());
+
+        // The thread still runs when the settings can't be applied.
+        let config = ThreadConfig::new("a19-bad").pin_to_core(usize::MAX);
+        let handle = spawn_configured(config.clone(), move || config.apply_to_current()).unwrap();
+        assert!(!handle.join().unwrap());
+        assert!(pin_current(usize::MAX).is_err());
+
+        let handle = spawn_configured(ThreadConfig::new("a19-nice"), || {
+            let applied = set_current_priority(5).is_ok();
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) as libc::id_t };
+            (applied, unsafe { libc::getpriority(libc::PRIO_PROCESS, tid) })
+        })
+        .unwrap();
+        let (applied, priority) = handle.join().unwrap();
+        assert!(!applied || priority == 5);
+    }
+}
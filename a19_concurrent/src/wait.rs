@@ -0,0 +1,198 @@
+//! How a thread waits when there is nothing for it to do.  The strategies trade off latency for the
+//! cpu used while idle.
+//!```text
+//! BusySpin      - Lowest latency, uses a whole core while waiting.
+//! Yielding      - Gives up the core to other threads, still shows up as busy.
+//! SleepBackoff  - Sleeps for a fixed time, the latency is up to the sleep time.
+//! Parking       - Sleeps until signaled or the timeout, needs the producer to call signal.
+//!```
+use crate::sync::hint;
+use crate::sync::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// How long a parked thread waits if it is never signaled.
+const DEFAULT_PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A way for a thread to wait for more work.
+pub trait WaitStrategy: Send + Sync {
+    /// Waits before checking for work again.
+    /// # Arguments
+    /// `attempt` - The number of times in a row nothing has been found.  Starts at 0 and is reset
+    /// once work is found.
+    fn wait(&self, attempt: u32);
+
+    /// Wakes up the threads waiting on the strategy.  Only does something for the strategies that
+    /// park the thread.
+    fn signal(&self) {}
+}
+
+/// Spins on the cpu without giving it up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BusySpin;
+
+impl WaitStrategy for BusySpin {
+    #[inline]
+    fn wait(&self, _attempt: u32) {
+        hint::spin_loop();
+    }
+}
+
+/// Yields the thread to the scheduler.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Yielding;
+
+impl WaitStrategy for Yielding {
+    #[inline]
+    fn wait(&self, _attempt: u32) {
+        thread::yield_now();
+    }
+}
+
+/// Sleeps for a fixed amount of time.
+#[derive(Debug, Clone, Copy)]
+pub struct SleepBackoff(pub Duration);
+
+impl WaitStrategy for SleepBackoff {
+    fn wait(&self, _attempt: u32) {
+        std::thread::sleep(self.0);
+    }
+}
+
+/// Parks the thread until it is signaled.  The timeout is there so a thread can check if it should
+/// stop without needing a signal.
+pub struct Parking {
+    timeout: Duration,
+    /// Set when signaled and cleared by the thread that wakes up.
+    signaled: AtomicBool,
+    /// The number of threads parked.  The signal only takes the lock if there is someone to wake.
+    waiters: AtomicUsize,
+    lock: Mutex<()>,
+    condvar: Condvar,
+}
+
+impl Parking {
+    /// Creates a parking strategy.
+    /// # Arguments
+    /// `timeout` - The longest to stay parked without being signaled.
+    pub fn new(timeout: Duration) -> Self {
+        Parking {
+            timeout,
+            signaled: AtomicBool::new(false),
+            waiters: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            condvar: Condvar::new(),
+        }
+    }
+}
+
+impl Default for Parking {
+    fn default() -> Self {
+        Parking::new(DEFAULT_PARK_TIMEOUT)
+    }
+}
+
+impl WaitStrategy for Parking {
+    fn wait(&self, _attempt: u32) {
+        if self.signaled.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let guard = self.lock.lock().unwrap();
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        // Check again now that we are counted as a waiter.  Either we see the signal or the signal
+        // sees us and waits for the lock before notifying.
+        if !self.signaled.swap(false, Ordering::SeqCst) {
+            let (_guard, _) = self.condvar.wait_timeout(guard, self.timeout).unwrap();
+            self.signaled.store(false, Ordering::SeqCst);
+        }
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn signal(&self) {
+        self.signaled.store(true, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock().unwrap();
+            self.condvar.notify_all();
+        }
+    }
+}
+
+/// The strategy the queues use if one isn't specified.
+pub fn yielding() -> Arc<dyn WaitStrategy> {
+    Arc::new(Yielding)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use crate::queue::mpsc_queue::MpscQueueWrap;
+    use crate::wait::*;
+    use std::sync::atomic::AtomicU32;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    pub fn wait_test() {
+        let started = Instant::now();
+        BusySpin.wait(0);
+        Yielding.wait(0);
+        BusySpin.signal();
+        Yielding.signal();
+        SleepBackoff(Duration::from_millis(20)).wait(0);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        // A signal before the wait is kept so the wait returns right away.
+        let parking = Parking::new(Duration::from_secs(10));
+        parking.signal();
+        let started = Instant::now();
+        parking.wait(0);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Times out without a signal.
+        let parking = Parking::new(Duration::from_millis(20));
+        let started = Instant::now();
+        parking.wait(0);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        // Wakes up the parked thread.
+        let parking = Arc::new(Parking::new(Duration::from_secs(10)));
+        let parked = parking.clone();
+        let started = Instant::now();
+        let waiter = thread::spawn(move || parked.wait(0));
+        thread::sleep(Duration::from_millis(20));
+        parking.signal();
+        waiter.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    pub fn parking_idle_test() {
+        let strategy: Arc<dyn WaitStrategy> = Arc::new(Parking::new(Duration::from_secs(1)));
+        let (write, read) = MpscQueueWrap::<u32>::with_wait_strategy(16, strategy.clone());
+        let attempts = Arc::new(AtomicU32::new(0));
+        let consumer_attempts = attempts.clone();
+        let consumer = thread::spawn(move || {
+            let mut attempt = 0;
+            loop {
+                match read.poll() {
+                    Some(v) => break v,
+                    None => {
+                        consumer_attempts.fetch_add(1, Ordering::Relaxed);
+                        strategy.wait(attempt);
+                        attempt += 1;
+                    }
+                }
+            }
+        });
+        thread::sleep(Duration::from_millis(300));
+        // Only wakes up for the timeouts while idle.
+        let idle_attempts = attempts.load(Ordering::Relaxed);
+        assert!(idle_attempts <= 2, "Woke up {} times.", idle_attempts);
+        let started = Instant::now();
+        assert!(write.offer(7));
+        assert_eq!(7, consumer.join().unwrap());
+        // The offer signals the consumer instead of it waiting for the timeout.
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
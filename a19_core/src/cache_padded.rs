@@ -0,0 +1,68 @@
+use std::ops::{Deref, DerefMut};
+
+/// Pads and aligns a value to 128 bytes so it doesn't share a cache line with its neighbors.  Uses
+/// 128 bytes since some processors pull in pairs of 64 byte lines and the Apple M-series have
+/// 128 byte lines.
+#[repr(align(128))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CachePadded<T>(pub T);
+
+impl<T> CachePadded<T> {
+    /// Pads a value.
+    /// # Arguments
+    /// `value` - The value to pad.
+    pub const fn new(value: T) -> Self {
+        CachePadded(value)
+    }
+
+    /// Gets the value back out.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> Self {
+        CachePadded::new(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::cache_padded::CachePadded;
+    use std::mem::{align_of, size_of};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const _: () = assert!(align_of::<CachePadded<AtomicUsize>>() == 128);
+    const _: () = assert!(size_of::<CachePadded<AtomicUsize>>() == 128);
+    const _: () = assert!(size_of::<CachePadded<[u8; 129]>>() == 256);
+
+    #[test]
+    pub fn cache_padded_test() {
+        let mut counters = [CachePadded::new(AtomicUsize::new(1)), CachePadded::default()];
+        counters[0].fetch_add(1, Ordering::Relaxed);
+        *counters[1].get_mut() = 5;
+        let first = &counters[0] as *const _ as usize;
+        let second = &counters[1] as *const _ as usize;
+        assert_eq!(128, second - first);
+        assert_eq!(0, first % 128);
+        let [first, second] = counters;
+        assert_eq!(2, first.into_inner().into_inner());
+        assert_eq!(5, second.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The source of time.  Lets the tests control the time instead of waiting for it to pass.
+pub trait Clock: Send + Sync {
+    /// The wall clock time in milliseconds since the epoch.
+    fn now_ms(&self) -> u64;
+
+    /// A time in nanoseconds that never goes backwards.  Only useful for measuring the time
+    /// between two calls.
+    fn monotonic_ns(&self) -> u64;
+}
+
+/// Gets the time from the system.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    /// The monotonic time is measured from when the clock was created.
+    started: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(n) => n.as_millis() as u64,
+            Err(_) => 0,
+        }
+    }
+
+    fn monotonic_ns(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+}
+
+/// A clock that only moves when it's told to.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now_ms: AtomicU64,
+    monotonic_ns: AtomicU64,
+}
+
+impl ManualClock {
+    /// Creates a new clock.
+    /// # Arguments
+    /// `now_ms` - The starting wall clock time in milliseconds since the epoch.
+    pub fn new(now_ms: u64) -> Self {
+        ManualClock {
+            now_ms: AtomicU64::new(now_ms),
+            monotonic_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets the wall clock time.  Can go backwards to simulate the system clock being changed.
+    /// The monotonic time isn't changed.
+    /// # Arguments
+    /// `now_ms` - The time in milliseconds since the epoch.
+    pub fn set_ms(&self, now_ms: u64) {
+        self.now_ms.store(now_ms, Ordering::Release);
+    }
+
+    /// Moves both the wall clock and the monotonic time forward.
+    /// # Arguments
+    /// `amount` - The amount of time to move forward.
+    pub fn advance(&self, amount: Duration) {
+        self.now_ms
+            .fetch_add(amount.as_millis() as u64, Ordering::AcqRel);
+        self.monotonic_ns
+            .fetch_add(amount.as_nanos() as u64, Ordering::AcqRel);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::Acquire)
+    }
+
+    fn monotonic_ns(&self) -> u64 {
+        self.monotonic_ns.load(Ordering::Acquire)
+    }
+}
+
+/// Creates the clock used when one isn't specified.
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock::new())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::clock::*;
+
+    #[test]
+    pub fn manual_clock_test() {
+        let clock = ManualClock::new(1_000);
+        assert_eq!(1_000, clock.now_ms());
+        assert_eq!(0, clock.monotonic_ns());
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(1_005, clock.now_ms());
+        assert_eq!(5_000_000, clock.monotonic_ns());
+        clock.set_ms(10);
+        assert_eq!(10, clock.now_ms());
+        assert_eq!(5_000_000, clock.monotonic_ns());
+    }
+
+    #[test]
+    pub fn system_clock_test() {
+        let clock = SystemClock::new();
+        let first = clock.monotonic_ns();
+        assert!(clock.monotonic_ns() >= first);
+        assert!(clock.now_ms() > 1_500_000_000_000);
+    }
+}
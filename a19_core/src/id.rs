@@ -0,0 +1,307 @@
+//! Generates unique 64 bit ids that are roughly ordered by time.  Each server needs its own id so
+//! the ids don't collide across servers.
+//!```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-------------------------------------------------------------+
+//! |0|                 Milliseconds Since Epoch                    |
+//! +-+-----------------+-------------------+-----------------------+
+//! | Milliseconds ...  |    Server Id      |       Sequence        |
+//! +-------------------+-------------------+-----------------------+
+//!```
+//! The top bit is always 0 so the ids can be stored as a signed value.  The server id takes 10 to
+//! 16 bits and the sequence gets what is left over.
+use crate::clock::{system_clock, Clock};
+use std::fmt;
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of bits used for the time.
+const TIMESTAMP_BITS: u32 = 41;
+/// The number of bits shared by the server id and the sequence.
+const SERVER_SEQUENCE_BITS: u32 = 63 - TIMESTAMP_BITS;
+/// The default epoch, 2020-01-01 00:00:00 UTC.
+pub const DEFAULT_EPOCH_MS: u64 = 1_577_836_800_000;
+
+/// What to do when the clock goes backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockRegression {
+    /// Wait for the clock to catch back up to the last id.
+    Spin,
+    /// Return an error.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The clock went backwards.
+    ClockMovedBackwards { last_ms: u64, now_ms: u64 },
+    /// Ran out of bits for the time.
+    TimestampOverflow,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ClockMovedBackwards { last_ms, now_ms } => write!(
+                f,
+                "The clock moved backwards from {} to {}.",
+                last_ms, now_ms
+            ),
+            Error::TimestampOverflow => write!(f, "The timestamp doesn't fit in the id."),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The settings for the generator.
+#[derive(Debug, Clone, Copy)]
+pub struct IdGeneratorConfig {
+    /// The number of bits for the server id.  Needs to be between 10 and 16.
+    pub server_bits: u32,
+    /// The time in milliseconds since the unix epoch the ids start from.
+    pub epoch_ms: u64,
+    /// What to do when the clock goes backwards.
+    pub clock_regression: ClockRegression,
+}
+
+impl Default for IdGeneratorConfig {
+    fn default() -> Self {
+        IdGeneratorConfig {
+            server_bits: 10,
+            epoch_ms: DEFAULT_EPOCH_MS,
+            clock_regression: ClockRegression::Spin,
+        }
+    }
+}
+
+/// The values an id is made from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdParts {
+    /// The time in milliseconds since the unix epoch.
+    pub timestamp_ms: u64,
+    pub server_id: u16,
+    pub sequence: u64,
+}
+
+/// Generates the ids for a server.  Safe to share between threads.
+pub struct IdGenerator {
+    server_id: u64,
+    server_mask: u64,
+    sequence_bits: u32,
+    sequence_mask: u64,
+    epoch_ms: u64,
+    clock_regression: ClockRegression,
+    clock: Arc<dyn Clock>,
+    /// The timestamp and sequence of the last id.  Laid out the same as the id without the
+    /// server id.
+    state: AtomicU64,
+}
+
+impl IdGenerator {
+    /// Creates a generator using the default settings and the system clock.
+    /// # Arguments
+    /// `server_id` - The id of the server.  Needs to fit in 10 bits.
+    pub fn new(server_id: u16) -> Self {
+        IdGenerator::with_config(server_id, IdGeneratorConfig::default(), system_clock())
+    }
+
+    /// Creates a generator.
+    /// # Arguments
+    /// `server_id` - The id of the server.  Needs to fit in the server bits.
+    /// `config` - The settings for the generator.
+    /// `clock` - The clock to get the time from.
+    pub fn with_config(server_id: u16, config: IdGeneratorConfig, clock: Arc<dyn Clock>) -> Self {
+        assert!(
+            (10..=16).contains(&config.server_bits),
+            "The server bits need to be between 10 and 16."
+        );
+        assert!(
+            (server_id as u64) < 1 << config.server_bits,
+            "The server id {} doesn't fit in {} bits.",
+            server_id,
+            config.server_bits
+        );
+        let sequence_bits = SERVER_SEQUENCE_BITS - config.server_bits;
+        IdGenerator {
+            server_id: server_id as u64,
+            server_mask: (1 << config.server_bits) - 1,
+            sequence_bits,
+            sequence_mask: (1 << sequence_bits) - 1,
+            epoch_ms: config.epoch_ms,
+            clock_regression: config.clock_regression,
+            clock,
+            state: AtomicU64::new(0),
+        }
+    }
+
+    /// Gets the next id.  Waits for the next millisecond if we have run out of sequence numbers.
+    /// # Returns
+    /// The id or an error if the clock went backwards and the policy is to error.
+    pub fn next_id(&self) -> Result<u64> {
+        loop {
+            let now = self.clock.now_ms().saturating_sub(self.epoch_ms);
+            if now >> TIMESTAMP_BITS > 0 {
+                return Err(Error::TimestampOverflow);
+            }
+            let last = self.state.load(Ordering::Acquire);
+            let last_ms = last >> self.sequence_bits;
+            let next = if now > last_ms {
+                now << self.sequence_bits
+            } else if now == last_ms {
+                if last & self.sequence_mask == self.sequence_mask {
+                    // Out of ids for this millisecond.
+                    spin_loop();
+                    continue;
+                }
+                last + 1
+            } else {
+                match self.clock_regression {
+                    ClockRegression::Spin => {
+                        spin_loop();
+                        continue;
+                    }
+                    ClockRegression::Error => {
+                        return Err(Error::ClockMovedBackwards {
+                            last_ms: last_ms + self.epoch_ms,
+                            now_ms: now + self.epoch_ms,
+                        })
+                    }
+                }
+            };
+            if self
+                .state
+                .compare_exchange_weak(last, next, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                let timestamp = next >> self.sequence_bits;
+                let sequence = next & self.sequence_mask;
+                return Ok(timestamp << SERVER_SEQUENCE_BITS
+                    | self.server_id << self.sequence_bits
+                    | sequence);
+            }
+        }
+    }
+
+    /// Splits an id created by this generator back into its parts.
+    /// # Arguments
+    /// `id` - The id to split.
+    pub fn parts(&self, id: u64) -> IdParts {
+        IdParts {
+            timestamp_ms: (id >> SERVER_SEQUENCE_BITS) + self.epoch_ms,
+            server_id: ((id >> self.sequence_bits) & self.server_mask) as u16,
+            sequence: id & self.sequence_mask,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::clock::ManualClock;
+    use crate::id::*;
+    use std::collections::HashSet;
+    use std::thread;
+    use std::time::Duration;
+
+    const THREADS: usize = 8;
+    const IDS_PER_THREAD: usize = 125_000;
+
+    #[test]
+    pub fn unique_ids_test() {
+        let generator = Arc::new(IdGenerator::new(7));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let generator = generator.clone();
+                thread::spawn(move || {
+                    let mut ids = Vec::with_capacity(IDS_PER_THREAD);
+                    for _ in 0..IDS_PER_THREAD {
+                        ids.push(generator.next_id().unwrap());
+                    }
+                    ids
+                })
+            })
+            .collect();
+        let mut all = HashSet::with_capacity(THREADS * IDS_PER_THREAD);
+        for t in threads {
+            let ids = t.join().unwrap();
+            assert!(ids.windows(2).all(|w| w[0] < w[1]));
+            all.extend(ids);
+        }
+        assert_eq!(THREADS * IDS_PER_THREAD, all.len());
+        let id = generator.next_id().unwrap();
+        assert_eq!(7, generator.parts(id).server_id);
+        assert!(id < 1 << 63);
+    }
+
+    #[test]
+    pub fn sequence_test() {
+        let clock = Arc::new(ManualClock::new(DEFAULT_EPOCH_MS + 10));
+        let config = IdGeneratorConfig {
+            server_bits: 16,
+            ..IdGeneratorConfig::default()
+        };
+        let generator = Arc::new(IdGenerator::with_config(0xFFFF, config, clock.clone()));
+        for i in 0..64 {
+            let parts = generator.parts(generator.next_id().unwrap());
+            assert_eq!(
+                IdParts {
+                    timestamp_ms: DEFAULT_EPOCH_MS + 10,
+                    server_id: 0xFFFF,
+                    sequence: i,
+                },
+                parts
+            );
+        }
+        // Out of sequence numbers so it waits for the next millisecond.
+        let waiting = generator.clone();
+        let next = thread::spawn(move || waiting.next_id().unwrap());
+        thread::sleep(Duration::from_millis(20));
+        assert!(!next.is_finished());
+        clock.advance(Duration::from_millis(1));
+        let parts = generator.parts(next.join().unwrap());
+        assert_eq!(DEFAULT_EPOCH_MS + 11, parts.timestamp_ms);
+        assert_eq!(0, parts.sequence);
+    }
+
+    #[test]
+    pub fn clock_regression_test() {
+        let clock = Arc::new(ManualClock::new(DEFAULT_EPOCH_MS + 100));
+        let config = IdGeneratorConfig {
+            clock_regression: ClockRegression::Error,
+            ..IdGeneratorConfig::default()
+        };
+        let generator = IdGenerator::with_config(1, config, clock.clone());
+        let first = generator.next_id().unwrap();
+        clock.set_ms(DEFAULT_EPOCH_MS + 90);
+        assert_eq!(
+            Err(Error::ClockMovedBackwards {
+                last_ms: DEFAULT_EPOCH_MS + 100,
+                now_ms: DEFAULT_EPOCH_MS + 90,
+            }),
+            generator.next_id()
+        );
+        clock.set_ms(DEFAULT_EPOCH_MS + 100);
+        assert!(generator.next_id().unwrap() > first);
+
+        let generator = Arc::new(IdGenerator::with_config(
+            1,
+            IdGeneratorConfig::default(),
+            clock.clone(),
+        ));
+        let first = generator.next_id().unwrap();
+        clock.set_ms(DEFAULT_EPOCH_MS + 50);
+        let waiting = generator.clone();
+        let next = thread::spawn(move || waiting.next_id().unwrap());
+        thread::sleep(Duration::from_millis(20));
+        assert!(!next.is_finished());
+        clock.set_ms(DEFAULT_EPOCH_MS + 101);
+        let next = next.join().unwrap();
+        assert!(next > first);
+        assert_eq!(DEFAULT_EPOCH_MS + 101, generator.parts(next).timestamp_ms);
+    }
+}
@@ -1,6 +1,9 @@
 use rand::{thread_rng, RngCore};
 use std::time::SystemTime;
 
+pub mod cache_padded;
+pub mod clock;
+pub mod id;
 pub mod js;
 pub mod pow2;
 pub mod validation;
@@ -1,55 +1,90 @@
-pub trait PowOf2 {
+//! Power of two arithmetic used for the buffer capacities and the message alignment.
+
+/// Power of two helpers implemented for `usize`, `u32` and `u64`.
+pub trait PowOf2: Sized {
+    /// Rounds the value up to a power of two.  A value that is already a power of two is kept.
     fn round_to_power_of_two(&self) -> Self;
+
+    /// The smallest power of two greater than or equal to the value.  0 goes to 1.
+    /// # Panics
+    /// If the power of two doesn't fit in the type.
     fn next_power_of_two(&self) -> Self;
-    fn is_power_of_2(&self) -> bool;
-}
 
-impl PowOf2 for u64 {
-    fn round_to_power_of_two(&self) -> u64 {
-        if self.is_power_of_2() {
-            *self
-        } else {
-            self.next_power_of_two()
-        }
-    }
-    fn next_power_of_two(&self) -> u64 {
-        let mut x = *self;
-        x |= x >> 1;
-        x |= x >> 2;
-        x |= x >> 4;
-        x |= x >> 8;
-        x |= x >> 16;
-        x + 1
-    }
+    /// The largest power of two less than or equal to the value.  0 stays 0.
+    fn prev_power_of_two(&self) -> Self;
+
+    /// True if the value is a power of two.  0 isn't a power of two.
+    fn is_power_of_two(&self) -> bool;
 
+    /// Same as `is_power_of_two`.
     fn is_power_of_2(&self) -> bool {
-        self & (self - 1) == 0
+        self.is_power_of_two()
     }
+
+    /// Rounds the value up to the next multiple of the alignment.
+    /// # Arguments
+    /// `alignment` - The alignment to round to.  Must be a power of two.
+    fn align_up(&self, alignment: Self) -> Self;
+
+    /// Rounds the value down to a multiple of the alignment.
+    /// # Arguments
+    /// `alignment` - The alignment to round to.  Must be a power of two.
+    fn align_down(&self, alignment: Self) -> Self;
 }
 
-impl PowOf2 for usize {
-    fn round_to_power_of_two(&self) -> usize {
-        if self.is_power_of_2() {
-            *self
-        } else {
-            self.next_power_of_two()
-        }
-    }
-    fn next_power_of_two(&self) -> usize {
-        let mut x = *self;
-        x |= x >> 1;
-        x |= x >> 2;
-        x |= x >> 4;
-        x |= x >> 8;
-        x |= x >> 16;
-        x + 1
-    }
+macro_rules! impl_pow_of_2 {
+    ($($t:ty),*) => {
+        $(
+            impl PowOf2 for $t {
+                #[inline]
+                fn round_to_power_of_two(&self) -> $t {
+                    PowOf2::next_power_of_two(self)
+                }
 
-    fn is_power_of_2(&self) -> bool {
-        self & (self - 1) == 0
-    }
+                #[inline]
+                fn next_power_of_two(&self) -> $t {
+                    self.checked_next_power_of_two()
+                        .expect("The next power of two doesn't fit in the type.")
+                }
+
+                #[inline]
+                fn prev_power_of_two(&self) -> $t {
+                    if *self == 0 {
+                        0
+                    } else {
+                        1 << (<$t>::MAX.count_ones() - 1 - self.leading_zeros())
+                    }
+                }
+
+                #[inline]
+                fn is_power_of_two(&self) -> bool {
+                    *self != 0 && self & (self - 1) == 0
+                }
+
+                #[inline]
+                fn align_up(&self, alignment: $t) -> $t {
+                    debug_assert!(
+                        PowOf2::is_power_of_two(&alignment),
+                        "The alignment must be a power of two."
+                    );
+                    (self + (alignment - 1)) & !(alignment - 1)
+                }
+
+                #[inline]
+                fn align_down(&self, alignment: $t) -> $t {
+                    debug_assert!(
+                        PowOf2::is_power_of_two(&alignment),
+                        "The alignment must be a power of two."
+                    );
+                    self & !(alignment - 1)
+                }
+            }
+        )*
+    };
 }
 
+impl_pow_of_2!(usize, u32, u64);
+
 #[cfg(test)]
 pub mod tests {
 
@@ -90,4 +125,50 @@ pub mod tests {
         assert_eq!(8, (7 as usize).next_power_of_two());
         assert_eq!(16, (9 as usize).next_power_of_two());
     }
+
+    macro_rules! pow_of_2_tests {
+        ($($name:ident: $t:ty),*) => {
+            $(
+                #[test]
+                pub fn $name() {
+                    let powers = [false, true, true, false, true, false, false, false, true];
+                    let next: [$t; 9] = [1, 1, 2, 4, 4, 8, 8, 8, 8];
+                    let prev: [$t; 9] = [0, 1, 2, 2, 4, 4, 4, 4, 8];
+                    for v in 0..9 as $t {
+                        let i = v as usize;
+                        assert_eq!(powers[i], PowOf2::is_power_of_two(&v), "{}", v);
+                        assert_eq!(next[i], PowOf2::next_power_of_two(&v), "{}", v);
+                        assert_eq!(next[i], v.round_to_power_of_two(), "{}", v);
+                        assert_eq!(prev[i], v.prev_power_of_two(), "{}", v);
+                    }
+                    let up_4: [$t; 9] = [0, 4, 4, 4, 4, 8, 8, 8, 8];
+                    let down_4: [$t; 9] = [0, 0, 0, 0, 4, 4, 4, 4, 8];
+                    for v in 0..9 as $t {
+                        let i = v as usize;
+                        assert_eq!(v, v.align_up(1));
+                        assert_eq!(v, v.align_down(1));
+                        assert_eq!(up_4[i], v.align_up(4), "{}", v);
+                        assert_eq!(down_4[i], v.align_down(4), "{}", v);
+                    }
+                    assert_eq!(32, (1 as $t).align_up(32));
+                    assert_eq!(64, (33 as $t).align_up(32));
+                    assert_eq!(64, (64 as $t).align_up(32));
+
+                    let max = <$t>::MAX;
+                    let top: $t = 1 << (max.count_ones() - 1);
+                    assert!(!PowOf2::is_power_of_two(&max));
+                    assert!(PowOf2::is_power_of_two(&top));
+                    assert_eq!(top, max.prev_power_of_two());
+                    assert_eq!(top, PowOf2::next_power_of_two(&top));
+                    assert_eq!(max, max.align_up(1));
+                    assert_eq!(max - 31, max.align_down(32));
+                    assert_eq!(top, max.align_down(top));
+                    assert!(std::panic::catch_unwind(|| PowOf2::next_power_of_two(&max)).is_err());
+                    assert!(std::panic::catch_unwind(|| PowOf2::next_power_of_two(&(top + 1))).is_err());
+                }
+            )*
+        };
+    }
+
+    pow_of_2_tests!(pow_of_2_usize_test: usize, pow_of_2_u32_test: u32, pow_of_2_u64_test: u64);
 }
@@ -0,0 +1,71 @@
+//! Encodes the typed messages into the bodies stored in the files.  Each type is stored under its
+//! own message type so a reader knows how to decode the body.  The types generated from the
+//! flatbuffer schemas are available with the `codegen` feature.
+use a19_concurrent::buffer::atomic_buffer::AtomicByteBufferInt;
+use a19_concurrent::buffer::DirectByteBuffer;
+
+/// The types generated from the flatbuffer schemas in `flat_buffers`.
+#[cfg(feature = "codegen")]
+#[allow(dead_code, unused_imports, clippy::all)]
+pub mod generated {
+    include!(concat!(env!("OUT_DIR"), "/persisted_file_generated.rs"));
+}
+
+/// The errors decoding a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The body is shorter than the message needs.
+    Truncated { needed: usize, available: usize },
+    /// The body doesn't contain a valid message.
+    Invalid(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A message that can be written to and read from a topic.
+pub trait MessageCodec: Sized {
+    /// The message type the values are stored under.  Can't be negative.
+    const MESSAGE_TYPE: i32;
+
+    /// The maximum number of bytes needed to encode the value.
+    fn encoded_size(&self) -> usize;
+
+    /// Encodes the value into a buffer.
+    /// # Arguments
+    /// `buffer` - The buffer to write to.  Needs at least `encoded_size` bytes after `pos`.
+    /// `pos` - The position to start writing at.
+    /// # Returns
+    /// The number of bytes written.
+    fn encode_into<B: DirectByteBuffer>(&self, buffer: &mut B, pos: usize) -> usize;
+
+    /// Decodes the value from the body of a message.
+    /// # Arguments
+    /// `bytes` - The body of the message.
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+/// Encodes a value into a new buffer.
+/// # Arguments
+/// `value` - The value to encode.
+/// # Returns
+/// The buffer and the number of bytes written.
+pub fn encode<T: MessageCodec>(value: &T) -> (AtomicByteBufferInt, usize) {
+    let mut buffer = AtomicByteBufferInt::new(value.encoded_size().max(8));
+    let length = value.encode_into(&mut buffer, 0);
+    (buffer, length)
+}
+
+/// Checks there are enough bytes left to decode.
+/// # Arguments
+/// `bytes` - The bytes to decode.
+/// `needed` - The number of bytes needed.
+pub fn check_length(bytes: &[u8], needed: usize) -> Result<()> {
+    if bytes.len() < needed {
+        Err(Error::Truncated {
+            needed,
+            available: bytes.len(),
+        })
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+//! The error returned by the public apis of the crate.  The lower level errors are converted into
+//! it so the layers can be composed with `?`.  The file buffers keep returning `file::Error` since
+//! a reader uses it to find the end of the messages, but it converts into a `PersistError::Buffer`
+//! when it escapes.
+use crate::codec;
+use crate::file;
+use crate::raft::backlog::BacklogStatus;
+use futures::channel::oneshot::Canceled;
+use std::fmt;
+use std::io;
+
+/// Where an error happened.  Only the fields that are known are set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    /// The path of the file.
+    pub path: Option<String>,
+    /// The position in the file.
+    pub position: Option<usize>,
+    /// The id of the message, term or file.
+    pub id: Option<u64>,
+}
+
+impl Context {
+    fn is_empty(&self) -> bool {
+        self.path.is_none() && self.position.is_none() && self.id.is_none()
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let mut separator = " (";
+        if let Some(path) = &self.path {
+            write!(f, "{}path: {}", separator, path)?;
+            separator = ", ";
+        }
+        if let Some(position) = self.position {
+            write!(f, "{}position: {}", separator, position)?;
+            separator = ", ";
+        }
+        if let Some(id) = self.id {
+            write!(f, "{}id: {}", separator, id)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The errors for the persisted stores.
+#[derive(Debug)]
+pub enum PersistError {
+    /// Unable to read or write a file.
+    Io { context: Context, source: io::Error },
+    /// The error from reading or writing a file buffer.
+    Buffer { context: Context, source: file::Error },
+    /// A message isn't valid.
+    CorruptMessage { context: Context, reason: String },
+    /// A term in a commit file isn't valid.
+    CorruptTerm { context: Context, reason: String },
+    /// The file is full.
+    Full { context: Context },
+    /// The file is being used by someone else.
+    Locked { context: Context },
+    /// The store was opened read only so it can't be written to.
+    ReadOnly { context: Context },
+    /// Only the leader can accept writes.
+    NotLeader {
+        context: Context,
+        leader_id: Option<u32>,
+    },
+    /// There isn't a leader to handle the request.
+    NoLeader { context: Context },
+    /// The store has been stopped.
+    Closed { context: Context },
+    /// The writes weren't committed in time.
+    CommitTimedOut { context: Context, lag: BacklogStatus },
+    /// A setting isn't valid.
+    InvalidConfig { context: Context, reason: String },
+    /// The messages needed have been removed by the retention.
+    Retained { context: Context },
+}
+
+pub type Result<T> = std::result::Result<T, PersistError>;
+
+impl PersistError {
+    /// Creates an invalid config error.
+    /// # Arguments
+    /// `reason` - Why the config isn't valid.
+    pub fn invalid_config<S: Into<String>>(reason: S) -> Self {
+        PersistError::InvalidConfig {
+            context: Context::default(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Creates a closed error.
+    pub fn closed() -> Self {
+        PersistError::Closed {
+            context: Context::default(),
+        }
+    }
+
+    /// Where the error happened.
+    pub fn context(&self) -> &Context {
+        match self {
+            PersistError::Io { context, .. }
+            | PersistError::Buffer { context, .. }
+            | PersistError::CorruptMessage { context, .. }
+            | PersistError::CorruptTerm { context, .. }
+            | PersistError::Full { context }
+            | PersistError::Locked { context }
+            | PersistError::ReadOnly { context }
+            | PersistError::NotLeader { context, .. }
+            | PersistError::NoLeader { context }
+            | PersistError::Closed { context }
+            | PersistError::CommitTimedOut { context, .. }
+            | PersistError::InvalidConfig { context, .. }
+            | PersistError::Retained { context } => context,
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        match self {
+            PersistError::Io { context, .. }
+            | PersistError::Buffer { context, .. }
+            | PersistError::CorruptMessage { context, .. }
+            | PersistError::CorruptTerm { context, .. }
+            | PersistError::Full { context }
+            | PersistError::Locked { context }
+            | PersistError::ReadOnly { context }
+            | PersistError::NotLeader { context, .. }
+            | PersistError::NoLeader { context }
+            | PersistError::Closed { context }
+            | PersistError::CommitTimedOut { context, .. }
+            | PersistError::InvalidConfig { context, .. }
+            | PersistError::Retained { context } => context,
+        }
+    }
+
+    /// Sets the path of the file the error happened in.
+    /// # Arguments
+    /// `path` - The path of the file.
+    pub fn with_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.context_mut().path = Some(path.into());
+        self
+    }
+
+    /// Sets the position in the file the error happened at.
+    /// # Arguments
+    /// `position` - The position in the file.
+    pub fn with_position(mut self, position: usize) -> Self {
+        self.context_mut().position = Some(position);
+        self
+    }
+
+    /// Sets the id of the message, term or file the error happened for.
+    /// # Arguments
+    /// `id` - The id.
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.context_mut().id = Some(id);
+        self
+    }
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::Io { source, .. } => write!(f, "I/O error: {}", source)?,
+            PersistError::Buffer { source, .. } => write!(f, "Buffer error: {}", source)?,
+            PersistError::CorruptMessage { reason, .. } => {
+                write!(f, "Corrupt message: {}", reason)?
+            }
+            PersistError::CorruptTerm { reason, .. } => write!(f, "Corrupt term: {}", reason)?,
+            PersistError::Full { .. } => write!(f, "The file is full")?,
+            PersistError::Locked { .. } => write!(f, "The file is locked")?,
+            PersistError::ReadOnly { .. } => write!(f, "The store is read only")?,
+            PersistError::NotLeader {
+                leader_id: Some(leader_id),
+                ..
+            } => write!(f, "Not the leader, the leader is {}", leader_id)?,
+            PersistError::NotLeader { .. } => write!(f, "Not the leader")?,
+            PersistError::NoLeader { .. } => write!(f, "There isn't a leader")?,
+            PersistError::Closed { .. } => write!(f, "The store is closed")?,
+            PersistError::CommitTimedOut { lag, .. } => write!(
+                f,
+                "Timed out waiting for the commit with {} writes and {} bytes pending",
+                lag.pending, lag.pending_bytes
+            )?,
+            PersistError::InvalidConfig { reason, .. } => {
+                write!(f, "Invalid config: {}", reason)?
+            }
+            PersistError::Retained { .. } => {
+                write!(f, "The messages have been removed by the retention")?
+            }
+        }
+        write!(f, "{}", self.context())
+    }
+}
+
+impl std::error::Error for PersistError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PersistError::Io { source, .. } => Some(source),
+            PersistError::Buffer { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PersistError {
+    fn from(err: io::Error) -> Self {
+        PersistError::Io {
+            context: Context::default(),
+            source: err,
+        }
+    }
+}
+
+impl From<file::Error> for PersistError {
+    fn from(err: file::Error) -> Self {
+        let context = Context::default();
+        match err {
+            file::Error::FileError(source) => PersistError::Io { context, source },
+            file::Error::Full => PersistError::Full { context },
+            file::Error::Stopped => PersistError::Closed { context },
+            file::Error::ReadOnly => PersistError::ReadOnly { context },
+            file::Error::InvalidFile => PersistError::CorruptMessage {
+                context,
+                reason: "The file isn't a message file.".to_owned(),
+            },
+            file::Error::InvalidHeader(reason) => PersistError::CorruptMessage { context, reason },
+            file::Error::ChecksumMismatch {
+                message_id,
+                position,
+            } => PersistError::CorruptMessage {
+                context: Context {
+                    position: Some(position),
+                    id: Some(message_id),
+                    ..context
+                },
+                reason: "The message doesn't match its checksum.".to_owned(),
+            },
+            file::Error::NotRetained { message_id, .. } => PersistError::Retained {
+                context: Context {
+                    id: Some(message_id),
+                    ..context
+                },
+            },
+            file::Error::SequenceGap {
+                expected,
+                found,
+                position,
+            } => PersistError::CorruptMessage {
+                context: Context {
+                    position: Some(position),
+                    id: Some(found),
+                    ..context
+                },
+                reason: format!("Expected message {}.", expected),
+            },
+            file::Error::PositionOutOfRange(position) | file::Error::NotEnoughSpace { position, .. } => {
+                PersistError::Buffer {
+                    context: Context {
+                        position: Some(position),
+                        ..context
+                    },
+                    source: err,
+                }
+            }
+            file::Error::ProcessFailed(message_id) => PersistError::Buffer {
+                context: Context {
+                    id: Some(message_id),
+                    ..context
+                },
+                source: err,
+            },
+            file::Error::FileRemoved(file_id) => PersistError::Buffer {
+                context: Context {
+                    id: Some(file_id as u64),
+                    ..context
+                },
+                source: err,
+            },
+            file::Error::NoMessage | file::Error::AlreadyExists => {
+                PersistError::Buffer { context, source: err }
+            }
+        }
+    }
+}
+
+impl From<codec::Error> for PersistError {
+    fn from(err: codec::Error) -> Self {
+        PersistError::CorruptMessage {
+            context: Context::default(),
+            reason: format!("{:?}", err),
+        }
+    }
+}
+
+impl From<Canceled> for PersistError {
+    fn from(_: Canceled) -> Self {
+        PersistError::closed()
+    }
+}
+
+/// Adds the context to the error of a result.
+pub trait ResultExt<T> {
+    /// Sets the path of the file the error happened in.
+    /// # Arguments
+    /// `path` - The path of the file.
+    fn with_path<S: Into<String>>(self, path: S) -> Result<T>;
+}
+
+impl<T, E: Into<PersistError>> ResultExt<T> for std::result::Result<T, E> {
+    fn with_path<S: Into<String>>(self, path: S) -> Result<T> {
+        self.map_err(|e| e.into().with_path(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::error::*;
+    use std::error::Error;
+
+    #[test]
+    fn conversion_test() {
+        let err: PersistError = io::Error::new(io::ErrorKind::NotFound, "missing").into();
+        let err = err.with_path("/tmp/events.1");
+        match &err {
+            PersistError::Io { source, .. } => assert_eq!(io::ErrorKind::NotFound, source.kind()),
+            e => panic!("Expected an io error: {:?}", e),
+        }
+        assert_eq!("I/O error: missing (path: /tmp/events.1)", err.to_string());
+        assert!(err.source().is_some());
+
+        let err: PersistError = file::Error::SequenceGap {
+            expected: 5,
+            found: 7,
+            position: 128,
+        }
+        .into();
+        assert_eq!(
+            "Corrupt message: Expected message 5. (position: 128, id: 7)",
+            err.to_string()
+        );
+
+        let err: PersistError = file::Error::NotEnoughSpace {
+            message_size: 64,
+            position: 32,
+            capacity: 64,
+            remaining: 32,
+        }
+        .into();
+        match &err {
+            PersistError::Buffer { context, .. } => assert_eq!(Some(32), context.position),
+            e => panic!("Expected a buffer error: {:?}", e),
+        }
+        assert!(err.source().is_some());
+
+        let err: PersistError = file::Error::FileError(io::Error::other("disk")).into();
+        assert!(matches!(err, PersistError::Io { .. }));
+        let err: PersistError = file::Error::Stopped.into();
+        assert!(matches!(err, PersistError::Closed { .. }));
+        let err: PersistError = Canceled.into();
+        assert!(matches!(err, PersistError::Closed { .. }));
+        let err: PersistError = codec::Error::Truncated {
+            needed: 8,
+            available: 4,
+        }
+        .into();
+        assert!(matches!(err, PersistError::CorruptMessage { .. }));
+
+        let err = PersistError::NotLeader {
+            context: Context::default(),
+            leader_id: Some(3),
+        }
+        .with_id(12);
+        assert_eq!("Not the leader, the leader is 3 (id: 12)", err.to_string());
+
+        let result: std::result::Result<(), file::Error> = Err(file::Error::Full);
+        let err = result.with_path("/tmp/events.2").unwrap_err();
+        assert_eq!("The file is full (path: /tmp/events.2)", err.to_string());
+        assert!(err.source().is_none());
+    }
+}
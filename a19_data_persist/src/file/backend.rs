@@ -0,0 +1,282 @@
+//! Where the message files are kept.  The files are found by their path so the code reading them
+//! doesn't need to know if they are on disk or in memory.
+//!```text
+//!                          +--> FileBackend ---------> memory mapped files
+//! FileCollection ---+      |
+//!                   +------+
+//! MessageIterator --+      |
+//!                          +--> InMemoryMessageStore -> path -> buffer
+//!```
+//! The stores in memory use the same format as the files so everything that reads a
+//! `MessageFileStoreRead` works the same for both.
+use crate::file::header::{create_commit_file, file_id_of, FileHeader, FileType, FILE_HEADER_SIZE};
+use crate::file::{MessageFileStore, MessageFileStoreRead, MessageFileStoreWrite, StoreBuffer};
+use a19_concurrent::buffer::atomic_buffer::AtomicByteBufferInt;
+use a19_core::pow2::PowOf2;
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::fs::{read_dir, remove_file};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Creates and opens the message stores by their path.
+pub trait MessageStoreBackend: Send + Sync {
+    /// Creates a new store.
+    /// # Arguments
+    /// `path` - The path of the store.
+    /// `file_size` - The size of the store.
+    /// # Returns
+    /// The read and writers for the store.
+    fn create(
+        &self,
+        path: &Path,
+        file_size: usize,
+    ) -> io::Result<(MessageFileStoreRead, MessageFileStoreWrite)>;
+
+    /// Opens a store that already exists.
+    /// # Arguments
+    /// `path` - The path of the store.
+    fn open(&self, path: &Path) -> io::Result<(MessageFileStoreRead, MessageFileStoreWrite)>;
+
+    /// Opens a store to read.
+    /// # Arguments
+    /// `path` - The path of the store.
+    fn open_readonly(&self, path: &Path) -> io::Result<MessageFileStoreRead>;
+
+    /// Opens a store to write to and creates it if it doesn't exist.
+    /// # Arguments
+    /// `path` - The path of the store.
+    /// `file_size` - The size of the store if it needs to be created.
+    fn open_write(
+        &self,
+        path: &Path,
+        file_size: usize,
+    ) -> io::Result<(MessageFileStoreRead, MessageFileStoreWrite)> {
+        if self.exists(path) {
+            self.open(path)
+        } else {
+            self.create(path, file_size)
+        }
+    }
+
+    /// Creates a commit file.  The commit files don't have messages in them so they are a plain
+    /// buffer.
+    /// # Arguments
+    /// `path` - The path of the commit file.
+    /// `file_size` - The size for the terms.  The header is added in front of them.
+    fn create_commit(&self, path: &Path, file_size: usize) -> io::Result<StoreBuffer>;
+
+    /// True if there is a store at the path.
+    /// # Arguments
+    /// `path` - The path of the store.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Removes a store.  The readers that already have it open can keep reading it.
+    /// # Arguments
+    /// `path` - The path of the store.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists the stores in a directory.
+    /// # Arguments
+    /// `directory` - The directory to list.
+    /// # Returns
+    /// The paths of the stores sorted by the path.
+    fn list(&self, directory: &Path) -> io::Result<Vec<PathBuf>>;
+}
+
+/// Keeps the stores in memory mapped files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileBackend;
+
+impl MessageStoreBackend for FileBackend {
+    fn create(
+        &self,
+        path: &Path,
+        file_size: usize,
+    ) -> io::Result<(MessageFileStoreRead, MessageFileStoreWrite)> {
+        unsafe { MessageFileStore::new(&path, file_size) }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<(MessageFileStoreRead, MessageFileStoreWrite)> {
+        unsafe { MessageFileStore::open(&path) }
+    }
+
+    fn open_readonly(&self, path: &Path) -> io::Result<MessageFileStoreRead> {
+        unsafe { MessageFileStore::open_readonly(&path) }
+    }
+
+    fn create_commit(&self, path: &Path, file_size: usize) -> io::Result<StoreBuffer> {
+        let buffer = unsafe { create_commit_file(&path, file_size)? };
+        Ok(StoreBuffer::Mapped(buffer))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        remove_file(path)
+    }
+
+    fn list(&self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in read_dir(directory)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+/// Keeps the stores in memory.  Nothing touches the file system so it is useful for tests and for
+/// streams that don't need to survive a restart.  The stores are lost when it is dropped.
+#[derive(Default)]
+pub struct InMemoryMessageStore {
+    /// The stores by their path.
+    stores: Mutex<HashMap<PathBuf, Arc<UnsafeCell<MessageFileStore>>>>,
+}
+
+unsafe impl Send for InMemoryMessageStore {}
+unsafe impl Sync for InMemoryMessageStore {}
+
+impl InMemoryMessageStore {
+    /// Creates an empty set of stores.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The number of stores.
+    pub fn len(&self) -> usize {
+        self.stores.lock().unwrap().len()
+    }
+
+    /// True if there aren't any stores.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the store at a path.
+    /// # Arguments
+    /// `path` - The path of the store.
+    fn get(&self, path: &Path) -> io::Result<Arc<UnsafeCell<MessageFileStore>>> {
+        match self.stores.lock().unwrap().get(path) {
+            Some(store) => Ok(store.clone()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No store at {}", path.display()),
+            )),
+        }
+    }
+}
+
+impl MessageStoreBackend for InMemoryMessageStore {
+    /// Creates a new store.  A store already at the path is replaced.
+    fn create(
+        &self,
+        path: &Path,
+        file_size: usize,
+    ) -> io::Result<(MessageFileStoreRead, MessageFileStoreWrite)> {
+        let (read, write) = MessageFileStore::new_in_memory(file_size);
+        self.stores
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), read.store.clone());
+        Ok((read, write))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<(MessageFileStoreRead, MessageFileStoreWrite)> {
+        let store = self.get(path)?;
+        Ok((
+            MessageFileStoreRead {
+                store: store.clone(),
+            },
+            MessageFileStoreWrite { store },
+        ))
+    }
+
+    fn open_readonly(&self, path: &Path) -> io::Result<MessageFileStoreRead> {
+        Ok(MessageFileStoreRead {
+            store: self.get(path)?,
+        })
+    }
+
+    /// Creates a commit file in memory.  Only the commit stream it is given to can see it.
+    fn create_commit(&self, path: &Path, file_size: usize) -> io::Result<StoreBuffer> {
+        let file_size = file_size.round_to_power_of_two();
+        let mut buffer = AtomicByteBufferInt::new(FILE_HEADER_SIZE + file_size);
+        FileHeader::new(FileType::Commit, file_id_of(&path), file_size).write(&mut buffer);
+        Ok(StoreBuffer::Memory(buffer))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.stores.lock().unwrap().contains_key(path)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        match self.stores.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No store at {}", path.display()),
+            )),
+        }
+    }
+
+    fn list(&self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = self
+            .stores
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|path| path.parent() == Some(directory))
+            .cloned()
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::backend::*;
+
+    #[test]
+    pub fn in_memory_test() {
+        let stores = InMemoryMessageStore::new();
+        let directory = Path::new("/memory/events");
+        let first = directory.join("orders.events.1");
+        let second = directory.join("orders.events.2");
+        assert!(stores.open(&first).is_err());
+        let (_, write) = stores.open_write(&first, 2048).unwrap();
+        write.write(0, 1, 1, &[4, 5, 6]).unwrap();
+        assert!(stores.exists(&first));
+
+        let (read, write) = stores.create(&first, 2048).unwrap();
+        write.write(0, 1, 1, &[1, 2, 3]).unwrap();
+        stores.create(&second, 2048).unwrap();
+        stores
+            .create(&Path::new("/memory/other").join("orders.events.1"), 2048)
+            .unwrap();
+        assert_eq!(3, stores.len());
+        assert_eq!(
+            vec![first.clone(), second.clone()],
+            stores.list(directory).unwrap()
+        );
+
+        // The stores opened again share the memory.
+        let again = stores.open_readonly(&first).unwrap();
+        assert_eq!(&[1, 2, 3], again.read_new(0).unwrap().bytes());
+
+        stores.remove(&first).unwrap();
+        assert!(!stores.exists(&first));
+        assert!(stores.remove(&first).is_err());
+        assert_eq!(vec![second], stores.list(directory).unwrap());
+        // The reader that has it open can still read it.
+        assert_eq!(1, read.read_new(0).unwrap().message_id());
+    }
+}
@@ -0,0 +1,286 @@
+//! Messages too big for an event file are split into chunks.  Each chunk is written as its own
+//! frame with the chunk message type and all of the frames share the id of the message so they are
+//! copied and replicated like any other frame.  The chunk header is at the start of the body.
+//!```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Message Type of the message                                   |
+//! +---------------------------------------------------------------+ 32
+//! | Chunk Index                                                   |
+//! +---------------------------------------------------------------+ 64
+//! | Chunk Count                                                   |
+//! +---------------------------------------------------------------+ 96
+//! | Not used                                                      |
+//! +---------------------------------------------------------------+ 128
+//! | Total Length of the message                                   |
+//! |                                                               |
+//! +---------------------------------------------------------------+ 192
+//! |                   Chunk Body                                  ...
+//! ...                                                             |
+//! +---------------------------------------------------------------+
+//!```
+//! The chunks can span files.  When a chunk doesn't fit in a file the file is ended and the chunk
+//! is the first frame in the next one.
+use crate::file::{
+    Error, MessageFileStore, MessageFileStoreRead, MessageRead, MessageStore, ALIGNMENT,
+    CHECKSUM_SIZE, HEADER_SIZE,
+};
+use a19_core::pow2::PowOf2;
+use byteorder::{BigEndian, ByteOrder};
+use std::io;
+use std::sync::Arc;
+
+/// The message type of a frame holding a chunk of a message.
+pub const CHUNK_MESSAGE_TYPE: i32 = -4;
+/// The size of the chunk header at the start of the body.
+pub const CHUNK_HEADER_SIZE: usize = 24;
+/// The most bytes a chunk frame takes up in a file.
+const MAX_CHUNK_FRAME_SIZE: usize = 0x10000;
+
+/// The header of a chunk of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    /// The type of the message the chunk is a part of.
+    pub msg_type_id: i32,
+    /// The index of the chunk starting at 0.
+    pub index: u32,
+    /// The number of chunks in the message.
+    pub count: u32,
+    /// The length of the body of the whole message.
+    pub total_len: u64,
+}
+
+impl ChunkHeader {
+    /// Reads the chunk header from the start of the body of a frame.
+    /// # Arguments
+    /// `bytes` - The body of the frame.
+    /// # Returns
+    /// `None` if the body is too small to have a header.
+    pub fn read(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < CHUNK_HEADER_SIZE {
+            None
+        } else {
+            Some(ChunkHeader {
+                msg_type_id: BigEndian::read_i32(&bytes[0..4]),
+                index: BigEndian::read_u32(&bytes[4..8]),
+                count: BigEndian::read_u32(&bytes[8..12]),
+                total_len: BigEndian::read_u64(&bytes[16..24]),
+            })
+        }
+    }
+
+    /// Writes the chunk header to the start of the body of a frame.
+    /// # Arguments
+    /// `bytes` - The body of the frame.
+    pub fn write(&self, bytes: &mut [u8]) {
+        BigEndian::write_i32(&mut bytes[0..4], self.msg_type_id);
+        BigEndian::write_u32(&mut bytes[4..8], self.index);
+        BigEndian::write_u32(&mut bytes[8..12], self.count);
+        BigEndian::write_u32(&mut bytes[12..16], 0);
+        BigEndian::write_u64(&mut bytes[16..24], self.total_len);
+    }
+
+    /// True if this is the last chunk of the message.
+    #[inline]
+    pub fn is_last(&self) -> bool {
+        self.index + 1 >= self.count
+    }
+}
+
+/// The number of bytes of the message put in each chunk.  A chunk frame takes up at most a quarter
+/// of a file so not much of a file is left empty when a chunk doesn't fit at the end.
+/// # Arguments
+/// `file_size` - The size of the event files.
+pub fn chunk_size(file_size: usize) -> usize {
+    let frame = (file_size / 4)
+        .min(MAX_CHUNK_FRAME_SIZE)
+        .align_down(ALIGNMENT);
+    frame
+        .saturating_sub(HEADER_SIZE + CHECKSUM_SIZE + CHUNK_HEADER_SIZE)
+        .max(ALIGNMENT)
+}
+
+/// The number of chunks needed for a message.  Always at least 1.
+/// # Arguments
+/// `total_len` - The length of the body of the message.
+/// `chunk_size` - The number of bytes of the message in each chunk.
+pub fn chunk_count(total_len: u64, chunk_size: usize) -> u32 {
+    let chunk_size = chunk_size as u64;
+    total_len.div_ceil(chunk_size).max(1) as u32
+}
+
+/// The file the chunk being read is in.
+enum ChunkFile<'a> {
+    /// The file the first chunk was read from.
+    Store(&'a MessageFileStore),
+    /// One of the next files.
+    Next(Arc<MessageFileStoreRead>),
+}
+
+impl<'a> ChunkFile<'a> {
+    fn read_new(&self, pos: usize) -> crate::file::Result<MessageRead<'a>> {
+        match self {
+            ChunkFile::Store(store) => store.read_new(pos),
+            ChunkFile::Next(reader) => reader.read_new(pos),
+        }
+    }
+}
+
+/// Reads the body of a message.  A message in a single frame is read from the frame and a chunked
+/// message is stitched back together from its chunks.
+pub struct BodyReader<'a> {
+    file: ChunkFile<'a>,
+    message_id: u64,
+    /// The header of the chunk being read.  `None` if the message is in a single frame.
+    chunk: Option<ChunkHeader>,
+    /// The bytes of the chunk that haven't been read.
+    remaining: &'a [u8],
+    /// The position of the frame after the chunk being read.
+    next_pos: usize,
+    /// Opens the next file when the chunks continue into it.
+    next_file: Option<Box<dyn FnMut() -> Option<Arc<MessageFileStoreRead>> + 'a>>,
+}
+
+impl<'a> BodyReader<'a> {
+    /// Creates the reader for a message.
+    /// # Arguments
+    /// `store` - The file the message was read from.
+    /// `msg` - The message to read the body of.
+    pub(crate) fn new(store: &'a MessageFileStore, msg: &MessageRead<'a>) -> Self {
+        let chunk = msg.chunk();
+        BodyReader {
+            file: ChunkFile::Store(store),
+            message_id: msg.message_id(),
+            chunk,
+            remaining: match chunk {
+                Some(_) => &msg.bytes()[CHUNK_HEADER_SIZE..],
+                None => msg.bytes(),
+            },
+            next_pos: msg.next_pos(),
+            next_file: None,
+        }
+    }
+
+    /// Sets how to open the next file when the chunks continue past the end of the file.  Called
+    /// once for each file after the first.
+    /// # Arguments
+    /// `next_file` - Opens the file after the last one opened.
+    pub fn with_next_file<F>(mut self, next_file: F) -> Self
+    where
+        F: FnMut() -> Option<Arc<MessageFileStoreRead>> + 'a,
+    {
+        self.next_file = Some(Box::new(next_file));
+        self
+    }
+
+    /// The length of the body of the message.
+    pub fn total_len(&self) -> u64 {
+        match &self.chunk {
+            Some(chunk) => chunk.total_len,
+            None => self.remaining.len() as u64,
+        }
+    }
+
+    /// Moves onto the chunk after the one that was read.
+    /// # Arguments
+    /// `previous` - The header of the chunk that was read.
+    fn next_chunk(&mut self, previous: ChunkHeader) -> io::Result<()> {
+        loop {
+            let msg = match self.file.read_new(self.next_pos) {
+                Ok(msg) if msg.message_id() != u64::MAX => Some(msg),
+                Ok(_) | Err(Error::Full) | Err(Error::PositionOutOfRange(_)) => None,
+                Err(Error::NoMessage) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The next chunk hasn't been written.",
+                    ))
+                }
+                Err(e) => return Err(io::Error::other(e)),
+            };
+            match msg {
+                Some(msg) => {
+                    return match msg.chunk() {
+                        Some(chunk)
+                            if msg.message_id() == self.message_id
+                                && chunk.index == previous.index + 1 =>
+                        {
+                            self.remaining = &msg.bytes()[CHUNK_HEADER_SIZE..];
+                            self.next_pos = msg.next_pos();
+                            self.chunk = Some(chunk);
+                            Ok(())
+                        }
+                        _ => Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Expected chunk {} of message {} but found message {}.",
+                                previous.index + 1,
+                                self.message_id,
+                                msg.message_id()
+                            ),
+                        )),
+                    };
+                }
+                None => {
+                    // The rest of the chunks are in the next file.
+                    let next = self.next_file.as_mut().and_then(|next_file| next_file());
+                    match next {
+                        Some(reader) => {
+                            self.file = ChunkFile::Next(reader);
+                            self.next_pos = 0;
+                        }
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "The message continues in the next file.",
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a> io::Read for BodyReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.remaining.is_empty() || buf.is_empty() {
+                let length = self.remaining.len().min(buf.len());
+                buf[..length].copy_from_slice(&self.remaining[..length]);
+                self.remaining = &self.remaining[length..];
+                return Ok(length);
+            }
+            match self.chunk {
+                Some(chunk) if !chunk.is_last() => self.next_chunk(chunk)?,
+                _ => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::chunk::*;
+
+    #[test]
+    pub fn chunk_header_test() {
+        let header = ChunkHeader {
+            msg_type_id: 7,
+            index: 2,
+            count: 3,
+            total_len: 0x1_0000_0001,
+        };
+        let mut bytes = [0; CHUNK_HEADER_SIZE];
+        header.write(&mut bytes);
+        assert_eq!(Some(header), ChunkHeader::read(&bytes));
+        assert!(header.is_last());
+        assert_eq!(None, ChunkHeader::read(&bytes[..4]));
+
+        assert_eq!(0x4000 - 44, chunk_size(0x10000));
+        assert_eq!(1, chunk_count(0, 100));
+        assert_eq!(1, chunk_count(100, 100));
+        assert_eq!(2, chunk_count(101, 100));
+    }
+}
@@ -0,0 +1,214 @@
+//! Writes the event files with `O_DIRECT` so the messages skip the page cache.  The reads still go
+//! through the memory map.  Direct I/O needs the memory, the offset and the length of a write to
+//! be multiples of the block size so the messages are copied into an aligned staging buffer that
+//! starts at the block the next message is in.
+//!```text
+//!  block_start          position                end
+//!  |                    |                       |
+//!  +--------------------+-----------------------+-----------+
+//!  | written messages   | new message           | zeros     |
+//!  +--------------------+-----------------------+-----------+
+//!  |<------------------ staging buffer (whole blocks) ------>|
+//!```
+//! The blocks after the first one are written before the first block so the size of the message
+//! is the last thing to reach the file, the same as the memory mapped writer.  The block with the
+//! end of the message is kept in the staging buffer since the next message goes into it.
+//!
+//! Only linux with the `direct-io` feature supports it.  Everywhere else opening the writer
+//! returns an `Unsupported` error.
+use std::io;
+
+#[cfg(all(target_os = "linux", feature = "direct-io"))]
+pub(crate) use self::linux::DirectWriter;
+
+/// Checks the block size is one a device can use.
+/// # Arguments
+/// `block_size` - The block size of the device.
+pub(crate) fn check_block_size(block_size: usize) -> io::Result<()> {
+    if !block_size.is_power_of_two() || block_size < MIN_BLOCK_SIZE {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The block size {} needs to be a power of 2 of at least {}.",
+                block_size, MIN_BLOCK_SIZE
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The smallest block size a device uses.
+const MIN_BLOCK_SIZE: usize = 512;
+
+/// The most bytes written at once when filling in a section of the file.
+#[cfg(all(target_os = "linux", feature = "direct-io"))]
+const FILL_CHUNK: usize = 0x100000;
+
+#[cfg(all(target_os = "linux", feature = "direct-io"))]
+mod linux {
+    use crate::file::direct::{check_block_size, FILL_CHUNK};
+    use a19_concurrent::buffer::aligned::AlignedBuffer;
+    use a19_core::pow2::PowOf2;
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::unix::fs::{FileExt, OpenOptionsExt};
+    use std::path::Path;
+
+    /// Writes the messages to a file opened with `O_DIRECT`.
+    #[derive(Debug)]
+    pub(crate) struct DirectWriter {
+        file: File,
+        block_size: usize,
+        /// The blocks being written.  The first block is the one at `staging_pos`.
+        staging: AlignedBuffer,
+        /// The position in the file of the start of the staging buffer.
+        staging_pos: usize,
+    }
+
+    impl DirectWriter {
+        /// Opens a file to write to with direct I/O.
+        /// # Arguments
+        /// `path` - The path of the file.  Needs to exist.
+        /// `block_size` - The block size of the device.  A power of 2 of at least 512.
+        pub(crate) fn open<P: AsRef<Path>>(path: &P, block_size: usize) -> io::Result<Self> {
+            check_block_size(block_size)?;
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(path)?;
+            let mut staging = AlignedBuffer::new(block_size, block_size);
+            // The file could already have messages at the start.
+            file.read_exact_at(&mut staging[..block_size], 0)?;
+            Ok(DirectWriter {
+                file,
+                block_size,
+                staging,
+                staging_pos: 0,
+            })
+        }
+
+        /// Writes a section of the file.  The section is zeroed before it is filled in.
+        /// # Arguments
+        /// `position` - The position to write at.
+        /// `length` - The number of bytes to write.
+        /// `fill` - Fills in the bytes of the section.
+        pub(crate) fn write<F>(&mut self, position: usize, length: usize, fill: F) -> io::Result<()>
+        where
+            F: FnOnce(&mut [u8]),
+        {
+            let block_start = position.align_down(self.block_size);
+            if block_start != self.staging_pos {
+                // Only happens when the writer skips around so read in what is already there.
+                self.file
+                    .read_exact_at(&mut self.staging[..self.block_size], block_start as u64)?;
+                self.staging_pos = block_start;
+            }
+            let end = position + length;
+            let blocks = end.align_up(self.block_size) - block_start;
+            if blocks > self.staging.capacity() {
+                let mut staging = AlignedBuffer::new(blocks, self.block_size);
+                staging[..self.block_size].copy_from_slice(&self.staging[..self.block_size]);
+                self.staging = staging;
+            }
+            let offset = position - block_start;
+            for b in self.staging[offset..blocks].iter_mut() {
+                *b = 0;
+            }
+            fill(&mut self.staging[offset..offset + length]);
+
+            if blocks > self.block_size {
+                self.file.write_all_at(
+                    &self.staging[self.block_size..blocks],
+                    (block_start + self.block_size) as u64,
+                )?;
+            }
+            self.file
+                .write_all_at(&self.staging[..self.block_size], block_start as u64)?;
+
+            // Keep the block the next write starts in.
+            let next_start = end.align_down(self.block_size);
+            let keep = next_start - block_start;
+            if keep > 0 {
+                if keep < blocks {
+                    self.staging.copy_within(keep..keep + self.block_size, 0);
+                } else {
+                    for b in self.staging[..self.block_size].iter_mut() {
+                        *b = 0;
+                    }
+                }
+                self.staging_pos = next_start;
+            }
+            Ok(())
+        }
+
+        /// Sets a section of the file to a value.
+        /// # Arguments
+        /// `position` - The position to start at.
+        /// `length` - The number of bytes to set.
+        /// `value` - The value to set the bytes to.
+        pub(crate) fn fill(&mut self, position: usize, length: usize, value: u8) -> io::Result<()> {
+            let end = position + length;
+            let mut pos = position;
+            while pos < end {
+                let next = (pos + 1).align_up(FILL_CHUNK).min(end);
+                self.write(pos, next - pos, |bytes| {
+                    for b in bytes.iter_mut() {
+                        *b = value;
+                    }
+                })?;
+                pos = next;
+            }
+            Ok(())
+        }
+
+        /// Waits for the writes to be stored on the device.
+        pub(crate) fn flush(&self) -> io::Result<()> {
+            self.file.sync_data()
+        }
+
+        /// The file opened for direct I/O.
+        pub(crate) fn file(&self) -> &File {
+            &self.file
+        }
+    }
+}
+
+/// Stands in for the writer where direct I/O isn't supported.  Can't be created.
+#[cfg(not(all(target_os = "linux", feature = "direct-io")))]
+#[derive(Debug)]
+pub(crate) enum DirectWriter {}
+
+#[cfg(not(all(target_os = "linux", feature = "direct-io")))]
+impl DirectWriter {
+    pub(crate) fn open<P: AsRef<std::path::Path>>(
+        _path: &P,
+        block_size: usize,
+    ) -> io::Result<Self> {
+        check_block_size(block_size)?;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Direct I/O needs linux and the `direct-io` feature.",
+        ))
+    }
+
+    pub(crate) fn write<F>(&mut self, _position: usize, _length: usize, _fill: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut [u8]),
+    {
+        match *self {}
+    }
+
+    pub(crate) fn fill(&mut self, _position: usize, _length: usize, _value: u8) -> io::Result<()> {
+        match *self {}
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        match *self {}
+    }
+
+    pub(crate) fn file(&self) -> &std::fs::File {
+        match *self {}
+    }
+}
@@ -0,0 +1,233 @@
+//! Filters applied to the message headers while replaying a file.  The filter only looks at the
+//! header so a message that is skipped never has its body touched.
+use crate::file::{MessageHeaders, MessageId, MessageRead, MessageTypeId};
+
+/// A small set kept in a sorted vector.  Faster than a hash set for the handful of values we
+/// normally filter on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SmallSet<T: Ord + Copy> {
+    values: Vec<T>,
+}
+
+impl<T: Ord + Copy> SmallSet<T> {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        SmallSet { values: Vec::new() }
+    }
+
+    /// Adds a value to the set.
+    /// # Arguments
+    /// `value` - The value to add.
+    /// # returns
+    /// true if the value wasn't in the set.
+    pub fn insert(&mut self, value: T) -> bool {
+        match self.values.binary_search(&value) {
+            Ok(_) => false,
+            Err(pos) => {
+                self.values.insert(pos, value);
+                true
+            }
+        }
+    }
+
+    /// Checks to see if the value is in the set.
+    /// # Arguments
+    /// `value` - The value to look for.
+    #[inline]
+    pub fn contains(&self, value: &T) -> bool {
+        self.values.binary_search(value).is_ok()
+    }
+
+    /// The number of values in the set.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// True if the set doesn't have any values.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl<T: Ord + Copy> From<&[T]> for SmallSet<T> {
+    fn from(values: &[T]) -> Self {
+        let mut set = SmallSet::new();
+        for v in values {
+            set.insert(*v);
+        }
+        set
+    }
+}
+
+/// The header of a message without the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageHeaderView {
+    pub message_id: MessageId,
+    pub msg_type_id: MessageTypeId,
+    /// The user metadata of the message.
+    pub headers: MessageHeaders,
+    /// The size of the body.
+    pub size: usize,
+    /// The position of the message in the file.
+    pub position: usize,
+}
+
+impl MessageHeaderView {
+    /// Creates the header view for a message.
+    /// # Arguments
+    /// `msg` - The message that was read in.
+    /// `position` - The position the message was read from.
+    pub fn new(msg: &MessageRead<'_>, position: usize) -> Self {
+        MessageHeaderView {
+            message_id: msg.message_id(),
+            msg_type_id: msg.msg_type_id(),
+            headers: msg.headers(),
+            size: msg.bytes().len(),
+            position,
+        }
+    }
+}
+
+/// Checks the header of a message to see if it should be returned.
+pub type HeaderPredicate = Box<dyn Fn(&MessageHeaderView) -> bool + Send + Sync>;
+
+/// Used to select the messages to return when reading.  All of the conditions need to match.
+pub struct MessageFilter {
+    /// The message types to return.  `None` returns all of the types.
+    pub types: Option<SmallSet<u16>>,
+    /// The tenants to return.  `None` returns all of the tenants.
+    pub tenants: Option<SmallSet<u32>>,
+    /// The smallest message id to return.
+    pub min_id: MessageId,
+    /// The largest message id to return.
+    pub max_id: MessageId,
+    /// Checked last against the header of the message.
+    pub predicate: Option<HeaderPredicate>,
+}
+
+impl Default for MessageFilter {
+    fn default() -> Self {
+        MessageFilter {
+            types: None,
+            tenants: None,
+            min_id: 0,
+            max_id: MessageId::MAX,
+            predicate: None,
+        }
+    }
+}
+
+impl MessageFilter {
+    /// Creates a filter that only returns the specified message types.
+    /// # Arguments
+    /// `types` - The message types to return.
+    pub fn of_types(types: &[u16]) -> Self {
+        MessageFilter {
+            types: Some(SmallSet::from(types)),
+            ..Default::default()
+        }
+    }
+
+    /// Only returns the messages for the tenants.
+    /// # Arguments
+    /// `tenants` - The tenants to return.
+    pub fn with_tenants(mut self, tenants: &[u32]) -> Self {
+        self.tenants = Some(SmallSet::from(tenants));
+        self
+    }
+
+    /// Sets the range of message ids to return.
+    /// # Arguments
+    /// `min_id` - The smallest message id to return.
+    /// `max_id` - The largest message id to return.
+    pub fn with_range(mut self, min_id: MessageId, max_id: MessageId) -> Self {
+        self.min_id = min_id;
+        self.max_id = max_id;
+        self
+    }
+
+    /// Sets the predicate to check the header against.
+    /// # Arguments
+    /// `predicate` - Returns true if the message should be returned.
+    pub fn with_predicate<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&MessageHeaderView) -> bool + Send + Sync + 'static,
+    {
+        self.predicate = Some(Box::new(predicate));
+        self
+    }
+
+    /// Checks to see if we are past the messages the filter could return so the reader can stop.
+    /// # Arguments
+    /// `message_id` - The id of the message that was read in.
+    #[inline]
+    pub fn is_past_end(&self, message_id: MessageId) -> bool {
+        message_id > self.max_id
+    }
+
+    /// Checks to see if the message should be returned.
+    /// # Arguments
+    /// `header` - The header of the message.
+    pub fn matches(&self, header: &MessageHeaderView) -> bool {
+        if header.message_id < self.min_id || header.message_id > self.max_id {
+            return false;
+        }
+        if let Some(types) = &self.types {
+            if header.msg_type_id < 0
+                || header.msg_type_id > u16::MAX as i32
+                || !types.contains(&(header.msg_type_id as u16))
+            {
+                return false;
+            }
+        }
+        if let Some(tenants) = &self.tenants {
+            if !tenants.contains(&header.headers.tenant) {
+                return false;
+            }
+        }
+        match &self.predicate {
+            Some(predicate) => predicate(header),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::file::filter::*;
+
+    fn header(message_id: u64, msg_type_id: i32) -> MessageHeaderView {
+        MessageHeaderView {
+            message_id,
+            msg_type_id,
+            headers: MessageHeaders::default(),
+            size: 8,
+            position: 0,
+        }
+    }
+
+    #[test]
+    fn filter_matches_test() {
+        let filter = MessageFilter::of_types(&[3, 1, 3])
+            .with_range(2, 10)
+            .with_predicate(|h| h.message_id % 2 == 0);
+        assert_eq!(2, filter.types.as_ref().unwrap().len());
+        assert!(filter.matches(&header(2, 1)));
+        assert!(filter.matches(&header(4, 3)));
+        assert!(!filter.matches(&header(4, 2)));
+        assert!(!filter.matches(&header(3, 1)));
+        assert!(!filter.matches(&header(12, 1)));
+        assert!(!filter.matches(&header(2, -1)));
+        assert!(filter.is_past_end(11));
+        assert!(MessageFilter::default().matches(&header(1, -1)));
+
+        let filter = MessageFilter::default().with_tenants(&[7, 9]);
+        let mut tenant = header(1, 1);
+        assert!(!filter.matches(&tenant));
+        tenant.headers.tenant = 9;
+        assert!(filter.matches(&tenant));
+        tenant.headers.tenant = 8;
+        assert!(!filter.matches(&tenant));
+    }
+}
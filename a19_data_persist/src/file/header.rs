@@ -0,0 +1,402 @@
+//! The header at the start of the event and commit files.  It is checked when a file is opened so
+//! a file that isn't ours or is from a format we can't read is refused instead of being read as
+//! garbage.  The messages and terms start after it, the positions used to read and write them don't
+//! count the header.
+//!```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Magic "A19STORE"                                              |
+//! |                                                               | 64
+//! +---------------------------------------------------------------+
+//! | Format Version                                                | 96
+//! +---------------------------------------------------------------+
+//! | File Type (1 events, 2 commit, 3 raft meta)                   | 128
+//! +---------------------------------------------------------------+
+//! | File Id                                                       | 160
+//! +---------------------------------------------------------------+
+//! | Reserved                                                      | 192
+//! +---------------------------------------------------------------+
+//! | Created At (milliseconds since the epoch)                     |
+//! |                                                               | 256
+//! +---------------------------------------------------------------+
+//! | Max File Size (without the header)                            |
+//! |                                                               | 320
+//! +---------------------------------------------------------------+
+//! | Zeros                                                        ...
+//! ...                                                             | 4096
+//! +---------------------------------------------------------------+
+//!```
+use crate::file::Error;
+use a19_concurrent::buffer::mmap_buffer::MemoryMappedInt;
+use a19_concurrent::buffer::DirectByteBuffer;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The size of the header at the start of a file.
+pub const FILE_HEADER_SIZE: usize = 512;
+/// The bytes every file starts with.
+pub const FILE_MAGIC: [u8; 8] = *b"A19STORE";
+/// The version of the file format written.
+pub const FORMAT_VERSION: u32 = 1;
+
+const MAGIC: usize = 0;
+const VERSION: usize = 8;
+const FILE_TYPE: usize = 12;
+const FILE_ID: usize = 16;
+const CREATED_AT: usize = 24;
+const MAX_FILE_SIZE: usize = 32;
+
+/// What is kept in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    /// The messages.
+    Event,
+    /// The terms that have been committed.
+    Commit,
+    /// The current raft term and who we voted for.
+    Meta,
+}
+
+impl FileType {
+    /// The value stored in the header.
+    fn to_u32(self) -> u32 {
+        match self {
+            FileType::Event => 1,
+            FileType::Commit => 2,
+            FileType::Meta => 3,
+        }
+    }
+
+    /// Gets the file type from the value stored in the header.
+    /// # Arguments
+    /// `value` - The stored value.
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(FileType::Event),
+            2 => Some(FileType::Commit),
+            3 => Some(FileType::Meta),
+            _ => None,
+        }
+    }
+}
+
+/// The header of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    /// The version of the format the file was written with.
+    pub version: u32,
+    /// What is kept in the file.
+    pub file_type: FileType,
+    /// The id of the file.  Is the number at the end of the file name.
+    pub file_id: u32,
+    /// When the file was created in milliseconds since the epoch.
+    pub created_at: u64,
+    /// The size the file was created with not counting the header.
+    pub max_file_size: u64,
+}
+
+impl FileHeader {
+    /// Creates the header for a new file.
+    /// # Arguments
+    /// `file_type` - What is kept in the file.
+    /// `file_id` - The id of the file.
+    /// `max_file_size` - The size of the file not counting the header.
+    pub fn new(file_type: FileType, file_id: u32, max_file_size: usize) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        FileHeader {
+            version: FORMAT_VERSION,
+            file_type,
+            file_id,
+            created_at,
+            max_file_size: max_file_size as u64,
+        }
+    }
+
+    /// Writes the header to the start of a buffer.
+    /// # Arguments
+    /// `buffer` - The buffer of the file.
+    pub fn write<B: DirectByteBuffer + ?Sized>(&self, buffer: &mut B) {
+        buffer.set_bytes(0, FILE_HEADER_SIZE, 0);
+        buffer.write_bytes(MAGIC, &FILE_MAGIC);
+        buffer.put_u32(VERSION, self.version);
+        buffer.put_u32(FILE_TYPE, self.file_type.to_u32());
+        buffer.put_u32(FILE_ID, self.file_id);
+        buffer.put_u64(CREATED_AT, self.created_at);
+        buffer.put_u64(MAX_FILE_SIZE, self.max_file_size);
+    }
+
+    /// Reads the header from the start of a buffer.
+    /// # Arguments
+    /// `buffer` - The buffer of the file.
+    /// # Returns
+    /// The header or `InvalidHeader` saying what is wrong with it.
+    pub fn read<B: DirectByteBuffer + ?Sized>(buffer: &B) -> crate::file::Result<Self> {
+        FileHeader::from_bytes(buffer.get_bytes(0, buffer.capacity().min(FILE_HEADER_SIZE)))
+    }
+
+    /// Reads the header from the bytes at the start of a file.
+    /// # Arguments
+    /// `bytes` - The start of the file.  Only the first `FILE_HEADER_SIZE` bytes are read.
+    pub fn from_bytes(bytes: &[u8]) -> crate::file::Result<Self> {
+        if bytes.len() < FILE_HEADER_SIZE {
+            return Err(Error::InvalidHeader(format!(
+                "The file is {} bytes which is smaller than the {} byte header.",
+                bytes.len(),
+                FILE_HEADER_SIZE
+            )));
+        }
+        if bytes[MAGIC..MAGIC + FILE_MAGIC.len()] != FILE_MAGIC {
+            return Err(Error::InvalidHeader(
+                "The file doesn't start with the magic bytes.  It isn't a store file or was \
+                 written before the files had a header."
+                    .to_owned(),
+            ));
+        }
+        let read_u32 = |pos: usize| {
+            u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+        };
+        let read_u64 = |pos: usize| (read_u32(pos) as u64) << 32 | read_u32(pos + 4) as u64;
+        let version = read_u32(VERSION);
+        if version != FORMAT_VERSION {
+            return Err(Error::InvalidHeader(format!(
+                "The format version {} isn't supported, only version {} can be read.",
+                version, FORMAT_VERSION
+            )));
+        }
+        let file_type = match FileType::from_u32(read_u32(FILE_TYPE)) {
+            Some(file_type) => file_type,
+            None => {
+                return Err(Error::InvalidHeader(format!(
+                    "The file type {} isn't known.",
+                    read_u32(FILE_TYPE)
+                )))
+            }
+        };
+        Ok(FileHeader {
+            version,
+            file_type,
+            file_id: read_u32(FILE_ID),
+            created_at: read_u64(CREATED_AT),
+            max_file_size: read_u64(MAX_FILE_SIZE),
+        })
+    }
+
+    /// Checks the file has what we expect in it.
+    /// # Arguments
+    /// `file_type` - What should be kept in the file.
+    pub fn expect(self, file_type: FileType) -> crate::file::Result<Self> {
+        if self.file_type == file_type {
+            Ok(self)
+        } else {
+            Err(Error::InvalidHeader(format!(
+                "Expected a {:?} file but the header is for a {:?} file.",
+                file_type, self.file_type
+            )))
+        }
+    }
+}
+
+/// Turns a bad header into an io error so it can be returned when opening a file.  The
+/// `InvalidHeader` error is kept as the source.
+pub(crate) fn header_error(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Gets the `InvalidHeader` reason out of an io error returned when opening a file.
+/// # Arguments
+/// `e` - The error returned.
+/// # Returns
+/// The reason the header isn't valid or `None` if the error is for something else.
+pub fn invalid_header(e: &io::Error) -> Option<&str> {
+    match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(Error::InvalidHeader(reason)) => Some(reason),
+        _ => None,
+    }
+}
+
+/// Writes the header to a file that was just created or checks the header of one that already has
+/// it.  A file that doesn't have anything at the start is taken as new.
+/// # Arguments
+/// `buffer` - The buffer of the file.
+/// `file_type` - What is kept in the file.
+/// `file_id` - The id of the file.
+/// `max_file_size` - The size of the file not counting the header.
+pub(crate) fn init_header<B: DirectByteBuffer + ?Sized>(
+    buffer: &mut B,
+    file_type: FileType,
+    file_id: u32,
+    max_file_size: usize,
+) -> io::Result<FileHeader> {
+    if buffer.capacity() >= FILE_HEADER_SIZE
+        && buffer
+            .get_bytes(0, FILE_HEADER_SIZE)
+            .iter()
+            .all(|b| *b == 0)
+    {
+        let header = FileHeader::new(file_type, file_id, max_file_size);
+        header.write(buffer);
+        Ok(header)
+    } else {
+        check_header(buffer, Some(file_type))
+    }
+}
+
+/// Checks the header of a file that is being opened.
+/// # Arguments
+/// `buffer` - The buffer of the file.
+/// `file_type` - What should be kept in the file.  `None` takes either.
+pub(crate) fn check_header<B: DirectByteBuffer + ?Sized>(
+    buffer: &B,
+    file_type: Option<FileType>,
+) -> io::Result<FileHeader> {
+    let header = FileHeader::read(buffer).map_err(header_error)?;
+    match file_type {
+        Some(file_type) => header.expect(file_type).map_err(header_error),
+        None => Ok(header),
+    }
+}
+
+/// Gets the id of a file from the number at the end of its name.
+/// # Arguments
+/// `path` - The path of the file.
+/// # Returns
+/// The id or 0 if the name doesn't end in one.
+pub(crate) fn file_id_of<P: AsRef<Path>>(path: &P) -> u32 {
+    path.as_ref()
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(|e| e.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Creates a commit file with the header in front of the terms.  A commit file that already exists
+/// keeps its header.
+/// # Arguments
+/// `path` - The path of the file.
+/// `file_size` - The size for the terms not counting the header.
+/// # Safety
+/// The file is memory mapped so it can't be changed by another process while it is open.
+pub unsafe fn create_commit_file<P: AsRef<Path>>(
+    path: &P,
+    file_size: usize,
+) -> io::Result<MemoryMappedInt> {
+    let mut buffer = MemoryMappedInt::new(path, file_size + FILE_HEADER_SIZE)?;
+    init_header(&mut buffer, FileType::Commit, file_id_of(path), file_size)?;
+    Ok(buffer)
+}
+
+/// Opens a commit file after checking its header.
+/// # Arguments
+/// `file` - The file to open.
+/// # Safety
+/// The file is memory mapped so it can't be changed by another process while it is open.
+pub unsafe fn open_commit_file(file: File) -> io::Result<MemoryMappedInt> {
+    let buffer = MemoryMappedInt::open(file)?;
+    check_header(&buffer, Some(FileType::Commit))?;
+    Ok(buffer)
+}
+
+/// Reads the header of a file without mapping it.
+/// # Arguments
+/// `path` - The path of the file.
+/// # Returns
+/// The header or `InvalidHeader` if the file doesn't have a valid one.
+pub fn read_file_header<P: AsRef<Path>>(path: &P) -> crate::file::Result<FileHeader> {
+    let mut file = File::open(path)?;
+    let mut bytes = vec![0; FILE_HEADER_SIZE];
+    let mut read = 0;
+    while read < FILE_HEADER_SIZE {
+        match file.read(&mut bytes[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    FileHeader::from_bytes(&bytes[..read])
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::header::*;
+    use a19_concurrent::buffer::atomic_buffer::AtomicByteBufferInt;
+    use std::fs::{create_dir_all, write};
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_header";
+
+    #[test]
+    pub fn header_test() {
+        let mut buffer = AtomicByteBufferInt::new(0x1000);
+        let header = init_header(&mut buffer, FileType::Event, 3, 0x800).unwrap();
+        assert_eq!(FORMAT_VERSION, header.version);
+        assert_eq!(3, header.file_id);
+        assert_eq!(0x800, header.max_file_size);
+        assert!(header.created_at > 0);
+        assert_eq!(header, FileHeader::read(&buffer).unwrap());
+        // Opening it again keeps the header that is there.
+        assert_eq!(
+            header,
+            init_header(&mut buffer, FileType::Event, 9, 0x100).unwrap()
+        );
+
+        let e = check_header(&buffer, Some(FileType::Commit)).unwrap_err();
+        assert_eq!(
+            Some("Expected a Commit file but the header is for a Event file."),
+            invalid_header(&e)
+        );
+        buffer.put_u32(VERSION, FORMAT_VERSION + 1);
+        match FileHeader::read(&buffer) {
+            Err(Error::InvalidHeader(reason)) => assert_eq!(
+                "The format version 2 isn't supported, only version 1 can be read.",
+                reason
+            ),
+            _ => panic!("The version should be refused."),
+        }
+        buffer.put_u32(VERSION, FORMAT_VERSION);
+        buffer.put_u32(FILE_TYPE, 7);
+        match FileHeader::read(&buffer) {
+            Err(Error::InvalidHeader(reason)) => {
+                assert_eq!("The file type 7 isn't known.", reason)
+            }
+            _ => panic!("The file type should be refused."),
+        }
+    }
+
+    #[test]
+    pub fn no_header_test() {
+        create_dir_all(TEST_DIR).unwrap();
+        let path = format!("{}/old.events.1", TEST_DIR);
+        // A file from before the header that starts with a message.
+        let mut bytes = vec![0; 0x1000];
+        bytes[3] = 24;
+        bytes[7] = 1;
+        bytes[15] = 1;
+        write(&path, &bytes).unwrap();
+        match read_file_header(&path) {
+            Err(Error::InvalidHeader(reason)) => assert!(reason.contains("magic bytes")),
+            _ => panic!("The file without a header should be refused."),
+        }
+        let e = match unsafe { crate::file::MessageFileStore::open_readonly(&path) } {
+            Err(e) => e,
+            Ok(_) => panic!("The file without a header should be refused."),
+        };
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert!(invalid_header(&e).unwrap().contains("magic bytes"));
+
+        let short = format!("{}/short.commit.1", TEST_DIR);
+        write(&short, [0u8; 16]).unwrap();
+        match read_file_header(&short) {
+            Err(Error::InvalidHeader(reason)) => assert_eq!(
+                "The file is 16 bytes which is smaller than the 512 byte header.",
+                reason
+            ),
+            _ => panic!("The short file should be refused."),
+        }
+    }
+}
@@ -1,9 +1,22 @@
-use a19_concurrent::buffer::atomic_buffer::AtomicByteBuffer;
+pub mod backend;
+pub mod chunk;
+mod direct;
+pub mod filter;
+pub mod header;
+
+use crate::file::chunk::{BodyReader, ChunkHeader, CHUNK_MESSAGE_TYPE};
+use crate::file::direct::{check_block_size, DirectWriter};
+use crate::file::header::{
+    check_header, file_id_of, init_header, FileHeader, FileType, FILE_HEADER_SIZE,
+};
+use a19_concurrent::buffer::atomic_buffer::{AtomicByteBuffer, AtomicByteBufferInt};
 use a19_concurrent::buffer::mmap_buffer::MemoryMappedInt;
 use a19_concurrent::buffer::DirectByteBuffer;
-use a19_concurrent::buffer::next_pos;
+use a19_core::pow2::PowOf2;
+use byteorder::{BigEndian, ByteOrder};
 use std::cell::UnsafeCell;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
 use std::path::Path;
 use std::sync::atomic::{fence, Ordering};
 use std::sync::Arc;
@@ -11,6 +24,8 @@ use std::sync::Arc;
 pub type MessageId = u64;
 pub type MessageTypeId = i32;
 
+/// Reads a store.  Cloning it gives another handle to the same store that keeps it mapped.
+#[derive(Clone)]
 pub struct MessageFileStoreRead {
     store: Arc<UnsafeCell<MessageFileStore>>,
 }
@@ -26,17 +41,13 @@ impl MessageFileStoreRead {
     where
         F: FnOnce(i32, u64, &'a [u8]),
     {
-        unsafe {
-            let store = &mut *self.store.get();
-            store.read(pos, act)
-        }
+        let store = unsafe { &*self.store.get() };
+        store.read(pos, act)
     }
 
     pub fn read_new<'a>(&self, pos: usize) -> Result<MessageRead<'a>> {
-        unsafe {
-            let store = &mut *self.store.get();
-            store.read_new(pos)
-        }
+        let store = unsafe { &*self.store.get() };
+        store.read_new(pos)
     }
 
     pub fn read_block<'a>(
@@ -45,7 +56,7 @@ impl MessageFileStoreRead {
         max_message_id: u64,
         max_length: usize,
     ) -> Result<MessageBlock<'a>> {
-        let store = unsafe { &mut *self.store.get() };
+        let store = unsafe { &*self.store.get() };
         store.read_block(pos, max_message_id, max_length)
     }
 
@@ -54,14 +65,38 @@ impl MessageFileStoreRead {
     /// `pos` - The starting position.
     /// `length` - The length of the section to get.
     pub fn read_section<'a>(&'a self, pos: usize, length: usize) -> Result<&'a [u8]> {
-        let store = unsafe { &mut *self.store.get() };
+        let store = unsafe { &*self.store.get() };
         store.read_section(pos, length)
     }
 
     pub fn is_end(&self, pos: usize) -> bool {
-        let store = unsafe { &mut *self.store.get() };
+        let store = unsafe { &*self.store.get() };
         store.is_end(pos)
     }
+
+    /// Reads the id of a message and where the next one starts without reading the body.  The
+    /// checksum isn't checked so it is only for skipping over the messages.
+    /// # Arguments
+    /// `pos` - The position of the message.
+    /// # Returns
+    /// The id of the message and the position of the next message.
+    pub fn read_link(&self, pos: usize) -> Result<(MessageId, usize)> {
+        let store = unsafe { &*self.store.get() };
+        store.read_link(pos)
+    }
+
+    /// The size of the file not counting the header.
+    pub fn capacity(&self) -> usize {
+        let store = unsafe { &*self.store.get() };
+        store.size()
+    }
+
+    /// The buffer the messages are stored in.  Used to read the files that aren't message files.
+    /// The file header is at the start of it.
+    pub(crate) fn buffer(&self) -> &dyn AtomicByteBuffer {
+        let store = unsafe { &*self.store.get() };
+        &*store.buffer
+    }
 }
 
 unsafe impl Sync for MessageFileStoreRead {}
@@ -87,6 +122,21 @@ impl MessageFileStoreWrite {
         }
     }
 
+    /// Writes a message with the user metadata to the buffer.
+    pub fn write_with_headers(
+        &self,
+        position: usize,
+        msg_type_id: i32,
+        message_id: u64,
+        headers: &MessageHeaders,
+        buffer: &[u8],
+    ) -> Result<usize> {
+        unsafe {
+            let store = &mut *self.store.get();
+            store.write_with_headers(position, msg_type_id, message_id, headers, buffer)
+        }
+    }
+
     /// Flushes the memory mapped file to disk.
     pub fn flush(&self) -> Result<()> {
         unsafe {
@@ -94,6 +144,121 @@ impl MessageFileStoreWrite {
             store.flush()
         }
     }
+
+    /// Flushes the part of the file the messages were written to.
+    /// # Arguments
+    /// `position` - The position of the first byte to flush.
+    /// `length` - The number of bytes to flush.
+    pub fn flush_range(&self, position: usize, length: usize) -> Result<()> {
+        unsafe {
+            let store = &mut *self.store.get();
+            store.flush_range(position, length)
+        }
+    }
+
+    /// Writes the user metadata and body of a message without the header so the readers don't
+    /// see it yet.  Lets the writers that claimed the space for their messages fill it in at the
+    /// same time.
+    /// # Arguments
+    /// `position` - The position of the message.
+    /// `headers` - The user metadata.
+    /// `buffer` - The body of the message.
+    /// # Returns
+    /// The checksum of the metadata and body to pass to `publish`.
+    pub(crate) fn write_unpublished(
+        &self,
+        position: usize,
+        headers: &MessageHeaders,
+        buffer: &[u8],
+    ) -> Result<crc32fast::Hasher> {
+        let store = unsafe { &*self.store.get() };
+        store.write_unpublished(position, headers, buffer)
+    }
+
+    /// Writes the header of a message written with `write_unpublished`.  The size is written last
+    /// so the readers only see the whole message.
+    /// # Arguments
+    /// `position` - The position of the message.
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `headers` - The user metadata.
+    /// `length` - The length of the body.
+    /// `body` - The checksum returned by `write_unpublished`.
+    /// # Returns
+    /// The position after the message.
+    pub(crate) fn publish(
+        &self,
+        position: usize,
+        msg_type_id: i32,
+        message_id: u64,
+        headers: &MessageHeaders,
+        length: usize,
+        body: &crc32fast::Hasher,
+    ) -> Result<usize> {
+        let store = unsafe { &*self.store.get() };
+        store.publish(position, msg_type_id, message_id, headers, length, body)
+    }
+
+    /// Writes a whole message without taking the store mutably so it is safe while the other
+    /// writers sharing the file are filling in their messages.  Fills the rest of the file with
+    /// `u32::MAX` when it doesn't fit.
+    /// # Arguments
+    /// `position` - The position of the message.
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `buffer` - The body of the message.
+    /// # Returns
+    /// The position after the message or `Full` if it didn't fit.
+    pub(crate) fn write_shared(
+        &self,
+        position: usize,
+        msg_type_id: i32,
+        message_id: u64,
+        buffer: &[u8],
+    ) -> Result<usize> {
+        let store = unsafe { &*self.store.get() };
+        store.write_shared(position, msg_type_id, message_id, buffer)
+    }
+
+    /// True if the messages are written with direct I/O.
+    pub(crate) fn is_direct(&self) -> bool {
+        unsafe {
+            let store = &*self.store.get();
+            store.direct.is_some()
+        }
+    }
+
+    /// Zeros out a section of the file so the messages in it are removed.
+    /// # Arguments
+    /// `position` - The position to start clearing from.
+    /// `length` - The number of bytes to clear.
+    pub fn clear(&self, position: usize, length: usize) {
+        unsafe {
+            let store = &mut *self.store.get();
+            store.clear(position, length);
+        }
+    }
+
+    /// The size of the file.
+    pub fn capacity(&self) -> usize {
+        unsafe {
+            let store = &*self.store.get();
+            store.size()
+        }
+    }
+
+    /// The file the messages are written to.  `None` if the messages are kept in memory.
+    #[allow(dead_code)]
+    pub(crate) fn file(&self) -> Option<&File> {
+        unsafe {
+            let store = &*self.store.get();
+            match (&store.direct, &store.buffer) {
+                (Some(direct), _) => Some(direct.file()),
+                (None, StoreBuffer::Mapped(buffer)) => Some(buffer.file()),
+                (None, StoreBuffer::Memory(_)) => None,
+            }
+        }
+    }
 }
 
 unsafe impl Send for MessageFileStoreWrite {}
@@ -104,23 +269,220 @@ unsafe impl Sync for MessageFileStoreWrite {}
 #[derive(Debug)]
 pub struct MessageFileStore {
     /// The buffer we are writing to.
-    buffer: MemoryMappedInt,
+    buffer: StoreBuffer,
+    /// Writes the messages with direct I/O instead of through the buffer when set.
+    direct: Option<DirectWriter>,
+    /// The size for the messages.  Doesn't count the header.
+    size: usize,
+    /// The start of the memory of the buffer.  The writers sharing a file copy their messages in
+    /// through it so they never hold a mutable reference to the store at the same time.
+    base: *mut u8,
+}
+
+/// The memory the messages are stored in.  Also holds the commit files so they can be kept in
+/// memory.
+pub enum StoreBuffer {
+    /// A memory mapped file.
+    Mapped(MemoryMappedInt),
+    /// Memory that isn't backed by a file.  Lost when the store is dropped.
+    Memory(AtomicByteBufferInt),
+}
+
+impl std::fmt::Debug for StoreBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreBuffer::Mapped(buffer) => f.debug_tuple("Mapped").field(buffer).finish(),
+            StoreBuffer::Memory(buffer) => {
+                f.debug_tuple("Memory").field(&buffer.capacity()).finish()
+            }
+        }
+    }
+}
+
+impl StoreBuffer {
+    /// The size after the header.  A buffer in memory is rounded up to a power of 2 so it can be
+    /// bigger than what it was created with, the size in its header is used for it.
+    pub fn size(&self) -> usize {
+        match self {
+            StoreBuffer::Mapped(buffer) => buffer.capacity() - FILE_HEADER_SIZE,
+            StoreBuffer::Memory(buffer) => FileHeader::read(buffer)
+                .map(|header| header.max_file_size as usize)
+                .unwrap_or_else(|_| buffer.capacity() - FILE_HEADER_SIZE),
+        }
+    }
+
+    /// Flushes the bytes to the file.  Does nothing for memory.
+    pub fn flush(&self) -> std::io::Result<()> {
+        match self {
+            StoreBuffer::Mapped(buffer) => buffer.flush(),
+            StoreBuffer::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Flushes part of the bytes to the file.  Does nothing for memory.
+    /// # Arguments
+    /// `offset` - The offset in the buffer of the first byte to flush.
+    /// `len` - The number of bytes to flush.
+    pub fn flush_range(&self, offset: usize, len: usize) -> std::io::Result<()> {
+        match self {
+            StoreBuffer::Mapped(buffer) => buffer.flush_range(offset, len),
+            StoreBuffer::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Deref for StoreBuffer {
+    type Target = dyn AtomicByteBuffer;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            StoreBuffer::Mapped(buffer) => buffer,
+            StoreBuffer::Memory(buffer) => buffer,
+        }
+    }
+}
+
+impl DerefMut for StoreBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            StoreBuffer::Mapped(buffer) => buffer,
+            StoreBuffer::Memory(buffer) => buffer,
+        }
+    }
+}
+
+/// How the messages are written to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// Written to the memory map and flushed through the page cache.
+    #[default]
+    Mapped,
+    /// Written with `O_DIRECT` to skip the page cache.  The file size is a multiple of the block
+    /// size.  Needs linux and the `direct-io` feature.
+    Direct {
+        /// The block size of the device.  A power of 2 of at least 512.
+        block_size: usize,
+    },
 }
 
 unsafe impl Send for MessageFileStore {}
 unsafe impl Sync for MessageFileStore {}
 
-const MESSAGE_ID: usize = 8;
-const MESSAGE_TYPE: usize = 4;
-const MESSAGE_SIZE: usize = 0;
-const HEADER_SIZE: usize = 16;
-const ALIGNMENT: usize = HEADER_SIZE;
+pub(crate) const MESSAGE_ID: usize = 8;
+pub(crate) const MESSAGE_TYPE: usize = 4;
+pub(crate) const MESSAGE_SIZE: usize = 0;
+pub(crate) const HEADER_SIZE: usize = 16;
+pub(crate) const ALIGNMENT: usize = HEADER_SIZE;
+/// Set in the size of a message when the user metadata is between the header and the body.
+const USER_META_FLAG: u32 = 0x8000_0000;
+/// Set in the size of a message when the checksum is after the header.
+pub(crate) const CHECKSUM_FLAG: u32 = 0x4000_0000;
+/// The size of the checksum of a message.
+pub const CHECKSUM_SIZE: usize = 4;
+/// The size of the user metadata.
+pub const USER_META_SIZE: usize = 8;
+
+/// Small metadata stamped on a message without touching the body.  A message written without any
+/// reads back as all zeros and doesn't take up any space for it so the existing files are still
+/// valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MessageHeaders {
+    /// The tenant the message belongs to.
+    pub tenant: u32,
+    /// The priority of the message.
+    pub priority: u8,
+    /// The flags for the application like if the message is sampled for tracing.
+    pub flags: u8,
+    /// Free for the application to use.
+    pub reserved: u16,
+}
+
+impl MessageHeaders {
+    /// True if all of the fields are zero.  Nothing is stored for the message when they are.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        *self == MessageHeaders::default()
+    }
+
+    /// Writes the headers the way they are stored in the file.
+    pub fn to_bytes(&self) -> [u8; USER_META_SIZE] {
+        let mut bytes = [0; USER_META_SIZE];
+        BigEndian::write_u32(&mut bytes[0..4], self.tenant);
+        bytes[4] = self.priority;
+        bytes[5] = self.flags;
+        BigEndian::write_u16(&mut bytes[6..8], self.reserved);
+        bytes
+    }
+
+    /// Reads the headers from the way they are stored in the file.
+    /// # Arguments
+    /// `bytes` - The stored headers.  Needs at least `USER_META_SIZE` bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        MessageHeaders {
+            tenant: BigEndian::read_u32(&bytes[0..4]),
+            priority: bytes[4],
+            flags: bytes[5],
+            reserved: BigEndian::read_u16(&bytes[6..8]),
+        }
+    }
+}
+
+/// Splits the size stored for a message into the size of the message and if it has the user
+/// metadata.
+/// # Arguments
+/// `size` - The stored size.
+#[inline]
+pub(crate) fn split_size(size: u32) -> (usize, bool) {
+    (
+        (size & !(USER_META_FLAG | CHECKSUM_FLAG)) as usize,
+        size & USER_META_FLAG != 0,
+    )
+}
+
+/// True if the message has a checksum after the header.
+/// # Arguments
+/// `size` - The stored size.
+#[inline]
+pub(crate) fn has_checksum(size: u32) -> bool {
+    size & CHECKSUM_FLAG != 0
+}
+
+/// Calculates the checksum of a message.  Covers everything but the size since the size is what
+/// says the message is there.
+/// # Arguments
+/// `msg_type_id` - The type of the message.
+/// `message_id` - The id of the message.
+/// `meta` - The user metadata if the message has it.
+/// `body` - The body of the message.
+pub(crate) fn message_checksum(
+    msg_type_id: i32,
+    message_id: u64,
+    meta: Option<&[u8]>,
+    body: &[u8],
+) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&msg_type_id.to_be_bytes());
+    hasher.update(&message_id.to_be_bytes());
+    if let Some(meta) = meta {
+        hasher.update(meta);
+    }
+    hasher.update(body);
+    hasher.finalize()
+}
+
+/// The number of bytes a message takes up in the file.
+/// # Arguments
+/// `length` - The length of the body of the message.
+pub fn aligned_message_size(length: usize) -> usize {
+    (HEADER_SIZE + CHECKSUM_SIZE + length).align_up(ALIGNMENT)
+}
 
 /// Buffer format
 ///  0                   1                   2                   3
 ///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
 /// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-/// | Message Size total message size                               |
+/// |M|C| Message Size total message size                           |
 /// +---------------------------------------------------------------+ 32
 /// | Message Type                                                  |
 /// +---------------------------------------------------------------+ 64
@@ -128,9 +490,19 @@ const ALIGNMENT: usize = HEADER_SIZE;
 /// |                                                               | 96
 /// |                                                               |
 /// +---------------------------------------------------------------+ 128
+/// | Checksum (only when C is set)                                 |
+/// +---------------------------------------------------------------+ 160
+/// | Tenant (only when M is set)                                   |
+/// +---------------+---------------+-------------------------------+ 192
+/// | Priority      | Flags         | Reserved                      |
+/// +---------------+---------------+-------------------------------+ 224
 /// |                   Message Body                                ...
 /// ...                                                             |
 /// +---------------------------------------------------------------+
+/// M - Set when the message has the user metadata.  Counted in the size.
+/// C - Set when the message has the CRC32 of the type, id, user metadata and body.  Counted in the
+/// size.  Every message is written with it, the ones written before it was added don't have it
+/// and aren't checked.
 impl MessageFileStore {
     /// Creates a new file store.
     /// # Arguments
@@ -140,16 +512,105 @@ impl MessageFileStore {
         path: &P,
         file_size: usize,
     ) -> std::io::Result<(MessageFileStoreRead, MessageFileStoreWrite)> {
-        // Need to make sure the file size is aligned correctly.
-        let buffer = MemoryMappedInt::new(path, next_pos(file_size, ALIGNMENT))?;
-        let file_store = MessageFileStore { buffer };
+        MessageFileStore::new_with_mode(path, file_size, WriteMode::Mapped)
+    }
+
+    /// Creates a new file store that writes the messages using a mode.  The messages are always
+    /// read from the memory map.
+    /// # Arguments
+    /// `path` - The path to create the file.
+    /// `file_size` - The size of the file to create.
+    /// `mode` - How to write the messages.
+    /// # Returns
+    /// The read and writers for the file or an `Unsupported` error if the platform can't do direct
+    /// I/O.
+    /// # Safety
+    /// The file is memory mapped so it can't be changed by another process while it is open.
+    pub unsafe fn new_with_mode<P: AsRef<Path>>(
+        path: &P,
+        file_size: usize,
+        mode: WriteMode,
+    ) -> std::io::Result<(MessageFileStoreRead, MessageFileStoreWrite)> {
+        let alignment = match mode {
+            WriteMode::Mapped => ALIGNMENT,
+            WriteMode::Direct { block_size } => {
+                check_block_size(block_size)?;
+                block_size.max(ALIGNMENT)
+            }
+        };
+        // Need to make sure the file size is aligned correctly.  The header is in front of the
+        // messages.
+        let file_size = file_size.align_up(alignment);
+        let mut buffer =
+            MemoryMappedInt::new(path, (FILE_HEADER_SIZE + file_size).align_up(alignment))?;
+        init_header(&mut buffer, FileType::Event, file_id_of(path), file_size)?;
+        let direct = match mode {
+            WriteMode::Mapped => None,
+            WriteMode::Direct { block_size } => {
+                // The direct writer reads the first block from the disk so the header has to be
+                // there first.
+                buffer.flush()?;
+                Some(DirectWriter::open(path, block_size)?)
+            }
+        };
+        let file_store = MessageFileStore::mapped(buffer, direct);
+        Ok(MessageFileStore::split(file_store))
+    }
+
+    /// Creates a store that keeps the messages in memory instead of a file.  It has the same
+    /// format as a file so it can be used in place of one for tests and streams that don't need
+    /// to survive a restart.
+    /// # Arguments
+    /// `file_size` - The size of the store.  Rounded up to a power of 2.
+    /// # Returns
+    /// The read and writers for the store.
+    pub fn new_in_memory(file_size: usize) -> (MessageFileStoreRead, MessageFileStoreWrite) {
+        let file_size = file_size.align_up(ALIGNMENT).round_to_power_of_two();
+        let mut buffer = AtomicByteBufferInt::new(FILE_HEADER_SIZE + file_size);
+        FileHeader::new(FileType::Event, 0, file_size).write(&mut buffer);
+        let file_store = MessageFileStore {
+            buffer: StoreBuffer::Memory(buffer),
+            direct: None,
+            size: file_size,
+            base: std::ptr::null_mut(),
+        };
+        MessageFileStore::split(file_store.with_base())
+    }
+
+    /// Creates a store for a memory mapped file that has a header.
+    /// # Arguments
+    /// `buffer` - The memory mapped file.
+    /// `direct` - The direct I/O writer if the messages are written with it.
+    fn mapped(buffer: MemoryMappedInt, direct: Option<DirectWriter>) -> Self {
+        let size = buffer.capacity() - FILE_HEADER_SIZE;
+        MessageFileStore {
+            buffer: StoreBuffer::Mapped(buffer),
+            direct,
+            size,
+            base: std::ptr::null_mut(),
+        }
+        .with_base()
+    }
+
+    /// Sets the pointer to the memory of the buffer.  The memory doesn't move when the store is
+    /// moved since it is either mapped or on the heap.
+    fn with_base(mut self) -> Self {
+        let capacity = self.buffer.capacity();
+        self.base = self.buffer.as_bytes_mut(0, capacity).as_mut_ptr();
+        self
+    }
+
+    /// Splits a store into the reader and the writer.
+    /// # Arguments
+    /// `file_store` - The store to split.
+    fn split(file_store: MessageFileStore) -> (MessageFileStoreRead, MessageFileStoreWrite) {
         let cell = Arc::new(UnsafeCell::new(file_store));
-        Ok((
+        (
             MessageFileStoreRead {
                 store: cell.clone(),
             },
             MessageFileStoreWrite { store: cell },
-        ))
+        )
     }
 
     /// Used to open a buffer file.
@@ -157,7 +618,8 @@ impl MessageFileStore {
     /// # Arguments
     /// `path` - The path of the file to open.
     /// # Return
-    /// The read and writers for the file.
+    /// The read and writers for the file or an `InvalidData` error with `InvalidHeader` as the
+    /// source if the file doesn't start with a valid header.
     pub unsafe fn open<P: AsRef<Path>>(
         path: &P,
     ) -> std::io::Result<(MessageFileStoreRead, MessageFileStoreWrite)> {
@@ -167,14 +629,9 @@ impl MessageFileStore {
             .create(false)
             .open(path)?;
         let buffer = MemoryMappedInt::open(file)?;
-        let file_store = MessageFileStore { buffer };
-        let cell = Arc::new(UnsafeCell::new(file_store));
-        Ok((
-            MessageFileStoreRead {
-                store: cell.clone(),
-            },
-            MessageFileStoreWrite { store: cell },
-        ))
+        check_header(&buffer, None)?;
+        let file_store = MessageFileStore::mapped(buffer, None);
+        Ok(MessageFileStore::split(file_store))
     }
 
     /// Opens a file for writting only.
@@ -186,21 +643,27 @@ impl MessageFileStore {
     ) -> std::io::Result<MessageFileStoreWrite> {
         let buffer = if path.as_ref().exists() {
             let file = OpenOptions::new().read(true).write(true).open(path)?;
-            MemoryMappedInt::open(file)?
+            let buffer = MemoryMappedInt::open(file)?;
+            check_header(&buffer, Some(FileType::Event))?;
+            buffer
         } else {
             let _file = OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
                 .open(path)?;
-            MemoryMappedInt::new(path, next_pos(file_size, ALIGNMENT))?
+            let file_size = file_size.align_up(ALIGNMENT);
+            let mut buffer = MemoryMappedInt::new(path, FILE_HEADER_SIZE + file_size)?;
+            init_header(&mut buffer, FileType::Event, file_id_of(path), file_size)?;
+            buffer
         };
-        let file_store = MessageFileStore { buffer };
+        let file_store = MessageFileStore::mapped(buffer, None);
         let cell = Arc::new(UnsafeCell::new(file_store));
         Ok(MessageFileStoreWrite { store: cell })
     }
 
-    /// Opens a file that is readonly.
+    /// Opens a file that is readonly.  The commit files are opened with it too so either kind of
+    /// header is taken.
     /// # Arguments
     /// `path` - The path of the file to open to read.
     pub unsafe fn open_readonly<P: AsRef<Path>>(path: &P) -> std::io::Result<MessageFileStoreRead> {
@@ -210,7 +673,8 @@ impl MessageFileStore {
             .create(false)
             .open(path)?;
         let buffer = MemoryMappedInt::open(file)?;
-        let file_store = MessageFileStore { buffer };
+        check_header(&buffer, None)?;
+        let file_store = MessageFileStore::mapped(buffer, None);
         let cell = Arc::new(UnsafeCell::new(file_store));
         Ok(MessageFileStoreRead {
             store: cell.clone(),
@@ -246,6 +710,99 @@ pub enum Error {
     },
     InvalidFile,
     AlreadyExists,
+    /// The store has been stopped so the message isn't going to be committed.
+    Stopped,
+    /// A message id was skipped while reading in the messages.
+    SequenceGap {
+        expected: u64,
+        found: u64,
+        position: usize,
+    },
+    /// The file was removed from the directory while it was still being read.
+    FileRemoved(u32),
+    /// The store was opened read only so it can't be written to.
+    ReadOnly,
+    /// The message doesn't match its checksum.  Part of it wasn't written or it was changed
+    /// after.
+    ChecksumMismatch {
+        message_id: u64,
+        position: usize,
+    },
+    /// The header at the start of the file is missing or isn't one we can read.
+    InvalidHeader(String),
+    /// The message is before the first one still kept in the files.
+    NotRetained {
+        message_id: u64,
+        earliest: u64,
+    },
+    /// The processor failed to handle the message with the id.
+    ProcessFailed(u64),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Full => write!(f, "The file is full"),
+            Error::FileError(e) => write!(f, "{}", e),
+            Error::PositionOutOfRange(position) => {
+                write!(f, "The position {} is out of range", position)
+            }
+            Error::NoMessage => write!(f, "No message at the position"),
+            Error::NotEnoughSpace {
+                message_size,
+                position,
+                capacity,
+                remaining,
+            } => write!(
+                f,
+                "Not enough space for a {} byte message at {}, {} of {} bytes remaining",
+                message_size, position, remaining, capacity
+            ),
+            Error::InvalidFile => write!(f, "The file isn't valid"),
+            Error::AlreadyExists => write!(f, "The file already exists"),
+            Error::Stopped => write!(f, "The store has been stopped"),
+            Error::SequenceGap {
+                expected,
+                found,
+                position,
+            } => write!(
+                f,
+                "Expected message {} but found {} at {}",
+                expected, found, position
+            ),
+            Error::FileRemoved(file_id) => write!(f, "The file {} was removed", file_id),
+            Error::ReadOnly => write!(f, "The store is read only"),
+            Error::ChecksumMismatch {
+                message_id,
+                position,
+            } => write!(
+                f,
+                "Message {} at {} doesn't match its checksum",
+                message_id, position
+            ),
+            Error::InvalidHeader(reason) => write!(f, "The file header isn't valid: {}", reason),
+            Error::NotRetained {
+                message_id,
+                earliest,
+            } => write!(
+                f,
+                "Message {} has been removed, the earliest message is {}",
+                message_id, earliest
+            ),
+            Error::ProcessFailed(message_id) => {
+                write!(f, "The processor failed to handle message {}", message_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::FileError(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 /// Represents the storage of messages.
@@ -299,6 +856,31 @@ pub trait MessageStore {
         msg_type_id: i32,
         message_id: u64,
         buffer: &[u8],
+    ) -> Result<usize> {
+        self.write_with_headers(
+            position,
+            msg_type_id,
+            message_id,
+            &MessageHeaders::default(),
+            buffer,
+        )
+    }
+
+    /// Writes a message with the user metadata to the buffer.
+    /// # Arguments
+    /// `posiiton` - The position to write to the buffer.
+    /// `msg_type_id` - The type of the message.
+    /// `headers` - The user metadata.  Nothing is stored for it when it is empty.
+    /// `buffer` - THe buffer for the message.
+    /// # returns
+    /// The next position in the buffer.
+    fn write_with_headers(
+        &mut self,
+        position: usize,
+        msg_type_id: i32,
+        message_id: u64,
+        headers: &MessageHeaders,
+        buffer: &[u8],
     ) -> Result<usize>;
 
     /// Used to read in a section.
@@ -314,45 +896,365 @@ impl MessageFileStore {
     /// # Arguments
     /// `position` - The starting position.
     fn calculate_msg_type_pos(position: usize) -> usize {
-        position + MESSAGE_TYPE
+        FILE_HEADER_SIZE + position + MESSAGE_TYPE
     }
 
     /// Calculates the message position.
     /// # Arguments
     /// `position` - The starting position.
     fn calculate_msg_size_pos(position: usize) -> usize {
-        position + MESSAGE_SIZE
+        FILE_HEADER_SIZE + position + MESSAGE_SIZE
     }
 
     /// Calculates the message position.
     /// # Arguments
     /// `position` - The starting position.
     fn calculate_body_pos(position: usize) -> usize {
-        position + HEADER_SIZE
+        FILE_HEADER_SIZE + position + HEADER_SIZE
     }
 
     /// Calculates the position of the message id.
     /// # Arguments
     /// `position` - The position to calculate.
     fn calculate_message_id_pos(position: usize) -> usize {
-        position + MESSAGE_ID
+        FILE_HEADER_SIZE + position + MESSAGE_ID
+    }
+
+    /// Reads the id of a message and the position of the next message from the header.
+    /// # Arguments
+    /// `pos` - The position of the message.
+    fn read_link(&self, pos: usize) -> Result<(MessageId, usize)> {
+        if pos > self.size() - ALIGNMENT {
+            Err(Error::PositionOutOfRange(pos))
+        } else {
+            fence(Ordering::Acquire);
+            let size = self
+                .buffer
+                .get_u32(MessageFileStore::calculate_msg_size_pos(pos));
+            if size == 0 {
+                Err(Error::NoMessage)
+            } else if self.is_end(pos) {
+                Err(Error::Full)
+            } else {
+                let (length, _) = split_size(size);
+                let aligned = length.align_up(ALIGNMENT);
+                let remaining = self.size() - pos;
+                if remaining < aligned {
+                    Err(Error::NotEnoughSpace {
+                        capacity: self.size(),
+                        message_size: size,
+                        position: pos,
+                        remaining,
+                    })
+                } else {
+                    let message_id = self
+                        .buffer
+                        .get_u64(MessageFileStore::calculate_message_id_pos(pos));
+                    Ok((message_id, aligned + pos))
+                }
+            }
+        }
+    }
+
+    /// Reads the user metadata and the body of a message.  The checksum is checked if the message
+    /// has one.
+    /// # Arguments
+    /// `pos` - The position of the message.
+    /// `size` - The size stored for the message.
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// # Returns
+    /// The user metadata and the body or `ChecksumMismatch` if they don't match the checksum.
+    fn read_body(
+        &self,
+        pos: usize,
+        size: u32,
+        msg_type_id: i32,
+        message_id: u64,
+    ) -> Result<(MessageHeaders, &[u8])> {
+        let (length, has_meta) = split_size(size);
+        let start = MessageFileStore::calculate_msg_size_pos(pos);
+        let mut body = MessageFileStore::calculate_body_pos(pos);
+        let checksum = if has_checksum(size) {
+            let checksum = self.buffer.get_u32(body);
+            body += CHECKSUM_SIZE;
+            Some(checksum)
+        } else {
+            None
+        };
+        let meta = if has_meta {
+            let meta = self.buffer.get_bytes(body, USER_META_SIZE);
+            body += USER_META_SIZE;
+            Some(meta)
+        } else {
+            None
+        };
+        let bytes = self
+            .buffer
+            .get_bytes(body, length.saturating_sub(body - start));
+        match checksum {
+            Some(checksum)
+                if checksum != message_checksum(msg_type_id, message_id, meta, bytes) =>
+            {
+                Err(Error::ChecksumMismatch {
+                    message_id,
+                    position: pos,
+                })
+            }
+            _ => Ok((
+                meta.map(MessageHeaders::from_bytes).unwrap_or_default(),
+                bytes,
+            )),
+        }
     }
 
     /// Forces the file to flush to disk.
     pub fn flush(&mut self) -> Result<()> {
-        match self.buffer.flush() {
+        let result = match (&self.direct, &self.buffer) {
+            (Some(direct), _) => direct.flush(),
+            (None, buffer) => buffer.flush(),
+        };
+        match result {
             Ok(_) => Ok(()),
             Err(e) => Err(Error::FileError(e)),
         }
     }
+
+    /// Forces part of the file to flush to disk.  Direct I/O syncs the whole file.
+    /// # Arguments
+    /// `position` - The position of the first byte to flush.
+    /// `length` - The number of bytes to flush.
+    pub fn flush_range(&mut self, position: usize, length: usize) -> Result<()> {
+        if position + length > self.size() {
+            return Err(Error::PositionOutOfRange(position));
+        }
+        let result = match (&self.direct, &self.buffer) {
+            (Some(direct), _) => direct.flush(),
+            (None, buffer) => buffer.flush_range(FILE_HEADER_SIZE + position, length),
+        };
+        result.map_err(Error::FileError)
+    }
+
+    /// Zeros out a section of the file.
+    /// # Arguments
+    /// `position` - The position to start clearing from.
+    /// `length` - The number of bytes to clear.
+    fn clear(&mut self, position: usize, length: usize) {
+        match &mut self.direct {
+            Some(direct) => {
+                if let Err(e) = direct.fill(FILE_HEADER_SIZE + position, length, 0) {
+                    log::error!("Unable to clear the file: {}", e);
+                }
+            }
+            None => self
+                .buffer
+                .set_bytes(FILE_HEADER_SIZE + position, length, 0),
+        }
+    }
+
+    /// Copies bytes into the buffer through the pointer to its memory.
+    /// # Arguments
+    /// `offset` - The offset in the buffer to copy to.
+    /// `bytes` - The bytes to copy.
+    /// # Safety
+    /// The bytes can't be written by another writer at the same time.
+    unsafe fn copy_shared(&self, offset: usize, bytes: &[u8]) {
+        assert!(offset + bytes.len() <= self.buffer.capacity());
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.base.add(offset), bytes.len());
+    }
+
+    /// Writes the user metadata and body of a message without the header.  Only writes to the
+    /// space of the message so the writers sharing the file can do it at the same time.
+    /// # Arguments
+    /// `position` - The position of the message.
+    /// `headers` - The user metadata.
+    /// `buffer` - The body of the message.
+    /// # Returns
+    /// The checksum of the metadata and body.
+    fn write_unpublished(
+        &self,
+        position: usize,
+        headers: &MessageHeaders,
+        buffer: &[u8],
+    ) -> Result<crc32fast::Hasher> {
+        if self.direct.is_some() {
+            // Direct I/O writes whole blocks so the messages around it would be overwritten.
+            return Err(Error::FileError(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unable to write part of a message with direct I/O",
+            )));
+        }
+        let meta_size = if headers.is_empty() {
+            0
+        } else {
+            USER_META_SIZE
+        };
+        let aligned = (HEADER_SIZE + CHECKSUM_SIZE + meta_size + buffer.len()).align_up(ALIGNMENT);
+        if position > self.size() || aligned > self.size() - position {
+            return Err(Error::PositionOutOfRange(position));
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        let mut message_body = MessageFileStore::calculate_body_pos(position) + CHECKSUM_SIZE;
+        if !headers.is_empty() {
+            let meta = headers.to_bytes();
+            hasher.update(&meta);
+            unsafe { self.copy_shared(message_body, &meta) };
+            message_body += USER_META_SIZE;
+        }
+        hasher.update(buffer);
+        unsafe { self.copy_shared(message_body, buffer) };
+        Ok(hasher)
+    }
+
+    /// Writes the header of a message after its body with the size last.  Only writes to the
+    /// space of the message.
+    /// # Arguments
+    /// `position` - The position of the message.
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `headers` - The user metadata.
+    /// `length` - The length of the body.
+    /// `body` - The checksum of the metadata and body.
+    /// # Returns
+    /// The position after the message.
+    fn publish(
+        &self,
+        position: usize,
+        msg_type_id: i32,
+        message_id: u64,
+        headers: &MessageHeaders,
+        length: usize,
+        body: &crc32fast::Hasher,
+    ) -> Result<usize> {
+        let meta_size = if headers.is_empty() {
+            0
+        } else {
+            USER_META_SIZE
+        };
+        let size = HEADER_SIZE + CHECKSUM_SIZE + meta_size + length;
+        let aligned = size.align_up(ALIGNMENT);
+        if position > self.size() || aligned > self.size() - position {
+            return Err(Error::PositionOutOfRange(position));
+        }
+        let stored_size = if headers.is_empty() {
+            size as u32 | CHECKSUM_FLAG
+        } else {
+            size as u32 | USER_META_FLAG | CHECKSUM_FLAG
+        };
+        // Same as `message_checksum` with the checksum of the body added on.
+        let mut checksum = crc32fast::Hasher::new();
+        checksum.update(&msg_type_id.to_be_bytes());
+        checksum.update(&message_id.to_be_bytes());
+        checksum.combine(body);
+        unsafe {
+            self.copy_shared(
+                MessageFileStore::calculate_msg_type_pos(position),
+                &msg_type_id.to_be_bytes(),
+            );
+            self.copy_shared(
+                MessageFileStore::calculate_message_id_pos(position),
+                &message_id.to_be_bytes(),
+            );
+            self.copy_shared(
+                MessageFileStore::calculate_body_pos(position),
+                &checksum.finalize().to_be_bytes(),
+            );
+            // The size is written last with a StoreStore barrier so the message is complete when
+            // it is seen.
+            fence(Ordering::Release);
+            self.copy_shared(
+                MessageFileStore::calculate_msg_size_pos(position),
+                &stored_size.to_be_bytes(),
+            );
+        }
+        Ok(aligned + position)
+    }
+
+    /// Writes a whole message for the writers sharing the file.  When it doesn't fit the rest of
+    /// the file is filled with `u32::MAX` so the readers see the end of the file.
+    /// # Arguments
+    /// `position` - The position of the message.
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `buffer` - The body of the message.
+    /// # Returns
+    /// The position after the message or `Full` if it didn't fit.
+    fn write_shared(
+        &self,
+        position: usize,
+        msg_type_id: i32,
+        message_id: u64,
+        buffer: &[u8],
+    ) -> Result<usize> {
+        let headers = MessageHeaders::default();
+        match self.write_unpublished(position, &headers, buffer) {
+            Ok(checksum) => self.publish(
+                position,
+                msg_type_id,
+                message_id,
+                &headers,
+                buffer.len(),
+                &checksum,
+            ),
+            Err(Error::PositionOutOfRange(_)) if position <= self.size() => {
+                let ones = vec![255; self.size() - position];
+                unsafe { self.copy_shared(FILE_HEADER_SIZE + position, &ones) };
+                Err(Error::Full)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes a message with direct I/O.
+    /// # Arguments
+    /// `direct` - The writer to use.
+    /// `position` - The position to write the message to.
+    /// `aligned` - The number of bytes the message takes up in the file.
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `size` - The size to store for the message.
+    /// `checksum` - The checksum of the message.
+    /// `meta` - The user metadata if the message has it.
+    /// `buffer` - The body of the message.
+    #[allow(clippy::too_many_arguments)]
+    fn write_direct(
+        direct: &mut DirectWriter,
+        position: usize,
+        aligned: usize,
+        msg_type_id: i32,
+        message_id: u64,
+        size: u32,
+        checksum: u32,
+        meta: Option<[u8; USER_META_SIZE]>,
+        buffer: &[u8],
+    ) -> std::io::Result<()> {
+        direct.write(FILE_HEADER_SIZE + position, aligned, |bytes| {
+            BigEndian::write_u32(&mut bytes[MESSAGE_SIZE..MESSAGE_TYPE], size);
+            BigEndian::write_i32(&mut bytes[MESSAGE_TYPE..MESSAGE_ID], msg_type_id);
+            BigEndian::write_u64(&mut bytes[MESSAGE_ID..HEADER_SIZE], message_id);
+            BigEndian::write_u32(
+                &mut bytes[HEADER_SIZE..HEADER_SIZE + CHECKSUM_SIZE],
+                checksum,
+            );
+            let mut body = HEADER_SIZE + CHECKSUM_SIZE;
+            if let Some(meta) = meta {
+                bytes[body..body + USER_META_SIZE].copy_from_slice(&meta);
+                body += USER_META_SIZE;
+            }
+            bytes[body..body + buffer.len()].copy_from_slice(buffer);
+        })
+    }
 }
 
 /// Represents a read in message.
 pub struct MessageRead<'a> {
     msg_type_id: MessageTypeId,
     message_id: MessageId,
+    headers: MessageHeaders,
     bytes: &'a [u8],
     next_pos: usize,
+    /// The file the message was read from.
+    store: &'a MessageFileStore,
 }
 
 pub struct MessageBlock<'a> {
@@ -378,14 +1280,18 @@ impl<'a> MessageRead<'a> {
     fn new(
         msg_type_id: MessageTypeId,
         message_id: MessageId,
+        headers: MessageHeaders,
         bytes: &'a [u8],
         next_pos: usize,
+        store: &'a MessageFileStore,
     ) -> Self {
         MessageRead {
             msg_type_id,
             message_id,
+            headers,
             bytes,
             next_pos,
+            store,
         }
     }
 
@@ -399,8 +1305,14 @@ impl<'a> MessageRead<'a> {
         self.message_id
     }
 
+    /// The user metadata of the message.  All zeros if it was written without any.
+    #[inline]
+    pub fn headers(&self) -> MessageHeaders {
+        self.headers
+    }
+
     #[inline]
-    pub fn bytes(&'a self) -> &'a [u8] {
+    pub fn bytes(&self) -> &'a [u8] {
         self.bytes
     }
 
@@ -408,12 +1320,28 @@ impl<'a> MessageRead<'a> {
     pub fn next_pos(&self) -> usize {
         self.next_pos
     }
+
+    /// The chunk header if the frame holds a chunk of a message too big for a file.
+    pub fn chunk(&self) -> Option<ChunkHeader> {
+        if self.msg_type_id == CHUNK_MESSAGE_TYPE {
+            ChunkHeader::read(self.bytes)
+        } else {
+            None
+        }
+    }
+
+    /// Gets a reader for the body of the message.  The chunks of a message are read in after it
+    /// so the reader returns the whole body.  If the chunks continue into the next file the
+    /// reader needs to be told how to open it with `with_next_file`.
+    pub fn body_reader(&self) -> BodyReader<'a> {
+        BodyReader::new(self.store, self)
+    }
 }
 
 impl MessageStore for MessageFileStore {
     /// The size of the file.
     fn size(&self) -> usize {
-        self.buffer.capacity()
+        self.size
     }
 
     /// Gets the maximum length of a message we can store in the file.
@@ -433,16 +1361,19 @@ impl MessageStore for MessageFileStore {
             Err(Error::PositionOutOfRange(pos))
         } else {
             fence(Ordering::Acquire); // This fence is here so we get the latest value. LoadLoad
-            let size = self.buffer.get_u32(pos);
+            let size = self
+                .buffer
+                .get_u32(MessageFileStore::calculate_msg_size_pos(pos));
             if size == 0 {
                 Err(Error::NoMessage)
             } else {
-                let aligned = next_pos(size as usize, ALIGNMENT);
+                let (length, _) = split_size(size);
+                let aligned = length.align_up(ALIGNMENT);
                 // Check to see if we have enough space.
-                let remaining = self.buffer.capacity() - pos;
+                let remaining = self.size() - pos;
                 if remaining < aligned {
                     Err(Error::NotEnoughSpace {
-                        capacity: self.buffer.capacity(),
+                        capacity: self.size(),
                         message_size: size,
                         position: pos,
                         remaining,
@@ -454,10 +1385,7 @@ impl MessageStore for MessageFileStore {
                     let message_id = self
                         .buffer
                         .get_u64(MessageFileStore::calculate_message_id_pos(pos));
-                    let body_size = size as usize - HEADER_SIZE;
-                    let bytes = self
-                        .buffer
-                        .get_bytes(MessageFileStore::calculate_body_pos(pos), body_size);
+                    let (_, bytes) = self.read_body(pos, size, message_type, message_id)?;
                     act(message_type, message_id, bytes);
                     Ok(aligned + pos)
                 }
@@ -472,7 +1400,7 @@ impl MessageStore for MessageFileStore {
         } else if pos + length > self.size() {
             Err(Error::PositionOutOfRange(pos))
         } else {
-            Ok(self.buffer.get_bytes(pos, length))
+            Ok(self.buffer.get_bytes(FILE_HEADER_SIZE + pos, length))
         }
     }
 
@@ -484,18 +1412,21 @@ impl MessageStore for MessageFileStore {
             Err(Error::PositionOutOfRange(pos))
         } else {
             fence(Ordering::Acquire); // This fence is here so we get the latest value. LoadLoad
-            let size = self.buffer.get_u32(pos);
+            let size = self
+                .buffer
+                .get_u32(MessageFileStore::calculate_msg_size_pos(pos));
             if size == 0 {
                 Err(Error::NoMessage)
             } else if self.is_end(pos) {
                 Err(Error::Full)
             } else {
-                let aligned = next_pos(size as usize, ALIGNMENT);
+                let (length, _) = split_size(size);
+                let aligned = length.align_up(ALIGNMENT);
                 // Check to see if we have enough space.
-                let remaining = self.buffer.capacity() - pos;
+                let remaining = self.size() - pos;
                 if remaining < aligned {
                     Err(Error::NotEnoughSpace {
-                        capacity: self.buffer.capacity(),
+                        capacity: self.size(),
                         message_size: size,
                         position: pos,
                         remaining,
@@ -507,12 +1438,16 @@ impl MessageStore for MessageFileStore {
                     let message_id = self
                         .buffer
                         .get_u64(MessageFileStore::calculate_message_id_pos(pos));
-                    let body_size = size as usize - HEADER_SIZE;
-                    let bytes = self
-                        .buffer
-                        .get_bytes(MessageFileStore::calculate_body_pos(pos), body_size);
+                    let (headers, bytes) = self.read_body(pos, size, message_type, message_id)?;
                     let next_pos = aligned + pos;
-                    Ok(MessageRead::new(message_type, message_id, bytes, next_pos))
+                    Ok(MessageRead::new(
+                        message_type,
+                        message_id,
+                        headers,
+                        bytes,
+                        next_pos,
+                        self,
+                    ))
                 }
             }
         }
@@ -530,15 +1465,25 @@ impl MessageStore for MessageFileStore {
         let mut start_message_id = 0;
         let mut last_message_id = 0;
         loop {
-            let size = next_pos(self.buffer.get_u32(current_pos) as usize, HEADER_SIZE);
-            if size == 0 || self.is_end(current_pos) || size + length > max_length {
+            // Check for the end first since the last message can fill the file.
+            let size = if self.is_end(current_pos) {
+                0
+            } else {
+                split_size(
+                    self.buffer
+                        .get_u32(MessageFileStore::calculate_msg_size_pos(current_pos)),
+                )
+                .0
+                .align_up(HEADER_SIZE)
+            };
+            if size == 0 || size + length > max_length {
                 if length == 0 {
                     break Err(Error::NoMessage);
                 } else {
                     break Ok(MessageBlock::new(
                         start_message_id,
                         last_message_id,
-                        self.buffer.get_bytes(pos, length),
+                        self.buffer.get_bytes(FILE_HEADER_SIZE + pos, length),
                         pos + length,
                     ));
                 }
@@ -558,7 +1503,7 @@ impl MessageStore for MessageFileStore {
                         break Ok(MessageBlock::new(
                             start_message_id,
                             last_message_id,
-                            self.buffer.get_bytes(pos, length),
+                            self.buffer.get_bytes(FILE_HEADER_SIZE + pos, length),
                             pos + length,
                         ));
                     } else {
@@ -572,7 +1517,9 @@ impl MessageStore for MessageFileStore {
     fn is_end(&self, pos: usize) -> bool {
         let next = pos + HEADER_SIZE;
         if next < self.size() {
-            self.buffer.get_u32(pos) == std::u32::MAX
+            self.buffer
+                .get_u32(MessageFileStore::calculate_msg_size_pos(pos))
+                == u32::MAX
         } else {
             true
         }
@@ -582,25 +1529,50 @@ impl MessageStore for MessageFileStore {
     /// # Arguments
     /// `posiiton` - The position to write to the buffer.
     /// `msg_type_id` - The type of the message.
+    /// `headers` - The user metadata.  Nothing is stored for it when it is empty.
     /// `buffer` - THe buffer for the message.
     /// # returns
     /// The next position in the buffer.
-    fn write(
+    fn write_with_headers(
         &mut self,
         position: usize,
         msg_type_id: i32,
         message_id: u64,
+        headers: &MessageHeaders,
         buffer: &[u8],
     ) -> Result<usize> {
-        let size = HEADER_SIZE + buffer.len();
-        let aligned = next_pos(size, ALIGNMENT); // This is the aligned value at 32 bits
+        let meta = if headers.is_empty() {
+            None
+        } else {
+            Some(headers.to_bytes())
+        };
+        let meta_size = if meta.is_some() { USER_META_SIZE } else { 0 };
+        let size = HEADER_SIZE + CHECKSUM_SIZE + meta_size + buffer.len();
+        let stored_size = if meta.is_some() {
+            size as u32 | USER_META_FLAG | CHECKSUM_FLAG
+        } else {
+            size as u32 | CHECKSUM_FLAG
+        };
+        let checksum = message_checksum(
+            msg_type_id,
+            message_id,
+            meta.as_ref().map(|meta| &meta[..]),
+            buffer,
+        );
+        let aligned = size.align_up(ALIGNMENT); // This is the aligned value at 32 bits
         if self.size() < position {
             Err(Error::PositionOutOfRange(position))
         } else if aligned > (self.size() - position) {
+            let remaining = self.size() - position;
+            if let Some(direct) = &mut self.direct {
+                // The file size is a multiple of the block size so the end is too.
+                direct.fill(FILE_HEADER_SIZE + position, remaining, 255)?;
+                return Err(Error::Full);
+            }
             // TODO make it full and write all 00s
             let ones = [255; 1000];
-            let mut bucket = self.size() - position;
-            let mut pos = position;
+            let mut bucket = remaining;
+            let mut pos = FILE_HEADER_SIZE + position;
             loop {
                 if bucket > 1000 {
                     self.buffer.write_bytes(pos, &ones[..]);
@@ -612,19 +1584,36 @@ impl MessageStore for MessageFileStore {
                 }
             }
             Err(Error::Full)
+        } else if let Some(direct) = &mut self.direct {
+            MessageFileStore::write_direct(
+                direct,
+                position,
+                aligned,
+                msg_type_id,
+                message_id,
+                stored_size,
+                checksum,
+                meta,
+                buffer,
+            )?;
+            Ok(aligned + position)
         } else {
             let message_id_pos = MessageFileStore::calculate_message_id_pos(position);
             let message_size_pos = MessageFileStore::calculate_msg_size_pos(position);
             let message_type_pos = MessageFileStore::calculate_msg_type_pos(position);
-            let message_body = MessageFileStore::calculate_body_pos(position);
-            // Already verified it is small enought to fit in a u32.
-            let size = size as u32;
+            let mut message_body = MessageFileStore::calculate_body_pos(position);
             self.buffer.put_i32(message_type_pos, msg_type_id);
             self.buffer.put_u64(message_id_pos, message_id);
+            self.buffer.put_u32(message_body, checksum);
+            message_body += CHECKSUM_SIZE;
+            if let Some(meta) = meta {
+                self.buffer.write_bytes(message_body, &meta);
+                message_body += USER_META_SIZE;
+            }
             self.buffer.write_bytes(message_body, buffer);
             // Always write the size last since we are using this to check and we need a StoreStore
             // barrier here.  IE all of the previous stores need to be completed.
-            self.buffer.put_u32_volatile(message_size_pos, size);
+            self.buffer.put_u32_volatile(message_size_pos, stored_size);
             Ok(aligned + position)
         }
     }
@@ -633,7 +1622,10 @@ impl MessageStore for MessageFileStore {
 #[cfg(test)]
 mod tests {
 
-    use crate::file::{MessageFileStore, MessageStore};
+    use crate::file::backend::{InMemoryMessageStore, MessageStoreBackend};
+    use crate::file::{
+        Error, MessageFileStore, MessageFileStoreRead, MessageFileStoreWrite, MessageHeaders,
+    };
     use std::fs::remove_file;
     use std::path::Path;
 
@@ -649,12 +1641,12 @@ mod tests {
         file
     }
 
-    #[test]
-    pub fn create_test() {
-        let test_file = create_test_file("");
-        let (read, write) = unsafe { MessageFileStore::new(&test_file, 2048).unwrap() };
-
-        let read_again = unsafe { MessageFileStore::open_readonly(&test_file).unwrap() };
+    /// Writes a message and reads it back with the store and a second reader of it.
+    fn check_create(
+        read: &MessageFileStoreRead,
+        write: &MessageFileStoreWrite,
+        read_again: &MessageFileStoreRead,
+    ) {
         let bytes: Vec<u8> = vec![10, 11, 12, 13, 14, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32];
         write.write(0, 1, 2, &bytes[0..8]).unwrap();
         write.flush().unwrap();
@@ -674,10 +1666,24 @@ mod tests {
     }
 
     #[test]
-    pub fn read_block_test() {
-        let test_file = create_test_file("read_block_test");
+    pub fn create_test() {
+        let test_file = create_test_file("");
         let (read, write) = unsafe { MessageFileStore::new(&test_file, 2048).unwrap() };
+        let read_again = unsafe { MessageFileStore::open_readonly(&test_file).unwrap() };
+        check_create(&read, &write, &read_again);
+    }
 
+    #[test]
+    pub fn create_in_memory_test() {
+        let stores = InMemoryMessageStore::new();
+        let path = Path::new("/memory/create_test");
+        let (read, write) = stores.create(path, 2048).unwrap();
+        let read_again = stores.open_readonly(path).unwrap();
+        check_create(&read, &write, &read_again);
+    }
+
+    /// Reads the messages written as blocks.
+    fn check_read_block(read: &MessageFileStoreRead, write: &MessageFileStoreWrite) {
         let bytes: Vec<u8> = vec![10, 11, 12, 13, 14, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32];
         let mut pos = write.write(0, 2, 1, &bytes[0..8]).unwrap();
         pos = write.write(pos, 2, 2, &bytes[0..8]).unwrap();
@@ -702,4 +1708,285 @@ mod tests {
         assert_eq!(4, r.message_id_end);
         assert_eq!(128, r.next_pos);
     }
+
+    #[test]
+    pub fn read_block_test() {
+        let test_file = create_test_file("read_block_test");
+        let (read, write) = unsafe { MessageFileStore::new(&test_file, 2048).unwrap() };
+        check_read_block(&read, &write);
+    }
+
+    #[test]
+    pub fn read_block_in_memory_test() {
+        let (read, write) = MessageFileStore::new_in_memory(2048);
+        check_read_block(&read, &write);
+    }
+
+    /// Writes messages with and without the user metadata and reads them back.
+    fn check_headers(read: &MessageFileStoreRead, write: &MessageFileStoreWrite) {
+        let headers = MessageHeaders {
+            tenant: 42,
+            priority: 3,
+            flags: 0b101,
+            reserved: 7,
+        };
+        let bytes: Vec<u8> = vec![10, 11, 12, 13, 14, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32];
+        let mut pos = write.write(0, 2, 1, &bytes[0..8]).unwrap();
+        assert_eq!(32, pos);
+        pos = write
+            .write_with_headers(pos, 2, 2, &headers, &bytes[0..8])
+            .unwrap();
+        // The metadata takes up 8 bytes and the checksum 4.
+        assert_eq!(80, pos);
+        write
+            .write_with_headers(pos, 2, 3, &MessageHeaders::default(), &bytes[0..9])
+            .unwrap();
+        write.flush().unwrap();
+
+        let msg = read.read_new(0).unwrap();
+        assert_eq!(MessageHeaders::default(), msg.headers());
+        let msg = read.read_new(msg.next_pos()).unwrap();
+        assert_eq!(2, msg.message_id());
+        assert_eq!(headers, msg.headers());
+        assert_eq!(&bytes[0..8], msg.bytes());
+        assert_eq!(80, msg.next_pos());
+        let msg = read.read_new(msg.next_pos()).unwrap();
+        assert_eq!(3, msg.message_id());
+        assert_eq!(MessageHeaders::default(), msg.headers());
+        assert_eq!(&bytes[0..9], msg.bytes());
+
+        read.read(32, |msg_type, message_id, body| {
+            assert_eq!(2, msg_type);
+            assert_eq!(2, message_id);
+            assert_eq!(&bytes[0..8], body);
+        })
+        .unwrap();
+        let r = read.read_block(0, 10, 1024).unwrap();
+        assert_eq!(3, r.message_id_end);
+        assert_eq!(112, r.next_pos);
+
+        assert_eq!(headers, MessageHeaders::from_bytes(&headers.to_bytes()));
+        assert!(!headers.is_empty());
+    }
+
+    #[test]
+    pub fn checksum_test() {
+        use crate::file::header::FILE_HEADER_SIZE;
+        use crate::file::{CHECKSUM_SIZE, HEADER_SIZE};
+        use std::fs::{read, write};
+
+        let test_file = create_test_file("checksum_test");
+        {
+            let (_, store) = unsafe { MessageFileStore::new(&test_file, 2048).unwrap() };
+            let pos = store.write(0, 1, 1, &[1; 20]).unwrap();
+            store.write(pos, 1, 2, &[2; 20]).unwrap();
+            store.flush().unwrap();
+        }
+        // Flip a byte in the body of the second message on disk.
+        let mut bytes = read(&test_file).unwrap();
+        bytes[FILE_HEADER_SIZE + 48 + HEADER_SIZE + CHECKSUM_SIZE + 3] ^= 0x10;
+        write(&test_file, &bytes).unwrap();
+
+        let store = unsafe { MessageFileStore::open_readonly(&test_file).unwrap() };
+        let msg = store.read_new(0).unwrap();
+        assert_eq!(&[1; 20], msg.bytes());
+        assert_eq!(48, msg.next_pos());
+        match store.read_new(48) {
+            Err(Error::ChecksumMismatch {
+                message_id,
+                position,
+            }) => {
+                assert_eq!(2, message_id);
+                assert_eq!(48, position);
+            }
+            _ => panic!("The corrupt message was read"),
+        }
+        assert!(matches!(
+            store.read(48, |_, _, _| panic!("The corrupt message was read")),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    pub fn headers_test() {
+        let test_file = create_test_file("headers_test");
+        let (read, write) = unsafe { MessageFileStore::new(&test_file, 2048).unwrap() };
+        check_headers(&read, &write);
+    }
+
+    #[test]
+    pub fn headers_in_memory_test() {
+        let (read, write) = MessageFileStore::new_in_memory(2048);
+        check_headers(&read, &write);
+    }
+
+    /// Fills the store until it is full.  The rest of it is padded so the readers know to move on.
+    fn check_full(read: &MessageFileStoreRead, write: &MessageFileStoreWrite) {
+        let body = [7; 100];
+        let mut pos = 0;
+        let mut message_id = 1;
+        let end = loop {
+            match write.write(pos, 1, message_id, &body) {
+                Ok(next) => {
+                    pos = next;
+                    message_id += 1;
+                }
+                Err(Error::Full) => break pos,
+                Err(e) => panic!("Unexpected error {}", e),
+            }
+        };
+        // The messages are 128 bytes so 16 fit.
+        assert_eq!(17, message_id);
+        assert_eq!(2048, end);
+        pos = 0;
+        for id in 1..message_id {
+            let msg = read.read_new(pos).unwrap();
+            assert_eq!(id, msg.message_id());
+            assert_eq!(&body[..], msg.bytes());
+            pos = msg.next_pos();
+        }
+        assert!(read.is_end(pos));
+        match read.read_new(pos) {
+            Err(Error::PositionOutOfRange(_)) => {}
+            _ => panic!("Expected the end of the store"),
+        }
+
+        // A message that doesn't fit in the rest of the store pads it.
+        write.clear(0, 2048);
+        let pos = write.write(0, 1, 1, &[1; 1000]).unwrap();
+        match write.write(pos, 1, 2, &[1; 1500]) {
+            Err(Error::Full) => {}
+            _ => panic!("Expected the store to be full"),
+        }
+        assert!(read.is_end(pos));
+        match read.read_new(pos) {
+            Err(Error::Full) => {}
+            _ => panic!("Expected the padding"),
+        }
+    }
+
+    #[test]
+    pub fn full_test() {
+        let test_file = create_test_file("full_test");
+        let (read, write) = unsafe { MessageFileStore::new(&test_file, 2048).unwrap() };
+        check_full(&read, &write);
+    }
+
+    #[test]
+    pub fn full_in_memory_test() {
+        let (read, write) = MessageFileStore::new_in_memory(2048);
+        check_full(&read, &write);
+        assert!(write.file().is_none());
+        write.flush().unwrap();
+    }
+
+    /// Writes messages of different sizes until the file is full.
+    /// # Returns
+    /// The ids of the messages written.
+    #[cfg(all(target_os = "linux", feature = "direct-io"))]
+    fn write_until_full(write: &crate::file::MessageFileStoreWrite) -> Vec<u64> {
+        let mut ids = Vec::new();
+        let mut pos = 0;
+        for id in 1..1000u64 {
+            // Some of the messages are bigger than a block.
+            let body = vec![id as u8; (id as usize * 37) % 5000];
+            match write.write(pos, 3, id, &body) {
+                Ok(next) => {
+                    ids.push(id);
+                    pos = next;
+                }
+                Err(crate::file::Error::Full) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        write.flush().unwrap();
+        ids
+    }
+
+    #[cfg(all(target_os = "linux", feature = "direct-io"))]
+    #[test]
+    pub fn direct_write_test() {
+        use crate::file::header::FILE_HEADER_SIZE;
+        use crate::file::{Error, WriteMode};
+        use std::fs::read;
+
+        let direct_file = create_test_file("direct");
+        let mapped_file = create_test_file("direct_mapped");
+        let mode = WriteMode::Direct { block_size: 4096 };
+        let (read_direct, write_direct) =
+            unsafe { MessageFileStore::new_with_mode(&direct_file, 0x9000, mode).unwrap() };
+        let ids = write_until_full(&write_direct);
+        assert!(ids.len() > 10);
+
+        // Reads back what was written through the memory map.
+        let mut pos = 0;
+        for id in ids.iter() {
+            pos = read_direct
+                .read(pos, |msg_type, message_id, body| {
+                    assert_eq!(3, msg_type);
+                    assert_eq!(*id, message_id);
+                    assert_eq!((*id as usize * 37) % 5000, body.len());
+                    assert!(body.iter().all(|b| *b == *id as u8));
+                })
+                .unwrap();
+        }
+        assert!(read_direct.is_end(pos));
+        assert!(matches!(read_direct.read_new(pos), Err(Error::Full)));
+
+        // Replays the same from the file once it is opened again.
+        let replay = unsafe { MessageFileStore::open_readonly(&direct_file).unwrap() };
+        let mut pos = 0;
+        let mut replayed = Vec::new();
+        while let Ok(msg) = replay.read_new(pos) {
+            replayed.push(msg.message_id());
+            pos = msg.next_pos();
+        }
+        assert_eq!(ids, replayed);
+
+        // The file is the same as one written through the memory map.  The direct file is padded
+        // to the block size after the header.
+        let size = write_direct.capacity();
+        let (_, write_mapped) = unsafe { MessageFileStore::new(&mapped_file, size).unwrap() };
+        assert_eq!(ids, write_until_full(&write_mapped));
+        assert_eq!(
+            read(&mapped_file).unwrap()[FILE_HEADER_SIZE..],
+            read(&direct_file).unwrap()[FILE_HEADER_SIZE..]
+        );
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "direct-io")))]
+    #[test]
+    pub fn direct_unsupported_test() {
+        use crate::file::WriteMode;
+        use std::io::ErrorKind;
+
+        let file = create_test_file("direct_unsupported");
+        let mode = WriteMode::Direct { block_size: 4096 };
+        let err = unsafe {
+            MessageFileStore::new_with_mode(&file, 0x4000, mode)
+                .err()
+                .unwrap()
+        };
+        assert_eq!(ErrorKind::Unsupported, err.kind());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "direct-io"))]
+    #[test]
+    pub fn direct_block_size_test() {
+        use crate::file::WriteMode;
+        use std::io::ErrorKind;
+
+        let file = create_test_file("direct_block");
+        let mode = WriteMode::Direct { block_size: 1000 };
+        let err = unsafe {
+            MessageFileStore::new_with_mode(&file, 0x4000, mode)
+                .err()
+                .unwrap()
+        };
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        // The file size is rounded up to the block size.
+        let mode = WriteMode::Direct { block_size: 4096 };
+        let (read, _write) = unsafe { MessageFileStore::new_with_mode(&file, 5000, mode).unwrap() };
+        assert_eq!(8192, read.read_section(0, 8192).unwrap().len());
+    }
 }
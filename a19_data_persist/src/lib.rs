@@ -1,12 +1,28 @@
+pub mod codec;
+pub mod error;
 pub mod file;
+pub mod map;
 pub mod raft;
 pub mod message_stream;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 use futures::channel::oneshot;
 
+pub use crate::error::{PersistError, Result};
+
 /// Represents the committing of data as a future.  Since this will be distributed at some point we
 /// want to be able to batch events.
-type CommitFuture<TOUT> = oneshot::Sender<TOUT>;
+pub type CommitFuture<TOUT> = oneshot::Receiver<TOUT>;
+
+/// An event read back from the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event<'a> {
+    /// The commit key that was returned when the change was added.
+    pub commit_key: u64,
+    /// The value that was saved.
+    pub value: &'a [u8],
+}
 
 /// Used to presist an event stream to a persited store like disk.  Need to also decided how we are
 /// going to stream the events.
@@ -17,12 +33,18 @@ pub trait PersitEventStream {
     /// `value` - The value to save to persist.  This doesn't have to happen immediately.  Need to
     /// define your own header and for the message when you save it.  The point of this stream is
     /// it doesn't care.
+    /// # Returns
+    /// The future that completes with the commit key once the change has been committed.  The
+    /// future is canceled if the change couldn't be saved.
     fn add_change(&self, value: &[u8]) -> CommitFuture<u64>;
 
-    /// Used to replay the events from a specified number.
-    /// #Arguments
-    /// commit_key - The commit key to read the events from.
-    fn get_events_from(&self, commit_key: u64) -> &[u8];
+    /// Used to replay the events from a specified number.  Only the committed events are returned.
+    /// # Arguments
+    /// `commit_key` - The commit key to read the events from.
+    fn events_from(&self, commit_key: u64) -> Box<dyn Iterator<Item = Event<'_>> + '_>;
+
+    /// The commit key of the last event that has been committed.
+    fn committed_key(&self) -> u64;
 }
 
 #[cfg(test)]
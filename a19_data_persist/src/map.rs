@@ -0,0 +1,729 @@
+//! A `MrswMap` backed by a journal so it can be rebuilt after a restart or as it was at any
+//! committed event.  Each event is appended to the journal before it is applied to the map and the
+//! whole map is written to a snapshot file every so often so only the events after the newest
+//! snapshot need to be replayed.
+//!```text
+//! snapshots:  (empty) ............ map.snapshot.500 ............ map.snapshot.1000
+//! journal:    1 2 3 ... 499 500 501 ... 999 1000 1001 1002 ...
+//!                                             |____________|
+//!                        state_at(1002) = snapshot 1000 + events 1001..=1002
+//!```
+//! The commit keys of the journal need to go up by one for each event so the map has to be the
+//! only thing writing to the journal.  A gap in the keys means the events were removed by the
+//! retention.
+//!
+//! The layout of a snapshot file.  The entries are encoded by the `SnapshotCodec`.
+//!```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Id of the last event in the snapshot                          |
+//! |                                                               |
+//! +---------------------------------------------------------------+ 64
+//! | Number of entries                                             |
+//! |                                                               |
+//! +---------------------------------------------------------------+ 128
+//! | Length of the entry                                           |
+//! +---------------------------------------------------------------+
+//! |                   Entry                                       ...
+//! ...                                                             |
+//! +---------------------------------------------------------------+
+//! | ... the rest of the entries                                   ...
+//! +---------------------------------------------------------------+
+//! | CRC32 of the bytes before it                                  |
+//! +---------------------------------------------------------------+
+//!```
+use crate::codec;
+use crate::codec::{encode, MessageCodec};
+use crate::error::{Context, PersistError, ResultExt};
+use crate::file;
+use crate::PersitEventStream;
+use a19_concurrent::buffer::DirectByteBuffer;
+use a19_concurrent::map::mrsw_map::{ApplyChanges, MrswMap, MrswMapReader, MrswMapWriter};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::path::MAIN_SEPARATOR;
+use std::sync::Arc;
+
+/// The postfix of the snapshot files.
+const SNAPSHOT_POSTFIX: &str = "snapshot";
+/// The size of the header at the start of a snapshot.
+const SNAPSHOT_HEADER_SIZE: usize = 16;
+/// The size of the length in front of each entry.
+const ENTRY_LENGTH_SIZE: usize = 4;
+/// The size of the checksum at the end of a snapshot.
+const CRC_SIZE: usize = 4;
+
+/// Encodes the entries of the map for the snapshots.
+pub trait SnapshotCodec<K, V> {
+    /// Encodes an entry onto the end of the bytes.
+    /// # Arguments
+    /// `key` - The key of the entry.
+    /// `value` - The value of the entry.
+    /// `bytes` - The bytes to add the entry to.
+    fn encode_entry(&self, key: &K, value: &V, bytes: &mut Vec<u8>);
+
+    /// Decodes an entry.
+    /// # Arguments
+    /// `bytes` - The bytes written by `encode_entry`.
+    fn decode_entry(&self, bytes: &[u8]) -> codec::Result<(K, V)>;
+}
+
+/// The keys that are different between two versions of a map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapDiff<K> {
+    /// The keys only in the newer map.
+    pub inserted: Vec<K>,
+    /// The keys only in the older map.
+    pub removed: Vec<K>,
+    /// The keys in both maps with a different value.
+    pub changed: Vec<K>,
+}
+
+impl<K> MapDiff<K> {
+    /// True if the maps are the same.
+    pub fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// A map where the events are stored in a journal before they are applied.
+pub struct PersistedMrswMap<K, V, E, A, J>
+where
+    K: Hash + Eq,
+    A: ApplyChanges<K, V, E>,
+{
+    /// The journal the events are stored in.
+    journal: Arc<J>,
+    /// The directory the snapshots are stored in.
+    directory: String,
+    /// The name of the map used for the snapshot files.
+    name: String,
+    /// Applies the events when replaying the journal.
+    apply: A,
+    reader: MrswMapReader<K, V, E, A>,
+    writer: MrswMapWriter<K, V, E, A>,
+    /// The id of the last event applied to the map.
+    message_id: u64,
+}
+
+impl<K, V, E, A, J> PersistedMrswMap<K, V, E, A, J>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    E: MessageCodec,
+    A: ApplyChanges<K, V, E> + SnapshotCodec<K, V> + Clone,
+    J: PersitEventStream,
+{
+    /// Opens the map by loading the newest snapshot and replaying the events after it.
+    /// # Arguments
+    /// `journal` - The journal the events are stored in.  Nothing else can write to it.
+    /// `directory` - The directory to store the snapshots in.
+    /// `name` - The name of the map.  Used for the names of the snapshot files.
+    /// `apply` - Applies the events to the map.
+    pub fn open(journal: Arc<J>, directory: &str, name: &str, apply: A) -> crate::Result<Self> {
+        fs::create_dir_all(directory).with_path(directory)?;
+        let (snapshot_id, mut map) = load_snapshot(directory, name, u64::MAX, &apply)?;
+        let message_id = replay(journal.as_ref(), &apply, &mut map, snapshot_id, None)?;
+        let (reader, writer) = MrswMap::new(map.clone(), map, apply.clone());
+        Ok(PersistedMrswMap {
+            journal,
+            directory: directory.to_owned(),
+            name: name.to_owned(),
+            apply,
+            reader,
+            writer,
+            message_id,
+        })
+    }
+
+    /// Gets a reader for the map.
+    pub fn reader(&self) -> MrswMapReader<K, V, E, A> {
+        self.reader.clone()
+    }
+
+    /// The id of the last event applied to the map.
+    pub fn message_id(&self) -> u64 {
+        self.message_id
+    }
+
+    /// Stores an event in the journal and applies it to the map once it has been committed.
+    /// # Arguments
+    /// `event` - The event to apply.
+    /// # Returns
+    /// The id of the event in the journal.
+    pub async fn apply(&mut self, event: E) -> crate::Result<u64> {
+        let (buffer, length) = encode(&event);
+        let message_id = self.journal.add_change(buffer.get_bytes(0, length)).await?;
+        self.writer.add_event(event);
+        self.writer.commit();
+        self.message_id = message_id;
+        Ok(message_id)
+    }
+
+    /// Writes the map to a snapshot file so the events before it don't need to be replayed.
+    /// # Returns
+    /// The id of the last event in the snapshot.
+    pub fn snapshot(&self) -> crate::Result<u64> {
+        let message_id = self.message_id;
+        let apply = &self.apply;
+        let bytes = self
+            .reader
+            .get_all(|map| encode_snapshot(message_id, map, apply));
+        let path = snapshot_name(&self.directory, &self.name, message_id);
+        let temp = format!("{}.tmp", path);
+        fs::write(&temp, &bytes).with_path(temp.as_str())?;
+        fs::rename(&temp, &path).with_path(path.as_str())?;
+        Ok(message_id)
+    }
+
+    /// Rebuilds the map as it was after an event was applied.  The live map isn't touched.
+    /// # Arguments
+    /// `message_id` - The id of the last event to apply.  `0` is the empty map.
+    /// # Returns
+    /// The map or `PersistError::Retained` if the events needed have been removed.
+    pub fn state_at(&self, message_id: u64) -> crate::Result<HashMap<K, V>> {
+        let (snapshot_id, mut map) =
+            load_snapshot(&self.directory, &self.name, message_id, &self.apply)?;
+        replay(
+            self.journal.as_ref(),
+            &self.apply,
+            &mut map,
+            snapshot_id,
+            Some(message_id),
+        )?;
+        Ok(map)
+    }
+
+    /// Gets the keys that changed between two events.
+    /// # Arguments
+    /// `from_id` - The id of the event for the older map.
+    /// `to_id` - The id of the event for the newer map.
+    pub fn diff(&self, from_id: u64, to_id: u64) -> crate::Result<MapDiff<K>>
+    where
+        V: PartialEq,
+    {
+        let from = self.state_at(from_id)?;
+        let to = self.state_at(to_id)?;
+        Ok(diff_maps(&from, &to))
+    }
+}
+
+/// Gets the keys that are different between two maps.
+/// # Arguments
+/// `from` - The older map.
+/// `to` - The newer map.
+pub fn diff_maps<K: Hash + Eq + Clone, V: PartialEq>(
+    from: &HashMap<K, V>,
+    to: &HashMap<K, V>,
+) -> MapDiff<K> {
+    let mut diff = MapDiff {
+        inserted: Vec::new(),
+        removed: Vec::new(),
+        changed: Vec::new(),
+    };
+    for (key, value) in to.iter() {
+        match from.get(key) {
+            Some(old) if old != value => diff.changed.push(key.clone()),
+            Some(_) => {}
+            None => diff.inserted.push(key.clone()),
+        }
+    }
+    for key in from.keys() {
+        if !to.contains_key(key) {
+            diff.removed.push(key.clone());
+        }
+    }
+    diff
+}
+
+/// Creates the name of a snapshot file.
+/// # Arguments
+/// `directory` - The directory the snapshots are in.
+/// `name` - The name of the map.
+/// `message_id` - The id of the last event in the snapshot.
+fn snapshot_name(directory: &str, name: &str, message_id: u64) -> String {
+    format!(
+        "{}{}{}.{}.{}",
+        directory, MAIN_SEPARATOR, name, SNAPSHOT_POSTFIX, message_id
+    )
+}
+
+/// Finds the newest snapshot at or before an event.
+/// # Arguments
+/// `directory` - The directory the snapshots are in.
+/// `name` - The name of the map.
+/// `message_id` - The id of the newest event the snapshot can have.
+/// # Returns
+/// The id of the last event in the snapshot or `None` if there isn't one.
+fn newest_snapshot(directory: &str, name: &str, message_id: u64) -> crate::Result<Option<u64>> {
+    let prefix = format!("{}.{}.", name, SNAPSHOT_POSTFIX);
+    let mut newest = None;
+    for entry in fs::read_dir(directory).with_path(directory)? {
+        let entry = entry.with_path(directory)?;
+        let file_name = entry.file_name();
+        let id = file_name
+            .to_str()
+            .and_then(|f| f.strip_prefix(prefix.as_str()))
+            .and_then(|id| id.parse::<u64>().ok());
+        if let Some(id) = id {
+            if id <= message_id && newest.map(|n| id > n).unwrap_or(true) {
+                newest = Some(id);
+            }
+        }
+    }
+    Ok(newest)
+}
+
+/// Loads the newest snapshot at or before an event.
+/// # Arguments
+/// `directory` - The directory the snapshots are in.
+/// `name` - The name of the map.
+/// `message_id` - The id of the newest event the snapshot can have.
+/// `codec` - Decodes the entries.
+/// # Returns
+/// The id of the last event in the snapshot and the map.  The map is empty if there isn't a
+/// snapshot.
+fn load_snapshot<K, V, C>(
+    directory: &str,
+    name: &str,
+    message_id: u64,
+    codec: &C,
+) -> crate::Result<(u64, HashMap<K, V>)>
+where
+    K: Hash + Eq,
+    C: SnapshotCodec<K, V>,
+{
+    match newest_snapshot(directory, name, message_id)? {
+        Some(id) => {
+            let path = snapshot_name(directory, name, id);
+            let bytes = fs::read(&path).with_path(path.as_str())?;
+            decode_snapshot(&bytes, codec).map_err(|e| e.with_path(path))
+        }
+        None => Ok((0, HashMap::new())),
+    }
+}
+
+/// Encodes a snapshot of a map.
+/// # Arguments
+/// `message_id` - The id of the last event applied to the map.
+/// `map` - The map to encode.
+/// `codec` - Encodes the entries.
+fn encode_snapshot<K, V, C: SnapshotCodec<K, V>>(
+    message_id: u64,
+    map: &HashMap<K, V>,
+    codec: &C,
+) -> Vec<u8> {
+    let mut bytes = vec![0; SNAPSHOT_HEADER_SIZE];
+    BigEndian::write_u64(&mut bytes[0..8], message_id);
+    BigEndian::write_u64(&mut bytes[8..16], map.len() as u64);
+    for (key, value) in map.iter() {
+        let start = bytes.len();
+        bytes.extend_from_slice(&[0; ENTRY_LENGTH_SIZE]);
+        codec.encode_entry(key, value, &mut bytes);
+        let length = (bytes.len() - start - ENTRY_LENGTH_SIZE) as u32;
+        BigEndian::write_u32(&mut bytes[start..start + ENTRY_LENGTH_SIZE], length);
+    }
+    let crc = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+    bytes
+}
+
+/// Decodes a snapshot.
+/// # Arguments
+/// `bytes` - The bytes of the snapshot file.
+/// `codec` - Decodes the entries.
+/// # Returns
+/// The id of the last event in the snapshot and the map.
+fn decode_snapshot<K, V, C>(bytes: &[u8], codec: &C) -> crate::Result<(u64, HashMap<K, V>)>
+where
+    K: Hash + Eq,
+    C: SnapshotCodec<K, V>,
+{
+    let corrupt = |reason: &str| PersistError::CorruptMessage {
+        context: Context::default(),
+        reason: reason.to_owned(),
+    };
+    if bytes.len() < SNAPSHOT_HEADER_SIZE + CRC_SIZE {
+        return Err(corrupt("The snapshot is too short."));
+    }
+    let (body, crc) = bytes.split_at(bytes.len() - CRC_SIZE);
+    if crc32fast::hash(body) != BigEndian::read_u32(crc) {
+        return Err(corrupt("The checksum of the snapshot doesn't match."));
+    }
+    let message_id = BigEndian::read_u64(&body[0..8]);
+    let count = BigEndian::read_u64(&body[8..16]);
+    let mut map = HashMap::with_capacity(count as usize);
+    let mut pos = SNAPSHOT_HEADER_SIZE;
+    for _ in 0..count {
+        codec::check_length(&body[pos..], ENTRY_LENGTH_SIZE)?;
+        let length = BigEndian::read_u32(&body[pos..pos + ENTRY_LENGTH_SIZE]) as usize;
+        pos += ENTRY_LENGTH_SIZE;
+        codec::check_length(&body[pos..], length)?;
+        let (key, value) = codec.decode_entry(&body[pos..pos + length])?;
+        map.insert(key, value);
+        pos += length;
+    }
+    Ok((message_id, map))
+}
+
+/// Replays the events in the journal onto a map.
+/// # Arguments
+/// `journal` - The journal to read the events from.
+/// `apply` - Applies the events to the map.
+/// `map` - The map to apply the events to.
+/// `after_id` - The id of the last event already in the map.
+/// `to_id` - The id of the last event to apply.  `None` to apply all of the committed events.
+/// # Returns
+/// The id of the last event applied.
+fn replay<K, V, E, A, J>(
+    journal: &J,
+    apply: &A,
+    map: &mut HashMap<K, V>,
+    after_id: u64,
+    to_id: Option<u64>,
+) -> crate::Result<u64>
+where
+    K: Hash + Eq,
+    E: MessageCodec,
+    A: ApplyChanges<K, V, E>,
+    J: PersitEventStream,
+{
+    let committed = journal.committed_key();
+    let to_id = to_id.unwrap_or(committed);
+    if to_id > committed {
+        return Err(PersistError::from(file::Error::NoMessage).with_id(to_id));
+    }
+    let mut last_id = after_id;
+    if last_id < to_id {
+        for event in journal.events_from(after_id + 1) {
+            if event.commit_key != last_id + 1 {
+                break;
+            }
+            let value = E::decode(event.value)
+                .map_err(|e| PersistError::from(e).with_id(event.commit_key))?;
+            apply.apply(map, &value);
+            last_id = event.commit_key;
+            if last_id == to_id {
+                break;
+            }
+        }
+    }
+    if last_id < to_id {
+        // The events are committed so they have been removed.
+        Err(PersistError::Retained {
+            context: Context::default(),
+        }
+        .with_id(last_id + 1))
+    } else {
+        Ok(last_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::codec::check_length;
+    use crate::file::MessageRead;
+    use crate::map::*;
+    use crate::raft::{startup_single_node, InfallibleProcessor, PersistedMessageFile};
+    use crate::testing::seeded_rng;
+    use crate::{CommitFuture, Event};
+    use futures::executor::block_on;
+    use rand::Rng;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_map";
+    const TEST_PREFIX: &str = "journal";
+
+    struct NoOp;
+
+    impl InfallibleProcessor for NoOp {
+        fn handle<'a>(&mut self, _read: &MessageRead<'a>) {}
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum SessionEvent {
+        Put(u64, u64),
+        Remove(u64),
+    }
+
+    const PUT: u8 = 1;
+    const REMOVE: u8 = 2;
+
+    impl MessageCodec for SessionEvent {
+        const MESSAGE_TYPE: i32 = 1;
+
+        fn encoded_size(&self) -> usize {
+            17
+        }
+
+        fn encode_into<B: DirectByteBuffer>(&self, buffer: &mut B, pos: usize) -> usize {
+            match *self {
+                SessionEvent::Put(key, value) => {
+                    buffer.write_bytes(pos, &[PUT]);
+                    buffer.put_u64(pos + 1, key);
+                    buffer.put_u64(pos + 9, value);
+                }
+                SessionEvent::Remove(key) => {
+                    buffer.write_bytes(pos, &[REMOVE]);
+                    buffer.put_u64(pos + 1, key);
+                    buffer.put_u64(pos + 9, 0);
+                }
+            }
+            17
+        }
+
+        fn decode(bytes: &[u8]) -> codec::Result<Self> {
+            check_length(bytes, 17)?;
+            let key = BigEndian::read_u64(&bytes[1..9]);
+            match bytes[0] {
+                PUT => Ok(SessionEvent::Put(key, BigEndian::read_u64(&bytes[9..17]))),
+                REMOVE => Ok(SessionEvent::Remove(key)),
+                t => Err(codec::Error::Invalid(format!("Unknown event {}", t))),
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct Sessions;
+
+    impl ApplyChanges<u64, u64, SessionEvent> for Sessions {
+        fn apply(&self, map: &mut HashMap<u64, u64>, event: &SessionEvent) {
+            match *event {
+                SessionEvent::Put(key, value) => {
+                    map.insert(key, value);
+                }
+                SessionEvent::Remove(key) => {
+                    map.remove(&key);
+                }
+            }
+        }
+    }
+
+    impl SnapshotCodec<u64, u64> for Sessions {
+        fn encode_entry(&self, key: &u64, value: &u64, bytes: &mut Vec<u8>) {
+            bytes.extend_from_slice(&key.to_be_bytes());
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+
+        fn decode_entry(&self, bytes: &[u8]) -> codec::Result<(u64, u64)> {
+            check_length(bytes, 16)?;
+            Ok((
+                BigEndian::read_u64(&bytes[0..8]),
+                BigEndian::read_u64(&bytes[8..16]),
+            ))
+        }
+    }
+
+    type SessionMap<J = PersistedMessageFile> =
+        PersistedMrswMap<u64, u64, SessionEvent, Sessions, J>;
+
+    fn start(dir: &str) -> Arc<PersistedMessageFile> {
+        Arc::new(startup_single_node(
+            dir.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x10000,
+            0x10000,
+            NoOp,
+            0x10000,
+            0x40,
+        ))
+    }
+
+    /// A journal where the events before the first key have been removed.
+    struct MemoryJournal {
+        first_key: u64,
+        events: Vec<Vec<u8>>,
+    }
+
+    impl MemoryJournal {
+        fn new(first_key: u64, events: &[SessionEvent]) -> Self {
+            MemoryJournal {
+                first_key,
+                events: events
+                    .iter()
+                    .map(|e| {
+                        let (buffer, length) = encode(e);
+                        buffer.get_bytes(0, length).to_vec()
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    impl PersitEventStream for MemoryJournal {
+        fn add_change(&self, _value: &[u8]) -> CommitFuture<u64> {
+            unreachable!("The events are added when the journal is created.")
+        }
+
+        fn events_from(&self, commit_key: u64) -> Box<dyn Iterator<Item = Event<'_>> + '_> {
+            let first_key = self.first_key;
+            Box::new(
+                self.events
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, value)| Event {
+                        commit_key: first_key + i as u64,
+                        value,
+                    })
+                    .filter(move |e| e.commit_key >= commit_key),
+            )
+        }
+
+        fn committed_key(&self) -> u64 {
+            self.first_key + self.events.len() as u64 - 1
+        }
+    }
+
+    fn clean(dir: &str) {
+        let path = Path::new(dir);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn state_at_test() {
+        let dir = format!("{}_state_at", TEST_DIR);
+        clean(&dir);
+        let journal = start(&dir);
+        let mut map = SessionMap::open(journal.clone(), &dir, "sessions", Sessions).unwrap();
+        let mut rng = seeded_rng(1715);
+        // The map after each event with the index being the id of the event.
+        let mut reference = vec![HashMap::new()];
+        for i in 1..=200u64 {
+            let key = rng.gen_range(0, 20);
+            let event = if rng.gen_range(0, 4) == 0 {
+                SessionEvent::Remove(key)
+            } else {
+                SessionEvent::Put(key, rng.gen())
+            };
+            let mut next = reference.last().unwrap().clone();
+            Sessions.apply(&mut next, &event);
+            reference.push(next);
+            assert_eq!(i, block_on(map.apply(event)).unwrap());
+            if i == 50 || i == 120 {
+                assert_eq!(i, map.snapshot().unwrap());
+            }
+        }
+        for id in [0, 1, 49, 50, 51, 119, 120, 121, 175, 200].iter() {
+            assert_eq!(reference[*id], map.state_at(*id as u64).unwrap());
+        }
+        for _ in 0..20 {
+            let id = rng.gen_range(0, 201);
+            assert_eq!(reference[id], map.state_at(id as u64).unwrap());
+        }
+        // Reading the old state doesn't change the live map.
+        let live = map.reader().get_all(|m| m.clone());
+        assert_eq!(reference[200], live);
+        assert!(map.state_at(201).is_err());
+
+        // Opening again starts at the newest snapshot and replays the rest.
+        drop(map);
+        let map = SessionMap::open(journal.clone(), &dir, "sessions", Sessions).unwrap();
+        assert_eq!(200, map.message_id());
+        assert_eq!(reference[200], map.reader().get_all(|m| m.clone()));
+        drop(map);
+        Arc::try_unwrap(journal).ok().unwrap().stop();
+    }
+
+    #[test]
+    pub fn diff_test() {
+        let dir = format!("{}_diff", TEST_DIR);
+        clean(&dir);
+        let journal = start(&dir);
+        let mut map = SessionMap::open(journal.clone(), &dir, "sessions", Sessions).unwrap();
+        let history = [
+            SessionEvent::Put(1, 10),
+            SessionEvent::Put(2, 20),
+            SessionEvent::Put(3, 30),
+            SessionEvent::Remove(2),
+            SessionEvent::Put(1, 11),
+            SessionEvent::Put(4, 40),
+            SessionEvent::Put(3, 30),
+        ];
+        for event in history.iter() {
+            block_on(map.apply(*event)).unwrap();
+        }
+        let sorted = |mut diff: MapDiff<u64>| {
+            diff.inserted.sort();
+            diff.removed.sort();
+            diff.changed.sort();
+            diff
+        };
+        assert_eq!(
+            MapDiff {
+                inserted: vec![1, 2, 3],
+                removed: vec![],
+                changed: vec![],
+            },
+            sorted(map.diff(0, 3).unwrap())
+        );
+        assert_eq!(
+            MapDiff {
+                inserted: vec![4],
+                removed: vec![2],
+                changed: vec![1],
+            },
+            sorted(map.diff(3, 7).unwrap())
+        );
+        // Going back in time flips the inserts and removes.
+        assert_eq!(
+            MapDiff {
+                inserted: vec![2],
+                removed: vec![4],
+                changed: vec![1],
+            },
+            sorted(map.diff(7, 3).unwrap())
+        );
+        // Putting the same value back isn't a change.
+        assert!(map.diff(6, 7).unwrap().is_empty());
+        drop(map);
+        Arc::try_unwrap(journal).ok().unwrap().stop();
+    }
+
+    #[test]
+    pub fn retained_test() {
+        let dir = format!("{}_retained", TEST_DIR);
+        clean(&dir);
+        let events: Vec<SessionEvent> =
+            (1..=200u64).map(|i| SessionEvent::Put(i % 13, i)).collect();
+        let mut reference = vec![HashMap::new()];
+        for event in events.iter() {
+            let mut next = reference.last().unwrap().clone();
+            Sessions.apply(&mut next, event);
+            reference.push(next);
+        }
+        let full = Arc::new(MemoryJournal::new(1, &events[..150]));
+        let map = SessionMap::<MemoryJournal>::open(full, &dir, "retained", Sessions).unwrap();
+        assert_eq!(150, map.snapshot().unwrap());
+        drop(map);
+
+        // The first 100 events have been removed.
+        let journal = Arc::new(MemoryJournal::new(101, &events[100..]));
+        let map =
+            SessionMap::<MemoryJournal>::open(journal.clone(), &dir, "retained", Sessions).unwrap();
+        assert_eq!(reference[200], map.reader().get_all(|m| m.clone()));
+        match map.state_at(10) {
+            Err(PersistError::Retained { context }) => assert_eq!(Some(1), context.id),
+            r => panic!("Expected the events to be retained: {:?}", r),
+        }
+        assert!(matches!(
+            map.diff(10, 160),
+            Err(PersistError::Retained { .. })
+        ));
+        // The snapshot covers the removed events.
+        assert_eq!(reference[150], map.state_at(150).unwrap());
+        assert_eq!(reference[160], map.state_at(160).unwrap());
+
+        // Can't open without the snapshot.
+        assert!(matches!(
+            SessionMap::<MemoryJournal>::open(journal, &dir, "other", Sessions),
+            Err(PersistError::Retained { .. })
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+//! At least once delivery on top of a consumer group.  Each message is handed out with a delivery
+//! tag and the group offset is only moved once every message before it has been acknowledged.
+//! The outstanding messages are only kept in memory, so after a restart everything after the
+//! committed offset is delivered again.
+use crate::message_stream::consumer_group::{ConsumerGroup, Result};
+use crate::message_stream::{CommittedMessage, TopicHandle};
+use std::collections::{BTreeMap, BTreeSet};
+use a19_core::clock::Clock;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A message handed out by the consumer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// The tag used to acknowledge the message.
+    pub tag: u64,
+    /// The number of times the message has been delivered.
+    pub attempt: u32,
+    pub message: CommittedMessage,
+}
+
+/// A message that has been delivered but isn't part of the committed offset yet.
+struct Outstanding {
+    message: CommittedMessage,
+    /// The monotonic time in nanoseconds when the message was delivered.
+    delivered_at: u64,
+    attempt: u32,
+    acked: bool,
+}
+
+/// Delivers the messages of a consumer group until they are acknowledged.
+pub struct AckingConsumer {
+    group: ConsumerGroup,
+    /// The messages delivered after the committed offset by message id.
+    outstanding: BTreeMap<u64, Outstanding>,
+    /// The messages waiting to be delivered again.
+    redeliver: BTreeSet<u64>,
+    /// The maximum number of messages that can be waiting for an acknowledgement.
+    max_outstanding: usize,
+    /// How long to wait for an acknowledgement before delivering the message again.
+    ack_timeout: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl AckingConsumer {
+    /// Opens the consumer for a group.
+    /// # Arguments
+    /// `topic` - The topic to read from.
+    /// `group_name` - The name of the consumer group.
+    /// `max_outstanding` - The maximum number of messages that haven't been acknowledged.
+    /// `ack_timeout` - How long to wait for an acknowledgement before delivering a message again.
+    pub fn open(
+        topic: &TopicHandle,
+        group_name: &str,
+        max_outstanding: usize,
+        ack_timeout: Duration,
+    ) -> Result<Self> {
+        let mut group = ConsumerGroup::open(topic, group_name)?;
+        group.set_auto_commit(false);
+        Ok(AckingConsumer {
+            group,
+            outstanding: BTreeMap::new(),
+            redeliver: BTreeSet::new(),
+            max_outstanding,
+            ack_timeout,
+            clock: topic.clock().clone(),
+        })
+    }
+
+    /// The consumer group the messages are read for.
+    pub fn group(&self) -> &ConsumerGroup {
+        &self.group
+    }
+
+    /// The number of messages that haven't been acknowledged.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.values().filter(|o| !o.acked).count()
+    }
+
+    /// Gets the next messages to process.  The messages that timed out or were rejected are
+    /// delivered first.  No new messages are read in while `max_outstanding` messages are waiting.
+    /// # Arguments
+    /// `max` - The maximum number of messages to get.
+    pub fn poll(&mut self, max: usize) -> Result<Vec<Delivery>> {
+        let now = self.clock.monotonic_ns();
+        let ack_timeout = self.ack_timeout.as_nanos() as u64;
+        for (id, o) in self.outstanding.iter() {
+            if !o.acked && now.saturating_sub(o.delivered_at) >= ack_timeout {
+                self.redeliver.insert(*id);
+            }
+        }
+        let mut deliveries = Vec::with_capacity(max);
+        while deliveries.len() < max {
+            let id = match self.redeliver.iter().next() {
+                Some(id) => *id,
+                None => break,
+            };
+            self.redeliver.remove(&id);
+            if let Some(o) = self.outstanding.get_mut(&id) {
+                o.delivered_at = now;
+                o.attempt += 1;
+                deliveries.push(Delivery {
+                    tag: id,
+                    attempt: o.attempt,
+                    message: o.message.clone(),
+                });
+            }
+        }
+        // Only the messages held in memory count against the backpressure.
+        let room = self.max_outstanding.saturating_sub(self.outstanding.len());
+        let count = (max - deliveries.len()).min(room);
+        if count > 0 {
+            for message in self.group.poll(count)? {
+                let id = message.message_id;
+                self.outstanding.insert(
+                    id,
+                    Outstanding {
+                        message: message.clone(),
+                        delivered_at: now,
+                        attempt: 1,
+                        acked: false,
+                    },
+                );
+                deliveries.push(Delivery {
+                    tag: id,
+                    attempt: 1,
+                    message,
+                });
+            }
+        }
+        Ok(deliveries)
+    }
+
+    /// Acknowledges a message.  The group offset is moved through the messages that have all been
+    /// acknowledged.
+    /// # Arguments
+    /// `tag` - The tag of the delivery.
+    /// # returns
+    /// true if the message was waiting for an acknowledgement.
+    pub fn ack(&mut self, tag: u64) -> Result<bool> {
+        let found = match self.outstanding.get_mut(&tag) {
+            Some(o) if !o.acked => {
+                o.acked = true;
+                true
+            }
+            _ => false,
+        };
+        self.redeliver.remove(&tag);
+        let mut last_acked = None;
+        while let Some((id, o)) = self.outstanding.iter().next() {
+            if !o.acked {
+                break;
+            }
+            let id = *id;
+            self.outstanding.remove(&id);
+            last_acked = Some(id);
+        }
+        if let Some(id) = last_acked {
+            self.group.commit_offset(id)?;
+        }
+        Ok(found)
+    }
+
+    /// Rejects a message so it is delivered again on the next poll.
+    /// # Arguments
+    /// `tag` - The tag of the delivery.
+    /// # returns
+    /// true if the message was waiting for an acknowledgement.
+    pub fn nack(&mut self, tag: u64) -> bool {
+        match self.outstanding.get(&tag) {
+            Some(o) if !o.acked => {
+                self.redeliver.insert(tag);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::message_stream::acking_consumer::*;
+    use crate::message_stream::{TopicConfig, TopicManager};
+    use a19_core::clock::{system_clock, ManualClock};
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_acking";
+
+    fn create_topic(name: &str, count: u64) -> (TopicManager, TopicHandle) {
+        create_topic_with_clock(name, count, system_clock())
+    }
+
+    fn create_topic_with_clock(
+        name: &str,
+        count: u64,
+        clock: Arc<dyn Clock>,
+    ) -> (TopicManager, TopicHandle) {
+        let directory = format!("{}_{}", TEST_DIR, name);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        let manager = TopicManager::open_with_clock(&directory, clock).unwrap();
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        for i in 0..count {
+            block_on(topic.append(1, &i.to_le_bytes())).unwrap();
+        }
+        (manager, topic)
+    }
+
+    fn tags(deliveries: &[Delivery]) -> Vec<u64> {
+        deliveries.iter().map(|d| d.tag).collect()
+    }
+
+    #[test]
+    fn redeliver_after_crash_test() {
+        let (_manager, topic) = create_topic("crash", 5);
+        {
+            let mut consumer =
+                AckingConsumer::open(&topic, "billing", 10, Duration::from_secs(60)).unwrap();
+            assert_eq!(vec![1, 2, 3], tags(&consumer.poll(3).unwrap()));
+            assert!(consumer.ack(1).unwrap());
+            // Crash before the rest are acknowledged.
+        }
+        let mut consumer =
+            AckingConsumer::open(&topic, "billing", 10, Duration::from_secs(60)).unwrap();
+        assert_eq!(1, consumer.group().committed());
+        assert_eq!(vec![2, 3, 4, 5], tags(&consumer.poll(10).unwrap()));
+    }
+
+    #[test]
+    fn out_of_order_ack_test() {
+        let (_manager, topic) = create_topic("order", 6);
+        let mut consumer =
+            AckingConsumer::open(&topic, "billing", 5, Duration::from_secs(60)).unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5], tags(&consumer.poll(10).unwrap()));
+        // At the limit so nothing new is handed out.
+        assert!(consumer.poll(10).unwrap().is_empty());
+        assert!(consumer.ack(3).unwrap());
+        assert!(consumer.ack(2).unwrap());
+        assert_eq!(0, consumer.group().committed());
+        assert!(consumer.ack(1).unwrap());
+        assert_eq!(3, consumer.group().committed());
+        assert!(!consumer.ack(1).unwrap());
+        assert!(consumer.ack(5).unwrap());
+        assert_eq!(3, consumer.group().committed());
+        assert!(consumer.nack(4));
+        let deliveries = consumer.poll(10).unwrap();
+        assert_eq!(vec![4, 6], tags(&deliveries));
+        assert_eq!(2, deliveries[0].attempt);
+        assert!(consumer.ack(4).unwrap());
+        assert_eq!(5, consumer.group().committed());
+    }
+
+    #[test]
+    fn ack_timeout_test() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let (_manager, topic) = create_topic_with_clock("timeout", 2, clock.clone());
+        let mut consumer =
+            AckingConsumer::open(&topic, "billing", 10, Duration::from_millis(20)).unwrap();
+        assert_eq!(vec![1, 2], tags(&consumer.poll(10).unwrap()));
+        assert!(consumer.ack(2).unwrap());
+        assert!(consumer.poll(10).unwrap().is_empty());
+        clock.advance(Duration::from_millis(19));
+        assert!(consumer.poll(10).unwrap().is_empty());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(vec![1], tags(&consumer.poll(10).unwrap()));
+        assert_eq!(1, consumer.outstanding());
+    }
+}
@@ -0,0 +1,322 @@
+//! Consumer groups keep track of how far a group of consumers has read into a topic.  The offset is
+//! stored in a small sidecar file next to the topic so each service can resume where it left off.
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Epoch                                                         |
+//! |                                                               |
+//! +---------------------------------------------------------------+
+//! | Committed Message Id                                          |
+//! |                                                               |
+//! +---------------------------------------------------------------+
+//! | CRC32 of the epoch and message id                             |
+//! +---------------------------------------------------------------+
+//! ```
+//!
+//! Every time the group is opened the epoch is incremented.  A consumer that has been replaced by
+//! a newer one sees the epoch has changed and is rejected when it tries to commit.
+use crate::message_stream::{CommittedMessage, CommittedMessageStream, TopicHandle};
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::{read, read_dir, rename, File};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+const EPOCH: usize = 0;
+const OFFSET: usize = 8;
+const CRC: usize = 16;
+const OFFSET_FILE_SIZE: usize = 20;
+
+/// The errors for a consumer group.
+#[derive(Debug)]
+pub enum ConsumerGroupError {
+    /// Another consumer has opened the group since we did.
+    Fenced { epoch: u64, current_epoch: u64 },
+    /// The offset file doesn't match its checksum.
+    InvalidChecksum,
+    /// There was a problem reading or writing the offset file.
+    FileError(io::Error),
+}
+
+impl From<io::Error> for ConsumerGroupError {
+    fn from(err: io::Error) -> Self {
+        ConsumerGroupError::FileError(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ConsumerGroupError>;
+
+/// The state stored in the offset file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GroupOffset {
+    epoch: u64,
+    message_id: u64,
+}
+
+impl GroupOffset {
+    /// Reads in the offset file.  If the file doesn't exist we start at the beginning.
+    /// # Arguments
+    /// `path` - The path to the offset file.
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(GroupOffset {
+                epoch: 0,
+                message_id: 0,
+            });
+        }
+        let bytes = read(path)?;
+        if bytes.len() != OFFSET_FILE_SIZE {
+            return Err(ConsumerGroupError::InvalidChecksum);
+        }
+        if crc32fast::hash(&bytes[..CRC]) != BigEndian::read_u32(&bytes[CRC..]) {
+            return Err(ConsumerGroupError::InvalidChecksum);
+        }
+        Ok(GroupOffset {
+            epoch: BigEndian::read_u64(&bytes[EPOCH..OFFSET]),
+            message_id: BigEndian::read_u64(&bytes[OFFSET..CRC]),
+        })
+    }
+
+    /// Saves the offset to a temporary file and renames it so a crash never leaves half of a file.
+    /// # Arguments
+    /// `path` - The path to the offset file.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut bytes = [0; OFFSET_FILE_SIZE];
+        BigEndian::write_u64(&mut bytes[EPOCH..OFFSET], self.epoch);
+        BigEndian::write_u64(&mut bytes[OFFSET..CRC], self.message_id);
+        let crc = crc32fast::hash(&bytes[..CRC]);
+        BigEndian::write_u32(&mut bytes[CRC..], crc);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+        }
+        rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The path to the offset file of a group.
+/// # Arguments
+/// `topic` - The topic the group reads from.
+/// `group_name` - The name of the group.
+fn offset_path(topic: &TopicHandle, group_name: &str) -> PathBuf {
+    Path::new(topic.directory()).join(format!("{}.group.{}", topic.name(), group_name))
+}
+
+/// Reads the committed offset of a group without opening it so the consumers aren't fenced off.
+/// # Arguments
+/// `topic` - The topic the group reads from.
+/// `group_name` - The name of the group.
+/// # Returns
+/// The id of the last message committed by the group.  0 if the group hasn't committed anything.
+pub(crate) fn committed_offset(topic: &TopicHandle, group_name: &str) -> Result<u64> {
+    Ok(GroupOffset::load(&offset_path(topic, group_name))?.message_id)
+}
+
+/// Finds the groups that have an offset file for a topic.
+/// # Arguments
+/// `topic` - The topic to get the groups for.
+/// # Returns
+/// The names of the groups sorted.
+pub(crate) fn group_names(topic: &TopicHandle) -> io::Result<Vec<String>> {
+    let prefix = format!("{}.group.", topic.name());
+    let mut names = Vec::new();
+    for entry in read_dir(topic.directory())? {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str() {
+            if let Some(group) = name.strip_prefix(&prefix) {
+                names.push(group.to_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// A named group of consumers reading from a topic.  Each group keeps its own committed offset.
+pub struct ConsumerGroup {
+    name: String,
+    path: PathBuf,
+    /// The epoch we got when opening the group.
+    epoch: u64,
+    /// The last message id that was committed.
+    committed: u64,
+    /// Commit the offset after each poll.
+    auto_commit: bool,
+    stream: CommittedMessageStream,
+}
+
+impl ConsumerGroup {
+    /// Opens a consumer group.  Any other consumer with the group open is fenced off.
+    /// # Arguments
+    /// `topic` - The topic to read from.
+    /// `group_name` - The name of the group.  Can only contain letters, numbers, `_` and `-`.
+    pub fn open(topic: &TopicHandle, group_name: &str) -> Result<Self> {
+        if group_name.is_empty()
+            || !group_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(ConsumerGroupError::FileError(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid group name: {}", group_name),
+            )));
+        }
+        let path = offset_path(topic, group_name);
+        let mut offset = GroupOffset::load(&path)?;
+        offset.epoch += 1;
+        offset.save(&path)?;
+        Ok(ConsumerGroup {
+            name: group_name.to_owned(),
+            path,
+            epoch: offset.epoch,
+            committed: offset.message_id,
+            auto_commit: false,
+            stream: topic.subscribe(offset.message_id + 1),
+        })
+    }
+
+    /// The name of the group.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The epoch for this consumer.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// The last message id that was committed.
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    /// Sets if the offset should be committed after each poll.
+    /// # Arguments
+    /// `auto_commit` - True to commit after each poll.
+    pub fn set_auto_commit(&mut self, auto_commit: bool) {
+        self.auto_commit = auto_commit;
+    }
+
+    /// Gets the next messages for the group.  Messages that haven't been committed are delivered
+    /// again the next time the group is opened.
+    /// # Arguments
+    /// `max` - The maximum number of messages to get.
+    pub fn poll(&mut self, max: usize) -> Result<Vec<CommittedMessage>> {
+        let mut messages = Vec::with_capacity(max);
+        while messages.len() < max {
+            match self.stream.next_message() {
+                Some(msg) => messages.push(msg),
+                None => break,
+            }
+        }
+        if self.auto_commit {
+            if let Some(last) = messages.last() {
+                self.commit_offset(last.message_id)?;
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Commits the offset for the group.
+    /// # Arguments
+    /// `message_id` - The id of the last message that has been processed.
+    pub fn commit_offset(&mut self, message_id: u64) -> Result<()> {
+        let current = GroupOffset::load(&self.path)?;
+        if current.epoch != self.epoch {
+            return Err(ConsumerGroupError::Fenced {
+                epoch: self.epoch,
+                current_epoch: current.epoch,
+            });
+        }
+        GroupOffset {
+            epoch: self.epoch,
+            message_id,
+        }
+        .save(&self.path)?;
+        self.committed = message_id;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::message_stream::consumer_group::*;
+    use crate::message_stream::{TopicConfig, TopicManager};
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_groups";
+
+    fn ids(messages: &[CommittedMessage]) -> Vec<u64> {
+        messages.iter().map(|m| m.message_id).collect()
+    }
+
+    #[test]
+    fn consumer_group_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let manager = TopicManager::open(TEST_DIR).unwrap();
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        for i in 0..10u64 {
+            block_on(topic.append(1, &i.to_le_bytes())).unwrap();
+        }
+
+        let mut group = ConsumerGroup::open(&topic, "billing").unwrap();
+        assert_eq!(vec![1, 2, 3, 4], ids(&group.poll(4).unwrap()));
+        group.commit_offset(4).unwrap();
+        assert_eq!(vec![5, 6, 7], ids(&group.poll(3).unwrap()));
+
+        // The uncommitted messages are delivered again.
+        let mut group = ConsumerGroup::open(&topic, "billing").unwrap();
+        assert_eq!(4, group.committed());
+        assert_eq!(vec![5, 6], ids(&group.poll(2).unwrap()));
+
+        // Each group has its own offset.
+        let mut other = ConsumerGroup::open(&topic, "audit").unwrap();
+        other.set_auto_commit(true);
+        assert_eq!(vec![1, 2, 3], ids(&other.poll(3).unwrap()));
+        let mut other = ConsumerGroup::open(&topic, "audit").unwrap();
+        assert_eq!(3, other.committed());
+        assert_eq!(vec![4, 5, 6, 7, 8, 9, 10], ids(&other.poll(20).unwrap()));
+    }
+
+    #[test]
+    fn consumer_group_fenced_test() {
+        let directory = format!("{}_fenced", TEST_DIR);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        block_on(topic.append(1, &[1, 2, 3])).unwrap();
+
+        let mut stale = ConsumerGroup::open(&topic, "billing").unwrap();
+        let mut current = ConsumerGroup::open(&topic, "billing").unwrap();
+        assert!(current.epoch() > stale.epoch());
+        match stale.commit_offset(1) {
+            Err(ConsumerGroupError::Fenced {
+                epoch,
+                current_epoch,
+            }) => {
+                assert_eq!(stale.epoch(), epoch);
+                assert_eq!(current.epoch(), current_epoch);
+            }
+            _ => panic!("The stale consumer should have been fenced."),
+        }
+        current.commit_offset(1).unwrap();
+        assert_eq!(1, current.committed());
+    }
+}
@@ -0,0 +1,575 @@
+//! Exports the messages in a topic to a portable format and imports them back into a message file.
+//! The format doesn't depend on the layout of the files so it can be used to move the messages
+//! between versions.  Everything is big endian.
+//!
+//! # Header
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Magic "A19X"                                                  |
+//! +-------------------------------+-------------------------------+
+//! | Version                       | Reserved                      |
+//! +-------------------------------+-------------------------------+
+//! ```
+//!
+//! # Message Record
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Kind (1)      | Message Type                                  |
+//! +---------------+-----------------------------------------------+
+//! |               | Message Id                                    |
+//! +---------------+                                               |
+//! |               |                                               |
+//! +---------------+-----------------------------------------------+
+//! |               | Length                                        |
+//! +---------------+-----------------------------------------------+
+//! |               | Body                                         ...
+//! ...                                                             |
+//! +---------------------------------------------------------------+
+//! | CRC32 of the record without the CRC                           |
+//! +---------------------------------------------------------------+
+//! ```
+//!
+//! # Message Record with Headers
+//!
+//! The same as the message record but with the user metadata of the message between the header and
+//! the body.  Messages without any metadata are written as a message record.
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Kind (3)      | Message Type                                  |
+//! +---------------+-----------------------------------------------+
+//! |               | Message Id                                    |
+//! +---------------+                                               |
+//! |               |                                               |
+//! +---------------+-----------------------------------------------+
+//! |               | Length                                        |
+//! +---------------+-----------------------------------------------+
+//! |               | Tenant                                        |
+//! +---------------+-----------------------------------------------+
+//! |               | Priority      | Flags         | Reserved      |
+//! +---------------+---------------+---------------+---------------+
+//! |               | Body                                         ...
+//! ...                                                             |
+//! +---------------------------------------------------------------+
+//! | CRC32 of the record without the CRC                           |
+//! +---------------------------------------------------------------+
+//! ```
+//!
+//! # End Record
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Kind (2)      | Number of Message Records                     |
+//! +---------------+                                               |
+//! |               |                                               |
+//! +---------------+-----------------------------------------------+
+//! |               | CRC32 of the record without the CRC           |
+//! +---------------+-----------------------------------------------+
+//! ```
+//! The end record lets us know the export wasn't cut off on the boundary of a record.
+use crate::file;
+use crate::file::{MessageFileStoreWrite, MessageHeaders, USER_META_SIZE};
+use crate::message_stream::TopicHandle;
+use crate::raft::CommittedCursor;
+use byteorder::{BigEndian, ByteOrder};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+
+/// The bytes at the start of an export.
+pub const EXPORT_MAGIC: [u8; 4] = *b"A19X";
+/// The version of the format.
+pub const EXPORT_VERSION: u16 = 1;
+/// The size of the export header.
+pub const EXPORT_HEADER_SIZE: usize = 8;
+/// The kind for a message record.
+pub const RECORD_MESSAGE: u8 = 1;
+/// The kind for the end record.
+pub const RECORD_END: u8 = 2;
+/// The kind for a message record with the user metadata.
+pub const RECORD_MESSAGE_HEADERS: u8 = 3;
+/// The size of the header of a message record.  Kind, message type, message id and length.
+pub const MESSAGE_RECORD_HEADER_SIZE: usize = 17;
+/// The size of the end record without the CRC.  Kind and the number of records.
+pub const END_RECORD_SIZE: usize = 9;
+/// The size of the CRC at the end of a record.
+pub const RECORD_CRC_SIZE: usize = 4;
+
+const KIND: usize = 0;
+const MESSAGE_TYPE: usize = 1;
+const MESSAGE_ID: usize = 5;
+const LENGTH: usize = 13;
+const RECORD_COUNT: usize = 1;
+
+/// The errors when exporting or importing.
+#[derive(Debug)]
+pub enum ExportError {
+    /// Unable to read or write the export.
+    Io(io::Error),
+    /// The export doesn't start with the magic bytes.
+    InvalidMagic,
+    /// The version of the export isn't supported.
+    UnsupportedVersion(u16),
+    /// A record doesn't match its CRC.
+    InvalidChecksum { record: u64 },
+    /// The export ended before the end record.
+    Truncated,
+    /// The kind of record isn't known.
+    InvalidRecord(u8),
+    /// The message id was already used when preserving the ids.
+    IdCollision { message_id: u64, last_message_id: u64 },
+    /// Unable to write to the message file.
+    File(file::Error),
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            ExportError::Truncated
+        } else {
+            ExportError::Io(err)
+        }
+    }
+}
+
+impl From<file::Error> for ExportError {
+    fn from(err: file::Error) -> Self {
+        ExportError::File(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ExportError>;
+
+/// What was exported or imported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExportStats {
+    /// The number of messages.
+    pub records: u64,
+    /// The number of bytes in the export.
+    pub bytes: u64,
+    /// The id of the last message.
+    pub last_message_id: u64,
+}
+
+/// How to assign the message ids when importing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdPolicy {
+    /// Keep the ids from the export.  They must be increasing.
+    Preserve,
+    /// Give the messages new ids starting at the specified id.
+    Renumber { start: u64 },
+}
+
+/// Writes an export.
+pub struct ExportWriter<'a, W: Write> {
+    to: &'a mut W,
+    stats: ExportStats,
+}
+
+impl<'a, W: Write> ExportWriter<'a, W> {
+    /// Starts an export by writing the header.
+    /// # Arguments
+    /// `to` - Where to write the export.
+    pub fn new(to: &'a mut W) -> Result<Self> {
+        let mut header = [0; EXPORT_HEADER_SIZE];
+        header[0..4].copy_from_slice(&EXPORT_MAGIC);
+        BigEndian::write_u16(&mut header[4..6], EXPORT_VERSION);
+        to.write_all(&header)?;
+        Ok(ExportWriter {
+            to,
+            stats: ExportStats {
+                bytes: EXPORT_HEADER_SIZE as u64,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Writes a message record.
+    /// # Arguments
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `body` - The body of the message.
+    pub fn write_message(&mut self, msg_type_id: i32, message_id: u64, body: &[u8]) -> Result<()> {
+        self.write_message_with_headers(msg_type_id, message_id, &MessageHeaders::default(), body)
+    }
+
+    /// Writes a message record with the user metadata of the message.
+    /// # Arguments
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `headers` - The user metadata of the message.
+    /// `body` - The body of the message.
+    pub fn write_message_with_headers(
+        &mut self,
+        msg_type_id: i32,
+        message_id: u64,
+        headers: &MessageHeaders,
+        body: &[u8],
+    ) -> Result<()> {
+        let meta = if headers.is_empty() {
+            None
+        } else {
+            Some(headers.to_bytes())
+        };
+        let mut header = [0; MESSAGE_RECORD_HEADER_SIZE];
+        header[KIND] = if meta.is_some() {
+            RECORD_MESSAGE_HEADERS
+        } else {
+            RECORD_MESSAGE
+        };
+        BigEndian::write_i32(&mut header[MESSAGE_TYPE..MESSAGE_ID], msg_type_id);
+        BigEndian::write_u64(&mut header[MESSAGE_ID..LENGTH], message_id);
+        BigEndian::write_u32(&mut header[LENGTH..], body.len() as u32);
+        let meta = meta.as_ref().map(|m| &m[..]).unwrap_or(&[]);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        hasher.update(meta);
+        hasher.update(body);
+        let mut crc = [0; RECORD_CRC_SIZE];
+        BigEndian::write_u32(&mut crc, hasher.finalize());
+        self.to.write_all(&header)?;
+        self.to.write_all(meta)?;
+        self.to.write_all(body)?;
+        self.to.write_all(&crc)?;
+        self.stats.records += 1;
+        self.stats.bytes +=
+            (MESSAGE_RECORD_HEADER_SIZE + meta.len() + body.len() + RECORD_CRC_SIZE) as u64;
+        self.stats.last_message_id = message_id;
+        Ok(())
+    }
+
+    /// Writes the end record.
+    /// # Returns
+    /// What was exported.
+    pub fn finish(self) -> Result<ExportStats> {
+        let mut record = [0; END_RECORD_SIZE + RECORD_CRC_SIZE];
+        record[KIND] = RECORD_END;
+        BigEndian::write_u64(&mut record[RECORD_COUNT..END_RECORD_SIZE], self.stats.records);
+        let crc = crc32fast::hash(&record[..END_RECORD_SIZE]);
+        BigEndian::write_u32(&mut record[END_RECORD_SIZE..], crc);
+        self.to.write_all(&record)?;
+        self.to.flush()?;
+        let mut stats = self.stats;
+        stats.bytes += record.len() as u64;
+        Ok(stats)
+    }
+}
+
+/// Exports the committed messages in a topic.
+/// # Arguments
+/// `topic` - The topic to export.
+/// `from_id` - The id of the message to start from.
+/// `to` - Where to write the export.
+pub fn export<W: Write>(topic: &TopicHandle, from_id: u64, to: &mut W) -> Result<ExportStats> {
+    let mut writer = ExportWriter::new(to)?;
+    let mut cursor = CommittedCursor::new(from_id);
+    while let Some(msg) = cursor.next(&topic.topic.file) {
+        writer.write_message_with_headers(
+            msg.msg_type_id(),
+            msg.message_id(),
+            &msg.headers(),
+            msg.bytes(),
+        )?;
+    }
+    writer.finish()
+}
+
+/// Imports the messages into a new message file.
+/// # Arguments
+/// `from` - The export to read.
+/// `into` - The message file to write to.  The messages are written from the start of the file.
+/// `id_policy` - How to assign the message ids.
+pub fn import<R: Read>(
+    from: &mut R,
+    into: &MessageFileStoreWrite,
+    id_policy: IdPolicy,
+) -> Result<ExportStats> {
+    let mut header = [0; EXPORT_HEADER_SIZE];
+    from.read_exact(&mut header)?;
+    if header[0..4] != EXPORT_MAGIC {
+        return Err(ExportError::InvalidMagic);
+    }
+    let version = BigEndian::read_u16(&header[4..6]);
+    if version != EXPORT_VERSION {
+        return Err(ExportError::UnsupportedVersion(version));
+    }
+    let mut stats = ExportStats {
+        bytes: EXPORT_HEADER_SIZE as u64,
+        ..Default::default()
+    };
+    let mut next_id = match id_policy {
+        IdPolicy::Preserve => 0,
+        IdPolicy::Renumber { start } => start,
+    };
+    let mut pos = 0;
+    let mut body = Vec::new();
+    let mut crc = [0; RECORD_CRC_SIZE];
+    loop {
+        let mut record = [0; MESSAGE_RECORD_HEADER_SIZE];
+        from.read_exact(&mut record[..1])?;
+        match record[KIND] {
+            RECORD_MESSAGE | RECORD_MESSAGE_HEADERS => {
+                from.read_exact(&mut record[1..])?;
+                let msg_type_id = BigEndian::read_i32(&record[MESSAGE_TYPE..MESSAGE_ID]);
+                let message_id = BigEndian::read_u64(&record[MESSAGE_ID..LENGTH]);
+                let length = BigEndian::read_u32(&record[LENGTH..]) as usize;
+                let mut meta = [0; USER_META_SIZE];
+                let meta = if record[KIND] == RECORD_MESSAGE_HEADERS {
+                    from.read_exact(&mut meta)?;
+                    &meta[..]
+                } else {
+                    &meta[..0]
+                };
+                body.resize(length, 0);
+                from.read_exact(&mut body)?;
+                from.read_exact(&mut crc)?;
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.update(&record);
+                hasher.update(meta);
+                hasher.update(&body);
+                if hasher.finalize() != BigEndian::read_u32(&crc) {
+                    return Err(ExportError::InvalidChecksum {
+                        record: stats.records,
+                    });
+                }
+                let id = match id_policy {
+                    IdPolicy::Preserve => {
+                        if message_id <= stats.last_message_id {
+                            return Err(ExportError::IdCollision {
+                                message_id,
+                                last_message_id: stats.last_message_id,
+                            });
+                        }
+                        message_id
+                    }
+                    IdPolicy::Renumber { .. } => {
+                        next_id += 1;
+                        next_id - 1
+                    }
+                };
+                let headers = if meta.is_empty() {
+                    MessageHeaders::default()
+                } else {
+                    MessageHeaders::from_bytes(meta)
+                };
+                pos = into.write_with_headers(pos, msg_type_id, id, &headers, &body)?;
+                stats.records += 1;
+                stats.bytes +=
+                    (MESSAGE_RECORD_HEADER_SIZE + meta.len() + length + RECORD_CRC_SIZE) as u64;
+                stats.last_message_id = id;
+            }
+            RECORD_END => {
+                from.read_exact(&mut record[1..END_RECORD_SIZE])?;
+                from.read_exact(&mut crc)?;
+                if crc32fast::hash(&record[..END_RECORD_SIZE]) != BigEndian::read_u32(&crc) {
+                    return Err(ExportError::InvalidChecksum {
+                        record: stats.records,
+                    });
+                }
+                if BigEndian::read_u64(&record[RECORD_COUNT..END_RECORD_SIZE]) != stats.records {
+                    return Err(ExportError::Truncated);
+                }
+                stats.bytes += (END_RECORD_SIZE + RECORD_CRC_SIZE) as u64;
+                into.flush()?;
+                break Ok(stats);
+            }
+            kind => break Err(ExportError::InvalidRecord(kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::file::MessageFileStore;
+    use crate::message_stream::export::*;
+    use crate::message_stream::{TopicConfig, TopicManager};
+    use futures::executor::block_on;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::Path;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_export";
+
+    fn setup(name: &str) -> String {
+        let directory = format!("{}_{}", TEST_DIR, name);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Reads all of the messages out of a message file.
+    fn read_all(path: &str) -> Vec<(i32, u64, Vec<u8>)> {
+        let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+        let mut pos = 0;
+        let mut messages = Vec::new();
+        while let Ok(msg) = reader.read_new(pos) {
+            if msg.message_id() == 0 {
+                break;
+            }
+            messages.push((msg.msg_type_id(), msg.message_id(), msg.bytes().to_vec()));
+            pos = msg.next_pos();
+        }
+        messages
+    }
+
+    #[test]
+    fn export_round_trip_test() {
+        let directory = setup("round_trip");
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        let mut expected = Vec::new();
+        for i in 1..=20u64 {
+            let body = vec![i as u8; i as usize];
+            let id = block_on(topic.append((i % 3) as i32, &body)).unwrap();
+            expected.push(((i % 3) as i32, id, body));
+        }
+        let mut exported = Vec::new();
+        let stats = export(&topic, 1, &mut exported).unwrap();
+        assert_eq!(20, stats.records);
+        assert_eq!(exported.len() as u64, stats.bytes);
+
+        let path = format!("{}/preserve", directory);
+        let (_, writer) = unsafe { MessageFileStore::new(&path, 0x10000).unwrap() };
+        let imported = import(&mut &exported[..], &writer, IdPolicy::Preserve).unwrap();
+        assert_eq!(stats, imported);
+        assert_eq!(expected, read_all(&path));
+
+        let path = format!("{}/renumber", directory);
+        let (_, writer) = unsafe { MessageFileStore::new(&path, 0x10000).unwrap() };
+        import(
+            &mut &exported[..],
+            &writer,
+            IdPolicy::Renumber { start: 100 },
+        )
+        .unwrap();
+        let renumbered = read_all(&path);
+        assert_eq!(20, renumbered.len());
+        for (i, (msg_type, id, body)) in renumbered.into_iter().enumerate() {
+            assert_eq!(100 + i as u64, id);
+            assert_eq!(expected[i].0, msg_type);
+            assert_eq!(expected[i].2, body);
+        }
+    }
+
+    #[test]
+    fn export_headers_test() {
+        let directory = setup("headers");
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        let mut expected = Vec::new();
+        for i in 1..=10u64 {
+            let headers = MessageHeaders {
+                tenant: (i % 2) as u32 * 7,
+                priority: i as u8,
+                flags: 1,
+                reserved: 0,
+            };
+            let headers = if i == 5 {
+                MessageHeaders::default()
+            } else {
+                headers
+            };
+            let body = vec![i as u8; i as usize];
+            let id = block_on(topic.append_with_headers(1, &headers, &body)).unwrap();
+            expected.push((id, headers, body));
+        }
+        let mut exported = Vec::new();
+        let stats = export(&topic, 1, &mut exported).unwrap();
+        assert_eq!(10, stats.records);
+        assert_eq!(exported.len() as u64, stats.bytes);
+
+        let path = format!("{}/preserve", directory);
+        let (_, writer) = unsafe { MessageFileStore::new(&path, 0x10000).unwrap() };
+        assert_eq!(
+            stats,
+            import(&mut &exported[..], &writer, IdPolicy::Preserve).unwrap()
+        );
+        let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+        let mut pos = 0;
+        for (id, headers, body) in expected {
+            let msg = reader.read_new(pos).unwrap();
+            assert_eq!(id, msg.message_id());
+            assert_eq!(headers, msg.headers());
+            assert_eq!(&body[..], msg.bytes());
+            pos = msg.next_pos();
+        }
+
+        // The metadata is covered by the CRC.
+        let mut corrupted = exported.clone();
+        corrupted[EXPORT_HEADER_SIZE + MESSAGE_RECORD_HEADER_SIZE] ^= 0xFF;
+        let (_, writer) =
+            unsafe { MessageFileStore::new(&format!("{}/corrupted", directory), 0x10000).unwrap() };
+        match import(&mut &corrupted[..], &writer, IdPolicy::Preserve) {
+            Err(ExportError::InvalidChecksum { record }) => assert_eq!(0, record),
+            r => panic!("Expected an invalid checksum: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn export_invalid_test() {
+        let directory = setup("invalid");
+        let mut exported = Vec::new();
+        {
+            let mut writer = ExportWriter::new(&mut exported).unwrap();
+            writer.write_message(1, 1, &[1, 2, 3]).unwrap();
+            writer.write_message(1, 2, &[4, 5, 6]).unwrap();
+            writer.finish().unwrap();
+        }
+        let (_, writer) =
+            unsafe { MessageFileStore::new(&format!("{}/file", directory), 0x1000).unwrap() };
+        // Cut off in the middle of a record.
+        let truncated = &exported[..exported.len() - 20];
+        match import(&mut &truncated[..], &writer, IdPolicy::Preserve) {
+            Err(ExportError::Truncated) => {}
+            r => panic!("Expected the export to be truncated: {:?}", r),
+        }
+        // Cut off on a record boundary.
+        let truncated = &exported[..exported.len() - END_RECORD_SIZE - RECORD_CRC_SIZE];
+        match import(&mut &truncated[..], &writer, IdPolicy::Preserve) {
+            Err(ExportError::Truncated) => {}
+            r => panic!("Expected the export to be truncated: {:?}", r),
+        }
+        let mut corrupted = exported.clone();
+        corrupted[EXPORT_HEADER_SIZE + MESSAGE_RECORD_HEADER_SIZE] ^= 0xFF;
+        match import(&mut &corrupted[..], &writer, IdPolicy::Preserve) {
+            Err(ExportError::InvalidChecksum { record }) => assert_eq!(0, record),
+            r => panic!("Expected an invalid checksum: {:?}", r),
+        }
+
+        let mut colliding = Vec::new();
+        {
+            let mut writer = ExportWriter::new(&mut colliding).unwrap();
+            writer.write_message(1, 5, &[1]).unwrap();
+            writer.write_message(1, 5, &[2]).unwrap();
+            writer.finish().unwrap();
+        }
+        match import(&mut &colliding[..], &writer, IdPolicy::Preserve) {
+            Err(ExportError::IdCollision {
+                message_id,
+                last_message_id,
+            }) => {
+                assert_eq!(5, message_id);
+                assert_eq!(5, last_message_id);
+            }
+            r => panic!("Expected an id collision: {:?}", r),
+        }
+        import(&mut &colliding[..], &writer, IdPolicy::Renumber { start: 1 }).unwrap();
+    }
+}
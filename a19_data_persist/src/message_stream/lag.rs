@@ -0,0 +1,182 @@
+//! How far the consumer groups are behind the topics they read from.  The numbers come from the
+//! offset files and the commit files so nothing has to read through the messages.  The lag counts
+//! the message ids so the messages used by the store like the transaction headers are included.
+use crate::message_stream::consumer_group::{committed_offset, group_names, Result};
+use crate::message_stream::TopicHandle;
+use crate::raft::TermLocation;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// The lag of a consumer group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LagInfo {
+    /// The name of the topic.
+    pub topic: String,
+    /// The name of the consumer group.
+    pub group: String,
+    /// The id of the last message the group committed.
+    pub committed_offset: u64,
+    /// The id of the last message committed to the topic.
+    pub max_committed_id: u64,
+    /// The number of messages the group hasn't consumed.
+    pub lag_messages: u64,
+    /// The approximate number of bytes the group hasn't consumed.  Measured from the start of the
+    /// term containing the first unconsumed message.
+    pub lag_bytes: u64,
+    /// When the first unconsumed message was committed in milliseconds since the epoch.  `None`
+    /// if the group is caught up.
+    pub oldest_unconsumed_at: Option<u64>,
+}
+
+/// Calculates the lag of a consumer group.
+/// # Arguments
+/// `topic` - The topic the group reads from.
+/// `group_name` - The name of the group.
+pub(crate) fn group_lag(topic: &TopicHandle, group_name: &str) -> Result<LagInfo> {
+    let file = &topic.topic.file;
+    let committed = committed_offset(topic, group_name)?;
+    let max_committed_id = file.max_message_id();
+    let lag_messages = max_committed_id.saturating_sub(committed);
+    let (lag_bytes, oldest_unconsumed_at) = if lag_messages > 0 {
+        match (file.find_term(committed + 1), file.find_term(max_committed_id)) {
+            (Some(start), Some(end)) => (
+                bytes_between(&start, &end, file.max_file_size() as u64),
+                Some(start.committed_at),
+            ),
+            (Some(start), None) => (0, Some(start.committed_at)),
+            _ => (0, None),
+        }
+    } else {
+        (0, None)
+    };
+    Ok(LagInfo {
+        topic: topic.name().to_owned(),
+        group: group_name.to_owned(),
+        committed_offset: committed,
+        max_committed_id,
+        lag_messages,
+        lag_bytes,
+        oldest_unconsumed_at,
+    })
+}
+
+/// Calculates the lag of all of the groups reading from a topic.
+/// # Arguments
+/// `topic` - The topic to get the lag for.
+pub(crate) fn topic_lags(topic: &TopicHandle) -> Result<Vec<LagInfo>> {
+    let mut lags = Vec::new();
+    for group in group_names(topic)? {
+        lags.push(group_lag(topic, &group)?);
+    }
+    Ok(lags)
+}
+
+/// The number of bytes from the start of a term to the end of another one.  The event files in
+/// between are assumed to be full.
+/// # Arguments
+/// `start` - The term to start from.
+/// `end` - The last term.
+/// `max_file_size` - The size of an event file.
+fn bytes_between(start: &TermLocation, end: &TermLocation, max_file_size: u64) -> u64 {
+    let end_pos = end.position + end.length as u64;
+    if start.file_id == end.file_id {
+        end_pos.saturating_sub(start.position)
+    } else {
+        let files_between = end.file_id.saturating_sub(start.file_id + 1) as u64;
+        max_file_size.saturating_sub(start.position) + files_between * max_file_size + end_pos
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::file::aligned_message_size;
+    use crate::message_stream::consumer_group::ConsumerGroup;
+    use crate::message_stream::{TopicConfig, TopicManager};
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_lag";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    #[test]
+    fn lag_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let manager = TopicManager::open(TEST_DIR).unwrap();
+        let orders = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        manager
+            .create_topic("trades", TopicConfig::default())
+            .unwrap();
+        let started = now();
+        for i in 0..10u64 {
+            block_on(orders.append(1, &i.to_le_bytes())).unwrap();
+        }
+        let message_size = aligned_message_size(8) as u64;
+
+        // Never consumed.
+        let lag = orders.lag("billing").unwrap();
+        assert_eq!(0, lag.committed_offset);
+        assert_eq!(10, lag.max_committed_id);
+        assert_eq!(10, lag.lag_messages);
+        assert_eq!(10 * message_size, lag.lag_bytes);
+        let oldest = lag.oldest_unconsumed_at.unwrap();
+        assert!(oldest >= started && oldest <= now());
+
+        let mut group = ConsumerGroup::open(&orders, "billing").unwrap();
+        group.poll(4).unwrap();
+        group.commit_offset(4).unwrap();
+        let lag = orders.lag("billing").unwrap();
+        assert_eq!(4, lag.committed_offset);
+        assert_eq!(6, lag.lag_messages);
+        assert_eq!(6 * message_size, lag.lag_bytes);
+        assert!(lag.oldest_unconsumed_at.unwrap() >= oldest);
+
+        let mut audit = ConsumerGroup::open(&orders, "audit").unwrap();
+        audit.poll(10).unwrap();
+        audit.commit_offset(10).unwrap();
+        let lag = orders.lag("audit").unwrap();
+        assert_eq!(0, lag.lag_messages);
+        assert_eq!(0, lag.lag_bytes);
+        assert_eq!(None, lag.oldest_unconsumed_at);
+
+        let lags = manager.all_lags().unwrap();
+        let names: Vec<(&str, &str)> = lags
+            .iter()
+            .map(|l| (l.topic.as_str(), l.group.as_str()))
+            .collect();
+        assert_eq!(vec![("orders", "audit"), ("orders", "billing")], names);
+        assert_eq!(6, lags[1].lag_messages);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn lag_serde_test() {
+        use crate::message_stream::lag::LagInfo;
+        let lag = LagInfo {
+            topic: "orders".to_owned(),
+            group: "billing".to_owned(),
+            committed_offset: 4,
+            max_committed_id: 10,
+            lag_messages: 6,
+            lag_bytes: 192,
+            oldest_unconsumed_at: None,
+        };
+        let json = serde_json::to_string(&lag).unwrap();
+        assert!(json.contains("\"oldest_unconsumed_at\":null"));
+        assert_eq!(lag, serde_json::from_str::<LagInfo>(&json).unwrap());
+    }
+}
@@ -0,0 +1,1005 @@
+pub mod acking_consumer;
+pub mod consumer_group;
+pub mod export;
+pub mod lag;
+pub mod partitioned;
+pub mod producer_session;
+pub mod runtime;
+pub mod sink;
+pub mod transaction;
+pub mod type_registry;
+pub mod typed;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{ AtomicU64, AtomicU32, Ordering };
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_to_string, rename, File};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::error::{PersistError, ResultExt};
+use crate::file::filter::MessageFilter;
+use crate::file::{ MessageFileStoreRead, MessageFileStoreWrite, MessageHeaders, MessageRead };
+use crate::raft::backlog::{BacklogStatus, Watermarks, WouldBlock};
+use crate::raft::latency::LatencySnapshot;
+use crate::raft::{
+    startup_single_node_with_clock, CommittedCursor, FlushPolicy, InfallibleProcessor, PersistedMessageFile,
+    QueueFuture, RetentionPolicy,
+};
+use crate::message_stream::consumer_group::Result as GroupResult;
+use crate::message_stream::lag::{group_lag, topic_lags, LagInfo};
+use crate::message_stream::transaction::Txn;
+use crate::message_stream::type_registry::TypeRegistry;
+use crate::message_stream::typed::TypedTopicHandle;
+use crate::codec::MessageCodec;
+use a19_concurrent::bloom::PersistedBloomFilter;
+use a19_concurrent::buffer::pool::{BufferPool, PooledBuf};
+use a19_concurrent::rate_limiter::RateLimiter;
+use a19_core::clock::{system_clock, Clock};
+
+/// Writing to the current memory map file messages sent by the client.
+/// Readers (StateMachine, Network) Writer (Client)
+const JOURNALING_STATE: u32 = 1;
+/// Forwarding the messages to the current leader if there is one.
+const FORWARDING_STATE: u32 = 2;
+/// Copying the journal messages to the in memory ring buffer.
+const COPYING_BUFFER: u32 = 3;
+/// Changing to forwarding buffer.  Need to wait until all of the writers are out.  Also have to block while we copy the messages to preserve order.
+const CHANGING_TO_FORWARDING: u32 = 4;
+/// Changing to journaling buffer.  Need to wait until all of the writers are out.  Also have to block while we copy the messages to preserve order.
+const CHANGING_TO_JOURNALING: u32 = 5;
+
+struct CommitFile {
+    path: String,
+    file_number: u32,
+    commit_file_size: usize,
+    max_message: Arc<AtomicU64>,
+    message_file_reader: MessageFileStoreRead,
+    message_file_writer: MessageFileStoreWrite,
+}
+
+pub struct MessageStream {
+    file_storage_directory: String,
+    file_prefix: String,
+    current_state: AtomicU32,
+    current_leader: u32,
+}
+
+impl MessageStream {
+
+}
+
+/// The name of the file containing the topics.
+const MANIFEST_FILE: &str = "topics.manifest";
+/// The size of the commit file for a topic.  Needs to be a multiple of the commit size.
+const TOPIC_COMMIT_FILE_SIZE: usize = 0x100000;
+/// The size of the incoming buffer for a topic.
+const TOPIC_INCOMING_BUFFER_SIZE: usize = 0x40000;
+/// The number of appends that can be waiting to be written for a topic.
+const TOPIC_INCOMING_QUEUE_SIZE: usize = 0x4000;
+/// The extension of the file the dedup filter for a topic is saved to.
+const DEDUP_FILTER_EXTENSION: &str = "dedup.bloom";
+/// The number of client messages the dedup filter for a topic is sized for.
+const DEDUP_FILTER_CAPACITY: u64 = 100_000;
+/// The false positive rate of the dedup filter once it is at capacity.
+const DEDUP_FILTER_RATE: f64 = 0.01;
+
+/// The settings for a topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopicConfig {
+    /// The maximum size of an event file before it rolls over.
+    pub max_file_size: usize,
+    /// Which of the files to keep.
+    pub retention: RetentionPolicy,
+    /// When to flush the messages to disk.
+    pub flush_policy: FlushPolicy,
+    /// The uncommitted bytes where the appends wait for the backlog to drain.  `None` never waits.
+    pub watermarks: Option<Watermarks>,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        TopicConfig {
+            max_file_size: 0x100000,
+            retention: RetentionPolicy::KeepAll,
+            flush_policy: FlushPolicy::EveryCommit,
+            watermarks: None,
+        }
+    }
+}
+
+impl TopicConfig {
+    /// Writes out the config for the manifest.
+    fn to_manifest(self) -> String {
+        let retention = match self.retention {
+            RetentionPolicy::KeepAll => "all".to_owned(),
+            RetentionPolicy::KeepFiles(n) => format!("files:{}", n),
+            RetentionPolicy::KeepBytes(n) => format!("bytes:{}", n),
+            RetentionPolicy::KeepNewerThan(d) => format!("newer_ms:{}", d.as_millis()),
+            RetentionPolicy::KeepAboveId(id) => format!("above_id:{}", id),
+        };
+        let flush_policy = match self.flush_policy {
+            FlushPolicy::EveryCommit => "commit".to_owned(),
+            FlushPolicy::EveryNMessages(n) => format!("messages:{}", n),
+            FlushPolicy::Interval(d) => format!("interval_ms:{}", d.as_millis()),
+            FlushPolicy::Os => "os".to_owned(),
+        };
+        let watermarks = match self.watermarks {
+            Some(w) => format!("{}:{}", w.high, w.low),
+            None => "none".to_owned(),
+        };
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.max_file_size, retention, flush_policy, watermarks
+        )
+    }
+
+    /// Parses the config from the manifest.
+    /// # Arguments
+    /// `max_file_size` - The max file size column.
+    /// `retention` - The retention column.
+    /// `flush_policy` - The flush policy column.
+    /// `watermarks` - The watermarks column.  Missing in the manifests written before it was added.
+    fn from_manifest(
+        max_file_size: &str,
+        retention: &str,
+        flush_policy: &str,
+        watermarks: Option<&str>,
+    ) -> Option<Self> {
+        let max_file_size = max_file_size.parse::<usize>().ok()?;
+        let (retention_type, retention_value) = split_setting(retention);
+        let retention = match retention_type {
+            "all" => RetentionPolicy::KeepAll,
+            "files" => RetentionPolicy::KeepFiles(retention_value?.parse().ok()?),
+            "bytes" => RetentionPolicy::KeepBytes(retention_value?.parse().ok()?),
+            "newer_ms" => RetentionPolicy::KeepNewerThan(Duration::from_millis(
+                retention_value?.parse().ok()?,
+            )),
+            "above_id" => RetentionPolicy::KeepAboveId(retention_value?.parse().ok()?),
+            _ => return None,
+        };
+        let (flush_type, flush_value) = split_setting(flush_policy);
+        let flush_policy = match flush_type {
+            "commit" => FlushPolicy::EveryCommit,
+            "messages" => FlushPolicy::EveryNMessages(flush_value?.parse().ok()?),
+            "interval_ms" => {
+                FlushPolicy::Interval(Duration::from_millis(flush_value?.parse().ok()?))
+            }
+            "os" => FlushPolicy::Os,
+            _ => return None,
+        };
+        let watermarks = match split_setting(watermarks.unwrap_or("none")) {
+            ("none", None) => None,
+            (high, Some(low)) => Some(Watermarks {
+                high: high.parse().ok()?,
+                low: low.parse().ok()?,
+            }),
+            _ => return None,
+        };
+        Some(TopicConfig {
+            max_file_size,
+            retention,
+            flush_policy,
+            watermarks,
+        })
+    }
+}
+
+/// Splits a setting in the form of `name:value`.
+fn split_setting(setting: &str) -> (&str, Option<&str>) {
+    let mut parts = setting.splitn(2, ':');
+    (parts.next().unwrap_or(""), parts.next())
+}
+
+/// A message that has been committed to a topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommittedMessage {
+    pub message_id: u64,
+    pub msg_type_id: i32,
+    /// The user metadata of the message.
+    pub headers: MessageHeaders,
+    pub body: Vec<u8>,
+}
+
+/// A committed message with the body in a buffer from a pool.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PooledMessage {
+    pub message_id: u64,
+    pub msg_type_id: i32,
+    pub body: PooledBuf,
+}
+
+/// Doesn't do anything with the messages.  The subscribers read the messages themselves.
+struct NoOpProcessor;
+
+impl InfallibleProcessor for NoOpProcessor {
+    fn handle<'a>(&mut self, _read: &MessageRead<'a>) {}
+}
+
+struct Topic {
+    name: String,
+    /// The directory the topic is stored in.
+    directory: String,
+    config: TopicConfig,
+    file: PersistedMessageFile,
+    types: TypeRegistry,
+    /// The number of messages appended.
+    appended: AtomicU64,
+    /// The number of messages appended with a type that isn't in the registry.
+    unregistered_appends: AtomicU64,
+    /// The id of the last message committed before the last flush.
+    flushed_message_id: AtomicU64,
+    /// The client messages the producer sessions have written.  Loaded when the first session is
+    /// opened.
+    dedup_filter: Mutex<Option<Arc<PersistedBloomFilter>>>,
+}
+
+/// The statistics for a topic since it was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TopicStats {
+    /// The number of messages appended.
+    pub appended: u64,
+    /// The number of messages appended with a type that isn't in the registry.
+    pub unregistered_appends: u64,
+    /// The id of the last message that is known to be flushed to disk.
+    pub flushed_message_id: u64,
+}
+
+impl Topic {
+    /// Gets the filter of the client messages written by the producer sessions.  Loaded from the
+    /// sidecar file the first time it is used.
+    fn dedup_filter(&self) -> Arc<PersistedBloomFilter> {
+        let mut filter = self.dedup_filter.lock().unwrap();
+        if let Some(filter) = filter.as_ref() {
+            return filter.clone();
+        }
+        let path = format!("{}/{}.{}", self.directory, self.name, DEDUP_FILTER_EXTENSION);
+        let loaded = match PersistedBloomFilter::open(&path, DEDUP_FILTER_CAPACITY, DEDUP_FILTER_RATE) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                log::warn!("Unable to load the dedup filter {}: {}", path, e);
+                // The filter is rebuilt from the log when the sessions are opened.
+                let _ = std::fs::remove_file(&path);
+                PersistedBloomFilter::open(&path, DEDUP_FILTER_CAPACITY, DEDUP_FILTER_RATE)
+                    .expect("No file to read.")
+            }
+        };
+        let loaded = Arc::new(loaded);
+        *filter = Some(loaded.clone());
+        loaded
+    }
+
+    /// Saves the dedup filter if it has been loaded.
+    fn save_dedup_filter(&self) {
+        if let Some(filter) = self.dedup_filter.lock().unwrap().as_ref() {
+            if let Err(e) = filter.save() {
+                log::warn!("Unable to save the dedup filter {}: {}", filter.path().display(), e);
+            }
+        }
+    }
+
+    /// Records a message being appended in the statistics.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    fn record_append(&self, msg_type: i32) {
+        self.appended.fetch_add(1, Ordering::Relaxed);
+        if !self.types.is_registered(msg_type) {
+            self.unregistered_appends.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Topic {
+    fn drop(&mut self) {
+        self.file.stop();
+        self.save_dedup_filter();
+    }
+}
+
+/// What a token of a rate limiter is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateUnit {
+    /// A token for each message.
+    Messages,
+    /// A token for each byte of the body.
+    Bytes,
+}
+
+impl RateUnit {
+    /// The number of tokens a message takes.
+    /// # Arguments
+    /// `body` - The body of the message.
+    fn tokens(&self, body: &[u8]) -> u64 {
+        match self {
+            RateUnit::Messages => 1,
+            RateUnit::Bytes => body.len() as u64,
+        }
+    }
+}
+
+/// A handle to a topic.  The topic is stopped when the manager and all of the handles are dropped.
+#[derive(Clone)]
+pub struct TopicHandle {
+    topic: Arc<Topic>,
+    /// Limits the rate of the appends made with the handle.
+    limiter: Option<(Arc<RateLimiter>, RateUnit)>,
+}
+
+impl TopicHandle {
+    /// The name of the topic.
+    pub fn name(&self) -> &str {
+        &self.topic.name
+    }
+
+    /// The settings for the topic.
+    pub fn config(&self) -> &TopicConfig {
+        &self.topic.config
+    }
+
+    /// The directory the topic is stored in.
+    pub fn directory(&self) -> &str {
+        &self.topic.directory
+    }
+
+    /// Limits the rate of the appends made with the handle.  The limiter can be shared between
+    /// handles to limit them together.
+    /// # Arguments
+    /// `limiter` - The limiter to take the tokens from.
+    /// `unit` - What a token is for.
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>, unit: RateUnit) -> Self {
+        self.limiter = Some((limiter, unit));
+        self
+    }
+
+    /// Appends a message to the topic.  Waits for the rate limiter and for the backlog to drain
+    /// first if it is over the watermarks.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `body` - The body of the message.
+    /// # Returns
+    /// The id of the message once it has been committed.
+    pub async fn append(
+        &self,
+        msg_type: i32,
+        body: &[u8],
+    ) -> crate::Result<u64> {
+        self.append_with_headers(msg_type, &MessageHeaders::default(), body)
+            .await
+    }
+
+    /// Appends a message with the user metadata to the topic.  Waits for the rate limiter and for
+    /// the backlog to drain first if it is over the watermarks.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `headers` - The user metadata of the message.
+    /// `body` - The body of the message.
+    /// # Returns
+    /// The id of the message once it has been committed.
+    pub async fn append_with_headers(
+        &self,
+        msg_type: i32,
+        headers: &MessageHeaders,
+        body: &[u8],
+    ) -> crate::Result<u64> {
+        if let Some((limiter, unit)) = &self.limiter {
+            limiter.acquire_async(unit.tokens(body)).await;
+        }
+        self.topic.file.capacity().await;
+        self.topic.record_append(msg_type);
+        self.topic
+            .file
+            .write_with_headers(msg_type, headers, body)
+            .await?
+    }
+
+    /// Appends a message without waiting for the rate limiter or the backlog to drain.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `body` - The body of the message.
+    /// # Returns
+    /// The future that completes with the id of the message once it has been committed or the
+    /// current backlog if the rate limiter is out of tokens or it is over the watermarks.
+    pub fn try_append(
+        &self,
+        msg_type: i32,
+        body: &[u8],
+    ) -> Result<QueueFuture<crate::Result<u64>>, WouldBlock> {
+        if let Some((limiter, unit)) = &self.limiter {
+            if !limiter.try_acquire(unit.tokens(body)) {
+                return Err(WouldBlock {
+                    lag: self.backlog(),
+                });
+            }
+        }
+        let receiver = self.topic.file.try_write(msg_type, body)?;
+        self.topic.record_append(msg_type);
+        Ok(receiver)
+    }
+
+    /// The appends that haven't been committed yet.
+    pub fn backlog(&self) -> BacklogStatus {
+        self.topic.file.backlog()
+    }
+
+    /// How far a consumer group is behind the topic.
+    /// # Arguments
+    /// `group_name` - The name of the consumer group.
+    pub fn lag(&self, group_name: &str) -> GroupResult<LagInfo> {
+        group_lag(self, group_name)
+    }
+
+    /// Gets a handle to write and read the values of a type.
+    pub fn typed<T: MessageCodec>(&self) -> TypedTopicHandle<T> {
+        TypedTopicHandle::new(self.clone())
+    }
+
+    /// Starts a transaction.  The messages added to the transaction become visible together once
+    /// it is committed.
+    pub fn begin_transaction(&self) -> Txn {
+        Txn::new(self.clone())
+    }
+
+    /// The registry of the message types for the topic.
+    pub fn types(&self) -> &TypeRegistry {
+        &self.topic.types
+    }
+
+    /// The statistics for the topic.
+    pub fn stats(&self) -> TopicStats {
+        TopicStats {
+            appended: self.topic.appended.load(Ordering::Relaxed),
+            unregistered_appends: self.topic.unregistered_appends.load(Ordering::Relaxed),
+            flushed_message_id: self.topic.flushed_message_id.load(Ordering::Acquire),
+        }
+    }
+
+    /// How long the appends to the topic have taken to be committed since it was opened.
+    pub fn latency(&self) -> LatencySnapshot {
+        self.topic.file.latency()
+    }
+
+    /// How long the appends to the topic have taken to be committed since the last reset.
+    /// Starts recording over.
+    pub fn latency_and_reset(&self) -> LatencySnapshot {
+        self.topic.file.latency_and_reset()
+    }
+
+    /// The clock the topic gets the time from.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        self.topic.file.clock()
+    }
+
+    /// The id of the last message committed to the topic.
+    pub fn committed_message_id(&self) -> u64 {
+        self.topic.file.max_message_id()
+    }
+
+    /// Flushes the messages in the topic to disk.
+    /// # Returns
+    /// The id of the last message that is known to be flushed.
+    pub fn flush(&self) -> crate::Result<u64> {
+        let flushed = self.topic.file.flush()?;
+        self.topic
+            .flushed_message_id
+            .fetch_max(flushed, Ordering::AcqRel);
+        self.topic.save_dedup_filter();
+        Ok(flushed)
+    }
+
+    /// Subscribes to the committed messages in the topic.
+    /// # Arguments
+    /// `from_id` - The id of the message to start from.
+    pub fn subscribe(&self, from_id: u64) -> CommittedMessageStream {
+        CommittedMessageStream {
+            topic: self.topic.clone(),
+            cursor: CommittedCursor::new(from_id),
+            pool: None,
+        }
+    }
+
+    /// Subscribes to the committed messages matching a filter.  The filter is checked against the
+    /// header so the bodies of the skipped messages are never copied.
+    /// # Arguments
+    /// `from_id` - The id of the message to start from.
+    /// `filter` - The filter for the messages to return.
+    pub fn subscribe_filtered(&self, from_id: u64, filter: MessageFilter) -> CommittedMessageStream {
+        CommittedMessageStream {
+            topic: self.topic.clone(),
+            cursor: CommittedCursor::with_filter(from_id, filter),
+            pool: None,
+        }
+    }
+}
+
+/// Reads the committed messages from a topic.
+pub struct CommittedMessageStream {
+    topic: Arc<Topic>,
+    cursor: CommittedCursor,
+    /// The pool to get the buffers for the bodies from.
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl CommittedMessageStream {
+    /// Uses a pool for the bodies of the messages returned by `next_pooled`.
+    /// # Arguments
+    /// `pool` - The pool to get the buffers from.
+    pub fn with_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Gets the next committed message with the body copied into a buffer from the pool.  The
+    /// buffer goes back to the pool when the message is dropped.  Without a pool the buffer is
+    /// allocated.
+    /// # Returns
+    /// None if we have read all of the committed messages.
+    pub fn next_pooled(&mut self) -> Option<PooledMessage> {
+        let pool = &self.pool;
+        self.cursor
+            .next(&self.topic.file)
+            .map(|msg| PooledMessage {
+                message_id: msg.message_id(),
+                msg_type_id: msg.msg_type_id(),
+                body: match pool {
+                    Some(pool) => pool.copy_of(msg.bytes()),
+                    None => PooledBuf::unpooled(msg.bytes().to_vec()),
+                },
+            })
+    }
+
+    /// Gets the next committed message.
+    /// # Returns
+    /// None if we have read all of the committed messages.  Can be called again to get the
+    /// messages committed since.
+    pub fn next_message(&mut self) -> Option<CommittedMessage> {
+        self.cursor
+            .next(&self.topic.file)
+            .map(|msg| CommittedMessage {
+                message_id: msg.message_id(),
+                msg_type_id: msg.msg_type_id(),
+                headers: msg.headers(),
+                body: msg.bytes().to_vec(),
+            })
+    }
+}
+
+/// Manages the named topics stored in a directory.  Each topic uses its name as the file prefix.
+pub struct TopicManager {
+    directory: String,
+    topics: Mutex<HashMap<String, TopicHandle>>,
+    /// The clock used by the topics.
+    clock: Arc<dyn Clock>,
+}
+
+impl TopicManager {
+    /// Opens the topics in a directory.  The directory is created if it doesn't exist.
+    /// # Arguments
+    /// `directory` - The directory to store the topics in.
+    pub fn open(directory: &str) -> crate::Result<Self> {
+        TopicManager::open_with_clock(directory, system_clock())
+    }
+
+    /// Opens the topics in a directory using a clock for the times.
+    /// # Arguments
+    /// `directory` - The directory to store the topics in.
+    /// `clock` - The clock to get the time from.
+    pub fn open_with_clock(directory: &str, clock: Arc<dyn Clock>) -> crate::Result<Self> {
+        create_dir_all(directory).with_path(directory)?;
+        let manager = TopicManager {
+            directory: directory.to_owned(),
+            topics: Mutex::new(HashMap::new()),
+            clock,
+        };
+        let path = manager.manifest_path();
+        if path.exists() {
+            let manifest = read_to_string(&path).with_path(path.to_string_lossy())?;
+            let mut topics = manager.topics.lock().unwrap();
+            for line in manifest.lines().filter(|l| !l.is_empty()) {
+                let columns: Vec<&str> = line.split('\t').collect();
+                let config = if columns.len() == 4 || columns.len() == 5 {
+                    TopicConfig::from_manifest(
+                        columns[1],
+                        columns[2],
+                        columns[3],
+                        columns.get(4).copied(),
+                    )
+                } else {
+                    None
+                };
+                match config {
+                    Some(config) => {
+                        let handle = manager.start_topic(columns[0], config)?;
+                        topics.insert(columns[0].to_owned(), handle);
+                    }
+                    None => {
+                        return Err(PersistError::invalid_config(format!(
+                            "Invalid topic in the manifest: {}",
+                            line
+                        ))
+                        .with_path(path.to_string_lossy()));
+                    }
+                }
+            }
+        }
+        Ok(manager)
+    }
+
+    /// Creates a new topic.
+    /// # Arguments
+    /// `name` - The name of the topic.  Can only contain letters, numbers, `_` and `-`.
+    /// `config` - The settings for the topic.
+    pub fn create_topic(&self, name: &str, config: TopicConfig) -> crate::Result<TopicHandle> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(PersistError::invalid_config(format!(
+                "Invalid topic name: {}",
+                name
+            )));
+        }
+        let mut topics = self.topics.lock().unwrap();
+        if topics.contains_key(name) {
+            return Err(PersistError::invalid_config(format!(
+                "The topic {} already exists.",
+                name
+            )));
+        }
+        let mut manifest = String::new();
+        let mut names: Vec<&String> = topics.keys().collect();
+        names.sort();
+        for existing in names {
+            let handle = topics.get(existing).unwrap();
+            manifest.push_str(&format!("{}\t{}\n", existing, handle.config().to_manifest()));
+        }
+        manifest.push_str(&format!("{}\t{}\n", name, config.to_manifest()));
+        self.write_manifest(&manifest)
+            .with_path(self.manifest_path().to_string_lossy())?;
+        let handle = self.start_topic(name, config)?;
+        topics.insert(name.to_owned(), handle.clone());
+        Ok(handle)
+    }
+
+    /// Gets a topic.
+    /// # Arguments
+    /// `name` - The name of the topic to get.
+    pub fn topic(&self, name: &str) -> Option<TopicHandle> {
+        self.topics.lock().unwrap().get(name).cloned()
+    }
+
+    /// The names of the topics sorted.
+    pub fn topic_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.topics.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The lag of every consumer group on all of the topics.
+    /// # Returns
+    /// The lag sorted by the topic and then the group.
+    pub fn all_lags(&self) -> GroupResult<Vec<LagInfo>> {
+        let mut lags = Vec::new();
+        for name in self.topic_names() {
+            if let Some(topic) = self.topic(&name) {
+                lags.append(&mut topic_lags(&topic)?);
+            }
+        }
+        Ok(lags)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        Path::new(&self.directory).join(MANIFEST_FILE)
+    }
+
+    /// Writes the manifest to a temporary file and renames it so we never have a partial manifest.
+    fn write_manifest(&self, manifest: &str) -> io::Result<()> {
+        let path = self.manifest_path();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(manifest.as_bytes())?;
+            file.sync_all()?;
+        }
+        rename(&tmp_path, &path)
+    }
+
+    fn start_topic(&self, name: &str, config: TopicConfig) -> crate::Result<TopicHandle> {
+        let types =
+            TypeRegistry::open(Path::new(&self.directory).join(format!("{}.types", name)))?;
+        let file = startup_single_node_with_clock(
+            self.directory.clone(),
+            name.to_owned(),
+            config.max_file_size,
+            TOPIC_COMMIT_FILE_SIZE,
+            NoOpProcessor,
+            TOPIC_INCOMING_BUFFER_SIZE,
+            TOPIC_INCOMING_QUEUE_SIZE,
+            self.clock.clone(),
+        );
+        file.set_watermarks(config.watermarks);
+        Ok(TopicHandle {
+            topic: Arc::new(Topic {
+                name: name.to_owned(),
+                directory: self.directory.clone(),
+                config,
+                file,
+                types,
+                appended: AtomicU64::new(0),
+                unregistered_appends: AtomicU64::new(0),
+                flushed_message_id: AtomicU64::new(0),
+                dedup_filter: Mutex::new(None),
+            }),
+            limiter: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::message_stream::*;
+    use crate::raft::create_event_name;
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::thread;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_topics";
+
+    /// Reads in the messages until we have the number expected.
+    fn read_messages(handle: &TopicHandle, count: usize) -> Vec<CommittedMessage> {
+        let mut stream = handle.subscribe(1);
+        let mut messages = Vec::new();
+        for _ in 0..500 {
+            while let Some(msg) = stream.next_message() {
+                messages.push(msg);
+            }
+            if messages.len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        messages
+    }
+
+    #[test]
+    fn topic_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let trades_config = TopicConfig {
+            max_file_size: 0x20000,
+            retention: RetentionPolicy::KeepFiles(3),
+            flush_policy: FlushPolicy::EveryNMessages(10),
+            watermarks: Some(Watermarks {
+                high: 0x10000,
+                low: 0x8000,
+            }),
+        };
+        {
+            let manager = TopicManager::open(TEST_DIR).unwrap();
+            manager
+                .create_topic("orders", TopicConfig::default())
+                .unwrap();
+            manager.create_topic("trades", trades_config).unwrap();
+            assert!(manager.create_topic("trades", trades_config).is_err());
+            assert!(manager.create_topic("bad.name", trades_config).is_err());
+
+            let threads: Vec<_> = manager
+                .topic_names()
+                .into_iter()
+                .map(|name| {
+                    let handle = manager.topic(&name).unwrap();
+                    thread::spawn(move || {
+                        (0..100u64)
+                            .map(|i| block_on(handle.append(1, &i.to_le_bytes())).unwrap())
+                            .collect::<Vec<u64>>()
+                    })
+                })
+                .collect();
+            for t in threads {
+                assert_eq!((1..=100).collect::<Vec<u64>>(), t.join().unwrap());
+            }
+            assert!(Path::new(&create_event_name(TEST_DIR, "orders", &1)).exists());
+            assert!(Path::new(&create_event_name(TEST_DIR, "trades", &1)).exists());
+        }
+
+        let manager = TopicManager::open(TEST_DIR).unwrap();
+        assert_eq!(vec!["orders", "trades"], manager.topic_names());
+        let trades = manager.topic("trades").unwrap();
+        assert_eq!(&trades_config, trades.config());
+        for name in manager.topic_names() {
+            let handle = manager.topic(&name).unwrap();
+            let messages = read_messages(&handle, 100);
+            assert_eq!(100, messages.len());
+            for (i, msg) in messages.iter().enumerate() {
+                assert_eq!(i as u64 + 1, msg.message_id);
+                assert_eq!(&(i as u64).to_le_bytes()[..], &msg.body[..]);
+            }
+            assert_eq!(101, block_on(handle.append(1, &[1, 2])).unwrap());
+        }
+    }
+
+    #[test]
+    fn topic_types_test() {
+        let directory = format!("{}_types", TEST_DIR);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        {
+            let manager = TopicManager::open(&directory).unwrap();
+            let topic = manager
+                .create_topic("orders", TopicConfig::default())
+                .unwrap();
+            let placed = topic.types().register("OrderPlaced", None).unwrap();
+            block_on(topic.append(placed as i32, &[1])).unwrap();
+            block_on(topic.append(99, &[2])).unwrap();
+            assert_eq!(
+                TopicStats {
+                    appended: 2,
+                    unregistered_appends: 1,
+                    flushed_message_id: 0
+                },
+                topic.stats()
+            );
+        }
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager.topic("orders").unwrap();
+        let messages = read_messages(&topic, 2);
+        assert_eq!(
+            Some("OrderPlaced".to_owned()),
+            topic.types().name_of(messages[0].msg_type_id as u16)
+        );
+        assert_eq!(None, topic.types().name_of(messages[1].msg_type_id as u16));
+    }
+
+    #[test]
+    fn topic_filtered_test() {
+        let directory = format!("{}_filtered", TEST_DIR);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        for i in 0..30u64 {
+            let body = vec![1; (i % 5) as usize + 1];
+            block_on(topic.append((i % 3) as i32 + 1, &body)).unwrap();
+        }
+        let mut stream = topic.subscribe_filtered(
+            1,
+            MessageFilter::of_types(&[2])
+                .with_range(1, 20)
+                .with_predicate(|h| h.size == (h.message_id as usize - 1) % 5 + 1),
+        );
+        let mut messages = Vec::new();
+        while let Some(msg) = stream.next_message() {
+            messages.push(msg);
+        }
+        assert_eq!(7, messages.len());
+        for msg in messages.iter() {
+            assert_eq!(2, msg.msg_type_id);
+            assert!(msg.message_id <= 20);
+        }
+        // Stays at the end of the range.
+        block_on(topic.append(2, &[1])).unwrap();
+        assert!(stream.next_message().is_none());
+    }
+
+    #[test]
+    fn topic_headers_test() {
+        let directory = format!("{}_headers", TEST_DIR);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        for i in 0..30u64 {
+            let headers = MessageHeaders {
+                tenant: (i % 3) as u32,
+                priority: (i % 4) as u8,
+                ..Default::default()
+            };
+            block_on(topic.append_with_headers(1, &headers, &i.to_le_bytes())).unwrap();
+        }
+        let messages = read_messages(&topic, 30);
+        assert_eq!(30, messages.len());
+        for (i, msg) in messages.iter().enumerate() {
+            assert_eq!((i % 3) as u32, msg.headers.tenant);
+            assert_eq!((i % 4) as u8, msg.headers.priority);
+            assert_eq!(&(i as u64).to_le_bytes()[..], &msg.body[..]);
+        }
+
+        let mut stream = topic.subscribe_filtered(
+            1,
+            MessageFilter::default()
+                .with_tenants(&[1, 2])
+                .with_predicate(|h| h.headers.priority != 0),
+        );
+        let mut messages = Vec::new();
+        while let Some(msg) = stream.next_message() {
+            messages.push(msg);
+        }
+        let expected: Vec<u64> = (0..30u64)
+            .filter(|i| i % 3 != 0 && i % 4 != 0)
+            .map(|i| i + 1)
+            .collect();
+        assert_eq!(
+            expected,
+            messages.iter().map(|m| m.message_id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn pooled_stream_test() {
+        let directory = format!("{}_pooled", TEST_DIR);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap();
+        for i in 0..20u64 {
+            block_on(topic.append(1, &i.to_le_bytes())).unwrap();
+        }
+        let pool = BufferPool::new(&[64], 4);
+        let mut stream = topic.subscribe(1).with_pool(pool.clone());
+        let mut ids = Vec::new();
+        while let Some(msg) = stream.next_pooled() {
+            assert!(msg.body.is_pooled());
+            let mut value = [0; 8];
+            value.copy_from_slice(&msg.body[..8]);
+            assert_eq!(msg.message_id - 1, u64::from_le_bytes(value));
+            ids.push(msg.message_id);
+        }
+        assert_eq!((1..=20).collect::<Vec<u64>>(), ids);
+        // Each message is dropped before the next is read so the same buffer is reused.
+        assert_eq!(1, pool.stats().misses);
+        assert_eq!(19, pool.stats().hits);
+
+        let msg = topic.subscribe(1).next_pooled().unwrap();
+        assert!(!msg.body.is_pooled());
+        assert_eq!(&0u64.to_le_bytes()[..], &msg.body[..]);
+    }
+
+    #[test]
+    fn rate_limited_test() {
+        let directory = format!("{}_rate", TEST_DIR);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        let manager = TopicManager::open(&directory).unwrap();
+        let clock = Arc::new(a19_core::clock::ManualClock::new(0));
+        let limiter = Arc::new(RateLimiter::with_clock(100, 16, clock.clone()));
+        let topic = manager
+            .create_topic("orders", TopicConfig::default())
+            .unwrap()
+            .with_rate_limiter(limiter.clone(), RateUnit::Bytes);
+        assert_eq!(1, block_on(topic.append(1, &[1; 8])).unwrap());
+        assert!(topic.try_append(1, &[2; 8]).is_ok());
+        // Out of bytes until the clock moves.
+        assert!(topic.try_append(1, &[3; 8]).is_err());
+        clock.advance(Duration::from_millis(50));
+        assert!(topic.try_append(1, &[3; 4]).is_ok());
+        assert_eq!(1, limiter.available());
+
+        // A handle without the limiter isn't throttled.
+        let other = manager.topic("orders").unwrap();
+        for _ in 0..10 {
+            assert!(other.try_append(1, &[4; 8]).is_ok());
+        }
+        assert_eq!(13, read_messages(&other, 13).len());
+    }
+}
@@ -0,0 +1,277 @@
+//! A topic split across shards so more than one writer can append at once.  The key of a message
+//! picks the shard with a consistent hash ring so adding a shard only moves the keys it takes.
+//!```text
+//!                         +--> orders-0 --+
+//! append(key) --> ring ---+--> orders-1 --+--> subscribe_all (merged by commit time)
+//!                         +--> orders-2 --+
+//!```
+//! The messages don't have a time of their own so the merged stream orders them by the time the
+//! term they were committed in was committed.  The messages in a shard stay in the order of their
+//! ids.
+use crate::message_stream::{
+    CommittedMessage, CommittedMessageStream, TopicConfig, TopicHandle, TopicManager,
+};
+use crate::raft::TermLocation;
+use a19_concurrent::hash_ring::{HashRing, RebalanceReport, DEFAULT_VIRTUAL_NODES};
+
+/// A message read from one of the shards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionedMessage {
+    /// The id of the shard the message is in.
+    pub shard: u32,
+    /// When the message was committed in milliseconds since the epoch.
+    pub committed_at: u64,
+    pub message: CommittedMessage,
+}
+
+/// A topic made up of shards.
+pub struct PartitionedTopic {
+    /// The shards with the id of the shard being the index.
+    shards: Vec<TopicHandle>,
+    ring: HashRing,
+}
+
+impl PartitionedTopic {
+    /// Creates a topic from the shards.
+    /// # Arguments
+    /// `shards` - The topics to spread the messages across.  The order needs to be the same each
+    /// time so the keys go to the same shard.
+    pub fn new(shards: Vec<TopicHandle>) -> Self {
+        let ring = HashRing::with_nodes(DEFAULT_VIRTUAL_NODES, 0..shards.len() as u32);
+        PartitionedTopic { shards, ring }
+    }
+
+    /// Opens the shards of a topic in the manager.  The shards are named `name-0`, `name-1`, ...
+    /// and are created if they don't exist.
+    /// # Arguments
+    /// `manager` - The manager of the topics.
+    /// `name` - The name of the topic.
+    /// `shards` - The number of shards.
+    /// `config` - The settings for the shards that need to be created.
+    pub fn open(
+        manager: &TopicManager,
+        name: &str,
+        shards: u32,
+        config: TopicConfig,
+    ) -> crate::Result<Self> {
+        let handles = (0..shards)
+            .map(|shard| {
+                let shard_name = format!("{}-{}", name, shard);
+                match manager.topic(&shard_name) {
+                    Some(handle) => Ok(handle),
+                    None => manager.create_topic(&shard_name, config),
+                }
+            })
+            .collect::<crate::Result<Vec<_>>>()?;
+        Ok(PartitionedTopic::new(handles))
+    }
+
+    /// The number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Gets a shard.
+    /// # Arguments
+    /// `shard` - The id of the shard.
+    pub fn shard(&self, shard: u32) -> Option<&TopicHandle> {
+        self.shards.get(shard as usize)
+    }
+
+    /// The ring used to pick the shards.
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Gets the shard for a key.
+    /// # Arguments
+    /// `key` - The key of the message.
+    /// # Returns
+    /// The id of the shard or `None` if there aren't any shards.
+    pub fn shard_for<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Option<u32> {
+        self.ring.node_for(key)
+    }
+
+    /// Adds a shard to the end.
+    /// # Arguments
+    /// `shard` - The topic for the shard.
+    /// # Returns
+    /// The ranges of the key hashes that moved to the new shard.
+    pub fn add_shard(&mut self, shard: TopicHandle) -> RebalanceReport {
+        let previous = self.ring.clone();
+        self.ring.add_node(self.shards.len() as u32);
+        self.shards.push(shard);
+        self.ring.rebalance_report(&previous)
+    }
+
+    /// Appends a message to the shard for the key.
+    /// # Arguments
+    /// `key` - The key of the message.
+    /// `msg_type` - The type of the message.
+    /// `body` - The body of the message.
+    /// # Returns
+    /// The id of the shard and the id of the message once it has been committed.
+    pub async fn append<K: AsRef<[u8]> + ?Sized>(
+        &self,
+        key: &K,
+        msg_type: i32,
+        body: &[u8],
+    ) -> crate::Result<(u32, u64)> {
+        let shard = self
+            .shard_for(key)
+            .expect("The topic doesn't have any shards.");
+        let message_id = self.shards[shard as usize].append(msg_type, body).await?;
+        Ok((shard, message_id))
+    }
+
+    /// Subscribes to the committed messages in all of the shards from the start.
+    pub fn subscribe_all(&self) -> PartitionedMessageStream {
+        PartitionedMessageStream {
+            shards: self
+                .shards
+                .iter()
+                .enumerate()
+                .map(|(shard, topic)| ShardCursor {
+                    shard: shard as u32,
+                    topic: topic.clone(),
+                    stream: topic.subscribe(1),
+                    term: None,
+                    head: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Reads a shard for the merged stream.
+struct ShardCursor {
+    shard: u32,
+    topic: TopicHandle,
+    stream: CommittedMessageStream,
+    /// The term of the last message read.  The messages are in order so it is reused until we
+    /// are past it.
+    term: Option<TermLocation>,
+    /// The next message in the shard.
+    head: Option<PartitionedMessage>,
+}
+
+impl ShardCursor {
+    /// Reads the next message in the shard if we don't already have one.
+    fn fill(&mut self) {
+        if self.head.is_some() {
+            return;
+        }
+        if let Some(message) = self.stream.next_message() {
+            let cached = match &self.term {
+                Some(term) => term.max_message_id >= message.message_id,
+                None => false,
+            };
+            if !cached {
+                if let Some(term) = self.topic.topic.file.find_term(message.message_id) {
+                    self.term = Some(term);
+                }
+            }
+            self.head = Some(PartitionedMessage {
+                shard: self.shard,
+                committed_at: self.term.as_ref().map(|t| t.committed_at).unwrap_or(0),
+                message,
+            });
+        }
+    }
+}
+
+/// Reads the committed messages of all of the shards merged by the time they were committed.
+pub struct PartitionedMessageStream {
+    shards: Vec<ShardCursor>,
+}
+
+impl PartitionedMessageStream {
+    /// Gets the next committed message.  Messages committed at the same time are returned in the
+    /// order of the shards.
+    /// # Returns
+    /// None if we have read all of the committed messages.  Can be called again to get the
+    /// messages committed since.
+    pub fn next_message(&mut self) -> Option<PartitionedMessage> {
+        let mut next: Option<(usize, u64)> = None;
+        for (i, cursor) in self.shards.iter_mut().enumerate() {
+            cursor.fill();
+            if let Some(head) = &cursor.head {
+                match next {
+                    Some((_, committed_at)) if committed_at <= head.committed_at => {}
+                    _ => next = Some((i, head.committed_at)),
+                }
+            }
+        }
+        next.and_then(|(i, _)| self.shards[i].head.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::message_stream::partitioned::*;
+    use a19_core::clock::ManualClock;
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_partitioned";
+
+    #[test]
+    fn subscribe_all_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let clock = Arc::new(ManualClock::new(1_000));
+        let manager = TopicManager::open_with_clock(TEST_DIR, clock.clone()).unwrap();
+        let mut topic =
+            PartitionedTopic::open(&manager, "orders", 3, TopicConfig::default()).unwrap();
+        assert_eq!(3, topic.shard_count());
+
+        let mut shards = Vec::new();
+        for i in 0..60u64 {
+            // Each append is committed in its own term so they all have a different time.
+            clock.set_ms(1_000 + i * 10);
+            let key = format!("account-{}", i % 17);
+            let (shard, _) = block_on(topic.append(&key, 1, &i.to_le_bytes())).unwrap();
+            assert_eq!(Some(shard), topic.shard_for(&key));
+            shards.push(shard);
+        }
+        // The keys are spread across all of the shards.
+        for shard in 0..3 {
+            assert!(shards.contains(&shard));
+        }
+
+        let mut stream = topic.subscribe_all();
+        let mut last = 0;
+        for i in 0..60u64 {
+            let msg = stream.next_message().unwrap();
+            assert!(msg.committed_at >= last);
+            last = msg.committed_at;
+            assert_eq!(1_000 + i * 10, msg.committed_at);
+            assert_eq!(shards[i as usize], msg.shard);
+            assert_eq!(&i.to_le_bytes()[..], &msg.message.body[..]);
+        }
+        assert_eq!(None, stream.next_message());
+
+        // Opening again finds the same shards.
+        let again = PartitionedTopic::open(&manager, "orders", 3, TopicConfig::default()).unwrap();
+        assert_eq!(topic.shard_for("account-3"), again.shard_for("account-3"));
+
+        let extra = manager
+            .create_topic("orders-3", TopicConfig::default())
+            .unwrap();
+        let report = topic.add_shard(extra);
+        assert!(report.moved.iter().all(|r| r.to == Some(3)));
+        clock.set_ms(5_000);
+        let (shard, _) = block_on(topic.append("account-3", 1, &[1])).unwrap();
+        assert_eq!(report.contains(HashRing::hash_key("account-3")), shard == 3);
+        let mut stream = topic.subscribe_all();
+        let messages: Vec<_> = (0..61).filter_map(|_| stream.next_message()).collect();
+        assert_eq!(61, messages.len());
+        assert_eq!(5_000, messages[60].committed_at);
+        assert_eq!(shard, messages[60].shard);
+    }
+}
@@ -0,0 +1,234 @@
+//! Producer sessions stop a client that retries a send from creating the same message twice.  Each
+//! message is written in a transaction behind a producer message with the id of the client and the
+//! sequence number the client gave the message.
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Client Id                                                     |
+//! |                                                               |
+//! +---------------------------------------------------------------+
+//! | Client Message Id                                             |
+//! |                                                               |
+//! +---------------------------------------------------------------+
+//! ```
+//!
+//! The log is the only place the state is kept so it is rebuilt by reading the topic when the
+//! session is opened.  A bloom filter of the client messages is shared by the sessions for a topic
+//! so a message that has never been sent skips the lookup.  The filter is saved next to the topic
+//! when it is flushed or closed.
+use crate::error::PersistError;
+use crate::message_stream::TopicHandle;
+use crate::raft::{CommittedCursor, TransactionBatch, PRODUCER_MESSAGE_TYPE};
+use a19_concurrent::bloom::PersistedBloomFilter;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const CLIENT_ID: usize = 0;
+const CLIENT_MESSAGE_ID: usize = 8;
+const PRODUCER_HEADER_SIZE: usize = 16;
+
+/// The errors for a producer session.
+#[derive(Debug)]
+pub enum ProducerError {
+    /// The client sent a sequence number lower than one that has already been written.
+    SequenceRegression {
+        client_message_id: u64,
+        last_client_message_id: u64,
+    },
+    /// Unable to write the message.
+    WriteFailed(PersistError),
+}
+
+impl From<PersistError> for ProducerError {
+    fn from(err: PersistError) -> Self {
+        ProducerError::WriteFailed(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ProducerError>;
+
+/// Sends the messages for a client so a retry returns the message that was already written.
+pub struct ProducerSession {
+    topic: TopicHandle,
+    client_id: u64,
+    /// The highest sequence number written for the client.
+    last_client_message_id: u64,
+    /// The global message id for each of the client sequence numbers.
+    sent: HashMap<u64, u64>,
+    /// The client messages written to the topic by all of the sessions.
+    seen: Arc<PersistedBloomFilter>,
+}
+
+impl ProducerSession {
+    /// Opens the session for a client.  Reads through the topic to find the messages the client
+    /// has already written.
+    /// # Arguments
+    /// `topic` - The topic to write to.
+    /// `client_id` - The id of the client.
+    pub fn open(topic: &TopicHandle, client_id: u64) -> Self {
+        let file = &topic.topic.file;
+        // Everything written is going to be committed so wait for it to get the full picture.
+        let written = file.written_message_id();
+        while file.max_message_id() < written {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut session = ProducerSession {
+            topic: topic.clone(),
+            client_id,
+            last_client_message_id: 0,
+            sent: HashMap::new(),
+            seen: topic.topic.dedup_filter(),
+        };
+        let mut cursor = CommittedCursor::with_store_messages(1);
+        let mut client_message_id = None;
+        while let Some(msg) = cursor.next(file) {
+            if msg.msg_type_id() == PRODUCER_MESSAGE_TYPE {
+                let bytes = msg.bytes();
+                client_message_id = if bytes.len() == PRODUCER_HEADER_SIZE
+                    && BigEndian::read_u64(&bytes[CLIENT_ID..CLIENT_MESSAGE_ID]) == client_id
+                {
+                    Some(BigEndian::read_u64(&bytes[CLIENT_MESSAGE_ID..]))
+                } else {
+                    None
+                };
+            } else if msg.msg_type_id() >= 0 {
+                if let Some(id) = client_message_id.take() {
+                    session.record(id, msg.message_id());
+                }
+            }
+        }
+        session
+    }
+
+    /// The id of the client.
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    /// The highest sequence number written for the client.
+    pub fn last_client_message_id(&self) -> u64 {
+        self.last_client_message_id
+    }
+
+    /// Sends a message.  If the sequence number has already been written the id of the message is
+    /// returned without writing it again.
+    /// # Arguments
+    /// `client_message_id` - The sequence number the client gave the message.
+    /// `msg_type` - The type of the message.
+    /// `body` - The body of the message.
+    /// # Returns
+    /// The id of the message in the topic.
+    pub async fn send(&mut self, client_message_id: u64, msg_type: i32, body: &[u8]) -> Result<u64> {
+        // Most of the messages are new so the filter answers without the lookup.
+        if self.seen.maybe_contains(&(self.client_id, client_message_id)) {
+            if let Some(message_id) = self.sent.get(&client_message_id) {
+                return Ok(*message_id);
+            }
+        }
+        if client_message_id <= self.last_client_message_id {
+            return Err(ProducerError::SequenceRegression {
+                client_message_id,
+                last_client_message_id: self.last_client_message_id,
+            });
+        }
+        let mut header = [0; PRODUCER_HEADER_SIZE];
+        BigEndian::write_u64(&mut header[CLIENT_ID..CLIENT_MESSAGE_ID], self.client_id);
+        BigEndian::write_u64(&mut header[CLIENT_MESSAGE_ID..], client_message_id);
+        let mut batch = TransactionBatch::new();
+        batch.add(PRODUCER_MESSAGE_TYPE, &header);
+        batch.add(msg_type, body);
+        self.topic.topic.file.capacity().await;
+        self.topic.topic.record_append(msg_type);
+        let message_id = self
+            .topic
+            .topic
+            .file
+            .write_transaction(&batch)
+            .await
+            .map_err(PersistError::from)??;
+        self.record(client_message_id, message_id);
+        Ok(message_id)
+    }
+
+    /// Records a message that has been written for the client.
+    fn record(&mut self, client_message_id: u64, message_id: u64) {
+        self.seen.insert(&(self.client_id, client_message_id));
+        self.sent.insert(client_message_id, message_id);
+        if client_message_id > self.last_client_message_id {
+            self.last_client_message_id = client_message_id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::message_stream::producer_session::*;
+    use crate::message_stream::{TopicConfig, TopicManager};
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_producer";
+
+    #[test]
+    fn producer_session_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let first_ids = {
+            let manager = TopicManager::open(TEST_DIR).unwrap();
+            let topic = manager
+                .create_topic("orders", TopicConfig::default())
+                .unwrap();
+            let mut session = ProducerSession::open(&topic, 7);
+            let mut other = ProducerSession::open(&topic, 8);
+            let ids: Vec<u64> = (1..=3u64)
+                .map(|i| block_on(session.send(i, 1, &i.to_le_bytes())).unwrap())
+                .collect();
+            assert!(block_on(other.send(1, 1, &[1])).unwrap() > ids[2]);
+            // A retry gets the same id back.
+            assert_eq!(ids[1], block_on(session.send(2, 1, &[2])).unwrap());
+            ids
+        };
+
+        let manager = TopicManager::open(TEST_DIR).unwrap();
+        let topic = manager.topic("orders").unwrap();
+        let filter = Path::new(TEST_DIR).join("orders.dedup.bloom");
+        assert!(filter.exists());
+        let mut session = ProducerSession::open(&topic, 7);
+        assert!(session.seen.maybe_contains(&(7u64, 3u64)));
+        assert!(session.seen.maybe_contains(&(8u64, 1u64)));
+        assert_eq!(3, session.last_client_message_id());
+        let replayed: Vec<u64> = (1..=3u64)
+            .map(|i| block_on(session.send(i, 1, &i.to_le_bytes())).unwrap())
+            .collect();
+        assert_eq!(first_ids, replayed);
+        let mut stream = topic.subscribe(1);
+        let mut count = 0;
+        while let Some(msg) = stream.next_message() {
+            assert_eq!(1, msg.msg_type_id);
+            count += 1;
+        }
+        // One copy for each of the sends from client 7 and the one from client 8.
+        assert_eq!(4, count);
+
+        block_on(session.send(5, 1, &[5])).unwrap();
+        match block_on(session.send(4, 1, &[4])) {
+            Err(ProducerError::SequenceRegression {
+                client_message_id,
+                last_client_message_id,
+            }) => {
+                assert_eq!(4, client_message_id);
+                assert_eq!(5, last_client_message_id);
+            }
+            _ => panic!("The regression should have been rejected."),
+        }
+    }
+}
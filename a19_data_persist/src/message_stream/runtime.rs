@@ -0,0 +1,745 @@
+//! Runs the background work for the topics on a small pool of threads instead of a thread per
+//! topic.  The work is flushing the topics according to their flush policy, telling the watchers
+//! when new messages are committed and the periodic tasks like the retention checks.
+//!
+//! ```text
+//!  add_topic/remove_topic/on_commit/schedule
+//!                  |
+//!                  V
+//!            +-----------+      +------------------------+
+//!            | commands  | ---> | timers (by due time)   |
+//!            +-----------+      +------------------------+
+//!                                 |        |         |
+//!                                 V        V         V
+//!                             worker 1  worker 2  worker n
+//! ```
+//!
+//! A topic only has one task running at a time.  If a task on a topic is blocked the timers for
+//! the topic are pushed back and the workers keep serving the other topics.  A task that runs
+//! longer than the deadline is logged and the worker is counted as blocked until it finishes.
+//!
+//! Starting the runtime waits on a latch for every worker to be running.  Dropping it waits on a
+//! wait group for the workers to finish up to the shutdown timeout and leaves behind the workers
+//! stuck in a task instead of hanging.
+use crate::message_stream::TopicHandle;
+use crate::raft::FlushPolicy;
+use a19_concurrent::latch::{Latch, LatchError, WaitGroup};
+use a19_concurrent::queue::mpsc_queue::{MpscQueueReceive, MpscQueueWrap};
+use a19_concurrent::threads::{spawn_configured, ThreadConfig};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use a19_core::clock::{system_clock, Clock};
+use std::time::Duration;
+
+/// The size of the queue for the commands sent to the workers.
+const COMMAND_QUEUE_SIZE: usize = 0x400;
+
+/// Called with the topic and the id of the last committed message when new messages are committed.
+pub type CommitCallback = Arc<dyn Fn(&TopicHandle, u64) + Send + Sync>;
+
+/// A task that is run periodically on a topic.
+pub type PeriodicTask = Arc<dyn Fn(&TopicHandle) + Send + Sync>;
+
+/// The settings for the runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// The number of worker threads.
+    pub workers: usize,
+    /// How often to check the topics for newly committed messages.
+    pub tick: Duration,
+    /// How long a task can run before the worker is considered blocked.
+    pub task_deadline: Duration,
+    /// The settings for the worker threads.  The workers get the name with their number added
+    /// and are pinned to the cores that follow the one in the settings.
+    pub worker_thread: Option<ThreadConfig>,
+    /// How long to wait for the workers to finish their tasks when the runtime is dropped.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            workers: 2,
+            tick: Duration::from_millis(1),
+            task_deadline: Duration::from_millis(100),
+            worker_thread: None,
+            shutdown_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The changes sent to the workers.
+#[derive(Clone)]
+enum Command {
+    AddTopic(TopicHandle),
+    RemoveTopic(String),
+    OnCommit(String, CommitCallback),
+    Schedule(String, Duration, PeriodicTask),
+}
+
+/// The kind of work to do on a topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TaskKind {
+    /// Checks for new commits and flushes if the policy is by commits.
+    Commit,
+    /// Flushes a topic with an interval policy.
+    Flush,
+    /// Runs the periodic task at the index.
+    Periodic(usize),
+}
+
+/// When to run the next task for a topic.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Timer {
+    /// The monotonic time in nanoseconds when the task is due.
+    due: u64,
+    /// Keeps the timers with the same due time in the order they were added.
+    sequence: u64,
+    topic: String,
+    /// The generation of the topic the timer was created for.
+    generation: u64,
+    kind: TaskKind,
+}
+
+/// A topic registered with the runtime.
+struct Registered {
+    topic: TopicHandle,
+    /// Changes every time a topic is added so the timers for a removed topic are ignored.
+    generation: u64,
+    /// True while a task is running on the topic.
+    busy: bool,
+    /// The last committed id the callbacks were called with.
+    notified_id: u64,
+    callbacks: Vec<CommitCallback>,
+    periodic: Vec<(Duration, PeriodicTask)>,
+}
+
+/// A task taken off of the timers to run outside of the lock.
+struct Job {
+    name: String,
+    generation: u64,
+    kind: TaskKind,
+    topic: TopicHandle,
+    notified_id: u64,
+    callbacks: Vec<CommitCallback>,
+    task: Option<PeriodicTask>,
+}
+
+impl Job {
+    /// Runs the task.
+    /// # Returns
+    /// The last committed id the callbacks were called with.
+    fn run(&self) -> u64 {
+        match self.kind {
+            TaskKind::Commit => {
+                let committed = self.topic.committed_message_id();
+                if committed > self.notified_id {
+                    for callback in self.callbacks.iter() {
+                        callback(&self.topic, committed);
+                    }
+                }
+                let flushed = self.topic.stats().flushed_message_id;
+                let flush = match self.topic.config().flush_policy {
+                    FlushPolicy::EveryCommit => committed > flushed,
+                    FlushPolicy::EveryNMessages(n) => {
+                        committed.saturating_sub(flushed) >= u64::from(n.max(1))
+                    }
+                    FlushPolicy::Interval(_) | FlushPolicy::Os => false,
+                };
+                if flush {
+                    self.flush();
+                }
+                committed.max(self.notified_id)
+            }
+            TaskKind::Flush => {
+                if self.topic.committed_message_id() > self.topic.stats().flushed_message_id {
+                    self.flush();
+                }
+                self.notified_id
+            }
+            TaskKind::Periodic(_) => {
+                if let Some(task) = self.task.as_ref() {
+                    task(&self.topic);
+                }
+                self.notified_id
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.topic.flush() {
+            log::error!("Unable to flush the topic {}: {}", self.name, e);
+        }
+    }
+}
+
+/// The topics and the timers shared by the workers.
+struct Scheduler {
+    commands: MpscQueueReceive<Command>,
+    topics: HashMap<String, Registered>,
+    timers: BinaryHeap<Reverse<Timer>>,
+    next_sequence: u64,
+    next_generation: u64,
+    tick: Duration,
+}
+
+impl Scheduler {
+    fn add_timer(&mut self, due: u64, topic: &str, generation: u64, kind: TaskKind) {
+        self.next_sequence += 1;
+        self.timers.push(Reverse(Timer {
+            due,
+            sequence: self.next_sequence,
+            topic: topic.to_owned(),
+            generation,
+            kind,
+        }));
+    }
+
+    /// Applies the commands that have been submitted.
+    /// # Arguments
+    /// `now` - The current time.
+    fn apply_commands(&mut self, now: u64) {
+        while let Some(command) = self.commands.poll() {
+            match command {
+                Command::AddTopic(topic) => {
+                    let name = topic.name().to_owned();
+                    if self.topics.contains_key(&name) {
+                        continue;
+                    }
+                    self.next_generation += 1;
+                    let generation = self.next_generation;
+                    self.add_timer(now, &name, generation, TaskKind::Commit);
+                    if let FlushPolicy::Interval(interval) = topic.config().flush_policy {
+                        self.add_timer(now + nanos(interval), &name, generation, TaskKind::Flush);
+                    }
+                    self.topics.insert(
+                        name,
+                        Registered {
+                            topic,
+                            generation,
+                            busy: false,
+                            notified_id: 0,
+                            callbacks: Vec::new(),
+                            periodic: Vec::new(),
+                        },
+                    );
+                }
+                Command::RemoveTopic(name) => {
+                    self.topics.remove(&name);
+                }
+                Command::OnCommit(name, callback) => {
+                    if let Some(registered) = self.topics.get_mut(&name) {
+                        registered.callbacks.push(callback);
+                    }
+                }
+                Command::Schedule(name, interval, task) => {
+                    let timer = self.topics.get_mut(&name).map(|registered| {
+                        registered.periodic.push((interval, task));
+                        (registered.generation, registered.periodic.len() - 1)
+                    });
+                    if let Some((generation, index)) = timer {
+                        self.add_timer(
+                            now + nanos(interval),
+                            &name,
+                            generation,
+                            TaskKind::Periodic(index),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gets the next task that is due.  Tasks for a topic that is already busy are pushed back.
+    /// # Arguments
+    /// `now` - The current time.
+    fn next_job(&mut self, now: u64) -> Option<Job> {
+        loop {
+            if self.timers.peek()?.0.due > now {
+                return None;
+            }
+            let Reverse(timer) = self.timers.pop()?;
+            let tick = self.tick;
+            let registered = match self.topics.get_mut(&timer.topic) {
+                Some(registered) if registered.generation == timer.generation => registered,
+                // The topic was removed.
+                _ => continue,
+            };
+            if registered.busy {
+                self.add_timer(now + nanos(tick), &timer.topic, timer.generation, timer.kind);
+                continue;
+            }
+            registered.busy = true;
+            let task = match timer.kind {
+                TaskKind::Periodic(index) => Some(registered.periodic[index].1.clone()),
+                _ => None,
+            };
+            return Some(Job {
+                name: timer.topic,
+                generation: timer.generation,
+                kind: timer.kind,
+                topic: registered.topic.clone(),
+                notified_id: registered.notified_id,
+                callbacks: registered.callbacks.clone(),
+                task,
+            });
+        }
+    }
+
+    /// Frees the topic and schedules the next run of the task.
+    /// # Arguments
+    /// `job` - The job that finished.
+    /// `notified_id` - The last committed id the callbacks were called with.
+    /// `now` - The current time.
+    fn finish(&mut self, job: Job, notified_id: u64, now: u64) {
+        let tick = self.tick;
+        let interval = match self.topics.get_mut(&job.name) {
+            Some(registered) if registered.generation == job.generation => {
+                registered.busy = false;
+                registered.notified_id = notified_id;
+                match job.kind {
+                    TaskKind::Commit => tick,
+                    TaskKind::Flush => match registered.topic.config().flush_policy {
+                        FlushPolicy::Interval(interval) => interval,
+                        _ => return,
+                    },
+                    TaskKind::Periodic(index) => registered.periodic[index].0,
+                }
+            }
+            _ => return,
+        };
+        self.add_timer(now + nanos(interval), &job.name, job.generation, job.kind);
+    }
+
+    /// When the next timer is due.
+    fn next_due(&self) -> Option<u64> {
+        self.timers.peek().map(|t| t.0.due)
+    }
+}
+
+/// The state shared with the workers.
+struct Shared {
+    config: RuntimeConfig,
+    scheduler: Mutex<Scheduler>,
+    wake: Condvar,
+    stopped: AtomicBool,
+    /// When the task running on each worker started.
+    running_since: Vec<Mutex<Option<u64>>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Runs the flushes, commit notifications and periodic tasks for the topics registered with it.
+/// The workers are stopped when the runtime is dropped.
+pub struct StreamRuntime {
+    shared: Arc<Shared>,
+    commands: MpscQueueWrap<Command>,
+    workers: Vec<JoinHandle<()>>,
+    /// Done once a worker has stopped.
+    running: WaitGroup,
+}
+
+impl StreamRuntime {
+    /// Starts the workers.
+    /// # Arguments
+    /// `config` - The settings for the runtime.
+    pub fn start(config: RuntimeConfig) -> Self {
+        StreamRuntime::start_with_clock(config, system_clock())
+    }
+
+    /// Starts the workers using a clock to decide when the tasks are due.
+    /// # Arguments
+    /// `config` - The settings for the runtime.
+    /// `clock` - The clock to get the time from.
+    pub fn start_with_clock(config: RuntimeConfig, clock: Arc<dyn Clock>) -> Self {
+        let (commands, receive) = MpscQueueWrap::new(COMMAND_QUEUE_SIZE);
+        let workers = config.workers.max(1);
+        let tick = config.tick;
+        let worker_thread = ThreadConfig::or_named(config.worker_thread.clone(), "stream-runtime");
+        let shared = Arc::new(Shared {
+            config,
+            scheduler: Mutex::new(Scheduler {
+                commands: receive,
+                topics: HashMap::new(),
+                timers: BinaryHeap::new(),
+                next_sequence: 0,
+                next_generation: 0,
+                tick,
+            }),
+            wake: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            running_since: (0..workers).map(|_| Mutex::new(None)).collect(),
+            clock,
+        });
+        let ready = Latch::new(workers);
+        let running = WaitGroup::new();
+        running.add(workers);
+        let workers = (0..workers)
+            .map(|worker| {
+                let shared = shared.clone();
+                let ready = ready.clone();
+                let running = running.clone();
+                let thread = ThreadConfig {
+                    name: format!("{}-{}", worker_thread.name, worker),
+                    pin_to_core: worker_thread.pin_to_core.map(|core| core + worker),
+                    priority: worker_thread.priority,
+                };
+                spawn_configured(thread, move || {
+                    ready.count_down();
+                    run_worker(shared, worker);
+                    running.done();
+                })
+                .unwrap()
+            })
+            .collect();
+        if let Err(e) = ready.wait() {
+            log::error!("The runtime workers didn't start: {}", e);
+        }
+        StreamRuntime {
+            shared,
+            commands,
+            workers,
+            running,
+        }
+    }
+
+    /// Registers a topic with the runtime.  Adding a topic that is already registered does nothing.
+    /// # Arguments
+    /// `topic` - The topic to add.
+    pub fn add_topic(&self, topic: &TopicHandle) {
+        self.submit(Command::AddTopic(topic.clone()));
+    }
+
+    /// Removes a topic from the runtime.  A task already running on the topic is allowed to finish.
+    /// # Arguments
+    /// `name` - The name of the topic to remove.
+    pub fn remove_topic(&self, name: &str) {
+        self.submit(Command::RemoveTopic(name.to_owned()));
+    }
+
+    /// Calls the callback when new messages are committed to a topic.
+    /// # Arguments
+    /// `name` - The name of the topic to watch.
+    /// `callback` - Called with the topic and the id of the last committed message.
+    pub fn on_commit<F>(&self, name: &str, callback: F)
+    where
+        F: Fn(&TopicHandle, u64) + Send + Sync + 'static,
+    {
+        self.submit(Command::OnCommit(name.to_owned(), Arc::new(callback)));
+    }
+
+    /// Runs a task on a topic periodically.  Used for the retention checks.
+    /// # Arguments
+    /// `name` - The name of the topic.
+    /// `interval` - How long to wait between runs.
+    /// `task` - The task to run.
+    pub fn schedule<F>(&self, name: &str, interval: Duration, task: F)
+    where
+        F: Fn(&TopicHandle) + Send + Sync + 'static,
+    {
+        self.submit(Command::Schedule(name.to_owned(), interval, Arc::new(task)));
+    }
+
+    /// The number of workers running a task past the deadline.
+    pub fn blocked_workers(&self) -> usize {
+        let deadline = nanos(self.shared.config.task_deadline);
+        let now = self.shared.clock.monotonic_ns();
+        self.shared
+            .running_since
+            .iter()
+            .filter(|r| r.lock().unwrap().is_some_and(|s| now.saturating_sub(s) > deadline))
+            .count()
+    }
+
+    fn submit(&self, command: Command) {
+        while !self.commands.offer(command.clone()) {
+            thread::yield_now();
+        }
+        self.shared.wake.notify_one();
+    }
+}
+
+impl Drop for StreamRuntime {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Release);
+        self.shared.wake.notify_all();
+        match self.running.wait_timeout(self.shared.config.shutdown_timeout) {
+            // A worker that panicked has already stopped.
+            Ok(()) | Err(LatchError::Abandoned) => {
+                for worker in self.workers.drain(..) {
+                    let _ = worker.join();
+                }
+            }
+            Err(LatchError::TimedOut) => {
+                log::warn!(
+                    "{} runtime workers didn't stop in {:?}",
+                    self.running.count(),
+                    self.shared.config.shutdown_timeout
+                );
+            }
+        }
+    }
+}
+
+/// Converts a duration into nanoseconds for the monotonic clock.
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos() as u64
+}
+
+/// Runs the tasks until the runtime is stopped.
+/// # Arguments
+/// `shared` - The state shared with the workers.
+/// `worker` - The index of the worker.
+fn run_worker(shared: Arc<Shared>, worker: usize) {
+    let tick = shared.config.tick;
+    let mut scheduler = shared.scheduler.lock().unwrap();
+    while !shared.stopped.load(Ordering::Acquire) {
+        let now = shared.clock.monotonic_ns();
+        scheduler.apply_commands(now);
+        match scheduler.next_job(now) {
+            Some(job) => {
+                drop(scheduler);
+                *shared.running_since[worker].lock().unwrap() = Some(shared.clock.monotonic_ns());
+                let notified_id = job.run();
+                let started = shared.running_since[worker].lock().unwrap().take();
+                if let Some(started) = started {
+                    let elapsed =
+                        Duration::from_nanos(shared.clock.monotonic_ns().saturating_sub(started));
+                    if elapsed > shared.config.task_deadline {
+                        log::warn!(
+                            "The task {:?} on topic {} took {:?}",
+                            job.kind,
+                            job.name,
+                            elapsed
+                        );
+                    }
+                }
+                scheduler = shared.scheduler.lock().unwrap();
+                scheduler.finish(job, notified_id, shared.clock.monotonic_ns());
+            }
+            None => {
+                let wait = scheduler
+                    .next_due()
+                    .map_or(tick, |due| Duration::from_nanos(due.saturating_sub(now)).min(tick));
+                scheduler = shared.wake.wait_timeout(scheduler, wait).unwrap().0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::message_stream::runtime::*;
+    use crate::message_stream::{TopicConfig, TopicManager};
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use a19_core::clock::ManualClock;
+    use std::sync::atomic::AtomicU64;
+    use std::time::Instant;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_runtime";
+    const CLOCK_TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_runtime_clock";
+    const SHUTDOWN_TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_runtime_shutdown";
+
+    fn policy(i: usize) -> FlushPolicy {
+        match i % 4 {
+            0 => FlushPolicy::EveryCommit,
+            1 => FlushPolicy::EveryNMessages(5),
+            2 => FlushPolicy::Interval(Duration::from_millis(20)),
+            _ => FlushPolicy::Os,
+        }
+    }
+
+    #[test]
+    fn runtime_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let manager = TopicManager::open(TEST_DIR).unwrap();
+        let runtime = StreamRuntime::start(RuntimeConfig {
+            workers: 2,
+            tick: Duration::from_millis(1),
+            task_deadline: Duration::from_millis(100),
+            worker_thread: Some(ThreadConfig::new("runtime-test")),
+            shutdown_timeout: Duration::from_secs(5),
+        });
+        let mut topics = Vec::new();
+        let mut notified = Vec::new();
+        for i in 0..20 {
+            let topic = manager
+                .create_topic(
+                    &format!("topic_{}", i),
+                    TopicConfig {
+                        flush_policy: policy(i),
+                        ..TopicConfig::default()
+                    },
+                )
+                .unwrap();
+            runtime.add_topic(&topic);
+            let seen = Arc::new(AtomicU64::new(0));
+            let callback_seen = seen.clone();
+            runtime.on_commit(topic.name(), move |_, id| {
+                callback_seen.fetch_max(id, Ordering::AcqRel);
+            });
+            topics.push(topic);
+            notified.push(seen);
+        }
+        // Blocks a worker for most of the test.
+        let slow_runs = Arc::new(AtomicU64::new(0));
+        let runs = slow_runs.clone();
+        let worker_name = Arc::new(Mutex::new(None));
+        let name = worker_name.clone();
+        runtime.schedule("topic_0", Duration::from_millis(1), move |_| {
+            runs.fetch_add(1, Ordering::AcqRel);
+            *name.lock().unwrap() = thread::current().name().map(|n| n.to_owned());
+            thread::sleep(Duration::from_millis(300));
+        });
+
+        let appenders: Vec<_> = topics
+            .iter()
+            .cloned()
+            .map(|topic| {
+                thread::spawn(move || {
+                    for i in 0..50u64 {
+                        block_on(topic.append(1, &i.to_le_bytes())).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for appender in appenders {
+            appender.join().unwrap();
+        }
+
+        let started = Instant::now();
+        loop {
+            let done = topics.iter().enumerate().all(|(i, topic)| {
+                let committed = topic.committed_message_id();
+                let flushed = topic.stats().flushed_message_id;
+                let flushed = match policy(i) {
+                    FlushPolicy::EveryNMessages(n) => committed - flushed < n as u64,
+                    FlushPolicy::Os => flushed == 0,
+                    _ => committed == flushed,
+                };
+                flushed && notified[i].load(Ordering::Acquire) == committed
+            });
+            if done {
+                break;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "The topics didn't flush in time."
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(slow_runs.load(Ordering::Acquire) > 0);
+
+        // Nothing runs on a removed topic.
+        runtime.remove_topic("topic_1");
+        thread::sleep(Duration::from_millis(20));
+        let before = notified[1].load(Ordering::Acquire);
+        block_on(topics[1].append(1, &[1])).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(before, notified[1].load(Ordering::Acquire));
+
+        // The tasks run on the named workers.
+        let name = worker_name.lock().unwrap().clone().unwrap();
+        assert!(name.starts_with("runtime-test-"), "Ran on {}.", name);
+
+        runtime.remove_topic("topic_0");
+        let started = Instant::now();
+        while runtime.blocked_workers() > 0 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn interval_clock_test() {
+        let path = Path::new(CLOCK_TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(CLOCK_TEST_DIR).unwrap();
+        }
+        let clock = Arc::new(ManualClock::new(1_000));
+        let manager = TopicManager::open_with_clock(CLOCK_TEST_DIR, clock.clone()).unwrap();
+        let runtime = StreamRuntime::start_with_clock(RuntimeConfig::default(), clock.clone());
+        let topic = manager
+            .create_topic(
+                "interval",
+                TopicConfig {
+                    flush_policy: FlushPolicy::Interval(Duration::from_secs(1)),
+                    ..TopicConfig::default()
+                },
+            )
+            .unwrap();
+        runtime.add_topic(&topic);
+        for i in 0..3u64 {
+            block_on(topic.append(1, &i.to_le_bytes())).unwrap();
+        }
+        // The flush isn't due until the clock moves.
+        thread::sleep(Duration::from_millis(50));
+        clock.advance(Duration::from_millis(999));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(0, topic.stats().flushed_message_id);
+
+        clock.advance(Duration::from_millis(1));
+        let started = Instant::now();
+        while topic.stats().flushed_message_id < topic.committed_message_id() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "The topic didn't flush."
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(3, topic.stats().flushed_message_id);
+    }
+
+    #[test]
+    fn shutdown_test() {
+        let path = Path::new(SHUTDOWN_TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(SHUTDOWN_TEST_DIR).unwrap();
+        }
+        let manager = TopicManager::open(SHUTDOWN_TEST_DIR).unwrap();
+        let topic = manager
+            .create_topic("shutdown", TopicConfig::default())
+            .unwrap();
+
+        // The workers are running once started and stop when dropped.
+        let runtime = StreamRuntime::start(RuntimeConfig::default());
+        assert_eq!(2, runtime.running.count());
+        let running = runtime.running.clone();
+        drop(runtime);
+        assert_eq!(0, running.count());
+
+        // A worker stuck in a task doesn't hold up the drop past the timeout.
+        let runtime = StreamRuntime::start(RuntimeConfig {
+            shutdown_timeout: Duration::from_millis(200),
+            ..RuntimeConfig::default()
+        });
+        runtime.add_topic(&topic);
+        let started = Arc::new(AtomicU64::new(0));
+        let task_started = started.clone();
+        let release = Latch::new(1);
+        let task_release = release.clone();
+        runtime.schedule("shutdown", Duration::from_millis(1), move |_| {
+            task_started.fetch_add(1, Ordering::AcqRel);
+            task_release.wait().unwrap();
+        });
+        while started.load(Ordering::Acquire) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The task is held until after the drop so the drop can only return by timing out.
+        let running = runtime.running.clone();
+        drop(runtime);
+        assert_eq!(1, running.count());
+        release.count_down();
+        assert_eq!(Ok(()), running.wait_timeout(Duration::from_secs(5)));
+        assert_eq!(0, running.count());
+    }
+}
@@ -0,0 +1,394 @@
+//! Lets a stream of messages be forwarded into a topic or a store.  The sink waits for the backlog
+//! to drop below the watermarks before taking a message and a flush waits until everything sent
+//! to it has been committed.
+//!```text
+//! stream --> poll_ready (watermarks) --> start_send (append) --> [ pending commits ]
+//!                                                                        |
+//!                               poll_flush/poll_close <-- committed ------+
+//!```
+//! If nothing is committed for the commit timeout the flush fails with the backlog at the time so
+//! a stalled disk doesn't hang the pipeline forever.
+use crate::error::{Context as ErrorContext, PersistError};
+use crate::message_stream::TopicHandle;
+use crate::raft::backlog::BacklogStatus;
+use crate::raft::{PersistedMessageFile, QueueFuture};
+use futures::future::BoxFuture;
+use futures::{FutureExt, Sink};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{delay_for, Delay};
+
+/// How long a flush waits without anything being committed before it gives up.
+const DEFAULT_COMMIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a sink appends the messages.
+pub trait SinkTarget {
+    /// Checks if the backlog is below the watermarks.  Wakes up the task once it is if not.
+    /// # Arguments
+    /// `cx` - The context of the task to wake up.
+    fn poll_capacity(&self, cx: &mut Context<'_>) -> Poll<()>;
+
+    /// Waits for the rate limit of the target before appending a message.
+    /// # Arguments
+    /// `body` - The body of the message.
+    /// # Returns
+    /// The future to wait on or `None` if there isn't a limit.
+    fn rate_limit(&self, _body: &[u8]) -> Option<BoxFuture<'static, ()>> {
+        None
+    }
+
+    /// Queues a message to be written.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `body` - The body of the message.
+    /// # Returns
+    /// The future that completes with the id of the message once it has been committed.
+    fn queue(&self, msg_type: i32, body: &[u8]) -> QueueFuture<crate::Result<u64>>;
+
+    /// The appends that haven't been committed yet.
+    fn backlog(&self) -> BacklogStatus;
+}
+
+impl SinkTarget for Arc<PersistedMessageFile> {
+    fn poll_capacity(&self, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.capacity()).poll(cx)
+    }
+
+    fn queue(&self, msg_type: i32, body: &[u8]) -> QueueFuture<crate::Result<u64>> {
+        self.write(msg_type, body)
+    }
+
+    fn backlog(&self) -> BacklogStatus {
+        PersistedMessageFile::backlog(self)
+    }
+}
+
+impl SinkTarget for TopicHandle {
+    fn poll_capacity(&self, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.topic.file.capacity()).poll(cx)
+    }
+
+    fn rate_limit(&self, body: &[u8]) -> Option<BoxFuture<'static, ()>> {
+        self.limiter.as_ref().map(|(limiter, unit)| {
+            let limiter = limiter.clone();
+            let tokens = unit.tokens(body);
+            async move { limiter.acquire_async(tokens).await }.boxed()
+        })
+    }
+
+    fn queue(&self, msg_type: i32, body: &[u8]) -> QueueFuture<crate::Result<u64>> {
+        self.topic.record_append(msg_type);
+        self.topic.file.write(msg_type, body)
+    }
+
+    fn backlog(&self) -> BacklogStatus {
+        TopicHandle::backlog(self)
+    }
+}
+
+/// A message waiting on the rate limiter before it is appended.
+struct Limited {
+    wait: BoxFuture<'static, ()>,
+    msg_type: i32,
+    body: Vec<u8>,
+}
+
+/// Appends the `(type, body)` messages sent to it.  Needs to be used on a tokio runtime since the
+/// commit timeout uses the tokio timer.
+pub struct MessageSink<T: SinkTarget> {
+    target: T,
+    /// The message waiting on the rate limiter.
+    limited: Option<Limited>,
+    /// The appends that haven't been committed in the order they were sent.
+    pending: VecDeque<QueueFuture<crate::Result<u64>>>,
+    /// The id of the last message sent that has been committed.
+    committed_message_id: u64,
+    commit_timeout: Duration,
+    /// Started once a flush is waiting and restarted every time a message is committed.
+    timer: Option<Delay>,
+    closed: bool,
+}
+
+impl<T: SinkTarget> MessageSink<T> {
+    /// Creates a sink for a topic or a store.
+    /// # Arguments
+    /// `target` - Where to append the messages.
+    pub fn new(target: T) -> Self {
+        MessageSink {
+            target,
+            limited: None,
+            pending: VecDeque::new(),
+            committed_message_id: 0,
+            commit_timeout: DEFAULT_COMMIT_TIMEOUT,
+            timer: None,
+            closed: false,
+        }
+    }
+
+    /// Sets how long a flush waits without anything being committed before it fails.
+    /// # Arguments
+    /// `timeout` - The time to wait.
+    pub fn with_commit_timeout(mut self, timeout: Duration) -> Self {
+        self.commit_timeout = timeout;
+        self
+    }
+
+    /// Where the messages are appended.
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
+    /// The id of the last message sent that has been committed.
+    pub fn committed_message_id(&self) -> u64 {
+        self.committed_message_id
+    }
+
+    /// The number of messages sent that haven't been committed.
+    pub fn pending(&self) -> usize {
+        self.pending.len() + self.limited.is_some() as usize
+    }
+
+    /// Appends the message waiting on the rate limiter once it has its tokens.
+    fn poll_limited(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(limited) = &mut self.limited {
+            if limited.wait.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let receiver = self.target.queue(limited.msg_type, &limited.body);
+            self.pending.push_back(receiver);
+            self.limited = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Waits for all of the messages sent to be committed.
+    fn poll_committed(&mut self, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        if self.poll_limited(cx).is_pending() {
+            return Poll::Pending;
+        }
+        while let Some(receiver) = self.pending.front_mut() {
+            match Pin::new(receiver).poll(cx) {
+                Poll::Ready(result) => {
+                    self.pending.pop_front();
+                    self.timer = None;
+                    match result {
+                        Ok(Ok(id)) => self.committed_message_id = id,
+                        Ok(Err(e)) => return Poll::Ready(Err(e)),
+                        Err(e) => return Poll::Ready(Err(e.into())),
+                    }
+                }
+                Poll::Pending => {
+                    let timeout = self.commit_timeout;
+                    let timer = self.timer.get_or_insert_with(|| delay_for(timeout));
+                    return match Pin::new(timer).poll(cx) {
+                        Poll::Ready(()) => {
+                            self.timer = None;
+                            Poll::Ready(Err(PersistError::CommitTimedOut {
+                                context: ErrorContext {
+                                    id: Some(self.committed_message_id),
+                                    ..ErrorContext::default()
+                                },
+                                lag: self.target.backlog(),
+                            }))
+                        }
+                        Poll::Pending => Poll::Pending,
+                    };
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: SinkTarget + Unpin> Sink<(i32, Vec<u8>)> for MessageSink<T> {
+    type Error = PersistError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(Err(PersistError::closed()));
+        }
+        if this.poll_limited(cx).is_pending() {
+            return Poll::Pending;
+        }
+        this.target.poll_capacity(cx).map(Ok)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (i32, Vec<u8>)) -> crate::Result<()> {
+        let this = self.get_mut();
+        if this.closed {
+            return Err(PersistError::closed());
+        }
+        let (msg_type, body) = item;
+        match this.target.rate_limit(&body) {
+            Some(wait) => {
+                this.limited = Some(Limited {
+                    wait,
+                    msg_type,
+                    body,
+                })
+            }
+            None => {
+                let receiver = this.target.queue(msg_type, &body);
+                this.pending.push_back(receiver);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        self.get_mut().poll_committed(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        let this = self.get_mut();
+        this.closed = true;
+        this.poll_committed(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::MessageRead;
+    use crate::message_stream::sink::*;
+    use crate::message_stream::{TopicConfig, TopicManager};
+    use crate::raft::backlog::Watermarks;
+    use crate::raft::{startup_single_node_with_wait, CommittedCursor, InfallibleProcessor};
+    use a19_concurrent::wait::WaitStrategy;
+    use a19_core::clock::system_clock;
+    use futures::{future, stream, SinkExt, StreamExt};
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_sink";
+
+    fn clean(dir: &str) {
+        let path = Path::new(dir);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(dir).unwrap();
+        }
+    }
+
+    struct NoOp;
+
+    impl InfallibleProcessor for NoOp {
+        fn handle<'a>(&mut self, _read: &MessageRead<'a>) {}
+    }
+
+    /// Holds up the commit thread while stalled.
+    struct Stall {
+        stalled: AtomicBool,
+    }
+
+    impl WaitStrategy for Stall {
+        fn wait(&self, _attempt: u32) {
+            while self.stalled.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            thread::sleep(Duration::from_micros(100));
+        }
+    }
+
+    /// Sends a message without waiting for it to be committed.  The futures version we are on doesn't
+    /// have `SinkExt::feed` yet.
+    async fn feed<T: SinkTarget + Unpin>(
+        sink: &mut MessageSink<T>,
+        item: (i32, Vec<u8>),
+    ) -> crate::Result<()> {
+        future::poll_fn(|cx| Pin::new(&mut *sink).poll_ready(cx)).await?;
+        Pin::new(sink).start_send(item)
+    }
+
+    #[tokio::test]
+    async fn forward_test() {
+        let dir = format!("{}_forward", TEST_DIR);
+        clean(&dir);
+        let manager = TopicManager::open(&dir).unwrap();
+        let config = TopicConfig {
+            watermarks: Some(Watermarks {
+                high: 0x800,
+                low: 0x400,
+            }),
+            ..TopicConfig::default()
+        };
+        let topic = manager.create_topic("orders", config).unwrap();
+        let mut sink = MessageSink::new(topic.clone());
+        let messages = stream::iter(0..500u32).map(|i| Ok((1, i.to_be_bytes().to_vec())));
+        messages.forward(&mut sink).await.unwrap();
+        // Forward closes the sink once everything is committed.
+        assert_eq!(0, sink.pending());
+        assert_eq!(500, sink.committed_message_id());
+        assert!(topic.committed_message_id() >= 500);
+        assert!(sink.send((1, vec![1])).await.is_err());
+
+        let mut stream = topic.subscribe(1);
+        let mut next = 0u32;
+        while let Some(msg) = stream.next_message() {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&msg.body);
+            assert_eq!(next, u32::from_be_bytes(bytes));
+            next += 1;
+        }
+        assert_eq!(500, next);
+    }
+
+    #[tokio::test]
+    async fn flush_stall_test() {
+        let dir = format!("{}_stall", TEST_DIR);
+        clean(&dir);
+        let stall = Arc::new(Stall {
+            stalled: AtomicBool::new(false),
+        });
+        let file = Arc::new(startup_single_node_with_wait(
+            dir,
+            "sink".to_owned(),
+            0x10000,
+            0x10000,
+            NoOp,
+            0x4000,
+            0x400,
+            system_clock(),
+            stall.clone(),
+        ));
+        let mut sink =
+            MessageSink::new(file.clone()).with_commit_timeout(Duration::from_millis(50));
+        for i in 0..10u8 {
+            feed(&mut sink, (1, vec![i; 10])).await.unwrap();
+        }
+        sink.flush().await.unwrap();
+        // Everything sent is committed once the flush completes.
+        assert_eq!(10, sink.committed_message_id());
+        assert!(file.max_message_id() >= 10);
+        assert_eq!(0, sink.pending());
+
+        stall.stalled.store(true, Ordering::Release);
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        for i in 0..5u8 {
+            feed(&mut sink, (1, vec![i; 10])).await.unwrap();
+        }
+        match sink.close().await {
+            Err(PersistError::CommitTimedOut { lag, context }) => {
+                assert_eq!(5, lag.pending);
+                assert_eq!(Some(10), context.id);
+            }
+            r => panic!("Expected the commit to time out: {:?}", r),
+        }
+        stall.stalled.store(false, Ordering::Release);
+        // Finishes once the commits catch up.
+        sink.close().await.unwrap();
+        assert_eq!(15, sink.committed_message_id());
+        let mut cursor = CommittedCursor::new(1);
+        let mut count = 0;
+        while cursor.next(&file).is_some() {
+            count += 1;
+        }
+        assert_eq!(15, count);
+    }
+}
@@ -0,0 +1,213 @@
+//! Transactions let a group of messages be appended to a topic so they become visible at the same
+//! time.  The messages are buffered until the commit and then written together so they end up in
+//! the same term.
+use crate::message_stream::TopicHandle;
+use crate::raft::TransactionBatch;
+use futures::future::Future;
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+
+/// A transaction on a topic.  Nothing is written until the transaction is committed.
+pub struct Txn {
+    topic: TopicHandle,
+    batch: TransactionBatch,
+    /// The number of messages with a type that isn't in the registry.
+    unregistered: u64,
+}
+
+impl Txn {
+    /// Creates a transaction for a topic.
+    /// # Arguments
+    /// `topic` - The topic to write the messages to.
+    pub(crate) fn new(topic: TopicHandle) -> Self {
+        Txn {
+            topic,
+            batch: TransactionBatch::new(),
+            unregistered: 0,
+        }
+    }
+
+    /// Adds a message to the transaction.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `body` - The body of the message.
+    pub fn add(&mut self, msg_type: i32, body: &[u8]) {
+        if !self.topic.types().is_registered(msg_type) {
+            self.unregistered += 1;
+        }
+        self.batch.add(msg_type, body);
+    }
+
+    /// The number of messages in the transaction.
+    pub fn len(&self) -> u32 {
+        self.batch.len()
+    }
+
+    /// True if no messages have been added.
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    /// Commits the transaction.  Waits for the backlog of the topic to drain first if it is over
+    /// the watermarks.
+    /// # Returns
+    /// The future that completes with the ids of the messages once they have all been committed.
+    pub fn commit(self) -> impl Future<Output = crate::Result<Range<u64>>> {
+        let count = self.batch.len() as u64;
+        let topic = self.topic.topic.clone();
+        let batch = self.batch;
+        let unregistered = self.unregistered;
+        async move {
+            if count > 0 {
+                topic.file.capacity().await;
+                topic.appended.fetch_add(count, Ordering::Relaxed);
+                topic
+                    .unregistered_appends
+                    .fetch_add(unregistered, Ordering::Relaxed);
+                let last_id = topic
+                    .file
+                    .write_transaction(&batch)
+                    .await??;
+                Ok((last_id + 1 - count)..(last_id + 1))
+            } else {
+                let next_id = topic.file.max_message_id() + 1;
+                Ok(next_id..next_id)
+            }
+        }
+    }
+
+    /// Throws away the messages in the transaction.
+    pub fn abort(self) {}
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::message_stream::transaction::*;
+    use crate::message_stream::{CommittedMessage, TopicConfig, TopicManager};
+    use byteorder::{BigEndian, ByteOrder};
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_txn";
+
+    fn setup(name: &str) -> String {
+        let directory = format!("{}_{}", TEST_DIR, name);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        directory
+    }
+
+    /// Checks that all of the transactions are whole.  The body is the writer and the transaction
+    /// number followed by the position in the transaction.
+    fn check_whole(messages: &[CommittedMessage], size: u32) {
+        assert_eq!(0, messages.len() as u32 % size);
+        for txn in messages.chunks(size as usize) {
+            let first = &txn[0].body;
+            for (i, msg) in txn.iter().enumerate() {
+                assert_eq!(&first[0..8], &msg.body[0..8]);
+                assert_eq!(i as u32, BigEndian::read_u32(&msg.body[8..12]));
+                assert_eq!(txn[0].message_id + i as u64, msg.message_id);
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_test() {
+        let directory = setup("concurrent");
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager
+            .create_topic("accounts", TopicConfig::default())
+            .unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let topic = topic.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut stream = topic.subscribe(1);
+                let mut messages = Vec::new();
+                loop {
+                    let finished = done.load(Ordering::Acquire);
+                    while let Some(msg) = stream.next_message() {
+                        messages.push(msg);
+                    }
+                    // Caught up so we must be on a transaction boundary.
+                    check_whole(&messages, 3);
+                    if finished {
+                        break messages.len();
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        let writers: Vec<_> = (0..2u32)
+            .map(|writer| {
+                let topic = topic.clone();
+                thread::spawn(move || {
+                    (0..50u32)
+                        .map(|t| {
+                            let mut txn = topic.begin_transaction();
+                            for i in 0..3u32 {
+                                let mut body = [0; 12];
+                                BigEndian::write_u32(&mut body[0..4], writer);
+                                BigEndian::write_u32(&mut body[4..8], t);
+                                BigEndian::write_u32(&mut body[8..12], i);
+                                txn.add(1, &body);
+                            }
+                            block_on(txn.commit()).unwrap()
+                        })
+                        .collect::<Vec<Range<u64>>>()
+                })
+            })
+            .collect();
+        for w in writers {
+            for range in w.join().unwrap() {
+                assert_eq!(3, range.end - range.start);
+            }
+        }
+        done.store(true, Ordering::Release);
+        assert_eq!(300, reader.join().unwrap());
+        assert_eq!(300, topic.stats().appended);
+    }
+
+    #[test]
+    fn transaction_abort_test() {
+        let directory = setup("abort");
+        {
+            let manager = TopicManager::open(&directory).unwrap();
+            let topic = manager
+                .create_topic("accounts", TopicConfig::default())
+                .unwrap();
+            block_on(topic.append(1, &[1])).unwrap();
+            let mut aborted = topic.begin_transaction();
+            aborted.add(1, &[2]);
+            aborted.abort();
+            let mut never_committed = topic.begin_transaction();
+            never_committed.add(1, &[3]);
+            never_committed.add(1, &[4]);
+            // Stopped before the commit.
+        }
+        let manager = TopicManager::open(&directory).unwrap();
+        let topic = manager.topic("accounts").unwrap();
+        let mut stream = topic.subscribe(1);
+        let mut first = None;
+        for _ in 0..500 {
+            first = stream.next_message();
+            if first.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(vec![1], first.unwrap().body);
+        assert!(stream.next_message().is_none());
+        let empty = block_on(topic.begin_transaction().commit()).unwrap();
+        assert!(empty.is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+//! Maps the message type ids to names so we know what the messages are when replaying a topic.
+//! The registry is stored in a sidecar file next to the topic with one type per line.
+//!
+//! ```text
+//! id<TAB>name<TAB>schema hint in hex
+//! ```
+use crate::error::{PersistError, ResultExt};
+use std::collections::HashMap;
+use std::fs::{read_to_string, rename, File};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A type that has been registered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeInfo {
+    pub id: u16,
+    pub name: String,
+    pub schema_hint: Option<Vec<u8>>,
+}
+
+struct RegistryState {
+    ids: HashMap<String, u16>,
+    types: HashMap<u16, TypeInfo>,
+    next_id: u16,
+}
+
+/// The registry of the message types for a topic.
+pub struct TypeRegistry {
+    path: PathBuf,
+    state: Mutex<RegistryState>,
+}
+
+impl TypeRegistry {
+    /// Opens the registry and loads in the types that have been registered.
+    /// # Arguments
+    /// `path` - The path to the registry file.
+    pub fn open<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = RegistryState {
+            ids: HashMap::new(),
+            types: HashMap::new(),
+            next_id: 1,
+        };
+        if path.exists() {
+            let contents = read_to_string(&path).with_path(path.to_string_lossy())?;
+            for line in contents.lines().filter(|l| !l.is_empty()) {
+                let info = parse_line(line).ok_or_else(|| {
+                    PersistError::invalid_config(format!("Invalid type in the registry: {}", line))
+                        .with_path(path.to_string_lossy())
+                })?;
+                if info.id >= state.next_id {
+                    state.next_id = info.id + 1;
+                }
+                state.ids.insert(info.name.clone(), info.id);
+                state.types.insert(info.id, info);
+            }
+        }
+        Ok(TypeRegistry {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Registers a message type.  If the name is already registered the existing id is returned.
+    /// # Arguments
+    /// `name` - The name of the type.  Can't contain tabs or new lines.
+    /// `schema_hint` - Something to describe the layout of the message.
+    /// # returns
+    /// The id of the type.
+    pub fn register(&self, name: &str, schema_hint: Option<&[u8]>) -> crate::Result<u16> {
+        if name.is_empty() || name.contains(['\t', '\n', '\r']) {
+            return Err(PersistError::invalid_config(format!(
+                "Invalid type name: {}",
+                name
+            )));
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(id) = state.ids.get(name) {
+            return Ok(*id);
+        }
+        if state.next_id == u16::MAX {
+            return Err(PersistError::invalid_config("Out of message type ids."));
+        }
+        let info = TypeInfo {
+            id: state.next_id,
+            name: name.to_owned(),
+            schema_hint: schema_hint.map(|s| s.to_vec()),
+        };
+        let mut contents = String::new();
+        let mut ids: Vec<&u16> = state.types.keys().collect();
+        ids.sort();
+        for id in ids {
+            contents.push_str(&format_line(state.types.get(id).unwrap()));
+        }
+        contents.push_str(&format_line(&info));
+        self.save(&contents).with_path(self.path.to_string_lossy())?;
+        let id = info.id;
+        state.next_id += 1;
+        state.ids.insert(info.name.clone(), id);
+        state.types.insert(id, info);
+        Ok(id)
+    }
+
+    /// Gets the name of a type.
+    /// # Arguments
+    /// `id` - The id of the type.
+    pub fn name_of(&self, id: u16) -> Option<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .types
+            .get(&id)
+            .map(|t| t.name.clone())
+    }
+
+    /// Gets the id of a type.
+    /// # Arguments
+    /// `name` - The name of the type.
+    pub fn id_of(&self, name: &str) -> Option<u16> {
+        self.state.lock().unwrap().ids.get(name).cloned()
+    }
+
+    /// Gets the information about a type.
+    /// # Arguments
+    /// `id` - The id of the type.
+    pub fn type_info(&self, id: u16) -> Option<TypeInfo> {
+        self.state.lock().unwrap().types.get(&id).cloned()
+    }
+
+    /// Checks to see if a message type has been registered.
+    /// # Arguments
+    /// `msg_type` - The message type to check.
+    pub fn is_registered(&self, msg_type: i32) -> bool {
+        msg_type > 0
+            && msg_type <= u16::MAX as i32
+            && self
+                .state
+                .lock()
+                .unwrap()
+                .types
+                .contains_key(&(msg_type as u16))
+    }
+
+    /// Writes the registry to a temporary file and renames it.
+    fn save(&self, contents: &str) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("types.tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+        }
+        rename(&tmp_path, &self.path)
+    }
+}
+
+fn format_line(info: &TypeInfo) -> String {
+    let schema: String = match &info.schema_hint {
+        Some(schema) => schema.iter().map(|b| format!("{:02x}", b)).collect(),
+        None => String::new(),
+    };
+    format!("{}\t{}\t{}\n", info.id, info.name, schema)
+}
+
+fn parse_line(line: &str) -> Option<TypeInfo> {
+    let columns: Vec<&str> = line.split('\t').collect();
+    if columns.len() != 3 || columns[2].len() & 1 == 1 {
+        return None;
+    }
+    let schema_hint = if columns[2].is_empty() {
+        None
+    } else {
+        let mut bytes = Vec::with_capacity(columns[2].len() / 2);
+        for i in (0..columns[2].len()).step_by(2) {
+            bytes.push(u8::from_str_radix(columns[2].get(i..i + 2)?, 16).ok()?);
+        }
+        Some(bytes)
+    };
+    Some(TypeInfo {
+        id: columns[0].parse().ok()?,
+        name: columns[1].to_owned(),
+        schema_hint,
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::message_stream::type_registry::*;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::sync::Arc;
+    use std::thread;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_types";
+
+    fn setup(name: &str) -> String {
+        let directory = format!("{}_{}", TEST_DIR, name);
+        let path = Path::new(&directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&directory).unwrap();
+        }
+        create_dir_all(&directory).unwrap();
+        format!("{}/test.types", directory)
+    }
+
+    #[test]
+    fn register_test() {
+        let path = setup("register");
+        {
+            let registry = TypeRegistry::open(&path).unwrap();
+            assert_eq!(1, registry.register("OrderPlaced", Some(&[1, 0xff])).unwrap());
+            assert_eq!(2, registry.register("OrderCanceled", None).unwrap());
+            assert_eq!(1, registry.register("OrderPlaced", None).unwrap());
+            assert!(registry.register("Bad\tName", None).is_err());
+            assert!(registry.is_registered(2));
+            assert!(!registry.is_registered(3));
+        }
+        let registry = TypeRegistry::open(&path).unwrap();
+        assert_eq!(Some("OrderPlaced".to_owned()), registry.name_of(1));
+        assert_eq!(Some(2), registry.id_of("OrderCanceled"));
+        assert_eq!(
+            Some(vec![1, 0xff]),
+            registry.type_info(1).unwrap().schema_hint
+        );
+        assert_eq!(3, registry.register("OrderFilled", None).unwrap());
+    }
+
+    #[test]
+    fn concurrent_register_test() {
+        let path = setup("concurrent");
+        let registry = Arc::new(TypeRegistry::open(&path).unwrap());
+        let names: Vec<String> = (0..20).map(|i| format!("Type{}", i)).collect();
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let registry = registry.clone();
+                let names = names.clone();
+                thread::spawn(move || {
+                    names
+                        .iter()
+                        .map(|n| registry.register(n, None).unwrap())
+                        .collect::<Vec<u16>>()
+                })
+            })
+            .collect();
+        let results: Vec<Vec<u16>> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(results[0], results[1]);
+        let mut ids = results[0].clone();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(20, ids.len());
+    }
+}
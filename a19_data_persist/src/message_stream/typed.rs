@@ -0,0 +1,221 @@
+//! Topics of typed messages.  The values are encoded with their codec when appended and decoded
+//! when read so the callers never touch the bytes.
+use crate::codec;
+use crate::codec::{encode, MessageCodec};
+use crate::file::filter::MessageFilter;
+use crate::message_stream::{CommittedMessageStream, TopicHandle};
+use a19_concurrent::buffer::DirectByteBuffer;
+use std::marker::PhantomData;
+
+/// A value read from a typed topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypedMessage<T> {
+    pub message_id: u64,
+    pub value: T,
+}
+
+/// A handle to a topic holding values of a single type.
+pub struct TypedTopicHandle<T: MessageCodec> {
+    topic: TopicHandle,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: MessageCodec> Clone for TypedTopicHandle<T> {
+    fn clone(&self) -> Self {
+        TypedTopicHandle::new(self.topic.clone())
+    }
+}
+
+impl<T: MessageCodec> TypedTopicHandle<T> {
+    /// Creates the typed handle for a topic.
+    /// # Arguments
+    /// `topic` - The topic to store the values in.
+    pub fn new(topic: TopicHandle) -> Self {
+        TypedTopicHandle {
+            topic,
+            _type: PhantomData,
+        }
+    }
+
+    /// The topic the values are stored in.
+    pub fn topic(&self) -> &TopicHandle {
+        &self.topic
+    }
+
+    /// Appends a value to the topic.
+    /// # Arguments
+    /// `value` - The value to append.
+    /// # Returns
+    /// The id of the message once it has been committed.
+    pub async fn append_typed(&self, value: &T) -> crate::Result<u64> {
+        let (buffer, length) = encode(value);
+        self.topic
+            .append(T::MESSAGE_TYPE, buffer.get_bytes(0, length))
+            .await
+    }
+
+    /// Subscribes to the committed values.  Messages of other types are skipped.
+    /// # Arguments
+    /// `from_id` - The id of the message to start from.
+    pub fn subscribe_typed(&self, from_id: u64) -> TypedMessageStream<T> {
+        let message_type = T::MESSAGE_TYPE;
+        TypedMessageStream {
+            stream: self.topic.subscribe_filtered(
+                from_id,
+                MessageFilter::default().with_predicate(move |h| h.msg_type_id == message_type),
+            ),
+            _type: PhantomData,
+        }
+    }
+}
+
+/// Reads the committed values from a typed topic.
+pub struct TypedMessageStream<T: MessageCodec> {
+    stream: CommittedMessageStream,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: MessageCodec> TypedMessageStream<T> {
+    /// Gets the next committed value.
+    /// # Returns
+    /// None if we have read all of the committed messages.  Can be called again to get the
+    /// messages committed since.
+    pub fn next_value(&mut self) -> Option<codec::Result<TypedMessage<T>>> {
+        self.stream.next_message().map(|msg| {
+            T::decode(&msg.body).map(|value| TypedMessage {
+                message_id: msg.message_id,
+                value,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::codec::check_length;
+    use crate::file::MessageFileStore;
+    use crate::message_stream::export::{export, import, IdPolicy};
+    use crate::message_stream::typed::*;
+    use crate::message_stream::{TopicConfig, TopicManager};
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_typed";
+
+    /// Laid out the same way as the generated types with the fixed fields first.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Trade {
+        trade_id: u64,
+        price: i64,
+        quantity: u32,
+        symbol: String,
+    }
+
+    const TRADE_ID: usize = 0;
+    const PRICE: usize = 8;
+    const QUANTITY: usize = 16;
+    const SYMBOL_LENGTH: usize = 20;
+    const SYMBOL: usize = 22;
+
+    impl MessageCodec for Trade {
+        const MESSAGE_TYPE: i32 = 10;
+
+        fn encoded_size(&self) -> usize {
+            SYMBOL + self.symbol.len()
+        }
+
+        fn encode_into<B: DirectByteBuffer>(&self, buffer: &mut B, pos: usize) -> usize {
+            buffer.put_u64(pos + TRADE_ID, self.trade_id);
+            buffer.put_i64(pos + PRICE, self.price);
+            buffer.put_u32(pos + QUANTITY, self.quantity);
+            buffer.put_u16(pos + SYMBOL_LENGTH, self.symbol.len() as u16);
+            buffer.write_bytes(pos + SYMBOL, self.symbol.as_bytes());
+            self.encoded_size()
+        }
+
+        fn decode(bytes: &[u8]) -> codec::Result<Self> {
+            check_length(bytes, SYMBOL)?;
+            let symbol_length = u16::from_be_bytes([bytes[SYMBOL_LENGTH], bytes[SYMBOL_LENGTH + 1]]);
+            check_length(bytes, SYMBOL + symbol_length as usize)?;
+            let mut trade_id = [0; 8];
+            trade_id.copy_from_slice(&bytes[TRADE_ID..PRICE]);
+            let mut price = [0; 8];
+            price.copy_from_slice(&bytes[PRICE..QUANTITY]);
+            let mut quantity = [0; 4];
+            quantity.copy_from_slice(&bytes[QUANTITY..SYMBOL_LENGTH]);
+            let symbol = String::from_utf8(
+                bytes[SYMBOL..SYMBOL + symbol_length as usize].to_vec(),
+            )
+            .map_err(|e| codec::Error::Invalid(e.to_string()))?;
+            Ok(Trade {
+                trade_id: u64::from_be_bytes(trade_id),
+                price: i64::from_be_bytes(price),
+                quantity: u32::from_be_bytes(quantity),
+                symbol,
+            })
+        }
+    }
+
+    fn trades() -> Vec<Trade> {
+        (1..=5u64)
+            .map(|i| Trade {
+                trade_id: i,
+                price: -(i as i64) * 100,
+                quantity: i as u32 * 7,
+                symbol: "ABC".repeat(i as usize),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn typed_topic_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let manager = TopicManager::open(TEST_DIR).unwrap();
+        let topic = manager
+            .create_topic("trades", TopicConfig::default())
+            .unwrap();
+        let typed = TypedTopicHandle::<Trade>::new(topic.clone());
+        for trade in trades() {
+            block_on(typed.append_typed(&trade)).unwrap();
+            // Another type in the same topic is skipped.
+            block_on(topic.append(1, &[1, 2, 3])).unwrap();
+        }
+        let mut stream = typed.subscribe_typed(1);
+        let mut read = Vec::new();
+        while let Some(msg) = stream.next_value() {
+            read.push(msg.unwrap().value);
+        }
+        assert_eq!(trades(), read);
+
+        let mut exported = Vec::new();
+        export(&topic, 1, &mut exported).unwrap();
+        let path = format!("{}/imported", TEST_DIR);
+        let (reader, writer) = unsafe { MessageFileStore::new(&path, 0x10000).unwrap() };
+        import(&mut &exported[..], &writer, IdPolicy::Preserve).unwrap();
+        let mut pos = 0;
+        let mut imported = Vec::new();
+        while let Ok(msg) = reader.read_new(pos) {
+            if msg.message_id() == 0 {
+                break;
+            }
+            if msg.msg_type_id() == Trade::MESSAGE_TYPE {
+                imported.push(Trade::decode(msg.bytes()).unwrap());
+            }
+            pos = msg.next_pos();
+        }
+        assert_eq!(trades(), imported);
+
+        match Trade::decode(&[0; 10]) {
+            Err(codec::Error::Truncated { needed, available }) => {
+                assert_eq!(SYMBOL, needed);
+                assert_eq!(10, available);
+            }
+            r => panic!("Expected the trade to be truncated: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Tracks the messages that have been queued but not committed yet.  When the disk can't keep up
+//! the writers wait for the backlog to drain instead of filling up the incoming buffer.  The
+//! writers are paused once the uncommitted bytes go over the high watermark and resumed once they
+//! fall back to the low watermark so they don't flap at the limit.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// The number of uncommitted bytes where the writers are paused and resumed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Watermarks {
+    /// The writers are paused when the uncommitted bytes go over the value.
+    pub high: usize,
+    /// The writers are resumed when the uncommitted bytes drop to the value.
+    pub low: usize,
+}
+
+/// The messages that are waiting to be committed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BacklogStatus {
+    /// The number of writes that haven't been committed.
+    pub pending: usize,
+    /// The number of bytes that haven't been committed.
+    pub pending_bytes: usize,
+}
+
+/// Returned instead of writing when the writer would have to wait for the backlog to drain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WouldBlock {
+    /// The backlog at the time of the write.
+    pub lag: BacklogStatus,
+}
+
+/// The writes that haven't been committed.
+pub(crate) struct Backlog {
+    pending: AtomicUsize,
+    pending_bytes: AtomicUsize,
+    high: AtomicUsize,
+    low: AtomicUsize,
+    /// Set once we go over the high watermark until we drop to the low watermark.
+    paused: AtomicBool,
+    /// The writers waiting for the backlog to drain.
+    waiting: Mutex<Vec<Waker>>,
+}
+
+impl Backlog {
+    /// Creates a backlog without any limits.
+    pub(crate) fn new() -> Self {
+        Backlog {
+            pending: AtomicUsize::new(0),
+            pending_bytes: AtomicUsize::new(0),
+            high: AtomicUsize::new(usize::MAX),
+            low: AtomicUsize::new(usize::MAX),
+            paused: AtomicBool::new(false),
+            waiting: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sets the watermarks.
+    /// # Arguments
+    /// `watermarks` - The watermarks to use.  `None` never pauses the writers.
+    pub(crate) fn set_watermarks(&self, watermarks: Option<Watermarks>) {
+        let (high, low) = match watermarks {
+            Some(w) => (w.high, w.low.min(w.high)),
+            None => (usize::MAX, usize::MAX),
+        };
+        self.low.store(low, Ordering::Release);
+        self.high.store(high, Ordering::Release);
+        if self.pending_bytes.load(Ordering::Acquire) <= low {
+            self.resume();
+        }
+    }
+
+    /// The current backlog.
+    pub(crate) fn status(&self) -> BacklogStatus {
+        BacklogStatus {
+            pending: self.pending.load(Ordering::Acquire),
+            pending_bytes: self.pending_bytes.load(Ordering::Acquire),
+        }
+    }
+
+    /// True if the writers need to wait.
+    #[inline]
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Reserves the space for a write.  The space is given back when the reservation is dropped.
+    /// # Arguments
+    /// `size` - The number of bytes being written.
+    pub(crate) fn reserve(self: &Arc<Self>, size: usize) -> Reservation {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        let bytes = self.pending_bytes.fetch_add(size, Ordering::AcqRel) + size;
+        if bytes > self.high.load(Ordering::Acquire) {
+            self.paused.store(true, Ordering::Release);
+            // Everything could have been committed before we paused.
+            if self.pending_bytes.load(Ordering::Acquire) <= self.low.load(Ordering::Acquire) {
+                self.resume();
+            }
+        }
+        Reservation {
+            backlog: self.clone(),
+            size,
+        }
+    }
+
+    /// Gives back the space for a write.
+    /// # Arguments
+    /// `size` - The number of bytes that were reserved.
+    fn release(&self, size: usize) {
+        self.pending.fetch_sub(1, Ordering::AcqRel);
+        let bytes = self.pending_bytes.fetch_sub(size, Ordering::AcqRel) - size;
+        if bytes <= self.low.load(Ordering::Acquire) {
+            self.resume();
+        }
+    }
+
+    /// Resumes the writers if they are paused.
+    fn resume(&self) {
+        if self.paused.swap(false, Ordering::AcqRel) {
+            for waker in self.waiting.lock().unwrap().drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Waits until the writers aren't paused.
+    pub(crate) fn capacity(&self) -> Capacity<'_> {
+        Capacity { backlog: self }
+    }
+}
+
+/// The space reserved for a write in the backlog.
+pub(crate) struct Reservation {
+    backlog: Arc<Backlog>,
+    size: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.backlog.release(self.size);
+    }
+}
+
+/// The future that completes once the writers can continue.
+pub struct Capacity<'a> {
+    backlog: &'a Backlog,
+}
+
+impl Future for Capacity<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.backlog.is_paused() {
+            return Poll::Ready(());
+        }
+        self.backlog.waiting.lock().unwrap().push(cx.waker().clone());
+        // Check again in case we resumed before the waker was added.
+        if self.backlog.is_paused() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::raft::backlog::*;
+
+    #[test]
+    fn watermark_test() {
+        let backlog = Arc::new(Backlog::new());
+        backlog.set_watermarks(Some(Watermarks { high: 100, low: 40 }));
+        let first = backlog.reserve(60);
+        assert!(!backlog.is_paused());
+        let second = backlog.reserve(60);
+        assert!(backlog.is_paused());
+        assert_eq!(
+            BacklogStatus {
+                pending: 2,
+                pending_bytes: 120
+            },
+            backlog.status()
+        );
+        drop(first);
+        // Still above the low watermark.
+        assert!(backlog.is_paused());
+        drop(second);
+        assert!(!backlog.is_paused());
+        assert_eq!(BacklogStatus::default(), backlog.status());
+    }
+}
@@ -0,0 +1,540 @@
+//! Pulls the settings for a store together so they don't have to be passed in order.  Every
+//! setting has a default and `validate` checks them against each other before anything is
+//! started.  With the `serde` feature the settings can be loaded from a config file, the clock,
+//! wait strategy, threads and progress callback are left at their defaults and set in code.
+use crate::error::PersistError;
+use crate::raft::backlog::Watermarks;
+use crate::raft::verify::{OpenOptions, VerifyLevel, VerifyProgress};
+use crate::raft::{
+    default_commit_wait, startup_single_node_with_options, FlushPolicy, GroupCommit,
+    MessageProcessor, PersistedMessageFile, StoreThreads, COMMIT_SIZE,
+};
+use a19_concurrent::wait::WaitStrategy;
+use a19_core::clock::{system_clock, Clock};
+use std::fmt;
+use std::path::MAIN_SEPARATOR;
+use std::sync::Arc;
+
+/// The smallest event file that can be created.
+pub const MIN_FILE_SIZE: usize = 0x400;
+/// The smallest incoming buffer that can be created.
+pub const MIN_INCOMING_BUFFER_SIZE: usize = 0x400;
+/// The alignment of the event files.
+const FILE_ALIGNMENT: usize = 16;
+
+/// The settings for starting a store.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PersistedMessageFileBuilder {
+    file_storage_directory: String,
+    file_prefix: String,
+    max_file_size: usize,
+    commit_file_size: usize,
+    incoming_buffer_size: usize,
+    incoming_queue_size: usize,
+    watermarks: Option<Watermarks>,
+    verify_on_open: VerifyLevel,
+    repair: bool,
+    sparse_index: Option<u64>,
+    group_commit: Option<GroupCommit>,
+    flush_policy: Option<FlushPolicy>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    threads: StoreThreads,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    commit_wait: Arc<dyn WaitStrategy>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    progress: Option<Arc<dyn Fn(VerifyProgress) + Send + Sync>>,
+}
+
+impl Default for PersistedMessageFileBuilder {
+    fn default() -> Self {
+        PersistedMessageFileBuilder {
+            file_storage_directory: String::new(),
+            file_prefix: String::new(),
+            max_file_size: 0x100000,
+            commit_file_size: 0x100000,
+            incoming_buffer_size: 0x40000,
+            incoming_queue_size: 0x4000,
+            watermarks: None,
+            verify_on_open: VerifyLevel::None,
+            repair: false,
+            sparse_index: None,
+            group_commit: None,
+            flush_policy: None,
+            threads: StoreThreads::default(),
+            clock: system_clock(),
+            commit_wait: default_commit_wait(),
+            progress: None,
+        }
+    }
+}
+
+impl fmt::Debug for PersistedMessageFileBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PersistedMessageFileBuilder")
+            .field("file_storage_directory", &self.file_storage_directory)
+            .field("file_prefix", &self.file_prefix)
+            .field("max_file_size", &self.max_file_size)
+            .field("commit_file_size", &self.commit_file_size)
+            .field("incoming_buffer_size", &self.incoming_buffer_size)
+            .field("incoming_queue_size", &self.incoming_queue_size)
+            .field("watermarks", &self.watermarks)
+            .field("verify_on_open", &self.verify_on_open)
+            .field("repair", &self.repair)
+            .field("sparse_index", &self.sparse_index)
+            .field("group_commit", &self.group_commit)
+            .field("flush_policy", &self.flush_policy)
+            .field("threads", &self.threads)
+            .finish()
+    }
+}
+
+impl PersistedMessageFileBuilder {
+    /// Creates the settings with the defaults.
+    /// # Arguments
+    /// `file_storage_directory` - The directory to keep the files in.
+    /// `file_prefix` - The prefix of the file names.
+    pub fn new<D: Into<String>, P: Into<String>>(
+        file_storage_directory: D,
+        file_prefix: P,
+    ) -> Self {
+        PersistedMessageFileBuilder {
+            file_storage_directory: file_storage_directory.into(),
+            file_prefix: file_prefix.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the size of an event file before it rolls over.
+    /// # Arguments
+    /// `size` - The size in bytes.  Needs to be a multiple of 16.
+    pub fn max_file_size(mut self, size: usize) -> Self {
+        self.max_file_size = size;
+        self
+    }
+
+    /// Sets the size of a commit file.
+    /// # Arguments
+    /// `size` - The size in bytes.  Needs to be a multiple of the commit size.
+    pub fn commit_file_size(mut self, size: usize) -> Self {
+        self.commit_file_size = size;
+        self
+    }
+
+    /// Sets the size of the buffer the writes are copied to before they are written.
+    /// # Arguments
+    /// `size` - The size in bytes.  Needs to be a power of 2.
+    pub fn incoming_buffer_size(mut self, size: usize) -> Self {
+        self.incoming_buffer_size = size;
+        self
+    }
+
+    /// Sets the number of writes that can be waiting to be written.
+    /// # Arguments
+    /// `size` - The number of writes.
+    pub fn incoming_queue_size(mut self, size: usize) -> Self {
+        self.incoming_queue_size = size;
+        self
+    }
+
+    /// Sets when the writers wait for the backlog to drain.
+    /// # Arguments
+    /// `watermarks` - The uncommitted bytes to pause and resume at.  `None` never waits.
+    pub fn watermarks(mut self, watermarks: Option<Watermarks>) -> Self {
+        self.watermarks = watermarks;
+        self
+    }
+
+    /// Sets how much of the newest files to check before taking writes.
+    /// # Arguments
+    /// `level` - How much to check.
+    pub fn verify_on_open(mut self, level: VerifyLevel) -> Self {
+        self.verify_on_open = level;
+        self
+    }
+
+    /// Sets if the check can clear the bad frames after the committed position.
+    /// # Arguments
+    /// `repair` - True to repair the files.
+    pub fn repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Sets if an index is kept next to each event file so the readers can start close to a
+    /// message instead of scanning the file.
+    /// # Arguments
+    /// `interval` - The number of messages between the entries.  `None` doesn't keep the index.
+    pub fn sparse_index(mut self, interval: Option<u64>) -> Self {
+        self.sparse_index = interval;
+        self
+    }
+
+    /// Sets if the written messages are batched into one term.  The batch is flushed once before
+    /// the term is saved unless a flush policy says otherwise.
+    /// # Arguments
+    /// `group_commit` - The limits of a batch.  `None` commits the messages as soon as they are
+    /// written.
+    pub fn group_commit(mut self, group_commit: Option<GroupCommit>) -> Self {
+        self.group_commit = group_commit;
+        self
+    }
+
+    /// Sets when the event files are flushed as the messages are committed.
+    /// # Arguments
+    /// `flush_policy` - When to flush.  `None` flushes on every commit with a group commit and
+    /// otherwise leaves it to the operating system.
+    pub fn flush_policy(mut self, flush_policy: Option<FlushPolicy>) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Sets the callback for the progress of the check.
+    /// # Arguments
+    /// `progress` - Called as the check goes through a file.
+    pub fn progress<F>(mut self, progress: F) -> Self
+    where
+        F: Fn(VerifyProgress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Sets the settings for the writer, commit and reader threads.
+    /// # Arguments
+    /// `threads` - The settings for the threads.
+    pub fn threads(mut self, threads: StoreThreads) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Sets the clock to get the commit times from.
+    /// # Arguments
+    /// `clock` - The clock to use.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets how the commit thread waits when there is nothing to commit.
+    /// # Arguments
+    /// `commit_wait` - The wait strategy.
+    pub fn commit_wait(mut self, commit_wait: Arc<dyn WaitStrategy>) -> Self {
+        self.commit_wait = commit_wait;
+        self
+    }
+
+    /// Checks the settings against each other.
+    /// # Returns
+    /// An `InvalidConfig` error saying what is wrong with the first bad setting.
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.file_storage_directory.is_empty() {
+            return Err(PersistError::invalid_config(
+                "The storage directory isn't set.",
+            ));
+        }
+        if self.file_prefix.is_empty() {
+            return Err(PersistError::invalid_config("The file prefix isn't set."));
+        }
+        if self.file_prefix.contains(MAIN_SEPARATOR) || self.file_prefix.contains('/') {
+            return Err(PersistError::invalid_config(format!(
+                "The file prefix {} can't have a path separator in it.",
+                self.file_prefix
+            )));
+        }
+        if self.max_file_size < MIN_FILE_SIZE || !self.max_file_size.is_multiple_of(FILE_ALIGNMENT) {
+            return Err(PersistError::invalid_config(format!(
+                "The max file size {} needs to be a multiple of {} and at least {}.",
+                self.max_file_size, FILE_ALIGNMENT, MIN_FILE_SIZE
+            )));
+        }
+        let commit_size = COMMIT_SIZE as usize;
+        if self.commit_file_size < commit_size || !self.commit_file_size.is_multiple_of(commit_size) {
+            return Err(PersistError::invalid_config(format!(
+                "The commit file size {} needs to be a multiple of the commit size {}.",
+                self.commit_file_size, commit_size
+            )));
+        }
+        if self.incoming_buffer_size < MIN_INCOMING_BUFFER_SIZE
+            || !self.incoming_buffer_size.is_power_of_two()
+        {
+            return Err(PersistError::invalid_config(format!(
+                "The incoming buffer size {} needs to be a power of 2 and at least {}.",
+                self.incoming_buffer_size, MIN_INCOMING_BUFFER_SIZE
+            )));
+        }
+        if self.incoming_queue_size == 0 {
+            return Err(PersistError::invalid_config(
+                "The incoming queue size needs to be at least 1.",
+            ));
+        }
+        if let Some(watermarks) = self.watermarks {
+            if watermarks.high == 0 || watermarks.low > watermarks.high {
+                return Err(PersistError::invalid_config(format!(
+                    "The low watermark {} needs to be at or below the high watermark {}.",
+                    watermarks.low, watermarks.high
+                )));
+            }
+        }
+        if self.repair && self.verify_on_open == VerifyLevel::None {
+            return Err(PersistError::invalid_config(
+                "Repairing the files needs a verify level since nothing is checked without one.",
+            ));
+        }
+        if self.sparse_index == Some(0) {
+            return Err(PersistError::invalid_config(
+                "The sparse index interval needs to be at least 1 message.",
+            ));
+        }
+        if let Some(group_commit) = self.group_commit {
+            if group_commit.max_bytes == 0 || group_commit.max_messages == 0 {
+                return Err(PersistError::invalid_config(
+                    "The group commit needs to wait for at least 1 byte and 1 message.",
+                ));
+            }
+        }
+        if self.flush_policy == Some(FlushPolicy::EveryNMessages(0)) {
+            return Err(PersistError::invalid_config(
+                "The flush policy needs to wait for at least 1 message.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the settings and starts the store.
+    /// # Arguments
+    /// `message_processor` - Handles the messages as they are committed.
+    /// # Returns
+    /// The started store or why it couldn't be started.
+    pub fn build<FRead>(self, message_processor: FRead) -> crate::Result<PersistedMessageFile>
+    where
+        FRead: MessageProcessor + 'static,
+    {
+        self.validate()?;
+        let options = OpenOptions {
+            verify_on_open: self.verify_on_open,
+            repair: self.repair,
+            progress: self.progress,
+            index_interval: self.sparse_index,
+            group_commit: self.group_commit,
+            flush_policy: self.flush_policy,
+        };
+        let file = startup_single_node_with_options(
+            self.file_storage_directory,
+            self.file_prefix,
+            self.max_file_size,
+            self.commit_file_size,
+            message_processor,
+            self.incoming_buffer_size,
+            self.incoming_queue_size,
+            self.clock,
+            self.commit_wait,
+            self.threads,
+            options,
+        )?;
+        file.set_watermarks(self.watermarks);
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::error::PersistError;
+    use crate::file::MessageRead;
+    use crate::raft::builder::*;
+    use crate::raft::InfallibleProcessor;
+    use a19_concurrent::threads::ThreadConfig;
+    use a19_concurrent::wait::SleepBackoff;
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_builder";
+    const TEST_PREFIX: &str = "builder";
+
+    struct IgnoreMessages;
+
+    impl InfallibleProcessor for IgnoreMessages {
+        fn handle<'a>(&mut self, _read: &MessageRead<'a>) {}
+    }
+
+    fn clean(name: &str) -> String {
+        let file_storage_directory = format!("{}_{}", TEST_DIR, name);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        file_storage_directory
+    }
+
+    #[test]
+    pub fn default_build_test() {
+        let file_storage_directory = clean("default");
+        let file = PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            0x10000,
+            IgnoreMessages,
+        )
+        .unwrap();
+        assert_eq!(1, block_on(file.write(1, &[1, 2, 3, 4])).unwrap().unwrap());
+        file.close().unwrap();
+    }
+
+    #[test]
+    pub fn custom_build_test() {
+        let file_storage_directory = clean("custom");
+        let builder = PersistedMessageFileBuilder::new(file_storage_directory.clone(), TEST_PREFIX)
+            .max_file_size(0x4000)
+            .commit_file_size(0x8000)
+            .incoming_buffer_size(0x10000)
+            .incoming_queue_size(0x100)
+            .watermarks(Some(Watermarks {
+                high: 0x8000,
+                low: 0x1000,
+            }))
+            .threads(StoreThreads {
+                writer: Some(ThreadConfig::new("builder-writer")),
+                commit: Some(ThreadConfig::new("builder-commit")),
+                reader: Some(ThreadConfig::new("builder-reader")),
+            })
+            .clock(system_clock())
+            .commit_wait(Arc::new(SleepBackoff(Duration::from_millis(1))))
+            .sparse_index(Some(16));
+        let file = builder.clone().build(IgnoreMessages).unwrap();
+        let receivers: Vec<_> = (0..200u64)
+            .map(|i| file.write(1, &i.to_le_bytes()))
+            .collect();
+        for receiver in receivers {
+            block_on(receiver).unwrap().unwrap();
+        }
+        assert_eq!(0, file.backlog().pending);
+        file.close().unwrap();
+        let index = crate::raft::create_index_name(&file_storage_directory, TEST_PREFIX, &1);
+        assert!(!crate::raft::sparse_index::read_index(&index)
+            .unwrap()
+            .is_empty());
+
+        // Opened again with the files checked.
+        let checked = Arc::new(AtomicUsize::new(0));
+        let counter = checked.clone();
+        let file = builder
+            .verify_on_open(VerifyLevel::Full)
+            .repair(true)
+            .progress(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .build(IgnoreMessages)
+            .unwrap();
+        assert!(checked.load(Ordering::Relaxed) > 0);
+        assert_eq!(200, file.max_message_id());
+        file.close().unwrap();
+    }
+
+    #[test]
+    pub fn validate_test() {
+        let valid = PersistedMessageFileBuilder::new(format!("{}_validate", TEST_DIR), TEST_PREFIX);
+        assert!(valid.validate().is_ok());
+        let cases =
+            vec![
+            (
+                PersistedMessageFileBuilder::new("", TEST_PREFIX),
+                "The storage directory isn't set.",
+            ),
+            (
+                PersistedMessageFileBuilder::new(TEST_DIR, ""),
+                "The file prefix isn't set.",
+            ),
+            (
+                PersistedMessageFileBuilder::new(TEST_DIR, "a/b"),
+                "The file prefix a/b can't have a path separator in it.",
+            ),
+            (
+                valid.clone().max_file_size(0x100),
+                "The max file size 256 needs to be a multiple of 16 and at least 1024.",
+            ),
+            (
+                valid.clone().max_file_size(0x1001),
+                "The max file size 4097 needs to be a multiple of 16 and at least 1024.",
+            ),
+            (
+                valid.clone().commit_file_size(1000),
+                "The commit file size 1000 needs to be a multiple of the commit size 128.",
+            ),
+            (
+                valid.clone().incoming_buffer_size(0x3000),
+                "The incoming buffer size 12288 needs to be a power of 2 and at least 1024.",
+            ),
+            (
+                valid.clone().incoming_queue_size(0),
+                "The incoming queue size needs to be at least 1.",
+            ),
+            (
+                valid.clone().watermarks(Some(Watermarks { high: 10, low: 20 })),
+                "The low watermark 20 needs to be at or below the high watermark 10.",
+            ),
+            (
+                valid.clone().repair(true),
+                "Repairing the files needs a verify level since nothing is checked without one.",
+            ),
+            (
+                valid.clone().sparse_index(Some(0)),
+                "The sparse index interval needs to be at least 1 message.",
+            ),
+            (
+                valid.clone().group_commit(Some(GroupCommit {
+                    max_messages: 0,
+                    ..Default::default()
+                })),
+                "The group commit needs to wait for at least 1 byte and 1 message.",
+            ),
+            (
+                valid
+                    .clone()
+                    .flush_policy(Some(FlushPolicy::EveryNMessages(0))),
+                "The flush policy needs to wait for at least 1 message.",
+            ),
+        ];
+        for (builder, expected) in cases {
+            match builder.validate() {
+                Err(PersistError::InvalidConfig { reason, .. }) => assert_eq!(expected, reason),
+                other => panic!("Expected {} but got {:?}", expected, other),
+            }
+            // Nothing is started when the settings aren't valid.
+            assert!(builder.build(IgnoreMessages).is_err());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn deserialize_test() {
+        let json = r#"{
+            "file_storage_directory": "/var/lib/store",
+            "file_prefix": "orders",
+            "max_file_size": 4096,
+            "watermarks": { "high": 2048, "low": 1024 },
+            "verify_on_open": "Tail"
+        }"#;
+        let builder: PersistedMessageFileBuilder = serde_json::from_str(json).unwrap();
+        assert_eq!("/var/lib/store", builder.file_storage_directory);
+        assert_eq!("orders", builder.file_prefix);
+        assert_eq!(4096, builder.max_file_size);
+        assert_eq!(0x100000, builder.commit_file_size);
+        assert_eq!(VerifyLevel::Tail, builder.verify_on_open);
+        assert_eq!(
+            Some(Watermarks {
+                high: 2048,
+                low: 1024
+            }),
+            builder.watermarks
+        );
+        assert!(builder.validate().is_ok());
+    }
+}
@@ -0,0 +1,618 @@
+//! Merges a run of old event files into one file so the files left after the retention or a
+//! truncation don't pile up.  The committed messages are copied in order with the same ids and
+//! bodies, the terms pointing at the files are moved to where the messages ended up and the files
+//! after the first one are removed.
+//!
+//! ```text
+//!  events.3 [1..40 | end]  events.4 [41..50 | end]  events.5 [51..90 | end]
+//!                               compact(3..=5)
+//!  events.3 [1..90 | end]                                                    events.6 ...
+//! ```
+//! The new file is built in `file_prefix.compact/` and nothing is changed until a journal with
+//! the files and the new positions of the terms is written to `file_prefix.compacting`.  A crash
+//! before the journal leaves the original files, a crash after it finishes the swap the next time
+//! the store starts.
+//!
+//! The journal.
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Id of the first event file                                    |
+//! +---------------------------------------------------------------+ 32
+//! | Id of the last event file                                     |
+//! +---------------------------------------------------------------+ 64
+//! | Number of terms moved                                         |
+//! +---------------------------------------------------------------+ 96
+//! | Id of the commit file  |  Position of the term  |            ...
+//! | New position in the event file  |  New length  |  (per term) ...
+//! +---------------------------------------------------------------+
+//! | CRC32 of the bytes before it                                  |
+//! +---------------------------------------------------------------+
+//! ```
+use crate::error::{PersistError, ResultExt};
+use crate::file;
+use crate::file::header::open_commit_file;
+use crate::file::{MessageFileStore, MessageFileStoreRead};
+use crate::raft::{
+    create_commit_name, create_event_name, create_index_name, CommitFile, PersistedMessageFile,
+    COMMIT_SIZE, END_OF_FILE_MESSAGE_TYPE,
+};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, remove_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, MAIN_SEPARATOR};
+
+/// The size of the start of the journal.
+const JOURNAL_HEADER_SIZE: usize = 12;
+/// The size of a term in the journal.
+const JOURNAL_TERM_SIZE: usize = 24;
+
+/// A term that points at a merged file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MovedTerm {
+    /// The id of the commit file the term is in.
+    commit_file_id: u32,
+    /// The position of the term in the commit file.
+    pos: u32,
+    /// The position of the first message of the term in the new file.
+    offset: u64,
+    /// The number of bytes in the term.
+    length: u32,
+}
+
+/// What is swapped in once the new file is built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Journal {
+    /// The id of the first file merged.  The new file takes its id.
+    first: u32,
+    /// The id of the last file merged.
+    last: u32,
+    /// The terms to point at the new file.
+    terms: Vec<MovedTerm>,
+}
+
+impl Journal {
+    /// Encodes the journal with the checksum at the end.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; JOURNAL_HEADER_SIZE + self.terms.len() * JOURNAL_TERM_SIZE];
+        BigEndian::write_u32(&mut bytes[0..], self.first);
+        BigEndian::write_u32(&mut bytes[4..], self.last);
+        BigEndian::write_u32(&mut bytes[8..], self.terms.len() as u32);
+        for (i, term) in self.terms.iter().enumerate() {
+            let pos = JOURNAL_HEADER_SIZE + i * JOURNAL_TERM_SIZE;
+            BigEndian::write_u32(&mut bytes[pos..], term.commit_file_id);
+            BigEndian::write_u32(&mut bytes[pos + 4..], term.pos);
+            BigEndian::write_u64(&mut bytes[pos + 8..], term.offset);
+            BigEndian::write_u32(&mut bytes[pos + 16..], term.length);
+        }
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        bytes
+    }
+
+    /// Decodes a journal.
+    /// # Returns
+    /// `None` if the journal was only partly written.
+    fn decode(bytes: &[u8]) -> Option<Journal> {
+        if bytes.len() < JOURNAL_HEADER_SIZE + 4 {
+            return None;
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        let count = BigEndian::read_u32(&body[8..]) as usize;
+        if crc32fast::hash(body) != BigEndian::read_u32(crc)
+            || body.len() != JOURNAL_HEADER_SIZE + count * JOURNAL_TERM_SIZE
+        {
+            return None;
+        }
+        let terms = (0..count)
+            .map(|i| {
+                let pos = JOURNAL_HEADER_SIZE + i * JOURNAL_TERM_SIZE;
+                MovedTerm {
+                    commit_file_id: BigEndian::read_u32(&body[pos..]),
+                    pos: BigEndian::read_u32(&body[pos + 4..]),
+                    offset: BigEndian::read_u64(&body[pos + 8..]),
+                    length: BigEndian::read_u32(&body[pos + 16..]),
+                }
+            })
+            .collect();
+        Some(Journal {
+            first: BigEndian::read_u32(&body[0..]),
+            last: BigEndian::read_u32(&body[4..]),
+            terms,
+        })
+    }
+}
+
+impl PersistedMessageFile {
+    /// Merges a run of old event files into the first file in the run.  All of the messages in the
+    /// files need to be committed and the file being written to can't be in the run.  The messages
+    /// keep their ids and bodies, only the files and the positions they are in change.  The
+    /// iterators in the merged files get `FileRemoved` once they move onto the next file, on unix
+    /// an iterator keeps reading the file it has open.
+    /// # Arguments
+    /// `files` - The ids of the event files to merge.  The ids already removed are skipped.
+    /// # Returns
+    /// The ids of the files removed.  Nothing is changed when an error is returned.
+    pub fn compact(&self, files: RangeInclusive<u32>) -> crate::Result<Vec<u32>> {
+        if self.followed_files.is_some() {
+            return Err(PersistError::from(file::Error::ReadOnly));
+        }
+        let merged: Vec<u32> = {
+            let message_files = self.message_files.lock().unwrap();
+            let committed = self.max_message_id();
+            let kept: Vec<_> = message_files.iter().filter(|f| !f.removed).collect();
+            let mut merged = Vec::new();
+            for (file, next) in kept.iter().zip(kept.iter().skip(1)) {
+                if files.contains(&file.file_id) {
+                    // The next file could start with the rest of a message split between them.
+                    if next.message_id_start > committed {
+                        return Err(PersistError::invalid_config(format!(
+                            "The messages in the event file {} haven't all been committed.",
+                            file.file_id
+                        )));
+                    }
+                    merged.push(file.file_id);
+                }
+            }
+            if kept
+                .last()
+                .map(|f| files.contains(&f.file_id))
+                .unwrap_or(false)
+            {
+                return Err(PersistError::invalid_config(
+                    "The event file being written to can't be compacted.",
+                ));
+            }
+            merged
+        };
+        if merged.len() < 2 {
+            return Ok(Vec::new());
+        }
+        let journal = self.build_compacted(&merged)?;
+        let path = create_compact_journal_name(&self.file_storage_directory, &self.file_prefix);
+        let mut file = File::create(&path).with_path(path.as_str())?;
+        file.write_all(&journal.encode())
+            .and_then(|_| file.sync_all())
+            .with_path(path.as_str())?;
+        {
+            // The cached readers would keep the files open.
+            let mut readers = self.event_readers.lock().unwrap();
+            for file_id in merged.iter() {
+                readers.remove(file_id);
+            }
+        }
+        finish_compact(&self.file_storage_directory, &self.file_prefix)
+            .with_path(self.file_storage_directory.as_str())?;
+        let removed: Vec<u32> = merged[1..].to_vec();
+        self.message_files
+            .lock()
+            .unwrap()
+            .retain(|f| !removed.contains(&f.file_id));
+        let stale: Vec<u64> = self
+            .positions
+            .iter()
+            .filter(|(_, (file_id, _))| merged.contains(file_id))
+            .map(|(id, _)| id)
+            .collect();
+        for id in stale {
+            self.positions.remove(&id);
+        }
+        Ok(removed)
+    }
+
+    /// Copies the messages of the files into a new file in the compact directory and works out
+    /// where the terms pointing at them move to.
+    /// # Arguments
+    /// `merged` - The ids of the files to merge in order.
+    /// # Returns
+    /// The journal to swap the new file in with.
+    fn build_compacted(&self, merged: &[u32]) -> crate::Result<Journal> {
+        let staging = create_compact_name(&self.file_storage_directory, &self.file_prefix);
+        if Path::new(&staging).exists() {
+            remove_dir_all(&staging).with_path(staging.as_str())?;
+        }
+        create_dir_all(&staging).with_path(staging.as_str())?;
+        let first = merged[0];
+        let path = create_event_name(&staging, &self.file_prefix, &first);
+        let result = self.copy_messages(merged, &path).and_then(|moved| {
+            let terms = self.move_terms(merged, &moved)?;
+            Ok(Journal {
+                first,
+                last: *merged.last().unwrap(),
+                terms,
+            })
+        });
+        if result.is_err() {
+            // The original files are still the ones used.
+            let _ = remove_dir_all(&staging);
+        }
+        result
+    }
+
+    /// Copies the committed messages of the files into a new file.  The file is sized to fit the
+    /// messages since they take up the same space they did in the old files.
+    /// # Arguments
+    /// `merged` - The ids of the files to merge in order.
+    /// `path` - The path of the new file.
+    /// # Returns
+    /// The new position of each message and the end of the messages in the old files.
+    fn copy_messages(
+        &self,
+        merged: &[u32],
+        path: &str,
+    ) -> crate::Result<BTreeMap<(u32, usize), usize>> {
+        let committed = self.max_message_id();
+        let mut readers = Vec::with_capacity(merged.len());
+        for file_id in merged {
+            let old_path =
+                create_event_name(&self.file_storage_directory, &self.file_prefix, file_id);
+            let reader = unsafe { MessageFileStore::open_readonly(&old_path) }
+                .with_path(old_path.as_str())?;
+            let end = messages_end(&reader, committed).with_path(old_path.as_str())?;
+            readers.push((*file_id, reader, end));
+        }
+        let size = readers.iter().map(|(_, _, end)| end).sum::<usize>().max(1);
+        let (_, writer) = unsafe { MessageFileStore::new(&path, size) }.with_path(path)?;
+        let mut moved = BTreeMap::new();
+        let mut new_pos = 0;
+        for (file_id, reader, end) in readers.iter() {
+            let mut pos = 0;
+            while pos < *end {
+                let msg = reader.read_new(pos).map_err(PersistError::from)?;
+                moved.insert((*file_id, pos), new_pos);
+                new_pos = writer
+                    .write_with_headers(
+                        new_pos,
+                        msg.msg_type_id(),
+                        msg.message_id(),
+                        &msg.headers(),
+                        msg.bytes(),
+                    )
+                    .map_err(|e| match e {
+                        file::Error::Full | file::Error::NotEnoughSpace { .. } => {
+                            PersistError::Full {
+                                context: Default::default(),
+                            }
+                        }
+                        e => PersistError::from(e),
+                    })
+                    .with_path(path)?;
+                pos = msg.next_pos();
+            }
+            // A term ends where the messages in the file end.
+            moved.insert((*file_id, *end), new_pos);
+        }
+        match writer.write(new_pos, END_OF_FILE_MESSAGE_TYPE, u64::MAX, &[0, 0]) {
+            // The readers move on at the end of the file when it doesn't fit.
+            Ok(_) | Err(file::Error::Full) | Err(file::Error::NotEnoughSpace { .. }) => {}
+            Err(e) => return Err(PersistError::from(e).with_path(path)),
+        }
+        writer.flush().with_path(path)?;
+        Ok(moved)
+    }
+
+    /// Finds the terms pointing at the merged files and where they move to.
+    /// # Arguments
+    /// `merged` - The ids of the files to merge.
+    /// `moved` - The new positions of the messages.
+    fn move_terms(
+        &self,
+        merged: &[u32],
+        moved: &BTreeMap<(u32, usize), usize>,
+    ) -> crate::Result<Vec<MovedTerm>> {
+        let commit_files: Vec<u32> = self
+            .commit_files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.file_id)
+            .collect();
+        let mut terms = Vec::new();
+        for commit_file_id in commit_files {
+            let path = create_commit_name(
+                &self.file_storage_directory,
+                &self.file_prefix,
+                &commit_file_id,
+            );
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .with_path(path.as_str())?;
+            let buffer = unsafe { open_commit_file(file) }.with_path(path.as_str())?;
+            let mut pos = 0;
+            while buffer.verify(pos) && buffer.term(pos) > 0 {
+                let file_id = buffer.file_id(pos);
+                if merged.contains(&file_id) {
+                    let start = buffer.file_position_offset(pos) as usize;
+                    let end = start + buffer.length_of_commit(pos) as usize;
+                    match (moved.get(&(file_id, start)), moved.get(&(file_id, end))) {
+                        (Some(offset), Some(new_end)) => terms.push(MovedTerm {
+                            commit_file_id,
+                            pos: pos as u32,
+                            offset: *offset as u64,
+                            length: (new_end - offset) as u32,
+                        }),
+                        _ => {
+                            return Err(PersistError::CorruptTerm {
+                                context: Default::default(),
+                                reason: format!(
+                                    "The term {} doesn't start and end on a message in the event file {}.",
+                                    buffer.term(pos),
+                                    file_id
+                                ),
+                            }
+                            .with_path(path))
+                        }
+                    }
+                }
+                pos += COMMIT_SIZE as usize;
+            }
+        }
+        Ok(terms)
+    }
+}
+
+/// Finds where the committed messages in an event file end.
+/// # Arguments
+/// `reader` - The reader for the file.
+/// `committed` - The id of the last message committed.
+/// # Returns
+/// The position after the last committed message.
+fn messages_end(reader: &MessageFileStoreRead, committed: u64) -> crate::Result<usize> {
+    let mut pos = 0;
+    loop {
+        match reader.read_new(pos) {
+            Ok(msg) => {
+                if msg.msg_type_id() == END_OF_FILE_MESSAGE_TYPE
+                    || msg.message_id() == 0
+                    || msg.message_id() > committed
+                {
+                    break Ok(pos);
+                }
+                pos = msg.next_pos();
+            }
+            Err(file::Error::NoMessage)
+            | Err(file::Error::Full)
+            | Err(file::Error::PositionOutOfRange(_)) => break Ok(pos),
+            Err(e) => break Err(PersistError::from(e)),
+        }
+    }
+}
+
+/// Finishes swapping in a compacted file.  Without a complete journal the original files are
+/// still the ones used so the compact directory is removed.  Is safe to run again if it is
+/// interrupted.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// # Returns
+/// True if a compaction was finished.
+pub(crate) fn finish_compact(file_storage_directory: &str, file_prefix: &str) -> io::Result<bool> {
+    let staging = create_compact_name(file_storage_directory, file_prefix);
+    let journal_path = create_compact_journal_name(file_storage_directory, file_prefix);
+    let journal = if Path::new(&journal_path).exists() {
+        Journal::decode(&read(&journal_path)?)
+    } else {
+        None
+    };
+    let journal = match journal {
+        Some(journal) => journal,
+        None => {
+            if Path::new(&staging).exists() {
+                remove_dir_all(&staging)?;
+            }
+            if Path::new(&journal_path).exists() {
+                remove_file(&journal_path)?;
+            }
+            return Ok(false);
+        }
+    };
+    let compacted = create_event_name(&staging, file_prefix, &journal.first);
+    if Path::new(&compacted).exists() {
+        rename(
+            &compacted,
+            create_event_name(file_storage_directory, file_prefix, &journal.first),
+        )?;
+    }
+    for term in journal.terms.iter() {
+        let path = create_commit_name(file_storage_directory, file_prefix, &term.commit_file_id);
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut buffer = unsafe { open_commit_file(file)? };
+        let pos = term.pos as usize;
+        buffer
+            .set_file_id(pos, journal.first)
+            .set_file_position_offset(pos, term.offset)
+            .set_length_of_commit(pos, term.length)
+            .set_checksum(pos);
+        buffer.flush()?;
+    }
+    for file_id in journal.first..=journal.last {
+        let index = create_index_name(file_storage_directory, file_prefix, &file_id);
+        if Path::new(&index).exists() {
+            remove_file(&index)?;
+        }
+        let path = create_event_name(file_storage_directory, file_prefix, &file_id);
+        if file_id != journal.first && Path::new(&path).exists() {
+            remove_file(&path)?;
+        }
+    }
+    File::open(file_storage_directory).and_then(|f| f.sync_all())?;
+    if Path::new(&staging).exists() {
+        remove_dir_all(&staging)?;
+    }
+    remove_file(&journal_path)?;
+    Ok(true)
+}
+
+/// Creates the name of the directory a compacted file is built in.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+fn create_compact_name(file_storage_directory: &str, file_prefix: &str) -> String {
+    format!(
+        "{}{}{}.compact",
+        file_storage_directory, MAIN_SEPARATOR, file_prefix
+    )
+}
+
+/// Creates the name of the journal for a compaction.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+fn create_compact_journal_name(file_storage_directory: &str, file_prefix: &str) -> String {
+    format!(
+        "{}{}{}.compacting",
+        file_storage_directory, MAIN_SEPARATOR, file_prefix
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::raft::builder::PersistedMessageFileBuilder;
+    use crate::raft::compact::*;
+    use crate::raft::{MessageProcessor, MessageRead, ProcessError};
+    use crate::PersitEventStream;
+    use futures::executor::block_on;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_compact";
+    const TEST_PREFIX: &str = "compact";
+
+    struct NoOp;
+
+    impl MessageProcessor for NoOp {
+        fn handle(&mut self, _read: MessageRead<'_>) -> Result<(), ProcessError> {
+            Ok(())
+        }
+    }
+
+    fn start(dir: &str) -> PersistedMessageFile {
+        PersistedMessageFileBuilder::new(dir, TEST_PREFIX)
+            .max_file_size(0x400)
+            .commit_file_size(COMMIT_SIZE as usize * 4)
+            .build(NoOp)
+            .unwrap()
+    }
+
+    /// Writes the messages with their id as the value.
+    fn write_messages(dir: &str, count: u64) -> PersistedMessageFile {
+        if Path::new(dir).exists() {
+            remove_dir_all(dir).unwrap();
+        }
+        let store = start(dir);
+        for i in 1..=count {
+            assert_eq!(
+                i,
+                block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap()
+            );
+        }
+        store
+    }
+
+    fn all_events(store: &PersistedMessageFile) -> Vec<(u64, Vec<u8>)> {
+        store
+            .events_from(1)
+            .map(|e| (e.commit_key, e.value.to_vec()))
+            .collect()
+    }
+
+    fn file_ids(store: &PersistedMessageFile) -> Vec<u32> {
+        store
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|f| !f.removed)
+            .map(|f| f.file_id)
+            .collect()
+    }
+
+    #[test]
+    pub fn compact_test() {
+        let dir = format!("{}/three_files", TEST_DIR);
+        let store = write_messages(&dir, 200);
+        let before = all_events(&store);
+        assert_eq!(
+            (1..=200).collect::<Vec<u64>>(),
+            before.iter().map(|e| e.0).collect::<Vec<u64>>()
+        );
+        let files = file_ids(&store);
+        assert!(files.len() > 4);
+        let last = *files.last().unwrap();
+        assert!(store.compact(2..=last).is_err());
+        assert!(store.compact(2..=2).unwrap().is_empty());
+
+        assert_eq!(vec![3, 4], store.compact(2..=4).unwrap());
+        assert!(!Path::new(&create_event_name(&dir, TEST_PREFIX, &3)).exists());
+        assert!(!Path::new(&create_compact_journal_name(&dir, TEST_PREFIX)).exists());
+        let compacted: Vec<u32> = files
+            .iter()
+            .filter(|id| **id < 3 || **id > 4)
+            .cloned()
+            .collect();
+        assert_eq!(compacted, file_ids(&store));
+        assert_eq!(before, all_events(&store));
+        for (id, _) in before.iter() {
+            let term = store.find_term(*id).unwrap();
+            assert!(term.file_id != 3 && term.file_id != 4);
+        }
+        assert_eq!(
+            201,
+            block_on(store.write(1, &201u64.to_le_bytes()))
+                .unwrap()
+                .unwrap()
+        );
+        store.close().unwrap();
+
+        let store = start(&dir);
+        let mut after = before.clone();
+        after.push((201, 201u64.to_le_bytes().to_vec()));
+        assert_eq!(after, all_events(&store));
+        assert_eq!(
+            202,
+            block_on(store.write(1, &202u64.to_le_bytes()))
+                .unwrap()
+                .unwrap()
+        );
+        store.close().unwrap();
+    }
+
+    #[test]
+    pub fn compact_crash_test() {
+        let dir = format!("{}/crash", TEST_DIR);
+        let store = write_messages(&dir, 200);
+        let before = all_events(&store);
+        let files = file_ids(&store);
+
+        // Crashed before the journal was written so the original files are kept.
+        store.build_compacted(&[1, 2, 3]).unwrap();
+        store.close().unwrap();
+        let store = start(&dir);
+        assert!(!Path::new(&create_compact_name(&dir, TEST_PREFIX)).exists());
+        assert_eq!(files, file_ids(&store));
+        assert_eq!(before, all_events(&store));
+
+        // Crashed after the journal was written so the swap is finished on startup.
+        let journal = store.build_compacted(&[1, 2, 3]).unwrap();
+        std::fs::write(
+            create_compact_journal_name(&dir, TEST_PREFIX),
+            journal.encode(),
+        )
+        .unwrap();
+        store.close().unwrap();
+        let store = start(&dir);
+        assert!(!Path::new(&create_compact_journal_name(&dir, TEST_PREFIX)).exists());
+        let compacted: Vec<u32> = files
+            .iter()
+            .filter(|id| **id != 2 && **id != 3)
+            .cloned()
+            .collect();
+        assert_eq!(compacted, file_ids(&store));
+        assert_eq!(before, all_events(&store));
+        store.close().unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Keeps track of the bytes written to the event files since they were last flushed so only the
+//! pages they are in are synced instead of the whole mapping.  The writer records the range of
+//! each write and the flush takes the range of the file it is flushing.
+//!
+//! ```text
+//!  writer: record(file, start, end) --> [ file 1: low..high | file 2: low..high ]
+//!  flush:  take(file)               --> flush_range(low, high - low)
+//! ```
+//! If a thread panics while holding the ranges they can't be trusted anymore so the flush falls
+//! back to syncing the whole file.
+use std::sync::Mutex;
+
+/// The bytes written to a file since it was last flushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRange {
+    /// The id of the event file.
+    pub file_id: u32,
+    /// The position of the first byte written.
+    pub start: usize,
+    /// The position after the last byte written.
+    pub end: usize,
+}
+
+impl DirtyRange {
+    /// The number of bytes in the range.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// True if nothing was written.
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+}
+
+/// What needs to be flushed for a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dirty {
+    /// Nothing has been written since the last flush.
+    Clean,
+    /// The bytes written since the last flush.
+    Range(DirtyRange),
+    /// The ranges can't be trusted so the whole file needs to be flushed.
+    Unknown,
+}
+
+/// The ranges written to the event files shared between the writer and the flush.
+#[derive(Debug, Default)]
+pub struct DirtyRanges {
+    ranges: Mutex<Vec<DirtyRange>>,
+}
+
+impl DirtyRanges {
+    /// Records a write.
+    /// # Arguments
+    /// `file_id` - The id of the file written to.
+    /// `start` - The position of the first byte written.
+    /// `end` - The position after the last byte written.
+    pub fn record(&self, file_id: u32, start: usize, end: usize) {
+        // The flush syncs the whole file once the ranges are poisoned.
+        if let Ok(mut ranges) = self.ranges.lock() {
+            match ranges.iter_mut().find(|r| r.file_id == file_id) {
+                Some(range) => {
+                    range.start = range.start.min(start);
+                    range.end = range.end.max(end);
+                }
+                None => ranges.push(DirtyRange {
+                    file_id,
+                    start,
+                    end,
+                }),
+            }
+        }
+    }
+
+    /// Takes the range written to a file so it can be flushed.
+    /// # Arguments
+    /// `file_id` - The id of the file to flush.
+    pub fn take(&self, file_id: u32) -> Dirty {
+        match self.ranges.lock() {
+            Ok(mut ranges) => match ranges.iter().position(|r| r.file_id == file_id) {
+                Some(index) => Dirty::Range(ranges.remove(index)),
+                None => Dirty::Clean,
+            },
+            Err(_) => Dirty::Unknown,
+        }
+    }
+
+    /// The ranges that haven't been flushed.
+    /// # Returns
+    /// `None` if the ranges can't be trusted.
+    pub fn ranges(&self) -> Option<Vec<DirtyRange>> {
+        self.ranges.lock().ok().map(|ranges| ranges.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::MessageFileStore;
+    use crate::raft::dirty_range::*;
+    use crate::raft::{
+        create_event_name, load_current_files, process_files, PersistedMessageWriteStream,
+        END_OF_FILE_MESSAGE_TYPE,
+    };
+    use std::fs::remove_dir_all;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::path::Path;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_dirty_range";
+    const TEST_PREFIX: &str = "dirty";
+
+    #[test]
+    pub fn rollover_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let file_size = 0x400;
+        let mut collection = load_current_files(TEST_PREFIX, TEST_DIR).unwrap();
+        process_files(&mut collection, TEST_PREFIX, TEST_DIR, &file_size, &0x1000).unwrap();
+        let dirty = Arc::new(DirtyRanges::default());
+        let mut writer = PersistedMessageWriteStream::new(
+            1,
+            TEST_DIR.to_owned(),
+            TEST_PREFIX.to_owned(),
+            file_size,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap()
+        .with_dirty_ranges(dirty.clone());
+        for message_id in 1..=40u64 {
+            writer
+                .add_message(1, message_id, &message_id.to_le_bytes())
+                .unwrap();
+        }
+        assert_eq!(2, writer.position().0);
+
+        // The first file is dirty up to the end of file marker or to the end when it didn't fit.
+        let first = unsafe {
+            MessageFileStore::open_readonly(&create_event_name(TEST_DIR, TEST_PREFIX, &1))
+        }
+        .unwrap();
+        let mut pos = 0;
+        let end = loop {
+            match first.read_new(pos) {
+                Ok(msg) if msg.msg_type_id() == END_OF_FILE_MESSAGE_TYPE => break msg.next_pos(),
+                Ok(msg) => pos = msg.next_pos(),
+                Err(_) => break first.capacity(),
+            }
+        };
+        assert_eq!(
+            Some(vec![
+                DirtyRange {
+                    file_id: 1,
+                    start: 0,
+                    end
+                },
+                DirtyRange {
+                    file_id: 2,
+                    start: 0,
+                    end: writer.position().1
+                },
+            ]),
+            dirty.ranges()
+        );
+        match dirty.take(1) {
+            Dirty::Range(range) => assert_eq!(end, range.len()),
+            other => panic!("Expected the range of file 1 but got {:?}", other),
+        }
+        assert_eq!(Dirty::Clean, dirty.take(1));
+        // Only what is written after the take is recorded.
+        let before = writer.position().1;
+        writer.add_message(1, 41, &41u64.to_le_bytes()).unwrap();
+        dirty.take(2);
+        writer.add_message(1, 42, &42u64.to_le_bytes()).unwrap();
+        assert_eq!(
+            Dirty::Range(DirtyRange {
+                file_id: 2,
+                start: before + 32,
+                end: writer.position().1
+            }),
+            dirty.take(2)
+        );
+    }
+
+    #[test]
+    pub fn poisoned_test() {
+        let dirty = DirtyRanges::default();
+        dirty.record(1, 0, 32);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _ranges = dirty.ranges.lock().unwrap();
+            panic!("Poisons the ranges.");
+        }));
+        dirty.record(1, 32, 64);
+        // Falls back to flushing the whole file.
+        assert_eq!(Dirty::Unknown, dirty.take(1));
+        assert_eq!(None, dirty.ranges());
+    }
+}
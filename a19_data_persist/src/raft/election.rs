@@ -0,0 +1,198 @@
+//! The timer a follower or a candidate uses to start an election when it hasn't heard from a
+//! leader.  Each reset picks a random timeout between the base and the base plus the jitter so the
+//! servers don't all run for leader at the same time and split the votes.
+//!
+//! ```text
+//!  heartbeat -> reset() -> deadline = now + base + rand(0..jitter)
+//!  tick      -> expired(now)?  --yes--> RaftNodeEvent::ElectionTimeout
+//! ```
+//! The time comes from a `Clock` so the tests can move it forward without sleeping.
+use crate::raft::node_state::RaftAction;
+use a19_core::clock::Clock;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// The default shortest time to wait for a leader.
+pub const DEFAULT_ELECTION_BASE: Duration = Duration::from_millis(150);
+/// The default amount of random time added to the base.
+pub const DEFAULT_ELECTION_JITTER: Duration = Duration::from_millis(150);
+
+/// Runs out when we haven't heard from the leader in a random amount of time.
+pub struct ElectionTimer {
+    /// Where the time comes from.
+    clock: Arc<dyn Clock>,
+    /// The shortest time to wait.
+    base: Duration,
+    /// The most random time to add to the base.
+    jitter: Duration,
+    /// Picks the random time.
+    rng: StdRng,
+    /// The monotonic time in nanoseconds when the timer runs out.
+    deadline_ns: u64,
+}
+
+impl ElectionTimer {
+    /// Creates a timer that is started.
+    /// # Arguments
+    /// `clock` - Where the time comes from.
+    /// `base` - The shortest time to wait.
+    /// `jitter` - The most random time to add to the base.
+    pub fn new(clock: Arc<dyn Clock>, base: Duration, jitter: Duration) -> Self {
+        ElectionTimer::with_rng(clock, base, jitter, StdRng::from_entropy())
+    }
+
+    /// Creates a timer that always picks the same timeouts for the seed.
+    /// # Arguments
+    /// `clock` - Where the time comes from.
+    /// `base` - The shortest time to wait.
+    /// `jitter` - The most random time to add to the base.
+    /// `seed` - The seed for the random timeouts.
+    pub fn with_seed(clock: Arc<dyn Clock>, base: Duration, jitter: Duration, seed: u64) -> Self {
+        ElectionTimer::with_rng(clock, base, jitter, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(clock: Arc<dyn Clock>, base: Duration, jitter: Duration, rng: StdRng) -> Self {
+        let mut timer = ElectionTimer {
+            clock,
+            base,
+            jitter,
+            rng,
+            deadline_ns: 0,
+        };
+        timer.reset();
+        timer
+    }
+
+    /// Starts the timer again with a new random timeout.
+    /// # Returns
+    /// The timeout that was picked.
+    pub fn reset(&mut self) -> Duration {
+        let jitter = self.jitter.as_nanos() as u64;
+        let timeout = if jitter > 0 {
+            self.base + Duration::from_nanos(self.rng.gen_range(0, jitter))
+        } else {
+            self.base
+        };
+        self.deadline_ns = self.clock.monotonic_ns() + timeout.as_nanos() as u64;
+        timeout
+    }
+
+    /// Resets the timer if the actions ask for it.
+    /// # Arguments
+    /// `actions` - The actions returned from handling an event.
+    pub fn apply(&mut self, actions: &[RaftAction]) {
+        if actions.contains(&RaftAction::ResetElectionTimer) {
+            self.reset();
+        }
+    }
+
+    /// Checks to see if the timer has run out.
+    /// # Arguments
+    /// `now_ns` - The monotonic time of the clock in nanoseconds.
+    pub fn expired(&self, now_ns: u64) -> bool {
+        now_ns >= self.deadline_ns
+    }
+
+    /// Checks to see if the timer has run out at the current time of the clock.
+    pub fn is_expired(&self) -> bool {
+        self.expired(self.clock.monotonic_ns())
+    }
+
+    /// The time left before the timer runs out.
+    pub fn remaining(&self) -> Duration {
+        Duration::from_nanos(self.deadline_ns.saturating_sub(self.clock.monotonic_ns()))
+    }
+
+    /// Waits for the timer to run out.
+    pub async fn wait(&self) {
+        while !self.is_expired() {
+            delay_for(self.remaining()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::raft::election::*;
+    use crate::raft::node_state::{RaftNodeEvent, RaftState};
+    use a19_core::clock::{system_clock, ManualClock};
+
+    #[test]
+    pub fn jitter_test() {
+        let clock = Arc::new(ManualClock::new(0));
+        let mut first = ElectionTimer::with_seed(
+            clock.clone(),
+            DEFAULT_ELECTION_BASE,
+            DEFAULT_ELECTION_JITTER,
+            1,
+        );
+        let mut second =
+            ElectionTimer::with_seed(clock, DEFAULT_ELECTION_BASE, DEFAULT_ELECTION_JITTER, 2);
+        let mut together = 0;
+        for _ in 0..1000 {
+            let first = first.reset();
+            let second = second.reset();
+            for timeout in &[first, second] {
+                assert!(*timeout >= DEFAULT_ELECTION_BASE);
+                assert!(*timeout < DEFAULT_ELECTION_BASE + DEFAULT_ELECTION_JITTER);
+            }
+            // Within a millisecond of each other is as good as at the same time.
+            if first.as_millis() == second.as_millis() {
+                together += 1;
+            }
+        }
+        assert!(together < 30, "Expired together {} times.", together);
+    }
+
+    #[test]
+    pub fn heartbeat_test() {
+        let clock = Arc::new(ManualClock::new(0));
+        let mut timer = ElectionTimer::with_seed(
+            clock.clone(),
+            DEFAULT_ELECTION_BASE,
+            DEFAULT_ELECTION_JITTER,
+            7,
+        );
+        let mut state = RaftState::new(1, 3, 1, None);
+        for _ in 0..20 {
+            clock.advance(Duration::from_millis(100));
+            let actions = state.handle_event(RaftNodeEvent::NewTerm {
+                term: 1,
+                leader_id: 2,
+            });
+            timer.apply(&actions);
+            assert!(state.tick(&mut timer).is_empty());
+        }
+        assert!(!timer.is_expired());
+
+        // The leader went away.
+        clock.advance(DEFAULT_ELECTION_BASE + DEFAULT_ELECTION_JITTER);
+        assert!(timer.is_expired());
+        assert_eq!(
+            vec![
+                RaftAction::SaveTerm {
+                    term: 2,
+                    voted_for: Some(1)
+                },
+                RaftAction::ResetElectionTimer,
+                RaftAction::RequestVotes { term: 2 },
+            ],
+            state.tick(&mut timer)
+        );
+        assert!(!timer.is_expired());
+    }
+
+    #[tokio::test]
+    pub async fn wait_test() {
+        let clock = system_clock();
+        let timer =
+            ElectionTimer::with_seed(clock, Duration::from_millis(5), Duration::from_millis(5), 3);
+        timer.wait().await;
+        assert!(timer.is_expired());
+        assert_eq!(Duration::from_millis(0), timer.remaining());
+    }
+}
@@ -0,0 +1,44 @@
+//! The lifecycle events the store publishes so the metrics, retention and the application can
+//! watch the node without being tied into it.  Nothing is published until an event bus is set.
+use a19_concurrent::event_bus::EventBus;
+use std::sync::RwLock;
+
+/// The number of terms a follower can be behind the leader before it is reported as lagging.
+pub const FOLLOWER_LAG_TERMS: u64 = 64;
+
+/// Something that happened in the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// A leader was elected.
+    LeaderElected { server_id: u32 },
+    /// The terms up to and including the id have been committed.
+    TermCommitted { term_id: u64 },
+    /// The writer finished an event file and moved onto the one with the id.
+    FileRolled { file_id: u32 },
+    /// A follower is more than `FOLLOWER_LAG_TERMS` behind the leader.
+    FollowerLagging { peer: u32, behind: u64 },
+}
+
+/// The bus to publish the events to if one has been set.  Shared with the threads of the store.
+#[derive(Default)]
+pub(crate) struct StoreEvents {
+    bus: RwLock<Option<EventBus<StoreEvent>>>,
+}
+
+impl StoreEvents {
+    /// Sets the bus to publish the events to.
+    /// # Arguments
+    /// `bus` - The bus to publish to.
+    pub(crate) fn set_bus(&self, bus: EventBus<StoreEvent>) {
+        *self.bus.write().unwrap() = Some(bus);
+    }
+
+    /// Publishes an event if there is a bus.
+    /// # Arguments
+    /// `event` - The event to publish.
+    pub(crate) fn emit(&self, event: StoreEvent) {
+        if let Some(bus) = self.bus.read().unwrap().as_ref() {
+            bus.publish(event);
+        }
+    }
+}
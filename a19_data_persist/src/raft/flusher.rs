@@ -0,0 +1,503 @@
+//! Flushes the event files to disk.  By default the thread calling flush syncs the memory map.
+//! With the `uring` feature the syncs are submitted to an io_uring owned by a thread of its own so
+//! the flushes of the files overlap and the writes can be submitted without waiting on them.
+//!```text
+//!  flush/write_at --> [ requests ] --> ring thread --> write (IO_LINK) --> sync_file_range
+//!        ^                                                 (IO_LINK) --> fsync
+//!        |                                                                  |
+//!        +-------------------- oneshot <------------ completion ------------+
+//!```
+//! The entries for a request are linked so the fsync only runs once the data it covers has been
+//! written.  If the kernel doesn't support io_uring or the operations the thread flusher is used.
+use crate::file;
+use crate::file::MessageFileStoreWrite;
+
+/// How the event files are flushed.
+pub(crate) enum Flusher {
+    /// Syncs the memory map on the calling thread.
+    Thread,
+    /// Submits the syncs to an io_uring.
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    Uring(uring::UringFlusher),
+}
+
+impl Flusher {
+    /// Creates the io_uring flusher if it is enabled and supported otherwise the thread flusher.
+    pub(crate) fn new() -> Self {
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        {
+            match uring::UringFlusher::new() {
+                Ok(flusher) => return Flusher::Uring(flusher),
+                Err(e) => log::warn!("Unable to use io_uring to flush, using a thread: {}", e),
+            }
+        }
+        Flusher::Thread
+    }
+
+    /// True if the flushes are submitted to an io_uring.
+    #[allow(dead_code)]
+    pub(crate) fn is_uring(&self) -> bool {
+        !matches!(self, Flusher::Thread)
+    }
+
+    /// Flushes an event file to disk.  Waits for the flush to complete.
+    /// # Arguments
+    /// `writer` - The writer for the file.
+    pub(crate) fn flush(&self, writer: &MessageFileStoreWrite) -> file::Result<()> {
+        match self {
+            Flusher::Thread => writer.flush(),
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            Flusher::Uring(flusher) => match writer.file() {
+                Some(event_file) => {
+                    let done = flusher.sync(event_file)?;
+                    match futures::executor::block_on(done) {
+                        Ok(result) => Ok(result?),
+                        Err(_) => Err(file::Error::Stopped),
+                    }
+                }
+                // Nothing to sync when the messages are kept in memory.
+                None => writer.flush(),
+            },
+        }
+    }
+
+    /// Flushes part of an event file to disk.  The io_uring syncs the whole file.
+    /// # Arguments
+    /// `writer` - The writer for the file.
+    /// `position` - The position of the first byte to flush.
+    /// `length` - The number of bytes to flush.
+    pub(crate) fn flush_range(
+        &self,
+        writer: &MessageFileStoreWrite,
+        position: usize,
+        length: usize,
+    ) -> file::Result<()> {
+        match self {
+            Flusher::Thread => writer.flush_range(position, length),
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            Flusher::Uring(_) => self.flush(writer),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub(crate) mod uring {
+    use crate::raft::QueueFuture;
+    use futures::channel::oneshot;
+    use io_uring::{opcode, squeue, types, IoUring, Probe};
+    use std::collections::{HashMap, VecDeque};
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+    use std::sync::Arc;
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    /// The number of entries in the submission queue.
+    const RING_ENTRIES: u32 = 256;
+
+    /// The bits of the user data for the index of the operation in the request.  The rest is the
+    /// id of the request.
+    const OP_BITS: u32 = 16;
+
+    /// An operation on a file.
+    enum Op {
+        /// Writes the bytes at the offset.
+        Write { offset: u64, data: Vec<u8> },
+        /// Starts writing back the dirty pages of the whole file.
+        SyncRange,
+        /// Waits for the data of the file to be on disk.
+        Fsync,
+    }
+
+    /// The operations to run in order on a file.
+    struct Request {
+        /// Keeps the file open until the operations complete.
+        file: Arc<File>,
+        ops: Vec<Op>,
+        done: oneshot::Sender<io::Result<()>>,
+    }
+
+    /// A request that has been submitted.
+    struct InFlight {
+        /// Keeps the file and the buffers alive while the kernel is using them.
+        request: Request,
+        /// The number of entries that haven't completed.
+        remaining: usize,
+        /// The first error for the entries.  The entries linked after a failure are canceled.
+        error: Option<io::Error>,
+    }
+
+    /// Submits the writes and syncs to an io_uring owned by its own thread.
+    pub(crate) struct UringFlusher {
+        requests: Option<Sender<Request>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl UringFlusher {
+        /// Creates the ring and starts the thread.
+        /// # Returns
+        /// The flusher or an `Unsupported` error if the kernel doesn't have the operations.
+        pub(crate) fn new() -> io::Result<Self> {
+            let ring = IoUring::new(RING_ENTRIES)?;
+            let mut probe = Probe::new();
+            ring.submitter().register_probe(&mut probe)?;
+            let supported = [
+                opcode::Write::CODE,
+                opcode::SyncFileRange::CODE,
+                opcode::Fsync::CODE,
+            ]
+            .iter()
+            .all(|code| probe.is_supported(*code));
+            if !supported {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "The kernel doesn't support the io_uring operations to flush.",
+                ));
+            }
+            let (sender, receiver) = channel();
+            let thread = thread::Builder::new()
+                .name("a19-uring-flusher".to_owned())
+                .spawn(move || run(ring, receiver))?;
+            Ok(UringFlusher {
+                requests: Some(sender),
+                thread: Some(thread),
+            })
+        }
+
+        /// Syncs the data of a file to disk.
+        /// # Arguments
+        /// `file` - The file to sync.
+        /// # Returns
+        /// The future that completes once the data is on disk.
+        pub(crate) fn sync(&self, file: &File) -> io::Result<QueueFuture<io::Result<()>>> {
+            self.submit(Arc::new(file.try_clone()?), vec![Op::SyncRange, Op::Fsync])
+        }
+
+        /// Writes bytes to a file without waiting for the write.
+        /// # Arguments
+        /// `file` - The file to write to.
+        /// `offset` - The position in the file to write at.
+        /// `data` - The bytes to write.
+        /// `sync` - True to sync the file once the bytes are written.
+        /// # Returns
+        /// The future that completes once the bytes are written and synced if asked for.
+        #[allow(dead_code)]
+        pub(crate) fn write_at(
+            &self,
+            file: &Arc<File>,
+            offset: u64,
+            data: Vec<u8>,
+            sync: bool,
+        ) -> io::Result<QueueFuture<io::Result<()>>> {
+            let mut ops = vec![Op::Write { offset, data }];
+            if sync {
+                ops.push(Op::Fsync);
+            }
+            self.submit(file.clone(), ops)
+        }
+
+        fn submit(&self, file: Arc<File>, ops: Vec<Op>) -> io::Result<QueueFuture<io::Result<()>>> {
+            let (done, receiver) = oneshot::channel();
+            let request = Request { file, ops, done };
+            self.requests
+                .as_ref()
+                .and_then(|r| r.send(request).ok())
+                .ok_or_else(|| io::Error::other("The flusher has stopped."))?;
+            Ok(receiver)
+        }
+    }
+
+    impl Drop for UringFlusher {
+        fn drop(&mut self) {
+            // The thread finishes what is in flight once there are no more requests.
+            self.requests.take();
+            if let Some(thread) = self.thread.take() {
+                if thread.join().is_err() {
+                    log::error!("The io_uring flusher thread panicked.");
+                }
+            }
+        }
+    }
+
+    /// Runs the ring until the flusher is dropped.
+    /// # Arguments
+    /// `ring` - The ring to submit to.
+    /// `requests` - The requests to submit.
+    fn run(mut ring: IoUring, requests: Receiver<Request>) {
+        let mut queued = VecDeque::new();
+        let mut in_flight: HashMap<u64, InFlight> = HashMap::new();
+        let mut next_id = 0u64;
+        let mut open = true;
+        loop {
+            if in_flight.is_empty() && queued.is_empty() {
+                if !open {
+                    break;
+                }
+                // Nothing to wait on so wait for a request.
+                match requests.recv() {
+                    Ok(request) => queued.push_back(request),
+                    Err(_) => break,
+                }
+            }
+            while open {
+                match requests.try_recv() {
+                    Ok(request) => queued.push_back(request),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => open = false,
+                }
+            }
+            while let Some(request) = queued.front() {
+                let available = {
+                    let submission = ring.submission();
+                    submission.capacity() - submission.len()
+                };
+                if request.ops.len() > available {
+                    if in_flight.is_empty() {
+                        let request = queued.pop_front().unwrap();
+                        let _ = request.done.send(Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Too many operations in the request.",
+                        )));
+                        continue;
+                    }
+                    break;
+                }
+                let request = queued.pop_front().unwrap();
+                next_id += 1;
+                push(&mut ring, next_id, &request);
+                in_flight.insert(
+                    next_id,
+                    InFlight {
+                        remaining: request.ops.len(),
+                        request,
+                        error: None,
+                    },
+                );
+            }
+            if in_flight.is_empty() {
+                continue;
+            }
+            if let Err(e) = ring.submit_and_wait(1) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                log::error!("Unable to submit to the io_uring: {}", e);
+                break;
+            }
+            for entry in ring.completion() {
+                let id = entry.user_data() >> OP_BITS;
+                let index = (entry.user_data() & ((1 << OP_BITS) - 1)) as usize;
+                let finished = match in_flight.get_mut(&id) {
+                    Some(flight) => {
+                        flight.remaining -= 1;
+                        if flight.error.is_none() {
+                            flight.error = check(&flight.request.ops[index], entry.result());
+                        }
+                        flight.remaining == 0
+                    }
+                    None => false,
+                };
+                if finished {
+                    let flight = in_flight.remove(&id).unwrap();
+                    let result = match flight.error {
+                        Some(e) => Err(e),
+                        None => Ok(()),
+                    };
+                    let _ = flight.request.done.send(result);
+                }
+            }
+        }
+        // The ring can't be used anymore so fail what is left.
+        for (_, flight) in in_flight.drain() {
+            let _ = flight
+                .request
+                .done
+                .send(Err(io::Error::other("The flusher stopped.")));
+        }
+        for request in queued.drain(..) {
+            let _ = request
+                .done
+                .send(Err(io::Error::other("The flusher stopped.")));
+        }
+    }
+
+    /// Checks the result of an operation.
+    /// # Arguments
+    /// `op` - The operation that completed.
+    /// `result` - The result from the kernel.
+    /// # Returns
+    /// The error if the operation failed.
+    fn check(op: &Op, result: i32) -> Option<io::Error> {
+        if result < 0 {
+            Some(io::Error::from_raw_os_error(-result))
+        } else {
+            match op {
+                Op::Write { data, .. } if (result as usize) < data.len() => Some(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!("Only wrote {} of {} bytes.", result, data.len()),
+                )),
+                _ => None,
+            }
+        }
+    }
+
+    /// Pushes the entries for a request.  Each entry is linked to the next so they run in order.
+    /// # Arguments
+    /// `ring` - The ring to push to.  Needs room for all of the entries.
+    /// `id` - The id of the request the completions are for.
+    /// `request` - The request to push.
+    fn push(ring: &mut IoUring, id: u64, request: &Request) {
+        let fd = types::Fd(request.file.as_raw_fd());
+        let last = request.ops.len() - 1;
+        let mut submission = ring.submission();
+        for (i, op) in request.ops.iter().enumerate() {
+            let entry = match op {
+                Op::Write { offset, data } => {
+                    opcode::Write::new(fd, data.as_ptr(), data.len() as u32)
+                        .offset(*offset)
+                        .build()
+                }
+                Op::SyncRange => opcode::SyncFileRange::new(fd, 0)
+                    .flags(libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE)
+                    .build(),
+                Op::Fsync => opcode::Fsync::new(fd)
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build(),
+            };
+            let entry = if i < last {
+                entry.flags(squeue::Flags::IO_LINK)
+            } else {
+                entry
+            };
+            // The buffers and the file are kept in the request until it completes.
+            unsafe {
+                submission
+                    .push(&entry.user_data(id << OP_BITS | i as u64))
+                    .expect("Checked there is room in the submission queue.");
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux", feature = "uring"))]
+mod tests {
+
+    use crate::file::header::FILE_HEADER_SIZE;
+    use crate::file::MessageFileStore;
+    use crate::raft::flusher::uring::UringFlusher;
+    use crate::raft::flusher::*;
+    use crate::raft::recover_transaction;
+    use crate::testing::{crash_seed, seeded_rng, Crash, FaultyStore};
+    use futures::executor::block_on;
+    use rand::Rng;
+    use std::fs::{create_dir_all, read, remove_dir_all, File, OpenOptions};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_uring";
+
+    fn clean(dir: &str) {
+        let path = Path::new(dir);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(dir).unwrap();
+        }
+        create_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn appends_test() {
+        let flusher = match UringFlusher::new() {
+            Ok(flusher) => flusher,
+            // The kernel doesn't support io_uring so there isn't anything to test.
+            Err(_) => return,
+        };
+        let dir = format!("{}_appends", TEST_DIR);
+        clean(&dir);
+        let path = format!("{}/appends", dir);
+        let file = Arc::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap(),
+        );
+        let count = 100_000u64;
+        let done: Vec<_> = (0..count)
+            .map(|i| {
+                let mut block = vec![0u8; 16];
+                block[..8].copy_from_slice(&i.to_be_bytes());
+                flusher
+                    .write_at(&file, i * 16, block, i == count - 1)
+                    .unwrap()
+            })
+            .collect();
+        for d in done {
+            block_on(d).unwrap().unwrap();
+        }
+        let bytes = read(&path).unwrap();
+        assert_eq!(count as usize * 16, bytes.len());
+        for (i, block) in bytes.chunks(16).enumerate() {
+            let mut id = [0; 8];
+            id.copy_from_slice(&block[..8]);
+            assert_eq!(i as u64, u64::from_be_bytes(id));
+        }
+    }
+
+    /// Writes random messages flushing them with the flusher and crashes.
+    /// # Returns
+    /// The id of the last message recovered and the bytes of the file before the crash.  The
+    /// header isn't included since it has when the file was created.
+    fn crash(flusher: &Flusher, dir: &str, seed: u64) -> Vec<(u64, Vec<u8>)> {
+        let crashed_dir = format!("{}_crashed", dir);
+        let mut rng = seeded_rng(seed);
+        let mut results = Vec::new();
+        for _ in 0..20 {
+            clean(dir);
+            clean(&crashed_dir);
+            let path = format!("{}/events", dir);
+            let (_, writer) = unsafe { MessageFileStore::new(&path, 0x10000).unwrap() };
+            let mut store = FaultyStore::wrap(&path, writer).unwrap();
+            let mut pos = 0;
+            for id in 1..rng.gen_range(2, 40) {
+                let body: Vec<u8> = (0..rng.gen_range(1, 200)).map(|_| rng.gen()).collect();
+                pos = store.write(pos, 1, id, &body).unwrap();
+                if rng.gen_bool(0.3) {
+                    store.flush_with(flusher).unwrap();
+                }
+            }
+            let crash = match rng.gen_range(0, 3) {
+                0 => Crash::LoseUnflushed,
+                1 => Crash::KeepWrites(rng.gen_range(0, store.unflushed_writes() + 1)),
+                _ => Crash::TornLastWrite,
+            };
+            let crashed = store.materialize(&crashed_dir, crash, &mut rng).unwrap();
+            let recovered = recover_transaction(crashed.to_str().unwrap()).unwrap();
+            store.flush_with(flusher).unwrap();
+            results.push((recovered, read(&path).unwrap()[FILE_HEADER_SIZE..].to_vec()));
+        }
+        results
+    }
+
+    #[test]
+    pub fn crash_parity_test() {
+        let uring = Flusher::new();
+        // The kernel doesn't support io_uring so there isn't anything to compare.
+        if !uring.is_uring() {
+            return;
+        }
+        let seed = crash_seed();
+        let expected = crash(&Flusher::Thread, &format!("{}_thread", TEST_DIR), seed);
+        let found = crash(&uring, &format!("{}_ring", TEST_DIR), seed);
+        assert_eq!(expected, found, "Seed {}.", seed);
+        // The flush reached the file.
+        let file = File::open(format!("{}_ring/events", TEST_DIR)).unwrap();
+        assert_eq!(
+            (FILE_HEADER_SIZE + 0x10000) as u64,
+            file.metadata().unwrap().len()
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Follows a storage directory owned by a writer in another process so the committed messages can
+//! be read without going through the writer.  The commit files are mapped read only and polled for
+//! new terms and the directory is refreshed to pick up the files the writer rolls over to or
+//! removes.  All of the writes to a follower are rejected with `Error::ReadOnly`.
+use crate::error::{PersistError, ResultExt};
+use crate::file::header::{header_error, FileHeader, FileType, FILE_HEADER_SIZE};
+use crate::raft::backlog::Backlog;
+use crate::raft::events::StoreEvents;
+use crate::raft::flusher::Flusher;
+use crate::raft::latency::StoreLatency;
+use crate::raft::tail::CommitSignal;
+use crate::raft::{
+    create_commit_name, term_checksum, AddMessageWriteRs, CommitQueue, FileCollection,
+    PersistedMessageFile, PositionIndex, RecoveredPosition, CHECKSUM, COMMITTED, COMMIT_SIZE,
+    MAX_MESSAGE_ID, TERM_ID_OFFSET,
+};
+use a19_concurrent::buffer::ring_buffer::create_many_to_one;
+use a19_concurrent::queue::mpsc_queue::MpscQueueWrap;
+use a19_concurrent::threads::{spawn_configured, ThreadConfig};
+use a19_core::clock::system_clock;
+use byteorder::{BigEndian, ByteOrder};
+use memmap::{Mmap, MmapOptions};
+use std::collections::HashMap;
+use std::fs::{metadata, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often to check the commit files for new terms.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// How often to scan the directory for new and removed files.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(10);
+/// The size of the incoming buffer.  Nothing is written to it since the writes are rejected.
+const FOLLOWER_BUFFER_SIZE: usize = 0x100;
+
+/// A commit file mapped read only.
+struct CommitView {
+    file_id: u32,
+    mmap: Mmap,
+}
+
+impl CommitView {
+    /// Maps a commit file.
+    /// # Arguments
+    /// `path` - The path to the commit file.
+    /// `file_id` - The id of the file.
+    fn open(path: &str, file_id: u32) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        FileHeader::from_bytes(&mmap)
+            .and_then(|header| header.expect(FileType::Commit))
+            .map_err(header_error)?;
+        Ok(CommitView { file_id, mmap })
+    }
+
+    fn has_term(&self, pos: usize) -> bool {
+        FILE_HEADER_SIZE + pos + COMMIT_SIZE as usize <= self.mmap.len()
+    }
+
+    fn term(&self, pos: usize) -> u64 {
+        let pos = FILE_HEADER_SIZE + TERM_ID_OFFSET + pos;
+        BigEndian::read_u64(&self.mmap[pos..pos + 8])
+    }
+
+    fn committed(&self, pos: usize) -> bool {
+        let pos = FILE_HEADER_SIZE + COMMITTED + pos;
+        BigEndian::read_u16(&self.mmap[pos..pos + 2]) > 0
+    }
+
+    fn max_message_id(&self, pos: usize) -> u64 {
+        let pos = FILE_HEADER_SIZE + MAX_MESSAGE_ID + pos;
+        BigEndian::read_u64(&self.mmap[pos..pos + 8])
+    }
+
+    /// Checks the term was completely written by the writer.
+    fn verify(&self, pos: usize) -> bool {
+        let start = FILE_HEADER_SIZE + pos;
+        let checksum = BigEndian::read_u16(&self.mmap[start + CHECKSUM..start + CHECKSUM + 2]);
+        checksum == term_checksum(&self.mmap[start..start + COMMIT_SIZE as usize])
+    }
+}
+
+/// Reads through the terms committed by the writer.
+struct CommitFollower {
+    /// The commit files ordered by their id.
+    files: Vec<CommitView>,
+    /// The index of the file we are reading the terms from.
+    current: usize,
+    /// The position of the next term to check in the current file.
+    pos: usize,
+    max_message_id: u64,
+}
+
+impl CommitFollower {
+    fn new() -> Self {
+        CommitFollower {
+            files: Vec::new(),
+            current: 0,
+            pos: 0,
+            max_message_id: 0,
+        }
+    }
+
+    /// Adds a commit file the writer created.
+    /// # Arguments
+    /// `file` - The file to add.
+    fn add_file(&mut self, file: CommitView) {
+        let index = self
+            .files
+            .iter()
+            .position(|f| f.file_id > file.file_id)
+            .unwrap_or(self.files.len());
+        if index < self.current || (index == self.current && self.pos > 0) {
+            // Already read past it.
+            self.current += 1;
+        }
+        self.files.insert(index, file);
+    }
+
+    /// Removes a commit file that was deleted by the writer.  The file we are reading from is kept
+    /// mapped until we move past it.
+    /// # Arguments
+    /// `file_id` - The id of the file that was removed.
+    fn remove_file(&mut self, file_id: u32) {
+        if let Some(index) = self.files.iter().position(|f| f.file_id == file_id) {
+            if index < self.current {
+                self.files.remove(index);
+                self.current -= 1;
+            }
+        }
+    }
+
+    /// Reads in the terms committed since the last poll.
+    /// # Returns
+    /// The id of the last committed message.
+    fn poll(&mut self) -> u64 {
+        while let Some(file) = self.files.get(self.current) {
+            if !file.has_term(self.pos) {
+                if self.current + 1 < self.files.len() {
+                    self.current += 1;
+                    self.pos = 0;
+                    continue;
+                }
+                break;
+            }
+            if file.term(self.pos) == 0 || !file.committed(self.pos) || !file.verify(self.pos) {
+                break;
+            }
+            self.max_message_id = self.max_message_id.max(file.max_message_id(self.pos));
+            self.pos += COMMIT_SIZE as usize;
+        }
+        self.max_message_id
+    }
+
+    /// Maps the commit files that were added.
+    /// # Arguments
+    /// `collection` - The files in the directory.
+    /// `file_ids` - The ids of the commit files that were added.
+    fn add_files(&mut self, collection: &FileCollection, file_ids: &[u32]) {
+        for file_id in file_ids {
+            let path = create_commit_name(
+                &collection.file_storage_directory,
+                &collection.file_prefix,
+                file_id,
+            );
+            match CommitView::open(&path, *file_id) {
+                Ok(view) => self.add_file(view),
+                Err(e) => log::error!("Unable to map the commit file {}: {}", path, e),
+            }
+        }
+    }
+}
+
+impl PersistedMessageFile {
+    /// Opens a storage directory that is owned by a writer in another process.  The committed
+    /// messages can be read as they are committed by the writer, but all of the writes are
+    /// rejected.
+    /// # Arguments
+    /// `file_storage_directory` - The directory the writer stores the files in.
+    /// `file_prefix` - The prefix for the files.
+    pub fn open_follower(
+        file_storage_directory: &str,
+        file_prefix: &str,
+    ) -> crate::Result<PersistedMessageFile> {
+        PersistedMessageFile::open_follower_with_thread(file_storage_directory, file_prefix, None)
+    }
+
+    /// Opens a storage directory that is owned by a writer in another process with the settings
+    /// for the thread that watches for the commits.
+    /// # Arguments
+    /// `file_storage_directory` - The directory the writer stores the files in.
+    /// `file_prefix` - The prefix for the files.
+    /// `thread` - The settings for the thread that watches the commit files.
+    pub fn open_follower_with_thread(
+        file_storage_directory: &str,
+        file_prefix: &str,
+        thread: Option<ThreadConfig>,
+    ) -> crate::Result<PersistedMessageFile> {
+        if !Path::new(file_storage_directory).is_dir() {
+            return Err(PersistError::invalid_config("The storage directory doesn't exist.")
+                .with_path(file_storage_directory));
+        }
+        let mut collection =
+            FileCollection::new(file_storage_directory.to_owned(), file_prefix.to_owned());
+        let delta = collection.refresh()?;
+        let mut commits = CommitFollower::new();
+        commits.add_files(&collection, &delta.added_commit_files);
+        let max_message_id = Arc::new(AtomicU64::new(commits.poll()));
+        let max_file_size = match collection.message_files.lock().unwrap().first() {
+            Some(file) => (metadata(&file.path).with_path(&file.path)?.len() as usize)
+                .saturating_sub(FILE_HEADER_SIZE),
+            None => 0,
+        };
+        let followed_files = collection.message_files.clone();
+        let commit_files = collection.commit_files.clone();
+        let message_files = followed_files.clone();
+        let stop = Arc::new(AtomicU8::new(0));
+        let commit_signal = Arc::new(CommitSignal::default());
+        let commit_join = Some(follow_thread(
+            stop.clone(),
+            collection,
+            commits,
+            max_message_id.clone(),
+            commit_signal.clone(),
+            ThreadConfig::or_named(thread, "a19-follower"),
+        ));
+        let (_, incoming_writer) = create_many_to_one(FOLLOWER_BUFFER_SIZE);
+        let (incoming_queue_writer, _) = MpscQueueWrap::<AddMessageWriteRs>::new(1);
+        Ok(PersistedMessageFile {
+            max_file_size,
+            commit_join,
+            writer_join: None,
+            reader_join: None,
+            stop,
+            incoming_writer,
+            incoming_queue_writer,
+            max_message_id: max_message_id.clone(),
+            written_message_id: max_message_id,
+            file_storage_directory: file_storage_directory.to_owned(),
+            file_prefix: file_prefix.to_owned(),
+            event_readers: Mutex::new(HashMap::new()),
+            backlog: Arc::new(Backlog::new()),
+            flush_writer: Mutex::new(None),
+            flusher: Flusher::new(),
+            followed_files: Some(followed_files),
+            message_files,
+            commit_files,
+            clock: system_clock(),
+            latency: Arc::new(StoreLatency::new(system_clock())),
+            positions: Arc::new(PositionIndex::new()),
+            events: Arc::new(StoreEvents::default()),
+            commit_queue: Arc::new(CommitQueue::new(1)),
+            commit_signal,
+            flush_count: Arc::new(AtomicU64::new(0)),
+            commit_flush_file: Mutex::new(1),
+            recovered: RecoveredPosition::default(),
+            closing: AtomicBool::new(false),
+            closed: false,
+        })
+    }
+
+    /// True if the store is following a writer in another process.
+    pub fn is_follower(&self) -> bool {
+        self.followed_files.is_some()
+    }
+}
+
+/// Polls the commit files and the directory until the follower is stopped.
+/// # Arguments
+/// `stop` - Set when the follower is stopped.
+/// `collection` - The files in the directory.
+/// `commits` - The terms read in so far.
+/// `max_message_id` - The id of the last committed message.
+/// `commit_signal` - Wakes the tail readers when the writer commits more messages.
+/// `thread` - The settings for the thread.
+fn follow_thread(
+    stop: Arc<AtomicU8>,
+    mut collection: FileCollection,
+    mut commits: CommitFollower,
+    max_message_id: Arc<AtomicU64>,
+    commit_signal: Arc<CommitSignal>,
+    thread: ThreadConfig,
+) -> JoinHandle<u32> {
+    spawn_configured(thread, move || {
+        let mut last_refresh = Instant::now();
+        loop {
+            if stop.load(Ordering::Acquire) > 0 {
+                break 0;
+            }
+            if last_refresh.elapsed() >= REFRESH_INTERVAL {
+                last_refresh = Instant::now();
+                match collection.refresh() {
+                    Ok(delta) => {
+                        for file_id in delta.removed_commit_files.iter() {
+                            commits.remove_file(*file_id);
+                        }
+                        commits.add_files(&collection, &delta.added_commit_files);
+                    }
+                    Err(e) => log::error!("Unable to refresh the files: {}", e),
+                }
+            }
+            let committed = commits.poll();
+            if max_message_id.swap(committed, Ordering::AcqRel) != committed {
+                commit_signal.notify();
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    })
+    .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::file::MessageRead;
+    use crate::PersistError;
+    use crate::raft::*;
+    use crate::PersitEventStream;
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_follower";
+    const TEST_PREFIX: &str = "test_follower";
+    const MESSAGES: u64 = 2000;
+
+    struct NoProcessor;
+
+    impl InfallibleProcessor for NoProcessor {
+        fn handle<'a>(&mut self, _read: &MessageRead<'a>) {}
+    }
+
+    #[test]
+    pub fn follower_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let writer = startup_single_node(
+            TEST_DIR.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x100000,
+            NoProcessor,
+            0x40000,
+            0x4000,
+        );
+        block_on(writer.add_change(&0u64.to_le_bytes())).unwrap();
+        let mut follower = PersistedMessageFile::open_follower(TEST_DIR, TEST_PREFIX).unwrap();
+        assert!(follower.is_follower());
+        match block_on(follower.write(1, &[1])) {
+            Ok(Err(PersistError::ReadOnly { .. })) => {}
+            _ => panic!("The follower shouldn't accept writes."),
+        }
+
+        let writer_thread = thread::spawn(move || {
+            for i in 1..MESSAGES {
+                block_on(writer.add_change(&i.to_le_bytes())).unwrap();
+            }
+            writer
+        });
+        let mut read = Vec::new();
+        let mut events = follower.events_from(1);
+        let started = Instant::now();
+        while (read.len() as u64) < MESSAGES {
+            match events.next() {
+                Some(event) => {
+                    let mut value = [0; 8];
+                    value.copy_from_slice(&event.value[..8]);
+                    read.push((event.commit_key, u64::from_le_bytes(value)));
+                }
+                None => {
+                    assert!(
+                        started.elapsed() < Duration::from_secs(30),
+                        "Only read {} messages.",
+                        read.len()
+                    );
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+        drop(events);
+        let mut writer = writer_thread.join().unwrap();
+        // Each message is seen once and in order.
+        let expected: Vec<(u64, u64)> = (0..MESSAGES).map(|i| (i + 1, i)).collect();
+        assert_eq!(expected, read);
+        assert!(follower.events_from(MESSAGES + 1).next().is_none());
+        writer.stop();
+        follower.stop();
+    }
+}
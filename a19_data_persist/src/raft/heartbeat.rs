@@ -0,0 +1,286 @@
+//! Sends the heartbeats from the leader so the followers know it is still alive and how far the
+//! messages have been committed.  Each follower acks the heartbeats it receives, a follower that
+//! misses too many in a row is reported as failed and once there aren't enough followers left for
+//! a quorum the leader is told to step down.
+//!
+//! ```text
+//!  tick(term) --every interval--> Heartbeat { term, leader_id, max_message_id } --> transport
+//!                                                                                      |
+//!  RaftNodeEvent::HeartbeatFailed / QuorumLost <-- missed >= max_missed <-- ack(server_id)
+//! ```
+use crate::raft::node_state::RaftNodeEvent;
+use a19_core::clock::Clock;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The default time between the heartbeats.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// The default number of heartbeats a follower can miss before it is reported.
+pub const DEFAULT_MAX_MISSED: u32 = 3;
+
+/// An empty append sent to a follower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// The term of the leader.
+    pub term: u64,
+    /// The id of the leader.
+    pub leader_id: u32,
+    /// The id of the last message committed.
+    pub max_message_id: u64,
+}
+
+/// Where the heartbeats are sent.
+pub trait HeartbeatTransport: Send {
+    /// Sends a heartbeat to a follower.
+    /// # Arguments
+    /// `server_id` - The id of the follower.
+    /// `heartbeat` - The heartbeat to send.
+    /// # Returns
+    /// True if the heartbeat was sent.
+    fn send_heartbeat(&mut self, server_id: u32, heartbeat: Heartbeat) -> bool;
+}
+
+/// How often the heartbeats are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// The time between the heartbeats.
+    pub interval: Duration,
+    /// The number of heartbeats in a row a follower can miss before it is reported.
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            max_missed: DEFAULT_MAX_MISSED,
+        }
+    }
+}
+
+/// A follower the heartbeats are sent to.
+#[derive(Debug, Default)]
+struct Follower {
+    /// The heartbeats sent since the last ack.
+    missed: u32,
+    /// Set once the follower has been reported so it is only reported once.
+    failed: bool,
+}
+
+/// Sends the heartbeats while we are the leader.
+pub struct HeartbeatSender {
+    /// The id of the leader.
+    leader_id: u32,
+    /// The followers by their id.
+    followers: BTreeMap<u32, Follower>,
+    /// The id of the last message committed.
+    max_message_id: Arc<AtomicU64>,
+    /// Where the heartbeats are sent.
+    transport: Box<dyn HeartbeatTransport>,
+    /// How often the heartbeats are sent.
+    config: HeartbeatConfig,
+    /// Where the time comes from.
+    clock: Arc<dyn Clock>,
+    /// The monotonic time the last heartbeats were sent.
+    last_sent_ns: Option<u64>,
+    /// Set once the quorum has been reported lost.
+    quorum_lost: bool,
+}
+
+impl HeartbeatSender {
+    /// Creates the sender.  The first heartbeats are sent on the first tick.
+    /// # Arguments
+    /// `leader_id` - The id of the leader.
+    /// `followers` - The ids of the voting followers.
+    /// `max_message_id` - The id of the last message committed.
+    /// `transport` - Where the heartbeats are sent.
+    /// `config` - How often the heartbeats are sent.
+    /// `clock` - Where the time comes from.
+    pub fn new(
+        leader_id: u32,
+        followers: &[u32],
+        max_message_id: Arc<AtomicU64>,
+        transport: Box<dyn HeartbeatTransport>,
+        config: HeartbeatConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        HeartbeatSender {
+            leader_id,
+            followers: followers
+                .iter()
+                .map(|id| (*id, Follower::default()))
+                .collect(),
+            max_message_id,
+            transport,
+            config,
+            clock,
+            last_sent_ns: None,
+            quorum_lost: false,
+        }
+    }
+
+    /// Sends the heartbeats if the interval has passed since the last ones.
+    /// # Arguments
+    /// `term` - The current term.
+    /// # Returns
+    /// The followers that failed and `QuorumLost` if there aren't enough of them left.
+    pub fn tick(&mut self, term: u64) -> Vec<RaftNodeEvent> {
+        let now = self.clock.monotonic_ns();
+        let due = match self.last_sent_ns {
+            Some(last) => now.saturating_sub(last) >= self.config.interval.as_nanos() as u64,
+            None => true,
+        };
+        if due {
+            self.send(term)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Sends the heartbeats right away.
+    /// # Arguments
+    /// `term` - The current term.
+    /// # Returns
+    /// The followers that failed and `QuorumLost` if there aren't enough of them left.
+    pub fn send(&mut self, term: u64) -> Vec<RaftNodeEvent> {
+        self.last_sent_ns = Some(self.clock.monotonic_ns());
+        let heartbeat = Heartbeat {
+            term,
+            leader_id: self.leader_id,
+            max_message_id: self.max_message_id.load(Ordering::Acquire),
+        };
+        let mut events = Vec::new();
+        for (server_id, follower) in self.followers.iter_mut() {
+            if follower.missed >= self.config.max_missed && !follower.failed {
+                follower.failed = true;
+                events.push(RaftNodeEvent::HeartbeatFailed {
+                    server_id: *server_id,
+                });
+            }
+            self.transport.send_heartbeat(*server_id, heartbeat);
+            follower.missed = follower.missed.saturating_add(1);
+        }
+        if self.live() < self.quorum() {
+            if !self.quorum_lost {
+                self.quorum_lost = true;
+                events.push(RaftNodeEvent::QuorumLost);
+            }
+        } else {
+            self.quorum_lost = false;
+        }
+        events
+    }
+
+    /// Records the ack of a heartbeat.  A follower that failed can be reported again once it
+    /// misses more heartbeats.
+    /// # Arguments
+    /// `server_id` - The id of the follower.
+    pub fn ack(&mut self, server_id: u32) {
+        if let Some(follower) = self.followers.get_mut(&server_id) {
+            follower.missed = 0;
+            follower.failed = false;
+        }
+    }
+
+    /// The number of servers that are answering the heartbeats including the leader.
+    pub fn live(&self) -> usize {
+        1 + self.followers.values().filter(|f| !f.failed).count()
+    }
+
+    /// The number of servers needed for a quorum.
+    pub fn quorum(&self) -> usize {
+        let voters = self.followers.len() + 1;
+        voters / 2 + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::raft::heartbeat::*;
+    use a19_core::clock::ManualClock;
+    use std::sync::Mutex;
+
+    /// Keeps the heartbeats instead of sending them.
+    struct MemoryTransport {
+        sent: Arc<Mutex<Vec<(u32, Heartbeat)>>>,
+    }
+
+    impl HeartbeatTransport for MemoryTransport {
+        fn send_heartbeat(&mut self, server_id: u32, heartbeat: Heartbeat) -> bool {
+            self.sent.lock().unwrap().push((server_id, heartbeat));
+            true
+        }
+    }
+
+    #[test]
+    pub fn dead_follower_test() {
+        let clock = Arc::new(ManualClock::new(0));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let max_message_id = Arc::new(AtomicU64::new(10));
+        let mut sender = HeartbeatSender::new(
+            1,
+            &[2, 3],
+            max_message_id.clone(),
+            Box::new(MemoryTransport { sent: sent.clone() }),
+            HeartbeatConfig::default(),
+            clock.clone(),
+        );
+        let count = |server_id: u32| {
+            sent.lock()
+                .unwrap()
+                .iter()
+                .filter(|(id, _)| *id == server_id)
+                .count()
+        };
+
+        // Server 3 is dead.
+        let mut events = Vec::new();
+        for _ in 0..10 {
+            events.extend(sender.tick(4));
+            // Not due until the interval has passed.
+            assert!(sender.tick(4).is_empty());
+            sender.ack(2);
+            clock.advance(DEFAULT_HEARTBEAT_INTERVAL);
+        }
+        assert_eq!((10, 10), (count(2), count(3)));
+        assert_eq!(
+            vec![RaftNodeEvent::HeartbeatFailed { server_id: 3 }],
+            events
+        );
+        assert_eq!(
+            Some(&(
+                3,
+                Heartbeat {
+                    term: 4,
+                    leader_id: 1,
+                    max_message_id: 10
+                }
+            )),
+            sent.lock().unwrap().last()
+        );
+
+        // Server 2 stops answering so there isn't a quorum.
+        max_message_id.store(20, Ordering::Release);
+        let mut events = Vec::new();
+        for _ in 0..5 {
+            events.extend(sender.tick(4));
+            clock.advance(DEFAULT_HEARTBEAT_INTERVAL);
+        }
+        assert_eq!(
+            vec![
+                RaftNodeEvent::HeartbeatFailed { server_id: 2 },
+                RaftNodeEvent::QuorumLost
+            ],
+            events
+        );
+        assert_eq!(20, sent.lock().unwrap().last().unwrap().1.max_message_id);
+
+        // Comes back and is reported again when it fails.
+        sender.ack(2);
+        assert!(sender.send(4).is_empty());
+        assert_eq!(2, sender.live());
+    }
+}
@@ -0,0 +1,344 @@
+//! Lets a tool look at the files the store is using without getting at the collection the writer
+//! and readers share.  The lists of files are copied while holding their locks and the files are
+//! read after they are released so the writer is only held up for the copy.
+//!
+//! ```text
+//!  events.1 [1..40]   events.2 [41..80]   events.3 [81..committed]
+//!  commit.1 [1..60]   commit.2 [61..committed]
+//! ```
+//! The first file of a new store is created before it has any messages so it starts at 1.  A
+//! message split into chunks can end in the file after the one it starts in, so an event file
+//! ends on the first message of the next file when it starts with the rest of a message.
+use crate::error::ResultExt;
+use crate::file::chunk::{ChunkHeader, CHUNK_MESSAGE_TYPE};
+use crate::file::header::{open_commit_file, FileHeader, FileType, FILE_HEADER_SIZE};
+use crate::raft::{CommitFile, PersistedMessageFile, COMMIT_SIZE};
+use a19_concurrent::buffer::DirectByteBuffer;
+use std::fs::{metadata, File, OpenOptions};
+use std::io::{self, Read};
+
+/// What is in one of the files of the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSummary {
+    /// If the file has the messages or the terms.
+    pub kind: FileType,
+    /// The path to the file.
+    pub path: String,
+    /// The id of the file from its name.
+    pub file_id: u32,
+    /// The id of the first message in the file.
+    pub first_message_id: u64,
+    /// The id of the last committed message in the file.  Is one less than the first id when none
+    /// of the messages in the file have been committed yet.
+    pub last_message_id: u64,
+    /// The size of the file in bytes including the header.
+    pub size: u64,
+    /// When the file was created in milliseconds since the epoch from the file header.
+    pub created_at: u64,
+}
+
+impl PersistedMessageFile {
+    /// Lists the event files and then the commit files the store is using.  The files the
+    /// retention removes while they are being read are left out.
+    /// # Returns
+    /// A summary of each file in the order of their ids.
+    pub fn files(&self) -> crate::Result<Vec<FileSummary>> {
+        let message_files: Vec<(u32, String, u64)> = self
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|f| !f.removed)
+            .map(|f| (f.file_id, f.path.clone(), f.message_id_start.max(1)))
+            .collect();
+        let commit_files: Vec<(u32, String)> = self
+            .commit_files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| (f.file_id, f.path.clone()))
+            .collect();
+        let committed = self.max_message_id();
+        let mut summaries = Vec::with_capacity(message_files.len() + commit_files.len());
+        for (i, (file_id, path, first_message_id)) in message_files.iter().enumerate() {
+            let last_message_id = match message_files.get(i + 1) {
+                Some((next_id, next_path, next_start)) => {
+                    if self.starts_with_chunk(*next_id, next_path)? {
+                        *next_start
+                    } else {
+                        next_start.saturating_sub(1)
+                    }
+                }
+                None => committed.max(first_message_id.saturating_sub(1)),
+            };
+            if let Some(summary) = summary(
+                FileType::Event,
+                path,
+                *file_id,
+                *first_message_id,
+                last_message_id,
+            )? {
+                summaries.push(summary);
+            }
+        }
+        let mut previous = None;
+        for (file_id, path) in commit_files {
+            let (first_message_id, last_message_id) =
+                match self.term_range(&path, previous, committed)? {
+                    Some(range) => range,
+                    None => continue,
+                };
+            previous = Some(last_message_id);
+            if let Some(summary) = summary(
+                FileType::Commit,
+                &path,
+                file_id,
+                first_message_id,
+                last_message_id,
+            )? {
+                summaries.push(summary);
+            }
+        }
+        Ok(summaries)
+    }
+
+    /// The id of the first message in the oldest event file the retention hasn't removed.
+    /// # Returns
+    /// The id of the message or 0 if there aren't any files yet.
+    pub fn earliest_message_id(&self) -> u64 {
+        self.message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|f| !f.removed)
+            .map(|f| f.message_id_start.max(1))
+            .unwrap_or(0)
+    }
+
+    /// The id of the last message that has been committed.
+    pub fn latest_committed_message_id(&self) -> u64 {
+        self.max_message_id()
+    }
+
+    /// Checks if an event file starts with a chunk of a message started in the file before it.
+    /// # Arguments
+    /// `file_id` - The id of the event file.
+    /// `path` - The path to the event file.
+    fn starts_with_chunk(&self, file_id: u32, path: &str) -> crate::Result<bool> {
+        let reader = match self.event_reader(file_id) {
+            Some(reader) => reader,
+            None => return Ok(false),
+        };
+        let msg = reader.read_new(0).with_path(path)?;
+        Ok(msg.msg_type_id() == CHUNK_MESSAGE_TYPE
+            && ChunkHeader::read(msg.bytes())
+                .map(|chunk| chunk.index > 0)
+                .unwrap_or(false))
+    }
+
+    /// Finds the messages the terms in a commit file cover.
+    /// # Arguments
+    /// `path` - The path to the commit file.
+    /// `previous` - The id of the last message in the commit file before it.
+    /// `committed` - The id of the last message committed.
+    /// # Returns
+    /// The ids of the first and last messages or `None` if the file was removed.  The first id is
+    /// read from the event file the first term starts in, if that file has been removed the id of
+    /// the last message of the first term is used.
+    fn term_range(
+        &self,
+        path: &str,
+        previous: Option<u64>,
+        committed: u64,
+    ) -> crate::Result<Option<(u64, u64)>> {
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_path(path),
+        };
+        let buffer = unsafe { open_commit_file(file) }.with_path(path)?;
+        let terms = (buffer.capacity() - FILE_HEADER_SIZE) / COMMIT_SIZE as usize;
+        let mut last = None;
+        for i in 0..terms {
+            let pos = i * COMMIT_SIZE as usize;
+            if buffer.term(pos) == 0 || !buffer.verify(pos) {
+                break;
+            }
+            last = Some(buffer.max_message_id(pos).min(committed));
+        }
+        let last = match last {
+            Some(last) => last,
+            None => return Ok(Some((previous.unwrap_or(0) + 1, previous.unwrap_or(0)))),
+        };
+        let first = match previous {
+            Some(previous) => previous + 1,
+            None => match self.event_reader(buffer.file_id(0)) {
+                Some(reader) => {
+                    reader
+                        .read_link(buffer.file_position_offset(0) as usize)
+                        .with_path(path)?
+                        .0
+                }
+                None => buffer.max_message_id(0),
+            },
+        };
+        Ok(Some((first, last)))
+    }
+}
+
+/// Reads the size and header of a file.
+/// # Arguments
+/// `kind` - What is in the file.
+/// `path` - The path to the file.
+/// `file_id` - The id of the file.
+/// `first_message_id` - The id of the first message in the file.
+/// `last_message_id` - The id of the last message in the file.
+/// # Returns
+/// The summary or `None` if the file has been removed.
+fn summary(
+    kind: FileType,
+    path: &str,
+    file_id: u32,
+    first_message_id: u64,
+    last_message_id: u64,
+) -> crate::Result<Option<FileSummary>> {
+    let mut bytes = vec![0; FILE_HEADER_SIZE];
+    let size = match metadata(path).and_then(|m| {
+        File::open(path)?.read_exact(&mut bytes)?;
+        Ok(m.len())
+    }) {
+        Ok(size) => size,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_path(path),
+    };
+    let header = FileHeader::from_bytes(&bytes)
+        .and_then(|h| h.expect(kind))
+        .with_path(path)?;
+    Ok(Some(FileSummary {
+        kind,
+        path: path.to_owned(),
+        file_id,
+        first_message_id,
+        last_message_id,
+        size,
+        created_at: header.created_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::raft::builder::PersistedMessageFileBuilder;
+    use crate::raft::inspect::*;
+    use crate::raft::{
+        create_commit_name, create_event_name, MessageProcessor, MessageRead, ProcessError,
+        RetentionPolicy,
+    };
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_inspect";
+    const TEST_PREFIX: &str = "inspect";
+
+    struct NoOp;
+
+    impl MessageProcessor for NoOp {
+        fn handle(&mut self, _read: MessageRead<'_>) -> Result<(), ProcessError> {
+            Ok(())
+        }
+    }
+
+    /// Checks the files cover the messages from `first` to `last` in order.
+    fn assert_covers(files: &[&FileSummary], first: u64, last: u64) {
+        assert_eq!(first, files[0].first_message_id);
+        assert_eq!(last, files.last().unwrap().last_message_id);
+        for pair in files.windows(2) {
+            assert!(pair[0].file_id < pair[1].file_id);
+            assert_eq!(pair[0].last_message_id + 1, pair[1].first_message_id);
+            assert!(pair[0].created_at <= pair[1].created_at);
+        }
+        for file in files {
+            assert_eq!(metadata(&file.path).unwrap().len(), file.size);
+            assert!(file.created_at > 0);
+        }
+    }
+
+    #[test]
+    pub fn files_test() {
+        let dir = format!("{}/files", TEST_DIR);
+        if Path::new(&dir).exists() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let store = PersistedMessageFileBuilder::new(&dir, TEST_PREFIX)
+            .max_file_size(0x400)
+            .commit_file_size(COMMIT_SIZE as usize * 4)
+            .build(NoOp)
+            .unwrap();
+        block_on(store.write(1, &1u64.to_le_bytes()))
+            .unwrap()
+            .unwrap();
+        let files = store.files().unwrap();
+        assert_eq!(
+            vec![
+                (
+                    FileType::Event,
+                    create_event_name(&dir, TEST_PREFIX, &1),
+                    1,
+                    1,
+                    1
+                ),
+                (
+                    FileType::Commit,
+                    create_commit_name(&dir, TEST_PREFIX, &1),
+                    1,
+                    1,
+                    1
+                )
+            ],
+            files
+                .iter()
+                .map(|f| (
+                    f.kind,
+                    f.path.clone(),
+                    f.file_id,
+                    f.first_message_id,
+                    f.last_message_id
+                ))
+                .collect::<Vec<_>>()
+        );
+
+        // Rolls over to more event and commit files.
+        for i in 2..=200u64 {
+            block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap();
+        }
+        assert_eq!(
+            (1, 200),
+            (
+                store.earliest_message_id(),
+                store.latest_committed_message_id()
+            )
+        );
+        let files = store.files().unwrap();
+        let events: Vec<&FileSummary> =
+            files.iter().filter(|f| f.kind == FileType::Event).collect();
+        let commits: Vec<&FileSummary> = files
+            .iter()
+            .filter(|f| f.kind == FileType::Commit)
+            .collect();
+        assert!(events.len() > 4 && commits.len() > 1);
+        assert_covers(&events, 1, 200);
+        assert_covers(&commits, 1, 200);
+
+        // The removed files are left out.
+        store
+            .apply_retention(RetentionPolicy::KeepFiles(2))
+            .unwrap();
+        let files = store.files().unwrap();
+        let events: Vec<&FileSummary> =
+            files.iter().filter(|f| f.kind == FileType::Event).collect();
+        assert_eq!(2, events.len());
+        assert_eq!(store.earliest_message_id(), events[0].first_message_id);
+        assert_covers(&events, events[0].first_message_id, 200);
+    }
+}
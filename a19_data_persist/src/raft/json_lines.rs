@@ -0,0 +1,653 @@
+//! Copies the committed messages out to JSON lines and writes them back into a store.  Used to
+//! look at a log while debugging or to move one to another environment.  Each line is one message
+//! with the body in base64.
+//!
+//! ```text
+//!  {"id":1,"type":1,"time_ms":1596049200000,"body":"AQIDBA=="}
+//!  {"id":2,"type":3,"time_ms":1596049200000,"body":"BQY=","headers":"AAAAAgEAAAA="}
+//! ```
+//! `time_ms` is when the term the message is in was committed.  The headers are only written when
+//! the message has them.  The messages are read and written one at a time so the log doesn't have
+//! to fit in memory.  `message_stream::export` has the binary format that is checked with a CRC,
+//! this one is meant to be read by people and other tools.
+//!
+//! An import writes the messages through the store like any other write so they get the next ids.
+//! The ids in the lines have to be the ids the store is going to give them, so a fresh store is
+//! started from an export of the whole log and a store can only be added to when the export
+//! carries on from its last message.
+use crate::error::{Context, PersistError};
+use crate::file::header::{open_commit_file, FILE_HEADER_SIZE};
+use crate::file::{MessageHeaders, USER_META_SIZE};
+use crate::message_stream::export::ExportStats;
+use crate::raft::{
+    create_commit_name, CommitFile, CommittedCursor, PersistedMessageFile, QueueFuture, COMMIT_SIZE,
+};
+use a19_concurrent::buffer::mmap_buffer::MemoryMappedInt;
+use a19_concurrent::buffer::DirectByteBuffer;
+use futures::executor::block_on;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+/// The number of imported messages that can be waiting to be committed.
+const IMPORT_WINDOW: usize = 1024;
+
+/// The characters used for base64.
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+impl PersistedMessageFile {
+    /// Writes the committed messages in a range as JSON lines.  The messages committed after the
+    /// export starts aren't included.
+    /// # Arguments
+    /// `writer` - Where to write the lines.
+    /// `from_id` - The id of the first message to export.
+    /// `to_id` - The id of the last message to export.
+    /// # Returns
+    /// What was exported or `Retained` if the retention has removed the first messages.
+    pub fn export<W: Write>(
+        &self,
+        writer: W,
+        from_id: u64,
+        to_id: u64,
+    ) -> crate::Result<ExportStats> {
+        if from_id < self.earliest_message_id() {
+            return Err(PersistError::Retained {
+                context: Context {
+                    id: Some(from_id),
+                    ..Context::default()
+                },
+            });
+        }
+        let to_id = to_id.min(self.max_message_id());
+        let mut writer = BufWriter::new(writer);
+        let mut cursor = CommittedCursor::new(from_id);
+        let mut times = TermTimes::new(self);
+        let mut line = String::new();
+        let mut stats = ExportStats::default();
+        while let Some(msg) = cursor.next(self) {
+            if msg.message_id() > to_id {
+                break;
+            }
+            line.clear();
+            line.push_str(&format!(
+                "{{\"id\":{},\"type\":{},\"time_ms\":{},\"body\":\"",
+                msg.message_id(),
+                msg.msg_type_id(),
+                times.time_of(msg.message_id())
+            ));
+            encode_base64(msg.bytes(), &mut line);
+            line.push('"');
+            let headers = msg.headers();
+            if !headers.is_empty() {
+                line.push_str(",\"headers\":\"");
+                encode_base64(&headers.to_bytes(), &mut line);
+                line.push('"');
+            }
+            line.push_str("}\n");
+            writer.write_all(line.as_bytes())?;
+            stats.records += 1;
+            stats.bytes += line.len() as u64;
+            stats.last_message_id = msg.message_id();
+        }
+        writer.flush()?;
+        Ok(stats)
+    }
+
+    /// Writes the messages from an export into the store and waits for them to be committed.
+    /// Nothing else can write to the store while it runs since the messages have to get the ids
+    /// they were exported with.
+    /// # Arguments
+    /// `reader` - Where to read the lines from.
+    /// `allow_append` - True to add the messages to a store that already has messages.  The first
+    /// one has to carry on from the last message in the store.
+    /// # Returns
+    /// What was imported, `InvalidConfig` if the store isn't empty or `CorruptMessage` with the
+    /// offset of the line that isn't valid or doesn't have the next id.
+    pub fn import<R: Read>(&self, reader: R, allow_append: bool) -> crate::Result<ExportStats> {
+        let mut next_id = self.written_message_id().max(self.max_message_id()) + 1;
+        if next_id > 1 && !allow_append {
+            return Err(PersistError::invalid_config(format!(
+                "The store already has {} messages.  Set allow_append to add to them.",
+                next_id - 1
+            )));
+        }
+        let mut reader = BufReader::new(reader);
+        let mut pending: VecDeque<(u64, QueueFuture<crate::Result<u64>>)> = VecDeque::new();
+        let mut line = String::new();
+        let mut stats = ExportStats::default();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            if !line.trim().is_empty() {
+                let record = parse_record(&line)
+                    .map_err(|reason| corrupt(stats.bytes as usize, None, reason))?;
+                if record.id != next_id {
+                    return Err(corrupt(
+                        stats.bytes as usize,
+                        Some(record.id),
+                        format!(
+                            "The message id {} doesn't carry on from {}.",
+                            record.id,
+                            next_id - 1
+                        ),
+                    ));
+                }
+                pending.push_back((
+                    record.id,
+                    self.write_with_headers(record.msg_type, &record.headers, &record.body),
+                ));
+                if pending.len() > IMPORT_WINDOW {
+                    let (id, future) = pending.pop_front().unwrap();
+                    wait_for(id, future)?;
+                }
+                next_id += 1;
+                stats.records += 1;
+                stats.last_message_id = record.id;
+            }
+            stats.bytes += read as u64;
+        }
+        for (id, future) in pending {
+            wait_for(id, future)?;
+        }
+        Ok(stats)
+    }
+}
+
+/// Reads the times of the terms as the messages are exported.  The terms are in the same order as
+/// the messages so it only moves forward.
+struct TermTimes<'a> {
+    store: &'a PersistedMessageFile,
+    /// The id of the commit file being read.
+    commit_file_id: u32,
+    /// The commit file being read.  `None` until it is opened.
+    buffer: Option<MemoryMappedInt>,
+    /// The position of the term being read.
+    pos: usize,
+}
+
+impl<'a> TermTimes<'a> {
+    fn new(store: &'a PersistedMessageFile) -> Self {
+        TermTimes {
+            store,
+            commit_file_id: store.first_commit_file_id(),
+            buffer: None,
+            pos: 0,
+        }
+    }
+
+    /// Gets the time the term a message is in was committed.
+    /// # Arguments
+    /// `message_id` - The id of the message.  Has to be at or after the last one asked for.
+    /// # Returns
+    /// The time in milliseconds since the epoch or 0 if the term can't be found.
+    fn time_of(&mut self, message_id: u64) -> u64 {
+        loop {
+            if self.buffer.is_none() {
+                let path = create_commit_name(
+                    &self.store.file_storage_directory,
+                    &self.store.file_prefix,
+                    &self.commit_file_id,
+                );
+                self.buffer = match OpenOptions::new().read(true).write(true).open(&path) {
+                    Ok(file) => match unsafe { open_commit_file(file) } {
+                        Ok(buffer) => Some(buffer),
+                        Err(_) => return 0,
+                    },
+                    Err(_) => return 0,
+                };
+                self.pos = 0;
+            }
+            let buffer = self.buffer.as_ref().unwrap();
+            if self.pos + COMMIT_SIZE as usize > buffer.capacity() - FILE_HEADER_SIZE
+                || buffer.term(self.pos) == 0
+            {
+                // The rest of the terms are in the next file.
+                self.commit_file_id += 1;
+                self.buffer = None;
+            } else if buffer.max_message_id(self.pos) >= message_id {
+                return buffer.start_time(self.pos);
+            } else {
+                self.pos += COMMIT_SIZE as usize;
+            }
+        }
+    }
+}
+
+/// A message read from a line of an export.
+#[derive(Debug, PartialEq, Eq)]
+struct Record {
+    id: u64,
+    msg_type: i32,
+    headers: MessageHeaders,
+    body: Vec<u8>,
+}
+
+/// A value in a line.  Only the numbers and strings are used.
+#[derive(Debug, PartialEq, Eq)]
+enum Value {
+    Number(i128),
+    Text(String),
+    Null,
+}
+
+/// Waits for an imported message to be committed.
+/// # Arguments
+/// `id` - The id the message was exported with.
+/// `future` - Completed once the message is committed.
+fn wait_for(id: u64, future: QueueFuture<crate::Result<u64>>) -> crate::Result<()> {
+    match block_on(future) {
+        Ok(Ok(written)) if written == id => Ok(()),
+        Ok(Ok(written)) => Err(corrupt(
+            0,
+            Some(id),
+            format!(
+                "The message was given the id {} instead of {}.  Something else is writing to the \
+                 store.",
+                written, id
+            ),
+        )),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(PersistError::closed()),
+    }
+}
+
+/// Creates the error for a line that can't be imported.
+/// # Arguments
+/// `offset` - The byte offset of the line.
+/// `id` - The id of the message on the line.
+/// `reason` - What is wrong with it.
+fn corrupt(offset: usize, id: Option<u64>, reason: String) -> PersistError {
+    PersistError::CorruptMessage {
+        context: Context {
+            path: None,
+            position: Some(offset),
+            id,
+        },
+        reason,
+    }
+}
+
+/// Reads a message from a line.
+/// # Arguments
+/// `line` - The JSON object for the message.
+/// # Returns
+/// The message or what is wrong with the line.
+fn parse_record(line: &str) -> Result<Record, String> {
+    let mut id = None;
+    let mut msg_type = None;
+    let mut body = None;
+    let mut headers = MessageHeaders::default();
+    for (key, value) in parse_object(line)? {
+        match (key.as_str(), value) {
+            ("id", Value::Number(n)) if n > 0 && n <= u64::MAX as i128 => id = Some(n as u64),
+            ("type", Value::Number(n)) if n >= 0 && n <= i32::MAX as i128 => {
+                msg_type = Some(n as i32)
+            }
+            ("body", Value::Text(text)) => {
+                body = Some(decode_base64(&text).ok_or("The body isn't valid base64.")?)
+            }
+            ("headers", Value::Text(text)) => match decode_base64(&text) {
+                Some(bytes) if bytes.len() == USER_META_SIZE => {
+                    headers = MessageHeaders::from_bytes(&bytes)
+                }
+                _ => {
+                    return Err(format!(
+                        "The headers aren't {} bytes of base64.",
+                        USER_META_SIZE
+                    ))
+                }
+            },
+            ("id", _) => return Err("The id has to be a number above 0.".to_owned()),
+            ("type", _) => {
+                return Err("The type has to be a number the store doesn't use.".to_owned())
+            }
+            ("body", _) => return Err("The body has to be a string.".to_owned()),
+            _ => {}
+        }
+    }
+    match (id, msg_type, body) {
+        (Some(id), Some(msg_type), Some(body)) => Ok(Record {
+            id,
+            msg_type,
+            headers,
+            body,
+        }),
+        _ => Err("The line needs an id, type and body.".to_owned()),
+    }
+}
+
+/// Reads a JSON object where the values are numbers, strings or null.
+/// # Arguments
+/// `text` - The object.
+/// # Returns
+/// The keys and values in the order they are in.
+fn parse_object(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let mut fields = Vec::new();
+    parser.expect(b'{')?;
+    if parser.peek() == Some(b'}') {
+        parser.pos += 1;
+    } else {
+        loop {
+            let key = parser.string()?;
+            parser.expect(b':')?;
+            let value = match parser.peek() {
+                Some(b'"') => Value::Text(parser.string()?),
+                Some(b'n') => {
+                    parser.keyword("null")?;
+                    Value::Null
+                }
+                _ => Value::Number(parser.number()?),
+            };
+            fields.push((key, value));
+            match parser.peek() {
+                Some(b',') => parser.pos += 1,
+                Some(b'}') => {
+                    parser.pos += 1;
+                    break;
+                }
+                _ => return Err(format!("Expected , or }} at {}.", parser.pos)),
+            }
+        }
+    }
+    if parser.peek().is_some() {
+        return Err(format!("There is more after the object at {}.", parser.pos));
+    }
+    Ok(fields)
+}
+
+/// Reads the parts of a JSON line.
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Skips the white space and looks at the next character.
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        self.bytes.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected {} at {}.", c as char, self.pos))
+        }
+    }
+
+    fn keyword(&mut self, word: &str) -> Result<(), String> {
+        if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(())
+        } else {
+            Err(format!("Expected {} at {}.", word, self.pos))
+        }
+    }
+
+    fn number(&mut self) -> Result<i128, String> {
+        let start = self.pos;
+        if self.bytes.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_digit() {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| format!("Expected a whole number at {}.", start))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut text = String::new();
+        let mut start = self.pos;
+        loop {
+            match self.bytes.get(self.pos) {
+                None => return Err("The string doesn't end.".to_owned()),
+                Some(b'"') => {
+                    text.push_str(&String::from_utf8_lossy(&self.bytes[start..self.pos]));
+                    self.pos += 1;
+                    return Ok(text);
+                }
+                Some(b'\\') => {
+                    text.push_str(&String::from_utf8_lossy(&self.bytes[start..self.pos]));
+                    let escaped = match self.bytes.get(self.pos + 1) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let code = self
+                                .bytes
+                                .get(self.pos + 2..self.pos + 6)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| format!("Bad \\u escape at {}.", self.pos))?;
+                            self.pos += 4;
+                            code
+                        }
+                        _ => return Err(format!("Bad escape at {}.", self.pos)),
+                    };
+                    text.push(escaped);
+                    self.pos += 2;
+                    start = self.pos;
+                }
+                Some(_) => self.pos += 1,
+            }
+        }
+    }
+}
+
+/// Adds the base64 of the bytes to a string.
+/// # Arguments
+/// `bytes` - The bytes to encode.
+/// `out` - Where to add the characters.
+fn encode_base64(bytes: &[u8], out: &mut String) {
+    for group in bytes.chunks(3) {
+        let b = [
+            group[0],
+            *group.get(1).unwrap_or(&0),
+            *group.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(BASE64[(n >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+/// Reads the bytes from base64 with the padding.
+/// # Arguments
+/// `text` - The base64.
+/// # Returns
+/// The bytes or `None` if it isn't valid.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    for (index, group) in text.chunks(4).enumerate() {
+        let last = index == text.len() / 4 - 1;
+        let padding = group.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for c in &group[..4 - padding] {
+            let value = BASE64.iter().position(|b| b == c)? as u32;
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding as u32;
+        bytes.push((n >> 16) as u8);
+        if padding < 2 {
+            bytes.push((n >> 8) as u8);
+        }
+        if padding < 1 {
+            bytes.push(n as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::raft::builder::PersistedMessageFileBuilder;
+    use crate::raft::json_lines::*;
+    use crate::raft::{MessageProcessor, MessageRead, ProcessError};
+    use a19_core::clock::ManualClock;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_json_lines";
+    const TEST_PREFIX: &str = "json_lines";
+
+    struct NoOp;
+
+    impl MessageProcessor for NoOp {
+        fn handle(&mut self, _read: MessageRead<'_>) -> Result<(), ProcessError> {
+            Ok(())
+        }
+    }
+
+    fn start(dir: &str, clock: Arc<ManualClock>) -> PersistedMessageFile {
+        if Path::new(dir).exists() {
+            remove_dir_all(dir).unwrap();
+        }
+        PersistedMessageFileBuilder::new(dir, TEST_PREFIX)
+            .max_file_size(0x400)
+            .clock(clock)
+            .build(NoOp)
+            .unwrap()
+    }
+
+    /// Reads the committed messages with their type, headers and body.
+    fn messages(store: &PersistedMessageFile) -> Vec<(u64, i32, MessageHeaders, Vec<u8>)> {
+        let mut cursor = CommittedCursor::new(1);
+        let mut messages = Vec::new();
+        while let Some(msg) = cursor.next(store) {
+            messages.push((
+                msg.message_id(),
+                msg.msg_type_id(),
+                msg.headers(),
+                msg.bytes().to_vec(),
+            ));
+        }
+        messages
+    }
+
+    #[test]
+    pub fn round_trip_test() {
+        let clock = Arc::new(ManualClock::new(5_000));
+        let source = start(&format!("{}/source", TEST_DIR), clock.clone());
+        for i in 1..=60u64 {
+            let body: Vec<u8> = (0..i as u8 * 4).map(|b| b.wrapping_mul(37)).collect();
+            let future = if i % 3 == 0 {
+                let headers = MessageHeaders {
+                    tenant: i as u32,
+                    priority: 2,
+                    flags: 1,
+                    reserved: 0,
+                };
+                source.write_with_headers(i as i32 % 5, &headers, &body)
+            } else {
+                source.write(i as i32 % 5, &body)
+            };
+            assert_eq!(i, block_on(future).unwrap().unwrap());
+            if i == 40 {
+                clock.set_ms(6_000);
+            }
+        }
+
+        let mut first = Vec::new();
+        let stats = source.export(&mut first, 1, 40).unwrap();
+        assert_eq!(
+            (40, 40, first.len() as u64),
+            (stats.records, stats.last_message_id, stats.bytes)
+        );
+        let mut rest = Vec::new();
+        assert_eq!(20, source.export(&mut rest, 41, u64::MAX).unwrap().records);
+        let text = String::from_utf8(first.clone()).unwrap();
+        assert_eq!(40, text.lines().count());
+        assert_eq!(
+            Some("{\"id\":1,\"type\":1,\"time_ms\":5000,\"body\":\"ACVKbw==\"}"),
+            text.lines().next()
+        );
+        assert!(text.lines().nth(2).unwrap().contains(",\"headers\":\""));
+        assert!(String::from_utf8(rest.clone())
+            .unwrap()
+            .lines()
+            .all(|line| line.contains("\"time_ms\":6000")));
+
+        let target = start(
+            &format!("{}/target", TEST_DIR),
+            Arc::new(ManualClock::new(0)),
+        );
+        assert_eq!(40, target.import(&first[..], false).unwrap().records);
+        // Has messages so it needs to be told to add to them.
+        match target.import(&rest[..], false) {
+            Err(PersistError::InvalidConfig { .. }) => {}
+            _ => panic!("Imported into a store with messages"),
+        }
+        // The ids have to carry on from the last message.
+        match target.import(&first[..], true) {
+            Err(PersistError::CorruptMessage { context, .. }) => {
+                assert_eq!((Some(0), Some(1)), (context.position, context.id));
+            }
+            _ => panic!("Imported the ids again"),
+        }
+        let stats = target.import(&rest[..], true).unwrap();
+        assert_eq!(
+            (20, 60, rest.len() as u64),
+            (stats.records, stats.last_message_id, stats.bytes)
+        );
+        assert_eq!(messages(&source), messages(&target));
+        assert_eq!(60, messages(&target).len());
+    }
+
+    #[test]
+    pub fn parse_test() {
+        for len in 0..8 {
+            let bytes: Vec<u8> = (0..len).map(|b| 0xFF - b).collect();
+            let mut text = String::new();
+            encode_base64(&bytes, &mut text);
+            assert_eq!(Some(bytes), decode_base64(&text));
+        }
+        assert_eq!(None, decode_base64("AB=C"));
+        assert_eq!(
+            Ok(Record {
+                id: 7,
+                msg_type: 2,
+                headers: MessageHeaders::default(),
+                body: b"hi".to_vec(),
+            }),
+            parse_record(" { \"id\" : 7, \"note\":\"a \\\"b\\\" \\u0041\", \"type\":2, \"time_ms\":null, \"body\":\"aGk=\" }\n")
+        );
+        assert!(parse_record("{\"id\":7,\"type\":-1,\"body\":\"\"}").is_err());
+        assert!(parse_record("{\"id\":7,\"body\":\"\"}").is_err());
+        assert!(parse_record("{\"id\":7,\"type\":1,\"body\":\"\"} x").is_err());
+    }
+}
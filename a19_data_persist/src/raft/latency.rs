@@ -0,0 +1,117 @@
+//! Records how long the messages take to get through the store.  The time is taken from the clock
+//! when a write is queued and again when the future is completed with the commit and when the
+//! processor has handled the message.
+//!```text
+//! queue_write ----------> commit thread completes the future   => commit
+//!      |
+//!      +----------------> processor.handle returns              => handled
+//!```
+//! The reader thread doesn't see the futures so the writer thread leaves the time the message was
+//! queued in a ring indexed by the message id.  A message that falls out of the ring before it is
+//! handled isn't recorded.
+use a19_concurrent::histogram::{Histogram, HistogramSnapshot};
+use a19_core::clock::Clock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of queue times kept for the reader.
+const APPEND_TIMES: usize = 0x1000;
+
+/// The latencies recorded for a store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// From the write being queued to its future being completed with the commit.
+    pub commit: HistogramSnapshot,
+    /// From the write being queued to the processor handling the message.
+    pub handled: HistogramSnapshot,
+}
+
+/// The time a message was queued.
+struct AppendTime {
+    /// The id of the message.  0 while the time is being changed.
+    message_id: AtomicU64,
+    at_ns: AtomicU64,
+}
+
+/// Records the latencies for a store.
+pub(crate) struct StoreLatency {
+    clock: Arc<dyn Clock>,
+    commit: Histogram,
+    handled: Histogram,
+    appended: Box<[AppendTime]>,
+}
+
+impl StoreLatency {
+    /// Creates the recorder.
+    /// # Arguments
+    /// `clock` - The clock to get the time from.
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        StoreLatency {
+            clock,
+            commit: Histogram::new(),
+            handled: Histogram::new(),
+            appended: (0..APPEND_TIMES)
+                .map(|_| AppendTime {
+                    message_id: AtomicU64::new(0),
+                    at_ns: AtomicU64::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    /// The current monotonic time in nanoseconds.
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.monotonic_ns()
+    }
+
+    /// Records a future being completed with the commit.
+    /// # Arguments
+    /// `queued_ns` - When the write was queued.
+    /// `now_ns` - When the future was completed.
+    pub(crate) fn committed(&self, queued_ns: u64, now_ns: u64) {
+        self.commit.record(now_ns.saturating_sub(queued_ns));
+    }
+
+    /// Keeps the time a message was queued for the reader.  Only called by the writer thread.
+    /// # Arguments
+    /// `message_id` - The id the message was written with.
+    /// `queued_ns` - When the write was queued.
+    pub(crate) fn appended(&self, message_id: u64, queued_ns: u64) {
+        let slot = &self.appended[message_id as usize % APPEND_TIMES];
+        slot.message_id.store(0, Ordering::Release);
+        slot.at_ns.store(queued_ns, Ordering::Release);
+        slot.message_id.store(message_id, Ordering::Release);
+    }
+
+    /// Records a message being handled by the processor.
+    /// # Arguments
+    /// `message_id` - The id of the message that was handled.
+    pub(crate) fn handled(&self, message_id: u64) {
+        let slot = &self.appended[message_id as usize % APPEND_TIMES];
+        if slot.message_id.load(Ordering::Acquire) != message_id {
+            return;
+        }
+        let queued_ns = slot.at_ns.load(Ordering::Acquire);
+        // Changed while we were reading it.
+        if slot.message_id.load(Ordering::Acquire) != message_id {
+            return;
+        }
+        self.handled.record(self.now().saturating_sub(queued_ns));
+    }
+
+    /// Gets the latencies recorded.
+    pub(crate) fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            commit: self.commit.snapshot(),
+            handled: self.handled.snapshot(),
+        }
+    }
+
+    /// Gets the latencies recorded and starts over.
+    pub(crate) fn snapshot_and_reset(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            commit: self.commit.snapshot_and_reset(),
+            handled: self.handled.snapshot_and_reset(),
+        }
+    }
+}
@@ -0,0 +1,496 @@
+//! An event file and a commit file that raft writes to directly.  The leader says where each
+//! message goes and which term it belongs to, the messages are laid down at those positions and
+//! the term is only saved to the commit file once raft has synced it.  A finished event file can
+//! also be opened on its own and scanned without any of the raft machinery.
+//!
+//! ```text
+//!  write(start, term) x N --> event file
+//!  commit_term(term)      --> flush event file --> save term as committed --> flush commit file
+//! ```
+use crate::file;
+use crate::file::backend::MessageStoreBackend;
+use crate::file::header::FILE_HEADER_SIZE;
+use crate::file::{MessageFileStoreRead, MessageFileStoreWrite, MessageRead, ALIGNMENT};
+use crate::raft::dirty_range::{Dirty, DirtyRanges};
+use crate::raft::{
+    CommitFile, MessageInfo, PersistedMessageFileMut, PersistedMessageFileReadOnly, TermCommit,
+    TermFile, TermPosResult, COMMIT_SIZE, END_OF_FILE_MESSAGE_TYPE, EVENT_MESSAGE_TYPE,
+};
+use a19_core::clock::{system_clock, Clock};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where the messages of a term that hasn't been committed were written.
+#[derive(Debug, Clone, Copy)]
+struct PendingTerm {
+    /// The position of the first message.
+    start: usize,
+    /// The position after the last message.
+    end: usize,
+    /// The id of the last message.
+    max_message_id: u64,
+    /// The time of the first message.
+    time: u64,
+}
+
+/// Writes the messages at the positions raft gives them and commits the terms.
+pub struct RaftMessageFile {
+    /// The id of the event file.
+    file_id: u32,
+    /// Reads the messages back in.
+    reader: MessageFileStoreRead,
+    /// Writes the messages.
+    writer: MessageFileStoreWrite,
+    /// The commit file the terms are saved to.
+    term_file: TermFile,
+    /// The id of the last message written.
+    last_message_id: u64,
+    /// The terms that have been written to but not committed.
+    pending: HashMap<u32, PendingTerm>,
+    /// The clock to get the commit times from.
+    clock: Arc<dyn Clock>,
+    /// The bytes written since the last flush.
+    dirty: DirtyRanges,
+}
+
+impl RaftMessageFile {
+    /// Creates the event file and the commit file.
+    /// # Arguments
+    /// `backend` - Where the files are kept.
+    /// `event_path` - The path of the event file.
+    /// `commit_path` - The path of the commit file.
+    /// `file_id` - The id of the event file saved in the terms.
+    /// `file_size` - The size of the event file.
+    /// `commit_file_size` - The size for the terms in the commit file.
+    pub fn create(
+        backend: &dyn MessageStoreBackend,
+        event_path: &Path,
+        commit_path: &Path,
+        file_id: u32,
+        file_size: usize,
+        commit_file_size: usize,
+    ) -> file::Result<Self> {
+        let (reader, writer) = backend.create(event_path, file_size)?;
+        let buffer = backend.create_commit(commit_path, commit_file_size)?;
+        Ok(RaftMessageFile {
+            file_id,
+            reader,
+            writer,
+            term_file: TermFile::with_buffer(buffer, 1, file_id),
+            last_message_id: 0,
+            pending: HashMap::new(),
+            clock: system_clock(),
+            dirty: DirtyRanges::default(),
+        })
+    }
+
+    /// Sets the clock to get the commit times from.
+    /// # Arguments
+    /// `clock` - The clock to use.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The id of the last message written.
+    pub fn last_message_id(&self) -> u64 {
+        self.last_message_id
+    }
+
+    /// True if a term has been saved to the commit file as committed.
+    /// # Arguments
+    /// `term_id` - The id of the term to check.
+    pub fn is_committed(&self, term_id: u32) -> bool {
+        match self.term_file.calculate_pos(&u64::from(term_id)) {
+            TermPosResult::Pos(pos) => {
+                self.term_file.buffer.term(pos) == u64::from(term_id)
+                    && self.term_file.buffer.committed(pos) > 0
+                    && self.term_file.buffer.verify(pos)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl PersistedMessageFileMut for RaftMessageFile {
+    fn write(
+        &mut self,
+        start: &usize,
+        term_id: &u32,
+        time: &u64,
+        body: &[u8],
+    ) -> file::Result<usize> {
+        if !start.is_multiple_of(ALIGNMENT) {
+            return Err(file::Error::PositionOutOfRange(*start));
+        }
+        let message_id = self.last_message_id + 1;
+        let end = self
+            .writer
+            .write(*start, EVENT_MESSAGE_TYPE, message_id, body)?;
+        self.last_message_id = message_id;
+        self.dirty.record(self.file_id, *start, end);
+        let term = self.pending.entry(*term_id).or_insert(PendingTerm {
+            start: *start,
+            end,
+            max_message_id: message_id,
+            time: *time,
+        });
+        term.start = term.start.min(*start);
+        term.end = term.end.max(end);
+        term.max_message_id = message_id;
+        Ok(end)
+    }
+
+    fn commit_term(&mut self, term_id: &u32) -> file::Result<()> {
+        let pending = match self.pending.get(term_id) {
+            Some(pending) => *pending,
+            None => return Err(file::Error::NoMessage),
+        };
+        let pos = match self.term_file.calculate_pos(&u64::from(*term_id)) {
+            TermPosResult::Pos(pos) => pos,
+            TermPosResult::Overflow => return Err(file::Error::Full),
+            TermPosResult::Underflow => {
+                return Err(file::Error::PositionOutOfRange(*term_id as usize))
+            }
+        };
+        // The messages have to be on disk before the term says they are committed.
+        self.writer
+            .flush_range(pending.start, pending.end - pending.start)?;
+        let term = TermCommit {
+            term_id: u64::from(*term_id),
+            version: 1,
+            type_id: 1,
+            server_id: 1,
+            leader_id: 1,
+            committed: 1,
+            timestamp: pending.time,
+            committed_timestamp: self.clock.now_ms(),
+            file_id: self.file_id,
+            file_position_offset: pending.start as u64,
+            file_max_message_id: pending.max_message_id,
+            length: (pending.end - pending.start) as u32,
+        };
+        self.term_file.buffer.save_term(pos, &term);
+        self.term_file
+            .buffer
+            .flush_range(FILE_HEADER_SIZE + pos, COMMIT_SIZE as usize)?;
+        self.pending.remove(term_id);
+        Ok(())
+    }
+
+    fn read<'a>(&'a mut self, start: &usize) -> file::Result<MessageInfo<'a>> {
+        self.reader.read_new(*start).map(|msg| message_info(&msg))
+    }
+
+    fn flush(&mut self) -> file::Result<()> {
+        match self.dirty.take(self.file_id) {
+            Dirty::Clean => {}
+            Dirty::Range(range) => self.writer.flush_range(range.start, range.len())?,
+            Dirty::Unknown => self.writer.flush()?,
+        }
+        self.term_file.buffer.flush()?;
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.writer.capacity()
+    }
+}
+
+/// Reads a finished event file without the raft machinery.
+pub struct ReadOnlyMessageFile {
+    /// Reads the messages in the file.
+    reader: MessageFileStoreRead,
+}
+
+impl ReadOnlyMessageFile {
+    /// Opens an event file to read.
+    /// # Arguments
+    /// `backend` - Where the files are kept.
+    /// `path` - The path of the event file.
+    pub fn open(backend: &dyn MessageStoreBackend, path: &Path) -> file::Result<Self> {
+        Ok(ReadOnlyMessageFile::new(backend.open_readonly(path)?))
+    }
+
+    /// Reads the messages of a file that is already open.
+    /// # Arguments
+    /// `reader` - The reader for the file.
+    pub fn new(reader: MessageFileStoreRead) -> Self {
+        ReadOnlyMessageFile { reader }
+    }
+
+    /// The size of the file.
+    pub fn capacity(&self) -> usize {
+        self.reader.capacity()
+    }
+}
+
+impl PersistedMessageFileReadOnly for ReadOnlyMessageFile {
+    fn read<'a>(&'a self, start: usize) -> file::Result<MessageInfo<'a>> {
+        self.reader.read_new(start).map(|msg| message_info(&msg))
+    }
+
+    fn read_msg_til<F>(&self, start: usize, mut func: F) -> u32
+    where
+        F: FnMut(MessageInfo) -> bool,
+    {
+        let mut pos = start;
+        let mut visited = 0;
+        // Stops at the end of file marker, the zeroed space after the last message or the end of
+        // the file.
+        while let Ok(msg) = self.reader.read_new(pos) {
+            if msg.msg_type_id() == END_OF_FILE_MESSAGE_TYPE {
+                break;
+            }
+            visited += 1;
+            if !func(message_info(&msg)) {
+                break;
+            }
+            pos = msg.next_pos();
+        }
+        visited
+    }
+}
+
+/// Gets the info of a message read in from a file.  The frames don't keep the time so `time_ms`
+/// is 0.
+/// # Arguments
+/// `msg` - The message read in.
+fn message_info<'a>(msg: &MessageRead<'a>) -> MessageInfo<'a> {
+    MessageInfo::new(
+        msg.message_id(),
+        0,
+        msg.msg_type_id(),
+        msg.headers(),
+        msg.bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::backend::{FileBackend, InMemoryMessageStore};
+    use crate::file::Error;
+    use crate::raft::message_file::*;
+    use crate::raft::{
+        create_event_name, load_current_files, process_files, PersistedMessageWriteStream,
+    };
+    use a19_core::clock::ManualClock;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::sync::atomic::AtomicU64;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_message_file";
+
+    #[test]
+    pub fn round_trip_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        create_dir_all(TEST_DIR).unwrap();
+        let mut file = RaftMessageFile::create(
+            &FileBackend,
+            &path.join("raft.events.1"),
+            &path.join("raft.commit.1"),
+            1,
+            0x1000,
+            0x1000,
+        )
+        .unwrap()
+        .with_clock(Arc::new(ManualClock::new(2000)));
+        let store: &mut dyn PersistedMessageFileMut = &mut file;
+        let mut positions = Vec::new();
+        let mut pos = 0;
+        for i in 0..8u64 {
+            positions.push(pos);
+            let term_id = if i < 5 { 1 } else { 2 };
+            pos = store
+                .write(&pos, &term_id, &(1000 + i), &i.to_le_bytes())
+                .unwrap();
+            assert_eq!(0, pos % ALIGNMENT);
+        }
+        store.commit_term(&1).unwrap();
+        for (i, pos) in positions.iter().enumerate() {
+            let msg = store.read(pos).unwrap();
+            assert_eq!(i as u64 + 1, msg.message_id());
+            assert_eq!(EVENT_MESSAGE_TYPE, msg.message_type());
+            assert_eq!(&(i as u64).to_le_bytes()[..], msg.body());
+        }
+        assert_eq!(0x1000, store.capacity());
+        // Only the term raft synced is committed.
+        assert!(file.is_committed(1));
+        assert!(!file.is_committed(2));
+        let pos = file.term_file.calculate_pos(&1);
+        if let TermPosResult::Pos(pos) = pos {
+            assert_eq!(5, file.term_file.buffer.max_message_id(pos));
+            assert_eq!(0, file.term_file.buffer.file_position_offset(pos));
+            assert_eq!(1000, file.term_file.buffer.start_time(pos));
+            assert_eq!(2000, file.term_file.buffer.committed_timestamp(pos));
+        } else {
+            panic!("Term 1 should be in the commit file.");
+        }
+        file.commit_term(&2).unwrap();
+        assert!(file.is_committed(2));
+        file.flush().unwrap();
+    }
+
+    #[test]
+    pub fn out_of_bounds_test() {
+        let backend = InMemoryMessageStore::new();
+        let mut file = RaftMessageFile::create(
+            &backend,
+            Path::new("bounds.events.1"),
+            Path::new("bounds.commit.1"),
+            1,
+            0x400,
+            0x400,
+        )
+        .unwrap();
+        let store: &mut dyn PersistedMessageFileMut = &mut file;
+        match store.write(&0x800, &1, &0, &[1, 2, 3]) {
+            Err(Error::PositionOutOfRange(0x800)) => {}
+            other => panic!(
+                "Expected the position to be out of range but got {:?}",
+                other
+            ),
+        }
+        match store.write(&8, &1, &0, &[1, 2, 3]) {
+            Err(Error::PositionOutOfRange(8)) => {}
+            other => panic!(
+                "Expected the position to be out of range but got {:?}",
+                other
+            ),
+        }
+        match store.write(&0, &1, &0, &[0; 0x800]) {
+            Err(Error::Full) => {}
+            other => panic!("Expected the file to be full but got {:?}", other),
+        }
+        match store.read(&0x800) {
+            Err(Error::PositionOutOfRange(_)) => {}
+            other => panic!(
+                "Expected the position to be out of range but got {:?}",
+                other.is_ok()
+            ),
+        }
+        // Nothing was written for the term.
+        match store.commit_term(&1) {
+            Err(Error::NoMessage) => {}
+            other => panic!("Expected no messages but got {:?}", other),
+        }
+        // The commit file only has room for 8 terms.
+        store.write(&0, &9, &0, &[1]).unwrap();
+        match store.commit_term(&9) {
+            Err(Error::Full) => {}
+            other => panic!("Expected the commit file to be full but got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn read_only_test() {
+        let dir = format!("{}_read_only", TEST_DIR);
+        let path = Path::new(&dir);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let file_size = 0x400;
+        let mut collection = load_current_files("read", &dir).unwrap();
+        process_files(&mut collection, "read", &dir, &file_size, &0x1000).unwrap();
+        let mut writer = PersistedMessageWriteStream::new(
+            1,
+            dir.clone(),
+            "read".to_owned(),
+            file_size,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap();
+        for message_id in 1..=40u64 {
+            writer
+                .add_message(1, message_id, &message_id.to_le_bytes())
+                .unwrap();
+        }
+        writer.flush().unwrap();
+
+        // The first file rolled over so it ends with the end of file marker.
+        let first = ReadOnlyMessageFile::open(
+            &FileBackend,
+            Path::new(&create_event_name(&dir, "read", &1)),
+        )
+        .unwrap();
+        let read = first.read_msg_til(0, |_| true);
+        assert!(read > 0 && read < 40);
+        let msg = first.read(0).unwrap();
+        assert_eq!(1, msg.message_id());
+        assert_eq!(&1u64.to_le_bytes()[..], msg.body());
+        // Stops when the callback says to.
+        assert_eq!(5, first.read_msg_til(0, |msg| msg.message_id() < 5));
+        // The rest are in the second file which stops at the zeroed space.
+        let second = ReadOnlyMessageFile::open(
+            &FileBackend,
+            Path::new(&create_event_name(&dir, "read", &2)),
+        )
+        .unwrap();
+        assert_eq!(40 - read, second.read_msg_til(0, |_| true));
+        assert_eq!(u64::from(read) + 1, second.read(0).unwrap().message_id());
+    }
+
+    #[test]
+    pub fn read_msg_til_capture_test() {
+        let backend = InMemoryMessageStore::new();
+        let (reader, writer) = backend
+            .create(Path::new("capture.events.1"), 0x400)
+            .unwrap();
+        let mut pos = 0;
+        for message_id in 1..=10u64 {
+            pos = writer
+                .write(pos, 1, message_id, &message_id.to_le_bytes())
+                .unwrap();
+        }
+        let file = ReadOnlyMessageFile::new(reader);
+        // Collects into the state the closure captured.
+        let mut ids = Vec::new();
+        let visited = file.read_msg_til(0, |msg| {
+            ids.push(msg.message_id());
+            ids.len() < 4
+        });
+        assert_eq!(4, visited);
+        assert_eq!(vec![1, 2, 3, 4], ids);
+
+        // Starts part of the way in.
+        let mut bytes = 0;
+        assert_eq!(
+            6,
+            file.read_msg_til(4 * 32, |msg| {
+                bytes += msg.len();
+                true
+            })
+        );
+        assert_eq!(6 * 8, bytes);
+    }
+
+    #[test]
+    pub fn read_only_edges_test() {
+        let backend = InMemoryMessageStore::new();
+        let (reader, _writer) = backend.create(Path::new("empty.events.1"), 0x400).unwrap();
+        let empty = ReadOnlyMessageFile::new(reader);
+        assert_eq!(0, empty.read_msg_til(0, |_| true));
+        match empty.read(0) {
+            Err(Error::NoMessage) => {}
+            other => panic!("Expected no message but got {:?}", other.is_ok()),
+        }
+
+        // 32 messages of 32 bytes fill the file exactly.
+        let (reader, writer) = backend.create(Path::new("full.events.1"), 0x400).unwrap();
+        let mut pos = 0;
+        for message_id in 1..=32u64 {
+            pos = writer
+                .write(pos, 1, message_id, &message_id.to_le_bytes())
+                .unwrap();
+        }
+        assert_eq!(0x400, pos);
+        let full = ReadOnlyMessageFile::new(reader);
+        assert_eq!(0x400, full.capacity());
+        assert_eq!(32, full.read_msg_til(0, |_| true));
+        assert_eq!(32, full.read(0x3E0).unwrap().message_id());
+    }
+}
@@ -0,0 +1,255 @@
+//! Keeps the current raft term and who we voted for so they survive a restart.  Raft needs them to
+//! be on disk before a vote is sent or a term is used, otherwise a server that crashes can vote
+//! twice in the same term.
+//!
+//! The values are written to one of two slots after the file header, each save goes to the slot
+//! that doesn't have the latest values and is flushed before returning.  A save that is torn by a
+//! crash fails its checksum so the other slot is used, the vote it was for was never sent.
+//! ```text
+//!  file_prefix.meta
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | File Header                                                  ...
+//! ...                                                             | 4096
+//! +---------------------------------------------------------------+
+//! | Sequence                                                      |
+//! |                                                               | 64
+//! +---------------------------------------------------------------+
+//! | Current Term                                                  |
+//! |                                                               | 128
+//! +---------------------------------------------------------------+
+//! | Voted For                                                     | 160
+//! +---------------------------------------------------------------+
+//! | Has Voted (1 if we voted in the term)                         | 192
+//! +---------------------------------------------------------------+
+//! | Server Id                                                     | 224
+//! +---------------------------------------------------------------+
+//! | CRC                                                           | 256
+//! +---------------------------------------------------------------+
+//! | The second slot                                              ...
+//! ```
+use crate::file;
+use crate::file::header::{init_header, FileType, FILE_HEADER_SIZE};
+use crate::raft::create_meta_name;
+use crate::raft::node_state::RaftAction;
+use a19_concurrent::buffer::mmap_buffer::MemoryMappedInt;
+use a19_concurrent::buffer::DirectByteBuffer;
+use std::fs::create_dir_all;
+
+/// The size of a slot.
+const SLOT_SIZE: usize = 32;
+const SEQUENCE: usize = 0;
+const CURRENT_TERM: usize = 8;
+const VOTED_FOR: usize = 16;
+const HAS_VOTED: usize = 20;
+const SERVER_ID: usize = 24;
+const CRC: usize = 28;
+
+/// The current raft term and who we voted for saved to `file_prefix.meta`.
+pub struct RaftMeta {
+    /// The mapped file.
+    buffer: MemoryMappedInt,
+    /// The sequence of the last save.  0 if nothing has been saved.
+    sequence: u64,
+    /// The id of this server.
+    server_id: u32,
+    /// The current term.
+    current_term: u64,
+    /// Who we voted for in the current term.
+    voted_for: Option<u32>,
+}
+
+impl RaftMeta {
+    /// Opens the file or creates it if it doesn't exist.  A new file starts at term 0 without a
+    /// vote.
+    /// # Arguments
+    /// `file_storage_directory` - The directory the files are in.
+    /// `file_prefix` - The prefix of the files.
+    /// # Returns
+    /// The saved values or `InvalidHeader` if the file isn't a meta file.
+    pub fn load(file_storage_directory: &str, file_prefix: &str) -> file::Result<Self> {
+        create_dir_all(file_storage_directory)?;
+        let path = create_meta_name(file_storage_directory, file_prefix);
+        let mut buffer = unsafe { MemoryMappedInt::new(&path, FILE_HEADER_SIZE + SLOT_SIZE * 2)? };
+        init_header(&mut buffer, FileType::Meta, 0, SLOT_SIZE * 2)?;
+        let mut meta = RaftMeta {
+            buffer,
+            sequence: 0,
+            server_id: 0,
+            current_term: 0,
+            voted_for: None,
+        };
+        let latest = (0..2)
+            .filter(|slot| meta.verify(*slot))
+            .max_by_key(|slot| meta.buffer.get_u64(slot_pos(*slot) + SEQUENCE));
+        if let Some(slot) = latest {
+            let pos = slot_pos(slot);
+            meta.sequence = meta.buffer.get_u64(pos + SEQUENCE);
+            meta.current_term = meta.buffer.get_u64(pos + CURRENT_TERM);
+            meta.server_id = meta.buffer.get_u32(pos + SERVER_ID);
+            if meta.buffer.get_u32(pos + HAS_VOTED) == 1 {
+                meta.voted_for = Some(meta.buffer.get_u32(pos + VOTED_FOR));
+            }
+        }
+        Ok(meta)
+    }
+
+    /// The id of this server.  0 if it hasn't been set.
+    pub fn server_id(&self) -> u32 {
+        self.server_id
+    }
+
+    /// The current term.
+    pub fn current_term(&self) -> u64 {
+        self.current_term
+    }
+
+    /// Who we voted for in the current term.
+    pub fn voted_for(&self) -> Option<u32> {
+        self.voted_for
+    }
+
+    /// Sets the id of this server.  Isn't saved until `save` is called.
+    /// # Arguments
+    /// `server_id` - The id of this server.
+    pub fn set_server_id(&mut self, server_id: u32) {
+        self.server_id = server_id;
+    }
+
+    /// Sets the term and the vote.  Aren't saved until `save` is called.
+    /// # Arguments
+    /// `current_term` - The current term.
+    /// `voted_for` - Who we voted for in the term.
+    pub fn set_term(&mut self, current_term: u64, voted_for: Option<u32>) {
+        self.current_term = current_term;
+        self.voted_for = voted_for;
+    }
+
+    /// Writes the values to the slot that doesn't have the latest ones and flushes it.
+    pub fn save(&mut self) -> file::Result<()> {
+        let sequence = self.sequence + 1;
+        let pos = slot_pos((sequence % 2) as usize);
+        self.buffer.put_u64(pos + SEQUENCE, sequence);
+        self.buffer.put_u64(pos + CURRENT_TERM, self.current_term);
+        self.buffer
+            .put_u32(pos + VOTED_FOR, self.voted_for.unwrap_or_default());
+        self.buffer
+            .put_u32(pos + HAS_VOTED, self.voted_for.is_some() as u32);
+        self.buffer.put_u32(pos + SERVER_ID, self.server_id);
+        let crc = crc32fast::hash(self.buffer.get_bytes(pos, CRC));
+        self.buffer.put_u32(pos + CRC, crc);
+        self.buffer.flush_range(pos, SLOT_SIZE)?;
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    /// Saves the term and vote the actions ask for.  Needs to be called before the rest of the
+    /// actions are run.
+    /// # Arguments
+    /// `actions` - The actions returned from handling an event.
+    pub fn apply(&mut self, actions: &[RaftAction]) -> file::Result<()> {
+        for action in actions {
+            if let RaftAction::SaveTerm { term, voted_for } = action {
+                self.set_term(*term, *voted_for);
+                self.save()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the slot has been written and matches its checksum.
+    fn verify(&self, slot: usize) -> bool {
+        let pos = slot_pos(slot);
+        self.buffer.get_u64(pos + SEQUENCE) > 0
+            && self.buffer.get_u32(pos + CRC) == crc32fast::hash(self.buffer.get_bytes(pos, CRC))
+    }
+}
+
+/// The position of a slot in the file.
+fn slot_pos(slot: usize) -> usize {
+    FILE_HEADER_SIZE + slot * SLOT_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::raft::meta::*;
+    use crate::raft::node_state::{RaftNodeEvent, RaftState};
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_meta";
+    const TEST_PREFIX: &str = "meta";
+
+    fn clean(dir: &str) {
+        let path = Path::new(dir);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn round_trip_test() {
+        let dir = format!("{}/round_trip", TEST_DIR);
+        clean(&dir);
+        {
+            let mut meta = RaftMeta::load(&dir, TEST_PREFIX).unwrap();
+            assert_eq!(
+                (0, 0, None),
+                (meta.server_id(), meta.current_term(), meta.voted_for())
+            );
+            meta.set_server_id(4);
+            meta.set_term(3, Some(2));
+            meta.save().unwrap();
+            meta.set_term(5, None);
+            meta.save().unwrap();
+        }
+        let mut meta = RaftMeta::load(&dir, TEST_PREFIX).unwrap();
+        assert_eq!(
+            (4, 5, None),
+            (meta.server_id(), meta.current_term(), meta.voted_for())
+        );
+
+        // A torn save falls back to the values before it.
+        meta.set_term(6, Some(1));
+        meta.save().unwrap();
+        let pos = slot_pos((meta.sequence % 2) as usize);
+        meta.buffer.put_u64(pos + CURRENT_TERM, 7);
+        drop(meta);
+        let meta = RaftMeta::load(&dir, TEST_PREFIX).unwrap();
+        assert_eq!(
+            (4, 5, None),
+            (meta.server_id(), meta.current_term(), meta.voted_for())
+        );
+    }
+
+    #[test]
+    pub fn vote_once_test() {
+        let dir = format!("{}/vote_once", TEST_DIR);
+        clean(&dir);
+        {
+            let mut meta = RaftMeta::load(&dir, TEST_PREFIX).unwrap();
+            meta.set_server_id(1);
+            let mut state = RaftState::from_meta(3, &meta);
+            let actions = state.handle_event(RaftNodeEvent::VoteRequested {
+                term: 2,
+                candidate_id: 2,
+                up_to_date: true,
+            });
+            meta.apply(&actions).unwrap();
+            assert_eq!(Some(2), meta.voted_for());
+        }
+        // Restarted so the vote has to come from the file.
+        let meta = RaftMeta::load(&dir, TEST_PREFIX).unwrap();
+        let mut state = RaftState::from_meta(3, &meta);
+        assert_eq!((2, Some(2)), (state.current_term(), state.voted_for()));
+        assert!(state
+            .handle_event(RaftNodeEvent::VoteRequested {
+                term: 2,
+                candidate_id: 3,
+                up_to_date: true,
+            })
+            .is_empty());
+    }
+}
@@ -10,62 +10,335 @@
 //! buffer when we get all of them.  This buffer can the rollover and is the one that we keep.  We
 //! don't want to keep theses buffers.
 //!
-//! file_prefix.events.1
-//! file_prefix.commit.1
-//! file_prefix.events.2
-//! file_prefix.commit.2
-//! file_prefix.events.3
-//! file_prefix.commit.3
+//! file_prefix.events.0000000001
+//! file_prefix.commit.0000000001
+//! file_prefix.events.0000000002
+//! file_prefix.commit.0000000002
+//! file_prefix.events.0000000003
+//! file_prefix.commit.0000000003
 //!
+//! The ids are zero padded so the files list in order.  The files named before the padding was
+//! added are renamed when the store starts.
+//!
+pub mod backlog;
+pub mod builder;
+pub mod compact;
+pub mod dirty_range;
+pub mod election;
+pub mod events;
+pub(crate) mod flusher;
+pub mod follower;
+pub mod heartbeat;
+pub mod inspect;
+pub mod json_lines;
 pub mod incoming_message;
+pub mod latency;
+pub mod message_file;
+pub mod meta;
 pub mod network;
+pub mod node_state;
+pub mod quorum;
+pub mod reverse;
+pub mod shared_writer;
+pub mod snapshot;
+pub mod sparse_index;
 pub mod state_machine;
+pub mod tail;
+pub mod verify;
 pub mod write_message;
 
 pub const EVENT_FILE_POSTFIX: &str = "events";
 pub const COMMIT_FILE_POSTIX: &str = "commit";
+pub const INDEX_FILE_POSTFIX: &str = "index";
+pub const META_FILE_POSTFIX: &str = "meta";
+/// The number of digits the file ids are padded to.  Fits the largest `u32`.
+pub const FILE_ID_WIDTH: usize = 10;
 pub const COMMIT_SIZE: u64 = 128;
 pub const COMMIT_SIZE_BITS: usize = 128 * 8;
 pub const EVENT_HEADER_SIZE: usize = 32;
-
+/// The message type marking the end of an event file.
+pub const END_OF_FILE_MESSAGE_TYPE: i32 = -1;
+/// The message type written in front of the messages in a transaction.  The body is the number of
+/// messages in the transaction.
+pub const TRANSACTION_MESSAGE_TYPE: i32 = -2;
+/// The message type identifying the producer of the message after it in a transaction.
+pub const PRODUCER_MESSAGE_TYPE: i32 = -3;
+/// The type used for a transaction in the incoming buffer since it can't take negative types.  Can't
+/// be used as the type of a message.
+const INCOMING_TRANSACTION_TYPE: i32 = i32::MAX;
+/// The type used for a message with the user metadata in the incoming buffer.  The body has the
+/// type of the message and the metadata in front of the body of the message.  Can't be used as the
+/// type of a message.
+const INCOMING_HEADERS_TYPE: i32 = i32::MAX - 1;
+/// The size of what is in front of the body of a message with the user metadata in the incoming
+/// buffer.
+const INCOMING_HEADERS_SIZE: usize = 4 + USER_META_SIZE;
+/// The maximum number of bytes to commit in a term unless a transaction is bigger.
+const COMMIT_BLOCK_SIZE: usize = 0x10000;
+/// The size of the header of a message in a transaction batch.
+const BATCH_HEADER_SIZE: usize = 8;
+
+use crate::error::{Context, PersistError, ResultExt};
 use crate::file;
-use crate::file::{MessageFileStore, MessageFileStoreRead, MessageFileStoreWrite, MessageRead};
+use crate::file::filter::{MessageFilter, MessageHeaderView};
+use crate::raft::backlog::{Backlog, BacklogStatus, Capacity, Reservation, Watermarks, WouldBlock};
+use crate::raft::dirty_range::{Dirty, DirtyRanges};
+use crate::raft::events::{StoreEvent, StoreEvents};
+use crate::raft::flusher::Flusher;
+use crate::raft::latency::{LatencySnapshot, StoreLatency};
+use crate::raft::sparse_index::SparseIndexWriter;
+use crate::raft::tail::{CommitSignal, CommittedStream, TailReader};
+use crate::{CommitFuture, Event, PersitEventStream};
+use crate::file::chunk::{chunk_count, chunk_size, ChunkHeader, CHUNK_HEADER_SIZE, CHUNK_MESSAGE_TYPE};
+use crate::file::backend::{FileBackend, MessageStoreBackend};
+use crate::file::header::{create_commit_file, open_commit_file, FILE_HEADER_SIZE};
+use crate::file::{
+    aligned_message_size, MessageBlock, MessageFileStore, MessageFileStoreRead,
+    MessageFileStoreWrite, MessageHeaders, MessageRead, StoreBuffer, USER_META_SIZE,
+};
 use a19_concurrent::buffer::mmap_buffer::MemoryMappedInt;
+use a19_concurrent::buffer::pool::{BufferPool, PooledBuf};
 use a19_concurrent::buffer::ring_buffer::{
     create_many_to_one, ManyToOneBufferReader, ManyToOneBufferWriter,
 };
 use a19_concurrent::buffer::{next_pos, DirectByteBuffer};
+use a19_concurrent::event_bus::EventBus;
+use a19_concurrent::map::skip_list::ConcurrentSkipListMap;
 use a19_concurrent::queue::mpsc_queue::{MpscQueueReceive, MpscQueueWrap};
-use a19_concurrent::queue::spsc_queue::{SpscQueueReceiveWrap, SpscQueueSendWrap};
+use a19_concurrent::threads::{spawn_configured, ThreadConfig};
+use a19_concurrent::wait::{SleepBackoff, WaitStrategy};
+use a19_core::clock::{system_clock, Clock};
+use byteorder::{BigEndian, ByteOrder};
 use futures::channel::oneshot;
-use std::cell::Cell;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fs::*;
 use std::io;
 use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf, MAIN_SEPARATOR};
-use std::rc::Rc;
-use std::sync::atomic::{AtomicU64, AtomicU8};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8};
 use std::sync::{atomic, Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
-#[allow(unused_attributes)]
-#[path = "../target/a19_data_persist/message/persisted_file_generated.rs"]
-
 pub type QueueFuture<TOUT> = oneshot::Receiver<TOUT>;
 
+/// The messages in a transaction.  They are written one after another and always committed in the
+/// same term so a reader never sees part of a transaction.
+///
+/// ```text
+///  0                   1                   2                   3
+///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// | Message Type                                                  |
+/// +---------------------------------------------------------------+
+/// | Length                                                        |
+/// +---------------------------------------------------------------+
+/// | Body                                                         ...
+/// ...                                                             |
+/// +---------------------------------------------------------------+
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionBatch {
+    bytes: Vec<u8>,
+    count: u32,
+}
+
+impl TransactionBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        TransactionBatch::default()
+    }
+
+    /// Adds a message to the batch.
+    /// # Arguments
+    /// `msg_type_id` - The type of the message.
+    /// `body` - The body of the message.
+    pub fn add(&mut self, msg_type_id: i32, body: &[u8]) {
+        let mut header = [0; BATCH_HEADER_SIZE];
+        BigEndian::write_i32(&mut header[0..4], msg_type_id);
+        BigEndian::write_u32(&mut header[4..8], body.len() as u32);
+        self.bytes.extend_from_slice(&header);
+        self.bytes.extend_from_slice(body);
+        self.count += 1;
+    }
+
+    /// The number of messages in the batch.
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+
+    /// True if the batch doesn't have any messages.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Removes all of the messages.
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.count = 0;
+    }
+
+    /// The encoded messages.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Reads the messages out of an encoded transaction batch.
+/// # Arguments
+/// `bytes` - The encoded batch.
+/// `act` - Called with the type and body of each message.
+fn for_each_batch_message<F>(bytes: &[u8], mut act: F)
+where
+    F: FnMut(i32, &[u8]),
+{
+    let mut pos = 0;
+    while pos + BATCH_HEADER_SIZE <= bytes.len() {
+        let msg_type_id = BigEndian::read_i32(&bytes[pos..(pos + 4)]);
+        let length = BigEndian::read_u32(&bytes[(pos + 4)..(pos + 8)]) as usize;
+        let body = pos + BATCH_HEADER_SIZE;
+        act(msg_type_id, &bytes[body..(body + length)]);
+        pos = body + length;
+    }
+}
+
+/// Gets the number of messages in a transaction from the body of the transaction message.
+/// # Arguments
+/// `bytes` - The body of the transaction message.
+fn transaction_count(bytes: &[u8]) -> u64 {
+    if bytes.len() < 4 {
+        0
+    } else {
+        BigEndian::read_u32(&bytes[0..4]) as u64
+    }
+}
+
 /// Represents a message that was read in.
-#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MessageInfo<'a> {
     message_id: u64,
     time_ms: i64,
     message_type: i32,
+    headers: MessageHeaders,
     message_body: &'a [u8],
 }
 
+impl<'a> MessageInfo<'a> {
+    /// Creates the info of a message.  The messages are normally read in from a file but it can
+    /// be created to test an implementation of the message file traits.
+    /// # Arguments
+    /// `message_id` - The id of the message.
+    /// `time_ms` - The time of the message in unix milliseconds.
+    /// `message_type` - The type of the message.
+    /// `headers` - The user metadata.
+    /// `message_body` - The body of the message.
+    pub fn new(
+        message_id: u64,
+        time_ms: i64,
+        message_type: i32,
+        headers: MessageHeaders,
+        message_body: &'a [u8],
+    ) -> Self {
+        MessageInfo {
+            message_id,
+            time_ms,
+            message_type,
+            headers,
+            message_body,
+        }
+    }
+
+    /// The id of the message.
+    pub fn message_id(&self) -> u64 {
+        self.message_id
+    }
+
+    /// The time of the message in unix milliseconds.  0 when the file doesn't keep the time.
+    pub fn time_ms(&self) -> i64 {
+        self.time_ms
+    }
+
+    /// The type of the message.  The negative types are used by the store.
+    pub fn message_type(&self) -> i32 {
+        self.message_type
+    }
+
+    /// The user metadata.
+    pub fn headers(&self) -> MessageHeaders {
+        self.headers
+    }
+
+    /// The body of the message.
+    pub fn body(&self) -> &'a [u8] {
+        self.message_body
+    }
+
+    /// The number of bytes in the body.
+    pub fn len(&self) -> usize {
+        self.message_body.len()
+    }
+
+    /// True if the body is empty.
+    pub fn is_empty(&self) -> bool {
+        self.message_body.is_empty()
+    }
+
+    /// True if this is the marker padding out the rest of a file.  The space after it has its
+    /// size set to `u32::MAX` so the marker is the last message read in from the file.
+    pub fn is_padding(&self) -> bool {
+        self.message_type == END_OF_FILE_MESSAGE_TYPE || self.message_id == u64::MAX
+    }
+
+    /// Copies the message so it can outlive the buffer it was read from.
+    pub fn to_owned_info(&self) -> OwnedMessageInfo {
+        OwnedMessageInfo {
+            message_id: self.message_id,
+            time_ms: self.time_ms,
+            message_type: self.message_type,
+            headers: self.headers,
+            message_body: self.message_body.to_vec(),
+        }
+    }
+
+    /// Copies the body of the message into a buffer from a pool.
+    /// # Arguments
+    /// `pool` - The pool to get the buffer from.
+    pub fn pooled_body(&self, pool: &Arc<BufferPool>) -> PooledBuf {
+        pool.copy_of(self.message_body)
+    }
+}
+
+/// A message that was read in with a copy of the body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedMessageInfo {
+    pub message_id: u64,
+    pub time_ms: i64,
+    pub message_type: i32,
+    /// The user metadata.  Zeros when it is missing so the older messages can still be read.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub headers: MessageHeaders,
+    pub message_body: Vec<u8>,
+}
+
+impl OwnedMessageInfo {
+    /// Copies a message read in from a file.  The frames don't keep the time so `time_ms` is 0.
+    /// # Arguments
+    /// `msg` - The message to copy.
+    pub(crate) fn copy_of(msg: &MessageRead) -> Self {
+        OwnedMessageInfo {
+            message_id: msg.message_id(),
+            time_ms: 0,
+            message_type: msg.msg_type_id(),
+            headers: msg.headers(),
+            message_body: msg.bytes().to_vec(),
+        }
+    }
+}
+
 #[allow(dead_code)]
 struct FileWriteInfo {
     file_id: u32,
@@ -89,7 +362,7 @@ struct FileWriteInfo {
 /// +---------------------------------------------------------------+ 128 | 16
 /// | Leader Id                                                     |
 /// +-------------------------------+-------------------------------+ 160 | 20
-/// | Committed                     | NOT USED FOR NOW              |
+/// | Committed                     | Checksum                      |
 /// +-------------------------------+-------------------------------+ 192 | 24
 /// |                                                               |
 /// |               Start Timestamp                                 | 224 | 28
@@ -117,12 +390,16 @@ struct FileWriteInfo {
 /// |                                                               ...
 /// +---------------------------------------------------------------+ 1024 | 128
 /// ```
+/// The checksum covers the fields that don't change once the term is saved so the committed flag,
+/// the committed timestamp and the votes aren't part of it.  It is written last so a term that was
+/// only partly written doesn't match it.
 const TERM_ID_OFFSET: usize = 0;
 const VERSION_OFFSET: usize = 8;
 const TYPE_OFFSET: usize = 10;
 const SERVER_OFFSET: usize = 12;
 const LEADER_OFFSET: usize = 16;
 const COMMITTED: usize = 20;
+const CHECKSUM: usize = 22;
 const START_TIMESTAMP: usize = 24;
 const COMMITTED_TIMESTAMP: usize = 32;
 const FILE_ID: usize = 40;
@@ -157,178 +434,181 @@ trait CommitFile {
     fn set_length_of_commit(&mut self, pos: usize, val: u32) -> &mut Self;
     fn length_of_commit(&self, pos: usize) -> u32;
     fn save_term(&mut self, pos: usize, term: &TermCommit) -> &mut Self;
+    fn set_checksum(&mut self, pos: usize) -> &mut Self;
+    fn checksum(&self, pos: usize) -> u16;
+    fn verify(&self, pos: usize) -> bool;
     fn set_votes(&mut self, pos: usize, votes: u16) -> &mut Self;
     fn inc_votes(&mut self, pos: usize) -> u16;
     fn get_votes(&mut self, pos: usize) -> u16;
 }
 
-impl CommitFile for MemoryMappedInt {
+impl<B: DirectByteBuffer + ?Sized> CommitFile for B {
     #[inline]
     fn set_term(&mut self, pos: usize, val: u64) -> &mut Self {
-        let pos = TERM_ID_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + TERM_ID_OFFSET + pos;
         self.put_u64(pos, val);
         self
     }
 
     #[inline]
     fn term(&self, pos: usize) -> u64 {
-        let pos = TERM_ID_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + TERM_ID_OFFSET + pos;
         self.get_u64(pos)
     }
 
     #[inline]
     fn set_version(&mut self, pos: usize, val: u16) -> &mut Self {
-        let pos = VERSION_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + VERSION_OFFSET + pos;
         self.put_u16(pos, val);
         self
     }
 
     #[inline]
     fn version(&self, pos: usize) -> u16 {
-        let pos = VERSION_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + VERSION_OFFSET + pos;
         self.get_u16(pos)
     }
 
     #[inline]
     fn set_msg_type(&mut self, pos: usize, val: u16) -> &mut Self {
-        let pos = TYPE_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + TYPE_OFFSET + pos;
         self.put_u16(pos, val);
         self
     }
 
     #[inline]
     fn msg_type(&self, pos: usize) -> u16 {
-        let pos = TYPE_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + TYPE_OFFSET + pos;
         self.get_u16(pos)
     }
 
     #[inline]
     fn set_server(&mut self, pos: usize, server_id: u32) -> &mut Self {
-        let pos = SERVER_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + SERVER_OFFSET + pos;
         self.put_u32(pos, server_id);
         self
     }
 
     #[inline]
     fn server(&self, pos: usize) -> u32 {
-        let pos = SERVER_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + SERVER_OFFSET + pos;
         self.get_u32(pos)
     }
 
     #[inline]
     fn set_leader(&mut self, pos: usize, val: u32) -> &mut Self {
-        let pos = LEADER_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + LEADER_OFFSET + pos;
         self.put_u32(pos, val);
         self
     }
 
     #[inline]
     fn leader(&self, pos: usize) -> u32 {
-        let pos = LEADER_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + LEADER_OFFSET + pos;
         self.get_u32(pos)
     }
 
     #[inline]
     fn set_committed(&mut self, pos: usize) -> &mut Self {
-        let pos = COMMITTED + pos;
+        let pos = FILE_HEADER_SIZE + COMMITTED + pos;
         self.put_u16(pos, 1);
         self
     }
 
     #[inline]
     fn committed(&self, pos: usize) -> u16 {
-        let pos = COMMITTED + pos;
+        let pos = FILE_HEADER_SIZE + COMMITTED + pos;
         self.get_u16(pos)
     }
 
     #[inline]
     fn set_start_time(&mut self, pos: usize, time: u64) -> &mut Self {
-        let pos = START_TIMESTAMP + pos;
+        let pos = FILE_HEADER_SIZE + START_TIMESTAMP + pos;
         self.put_u64(pos, time);
         self
     }
 
     #[inline]
     fn start_time(&self, pos: usize) -> u64 {
-        let pos = START_TIMESTAMP + pos;
+        let pos = FILE_HEADER_SIZE + START_TIMESTAMP + pos;
         self.get_u64(pos)
     }
 
     #[inline]
     fn set_committed_timestamp(&mut self, pos: usize, val: u64) -> &mut Self {
-        let pos = COMMITTED_TIMESTAMP + pos;
+        let pos = FILE_HEADER_SIZE + COMMITTED_TIMESTAMP + pos;
         self.put_u64(pos, val);
         self
     }
 
     #[inline]
     fn committed_timestamp(&mut self, pos: usize) -> u64 {
-        let pos = COMMITTED_TIMESTAMP + pos;
+        let pos = FILE_HEADER_SIZE + COMMITTED_TIMESTAMP + pos;
         self.get_u64(pos)
     }
 
     #[inline]
     fn set_file_id(&mut self, pos: usize, val: u32) -> &mut Self {
-        let pos = FILE_ID + pos;
+        let pos = FILE_HEADER_SIZE + FILE_ID + pos;
         self.put_u32(pos, val);
         self
     }
 
     #[inline]
     fn file_id(&self, pos: usize) -> u32 {
-        let pos = FILE_ID + pos;
+        let pos = FILE_HEADER_SIZE + FILE_ID + pos;
         self.get_u32(pos)
     }
 
     #[inline]
     fn set_file_position_offset(&mut self, pos: usize, val: u64) -> &mut Self {
-        let pos = FILE_POSITION_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + FILE_POSITION_OFFSET + pos;
         self.put_u64(pos, val);
         self
     }
 
     #[inline]
     fn file_position_offset(&self, pos: usize) -> u64 {
-        let pos = FILE_POSITION_OFFSET + pos;
+        let pos = FILE_HEADER_SIZE + FILE_POSITION_OFFSET + pos;
         self.get_u64(pos)
     }
 
     #[inline]
     fn set_max_message_id(&mut self, pos: usize, val: u64) -> &mut Self {
-        let pos = MAX_MESSAGE_ID + pos;
+        let pos = FILE_HEADER_SIZE + MAX_MESSAGE_ID + pos;
         self.put_u64(pos, val);
         self
     }
 
     #[inline]
     fn max_message_id(&self, pos: usize) -> u64 {
-        let pos = MAX_MESSAGE_ID + pos;
+        let pos = FILE_HEADER_SIZE + MAX_MESSAGE_ID + pos;
         self.get_u64(pos)
     }
 
     #[inline]
     fn set_length_of_commit(&mut self, pos: usize, val: u32) -> &mut Self {
-        let pos = LENGTH_OF_COMMIT + pos;
+        let pos = FILE_HEADER_SIZE + LENGTH_OF_COMMIT + pos;
         self.put_u32(pos, val);
         self
     }
 
     #[inline]
     fn length_of_commit(&self, pos: usize) -> u32 {
-        let pos = LENGTH_OF_COMMIT + pos;
+        let pos = FILE_HEADER_SIZE + LENGTH_OF_COMMIT + pos;
         self.get_u32(pos)
     }
 
     #[inline]
     fn set_votes(&mut self, pos: usize, votes: u16) -> &mut Self {
-        let pos = VOTES + pos;
+        let pos = FILE_HEADER_SIZE + VOTES + pos;
         self.put_u16(pos, votes);
         self
     }
 
     #[inline]
     fn inc_votes(&mut self, pos: usize) -> u16 {
-        let pos = VOTES + pos;
+        let pos = FILE_HEADER_SIZE + VOTES + pos;
         let v = self.get_u16(pos) + 1;
         self.put_u16(pos, v);
         v
@@ -336,7 +616,7 @@ impl CommitFile for MemoryMappedInt {
 
     #[inline]
     fn get_votes(&mut self, pos: usize) -> u16 {
-        let pos = VOTES + pos;
+        let pos = FILE_HEADER_SIZE + VOTES + pos;
         self.get_u16(pos)
     }
 
@@ -356,11 +636,48 @@ impl CommitFile for MemoryMappedInt {
         if term.committed > 0 {
             self.set_committed(pos);
         }
+        // The fields have to be visible before the checksum says the term is there.
+        atomic::fence(atomic::Ordering::Release);
+        self.set_checksum(pos)
+    }
+
+    #[inline]
+    fn set_checksum(&mut self, pos: usize) -> &mut Self {
+        let checksum = term_checksum(self.get_bytes(FILE_HEADER_SIZE + pos, COMMIT_SIZE as usize));
+        self.put_u16(FILE_HEADER_SIZE + CHECKSUM + pos, checksum);
         self
     }
+
+    #[inline]
+    fn checksum(&self, pos: usize) -> u16 {
+        let pos = FILE_HEADER_SIZE + CHECKSUM + pos;
+        self.get_u16(pos)
+    }
+
+    #[inline]
+    fn verify(&self, pos: usize) -> bool {
+        FILE_HEADER_SIZE + pos + COMMIT_SIZE as usize <= self.capacity()
+            && self.checksum(pos)
+                == term_checksum(self.get_bytes(FILE_HEADER_SIZE + pos, COMMIT_SIZE as usize))
+    }
+}
+
+/// Calculates the checksum of a term.  The crc is folded down to fit in the 16 bits left in the
+/// term.
+/// # Arguments
+/// `slot` - The bytes of the term.
+fn term_checksum(slot: &[u8]) -> u16 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&slot[TERM_ID_OFFSET..COMMITTED]);
+    hasher.update(&slot[START_TIMESTAMP..COMMITTED_TIMESTAMP]);
+    hasher.update(&slot[FILE_ID..VOTES]);
+    let crc = hasher.finalize();
+    (crc >> 16) as u16 ^ crc as u16
 }
 
 /// A term committed in the raft protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TermCommit {
     /// The raft term id.
     term_id: u64,
@@ -388,33 +705,222 @@ pub struct TermCommit {
     length: u32,
 }
 
-/// The state of the raft node.
-#[allow(dead_code)]
-enum RaftNodeState {
-    /// The node is currently in a follower type and is copying messages to the buffer.
-    Follower(PersistedCommitStreamFollower),
-    /// The node is a candidate.
-    Candidate,
-    /// The node is the leader and is writing and sending messages.
-    Leader(PersistedCommitStreamLeader),
-    /// In single node mode.
-    SingleNode(PersistedCommitSingleNode),
-}
-
-/// The events for the raft protocol.
-#[allow(dead_code)]
-enum RaftNodeEvent {
-    ElectionTimeout = 0,
-    NewTerm = 1,
-    HeartbeatFailed = 2,
-    HeartbeatTimeout = 3,
-    HigherTerm = 4,
+/// When the messages should be flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FlushPolicy {
+    /// Flush every time a term is committed.
+    EveryCommit,
+    /// Flush after the number of messages have been written.
+    EveryNMessages(u32),
+    /// Flush on an interval.
+    Interval(Duration),
+    /// Let the operating system decide when to write the pages back.
+    Os,
 }
 
+/// Batches the written messages so one flush of the event file and one term cover all of them
+/// instead of committing each block as soon as it is written.  A batch is committed once any of the
+/// limits is reached or the writer has moved past what one term can hold.
+///
+///```text
+///  add_message x N --> [ waiting batch ] --limit or timer--> flush event file --> save term
+///                                                                                     |
+///           complete the N commit futures <-------------------------------------------+
+///```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GroupCommit {
+    /// Commits once the waiting messages take up this many bytes.  A term can't hold more than
+    /// the commit block so anything larger is capped to it.
+    pub max_bytes: usize,
+    /// Commits once this many messages are waiting.
+    pub max_messages: u64,
+    /// The longest the first message in a batch waits before the batch is committed.
+    pub max_latency: Duration,
+}
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        GroupCommit {
+            max_bytes: COMMIT_BLOCK_SIZE,
+            max_messages: 1024,
+            max_latency: Duration::from_millis(1),
+        }
+    }
+}
+
+impl GroupCommit {
+    /// True if a batch has reached one of the limits.
+    /// # Arguments
+    /// `bytes` - The number of bytes waiting.
+    /// `messages` - The number of messages waiting.
+    /// `waited` - How long the first message has been waiting.
+    pub fn is_ready(&self, bytes: usize, messages: u64, waited: Duration) -> bool {
+        bytes >= self.max_bytes.min(COMMIT_BLOCK_SIZE)
+            || messages >= self.max_messages
+            || waited >= self.max_latency
+    }
+}
+
+/// Which files to keep when cleaning up old messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    /// Keep all of the files.
+    KeepAll,
+    /// Keep the last number of files.
+    KeepFiles(u32),
+    /// Keep up to the number of bytes.
+    KeepBytes(u64),
+    /// Keep the messages newer than the duration.
+    KeepNewerThan(Duration),
+    /// Keep everything above the message id.
+    KeepAboveId(u64),
+}
+
+/// The error a processor returns when it is unable to handle a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessError {
+    /// Why the message couldn't be handled.
+    pub reason: String,
+}
+
+impl ProcessError {
+    /// Creates a new process error.
+    /// # Arguments
+    /// `reason` - Why the message couldn't be handled.
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        ProcessError {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unable to process the message: {}", self.reason)
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+/// What the read stream does when the processor returns an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Stops on the message and returns the error.  The message is handed to the processor again
+    /// the next time the stream is run.
+    #[default]
+    Stop,
+    /// Counts the message as skipped and moves on to the next one.
+    Skip,
+    /// Hands the message to the processor again up to the number of times before stopping.
+    Retry(u32),
+}
+
+/// Handles the messages as they are committed.
 pub trait MessageProcessor: Send {
-    /// Handles an incoming message.
+    /// Handles an incoming message.  The message points into the mapping of the file so it isn't
+    /// copied.  The stream holds a handle to the file for the call so the mapping is valid even if
+    /// the file is removed but the message can't be kept after the call returns.  Copy the parts
+    /// that are needed or use `DeliveryMode::Owned`.
+    /// `read` - The message that has been read in.
+    /// # Returns
+    /// An error to have the stream apply its `ErrorPolicy`.
+    fn handle(&mut self, read: MessageRead<'_>) -> Result<(), ProcessError>;
+
+    /// Handles the committed messages gathered by the stream.  A batch never has a message that
+    /// isn't committed and never goes past the end of a file.  When an error is returned the
+    /// last message taken from the batch is the one that failed and the messages left in the
+    /// batch are handed out again.
+    /// `batch` - The messages in the order they were written.
+    /// # Returns
+    /// An error to have the stream apply its `ErrorPolicy` to the last message taken.
+    fn handle_batch(
+        &mut self,
+        batch: &mut dyn Iterator<Item = MessageRead<'_>>,
+    ) -> Result<(), ProcessError> {
+        for read in batch {
+            self.handle(read)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a message that was copied out of the file.  Only called when the stream uses
+    /// `DeliveryMode::Owned`.  The message can be kept or sent to another thread.
+    /// `msg` - The copy of the message.
+    fn handle_owned(&mut self, msg: OwnedMessage) -> Result<(), ProcessError> {
+        panic!(
+            "The processor doesn't handle owned messages so it can't be used with \
+            DeliveryMode::Owned.  Got message {}.",
+            msg.message_id
+        );
+    }
+}
+
+/// A processor that can't fail.  Every infallible processor is a `MessageProcessor` that always
+/// returns `Ok`.
+pub trait InfallibleProcessor: Send {
+    /// Handles an incoming message.  See `MessageProcessor::handle`.
     /// `read` - The message that has been read in.
     fn handle<'a>(&mut self, read: &MessageRead<'a>);
+
+    /// Handles a message that was copied out of the file.  See `MessageProcessor::handle_owned`.
+    /// `msg` - The copy of the message.
+    fn handle_owned(&mut self, msg: OwnedMessage) {
+        panic!(
+            "The processor doesn't handle owned messages so it can't be used with \
+            DeliveryMode::Owned.  Got message {}.",
+            msg.message_id
+        );
+    }
+}
+
+impl<P: InfallibleProcessor> MessageProcessor for P {
+    fn handle(&mut self, read: MessageRead<'_>) -> Result<(), ProcessError> {
+        InfallibleProcessor::handle(self, &read);
+        Ok(())
+    }
+
+    fn handle_owned(&mut self, msg: OwnedMessage) -> Result<(), ProcessError> {
+        InfallibleProcessor::handle_owned(self, msg);
+        Ok(())
+    }
+}
+
+/// How the read stream hands the messages to the processor.
+#[derive(Clone, Default)]
+pub enum DeliveryMode {
+    /// Calls `handle` with the message in the file.  Nothing is copied.
+    #[default]
+    Borrowed,
+    /// Copies the message into a buffer from the pool and calls `handle_owned`.
+    Owned(Arc<BufferPool>),
+}
+
+/// A message copied out of the file so it can outlive the call to the processor.
+#[derive(Debug)]
+pub struct OwnedMessage {
+    pub msg_type_id: i32,
+    pub message_id: u64,
+    /// The user metadata.
+    pub headers: MessageHeaders,
+    /// The body of the message.  The buffer goes back to the pool when it is dropped.
+    pub body: PooledBuf,
+}
+
+impl OwnedMessage {
+    /// Copies a message.
+    /// # Arguments
+    /// `read` - The message to copy.
+    /// `pool` - The pool to get the buffer for the body from.
+    pub fn copy_of(read: &MessageRead<'_>, pool: &Arc<BufferPool>) -> Self {
+        OwnedMessage {
+            msg_type_id: read.msg_type_id(),
+            message_id: read.message_id(),
+            headers: read.headers(),
+            body: pool.copy_of(read.bytes()),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -429,6 +935,15 @@ struct MessageReader {
     file_id: u32,
 }
 
+/// The number of messages between the entries in the position index.
+const POSITION_INDEX_INTERVAL: u64 = 256;
+
+/// The id of the file and the position of the messages written.  Only has an entry every
+/// `POSITION_INDEX_INTERVAL` messages and for the first message in a file so a reader can start
+/// close to a message instead of scanning from the first file.  Only the messages written since the
+/// store was opened are in it.
+pub(crate) type PositionIndex = ConcurrentSkipListMap<u64, (u32, usize)>;
+
 /// Represents the write stream.  Would only be used if we are the current leader.
 pub struct PersistedMessageWriteStream {
     /// The buffer we are writing to.
@@ -447,6 +962,20 @@ pub struct PersistedMessageWriteStream {
     current_pos: usize,
     /// The last message id in the file.
     loaded_message_id: u64,
+    /// Where the messages written are.
+    positions: Option<Arc<PositionIndex>>,
+    /// The id of the last message added to the position index.
+    last_indexed_id: u64,
+    /// Adds the positions to the index file next to the event file.
+    sparse_index: Option<SparseIndexWriter>,
+    /// Where to publish the files being rolled.
+    events: Option<Arc<StoreEvents>>,
+    /// The files the readers know about.  The files we roll onto are added to it.
+    message_files: Option<Arc<Mutex<Vec<MessageFileInfo>>>>,
+    /// Where the event files are kept.
+    backend: Arc<dyn MessageStoreBackend>,
+    /// Where the bytes written are recorded so only they are flushed.
+    dirty: Option<Arc<DirtyRanges>>,
 }
 
 impl PersistedMessageWriteStream {
@@ -463,33 +992,151 @@ impl PersistedMessageWriteStream {
         file_prefix: String,
         file_size: usize,
         max_message_id: Arc<AtomicU64>,
+    ) -> file::Result<Self> {
+        PersistedMessageWriteStream::new_with_backend(
+            start_file_id,
+            file_storage_directory,
+            file_prefix,
+            file_size,
+            max_message_id,
+            Arc::new(FileBackend),
+        )
+    }
+
+    /// Creates a new message write stream with the event files kept in a backend.
+    /// # Arguments
+    /// `start_file_id` - The starting file id.  This is expected to be the last file.
+    /// `file_storage_directory` - The file storage directory.
+    /// `file_prefix` - The prefix for the file.
+    /// `file_size` - The size of the file.
+    /// `max_message_id` - The current maximum message id.
+    /// `backend` - Where the event files are kept.
+    pub(crate) fn new_with_backend(
+        start_file_id: u32,
+        file_storage_directory: String,
+        file_prefix: String,
+        file_size: usize,
+        max_message_id: Arc<AtomicU64>,
+        backend: Arc<dyn MessageStoreBackend>,
     ) -> file::Result<Self> {
         let mut event_name =
             create_event_name(&file_storage_directory, &file_prefix, &start_file_id);
-        let mut buffer = unsafe { MessageFileStore::open_write(&event_name, file_size)? };
+        let (reader, mut buffer) = backend.open_write(Path::new(&event_name), file_size)?;
         let mut file_id = start_file_id;
-        let reader = unsafe { MessageFileStore::open_readonly(&event_name)? };
         let (pos, last_msg_id) = match find_end_of_buffer(&reader)? {
             FindEmptySlotResult::Pos(p, last_msg_id) => (p, last_msg_id),
             FindEmptySlotResult::Full(last_msg_id) => {
                 file_id += 1;
                 event_name = create_event_name(&file_storage_directory, &file_prefix, &file_id);
-                buffer = unsafe { MessageFileStore::open_write(&event_name, file_size)? };
+                buffer = backend.open_write(Path::new(&event_name), file_size)?.1;
                 (0, last_msg_id)
             }
         };
         Ok(PersistedMessageWriteStream {
             buffer,
-            file_id: start_file_id,
+            file_id,
             max_message_id,
             file_storage_directory,
             file_prefix,
             file_size,
             current_pos: pos,
             loaded_message_id: last_msg_id,
+            positions: None,
+            last_indexed_id: 0,
+            sparse_index: None,
+            events: None,
+            message_files: None,
+            backend,
+            dirty: None,
         })
     }
 
+    /// The id of the file and the position the next message is written at.
+    pub fn position(&self) -> (u32, usize) {
+        (self.file_id, self.current_pos)
+    }
+
+    /// Publishes the files being rolled.
+    /// # Arguments
+    /// `events` - The events for the store.
+    fn with_events(mut self, events: Arc<StoreEvents>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Publishes that we moved onto the next file.
+    fn file_rolled(&self) {
+        if let Some(events) = &self.events {
+            events.emit(StoreEvent::FileRolled {
+                file_id: self.file_id,
+            });
+        }
+    }
+
+    /// Adds the files we roll onto to the files the readers know about.
+    /// # Arguments
+    /// `message_files` - The message files of the file collection.
+    fn with_message_files(mut self, message_files: Arc<Mutex<Vec<MessageFileInfo>>>) -> Self {
+        self.message_files = Some(message_files);
+        self
+    }
+
+    /// Records the bytes written so the flush only syncs them.
+    /// # Arguments
+    /// `dirty` - Where to record the bytes written.
+    pub(crate) fn with_dirty_ranges(mut self, dirty: Arc<DirtyRanges>) -> Self {
+        self.dirty = Some(dirty);
+        self
+    }
+
+    /// Records the bytes written to the current file.
+    /// # Arguments
+    /// `start` - The position of the first byte written.
+    /// `end` - The position after the last byte written.
+    fn mark_dirty(&self, start: usize, end: usize) {
+        if let Some(dirty) = &self.dirty {
+            dirty.record(self.file_id, start, end);
+        }
+    }
+
+    /// Adds the positions of the messages written to an index.
+    /// # Arguments
+    /// `positions` - The index to add the positions to.
+    fn with_positions(mut self, positions: Arc<PositionIndex>) -> Self {
+        self.positions = Some(positions);
+        self
+    }
+
+    /// Keeps an index file next to each event file with the position of every `interval`
+    /// messages.  The index of the file being written to is rebuilt from the messages in it.
+    /// # Arguments
+    /// `interval` - The number of messages between the entries.
+    fn with_sparse_index(mut self, interval: u64) -> Self {
+        self.sparse_index = Some(SparseIndexWriter::open(
+            &self.file_storage_directory,
+            &self.file_prefix,
+            self.file_id,
+            interval,
+        ));
+        self
+    }
+
+    /// Adds the position of a message to the index if it is due for an entry.
+    /// # Arguments
+    /// `msg_id` - The id of the message.
+    /// `pos` - The position the message was written at.
+    fn index_position(&mut self, msg_id: u64, pos: usize) {
+        if let Some(positions) = &self.positions {
+            if pos == 0 || msg_id >= self.last_indexed_id + POSITION_INDEX_INTERVAL {
+                positions.insert(msg_id, (self.file_id, pos));
+                self.last_indexed_id = msg_id;
+            }
+        }
+        if let Some(index) = &mut self.sparse_index {
+            index.add(self.file_id, msg_id, pos);
+        }
+    }
+
     /// Writes to the file at a specified position.  Is done when copying the files.
     /// # Arguments
     #[allow(dead_code)]
@@ -509,40 +1156,56 @@ impl PersistedMessageWriteStream {
     /// `msg_type` - The type of the message.
     /// `msg_id` - The id of the message.
     /// `buffer` - The buffer to write to the message buffer.
-    fn add_message(
+    pub(crate) fn add_message(
+        &mut self,
+        msg_type: i32,
+        msg_id: u64,
+        buffer: &[u8],
+    ) -> crate::file::Result<(usize, u32)> {
+        self.add_message_with_headers(msg_type, msg_id, &MessageHeaders::default(), buffer)
+    }
+
+    /// Adds the message with the user metadata to the buffer.  Only to be used if this is the
+    /// leader.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `msg_id` - The id of the message.
+    /// `headers` - The user metadata of the message.
+    /// `buffer` - The buffer to write to the message buffer.
+    fn add_message_with_headers(
         &mut self,
         msg_type: i32,
         msg_id: u64,
+        headers: &MessageHeaders,
         buffer: &[u8],
     ) -> crate::file::Result<(usize, u32)> {
         match self
             .buffer
-            .write(self.current_pos, msg_type, msg_id, buffer)
+            .write_with_headers(self.current_pos, msg_type, msg_id, headers, buffer)
         {
             Ok(s) => {
+                let pos = self.current_pos;
                 self.current_pos = s;
+                self.mark_dirty(pos, s);
+                self.index_position(msg_id, pos);
                 self.max_message_id
                     .store(msg_id, atomic::Ordering::Release);
                 Ok((s, self.file_id))
             }
             Err(e) => match e {
                 file::Error::Full => {
-                    self.buffer
-                        .write(self.current_pos, -1, std::u64::MAX, &[0, 0])?;
-                    self.file_id += 1;
-                    self.current_pos = 0;
-                    let file = create_commit_name(
-                        &self.file_storage_directory,
-                        &self.file_prefix,
-                        &self.file_id,
-                    );
-                    self.buffer = unsafe { MessageFileStore::open_write(&file, self.file_size)? };
-                    match self
-                        .buffer
-                        .write(self.current_pos, msg_type, msg_id, buffer)
-                    {
+                    self.next_file(msg_id)?;
+                    match self.buffer.write_with_headers(
+                        self.current_pos,
+                        msg_type,
+                        msg_id,
+                        headers,
+                        buffer,
+                    ) {
                         Ok(s) => {
-                            self.current_pos += s;
+                            self.current_pos = s;
+                            self.mark_dirty(0, s);
+                            self.index_position(msg_id, 0);
                             self.max_message_id
                                 .store(msg_id, atomic::Ordering::Release);
                             Ok((s, self.file_id))
@@ -555,32 +1218,602 @@ impl PersistedMessageWriteStream {
         }
     }
 
-    pub fn flush(&self) -> crate::file::Result<()> {
-        self.buffer.flush()
+    /// Adds the messages in a transaction.  The transaction message is written first followed by
+    /// the messages.  A transaction is never split between files.
+    /// # Arguments
+    /// `msg_id` - The id of the transaction message.  The messages get the ids after it.
+    /// `batch` - The encoded messages in the transaction.
+    /// # Returns
+    /// The id of the last message in the transaction.
+    pub(crate) fn add_transaction(
+        &mut self,
+        msg_id: u64,
+        batch: &[u8],
+    ) -> crate::file::Result<u64> {
+        let mut count = 0u32;
+        let mut size = aligned_message_size(4);
+        for_each_batch_message(batch, |_, body| {
+            count += 1;
+            size += aligned_message_size(body.len());
+        });
+        // Leave room for the end of file message.
+        let end_size = aligned_message_size(2);
+        let capacity = self.buffer.capacity();
+        if size + end_size > capacity {
+            return Err(file::Error::NotEnoughSpace {
+                message_size: size as u32,
+                position: 0,
+                capacity,
+                remaining: capacity - end_size,
+            });
+        }
+        if size + end_size > capacity - self.current_pos {
+            self.next_file(msg_id)?;
+        }
+        let mut header = [0; 4];
+        BigEndian::write_u32(&mut header, count);
+        let pos = self.current_pos;
+        self.current_pos = self.buffer.write(
+            self.current_pos,
+            TRANSACTION_MESSAGE_TYPE,
+            msg_id,
+            &header,
+        )?;
+        let mut last_id = msg_id;
+        let mut result = Ok(());
+        for_each_batch_message(batch, |msg_type, body| {
+            if result.is_ok() {
+                last_id += 1;
+                match self.buffer.write(self.current_pos, msg_type, last_id, body) {
+                    Ok(pos) => self.current_pos = pos,
+                    Err(e) => result = Err(e),
+                }
+            }
+        });
+        result?;
+        self.mark_dirty(pos, self.current_pos);
+        self.index_position(msg_id, pos);
+        // Only publish once the whole transaction has been written.
+        self.max_message_id.store(last_id, atomic::Ordering::Release);
+        Ok(last_id)
     }
-}
 
-/// Represents a stream we are currently reading in a processing messages.  Each message is
-/// processed in a single thread.
-pub struct PersistedMessageReadStream<FRead>
-where
-    FRead: MessageProcessor,
-{
-    /// The buffer we are currently reading.
-    buffer: MessageFileStoreRead,
-    /// The id of the file.
-    file_id: u32,
-    /// The maximum message id.
-    max_message_id: Arc<AtomicU64>,
-    /// What processes the incomming message as they are commited.
-    message_processor: FRead,
-    /// The storage directory.
-    file_storage_directory: String,
-    /// The file prefix.
-    file_prefix: String,
-    /// The current position in the files.
-    current_pos: usize,
-}
+    /// Adds a message that is too big to fit in a file.  The body is split into chunks that are
+    /// written as their own frames with the id of the message and the chunks roll over into the
+    /// next files.  The id is only published once the last chunk has been written so a message
+    /// that was partly written is removed when the store is started again.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `reader` - Where to read the body from.
+    /// `total_len` - The length of the body.
+    /// # Returns
+    /// The position after the last chunk and the id of the file it is in.
+    pub fn append_large<R: io::Read>(
+        &mut self,
+        msg_type: i32,
+        message_id: u64,
+        reader: &mut R,
+        total_len: u64,
+    ) -> crate::file::Result<(usize, u32)> {
+        let chunk_size = chunk_size(self.file_size);
+        let count = chunk_count(total_len, chunk_size);
+        let mut frame = vec![0; CHUNK_HEADER_SIZE + chunk_size];
+        // Leave room for the end of file message.
+        let end_size = aligned_message_size(2);
+        let mut remaining = total_len;
+        for index in 0..count {
+            let length = remaining.min(chunk_size as u64) as usize;
+            ChunkHeader {
+                msg_type_id: msg_type,
+                index,
+                count,
+                total_len,
+            }
+            .write(&mut frame[..CHUNK_HEADER_SIZE]);
+            reader.read_exact(&mut frame[CHUNK_HEADER_SIZE..CHUNK_HEADER_SIZE + length])?;
+            let body = &frame[..CHUNK_HEADER_SIZE + length];
+            if aligned_message_size(body.len()) + end_size
+                > self.buffer.capacity() - self.current_pos
+            {
+                self.next_file(message_id)?;
+            }
+            let pos = self.current_pos;
+            self.current_pos = self
+                .buffer
+                .write(pos, CHUNK_MESSAGE_TYPE, message_id, body)?;
+            self.mark_dirty(pos, self.current_pos);
+            if index == 0 {
+                self.index_position(message_id, pos);
+            }
+            remaining -= length as u64;
+        }
+        self.max_message_id
+            .store(message_id, atomic::Ordering::Release);
+        Ok((self.current_pos, self.file_id))
+    }
+
+    /// Ends the current file and starts writing to the next one.
+    /// # Arguments
+    /// `next_message_id` - The id of the first message written to the next file.
+    fn next_file(&mut self, next_message_id: u64) -> crate::file::Result<()> {
+        match self.buffer.write(
+            self.current_pos,
+            END_OF_FILE_MESSAGE_TYPE,
+            u64::MAX,
+            &[0, 0],
+        ) {
+            Ok(end) => self.mark_dirty(self.current_pos, end),
+            // The rest of the file is filled in when the end message doesn't fit.
+            Err(file::Error::Full) => self.mark_dirty(self.current_pos, self.buffer.capacity()),
+            Err(e) => return Err(e),
+        }
+        self.file_id += 1;
+        self.current_pos = 0;
+        let file = create_event_name(&self.file_storage_directory, &self.file_prefix, &self.file_id);
+        self.buffer = self
+            .backend
+            .open_write(Path::new(&file), self.file_size)?
+            .1;
+        if let Some(message_files) = &self.message_files {
+            let mut message_files = message_files.lock().unwrap();
+            if !message_files.iter().any(|f| f.file_id == self.file_id) {
+                message_files.push(MessageFileInfo::new(file, self.file_id, next_message_id));
+                message_files.sort();
+            }
+        }
+        self.file_rolled();
+        Ok(())
+    }
+
+    /// Removes the messages after an id so a follower can write the messages of a new leader in
+    /// their place.  The id of the last message written is moved back first so the readers stop in
+    /// front of the messages being cleared and the first message removed is cleared from its size
+    /// so a reader that gets to it sees the end of the messages.  The event files after it are
+    /// removed.  The terms need to be removed from the commit stream before this is called.
+    /// # Arguments
+    /// `message_id` - The id of the last message to keep.
+    pub fn truncate_after(&mut self, message_id: u64) -> crate::file::Result<()> {
+        if message_id >= self.max_message_id.load(atomic::Ordering::Acquire) {
+            return Ok(());
+        }
+        self.max_message_id
+            .store(message_id, atomic::Ordering::Release);
+        let mut file_id = self.file_id;
+        let (writer, pos) = loop {
+            let path = create_event_name(&self.file_storage_directory, &self.file_prefix, &file_id);
+            let (reader, writer) = self.backend.open(Path::new(&path))?;
+            let pos = find_after(&reader, message_id)?;
+            if pos > 0 || file_id == 1 {
+                break (writer, pos);
+            }
+            let previous = create_event_name(
+                &self.file_storage_directory,
+                &self.file_prefix,
+                &(file_id - 1),
+            );
+            if !self.backend.exists(Path::new(&previous)) {
+                break (writer, pos);
+            }
+            file_id -= 1;
+        };
+        writer.clear(pos, writer.capacity() - pos);
+        writer.flush()?;
+        for removed in file_id + 1..=self.file_id {
+            let path = create_event_name(&self.file_storage_directory, &self.file_prefix, &removed);
+            self.backend.remove(Path::new(&path))?;
+            let index =
+                create_index_name(&self.file_storage_directory, &self.file_prefix, &removed);
+            if Path::new(&index).exists() {
+                remove_file(&index)?;
+            }
+        }
+        if let Some(message_files) = &self.message_files {
+            message_files
+                .lock()
+                .unwrap()
+                .retain(|f| f.file_id <= file_id);
+        }
+        if let Some(positions) = &self.positions {
+            let removed: Vec<u64> = positions
+                .range(message_id + 1..)
+                .map(|(id, _)| id)
+                .collect();
+            for id in removed {
+                positions.remove(&id);
+            }
+            self.last_indexed_id = positions
+                .floor_entry(&message_id)
+                .map(|(id, _)| id)
+                .unwrap_or(0);
+        }
+        self.buffer = writer;
+        self.file_id = file_id;
+        self.current_pos = pos;
+        self.loaded_message_id = self.loaded_message_id.min(message_id);
+        let interval = self.sparse_index.as_ref().map(|index| index.interval());
+        if let Some(interval) = interval {
+            // Rebuilt from the messages left in the file.
+            self.sparse_index = Some(SparseIndexWriter::open(
+                &self.file_storage_directory,
+                &self.file_prefix,
+                file_id,
+                interval,
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> crate::file::Result<()> {
+        self.buffer.flush()
+    }
+}
+
+impl Drop for PersistedMessageWriteStream {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::warn!("Unable to flush event file {}: {}", self.file_id, e);
+        }
+    }
+}
+
+/// Removes a transaction that was only partly written when we stopped.  A transaction is never
+/// split between files so only the last file needs to be checked.  The bytes after the last
+/// message are also cleared since a write that was torn by a crash can leave part of its body
+/// behind, which would be read as a message once a shorter one is written in front of it.
+/// # Arguments
+/// `path` - The path to the last event file.
+/// # Returns
+/// The id of the last message that is safe to commit.
+fn recover_transaction(path: &str) -> crate::file::Result<u64> {
+    let reader = unsafe { MessageFileStore::open_readonly(&path)? };
+    let mut pos = 0;
+    let mut last_id = 0;
+    // Set if the messages end before the end of the file.
+    let mut ended = false;
+    // The position of the transaction, the id before it and the last id in the transaction.
+    let mut transaction: Option<(usize, u64, u64)> = None;
+    loop {
+        match reader.read_new(pos) {
+            Ok(msg) => {
+                if msg.message_id() == 0 || msg.message_id() == u64::MAX {
+                    ended = msg.message_id() == 0;
+                    break;
+                }
+                if msg.msg_type_id() == TRANSACTION_MESSAGE_TYPE {
+                    transaction = Some((
+                        pos,
+                        last_id,
+                        msg.message_id() + transaction_count(msg.bytes()),
+                    ));
+                }
+                last_id = msg.message_id();
+                pos = msg.next_pos();
+            }
+            Err(e) => match e {
+                file::Error::NoMessage => {
+                    ended = true;
+                    break;
+                }
+                file::Error::Full | file::Error::PositionOutOfRange(_) => break,
+                _ => return Err(e),
+            },
+        }
+    }
+    let (start, recovered_id) = match transaction {
+        Some((start, before_id, end_id)) if last_id < end_id => (start, before_id),
+        _ => (pos, last_id),
+    };
+    let end = if ended {
+        pos.max(end_of_written(&reader, pos, reader.capacity())?)
+    } else {
+        pos
+    };
+    if start < end {
+        let writer = unsafe { MessageFileStore::open_write(&path, 0)? };
+        writer.clear(start, end - start);
+        writer.flush()?;
+    }
+    Ok(recovered_id)
+}
+
+/// Clears the messages after the committed position in the newest event file that were only
+/// partly written when the process died.  Has to happen before anything else reads the file.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the event files.
+/// `file_id` - The id of the newest event file.
+/// `commit_files` - The commit files to find the committed position in.
+/// # Returns
+/// The position the file was cleared from.
+fn recover_torn_write(
+    file_storage_directory: &str,
+    file_prefix: &str,
+    file_id: u32,
+    commit_files: &Arc<Mutex<Vec<CommitFileInfo>>>,
+) -> crate::file::Result<Option<usize>> {
+    let committed = match find_last_commit_pos(commit_files) {
+        LastCommitPos::LastCommit {
+            start_term_id,
+            term_id,
+            max_message_id,
+            path,
+            ..
+        } => {
+            let file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let buffer = unsafe { open_commit_file(file)? };
+            let pos = ((term_id - start_term_id) * COMMIT_SIZE) as usize;
+            let position =
+                buffer.file_position_offset(pos) as usize + buffer.length_of_commit(pos) as usize;
+            match buffer.file_id(pos) {
+                id if id == file_id => Some((position, max_message_id)),
+                id if id < file_id => Some((0, max_message_id)),
+                // Everything in the file has been committed.
+                _ => None,
+            }
+        }
+        LastCommitPos::NoCommits => Some((0, 0)),
+    };
+    match committed {
+        Some((start, last_id)) => verify::truncate_torn_tail(
+            &create_event_name(file_storage_directory, file_prefix, &file_id),
+            start,
+            last_id,
+        ),
+        None => Ok(None),
+    }
+}
+
+/// Recovers the event file the writer continues in.  The transaction that wasn't finished is
+/// cleared and the end of the messages is found.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the event files.
+/// `file_id` - The id of the event file the writer continues in.
+/// # Returns
+/// Where the writer picks up.
+fn recover_tail(
+    file_storage_directory: &str,
+    file_prefix: &str,
+    file_id: u32,
+) -> crate::file::Result<RecoveredPosition> {
+    let path = create_event_name(file_storage_directory, file_prefix, &file_id);
+    let message_id = recover_transaction(&path)?;
+    let reader = unsafe { MessageFileStore::open_readonly(&path)? };
+    Ok(match find_end_of_buffer(&reader)? {
+        FindEmptySlotResult::Pos(position, _) => RecoveredPosition {
+            file_id,
+            position,
+            message_id,
+        },
+        // The writer moves onto the next file.
+        FindEmptySlotResult::Full(_) => RecoveredPosition {
+            file_id: file_id + 1,
+            position: 0,
+            message_id,
+        },
+    })
+}
+
+/// Where the chunks of the last message in a file are.
+enum ChunkRun {
+    /// The last message in the file was completely written.
+    Complete,
+    /// The chunks of a message start at the position and the last chunk isn't in the file.
+    Partial(usize),
+    /// The file only has chunks of a message that started in a previous file.
+    Continued,
+}
+
+/// Finds out if a file ends in the middle of a chunked message.
+/// # Arguments
+/// `reader` - The reader for the file.
+fn last_chunk_run(reader: &MessageFileStoreRead) -> crate::file::Result<ChunkRun> {
+    let mut pos = 0;
+    let mut start = None;
+    let mut continued = false;
+    loop {
+        match reader.read_new(pos) {
+            Ok(msg) => {
+                if msg.message_id() == 0 || msg.message_id() == u64::MAX {
+                    break;
+                }
+                match msg.chunk() {
+                    Some(chunk) if chunk.is_last() => {
+                        start = None;
+                        continued = false;
+                    }
+                    Some(chunk) if chunk.index == 0 => start = Some(pos),
+                    Some(_) => continued = pos == 0 || continued,
+                    None => {
+                        start = None;
+                        continued = false;
+                    }
+                }
+                pos = msg.next_pos();
+            }
+            Err(e) => match e {
+                file::Error::NoMessage | file::Error::Full | file::Error::PositionOutOfRange(_) => {
+                    break
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+    Ok(match start {
+        Some(start) => ChunkRun::Partial(start),
+        None if continued => ChunkRun::Continued,
+        None => ChunkRun::Complete,
+    })
+}
+
+/// Removes a chunked message that was only partly written when we stopped.  The chunks can span
+/// files so the files after the one the message starts in are removed and the file it starts in
+/// is cleared from the first chunk.
+/// # Arguments
+/// `file_storage_directory` - The directory the event files are in.
+/// `file_prefix` - The prefix of the event files.
+/// `file_ids` - The ids of the event files in order.  A compaction can leave gaps in the ids.
+/// # Returns
+/// The id of the event file to keep writing to.
+fn recover_chunked_message(
+    file_storage_directory: &str,
+    file_prefix: &str,
+    file_ids: &[u32],
+) -> crate::file::Result<u32> {
+    let last = match file_ids.len() {
+        0 => return Ok(1),
+        len => len - 1,
+    };
+    let mut current = last;
+    let start = loop {
+        let path = create_event_name(file_storage_directory, file_prefix, &file_ids[current]);
+        let reader = unsafe { MessageFileStore::open_readonly(&path)? };
+        match last_chunk_run(&reader)? {
+            ChunkRun::Complete if current == last => return Ok(file_ids[last]),
+            // The message started at the start of the file after this one.
+            ChunkRun::Complete => {
+                current += 1;
+                break 0;
+            }
+            ChunkRun::Partial(pos) => break pos,
+            ChunkRun::Continued if current > 0 => current -= 1,
+            ChunkRun::Continued => break 0,
+        }
+    };
+    let path = create_event_name(file_storage_directory, file_prefix, &file_ids[current]);
+    let writer = unsafe { MessageFileStore::open_write(&path, 0)? };
+    // Clear the rest of the file so the end of file message is removed too.
+    writer.clear(start, writer.capacity() - start);
+    writer.flush()?;
+    for id in &file_ids[(current + 1)..] {
+        remove_file(create_event_name(file_storage_directory, file_prefix, id))?;
+    }
+    Ok(file_ids[current])
+}
+
+/// Finds the end of the bytes that have been written to in a file.
+/// # Arguments
+/// `reader` - The reader for the file.
+/// `pos` - The position to start looking from.
+/// `capacity` - The size of the file.
+/// # Returns
+/// The position after the last byte that isn't zero.
+fn end_of_written(
+    reader: &MessageFileStoreRead,
+    pos: usize,
+    capacity: usize,
+) -> crate::file::Result<usize> {
+    if pos + aligned_message_size(0) > capacity {
+        return Ok(pos);
+    }
+    let bytes = reader.read_section(pos, capacity - pos)?;
+    Ok(bytes
+        .iter()
+        .rposition(|b| *b != 0)
+        .map(|last| pos + last + 1)
+        .unwrap_or(pos))
+}
+
+/// Reads in the next block to commit.  The block always ends on the boundary of a transaction so a
+/// transaction is committed in a single term.
+/// # Arguments
+/// `message_file` - The file to read from.
+/// `pos` - The position to start reading from.
+/// `max_message_id` - The id of the last message that has been completely written.
+fn read_commit_block<'a>(
+    message_file: &'a MessageFileStoreRead,
+    pos: usize,
+    max_message_id: u64,
+) -> crate::file::Result<MessageBlock<'a>> {
+    let block = message_file.read_block(pos, max_message_id, COMMIT_BLOCK_SIZE)?;
+    let mut current = pos;
+    while current < block.next_pos {
+        let msg = message_file.read_new(current)?;
+        if msg.msg_type_id() == TRANSACTION_MESSAGE_TYPE {
+            let last_id = msg.message_id() + transaction_count(msg.bytes());
+            if last_id > block.message_id_end {
+                return if current == pos {
+                    // The transaction is bigger than a block so it gets a term to itself.
+                    message_file.read_block(pos, last_id, usize::MAX)
+                } else {
+                    message_file.read_block(pos, msg.message_id() - 1, COMMIT_BLOCK_SIZE)
+                };
+            }
+        }
+        current = msg.next_pos();
+    }
+    Ok(block)
+}
+
+/// Goes through the messages with a copy of each one.  The gaps in the message ids are skipped and
+/// the iterator ends at the last committed message or once the number of messages has been read.
+pub struct OwnedMessageIterator {
+    iterator: MessageIterator,
+    /// Set once the end or an error has been returned.
+    done: bool,
+}
+
+impl Iterator for OwnedMessageIterator {
+    type Item = crate::file::Result<OwnedMessageInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        loop {
+            let result = match self.iterator.next() {
+                Ok(NextResult::Some(msg)) => Some(Ok(OwnedMessageInfo::copy_of(&msg))),
+                // The message after the gap is returned on the next call.
+                Ok(NextResult::Gap { .. }) => continue,
+                Ok(NextResult::End(_)) | Ok(NextResult::More) | Ok(NextResult::NextFile { .. }) => {
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            };
+            self.done = !matches!(result, Some(Ok(_)));
+            break result;
+        }
+    }
+}
+
+/// Represents a stream we are currently reading in a processing messages.  Each message is
+/// processed in a single thread.
+pub struct PersistedMessageReadStream<FRead>
+where
+    FRead: MessageProcessor,
+{
+    /// The buffer we are currently reading.
+    buffer: MessageFileStoreRead,
+    /// The id of the file.
+    file_id: u32,
+    /// The maximum message id.
+    max_message_id: Arc<AtomicU64>,
+    /// What processes the incomming message as they are commited.
+    message_processor: FRead,
+    /// The storage directory.
+    file_storage_directory: String,
+    /// The file prefix.
+    file_prefix: String,
+    /// The current position in the files.
+    current_pos: usize,
+    /// Records how long the messages took to be handled.
+    latency: Option<Arc<StoreLatency>>,
+    /// Where the event files are kept.
+    backend: Arc<dyn MessageStoreBackend>,
+    /// How the messages are handed to the processor.
+    delivery: DeliveryMode,
+    /// What to do when the processor returns an error.
+    error_policy: ErrorPolicy,
+    /// The id of the last message the processor failed on and its error.
+    last_error: Option<(u64, ProcessError)>,
+    /// The number of messages skipped because the processor failed on them.
+    skipped: u64,
+    /// The most messages to hand to the processor at once.
+    batch_size: usize,
+    /// The number of messages handed to the processor or skipped.
+    processed: u64,
+}
 
 impl<FRead> PersistedMessageReadStream<FRead>
 where
@@ -602,12 +1835,41 @@ where
         message_processor: FRead,
         file_storage_directory: String,
         file_prefix: String,
+    ) -> file::Result<Self> {
+        PersistedMessageReadStream::new_with_backend(
+            starting_file_id,
+            from_message_id,
+            max_message_id,
+            message_processor,
+            file_storage_directory,
+            file_prefix,
+            Arc::new(FileBackend),
+        )
+    }
+
+    /// Used to create a new reader of the event files kept in a backend.
+    /// # Arguments
+    /// `starting_file_id` - The file to start the read from.
+    /// `from_message_id` - The id of the message to start reading from.
+    /// `max_message_id` - The maximum message id that is safe to read.
+    /// `message_processor` - What to call to handle processing the messages.
+    /// `file_storage_directory` - The directory the files are stored in.
+    /// `file_prefix` - The file prefix for the file storage.
+    /// `backend` - Where the event files are kept.
+    pub(crate) fn new_with_backend(
+        starting_file_id: u32,
+        from_message_id: u64,
+        max_message_id: Arc<AtomicU64>,
+        message_processor: FRead,
+        file_storage_directory: String,
+        file_prefix: String,
+        backend: Arc<dyn MessageStoreBackend>,
     ) -> file::Result<Self> {
         let mut file_id = starting_file_id;
         let mut starting_pos = 0;
         let buffer = loop {
             let file_name = create_event_name(&file_storage_directory, &file_prefix, &file_id);
-            let buffer = unsafe { MessageFileStore::open_readonly(&file_name)? };
+            let buffer = backend.open_readonly(Path::new(&file_name))?;
             match find_message(&buffer, from_message_id)? {
                 FindMessageResult::Found(pos) => {
                     starting_pos = pos;
@@ -626,54 +1888,303 @@ where
             file_prefix,
             buffer,
             file_storage_directory,
-            file_id: starting_file_id,
+            file_id,
             max_message_id,
             current_pos: starting_pos,
             message_processor,
+            latency: None,
+            backend,
+            delivery: DeliveryMode::Borrowed,
+            error_policy: ErrorPolicy::Stop,
+            last_error: None,
+            skipped: 0,
+            batch_size: 1,
+            processed: 0,
         })
     }
 
+    /// Sets how the messages are handed to the processor.
+    /// # Arguments
+    /// `delivery` - Borrowed to hand out the messages in the file or owned to copy them.
+    pub fn with_delivery(mut self, delivery: DeliveryMode) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    /// Sets what to do when the processor returns an error.
+    /// # Arguments
+    /// `error_policy` - Stop on the message, skip it or retry it.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Sets the most messages to hand to `MessageProcessor::handle_batch` at once.  The batches
+    /// are only used when the messages are borrowed.
+    /// # Arguments
+    /// `batch_size` - The most messages in a batch.  1 calls `handle` for each message.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// The last error the processor returned.
+    /// # Returns
+    /// The id of the message and the error.
+    pub fn last_error(&self) -> Option<(u64, &ProcessError)> {
+        self.last_error
+            .as_ref()
+            .map(|(message_id, error)| (*message_id, error))
+    }
+
+    /// The number of messages skipped because the processor failed on them.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The id of the file and the position of the next message to read.
+    pub fn position(&self) -> (u32, usize) {
+        (self.file_id, self.current_pos)
+    }
+
+    /// Records how long the messages took to be handled by the processor.
+    /// # Arguments
+    /// `latency` - The latencies of the store the messages are from.
+    #[allow(dead_code)]
+    pub(crate) fn with_latency(mut self, latency: Arc<StoreLatency>) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Processes the messages as they are committed until stopped.
+    /// # Arguments
+    /// `stop` - Set to a value greater than 0 to stop.
+    /// `wait` - How to wait when there are no committed messages to process.
+    /// # Returns
+    /// An error if we are unable to read the files or `ProcessFailed` when the processor fails and
+    /// the error policy doesn't skip the message.
+    pub fn run(&mut self, stop: Arc<AtomicU8>, wait: Arc<dyn WaitStrategy>) -> file::Result<()> {
+        let mut attempt = 0;
+        while stop.load(atomic::Ordering::Acquire) == 0 {
+            if self.process_next()? {
+                attempt = 0;
+            } else {
+                wait.wait(attempt);
+                attempt = attempt.saturating_add(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes the messages that have been committed.  Moves onto the next file when the
+    /// current one ends.
+    /// # Returns
+    /// The number of messages processed.  0 if we are caught up with the commits.
+    pub fn poll(&mut self) -> file::Result<usize> {
+        let processed = self.processed;
+        while self.process_next()? {}
+        Ok((self.processed - processed) as usize)
+    }
+
+    /// Processes the messages as they are committed on the current thread until stopped.
+    /// # Arguments
+    /// `stop` - Set to true to stop.
+    /// `idle` - How to wait when we are caught up with the commits.
+    /// # Returns
+    /// An error if we are unable to read the files or `ProcessFailed` when the processor fails and
+    /// the error policy doesn't skip the message.
+    pub fn run_until(&mut self, stop: &AtomicBool, idle: &dyn WaitStrategy) -> file::Result<()> {
+        let mut attempt = 0;
+        while !stop.load(atomic::Ordering::Acquire) {
+            if self.poll()? > 0 {
+                attempt = 0;
+            } else {
+                idle.wait(attempt);
+                attempt = attempt.saturating_add(1);
+            }
+        }
+        Ok(())
+    }
+
     /// called to process the next message in the buffer.
-    fn process_next(&mut self) -> file::Result<bool> {
-        match self.buffer.read_new(self.current_pos) {
+    /// # Returns
+    /// True if we moved forward.
+    pub(crate) fn process_next(&mut self) -> file::Result<bool> {
+        if self.batch_size > 1
+            && matches!(self.delivery, DeliveryMode::Borrowed)
+            && self.process_batch()?
+        {
+            return Ok(true);
+        }
+        // Keeps the file mapped while the processor has the message.
+        let file = self.buffer.clone();
+        match file.read_new(self.current_pos) {
             Ok(msg) => {
                 if msg.message_id() <= self.max_message_id.load(atomic::Ordering::Relaxed) {
-                    self.current_pos = msg.next_pos();
-                    self.message_processor.handle(&msg);
+                    let message_id = msg.message_id();
+                    let next_pos = msg.next_pos();
+                    // The negative types are used by the store.
+                    if msg.msg_type_id() >= 0 {
+                        // Stays on the message when the processor fails.
+                        let result = self.handle(msg);
+                        self.apply_error_policy(&file, message_id, result)?;
+                        self.handled(&[(message_id, self.current_pos)]);
+                    }
+                    self.current_pos = next_pos;
                     Ok(true)
                 } else if msg.message_id() == std::u64::MAX {
-                    self.switch_to_next_buffer()?;
-                    Ok(true)
+                    self.switch_to_next_buffer()
                 } else {
                     Ok(false)
                 }
             }
             Err(e) => match e {
                 file::Error::NoMessage => Ok(false),
-                file::Error::PositionOutOfRange(_) => {
-                    self.switch_to_next_buffer()?;
-                    Ok(true)
-                }
-                file::Error::Full => {
-                    self.switch_to_next_buffer()?;
-                    Ok(true)
-                }
+                file::Error::PositionOutOfRange(_) => self.switch_to_next_buffer(),
+                file::Error::Full => self.switch_to_next_buffer(),
                 _ => Err(e),
             },
         }
     }
 
-    /// Switches to the next file buffer.
-    fn switch_to_next_buffer(&mut self) -> file::Result<()> {
-        let new_buffer_name = create_event_name(
-            &self.file_storage_directory,
+    /// Hands the committed messages in the current file to the processor as a batch.
+    /// # Returns
+    /// False if there wasn't a committed message to hand out.
+    fn process_batch(&mut self) -> file::Result<bool> {
+        // Keeps the file mapped while the processor has the messages.
+        let file = self.buffer.clone();
+        let max_message_id = self.max_message_id.load(atomic::Ordering::Relaxed);
+        let mut batch = Vec::with_capacity(self.batch_size);
+        // The id and position of each message in the batch.
+        let mut starts = Vec::with_capacity(self.batch_size);
+        let mut pos = self.current_pos;
+        while batch.len() < self.batch_size {
+            match file.read_new(pos) {
+                // The end of the file has the max id so it is never committed.
+                Ok(msg) if msg.message_id() <= max_message_id => {
+                    let next_pos = msg.next_pos();
+                    // The negative types are used by the store.
+                    if msg.msg_type_id() >= 0 {
+                        starts.push((msg.message_id(), pos));
+                        batch.push(msg);
+                    }
+                    pos = next_pos;
+                }
+                _ => break,
+            }
+        }
+        if pos == self.current_pos {
+            return Ok(false);
+        }
+        let mut taken = 0;
+        let result = {
+            let mut batch = batch.into_iter().inspect(|_| taken += 1);
+            self.message_processor.handle_batch(&mut batch)
+        };
+        match result {
+            Ok(()) => {
+                self.handled(&starts);
+                self.current_pos = pos;
+            }
+            Err(e) => {
+                // The failed message is the last one taken.
+                let failed = taken.max(1) - 1;
+                let (message_id, start) = starts[failed];
+                self.handled(&starts[..failed]);
+                self.current_pos = start;
+                self.apply_error_policy(&file, message_id, Err(e))?;
+                self.handled(&starts[failed..=failed]);
+                self.current_pos = starts.get(failed + 1).map_or(pos, |(_, start)| *start);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Records the messages as handled.
+    /// # Arguments
+    /// `starts` - The ids and positions of the messages.
+    fn handled(&mut self, starts: &[(u64, usize)]) {
+        self.processed += starts.len() as u64;
+        if let Some(latency) = &self.latency {
+            for (message_id, _) in starts {
+                latency.handled(*message_id);
+            }
+        }
+    }
+
+    /// Applies the error policy to the result of handing a message to the processor.
+    /// # Arguments
+    /// `file` - The file the message is in.
+    /// `message_id` - The id of the message at the current position.
+    /// `result` - The result of the first attempt.
+    /// # Returns
+    /// `ProcessFailed` if the processor failed and the message wasn't skipped.
+    fn apply_error_policy(
+        &mut self,
+        file: &MessageFileStoreRead,
+        message_id: u64,
+        result: Result<(), ProcessError>,
+    ) -> file::Result<()> {
+        let retries = match self.error_policy {
+            ErrorPolicy::Retry(retries) => retries,
+            _ => 0,
+        };
+        let mut result = result;
+        let mut attempt = 0;
+        while result.is_err() && attempt < retries {
+            attempt += 1;
+            // The message was handed off so it is read in again.
+            result = self.handle(file.read_new(self.current_pos)?);
+        }
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                log::error!("Message {}: {}", message_id, e);
+                self.last_error = Some((message_id, e));
+                if self.error_policy == ErrorPolicy::Skip {
+                    self.skipped += 1;
+                    Ok(())
+                } else {
+                    Err(file::Error::ProcessFailed(message_id))
+                }
+            }
+        }
+    }
+
+    /// Calls the processor with the message.
+    /// # Arguments
+    /// `msg` - The message to hand to the processor.
+    fn handle(&mut self, msg: MessageRead<'_>) -> Result<(), ProcessError> {
+        match &self.delivery {
+            DeliveryMode::Borrowed => self.message_processor.handle(msg),
+            DeliveryMode::Owned(pool) => self
+                .message_processor
+                .handle_owned(OwnedMessage::copy_of(&msg, pool)),
+        }
+    }
+
+    /// Switches to the next file buffer.
+    /// # Returns
+    /// False if the writer hasn't created the next file yet.
+    fn switch_to_next_buffer(&mut self) -> file::Result<bool> {
+        let next_file_id = match next_event_file_id(
+            self.backend.as_ref(),
+            &self.file_storage_directory,
+            &self.file_prefix,
+            self.file_id,
+        ) {
+            Some(file_id) => file_id,
+            None => return Ok(false),
+        };
+        let new_buffer_name = create_event_name(
+            &self.file_storage_directory,
             &self.file_prefix,
-            &(self.file_id + 1),
+            &next_file_id,
         );
-        let new_buffer = unsafe { MessageFileStore::open_readonly(&new_buffer_name) }?;
-        self.buffer = new_buffer;
-        self.file_id += 1;
-        Ok(())
+        self.buffer = self.backend.open_readonly(Path::new(&new_buffer_name))?;
+        self.file_id = next_file_id;
+        self.current_pos = 0;
+        Ok(true)
     }
 }
 
@@ -758,10 +2269,35 @@ fn find_end_of_buffer(buffer: &MessageFileStoreRead) -> crate::file::Result<Find
     }
 }
 
+/// Finds the first message after an id in a file.
+/// # Arguments
+/// `buffer` - The file to look in.
+/// `message_id` - The id of the last message to keep.
+/// # Returns
+/// The position of the first message with a larger id or where the messages end.
+fn find_after(buffer: &MessageFileStoreRead, message_id: u64) -> crate::file::Result<usize> {
+    let mut pos = 0;
+    loop {
+        match buffer.read_new(pos) {
+            Ok(msg) => {
+                // The end of file message has the largest id.
+                if msg.message_id() == 0 || msg.message_id() > message_id {
+                    break Ok(pos);
+                }
+                pos = msg.next_pos();
+            }
+            Err(crate::file::Error::NoMessage)
+            | Err(crate::file::Error::Full)
+            | Err(crate::file::Error::PositionOutOfRange(_)) => break Ok(pos),
+            Err(e) => break Err(e),
+        }
+    }
+}
+
 /// Represents a term file.
 pub struct TermFile {
     /// The buffer we are writing to.
-    pub buffer: MemoryMappedInt,
+    pub buffer: StoreBuffer,
     /// The term start.
     pub term_start: u64,
     /// The term end.
@@ -785,7 +2321,15 @@ impl TermFile {
     /// `term_start` - The starting term buffer.
     /// `file_id` - The id of the file.
     fn new(buffer: MemoryMappedInt, term_start: u64, file_id: u32) -> Self {
-        let c = buffer.capacity() as u64;
+        TermFile::with_buffer(StoreBuffer::Mapped(buffer), term_start, file_id)
+    }
+
+    /// A new term file that might not be backed by a file.
+    /// `buffer` - The current buffer.
+    /// `term_start` - The starting term buffer.
+    /// `file_id` - The id of the file.
+    fn with_buffer(buffer: StoreBuffer, term_start: u64, file_id: u32) -> Self {
+        let c = buffer.size() as u64;
         TermFile {
             buffer,
             term_start,
@@ -811,12 +2355,513 @@ impl TermFile {
     }
 }
 
+/// What happened when the commit stream was stepped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitStep {
+    /// A new term was committed.
+    Committed {
+        /// The id of the term.
+        term_id: u64,
+        /// The id of the last message in the term.
+        max_message_id: u64,
+    },
+    /// Moved onto the next commit file.  The term is committed on the next step.
+    NextCommitFile(u32),
+    /// Moved onto the next event file.
+    NextEventFile(u32),
+    /// There isn't anything new to commit.
+    Idle,
+}
+
+/// Commits the messages that have been written for a single node.  Each step commits the next
+/// block of the written messages as a new term.
+pub struct PersistedCommitStream {
+    /// The commit file the terms are saved to.
+    term_file: TermFile,
+    /// The id of the last term committed.
+    current_term: u64,
+    /// The event file being committed.
+    message_file: MessageFileStoreRead,
+    /// The position of the next message to commit.
+    read_pos: usize,
+    /// The id of the event file being committed.
+    read_file_id: u32,
+    /// The storage directory.
+    file_storage_directory: String,
+    /// The file prefix.
+    file_prefix: String,
+    /// The size of the commit files to create.
+    commit_file_size: usize,
+    /// Where the files are kept.
+    backend: Arc<dyn MessageStoreBackend>,
+    /// The id of the last message committed.
+    max_message: Arc<AtomicU64>,
+    /// The id of the last message that has been completely written.
+    written_message_id: Arc<AtomicU64>,
+    /// The clock to get the commit times from.
+    clock: Arc<dyn Clock>,
+    /// The commit files the collection knows about.  The files we roll onto are added to it.
+    commit_files: Option<Arc<Mutex<Vec<CommitFileInfo>>>>,
+    /// Batches the messages so one term covers them.  `None` commits the messages as soon as
+    /// they are written.
+    group_commit: Option<GroupCommit>,
+    /// When the first message of the waiting batch was seen.
+    batch_started: Option<Instant>,
+    /// The writer for the event file being committed so it can be flushed.
+    flush_writer: Option<MessageFileStoreWrite>,
+    /// Syncs the event file to disk.
+    flusher: Flusher,
+    /// The number of times the event files have been flushed.
+    flush_count: Arc<AtomicU64>,
+    /// When the event files are flushed.
+    flush_policy: FlushPolicy,
+    /// The id of the last message flushed to disk.
+    flushed_message_id: u64,
+    /// When the event file was last flushed.
+    last_flush: Instant,
+    /// The bytes the writer has written so only they are flushed.  `None` flushes the whole file.
+    dirty: Option<Arc<DirtyRanges>>,
+}
+
+impl PersistedCommitStream {
+    /// Creates a commit stream that continues from the last term.
+    /// # Arguments
+    /// `term_file` - The commit file the last term is in.
+    /// `current_term` - The id of the last term committed.
+    /// `read_file_id` - The id of the event file to commit from.
+    /// `read_pos` - The position of the first message that hasn't been committed.
+    /// `file_storage_directory` - The storage directory.
+    /// `file_prefix` - The file prefix.
+    /// `commit_file_size` - The size of the commit files to create.
+    /// `backend` - Where the files are kept.
+    /// `max_message` - The id of the last message committed.
+    /// `written_message_id` - The id of the last message that has been completely written.
+    /// `clock` - The clock to get the commit times from.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        term_file: TermFile,
+        current_term: u64,
+        read_file_id: u32,
+        read_pos: usize,
+        file_storage_directory: String,
+        file_prefix: String,
+        commit_file_size: usize,
+        backend: Arc<dyn MessageStoreBackend>,
+        max_message: Arc<AtomicU64>,
+        written_message_id: Arc<AtomicU64>,
+        clock: Arc<dyn Clock>,
+    ) -> file::Result<Self> {
+        let path = create_event_name(&file_storage_directory, &file_prefix, &read_file_id);
+        let message_file = backend.open_readonly(Path::new(&path))?;
+        let flushed_message_id = max_message.load(atomic::Ordering::Acquire);
+        Ok(PersistedCommitStream {
+            term_file,
+            current_term,
+            message_file,
+            read_pos,
+            read_file_id,
+            file_storage_directory,
+            file_prefix,
+            commit_file_size,
+            backend,
+            max_message,
+            written_message_id,
+            clock,
+            commit_files: None,
+            group_commit: None,
+            batch_started: None,
+            flush_writer: None,
+            flusher: Flusher::Thread,
+            flush_count: Arc::new(AtomicU64::new(0)),
+            flush_policy: FlushPolicy::Os,
+            flushed_message_id,
+            last_flush: Instant::now(),
+            dirty: None,
+        })
+    }
+
+    /// Only flushes the bytes the writer recorded.
+    /// # Arguments
+    /// `dirty` - Where the writer records the bytes written.
+    pub(crate) fn with_dirty_ranges(mut self, dirty: Arc<DirtyRanges>) -> Self {
+        self.dirty = Some(dirty);
+        self
+    }
+
+    /// Batches the messages into one term.
+    /// # Arguments
+    /// `group_commit` - The limits of a batch.
+    pub(crate) fn with_group_commit(mut self, group_commit: GroupCommit) -> Self {
+        self.group_commit = Some(group_commit);
+        self
+    }
+
+    /// Flushes the event files as the messages are committed.
+    /// # Arguments
+    /// `flush_policy` - When to flush.  With `EveryCommit` the messages of a term are flushed
+    /// before the term is saved.
+    /// `flush_count` - Counts the flushes of the event files.
+    pub(crate) fn with_flush_policy(
+        mut self,
+        flush_policy: FlushPolicy,
+        flush_count: Arc<AtomicU64>,
+    ) -> Self {
+        if flush_policy != FlushPolicy::Os {
+            self.flusher = Flusher::new();
+        }
+        self.flush_policy = flush_policy;
+        self.flush_count = flush_count;
+        self
+    }
+
+    /// Adds the commit files we roll onto to the files in a collection.
+    /// # Arguments
+    /// `commit_files` - The commit files of the file collection.
+    pub(crate) fn with_commit_files(
+        mut self,
+        commit_files: Arc<Mutex<Vec<CommitFileInfo>>>,
+    ) -> Self {
+        self.commit_files = Some(commit_files);
+        self
+    }
+
+    /// Creates a commit stream with no terms with the commit file created in the backend.
+    /// # Arguments
+    /// `file_storage_directory` - The storage directory.
+    /// `file_prefix` - The file prefix.
+    /// `commit_file_size` - The size of the commit files to create.
+    /// `backend` - Where the files are kept.
+    /// `max_message` - The id of the last message committed.
+    /// `written_message_id` - The id of the last message that has been completely written.
+    /// `clock` - The clock to get the commit times from.
+    #[cfg(test)]
+    pub(crate) fn create(
+        file_storage_directory: String,
+        file_prefix: String,
+        commit_file_size: usize,
+        backend: Arc<dyn MessageStoreBackend>,
+        max_message: Arc<AtomicU64>,
+        written_message_id: Arc<AtomicU64>,
+        clock: Arc<dyn Clock>,
+    ) -> file::Result<Self> {
+        let path = create_commit_name(&file_storage_directory, &file_prefix, &1);
+        let buffer = backend.create_commit(Path::new(&path), commit_file_size)?;
+        PersistedCommitStream::new(
+            TermFile::with_buffer(buffer, 1, 1),
+            0,
+            1,
+            0,
+            file_storage_directory,
+            file_prefix,
+            commit_file_size,
+            backend,
+            max_message,
+            written_message_id,
+            clock,
+        )
+    }
+
+    /// The id of the event file and the position of the next message to commit.
+    pub fn position(&self) -> (u32, usize) {
+        (self.read_file_id, self.read_pos)
+    }
+
+    /// The id of the last term committed.
+    pub fn current_term(&self) -> u64 {
+        self.current_term
+    }
+
+    /// Commits the next block of written messages.
+    /// # Returns
+    /// What the step did.  An error if we are unable to read or create the files.
+    pub fn step(&mut self) -> file::Result<CommitStep> {
+        if self.at_end_of_file() {
+            return self.next_event_file();
+        }
+        let written = self.written_message_id.load(atomic::Ordering::Acquire);
+        let (message_id_end, next_pos, length) =
+            match read_commit_block(&self.message_file, self.read_pos, written) {
+                Ok(block) => (block.message_id_end, block.next_pos, block.bytes.len()),
+                Err(file::Error::NoMessage) => {
+                    self.flush_on_interval()?;
+                    return Ok(CommitStep::Idle);
+                }
+                Err(file::Error::Full) | Err(file::Error::PositionOutOfRange(_)) => {
+                    return self.next_event_file()
+                }
+                Err(e) => return Err(e),
+            };
+        let new_term = self.current_term + 1;
+        match self.term_file.calculate_pos(&new_term) {
+            TermPosResult::Pos(p) => {
+                if let Some(group_commit) = self.group_commit {
+                    let messages =
+                        message_id_end - self.max_message.load(atomic::Ordering::Acquire);
+                    let started = *self.batch_started.get_or_insert_with(Instant::now);
+                    // The block stopping short of the written messages means it is full.
+                    if message_id_end >= written
+                        && !group_commit.is_ready(length, messages, started.elapsed())
+                    {
+                        return Ok(CommitStep::Idle);
+                    }
+                    self.batch_started = None;
+                }
+                if self.flush_due(message_id_end) {
+                    self.flush_event_file()?;
+                    self.flushed_message_id = message_id_end;
+                }
+                let since_epoch = self.clock.now_ms();
+                let term = TermCommit {
+                    file_position_offset: self.read_pos as u64,
+                    file_id: self.read_file_id,
+                    term_id: new_term,
+                    length: length as u32,
+                    version: 1,
+                    type_id: 1,
+                    leader_id: 1,
+                    server_id: 1,
+                    committed: 1,
+                    file_max_message_id: message_id_end,
+                    timestamp: since_epoch,
+                    committed_timestamp: since_epoch,
+                };
+                self.term_file.buffer.save_term(p, &term);
+                self.max_message
+                    .store(message_id_end, atomic::Ordering::Release);
+                self.read_pos = next_pos;
+                self.current_term = new_term;
+                Ok(CommitStep::Committed {
+                    term_id: new_term,
+                    max_message_id: message_id_end,
+                })
+            }
+            TermPosResult::Overflow => {
+                let next_file_id = self.term_file.file_id + 1;
+                let path = create_commit_name(
+                    &self.file_storage_directory,
+                    &self.file_prefix,
+                    &next_file_id,
+                );
+                let buffer = self
+                    .backend
+                    .create_commit(Path::new(&path), self.commit_file_size)?;
+                self.term_file = TermFile::with_buffer(buffer, new_term, next_file_id);
+                if let Some(commit_files) = &self.commit_files {
+                    // Carries the last message committed until the first term is saved to it.
+                    let max_message_id = self.max_message.load(atomic::Ordering::Acquire);
+                    let mut commit_files = commit_files.lock().unwrap();
+                    if !commit_files.iter().any(|f| f.file_id == next_file_id) {
+                        commit_files.push(CommitFileInfo::new(
+                            path,
+                            next_file_id,
+                            new_term,
+                            max_message_id,
+                        ));
+                        commit_files.sort();
+                    }
+                }
+                Ok(CommitStep::NextCommitFile(next_file_id))
+            }
+            TermPosResult::Underflow => {
+                panic!("We should never underflow when writing new terms!");
+            }
+        }
+    }
+
+    /// Removes the terms with the messages after an id so the messages written in their place are
+    /// committed as new terms.  Has to be called before the messages are truncated from the event
+    /// files so the first message of a removed term can still be read.
+    /// # Arguments
+    /// `message_id` - The id of the last message to keep.
+    /// # Returns
+    /// `NotRetained` if the terms to remove start in an earlier commit file.
+    pub fn truncate_after(&mut self, message_id: u64) -> file::Result<()> {
+        let mut term_id = self.current_term;
+        let mut first_removed = None;
+        while let TermPosResult::Pos(pos) = self.term_file.calculate_pos(&term_id) {
+            if self.term_file.buffer.max_message_id(pos) <= message_id {
+                break;
+            }
+            first_removed = Some(pos);
+            term_id -= 1;
+        }
+        let first_removed = match first_removed {
+            Some(pos) => pos,
+            None => return Ok(()),
+        };
+        let buffer = &self.term_file.buffer;
+        let (max_message, read_file_id, read_pos) = match self.term_file.calculate_pos(&term_id) {
+            // Picks up after the last term kept.
+            TermPosResult::Pos(kept) => (
+                buffer.max_message_id(kept),
+                buffer.file_id(kept),
+                buffer.file_position_offset(kept) as usize + buffer.length_of_commit(kept) as usize,
+            ),
+            // The term before is in an earlier commit file so start where the first removed one
+            // does.
+            _ => {
+                let file_id = buffer.file_id(first_removed);
+                let pos = buffer.file_position_offset(first_removed) as usize;
+                let path =
+                    create_event_name(&self.file_storage_directory, &self.file_prefix, &file_id);
+                let first_id = self
+                    .backend
+                    .open_readonly(Path::new(&path))?
+                    .read_new(pos)?
+                    .message_id();
+                if first_id - 1 > message_id {
+                    return Err(file::Error::NotRetained {
+                        message_id,
+                        earliest: first_id,
+                    });
+                }
+                (first_id - 1, file_id, pos)
+            }
+        };
+        // Stops the readers before the terms are cleared.
+        self.max_message
+            .store(max_message, atomic::Ordering::Release);
+        let removed = (self.current_term - term_id) * COMMIT_SIZE;
+        self.term_file
+            .buffer
+            .set_bytes(FILE_HEADER_SIZE + first_removed, removed as usize, 0);
+        self.term_file.buffer.flush()?;
+        if read_file_id != self.read_file_id {
+            let path = create_event_name(
+                &self.file_storage_directory,
+                &self.file_prefix,
+                &read_file_id,
+            );
+            self.message_file = self.backend.open_readonly(Path::new(&path))?;
+            self.read_file_id = read_file_id;
+            self.flush_writer = None;
+        }
+        self.read_pos = read_pos;
+        self.current_term = term_id;
+        self.batch_started = None;
+        self.flushed_message_id = self.flushed_message_id.min(max_message);
+        Ok(())
+    }
+
+    /// True if the writer has moved past the event file.
+    fn at_end_of_file(&self) -> bool {
+        match self.message_file.read_new(self.read_pos) {
+            Ok(msg) => msg.message_id() == u64::MAX,
+            Err(file::Error::Full) | Err(file::Error::PositionOutOfRange(_)) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Moves onto the next event file if the writer has created it.
+    fn next_event_file(&mut self) -> file::Result<CommitStep> {
+        let next_file_id = self.read_file_id + 1;
+        let path = create_event_name(
+            &self.file_storage_directory,
+            &self.file_prefix,
+            &next_file_id,
+        );
+        let path = Path::new(&path);
+        if !self.backend.exists(path) {
+            return Ok(CommitStep::Idle);
+        }
+        // The committed messages left in the file won't be flushed once we move on.
+        let max_message_id = self.max_message.load(atomic::Ordering::Acquire);
+        if self.flush_policy != FlushPolicy::Os && self.flushed_message_id < max_message_id {
+            self.flush_event_file()?;
+            self.flushed_message_id = max_message_id;
+        }
+        self.message_file = self.backend.open_readonly(path)?;
+        self.read_file_id = next_file_id;
+        self.read_pos = 0;
+        self.flush_writer = None;
+        Ok(CommitStep::NextEventFile(next_file_id))
+    }
+
+    /// Flushes the event file being committed.
+    fn flush_event_file(&mut self) -> file::Result<()> {
+        if self.flush_writer.is_none() {
+            let path = create_event_name(
+                &self.file_storage_directory,
+                &self.file_prefix,
+                &self.read_file_id,
+            );
+            let (_, writer) = self.backend.open(Path::new(&path))?;
+            self.flush_writer = Some(writer);
+        }
+        if let Some(writer) = &self.flush_writer {
+            let dirty = match &self.dirty {
+                Some(dirty) => dirty.take(self.read_file_id),
+                None => Dirty::Unknown,
+            };
+            match dirty {
+                Dirty::Clean => {}
+                Dirty::Range(range) => {
+                    self.flusher.flush_range(writer, range.start, range.len())?;
+                    self.flush_count.fetch_add(1, atomic::Ordering::Relaxed);
+                }
+                Dirty::Unknown => {
+                    self.flusher.flush(writer)?;
+                    self.flush_count.fetch_add(1, atomic::Ordering::Relaxed);
+                }
+            }
+        }
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// True if the event file needs to be flushed before the term is saved.
+    /// # Arguments
+    /// `message_id_end` - The id of the last message in the term.
+    fn flush_due(&self, message_id_end: u64) -> bool {
+        match self.flush_policy {
+            FlushPolicy::EveryCommit => true,
+            FlushPolicy::EveryNMessages(n) => {
+                message_id_end - self.flushed_message_id >= u64::from(n)
+            }
+            FlushPolicy::Interval(interval) => self.last_flush.elapsed() >= interval,
+            FlushPolicy::Os => false,
+        }
+    }
+
+    /// Flushes the committed messages once the interval has passed even if nothing else is
+    /// written.
+    fn flush_on_interval(&mut self) -> file::Result<()> {
+        if let FlushPolicy::Interval(interval) = self.flush_policy {
+            let max_message_id = self.max_message.load(atomic::Ordering::Acquire);
+            if self.flushed_message_id < max_message_id && self.last_flush.elapsed() >= interval {
+                self.flush_event_file()?;
+                self.flushed_message_id = max_message_id;
+            }
+        }
+        Ok(())
+    }
+
+    /// How long until the waiting batch is committed by the timer.
+    /// # Returns
+    /// `None` if there isn't a batch waiting.
+    pub(crate) fn batch_remaining(&self) -> Option<Duration> {
+        match (self.group_commit, self.batch_started) {
+            (Some(group_commit), Some(started)) => {
+                Some(group_commit.max_latency.saturating_sub(started.elapsed()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Flushes the commit file.
+    pub fn flush(&self) -> io::Result<()> {
+        self.term_file.buffer.flush()
+    }
+}
+
+/// The commit stream for the leader.  The commit files are behind a lock so only the commit
+/// thread writes to them while the readers get the committed messages from `max_message_id`.
 #[allow(dead_code)]
 pub struct PersistedCommitStreamLeader {
     /// The current commit file.
-    commit_file: Rc<Cell<TermFile>>,
+    commit_file: Arc<Mutex<TermFile>>,
     /// The current file we are placing new terms to.
-    new_term_file: Rc<Cell<TermFile>>,
+    new_term_file: Arc<Mutex<TermFile>>,
     max_message_id: Arc<AtomicU64>,
     file_storage_directory: String,
     file_prefix: String,
@@ -827,9 +2872,9 @@ pub struct PersistedCommitStreamLeader {
 #[allow(dead_code)]
 pub struct PersistedCommitSingleNode {
     /// The current commit file.
-    commit_file: Rc<TermFile>,
+    commit_file: Arc<Mutex<TermFile>>,
     /// The current file we are placing new terms to.
-    new_term_file: Rc<TermFile>,
+    new_term_file: Arc<Mutex<TermFile>>,
     /// The maximum message id.
     max_message_id: Arc<AtomicU64>,
     /// The file storage directory.
@@ -845,8 +2890,8 @@ pub struct PersistedCommitSingleNode {
 impl PersistedCommitSingleNode {
     #[allow(dead_code)]
     fn new(
-        commit_file: Rc<TermFile>,
-        new_term_file: Rc<TermFile>,
+        commit_file: Arc<Mutex<TermFile>>,
+        new_term_file: Arc<Mutex<TermFile>>,
         max_message_id: Arc<AtomicU64>,
         file_storage_directory: String,
         file_prefix: String,
@@ -869,9 +2914,9 @@ impl PersistedCommitSingleNode {
 #[allow(dead_code)]
 pub struct PersistedCommitStreamFollower {
     /// The file we are currently commit messages to.
-    commit_file: Rc<Cell<TermFile>>,
+    commit_file: Arc<Mutex<TermFile>>,
     /// The new term file.
-    new_term_file: Rc<Cell<TermFile>>,
+    new_term_file: Arc<Mutex<TermFile>>,
     /// The current max committed file id.
     max_message_id: Arc<AtomicU64>,
     /// The storage directory.
@@ -891,84 +2936,413 @@ struct FileStorageInfo {
     max_file_size: u64,
 }
 
+/// The future to complete once a message has been committed.
+enum WriteComplete {
+    /// Completes with the result of the write.
+    Write(oneshot::Sender<crate::Result<u64>>),
+    /// Completes with the id of the message.  The sender is dropped if the write failed so the
+    /// receiver gets canceled.
+    Change(oneshot::Sender<u64>),
+}
+
+impl WriteComplete {
+    /// Completes the future.
+    /// # Arguments
+    /// `result` - The id of the message or the error that occurred.
+    fn complete(self, result: file::Result<u64>) {
+        match self {
+            WriteComplete::Write(sender) => sender
+                .send(result.map_err(PersistError::from))
+                .unwrap_or_default(),
+            WriteComplete::Change(sender) => {
+                if let Ok(message_id) = result {
+                    sender.send(message_id).unwrap_or_default();
+                }
+            }
+        }
+    }
+}
+
 struct AddMessageWriteRs {
     position_start: usize,
-    complete: oneshot::Sender<file::Result<()>>,
+    complete: WriteComplete,
+    /// The space in the backlog.  Given back once the future is completed.
+    reserved: Reservation,
+    /// The monotonic time the write was queued.
+    queued_ns: u64,
 }
 
 struct AddMessageCommit {
-    file_pos: usize,
-    file_id: u32,
-    complete: oneshot::Sender<file::Result<()>>,
+    message_id: u64,
+    complete: WriteComplete,
+    reserved: Reservation,
+    queued_ns: u64,
 }
 
 impl AddMessageWriteRs {
-    fn new(position_start: usize, complete: oneshot::Sender<file::Result<()>>) -> Self {
+    fn new(
+        position_start: usize,
+        complete: WriteComplete,
+        reserved: Reservation,
+        queued_ns: u64,
+    ) -> Self {
         AddMessageWriteRs {
             position_start,
             complete,
+            reserved,
+            queued_ns,
         }
     }
+
+    /// Completes the future after giving back the space in the backlog.
+    /// # Arguments
+    /// `result` - The id of the message or the error that occurred.
+    fn complete(self, result: file::Result<u64>) {
+        drop(self.reserved);
+        self.complete.complete(result);
+    }
 }
 
 impl AddMessageCommit {
     #[inline]
-    fn new(file_pos: usize, file_id: u32, complete: oneshot::Sender<file::Result<()>>) -> Self {
+    fn new(
+        message_id: u64,
+        complete: WriteComplete,
+        reserved: Reservation,
+        queued_ns: u64,
+    ) -> Self {
         AddMessageCommit {
-            file_pos,
-            file_id,
+            message_id,
             complete,
+            reserved,
+            queued_ns,
         }
     }
 
-    /// Checks to see if a messaged is processed based on the position.
+    /// Completes the future after giving back the space in the backlog.
+    /// # Arguments
+    /// `result` - The id of the message or the error that occurred.
+    fn complete(self, result: file::Result<u64>) {
+        drop(self.reserved);
+        self.complete.complete(result);
+    }
+
+    /// Checks to see if a messaged is processed based on the last message id processed.
     #[inline]
-    fn is_processed(&self, position: &usize, file_id: &u32) -> bool {
-        (self.file_id == *file_id && self.file_pos < *position) || self.file_id < *file_id
+    fn is_processed(&self, message_id: u64) -> bool {
+        self.message_id <= message_id
     }
 }
 
-/// The persisted file.
-pub struct PersistedMessageFile {
-    /// The maximum file size before it roles overs.
-    #[allow(dead_code)]
-    max_file_size: usize,
-    /// The thread that processes the commit.
-    commit_join: Option<JoinHandle<u32>>,
-    /// The writer joiner.
-    writer_join: Option<JoinHandle<u32>>,
-    /// The reader joiner.
-    reader_join: Option<JoinHandle<u32>>,
-    /// The atomic u8.
-    stop: Arc<AtomicU8>,
-    /// The incoming byte buffer to write the messages to.
-    incoming_writer: ManyToOneBufferWriter,
-    /// The incoming reader queue that contains the messages to complete.
-    incoming_queue_writer: MpscQueueWrap<AddMessageWriteRs>,
-    /// The current maximum message id that has been processed.
-    #[allow(dead_code)]
-    max_message_id: Arc<AtomicU64>,
-    /// The directory to store the files.
-    #[allow(dead_code)]
-    file_storage_directory: String,
-    /// The prefix for the fille storage.
-    #[allow(dead_code)]
-    file_prefix: String,
+/// Hands the futures from the writer to the commit thread.  The writer never waits on the futures
+/// being completed since that runs the code waiting on them.  The lock is only held to move the
+/// futures out of the queue and is only contended when stopping.
+///
+/// ```text
+///  writer thread                           commit thread
+/// +-------------+   try_offer   +-------+   drain   +----------------+
+/// | matched ids | ------------> | queue | --------> | PendingCommits |--> complete
+/// +-------------+               +-------+           +----------------+
+///        | full                                     owned, no lock
+///        v
+///   overflow, offered again on the next pass
+/// ```
+struct CommitQueue {
+    sender: MpscQueueWrap<AddMessageCommit>,
+    receiver: Mutex<MpscQueueReceive<AddMessageCommit>>,
+    /// The number of futures that haven't been completed.
+    pending: AtomicU64,
+    /// The last message id the commit thread completed the futures to.
+    committed: AtomicU64,
 }
 
-///  The file format for the messages.  The goal is to have raft replicate the messages onto the
-///  server.  Would require 2 files, one with the messages and one with raft indicating which
-///  messages have been submitted.  The messages are aligned at 32 bytes for faster lookup.
-///
-///  Raw message log.
-///  0                   1                   2                   3
-///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
-/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-/// | Message Size                                                  |
-/// +-------------------------------+-------------------------------+ 32
-/// | Version                       |  Message Type                 ...
-/// +-------------------------------+-------------------------------+ 64
-/// ... Message Type cont.          | Global Message Id             |
+impl CommitQueue {
+    /// Creates the queue.
+    /// # Arguments
+    /// `queue_size` - The number of futures that can be waiting to be taken by the commit thread.
+    fn new(queue_size: usize) -> Self {
+        let (sender, receiver) = MpscQueueWrap::new(queue_size);
+        CommitQueue {
+            sender,
+            receiver: Mutex::new(receiver),
+            pending: AtomicU64::new(0),
+            committed: AtomicU64::new(0),
+        }
+    }
+
+    /// Hands a future to the commit thread.
+    /// # Arguments
+    /// `commit` - The future to complete once its message is committed.
+    /// # Returns
+    /// The future back if the queue is full.
+    fn offer(&self, commit: AddMessageCommit) -> Result<(), AddMessageCommit> {
+        // Counted first so the commit thread can't complete it before it's counted.
+        self.pending.fetch_add(1, atomic::Ordering::AcqRel);
+        let result = self.sender.try_offer(commit);
+        if result.is_err() {
+            self.pending.fetch_sub(1, atomic::Ordering::AcqRel);
+        }
+        result
+    }
+
+    /// Moves the futures in the queue to a batch.
+    /// # Arguments
+    /// `batch` - Where to put the futures.
+    fn drain_into(&self, batch: &mut Vec<AddMessageCommit>) {
+        let receiver = self.receiver.lock().unwrap();
+        while let Some(commit) = receiver.poll() {
+            batch.push(commit);
+        }
+    }
+
+    /// Records the futures that have been completed.
+    /// # Arguments
+    /// `count` - The number of futures completed.
+    fn completed(&self, count: usize) {
+        if count > 0 {
+            self.pending.fetch_sub(count as u64, atomic::Ordering::AcqRel);
+        }
+    }
+
+    /// Finishes the futures still in the queue when stopping.  The thread that stops last gets
+    /// the futures the other one handed off.  The messages that were committed before the commit
+    /// thread stopped still complete.
+    /// # Arguments
+    /// `unqueued` - The futures that never made it into the queue.
+    /// # Returns
+    /// The number of futures that were failed.
+    fn stop(&self, unqueued: Vec<AddMessageCommit>) -> usize {
+        let mut batch = Vec::new();
+        self.drain_into(&mut batch);
+        self.completed(batch.len());
+        let committed = self.committed.load(atomic::Ordering::Acquire);
+        let mut failed = 0;
+        for commit in batch.into_iter().chain(unqueued) {
+            if commit.is_processed(committed) {
+                let message_id = commit.message_id;
+                commit.complete(Ok(message_id));
+            } else {
+                failed += 1;
+                commit.complete(Err(file::Error::Stopped));
+            }
+        }
+        failed
+    }
+
+    /// The number of futures waiting for their message to be committed.
+    fn pending(&self) -> u64 {
+        self.pending.load(atomic::Ordering::Acquire)
+    }
+}
+
+/// The futures waiting for their message to be committed.  Only the commit thread has it so the
+/// futures are completed without holding a lock.  Kept sorted by the message id so they can be
+/// completed in a single pass when the commit advances.
+struct PendingCommits {
+    pending: Vec<AddMessageCommit>,
+    /// The futures taken from the queue that haven't been merged in yet.
+    incoming: Vec<AddMessageCommit>,
+    /// The maximum message id that has been committed.
+    committed: u64,
+    /// Where the writer puts the futures.
+    queue: Arc<CommitQueue>,
+    /// Records how long the futures took to complete.
+    latency: Arc<StoreLatency>,
+}
+
+impl PendingCommits {
+    fn new(queue: Arc<CommitQueue>, latency: Arc<StoreLatency>) -> Self {
+        PendingCommits {
+            pending: Vec::with_capacity(1024),
+            incoming: Vec::with_capacity(1024),
+            committed: 0,
+            queue,
+            latency,
+        }
+    }
+
+    /// Takes the futures the writer has handed off.  The commit can get ahead of the writer
+    /// handing off the futures so anything already committed is completed right away.
+    /// # Returns
+    /// The number of futures completed.
+    fn drain(&mut self) -> usize {
+        self.queue.drain_into(&mut self.incoming);
+        if self.incoming.is_empty() {
+            return 0;
+        }
+        let sorted = self.incoming.windows(2).all(|w| w[0].message_id < w[1].message_id)
+            && match (self.pending.last(), self.incoming.first()) {
+                (Some(last), Some(first)) => last.message_id < first.message_id,
+                _ => true,
+            };
+        self.pending.append(&mut self.incoming);
+        if !sorted {
+            self.pending.sort_by_key(|p| p.message_id);
+        }
+        self.complete_to(self.committed)
+    }
+
+    /// Completes all of the futures up to the message id.  If the receiver has been dropped the
+    /// future is skipped.
+    /// # Arguments
+    /// `max_message_id` - The maximum message id that has been committed.
+    /// # returns
+    /// The number of futures completed.
+    fn complete_to(&mut self, max_message_id: u64) -> usize {
+        if max_message_id > self.committed {
+            self.committed = max_message_id;
+            self.queue
+                .committed
+                .store(max_message_id, atomic::Ordering::Release);
+        }
+        let end = self
+            .pending
+            .iter()
+            .position(|p| !p.is_processed(max_message_id))
+            .unwrap_or(self.pending.len());
+        if end > 0 {
+            // Counted first since completing them can run the code waiting on them.
+            self.queue.completed(end);
+            let now = self.latency.now();
+            for commit in self.pending.drain(..end) {
+                let message_id = commit.message_id;
+                self.latency.committed(commit.queued_ns, now);
+                commit.complete(Ok(message_id));
+            }
+        }
+        end
+    }
+
+    /// Fails all of the pending futures.  Used when shutting down.
+    /// # Returns
+    /// The number of futures that were failed.
+    fn fail_all(&mut self) -> usize {
+        let failed = self.pending.len() + self.incoming.len();
+        self.queue.completed(failed);
+        for commit in self.pending.drain(..).chain(self.incoming.drain(..)) {
+            commit.complete(Err(file::Error::Stopped));
+        }
+        failed + self.queue.stop(Vec::new())
+    }
+}
+
+/// The persisted file.
+pub struct PersistedMessageFile {
+    /// The maximum file size before it roles overs.
+    max_file_size: usize,
+    /// The thread that processes the commit.
+    commit_join: Option<JoinHandle<u32>>,
+    /// The writer joiner.
+    writer_join: Option<JoinHandle<u32>>,
+    /// The reader joiner.
+    reader_join: Option<JoinHandle<u32>>,
+    /// The atomic u8.
+    stop: Arc<AtomicU8>,
+    /// The incoming byte buffer to write the messages to.
+    incoming_writer: ManyToOneBufferWriter,
+    /// The incoming reader queue that contains the messages to complete.
+    incoming_queue_writer: MpscQueueWrap<AddMessageWriteRs>,
+    /// The current maximum message id that has been processed.
+    #[allow(dead_code)]
+    max_message_id: Arc<AtomicU64>,
+    /// The id of the last message that has been written.
+    written_message_id: Arc<AtomicU64>,
+    /// The directory to store the files.
+    #[allow(dead_code)]
+    file_storage_directory: String,
+    /// The prefix for the fille storage.
+    #[allow(dead_code)]
+    file_prefix: String,
+    /// The readers for the event files.  Kept open so the events read in stay valid for as long as
+    /// the file is borrowed.
+    event_readers: Mutex<HashMap<u32, Arc<MessageFileStoreRead>>>,
+    /// The writes waiting to be committed.
+    backlog: Arc<Backlog>,
+    /// The event file that was last flushed.  Kept open so it doesn't need to be mapped again.
+    flush_writer: Mutex<Option<(u32, MessageFileStoreWrite)>>,
+    /// Syncs the event files to disk.
+    flusher: Flusher,
+    /// The event files in the directory when following a writer in another process.  `None` if
+    /// we own the directory.
+    followed_files: Option<Arc<Mutex<Vec<MessageFileInfo>>>>,
+    /// The event files the iterators read from.
+    message_files: Arc<Mutex<Vec<MessageFileInfo>>>,
+    /// The commit files the terms are looked up in.
+    commit_files: Arc<Mutex<Vec<CommitFileInfo>>>,
+    /// The clock used for the commit times.
+    clock: Arc<dyn Clock>,
+    /// How long the messages take to be committed and handled.
+    latency: Arc<StoreLatency>,
+    /// Where the messages written since the store was opened are so the readers can skip to them.
+    positions: Arc<PositionIndex>,
+    /// Where to publish the lifecycle events.
+    events: Arc<StoreEvents>,
+    /// Where the futures wait for their message to be committed.
+    commit_queue: Arc<CommitQueue>,
+    /// Wakes the tail readers when messages are committed.
+    commit_signal: Arc<CommitSignal>,
+    /// The number of times the event files have been flushed.
+    flush_count: Arc<AtomicU64>,
+    /// The commit file that was last flushed.  The files before it have already been flushed.
+    commit_flush_file: Mutex<u32>,
+    /// Where the writer picked up when the store was opened.
+    recovered: RecoveredPosition,
+    /// Set once we start closing so no more writes are accepted.
+    closing: AtomicBool,
+    /// Set once the store has been closed so it isn't closed again when dropped.
+    closed: bool,
+}
+
+/// How far out of order the commit times can be by default when looking up the messages by time.
+pub const DEFAULT_TIME_SLOP_MS: u64 = 1_000;
+
+/// The default time to wait for the writes to be committed when closing.
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do with the writes that haven't been committed when closing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClosePolicy {
+    /// Wait up to the timeout for the writes to be committed.  The writes left after the timeout
+    /// are failed.
+    Commit(Duration),
+    /// Fail the writes that haven't been committed right away.  The messages already written to
+    /// the event files are still committed the next time the store is opened.
+    Abandon,
+}
+
+impl Default for ClosePolicy {
+    fn default() -> Self {
+        ClosePolicy::Commit(DEFAULT_CLOSE_TIMEOUT)
+    }
+}
+
+/// What happened when the store was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CloseReport {
+    /// The id of the last message that was committed and flushed to disk.
+    pub flushed_message_id: u64,
+    /// The id of the last message written to the event files.  The messages after the flushed id
+    /// weren't committed, but are committed the next time the store is opened.
+    pub written_message_id: u64,
+    /// The number of writes that were failed because they weren't committed before we stopped.
+    pub abandoned_writes: usize,
+    /// True if we gave up waiting for the writes to be committed.
+    pub timed_out: bool,
+}
+
+///  The file format for the messages.  The goal is to have raft replicate the messages onto the
+///  server.  Would require 2 files, one with the messages and one with raft indicating which
+///  messages have been submitted.  The messages are aligned at 32 bytes for faster lookup.
+///
+///  Raw message log.
+///  0                   1                   2                   3
+///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// | Message Size                                                  |
+/// +-------------------------------+-------------------------------+ 32
+/// | Version                       |  Message Type                 ...
+/// +-------------------------------+-------------------------------+ 64
+/// ... Message Type cont.          | Global Message Id             |
 /// +-------------------------------+                               | 96
 /// |   Global message Id cont.                                     |
 /// |                               +-------------------------------+ 128
@@ -991,13 +3365,17 @@ pub trait PersistedMessageFileReadOnly {
     /// Reads a message a speific location.
     /// # Arguments
     /// `start` - The starting index to read in.
-    fn read<'a>(&'a self, start: usize) -> MessageInfo;
+    fn read<'a>(&'a self, start: usize) -> file::Result<MessageInfo<'a>>;
 
     /// Reads messages until the func returns false.
     /// # Arguments
     /// `start` - The starting point to start reading the messages.
-    /// `func` - The function to call when a message is read in.
-    fn read_msg_til<'a>(&'a self, start: usize, func: fn(message: MessageInfo) -> bool) -> u32;
+    /// `func` - Called with each message read in.  Can capture state to collect the messages.
+    /// # Returns
+    /// The number of messages handed to the function.
+    fn read_msg_til<F>(&self, start: usize, func: F) -> u32
+    where
+        F: FnMut(MessageInfo) -> bool;
 }
 
 /// Represents a mutable message file.  Need to be able to write the messages in a way where raft
@@ -1014,19 +3392,27 @@ pub trait PersistedMessageFileMut {
     /// `term_id` - The id of the message to write.
     /// `time` - The time in unix.
     /// `body` - The body of the file.
-    fn write(&mut self, start: &usize, term_id: &u32, time: &u64, body: &[u8]);
+    /// # Returns
+    /// The position after the message or an error if it doesn't fit in the file.
+    fn write(
+        &mut self,
+        start: &usize,
+        term_id: &u32,
+        time: &u64,
+        body: &[u8],
+    ) -> file::Result<usize>;
 
     /// Commits a term after the raft sync.  Flushes the data to disks.
     /// `term_id` - The id of the term we are committing.
-    fn commit_term(&mut self, term_id: &u32);
+    fn commit_term(&mut self, term_id: &u32) -> file::Result<()>;
 
     /// Reads a message a speific location.
     /// # Arguments
     /// `start` - The starting index to read in.
-    fn read<'a>(&'a mut self, start: &usize) -> MessageInfo;
+    fn read<'a>(&'a mut self, start: &usize) -> file::Result<MessageInfo<'a>>;
 
     /// Flushes the written data to the disk.
-    fn flush(&mut self);
+    fn flush(&mut self) -> file::Result<()>;
 
     /// The capacity of the file.
     fn capacity(&self) -> usize;
@@ -1037,6 +3423,8 @@ pub(crate) struct MessageFileInfo {
     path: String,
     file_id: u32,
     message_id_start: u64,
+    /// The file is no longer in the directory.  Kept so the iterators can report it.
+    removed: bool,
 }
 
 impl MessageFileInfo {
@@ -1084,6 +3472,7 @@ impl MessageFileInfo {
             path,
             file_id,
             message_id_start,
+            removed: false,
         }
     }
 }
@@ -1129,6 +3518,8 @@ pub struct FileCollection {
     /// The prefix for the files
     #[allow(dead_code)]
     file_prefix: String,
+    /// Where the files are kept.
+    backend: Arc<dyn MessageStoreBackend>,
 }
 
 unsafe impl Sync for FileCollection {}
@@ -1144,6 +3535,16 @@ pub struct MessageIterator {
     #[allow(dead_code)]
     start_message_id: u64,
     pos: usize,
+    /// The id of the last message read in.  0 if we don't know it yet.
+    last_message_id: u64,
+    /// Return an error instead of `NextResult::Gap` when a message id is missing.
+    strict_sequence: bool,
+    /// Only the messages matching the filter are returned.
+    filter: Option<MessageFilter>,
+    /// Where the files are kept.
+    backend: Arc<dyn MessageStoreBackend>,
+    /// Opens the next file instead of returning `NextResult::NextFile`.
+    cross_files: bool,
 }
 
 pub enum NextResult<'a> {
@@ -1151,10 +3552,28 @@ pub enum NextResult<'a> {
     End(u32),
     /// If there is more after processing the specified number.
     More,
-    /// The next file id.
+    /// The next file id.  Only returned when the iterator doesn't cross the files.
     NextFile { file_id: u32, readed: u32 },
     /// The message that was read in.
     Some(MessageRead<'a>),
+    /// A message id is missing.  The next call returns the message that was found.
+    Gap {
+        expected: u64,
+        found: u64,
+        position: usize,
+    },
+}
+
+/// Finds the index of the file a message is in.  The files are sorted by their id so the start ids
+/// only go up and the file can be found with a binary search.
+/// # Arguments
+/// `files` - The message files sorted by their id.
+/// `start_message_id` - The id of the message to find.
+/// # Returns
+/// The last file that isn't removed starting at or before the message or 0 if there isn't one.
+fn starting_file_index(files: &[MessageFileInfo], start_message_id: u64) -> usize {
+    let after = files.partition_point(|f| f.message_id_start <= start_message_id);
+    files[..after].iter().rposition(|f| !f.removed).unwrap_or(0)
 }
 
 impl MessageIterator {
@@ -1164,22 +3583,27 @@ impl MessageIterator {
     /// `start_message_id` - The starting message id.
     /// `max_commit_id` - The maximum id that has been commited.
     /// `message_files` - The files containing the messages.
+    /// `strict_sequence` - True to return an error when a message id is missing instead of a gap.
+    /// `backend` - Where the files are kept.
     #[allow(dead_code)]
     fn new(
         number: u32,
         start_message_id: u64,
         max_commit_id: u64,
         message_files: Arc<Mutex<Vec<MessageFileInfo>>>,
+        strict_sequence: bool,
+        backend: Arc<dyn MessageStoreBackend>,
     ) -> crate::file::Result<Self> {
         let file = MessageIterator::find_starting_file(message_files.clone(), start_message_id);
-        let reader = unsafe { MessageFileStore::open_readonly(&file.path) }?;
-        let mut pos = 0;
+        let reader = backend.open_readonly(Path::new(&file.path))?;
+        let mut pos = sparse_index::start_position(&reader, &file.path, start_message_id);
         loop {
-            let msg = reader.read_new(pos)?;
-            if msg.message_id() >= start_message_id || msg.message_id() == 0 {
+            // Only the headers are read to skip over the messages before the start.
+            let (message_id, next_pos) = reader.read_link(pos)?;
+            if message_id >= start_message_id || message_id == 0 {
                 break;
             } else {
-                pos = msg.next_pos();
+                pos = next_pos;
             }
         }
         Ok(MessageIterator {
@@ -1190,9 +3614,31 @@ impl MessageIterator {
             number,
             max_commit_id,
             pos,
+            last_message_id: start_message_id.saturating_sub(1),
+            strict_sequence,
+            filter: None,
+            backend,
+            cross_files: false,
         })
     }
 
+    /// Sets if the iterator moves onto the next file by itself.  When it does the messages keep
+    /// coming until the last committed message without `NextResult::NextFile` being returned.
+    /// # Arguments
+    /// `cross_files` - True to open the next file when the current one runs out.
+    pub fn set_cross_files(&mut self, cross_files: bool) {
+        self.cross_files = cross_files;
+    }
+
+    /// Sets the filter for the messages to return.  Messages that don't match are skipped without
+    /// counting against the number to retreive.
+    /// # Arguments
+    /// `filter` - The filter to apply to the message headers.
+    #[allow(dead_code)]
+    fn set_filter(&mut self, filter: MessageFilter) {
+        self.filter = Some(filter);
+    }
+
     /// Finds the starting message file.
     /// # Arguments
     /// `message_files` - The message file to search.
@@ -1203,94 +3649,277 @@ impl MessageIterator {
         start_message_id: u64,
     ) -> MessageFileInfo {
         let messages = message_files.lock().unwrap();
-        let mut last_file = 0;
-        for x in 0..messages.len() {
-            let file: &MessageFileInfo = messages.get(x).unwrap();
-            if file.message_id_start > start_message_id {
-                break;
+        let index = starting_file_index(&messages, start_message_id);
+        messages.get(index).unwrap().clone()
+    }
+
+    /// Moves the iterator to a message.  The file is only opened again when the message is in a
+    /// different one.
+    /// # Arguments
+    /// `message_id` - The id of the message to read next.  The iterator is moved to the first
+    /// message after it when the id is missing.
+    /// # Returns
+    /// `NotRetained` when the message is before the first one kept in the files.
+    pub fn seek_to(&mut self, message_id: u64) -> crate::file::Result<()> {
+        let earliest = self
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|f| !f.removed)
+            .map(|f| f.message_id_start)
+            .unwrap_or(0);
+        if message_id < earliest {
+            return Err(crate::file::Error::NotRetained {
+                message_id,
+                earliest,
+            });
+        }
+        let file = MessageIterator::find_starting_file(self.message_files.clone(), message_id);
+        let same_file = file.file_id == self.current_file_id;
+        if !same_file {
+            self.current_reader = self.backend.open_readonly(Path::new(&file.path))?;
+            self.current_file_id = file.file_id;
+        }
+        let indexed = sparse_index::start_position(&self.current_reader, &file.path, message_id);
+        let mut pos = if same_file && message_id > self.last_message_id {
+            // Going forward in the same file so we don't need to start over.
+            self.pos.max(indexed)
+        } else {
+            indexed
+        };
+        loop {
+            // The end of file message has the largest id so it stops the scan too.
+            match self.current_reader.read_link(pos) {
+                Ok((id, next_pos)) => {
+                    if id >= message_id || id == 0 {
+                        break;
+                    }
+                    pos = next_pos;
+                }
+                Err(crate::file::Error::NoMessage)
+                | Err(crate::file::Error::Full)
+                | Err(crate::file::Error::PositionOutOfRange(_)) => break,
+                Err(e) => return Err(e),
             }
-            last_file = x;
         }
-        messages.get(last_file).unwrap().clone()
+        self.pos = pos;
+        self.last_message_id = message_id.saturating_sub(1);
+        Ok(())
+    }
+
+    /// Copies each message that is read in so the messages can be used with a for loop, collected
+    /// or kept past the next call.  Moves onto the next file when the current one runs out.
+    pub fn into_owned(mut self) -> OwnedMessageIterator {
+        self.cross_files = true;
+        OwnedMessageIterator {
+            iterator: self,
+            done: false,
+        }
+    }
+
+    /// Starts reading at the start of a file.
+    /// # Arguments
+    /// `file_id` - The id of the file to read next.
+    fn open_file(&mut self, file_id: u32) -> crate::file::Result<()> {
+        let path = self
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|f| f.file_id == file_id && !f.removed)
+            .map(|f| f.path.clone())
+            .ok_or(crate::file::Error::FileRemoved(file_id))?;
+        self.current_reader = self.backend.open_readonly(Path::new(&path))?;
+        self.current_file_id = file_id;
+        self.pos = 0;
+        Ok(())
+    }
+
+    /// Finds out if there is a file after the current one once we are at the end of it.
+    /// # Returns
+    /// `NextFile` if the file after this one is known otherwise `End`.
+    fn find_next_file<'a>(&self) -> crate::file::Result<NextResult<'a>> {
+        let message_files = self.message_files.lock().unwrap();
+        if message_files
+            .iter()
+            .any(|f| f.removed && f.file_id == self.current_file_id)
+        {
+            return Err(crate::file::Error::FileRemoved(self.current_file_id));
+        }
+        // The ids can skip once the files have been compacted.
+        match message_files
+            .iter()
+            .filter(|f| f.file_id > self.current_file_id)
+            .min_by_key(|f| f.file_id)
+        {
+            Some(file) if file.removed => Err(crate::file::Error::FileRemoved(file.file_id)),
+            Some(file) => Ok(NextResult::NextFile {
+                file_id: file.file_id,
+                readed: self.number,
+            }),
+            None => Ok(NextResult::End(self.number)),
+        }
     }
 
     /// Used to get the next element.  Since this needs a lifetime because we are just exporting
     /// the memory directly.
     pub fn next<'a>(&'a mut self) -> crate::file::Result<NextResult<'a>> {
         if self.number == 0 {
-            Ok(NextResult::More)
-        } else {
+            return Ok(NextResult::More);
+        }
+        loop {
             match self.current_reader.read_new(self.pos) {
                 Ok(reader) => {
-                    if reader.message_id() <= self.max_commit_id {
-                        self.pos = reader.next_pos();
-                        self.number = self.number - 1;
-                        Ok(NextResult::Some(reader))
-                    } else {
-                        Ok(NextResult::End(self.number))
+                    if reader.msg_type_id() == END_OF_FILE_MESSAGE_TYPE {
+                        match self.next_file_result()? {
+                            Some(result) => break Ok(result),
+                            None => continue,
+                        }
                     }
-                }
-                Err(e) => {
-                    match e {
-                        crate::file::Error::NoMessage => {
-                            // Find the next file.  Lets just go backwards :)
-                            let message_files = self.message_files.lock().unwrap();
-                            let next_file_id = self.current_file_id + 1;
-                            let length = message_files.len() - 1;
-                            let mut found = false;
-                            for m in 0..message_files.len() {
-                                let i = m - length;
-                                let file: &MessageFileInfo = message_files.get(i).unwrap();
-                                if file.file_id == next_file_id {
-                                    found = true;
-                                    break;
-                                } else if file.file_id == self.current_file_id {
-                                    found = false;
-                                    break;
-                                }
+                    let message_id = reader.message_id();
+                    if message_id <= self.max_commit_id {
+                        // Messages without an id are skip frames so they aren't part of the sequence.
+                        // The chunks of a message all have the id of the message.
+                        let chunk = message_id == self.last_message_id
+                            && reader.msg_type_id() == CHUNK_MESSAGE_TYPE;
+                        if message_id > 0 && self.last_message_id > 0 && !chunk {
+                            let expected = self.last_message_id + 1;
+                            if message_id != expected {
+                                self.last_message_id = message_id - 1;
+                                return if self.strict_sequence {
+                                    Err(crate::file::Error::SequenceGap {
+                                        expected,
+                                        found: message_id,
+                                        position: self.pos,
+                                    })
+                                } else {
+                                    Ok(NextResult::Gap {
+                                        expected,
+                                        found: message_id,
+                                        position: self.pos,
+                                    })
+                                };
                             }
-                            if found {
-                                Ok(NextResult::NextFile {
-                                    file_id: next_file_id,
-                                    readed: self.number,
-                                })
-                            } else {
-                                Ok(NextResult::End(self.number))
+                        }
+                        if message_id > 0 {
+                            self.last_message_id = message_id;
+                        }
+                        let position = self.pos;
+                        self.pos = reader.next_pos();
+                        if let Some(filter) = &self.filter {
+                            if filter.is_past_end(message_id) {
+                                break Ok(NextResult::End(self.number));
+                            }
+                            if !filter.matches(&MessageHeaderView::new(&reader, position)) {
+                                continue;
                             }
                         }
-                        _ => Err(e),
+                        self.number -= 1;
+                        break Ok(NextResult::Some(reader));
+                    } else {
+                        break Ok(NextResult::End(self.number));
                     }
                 }
+                Err(e) => match e {
+                    crate::file::Error::NoMessage
+                    | crate::file::Error::Full
+                    | crate::file::Error::PositionOutOfRange(_) => {
+                        match self.next_file_result()? {
+                            Some(result) => break Ok(result),
+                            None => continue,
+                        }
+                    }
+                    _ => break Err(e),
+                },
+            }
+        }
+    }
+
+    /// Gets the next result after the current file has run out.  Opens the next file when the
+    /// iterator crosses the files.
+    /// # Returns
+    /// `None` if the next file was opened and the messages can be read from it.
+    fn next_file_result<'a>(&mut self) -> crate::file::Result<Option<NextResult<'a>>> {
+        match self.find_next_file()? {
+            NextResult::NextFile { file_id, .. } if self.cross_files => {
+                self.open_file(file_id)?;
+                Ok(None)
             }
+            result => Ok(Some(result)),
         }
     }
 }
 
 pub trait MessageStore {
-    /// Used to add a message to a buffer.
+    /// Used to add a message to a buffer.  The store gives the message its id when it is written.
     /// # Arguments
     /// `msg_type_id` - The type of the message.
-    /// `message_id` - The id of the message.
     /// `buffer` - The buffer to add to the messate queue.
     /// # Returns
-    /// TODO a future that gets called when it gets committed.
-    fn add_message(&self, msg_type_id: &i32, message_id: &u64, buffer: &[u8]);
+    /// A future that gets completed with the id of the message once it has been committed.  It is
+    /// completed with an error if the message is dropped before it gets committed.
+    fn add_message(&self, msg_type_id: &i32, buffer: &[u8]) -> CommitFuture<crate::Result<u64>>;
 }
 
-/// Gets the file id from the extension.
+/// Gets the file id from the name of an event or commit file.
 /// # Arguments
-/// path_str - The path string to parse in.
+/// `file_prefix` - The prefix of the files.
+/// `name` - The name of the file without the directory.
 /// # Returns
-/// The id of the file if it can be parsed in.
-pub(crate) fn read_file_id(path_str: &str) -> Option<u32> {
-    let file_id_opt: Option<&str> = path_str.split(".").last();
-    match file_id_opt {
-        Some(file_id) => match file_id.parse::<u32>() {
-            Ok(id) => Some(id),
-            _ => None,
-        },
-        None => None,
+/// The id if the name is `file_prefix.events.<id>` or `file_prefix.commit.<id>`.  The id can be
+/// zero padded or not so the files named before the padding was added are still read.
+pub(crate) fn read_file_id(file_prefix: &str, name: &str) -> Option<u32> {
+    parse_file_id(file_prefix, EVENT_FILE_POSTFIX, name)
+        .or_else(|| parse_file_id(file_prefix, COMMIT_FILE_POSTIX, name))
+}
+
+/// Gets the file id from a file name of the form `file_prefix.postfix.<digits>`.
+/// # Arguments
+/// `file_prefix` - The prefix of the files.
+/// `postfix` - The kind of file.
+/// `name` - The name of the file without the directory.
+/// # Returns
+/// The id or `None` if the name is for something else or the id doesn't fit in a `u32`.
+fn parse_file_id(file_prefix: &str, postfix: &str, name: &str) -> Option<u32> {
+    let digits = name
+        .strip_prefix(file_prefix)?
+        .strip_prefix('.')?
+        .strip_prefix(postfix)?
+        .strip_prefix('.')?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    digits.parse().ok()
+}
+
+/// Gets the id of the event file after a file.  The ids skip the files merged by a compaction so
+/// the directory is listed when the next id doesn't exist.
+/// # Arguments
+/// `backend` - Where the files are kept.
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// `file_id` - The id of the file to start after.
+/// # Returns
+/// The id of the next file or `None` if it hasn't been created yet.
+pub(crate) fn next_event_file_id(
+    backend: &dyn MessageStoreBackend,
+    file_storage_directory: &str,
+    file_prefix: &str,
+    file_id: u32,
+) -> Option<u32> {
+    let next = create_event_name(file_storage_directory, file_prefix, &(file_id + 1));
+    if backend.exists(Path::new(&next)) {
+        return Some(file_id + 1);
+    }
+    backend
+        .list(Path::new(file_storage_directory))
+        .ok()?
+        .iter()
+        .filter_map(|path| path.file_name().and_then(|name| name.to_str()))
+        .filter_map(|name| parse_file_id(file_prefix, EVENT_FILE_POSTFIX, name))
+        .filter(|id| *id > file_id)
+        .min()
 }
 
 impl FileCollection {
@@ -1299,107 +3928,507 @@ impl FileCollection {
     /// `file_storage_directory` - The file storage directory.
     /// `file_prefix` - The file name prefix.
     fn new(file_storage_directory: String, file_prefix: String) -> Self {
+        FileCollection::with_backend(file_storage_directory, file_prefix, Arc::new(FileBackend))
+    }
+
+    /// Gets a new file collection with the files kept in a backend.
+    /// # Arguments
+    /// `file_storage_directory` - The file storage directory.
+    /// `file_prefix` - The file name prefix.
+    /// `backend` - Where the files are kept.
+    pub fn with_backend(
+        file_storage_directory: String,
+        file_prefix: String,
+        backend: Arc<dyn MessageStoreBackend>,
+    ) -> Self {
         FileCollection {
             commit_files: Arc::new(Mutex::new(Vec::with_capacity(10))),
             message_files: Arc::new(Mutex::new(Vec::with_capacity(10))),
             file_storage_directory,
             file_prefix,
+            backend,
         }
     }
 
+    /// Where the files are kept.
+    pub fn backend(&self) -> &Arc<dyn MessageStoreBackend> {
+        &self.backend
+    }
+
     /// Adds a message file if it has a message id.
     /// # Arguments
     /// `path` - The path buf to the file.
-    /// `path_str` - The path of the file as a string.
-    fn add_message_file(&mut self, path: PathBuf, path_str: &str) -> std::io::Result<()> {
-        let mut message_files = self.message_files.lock().unwrap();
-        match read_file_id(path_str) {
-            Some(id) => {
-                let (read, _) = unsafe { MessageFileStore::open(&path)? };
-                let mut msg_id: u64 = 0;
-                {
-                    let result = read.read(0, move |_, id, _| {
-                        if id > 0 {
-                            msg_id = id;
-                        }
-                    });
-                    match result {
-                        Ok(_) => {
-                            message_files.push(MessageFileInfo {
-                                path: path_str.to_owned(),
-                                file_id: id,
-                                message_id_start: msg_id,
-                            });
-                            Ok(())
-                        }
-                        Err(e) => match e {
-                            file::Error::FileError(e) => Err(e),
-                            _ => Ok(()),
-                        },
-                    }?
-                }
-                message_files.sort();
-                Ok(())
-            }
-            _ => Ok(()),
+    /// `file_id` - The id from the name of the file.
+    fn add_message_file(&mut self, path: PathBuf, file_id: u32) -> std::io::Result<()> {
+        if let Some(file) = read_message_file_info(self.backend.as_ref(), &path, file_id)? {
+            let mut message_files = self.message_files.lock().unwrap();
+            message_files.push(file);
+            message_files.sort();
         }
+        Ok(())
     }
 
     /// Used to add a commit file.
     /// # Arguments
     /// `path` - The path buffer for the file.
-    /// `path_str` - The path string.
-    fn add_commit_file(&mut self, path: PathBuf, path_str: &str) -> std::io::Result<()> {
-        match read_file_id(path_str) {
-            Some(id) => {
-                let file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(false)
-                    .open(path)?;
-                let buffer = unsafe { MemoryMappedInt::open(file) }?;
-                let term_id = buffer.term(0); // Get the starting message.
-                let message_id = buffer.max_message_id(0);
-                let time = buffer.start_time(0);
-                if time > 0 {
-                    self.commit_files.lock().unwrap().push(CommitFileInfo::new(
-                        path_str.to_owned(),
-                        id,
-                        term_id,
-                        message_id,
-                    ));
-                    Ok(())
-                } else {
-                    // Not sure what we should do with the file since it's not valid.
-                    Ok(())
-                }
+    /// `file_id` - The id from the name of the file.
+    /// # Returns
+    /// `CorruptTerm` if the file doesn't hold a whole number of terms.
+    fn add_commit_file(&mut self, path: PathBuf, file_id: u32) -> crate::Result<()> {
+        let path_str = path.to_string_lossy().into_owned();
+        let size = self
+            .backend
+            .open_readonly(&path)
+            .with_path(path_str.as_str())?
+            .buffer()
+            .capacity();
+        if size < FILE_HEADER_SIZE
+            || !(size - FILE_HEADER_SIZE).is_multiple_of(COMMIT_SIZE as usize)
+        {
+            return Err(PersistError::CorruptTerm {
+                context: Context::default(),
+                reason: format!(
+                    "The commit file is {} bytes which isn't the header and whole {} byte terms.",
+                    size, COMMIT_SIZE
+                ),
             }
-            _ => Ok(()),
+            .with_path(path_str));
+        }
+        if let Some(file) =
+            read_commit_file_info(self.backend.as_ref(), &path, file_id).with_path(path_str)?
+        {
+            let mut commit_files = self.commit_files.lock().unwrap();
+            commit_files.push(file);
+            // The newest file is expected to be last when we start up.
+            commit_files.sort();
         }
+        Ok(())
     }
-}
 
-pub fn create_event_name(file_storage_directory: &str, file_prefix: &str, file_id: &u32) -> String {
-    let path = Path::new(file_storage_directory);
-    if !path.exists() {
-        create_dir_all(path).unwrap();
+    /// Scans the directory again to pick up the files that were added or removed by something
+    /// else.  Removed message files are kept but marked so the iterators reading them get a
+    /// `FileRemoved` error instead of a missing file.
+    /// # Returns
+    /// The ids of the files that changed.
+    pub fn refresh(&mut self) -> crate::Result<RefreshDelta> {
+        let mut event_paths = Vec::new();
+        let mut commit_paths = Vec::new();
+        let paths = self
+            .backend
+            .list(Path::new(&self.file_storage_directory))
+            .with_path(&self.file_storage_directory)?;
+        for path in paths {
+            let name = match path.file_name().and_then(|p| p.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            if let Some(file_id) = parse_file_id(&self.file_prefix, EVENT_FILE_POSTFIX, &name) {
+                event_paths.push((file_id, path));
+            } else if let Some(file_id) =
+                parse_file_id(&self.file_prefix, COMMIT_FILE_POSTIX, &name)
+            {
+                commit_paths.push((file_id, path));
+            }
+        }
+
+        let mut delta = RefreshDelta::default();
+        {
+            let mut message_files = self.message_files.lock().unwrap();
+            for file in message_files.iter_mut() {
+                if !file.removed && !event_paths.iter().any(|(id, _)| *id == file.file_id) {
+                    file.removed = true;
+                    delta.removed_message_files.push(file.file_id);
+                }
+            }
+            for (file_id, path) in event_paths.iter() {
+                match message_files.iter().position(|f| f.file_id == *file_id) {
+                    Some(i) if !message_files[i].removed => {}
+                    existing => {
+                        if let Some(file) =
+                            read_message_file_info(self.backend.as_ref(), path, *file_id)?
+                        {
+                            match existing {
+                                Some(i) => message_files[i] = file,
+                                None => message_files.push(file),
+                            }
+                            delta.added_message_files.push(*file_id);
+                        }
+                    }
+                }
+            }
+            message_files.sort();
+        }
+        {
+            let mut commit_files = self.commit_files.lock().unwrap();
+            commit_files.retain(|file| {
+                let exists = commit_paths.iter().any(|(id, _)| *id == file.file_id);
+                if !exists {
+                    delta.removed_commit_files.push(file.file_id);
+                }
+                exists
+            });
+            for (file_id, path) in commit_paths.iter() {
+                if !commit_files.iter().any(|f| f.file_id == *file_id) {
+                    if let Some(file) =
+                        read_commit_file_info(self.backend.as_ref(), path, *file_id)?
+                    {
+                        commit_files.push(file);
+                        delta.added_commit_files.push(*file_id);
+                    }
+                }
+            }
+            commit_files.sort();
+        }
+        delta.added_message_files.sort_unstable();
+        delta.removed_message_files.sort_unstable();
+        delta.added_commit_files.sort_unstable();
+        delta.removed_commit_files.sort_unstable();
+        Ok(delta)
     }
-    format!(
-        "{}{}{}.{}.{}",
-        file_storage_directory, MAIN_SEPARATOR, file_prefix, EVENT_FILE_POSTFIX, file_id
-    )
-}
 
-/// Used to create the commit file name.
-pub fn create_commit_name(
-    file_storage_directory: &str,
-    file_prefix: &str,
-    file_id: &u32,
-) -> String {
-    format!(
-        "{}{}{}.{}.{}",
-        file_storage_directory, MAIN_SEPARATOR, file_prefix, COMMIT_FILE_POSTIX, file_id
-    )
+    /// Removes the files the retention policy doesn't keep.  Only the event files with all of their
+    /// messages committed are removed and the newest one is always kept.  The removed event files
+    /// are marked so the iterators get `FileRemoved`, a commit file is removed once all of its
+    /// terms are before the first message kept.  On unix an iterator that has a removed file open
+    /// keeps reading it, Windows doesn't allow an open file to be removed so it is left for the
+    /// next time the retention is applied.
+    /// # Arguments
+    /// `policy` - Which files to keep.
+    /// `now_ms` - The current time in milliseconds since the epoch.
+    /// # Returns
+    /// The ids of the files that were removed.
+    pub fn apply_retention(
+        &self,
+        policy: RetentionPolicy,
+        now_ms: u64,
+    ) -> crate::Result<RefreshDelta> {
+        let mut delta = RefreshDelta::default();
+        if policy == RetentionPolicy::KeepAll {
+            return Ok(delta);
+        }
+        let cutoff_ms = match policy {
+            RetentionPolicy::KeepNewerThan(age) => now_ms.saturating_sub(age.as_millis() as u64),
+            _ => 0,
+        };
+        let (committed, expired) = self.scan_committed(cutoff_ms)?;
+        {
+            let mut message_files = self.message_files.lock().unwrap();
+            let live: Vec<usize> = (0..message_files.len())
+                .filter(|i| !message_files[*i].removed)
+                .collect();
+            // The number of files at the front with all of their messages at or before an id.  The
+            // next file could start with the rest of a message split between them so it has to
+            // start at or before the id.
+            let before = |message_id: u64| {
+                live.windows(2)
+                    .take_while(|w| message_files[w[1]].message_id_start <= message_id)
+                    .count()
+            };
+            let count = match policy {
+                RetentionPolicy::KeepAll => 0,
+                RetentionPolicy::KeepFiles(files) => {
+                    live.len().saturating_sub(files.max(1) as usize)
+                }
+                RetentionPolicy::KeepBytes(bytes) => {
+                    let mut total = 0;
+                    let mut kept = 0;
+                    for i in live.iter().rev() {
+                        let file = &message_files[*i];
+                        let size = self
+                            .backend
+                            .open_readonly(Path::new(&file.path))
+                            .with_path(file.path.as_str())?
+                            .capacity() as u64
+                            + FILE_HEADER_SIZE as u64;
+                        total += size;
+                        if kept > 0 && total > bytes {
+                            break;
+                        }
+                        kept += 1;
+                    }
+                    live.len() - kept
+                }
+                RetentionPolicy::KeepNewerThan(_) => before(expired),
+                RetentionPolicy::KeepAboveId(message_id) => before(message_id),
+            }
+            .min(before(committed));
+            for i in live.into_iter().take(count) {
+                let file = &mut message_files[i];
+                if let Err(e) = self.backend.remove(Path::new(&file.path)) {
+                    // Still open on Windows so try again next time.
+                    log::warn!("Unable to remove {}: {}", file.path, e);
+                    break;
+                }
+                let index = create_index_name(
+                    &self.file_storage_directory,
+                    &self.file_prefix,
+                    &file.file_id,
+                );
+                if self.backend.exists(Path::new(&index)) {
+                    self.backend
+                        .remove(Path::new(&index))
+                        .with_path(index.as_str())?;
+                }
+                file.removed = true;
+                delta.removed_message_files.push(file.file_id);
+            }
+        }
+        let first_kept = match self
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|f| !f.removed)
+        {
+            Some(file) => file.message_id_start,
+            None => return Ok(delta),
+        };
+        let mut commit_files = self.commit_files.lock().unwrap();
+        // The terms in a file end before the first term of the next one.
+        while commit_files.len() > 1 && commit_files[1].message_id < first_kept {
+            let file = &commit_files[0];
+            self.backend
+                .remove(Path::new(&file.path))
+                .with_path(file.path.as_str())?;
+            delta.removed_commit_files.push(file.file_id);
+            commit_files.remove(0);
+        }
+        Ok(delta)
+    }
+
+    /// Reads through the committed terms.
+    /// # Arguments
+    /// `cutoff_ms` - The time to find the last message committed before.
+    /// # Returns
+    /// The id of the last committed message and the id of the last message committed before the
+    /// cutoff.
+    fn scan_committed(&self, cutoff_ms: u64) -> crate::Result<(u64, u64)> {
+        let commit_files = self.commit_files.lock().unwrap();
+        let mut committed = 0;
+        let mut expired = 0;
+        let mut expiring = true;
+        for file in commit_files.iter() {
+            let reader = self
+                .backend
+                .open_readonly(Path::new(&file.path))
+                .with_path(file.path.as_str())?;
+            let buffer = reader.buffer();
+            let mut pos = 0;
+            while FILE_HEADER_SIZE + pos + COMMIT_SIZE as usize <= buffer.capacity()
+                && buffer.term(pos) > 0
+                && buffer.committed(pos) > 0
+                && buffer.verify(pos)
+            {
+                committed = buffer.max_message_id(pos);
+                // Stops at the first term that is new enough in case the times are out of order.
+                if expiring && buffer.start_time(pos) < cutoff_ms {
+                    expired = committed;
+                } else {
+                    expiring = false;
+                }
+                pos += COMMIT_SIZE as usize;
+            }
+        }
+        Ok((committed, expired))
+    }
+
+    /// Watches the directory and refreshes the collection when a file is created or removed.
+    /// # Arguments
+    /// `callback` - Called with the changes after each refresh that found something.
+    /// # Returns
+    /// The watcher.  The directory stops being watched when it is dropped.
+    #[cfg(feature = "watch")]
+    pub fn watch<F>(&self, callback: F) -> notify::Result<FileWatcher>
+    where
+        F: Fn(&RefreshDelta) + Send + 'static,
+    {
+        use notify::{EventKind, RecursiveMode, Watcher};
+        let mut collection = FileCollection {
+            commit_files: self.commit_files.clone(),
+            message_files: self.message_files.clone(),
+            file_storage_directory: self.file_storage_directory.clone(),
+            file_prefix: self.file_prefix.clone(),
+            backend: self.backend.clone(),
+        };
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    match event.kind {
+                        EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(_) => {
+                            if let Ok(delta) = collection.refresh() {
+                                if !delta.is_empty() {
+                                    callback(&delta);
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            })?;
+        watcher.watch(
+            Path::new(&self.file_storage_directory),
+            RecursiveMode::NonRecursive,
+        )?;
+        Ok(FileWatcher { _watcher: watcher })
+    }
+}
+
+/// Watches the storage directory for a file collection.
+#[cfg(feature = "watch")]
+pub struct FileWatcher {
+    _watcher: notify::RecommendedWatcher,
+}
+
+/// The files that changed when the collection was refreshed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RefreshDelta {
+    /// The ids of the message files that were added.
+    pub added_message_files: Vec<u32>,
+    /// The ids of the message files that were removed.
+    pub removed_message_files: Vec<u32>,
+    /// The ids of the commit files that were added.
+    pub added_commit_files: Vec<u32>,
+    /// The ids of the commit files that were removed.
+    pub removed_commit_files: Vec<u32>,
+}
+
+impl RefreshDelta {
+    /// True if nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added_message_files.is_empty()
+            && self.removed_message_files.is_empty()
+            && self.added_commit_files.is_empty()
+            && self.removed_commit_files.is_empty()
+    }
+}
+
+/// Reads in the information for a message file.
+/// # Arguments
+/// `backend` - Where the file is kept.
+/// `path` - The path to the file.
+/// `file_id` - The id from the name of the file.
+/// # Returns
+/// The file information or `None` if the file doesn't have any messages.
+fn read_message_file_info(
+    backend: &dyn MessageStoreBackend,
+    path: &Path,
+    file_id: u32,
+) -> std::io::Result<Option<MessageFileInfo>> {
+    let read = backend.open_readonly(path)?;
+    match read.read_new(0) {
+        Ok(msg) => Ok(Some(MessageFileInfo::new(
+            path.to_string_lossy().into_owned(),
+            file_id,
+            msg.message_id(),
+        ))),
+        Err(file::Error::FileError(e)) => Err(e),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads in the information for a commit file.
+/// # Arguments
+/// `backend` - Where the file is kept.
+/// `path` - The path to the file.
+/// `file_id` - The id from the name of the file.
+/// # Returns
+/// The file information or `None` if the file doesn't have a term.
+fn read_commit_file_info(
+    backend: &dyn MessageStoreBackend,
+    path: &Path,
+    file_id: u32,
+) -> std::io::Result<Option<CommitFileInfo>> {
+    let reader = backend.open_readonly(path)?;
+    let buffer = reader.buffer();
+    let term_id = buffer.term(0); // Get the starting message.
+    let message_id = buffer.max_message_id(0);
+    // A first term that doesn't match its checksum was never finished.
+    if buffer.verify(0) {
+        Ok(Some(CommitFileInfo::new(
+            path.to_string_lossy().into_owned(),
+            file_id,
+            term_id,
+            message_id,
+        )))
+    } else {
+        // Not sure what we should do with the file since it's not valid.
+        Ok(None)
+    }
+}
+
+pub fn create_event_name(file_storage_directory: &str, file_prefix: &str, file_id: &u32) -> String {
+    let path = Path::new(file_storage_directory);
+    if !path.exists() {
+        create_dir_all(path).unwrap();
+    }
+    create_file_name(file_storage_directory, file_prefix, EVENT_FILE_POSTFIX, file_id)
+}
+
+/// Used to create the commit file name.
+/// Creates the name of the index for an event file.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// `file_id` - The id of the event file.
+pub fn create_index_name(file_storage_directory: &str, file_prefix: &str, file_id: &u32) -> String {
+    create_file_name(file_storage_directory, file_prefix, INDEX_FILE_POSTFIX, file_id)
+}
+
+/// Creates the name of an event, commit or index file.  The id is zero padded unless the file
+/// already exists with the unpadded name it had before the ids were padded.  Those files are left
+/// where they are since `read_file_id` reads both names.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// `postfix` - The kind of file.
+/// `file_id` - The id of the file.
+fn create_file_name(
+    file_storage_directory: &str,
+    file_prefix: &str,
+    postfix: &str,
+    file_id: &u32,
+) -> String {
+    let padded = format!(
+        "{}{}{}.{}.{:0width$}",
+        file_storage_directory,
+        MAIN_SEPARATOR,
+        file_prefix,
+        postfix,
+        file_id,
+        width = FILE_ID_WIDTH
+    );
+    if Path::new(&padded).exists() {
+        return padded;
+    }
+    let unpadded = format!(
+        "{}{}{}.{}.{}",
+        file_storage_directory, MAIN_SEPARATOR, file_prefix, postfix, file_id
+    );
+    if Path::new(&unpadded).is_file() {
+        unpadded
+    } else {
+        padded
+    }
+}
+
+/// Creates the name of the file with the raft term and vote.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+pub fn create_meta_name(file_storage_directory: &str, file_prefix: &str) -> String {
+    format!(
+        "{}{}{}.{}",
+        file_storage_directory, MAIN_SEPARATOR, file_prefix, META_FILE_POSTFIX
+    )
+}
+
+pub fn create_commit_name(
+    file_storage_directory: &str,
+    file_prefix: &str,
+    file_id: &u32,
+) -> String {
+    create_file_name(file_storage_directory, file_prefix, COMMIT_FILE_POSTIX, file_id)
 }
 
 /// Used to process the file collection and get the current term file and message file.
@@ -1409,7 +4438,6 @@ pub fn create_commit_name(
 /// `file_storage_directory` - The location to store the file.
 /// `max_file_size` - The maximum file size.  The value is assumed to be already aligned.
 /// `commit_file_size` - The commit file size.
-#[allow(dead_code)]
 fn process_files(
     file_collection: &mut FileCollection,
     file_prefix: &str,
@@ -1428,7 +4456,7 @@ fn process_files(
         let path_commit = create_event_name(file_storage_directory, file_prefix, &file_id);
         let (_read, _write) = unsafe { MessageFileStore::new(&path_commit, *max_file_size)? };
         let path_event = create_commit_name(file_storage_directory, file_prefix, &file_id);
-        let _commit_file = unsafe { MemoryMappedInt::new(&path_event, *commit_file_size)? };
+        let _commit_file = unsafe { create_commit_file(&path_event, *commit_file_size)? };
         message_files.push(MessageFileInfo::new(path_commit.clone(), 1, 0));
         commit_files.push(CommitFileInfo::new(path_event.clone(), 1, 0, 0));
         Ok(())
@@ -1441,32 +4469,52 @@ fn process_files(
 /// # Arguments
 /// `file_prefix` - The file prefix to load.
 /// `file_storage_directory` - The file storage directory.
-#[allow(dead_code)]
+/// # Returns
+/// The files or an error if one of them is smaller than the file header or a commit file doesn't
+/// hold a whole number of terms.
 fn load_current_files(
     file_prefix: &str,
     file_storage_directory: &str,
-) -> io::Result<FileCollection> {
+) -> crate::Result<FileCollection> {
     let dir_path = Path::new(&file_storage_directory);
     if dir_path.is_dir() || !dir_path.exists() {
         if !dir_path.exists() {
-            create_dir_all(dir_path)?;
+            create_dir_all(dir_path).with_path(file_storage_directory)?;
         }
         let mut file_collection =
             FileCollection::new(file_storage_directory.to_owned(), file_prefix.to_owned());
-        let starts_with_events = format!("{}.{}", &file_prefix, &EVENT_FILE_POSTFIX);
-        let starts_with_commits = format!("{}.{}", &file_prefix, &COMMIT_FILE_POSTIX);
-        for entry in read_dir(file_storage_directory)? {
-            let file = entry?;
+        for entry in read_dir(file_storage_directory).with_path(file_storage_directory)? {
+            let file = entry.with_path(file_storage_directory)?;
             let path: PathBuf = file.path();
+            let path_str = path.to_string_lossy().into_owned();
             if path.is_file() {
                 match path.file_name() {
                     Some(p) => {
                         match p.to_str() {
                             Some(p) => {
-                                if p.starts_with(&starts_with_commits) {
-                                    file_collection.add_message_file(path.clone(), p)?;
-                                } else if p.starts_with(&starts_with_events) {
-                                    file_collection.add_commit_file(path.clone(), p)?;
+                                // Anything else like a temporary file is skipped.
+                                let event_id = parse_file_id(file_prefix, EVENT_FILE_POSTFIX, p);
+                                let commit_id = parse_file_id(file_prefix, COMMIT_FILE_POSTIX, p);
+                                if (event_id.is_some() || commit_id.is_some())
+                                    && file.metadata().with_path(path_str.as_str())?.len()
+                                        < FILE_HEADER_SIZE as u64
+                                {
+                                    return Err(Error::new(
+                                        ErrorKind::InvalidData,
+                                        format!(
+                                            "The file {} is smaller than the header size of {} bytes.",
+                                            path.display(),
+                                            FILE_HEADER_SIZE
+                                        ),
+                                    ))
+                                    .with_path(path_str);
+                                }
+                                if let Some(file_id) = event_id {
+                                    file_collection
+                                        .add_message_file(path.clone(), file_id)
+                                        .with_path(path_str.as_str())?;
+                                } else if let Some(file_id) = commit_id {
+                                    file_collection.add_commit_file(path.clone(), file_id)?;
                                 }
                             }
                             _ => {
@@ -1486,6 +4534,7 @@ fn load_current_files(
             ErrorKind::InvalidInput,
             "Path isn't a directory!",
         ))
+        .with_path(file_storage_directory)
     }
 }
 
@@ -1520,13 +4569,17 @@ pub(crate) fn find_last_commit_pos(
                 .create(false)
                 .open(&file_commit.path)
                 .unwrap();
-            let buffer = unsafe { MemoryMappedInt::open(file).unwrap() };
+            let buffer = unsafe { open_commit_file(file).unwrap() };
             let mut pos = 0;
             let mut last_term = 0;
             let mut found_commit = false;
             let mut last_max_message = 0;
             let start_term_id = file_commit.term_start;
             loop {
+                // A full file ends without an empty term after the last one.
+                if !buffer.verify(pos) {
+                    break;
+                }
                 let term = buffer.term(pos);
                 if term == 0 {
                     break;
@@ -1586,12 +4639,16 @@ fn find_last_term(commit_files: &Arc<Mutex<Vec<CommitFileInfo>>>) -> LastTermPos
                 .create(false)
                 .open(&file_commit.path)
                 .unwrap();
-            let buffer = unsafe { MemoryMappedInt::open(file).unwrap() };
+            let buffer = unsafe { open_commit_file(file).unwrap() };
             let mut pos = 0;
             let mut last_term = 0;
             let mut found_commit = false;
             let start_term_id = file_commit.term_start;
             loop {
+                // A full file ends without an empty term after the last one.
+                if !buffer.verify(pos) {
+                    break;
+                }
                 let term = buffer.term(pos);
                 if term == 0 {
                     break;
@@ -1627,79 +4684,168 @@ fn find_last_term(commit_files: &Arc<Mutex<Vec<CommitFileInfo>>>) -> LastTermPos
 /// `stop` - We should stop processing.
 /// `receiver` - The queue we are receiving from.
 /// `peding_queue` - The pending queue.
-/// `max_message_id` - The maximum message id.
+/// `written_message_id` - The id of the last message written.
 /// `event_file_size` - The file size for the event.
 /// `file_storage_directory` - The storage directory for the files.
 /// `file_prefix` - The file prefix.
 /// `file_id_start` - The starting file id.
+/// `commit_queue` - Where to hand off the futures to complete once the messages are committed.
+/// `commit_wait` - Signaled when messages are written so the commit thread wakes up.
+/// `latency` - Given the time the messages were queued for the reader.
+/// `positions` - The index to add the positions of the messages written to.
+/// `events` - Where to publish the files being rolled.
+/// `message_files` - The files the readers know about.  The files rolled onto are added to it.
+/// `index_interval` - The number of messages between the entries of the index kept next to each
+/// event file.  `None` doesn't keep the index.
+/// `dirty` - Where to record the bytes written so the commit thread only flushes them.
+/// `thread` - The settings for the thread.
+#[allow(clippy::too_many_arguments)]
 fn write_thread_single(
     stop: Arc<AtomicU8>,
     receiver: MpscQueueReceive<AddMessageWriteRs>,
     pending_write_queue: ManyToOneBufferReader,
-    max_message_id: Arc<AtomicU64>,
+    written_message_id: Arc<AtomicU64>,
     event_file_size: usize,
     file_storage_directory: String,
     file_prefix: String,
     file_id_start: u32,
-    commit_writer: SpscQueueSendWrap<AddMessageCommit>,
+    commit_queue: Arc<CommitQueue>,
+    commit_wait: Arc<dyn WaitStrategy>,
+    latency: Arc<StoreLatency>,
+    positions: Arc<PositionIndex>,
+    events: Arc<StoreEvents>,
+    message_files: Arc<Mutex<Vec<MessageFileInfo>>>,
+    index_interval: Option<u64>,
+    dirty: Arc<DirtyRanges>,
+    thread: ThreadConfig,
 ) -> JoinHandle<u32> {
-    thread::spawn(move || {
+    spawn_configured(thread, move || {
         let mut file_buffer = PersistedMessageWriteStream::new(
             file_id_start,
             file_storage_directory,
             file_prefix,
             event_file_size,
-            max_message_id.clone(),
+            written_message_id,
         )
-        .unwrap();
+        .unwrap()
+        .with_positions(positions)
+        .with_events(events)
+        .with_message_files(message_files)
+        .with_dirty_ranges(dirty);
+        if let Some(interval) = index_interval {
+            file_buffer = file_buffer.with_sparse_index(interval);
+        }
         let mut last_msg_id = file_buffer.loaded_message_id;
+        // The message ids for the positions in the incoming buffer we haven't gotten the future
+        // for yet.
+        let mut written: HashMap<usize, file::Result<u64>> = HashMap::new();
+        let mut waiting: Vec<AddMessageWriteRs> = Vec::new();
+        let mut matched: Vec<AddMessageCommit> = Vec::new();
+        // The futures that didn't fit in the commit queue.  Offered again on the next pass.
+        let mut overflow: Vec<AddMessageCommit> = Vec::new();
         loop {
             if stop.load(atomic::Ordering::Relaxed) > 0 {
-                break 0;
+                // Anything we haven't written is never going to be committed.
+                while let Some(value) = receiver.poll() {
+                    waiting.push(value);
+                }
+                let mut failed = 0;
+                for value in waiting.drain(..) {
+                    match written.remove(&value.position_start) {
+                        // The message was written so it could have been committed.
+                        Some(Ok(message_id)) => overflow.push(AddMessageCommit::new(
+                            message_id,
+                            value.complete,
+                            value.reserved,
+                            value.queued_ns,
+                        )),
+                        Some(Err(e)) => {
+                            failed += 1;
+                            value.complete(Err(e));
+                        }
+                        None => {
+                            failed += 1;
+                            value.complete(Err(file::Error::Stopped));
+                        }
+                    }
+                }
+                // The commit thread could have already stopped before we handed off the last of
+                // them.
+                break (failed + commit_queue.stop(std::mem::take(&mut overflow))) as u32;
             } else {
-                let write_out_pos = 0;
-                let write_out_file = 0;
-                let r = pending_write_queue.read(
-                    |msg_type, bytes| {
-                        last_msg_id += 1;
-                        let (pos, file) = file_buffer
-                            .add_message(msg_type, last_msg_id, bytes)
-                            .unwrap();
-                        let _write_out_pos = pos;
-                        let _write_out_file = file;
+                let r = pending_write_queue.read_indexed(
+                    |index, msg_type, bytes| {
+                        if msg_type == INCOMING_TRANSACTION_TYPE {
+                            let result = file_buffer.add_transaction(last_msg_id + 1, bytes);
+                            if let Ok(id) = result {
+                                last_msg_id = id;
+                            }
+                            written.insert(index, result);
+                        } else if msg_type == INCOMING_HEADERS_TYPE {
+                            last_msg_id += 1;
+                            let headers =
+                                MessageHeaders::from_bytes(&bytes[4..INCOMING_HEADERS_SIZE]);
+                            file_buffer
+                                .add_message_with_headers(
+                                    BigEndian::read_i32(&bytes[0..4]),
+                                    last_msg_id,
+                                    &headers,
+                                    &bytes[INCOMING_HEADERS_SIZE..],
+                                )
+                                .unwrap();
+                            written.insert(index, Ok(last_msg_id));
+                        } else {
+                            last_msg_id += 1;
+                            file_buffer
+                                .add_message(msg_type, last_msg_id, bytes)
+                                .unwrap();
+                            written.insert(index, Ok(last_msg_id));
+                        }
                     },
                     100,
                 );
                 pending_write_queue.read_completed(&r);
-                if r.messages_read > 0 {
-                    loop {
-                        match receiver.peek() {
-                            Some(value) => {
-                                if value.position_start <= r.start {
-                                    match receiver.poll() {
-                                        Some(value) => {
-                                            let message = AddMessageCommit::new(
-                                                write_out_pos,
-                                                write_out_file,
-                                                value.complete,
-                                            );
-                                            if !commit_writer.offer(message) {
-                                                thread::sleep(Duration::from_millis(1));
-                                            }
-                                        }
-                                        None => {}
-                                    }
-                                }
-                            }
-                            None => break,
+                while let Some(value) = receiver.poll() {
+                    waiting.push(value);
+                }
+                let mut i = 0;
+                while i < waiting.len() {
+                    match written.remove(&waiting[i].position_start) {
+                        Some(Ok(message_id)) => {
+                            let value = waiting.swap_remove(i);
+                            latency.appended(message_id, value.queued_ns);
+                            matched.push(AddMessageCommit::new(
+                                message_id,
+                                value.complete,
+                                value.reserved,
+                                value.queued_ns,
+                            ));
                         }
+                        Some(Err(e)) => {
+                            waiting.swap_remove(i).complete(Err(e));
+                        }
+                        None => i += 1,
                     }
-                } else {
+                }
+                if !matched.is_empty() || !overflow.is_empty() {
+                    matched.sort_by_key(|m| m.message_id);
+                    let mut handing_off = std::mem::take(&mut overflow);
+                    handing_off.append(&mut matched);
+                    for commit in handing_off {
+                        if let Err(commit) = commit_queue.offer(commit) {
+                            overflow.push(commit);
+                        }
+                    }
+                }
+                if r.messages_read == 0 {
                     thread::sleep(Duration::from_millis(1));
+                } else {
+                    commit_wait.signal();
                 }
             }
         }
     })
+    .unwrap()
 }
 
 /// Starts the commit thread.
@@ -1709,19 +4855,43 @@ fn write_thread_single(
 /// `file_prefix` - The file prefix.
 /// `commit_file_size` - The size of the commit file size.
 /// `max_message` - The current maximum message that has been committed.
+/// `written_message_id` - The id of the last message that has been completely written.
 /// `collection` - The collection of the files.
-/// `pending_commit_queue` - The current pending commit queue.
+/// `pending_commits` - The futures waiting for their message to be committed.  Only this thread
+/// has them.
+/// `clock` - The clock to get the commit times from.
+/// `commit_wait` - How to wait when there are no new messages to commit.
+/// `events` - Where to publish the terms committed.
+/// `commit_signal` - Wakes the readers waiting for the messages to be committed.
+/// `group_commit` - Batches the messages into one term.  `None` commits them as they are written.
+/// `flush_policy` - When the event files are flushed.  `None` flushes on every commit with a group
+/// commit and otherwise leaves it to the operating system.
+/// `flush_count` - Counts the flushes of the event files.
+/// `dirty` - Where the writer records the bytes written so only they are flushed.
+/// `thread` - The settings for the thread.
 /// # Returns
 /// The join handler to indicate when the thread has stopped.
+#[allow(clippy::too_many_arguments)]
 fn commit_thread_single(
     stop: Arc<AtomicU8>,
     file_storage_directory: String,
     file_prefix: String,
     commit_file_size: usize,
     max_message: Arc<AtomicU64>,
+    written_message_id: Arc<AtomicU64>,
     collection: Arc<FileCollection>,
+    mut pending_commits: PendingCommits,
+    clock: Arc<dyn Clock>,
+    commit_wait: Arc<dyn WaitStrategy>,
+    events: Arc<StoreEvents>,
+    commit_signal: Arc<CommitSignal>,
+    group_commit: Option<GroupCommit>,
+    flush_policy: Option<FlushPolicy>,
+    flush_count: Arc<AtomicU64>,
+    dirty: Arc<DirtyRanges>,
+    thread: ThreadConfig,
 ) -> JoinHandle<u32> {
-    thread::spawn(move || {
+    spawn_configured(thread, move || {
         let (commit_term, max_commit_term) = match find_last_commit_pos(&collection.commit_files) {
             LastCommitPos::NoCommits => {
                 let file_id = 1;
@@ -1732,7 +4902,7 @@ fn commit_thread_single(
                     .create_new(true)
                     .open(&file_name);
                 let map = unsafe {
-                    MemoryMappedInt::new(
+                    create_commit_file(
                         &file_name,
                         next_pos(commit_file_size, COMMIT_SIZE as usize),
                     )
@@ -1754,7 +4924,7 @@ fn commit_thread_single(
                     .create(false)
                     .open(&path)
                     .unwrap();
-                let map = unsafe { MemoryMappedInt::open(file).unwrap() };
+                let map = unsafe { open_commit_file(file).unwrap() };
                 max_message.store(max_message_id, atomic::Ordering::Relaxed);
                 // Get the current term we need to find.
                 let term = TermFile::new(map, start_term_id, file_id);
@@ -1774,12 +4944,12 @@ fn commit_thread_single(
                     .create(false)
                     .open(&path)
                     .unwrap();
-                let map = unsafe { MemoryMappedInt::open(file).unwrap() };
+                let map = unsafe { open_commit_file(file).unwrap() };
                 (TermFile::new(map, term_start, file_id), last_term)
             }
             LastTermPos::NoTerms => {
                 let path = create_commit_name(&file_storage_directory, &file_prefix, &1);
-                let map = unsafe { MemoryMappedInt::new(&path, commit_file_size).unwrap() };
+                let map = unsafe { create_commit_file(&path, commit_file_size).unwrap() };
                 (TermFile::new(map, 1, 1), 0)
             }
         };
@@ -1787,14 +4957,9 @@ fn commit_thread_single(
             panic!("Terms must match.  This node must have run in cluster node.")
         } else {
             let current_term = max_commit_term;
-            let (message_file, read_pos, read_file_id) = if current_term == 0 {
+            let (read_pos, read_file_id) = if current_term == 0 {
                 // New file so we don't need to do much.
-                let path = create_event_name(&file_storage_directory, &file_prefix, &1);
-                (
-                    unsafe { MessageFileStore::open_readonly(&path).unwrap() },
-                    0,
-                    1,
-                )
+                (0, 1)
             } else {
                 let term_pos = match commit_term.calculate_pos(&max_commit_term) {
                     TermPosResult::Pos(pos) => pos,
@@ -1805,133 +4970,82 @@ fn commit_thread_single(
                 let msg_file_id = commit_term.buffer.file_id(term_pos);
                 let position = commit_term.buffer.file_position_offset(term_pos) as usize
                     + commit_term.buffer.length_of_commit(term_pos) as usize;
-                let path = create_event_name(&file_storage_directory, &file_prefix, &msg_file_id);
-                (
-                    unsafe { MessageFileStore::open_readonly(&path).unwrap() },
-                    position,
-                    msg_file_id,
-                )
+                (position, msg_file_id)
             };
-            let mut message_file = message_file;
-            let mut read_pos = read_pos;
-            let mut read_file_id = read_file_id;
-            let mut term_file = commit_term;
-            let mut current_term = max_commit_term;
+            let mut commit = PersistedCommitStream::new(
+                commit_term,
+                current_term,
+                read_file_id,
+                read_pos,
+                file_storage_directory,
+                file_prefix,
+                commit_file_size,
+                collection.backend().clone(),
+                max_message,
+                written_message_id,
+                clock,
+            )
+            .unwrap()
+            .with_commit_files(collection.commit_files.clone())
+            .with_dirty_ranges(dirty);
+            if let Some(group_commit) = group_commit {
+                commit = commit.with_group_commit(group_commit);
+            }
+            let flush_policy = flush_policy.unwrap_or(if group_commit.is_some() {
+                FlushPolicy::EveryCommit
+            } else {
+                FlushPolicy::Os
+            });
+            commit = commit.with_flush_policy(flush_policy, flush_count);
+            let mut attempt = 0;
             loop {
                 if stop.load(atomic::Ordering::Acquire) > 0 {
-                    break 0;
+                    if let Err(e) = commit.flush() {
+                        log::error!("Unable to flush the commit file: {}", e);
+                    }
+                    // Completes the futures handed off for the messages already committed.
+                    pending_commits.drain();
+                    break pending_commits.fail_all() as u32;
                 } else {
-                    match message_file.read_block(read_pos, std::u64::MAX, 0x10000) {
-                        Ok(result) => {
-                            let new_term = current_term + 1;
-                            let current_time = SystemTime::now();
-                            let since_epoch =
-                                current_time.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
-                            let term = TermCommit {
-                                file_position_offset: read_pos as u64,
-                                file_id: read_file_id,
-                                term_id: new_term,
-                                length: result.bytes.len() as u32,
-                                version: 1,
-                                type_id: 1,
-                                leader_id: 1,
-                                server_id: 1,
-                                committed: 1,
-                                file_max_message_id: result.message_id_end,
-                                timestamp: since_epoch,
-                                committed_timestamp: since_epoch,
-                            };
-                            match term_file.calculate_pos(&new_term) {
-                                TermPosResult::Pos(p) => {
-                                    term_file.buffer.save_term(p, &term);
-                                    max_message
-                                        .store(result.message_id_end, atomic::Ordering::Relaxed);
-                                    read_pos = result.next_pos;
-                                    current_term = new_term;
-                                }
-                                TermPosResult::Overflow => {
-                                    let next_file_id = term_file.file_id + 1;
-                                    let path = create_commit_name(
-                                        &file_storage_directory,
-                                        &file_prefix,
-                                        &next_file_id,
-                                    );
-                                    let buffer = unsafe {
-                                        MemoryMappedInt::new(&path, commit_file_size).unwrap()
-                                    };
-                                    term_file = TermFile::new(buffer, new_term, next_file_id);
-                                    read_file_id = next_file_id;
-                                }
-                                TermPosResult::Underflow => {
-                                    panic!("We should never underflow when writing new terms!");
-                                }
-                            }
+                    pending_commits.drain();
+                    match commit.step() {
+                        Ok(CommitStep::Committed {
+                            term_id,
+                            max_message_id,
+                        }) => {
+                            attempt = 0;
+                            pending_commits.complete_to(max_message_id);
+                            commit_signal.notify();
+                            events.emit(StoreEvent::TermCommitted { term_id });
                         }
-                        Err(e) => {
-                            match e {
-                                file::Error::Full => {
-                                    // Next file
-                                    let next_file = read_file_id + 1;
-                                    let file_path = create_event_name(
-                                        &file_storage_directory,
-                                        &file_prefix,
-                                        &next_file,
-                                    );
-                                    match unsafe { MessageFileStore::open_readonly(&file_path) } {
-                                        Ok(buffer) => {
-                                            message_file = buffer;
-                                        }
-                                        Err(e) => {
-                                            log::error!("{}", e);
-                                            // Spin
-                                            thread::sleep(Duration::from_millis(10));
-                                        }
-                                    }
-                                }
-                                file::Error::FileError(e) => {
-                                    log::error!("{}", e);
-                                    // Spin
-                                    thread::sleep(Duration::from_millis(100));
-                                }
-                                file::Error::NoMessage => {
-                                    thread::sleep(Duration::from_millis(2));
-                                }
-                                file::Error::PositionOutOfRange(_) => {
-                                    // Next file
-                                    let next_file = read_file_id + 1;
-                                    let file_path = create_event_name(
-                                        &file_storage_directory,
-                                        &file_prefix,
-                                        &next_file,
-                                    );
-                                    match unsafe { MessageFileStore::open_readonly(&file_path) } {
-                                        Ok(buffer) => {
-                                            message_file = buffer;
-                                        }
-                                        Err(_) => {
-                                            // Spin
-                                            thread::sleep(Duration::from_millis(10));
-                                        }
-                                    }
-                                }
-                                file::Error::NotEnoughSpace {
-                                    position: _,
-                                    capacity: _,
-                                    remaining: _,
-                                    message_size: _,
-                                } => {
-                                    panic!("We are reading in a message this should never happen!");
-                                }
-                                file::Error::AlreadyExists | file::Error::InvalidFile => {
-                                    panic!("Unable to get the file!");
-                                }
+                        Ok(CommitStep::NextCommitFile(_)) | Ok(CommitStep::NextEventFile(_)) => {
+                            attempt = 0;
+                        }
+                        Ok(CommitStep::Idle) => match commit.batch_remaining() {
+                            // Checks back in time for the timer of the waiting batch.
+                            Some(remaining) => thread::sleep(remaining.min(DEFAULT_COMMIT_WAIT)),
+                            None => {
+                                commit_wait.wait(attempt);
+                                attempt = attempt.saturating_add(1);
                             }
+                        },
+                        Err(file::Error::FileError(e)) => {
+                            log::error!("{}", e);
+                            // Spin
+                            thread::sleep(Duration::from_millis(100));
+                        }
+                        Err(file::Error::NotEnoughSpace { .. }) => {
+                            panic!("We are reading in a message this should never happen!");
+                        }
+                        Err(_) => {
+                            panic!("Unable to get the file!");
                         }
                     }
                 }
             }
         }
     })
+    .unwrap()
 }
 
 /// Starts the process thread.  Currently doesn't support a snapshot.
@@ -1941,8 +5055,11 @@ fn commit_thread_single(
 /// `file_collection` - The file collection.
 /// `message_processor` - The message processor to call.
 /// `max_message_id` - The maximum message we should process.
+/// `latency` - Records how long the messages took to be handled.
+/// `thread` - The settings for the thread.
 /// # Returns
 /// The join handler for when the thread quits.
+#[allow(clippy::too_many_arguments)]
 fn read_thread<FRead>(
     stop: Arc<AtomicU8>,
     file_storage_directory: String,
@@ -1950,15 +5067,24 @@ fn read_thread<FRead>(
     file_collection: Arc<FileCollection>,
     message_processor: FRead,
     max_message_id: Arc<AtomicU64>,
-    pending_commit_queue: SpscQueueReceiveWrap<AddMessageCommit>,
+    latency: Arc<StoreLatency>,
+    thread: ThreadConfig,
 ) -> JoinHandle<u32>
 where
     FRead: MessageProcessor + 'static,
 {
-    thread::spawn(move || {
+    spawn_configured(thread, move || {
         println!("Starting up reading thread!");
         let mut message_processor = message_processor;
-        let mut read_file_id = 1;
+        // The files in front could have been removed by the retention.
+        let mut read_file_id = file_collection
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|f| !f.removed)
+            .map(|f| f.file_id)
+            .unwrap_or(1);
         let read_file_path =
             create_event_name(&file_storage_directory, &file_prefix, &read_file_id);
         let mut read = unsafe { MessageFileStore::open_readonly(&read_file_path).unwrap() };
@@ -1972,26 +5098,13 @@ where
                         if result.msg_type_id() > 0 {
                             if result.message_id() <= max_message_id.load(atomic::Ordering::Relaxed)
                             {
-                                message_processor.handle(&result);
-                                loop {
-                                    let top = pending_commit_queue.peek();
-                                    match top {
-                                        Some(t) => {
-                                            if t.is_processed(&read_pos, &read_file_id) {
-                                                match pending_commit_queue.poll() {
-                                                    Some(f) => {
-                                                        f.complete.send(Ok(())).unwrap_or_default();
-                                                    }
-                                                    None => {
-                                                        panic!("Something took the value from the peek.");
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        None => break,
-                                    }
-                                }
+                                let message_id = result.message_id();
                                 read_pos = result.next_pos();
+                                // Nothing to hand the error to so it is logged and skipped.
+                                if let Err(e) = message_processor.handle(result) {
+                                    log::error!("Message {}: {}", message_id, e);
+                                }
+                                latency.handled(message_id);
                             } else {
                                 thread::sleep(Duration::from_millis(1));
                             }
@@ -2007,7 +5120,13 @@ where
                             }
                             file::Error::Full => {
                                 // TODO go to next file
-                                let next_file_id = read_file_id + 1;
+                                let next_file_id = next_event_file_id(
+                                    file_collection.backend.as_ref(),
+                                    &file_storage_directory,
+                                    &file_prefix,
+                                    read_file_id,
+                                )
+                                .unwrap_or(read_file_id + 1);
                                 let next_path = create_event_name(
                                     &file_storage_directory,
                                     &file_prefix,
@@ -2032,7 +5151,13 @@ where
                             }
                             file::Error::PositionOutOfRange(_) => {
                                 // TODO go to next file
-                                let next_file_id = read_file_id + 1;
+                                let next_file_id = next_event_file_id(
+                                    file_collection.backend.as_ref(),
+                                    &file_storage_directory,
+                                    &file_prefix,
+                                    read_file_id,
+                                )
+                                .unwrap_or(read_file_id + 1);
                                 let next_path = create_event_name(
                                     &file_storage_directory,
                                     &file_prefix,
@@ -2054,7 +5179,16 @@ where
                                 log::error!("{}", e);
                                 thread::sleep(Duration::from_millis(2));
                             }
-                            file::Error::InvalidFile | file::Error::AlreadyExists => {
+                            file::Error::InvalidFile
+                            | file::Error::AlreadyExists
+                            | file::Error::Stopped
+                            | file::Error::ReadOnly
+                            | file::Error::SequenceGap { .. }
+                            | file::Error::FileRemoved(_)
+                            | file::Error::InvalidHeader(_)
+                            | file::Error::ChecksumMismatch { .. }
+                            | file::Error::NotRetained { .. }
+                            | file::Error::ProcessFailed(_) => {
                                 // do nothing
                             }
                         }
@@ -2063,6 +5197,7 @@ where
             }
         }
     })
+    .unwrap()
 }
 
 pub fn startup_single_node<FRead>(
@@ -2074,19 +5209,195 @@ pub fn startup_single_node<FRead>(
     incoming_buffer_size: usize,
     incoming_queue_size: usize,
 ) -> PersistedMessageFile
+where
+    FRead: MessageProcessor + 'static,
+{
+    startup_single_node_with_clock(
+        file_storage_directory,
+        file_prefix,
+        max_file_size,
+        commit_file_size,
+        message_processor,
+        incoming_buffer_size,
+        incoming_queue_size,
+        system_clock(),
+    )
+}
+
+/// Starts a single node using a clock for the commit times.
+/// # Arguments
+/// `clock` - The clock to get the time from.
+#[allow(clippy::too_many_arguments)]
+pub fn startup_single_node_with_clock<FRead>(
+    file_storage_directory: String,
+    file_prefix: String,
+    max_file_size: usize,
+    commit_file_size: usize,
+    message_processor: FRead,
+    incoming_buffer_size: usize,
+    incoming_queue_size: usize,
+    clock: Arc<dyn Clock>,
+) -> PersistedMessageFile
+where
+    FRead: MessageProcessor + 'static,
+{
+    startup_single_node_with_wait(
+        file_storage_directory,
+        file_prefix,
+        max_file_size,
+        commit_file_size,
+        message_processor,
+        incoming_buffer_size,
+        incoming_queue_size,
+        clock,
+        default_commit_wait(),
+    )
+}
+
+/// How long the commit thread sleeps by default when there is nothing to commit.
+const DEFAULT_COMMIT_WAIT: Duration = Duration::from_millis(2);
+
+/// The strategy the commit thread uses if one isn't specified.
+pub fn default_commit_wait() -> Arc<dyn WaitStrategy> {
+    Arc::new(SleepBackoff(DEFAULT_COMMIT_WAIT))
+}
+
+/// Starts a single node using a wait strategy for the commit thread.  The strategy is signaled
+/// each time messages are written so a parking strategy wakes up to commit them.
+/// # Arguments
+/// `clock` - The clock to get the time from.
+/// `commit_wait` - How the commit thread waits when there is nothing to commit.
+#[allow(clippy::too_many_arguments)]
+pub fn startup_single_node_with_wait<FRead>(
+    file_storage_directory: String,
+    file_prefix: String,
+    max_file_size: usize,
+    commit_file_size: usize,
+    message_processor: FRead,
+    incoming_buffer_size: usize,
+    incoming_queue_size: usize,
+    clock: Arc<dyn Clock>,
+    commit_wait: Arc<dyn WaitStrategy>,
+) -> PersistedMessageFile
+where
+    FRead: MessageProcessor + 'static,
+{
+    startup_single_node_with_threads(
+        file_storage_directory,
+        file_prefix,
+        max_file_size,
+        commit_file_size,
+        message_processor,
+        incoming_buffer_size,
+        incoming_queue_size,
+        clock,
+        commit_wait,
+        StoreThreads::default(),
+    )
+}
+
+/// The settings for the threads a store starts.  The threads that don't have settings are only
+/// named.
+#[derive(Debug, Clone, Default)]
+pub struct StoreThreads {
+    /// The thread that writes the messages to the event files.
+    pub writer: Option<ThreadConfig>,
+    /// The thread that commits the written messages.
+    pub commit: Option<ThreadConfig>,
+    /// The thread that passes the committed messages to the processor.
+    pub reader: Option<ThreadConfig>,
+}
+
+/// Starts a single node with the settings for its threads.
+/// # Arguments
+/// `clock` - The clock to get the time from.
+/// `commit_wait` - How the commit thread waits when there is nothing to commit.
+/// `threads` - The settings for the writer, commit and reader threads.
+#[allow(clippy::too_many_arguments)]
+pub fn startup_single_node_with_threads<FRead>(
+    file_storage_directory: String,
+    file_prefix: String,
+    max_file_size: usize,
+    commit_file_size: usize,
+    message_processor: FRead,
+    incoming_buffer_size: usize,
+    incoming_queue_size: usize,
+    clock: Arc<dyn Clock>,
+    commit_wait: Arc<dyn WaitStrategy>,
+    threads: StoreThreads,
+) -> PersistedMessageFile
+where
+    FRead: MessageProcessor + 'static,
+{
+    startup_single_node_with_options(
+        file_storage_directory,
+        file_prefix,
+        max_file_size,
+        commit_file_size,
+        message_processor,
+        incoming_buffer_size,
+        incoming_queue_size,
+        clock,
+        commit_wait,
+        threads,
+        verify::OpenOptions::default(),
+    )
+    .unwrap()
+}
+
+/// Starts a single node after checking the newest files.  The store refuses to start when the
+/// check finds a problem it isn't allowed to repair.
+/// # Arguments
+/// `clock` - The clock to get the time from.
+/// `commit_wait` - How the commit thread waits when there is nothing to commit.
+/// `threads` - The settings for the writer, commit and reader threads.
+/// `options` - How much of the newest files to check before taking writes.
+/// # Returns
+/// The store or the first bad position in the files.
+#[allow(clippy::too_many_arguments)]
+pub fn startup_single_node_with_options<FRead>(
+    file_storage_directory: String,
+    file_prefix: String,
+    max_file_size: usize,
+    commit_file_size: usize,
+    message_processor: FRead,
+    incoming_buffer_size: usize,
+    incoming_queue_size: usize,
+    clock: Arc<dyn Clock>,
+    commit_wait: Arc<dyn WaitStrategy>,
+    threads: StoreThreads,
+    options: verify::OpenOptions,
+) -> crate::Result<PersistedMessageFile>
 where
     FRead: MessageProcessor + 'static,
 {
     let store_path = Path::new(&file_storage_directory);
     if !store_path.exists() {
-        create_dir_all(&file_storage_directory).unwrap();
+        create_dir_all(&file_storage_directory).with_path(file_storage_directory.as_str())?;
     }
-    let collection = Arc::new(FileCollection::new(
-        file_storage_directory.clone(),
-        file_prefix.clone(),
+    snapshot::finish_install(&file_storage_directory, &file_prefix)
+        .with_path(file_storage_directory.as_str())?;
+    compact::finish_compact(&file_storage_directory, &file_prefix)
+        .with_path(file_storage_directory.as_str())?;
+    verify::verify_store(&FileBackend, &file_storage_directory, &file_prefix, &options)?;
+    let mut collection = load_current_files(&file_prefix, &file_storage_directory)?;
+    process_files(
+        &mut collection,
+        &file_prefix,
+        &file_storage_directory,
+        &max_file_size,
+        &commit_file_size,
+    )
+    .with_path(file_storage_directory.as_str())?;
+    let collection = Arc::new(collection);
+    // Load the last commit now so the committed messages can be read as soon as we start.
+    let max_message = Arc::new(AtomicU64::new(
+        match find_last_commit_pos(&collection.commit_files) {
+            LastCommitPos::LastCommit { max_message_id, .. } => max_message_id,
+            LastCommitPos::NoCommits => 0,
+        },
     ));
-    let max_message = Arc::new(AtomicU64::new(0));
-    let message_files = collection.message_files.lock().unwrap();
+    let mut message_files = collection.message_files.lock().unwrap();
     let writer = if message_files.len() > 0 {
         let file: &MessageFileInfo = message_files.get(message_files.len() - 1).unwrap();
         file.file_id
@@ -2101,20 +5412,53 @@ where
         .unwrap();
         1
     };
+    recover_torn_write(
+        &file_storage_directory,
+        &file_prefix,
+        writer,
+        &collection.commit_files,
+    )
+    .with_path(file_storage_directory.as_str())?;
+    let mut file_ids: Vec<u32> = message_files.iter().map(|f| f.file_id).collect();
+    if file_ids.last() != Some(&writer) {
+        file_ids.push(writer);
+    }
+    let writer = recover_chunked_message(&file_storage_directory, &file_prefix, &file_ids)
+        .with_path(file_storage_directory.as_str())?;
+    message_files.retain(|f| f.file_id <= writer);
+    drop(message_files);
+    let recovered = recover_tail(&file_storage_directory, &file_prefix, writer)
+        .with_path(file_storage_directory.as_str())?;
+    let written_message_id = Arc::new(AtomicU64::new(recovered.message_id));
     let (incoming_reader, incoming_writer) = create_many_to_one(incoming_buffer_size);
     let (queue_writer, queue_reader) = MpscQueueWrap::new(incoming_queue_size);
-    let (commit_writer, commit_reader) = SpscQueueSendWrap::new(incoming_queue_size);
+    let latency = Arc::new(StoreLatency::new(clock.clone()));
+    let commit_queue = Arc::new(CommitQueue::new(incoming_queue_size));
+    let pending_commits = PendingCommits::new(commit_queue.clone(), latency.clone());
     let stop = Arc::new(AtomicU8::new(0));
+    let positions = Arc::new(PositionIndex::new());
+    let events = Arc::new(StoreEvents::default());
+    let commit_signal = Arc::new(CommitSignal::default());
+    let flush_count = Arc::new(AtomicU64::new(0));
+    let dirty = Arc::new(DirtyRanges::default());
     let writer_join = Some(write_thread_single(
         stop.clone(),
         queue_reader,
         incoming_reader,
-        max_message.clone(),
+        written_message_id.clone(),
         max_file_size,
         file_storage_directory.clone(),
         file_prefix.clone(),
         writer,
-        commit_writer,
+        commit_queue.clone(),
+        commit_wait.clone(),
+        latency.clone(),
+        positions.clone(),
+        events.clone(),
+        collection.message_files.clone(),
+        options.index_interval,
+        dirty.clone(),
+        ThreadConfig::or_named(threads.writer, "a19-writer"),
     ));
     let commit_join = Some(commit_thread_single(
         stop.clone(),
@@ -2122,7 +5466,18 @@ where
         file_prefix.clone(),
         commit_file_size,
         max_message.clone(),
+        written_message_id.clone(),
         collection.clone(),
+        pending_commits,
+        clock.clone(),
+        commit_wait,
+        events.clone(),
+        commit_signal.clone(),
+        options.group_commit,
+        options.flush_policy,
+        flush_count.clone(),
+        dirty,
+        ThreadConfig::or_named(threads.commit, "a19-commit"),
     ));
     let reader_join = Some(read_thread(
         stop.clone(),
@@ -2131,9 +5486,10 @@ where
         collection.clone(),
         message_processor,
         max_message.clone(),
-        commit_reader,
+        latency.clone(),
+        ThreadConfig::or_named(threads.reader, "a19-reader"),
     ));
-    PersistedMessageFile {
+    Ok(PersistedMessageFile {
         max_file_size,
         commit_join,
         writer_join,
@@ -2142,12 +5498,82 @@ where
         incoming_writer,
         incoming_queue_writer: queue_writer,
         max_message_id: max_message,
+        written_message_id,
         file_storage_directory: file_storage_directory.clone(),
         file_prefix: file_prefix.clone(),
+        event_readers: Mutex::new(HashMap::new()),
+        backlog: Arc::new(Backlog::new()),
+        flush_writer: Mutex::new(None),
+        flusher: Flusher::new(),
+        followed_files: None,
+        message_files: collection.message_files.clone(),
+        commit_files: collection.commit_files.clone(),
+        clock,
+        latency,
+        positions,
+        events,
+        commit_queue,
+        commit_signal,
+        flush_count,
+        commit_flush_file: Mutex::new(1),
+        recovered,
+        closing: AtomicBool::new(false),
+        closed: false,
+    })
+}
+
+impl Drop for PersistedMessageFile {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if self.writer_join.is_some() || self.commit_join.is_some() {
+            log::warn!(
+                "The store {} was dropped without being closed.",
+                self.file_storage_directory
+            );
+        }
+        if let Err(e) = self.close_inner(ClosePolicy::default()) {
+            log::warn!(
+                "Unable to close the store {}: {}",
+                self.file_storage_directory,
+                e
+            );
+        }
     }
 }
 
-/// Crates a new term file.
+/// Where a committed term is in the event files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TermLocation {
+    /// The id of the term.
+    pub term_id: u64,
+    /// The id of the event file the term starts in.
+    pub file_id: u32,
+    /// The position in the event file the term starts at.
+    pub position: u64,
+    /// The number of bytes in the term.
+    pub length: u32,
+    /// The id of the last message in the term.
+    pub max_message_id: u64,
+    /// When the term was committed in milliseconds since the epoch.
+    pub committed_at: u64,
+}
+
+/// Where the log picks up when a store is opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecoveredPosition {
+    /// The id of the event file the next message is written to.
+    pub file_id: u32,
+    /// The position in the event file the next message is written at.
+    pub position: usize,
+    /// The id of the last message that was kept.
+    pub message_id: u64,
+}
+
+/// Crates a new term file.
 pub(crate) fn create_term_file(
     file_storage_directory: &str,
     file_prefix: &str,
@@ -2156,17 +5582,106 @@ pub(crate) fn create_term_file(
     file_size: usize,
 ) -> TermFile {
     let path = create_commit_name(file_storage_directory, file_prefix, &file_id);
-    let buffer = unsafe { MemoryMappedInt::new(&path, file_size) }.unwrap();
+    let buffer = unsafe { create_commit_file(&path, file_size) }.unwrap();
     TermFile::new(buffer, term_start, file_id)
 }
 
 impl PersistedMessageFile {
-    /// Tells the processes to stop.
+    /// Starts a store with the default settings for everything else.  The files already in the
+    /// directory are loaded and the writer continues after the last message in them, otherwise the
+    /// first files are created.  Use `PersistedMessageFileBuilder` to change the rest of them.
+    /// # Arguments
+    /// `file_storage_directory` - The directory to keep the files in.
+    /// `file_prefix` - The prefix of the file names.
+    /// `max_file_size` - The size of an event file before it rolls over.
+    /// `commit_file_size` - The size of a commit file.
+    /// `message_processor` - Handles the messages as they are committed.
+    /// # Returns
+    /// The store or an error if the directory can't be created or a file is too small to be valid.
+    pub fn new<FRead>(
+        file_storage_directory: &str,
+        file_prefix: &str,
+        max_file_size: usize,
+        commit_file_size: usize,
+        message_processor: FRead,
+    ) -> crate::Result<Self>
+    where
+        FRead: MessageProcessor + 'static,
+    {
+        builder::PersistedMessageFileBuilder::new(file_storage_directory, file_prefix)
+            .max_file_size(max_file_size)
+            .commit_file_size(commit_file_size)
+            .build(message_processor)
+    }
+
+    /// Tells the processes to stop.  The writes that haven't been committed are failed.  Use
+    /// `close` to wait for them to be committed.
     pub fn stop(&mut self) {
+        self.join_threads();
+    }
+
+    /// Closes the store.  Stops accepting writes, waits for the writes already accepted to be
+    /// committed, stops the threads and flushes the files.
+    /// # Returns
+    /// What was flushed and what was abandoned.
+    pub fn close(self) -> crate::Result<CloseReport> {
+        self.close_with(ClosePolicy::default())
+    }
+
+    /// Closes the store.
+    /// # Arguments
+    /// `policy` - What to do with the writes that haven't been committed.
+    /// # Returns
+    /// What was flushed and what was abandoned.
+    pub fn close_with(mut self, policy: ClosePolicy) -> crate::Result<CloseReport> {
+        self.closed = true;
+        self.close_inner(policy)
+    }
+
+    fn close_inner(&mut self, policy: ClosePolicy) -> crate::Result<CloseReport> {
+        self.closing.store(true, atomic::Ordering::Release);
+        let mut timed_out = false;
+        if let ClosePolicy::Commit(timeout) = policy {
+            let started = Instant::now();
+            while self.commit_join.is_some() && self.backlog.status().pending > 0 {
+                if started.elapsed() >= timeout {
+                    timed_out = true;
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        let abandoned_writes = self.join_threads();
+        let flushed_message_id = if self.followed_files.is_some() {
+            // The files belong to the writer.
+            self.max_message_id()
+        } else {
+            self.flush()?
+        };
+        Ok(CloseReport {
+            flushed_message_id,
+            written_message_id: self.written_message_id(),
+            abandoned_writes,
+            timed_out,
+        })
+    }
+
+    /// Stops the threads and waits for them to finish.
+    /// # Returns
+    /// The number of writes that were failed.
+    fn join_threads(&mut self) -> usize {
         self.stop.store(1, atomic::Ordering::Release);
-        self.writer_join.take().map(JoinHandle::join);
-        self.reader_join.take().map(JoinHandle::join);
-        self.commit_join.take().map(JoinHandle::join);
+        // Lets the tail readers see we are stopping.
+        self.commit_signal.notify();
+        vec![
+            self.writer_join.take(),
+            self.reader_join.take(),
+            self.commit_join.take(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|join| join.join().unwrap_or(0) as usize)
+        .sum()
     }
 
     /// Writers a message to the buffer.
@@ -2174,20 +5689,686 @@ impl PersistedMessageFile {
     /// `msg_type_id` - The type of the message we are writing.
     /// `bytes` - The bytes to write the buffer.
     /// # Returns
-    /// The future that gets completed.
-    pub fn write(&self, msg_type_id: i32, bytes: &[u8]) -> QueueFuture<file::Result<()>> {
+    /// The future that gets completed with the id of the message.
+    pub fn write(&self, msg_type_id: i32, bytes: &[u8]) -> QueueFuture<crate::Result<u64>> {
+        let (sender, receiver) = oneshot::channel();
+        self.queue_write(msg_type_id, bytes, WriteComplete::Write(sender));
+        receiver
+    }
+
+    /// Writes a message with the user metadata to the buffer.
+    /// # Arguments
+    /// `msg_type_id` - The type of the message we are writing.
+    /// `headers` - The user metadata of the message.
+    /// `bytes` - The bytes to write the buffer.
+    /// # Returns
+    /// The future that gets completed with the id of the message.
+    pub fn write_with_headers(
+        &self,
+        msg_type_id: i32,
+        headers: &MessageHeaders,
+        bytes: &[u8],
+    ) -> QueueFuture<crate::Result<u64>> {
+        if headers.is_empty() {
+            return self.write(msg_type_id, bytes);
+        }
+        let mut incoming = Vec::with_capacity(INCOMING_HEADERS_SIZE + bytes.len());
+        incoming.extend_from_slice(&msg_type_id.to_be_bytes());
+        incoming.extend_from_slice(&headers.to_bytes());
+        incoming.extend_from_slice(bytes);
+        let (sender, receiver) = oneshot::channel();
+        self.queue_write(INCOMING_HEADERS_TYPE, &incoming, WriteComplete::Write(sender));
+        receiver
+    }
+
+    /// The id of the last message that has been committed.
+    pub fn max_message_id(&self) -> u64 {
+        self.max_message_id.load(atomic::Ordering::Acquire)
+    }
+
+    /// Where the log picked up when the store was opened.  Anything that was only partly written
+    /// past it before a crash has been cleared.
+    pub fn recovered_position(&self) -> RecoveredPosition {
+        self.recovered
+    }
+
+    /// The clock used for the commit times.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// How long the writes have taken to be committed and handled by the processor since the
+    /// store was started.
+    pub fn latency(&self) -> LatencySnapshot {
+        self.latency.snapshot()
+    }
+
+    /// How long the writes have taken to be committed and handled by the processor since the last
+    /// reset.  Starts recording over.
+    pub fn latency_and_reset(&self) -> LatencySnapshot {
+        self.latency.snapshot_and_reset()
+    }
+
+    /// Publishes the lifecycle events of the store to a bus.  The files being rolled and the terms
+    /// committed are published from the store's threads.
+    /// # Arguments
+    /// `bus` - The bus to publish to.
+    pub fn set_event_bus(&self, bus: EventBus<StoreEvent>) {
+        self.events.set_bus(bus);
+    }
+
+    /// The id of the last message that has been written.  It is committed shortly after.
+    pub fn written_message_id(&self) -> u64 {
+        self.written_message_id.load(atomic::Ordering::Acquire)
+    }
+
+    /// The writes that haven't been committed yet.
+    pub fn backlog(&self) -> BacklogStatus {
+        self.backlog.status()
+    }
+
+    /// The number of futures waiting for their message to be committed.
+    pub fn pending_commits(&self) -> u64 {
+        self.commit_queue.pending()
+    }
+
+    /// Sets when the writers should wait for the backlog to drain.
+    /// # Arguments
+    /// `watermarks` - The uncommitted bytes to pause and resume at.  `None` never waits.
+    pub fn set_watermarks(&self, watermarks: Option<Watermarks>) {
+        self.backlog.set_watermarks(watermarks);
+    }
+
+    /// Waits until the backlog is below the watermarks.
+    /// # Returns
+    /// The future that completes once there is room to write.
+    pub fn capacity(&self) -> Capacity<'_> {
+        self.backlog.capacity()
+    }
+
+    /// Writes a message if the backlog isn't over the watermarks.
+    /// # Arguments
+    /// `msg_type_id` - The type of the message we are writing.
+    /// `bytes` - The bytes to write the buffer.
+    /// # Returns
+    /// The future that gets completed with the id of the message or the current backlog if the
+    /// writer needs to wait.
+    pub fn try_write(
+        &self,
+        msg_type_id: i32,
+        bytes: &[u8],
+    ) -> std::result::Result<QueueFuture<crate::Result<u64>>, WouldBlock> {
+        if self.backlog.is_paused() {
+            Err(WouldBlock {
+                lag: self.backlog.status(),
+            })
+        } else {
+            Ok(self.write(msg_type_id, bytes))
+        }
+    }
+
+    /// Writes the messages in a transaction.  All of the messages are committed in the same term.
+    /// # Arguments
+    /// `batch` - The messages in the transaction.
+    /// # Returns
+    /// The future that gets completed with the id of the last message in the transaction.
+    pub fn write_transaction(&self, batch: &TransactionBatch) -> QueueFuture<crate::Result<u64>> {
         let (sender, receiver) = oneshot::channel();
+        self.queue_write(INCOMING_TRANSACTION_TYPE, batch.bytes(), WriteComplete::Write(sender));
+        receiver
+    }
+
+    /// Flushes the event files to disk.  Any file that has been rolled over to since the last
+    /// flush is flushed as well.
+    /// # Returns
+    /// The id of the last message committed before the flush.
+    pub fn flush(&self) -> crate::Result<u64> {
+        let committed = self.max_message_id();
+        let mut flush_writer = self.flush_writer.lock().unwrap();
+        let mut file_id = flush_writer.as_ref().map(|(id, _)| *id).unwrap_or(1);
+        loop {
+            if flush_writer.is_none() {
+                let path = create_event_name(&self.file_storage_directory, &self.file_prefix, &file_id);
+                if !Path::new(&path).exists() {
+                    break;
+                }
+                let writer = unsafe { MessageFileStore::open_write(&path, self.max_file_size) }
+                    .with_path(&path)?;
+                *flush_writer = Some((file_id, writer));
+            }
+            if let Some((_, writer)) = flush_writer.as_ref() {
+                self.flusher
+                    .flush(writer)
+                    .map_err(|e| PersistError::from(e).with_id(file_id as u64))?;
+                self.flush_count.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            let next = create_event_name(&self.file_storage_directory, &self.file_prefix, &(file_id + 1));
+            if Path::new(&next).exists() {
+                file_id += 1;
+                *flush_writer = None;
+            } else {
+                break;
+            }
+        }
+        Ok(committed)
+    }
+
+    /// Flushes the event files and then the commit files no matter the flush policy.  Everything
+    /// committed before the call is on disk once it returns.
+    /// # Returns
+    /// The id of the last message committed before the flush.
+    pub fn force_flush(&self) -> crate::Result<u64> {
+        let committed = self.flush()?;
+        let mut file_id = self.commit_flush_file.lock().unwrap();
+        loop {
+            let path =
+                create_commit_name(&self.file_storage_directory, &self.file_prefix, &file_id);
+            let file = match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(file) => file,
+                Err(_) => break,
+            };
+            let buffer = unsafe { open_commit_file(file) }.with_path(&path)?;
+            buffer.flush().with_path(&path)?;
+            let next = create_commit_name(
+                &self.file_storage_directory,
+                &self.file_prefix,
+                &(*file_id + 1),
+            );
+            if Path::new(&next).exists() {
+                *file_id += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(committed)
+    }
+
+    /// The number of times the event files have been flushed to disk.  Counts the explicit flushes
+    /// and the flushes done by the flush policy.
+    pub fn flush_count(&self) -> u64 {
+        self.flush_count.load(atomic::Ordering::Relaxed)
+    }
+
+    /// The maximum size of an event file.
+    pub fn max_file_size(&self) -> usize {
+        self.max_file_size
+    }
+
+    /// The id of the oldest commit file the retention hasn't removed.
+    fn first_commit_file_id(&self) -> u32 {
+        self.commit_files
+            .lock()
+            .unwrap()
+            .first()
+            .map(|f| f.file_id)
+            .unwrap_or(1)
+    }
+
+    /// Removes the files the retention policy doesn't keep.  See `FileCollection::apply_retention`
+    /// for which files can be removed.
+    /// # Arguments
+    /// `policy` - Which files to keep.
+    /// # Returns
+    /// The ids of the files that were removed.
+    pub fn apply_retention(&self, policy: RetentionPolicy) -> crate::Result<RefreshDelta> {
+        if self.followed_files.is_some() {
+            return Err(PersistError::from(file::Error::ReadOnly));
+        }
+        {
+            // The cached readers would keep the files open.
+            let newest = self.message_files.lock().unwrap().last().map(|f| f.file_id);
+            let mut readers = self.event_readers.lock().unwrap();
+            readers.retain(|file_id, _| Some(*file_id) == newest);
+        }
+        let collection = FileCollection {
+            commit_files: self.commit_files.clone(),
+            message_files: self.message_files.clone(),
+            file_storage_directory: self.file_storage_directory.clone(),
+            file_prefix: self.file_prefix.clone(),
+            backend: Arc::new(FileBackend),
+        };
+        let delta = collection.apply_retention(policy, self.clock.now_ms())?;
+        let stale: Vec<u64> = self
+            .positions
+            .iter()
+            .filter(|(_, (file_id, _))| delta.removed_message_files.contains(file_id))
+            .map(|(id, _)| id)
+            .collect();
+        for id in stale {
+            self.positions.remove(&id);
+        }
+        Ok(delta)
+    }
+
+    /// Finds the term a message was committed in.  The terms are in order so the commit files are
+    /// binary searched instead of reading through the messages.
+    /// # Arguments
+    /// `message_id` - The id of the message to look for.
+    /// # Returns
+    /// The location of the term or `None` if the message hasn't been committed.
+    pub fn find_term(&self, message_id: u64) -> Option<TermLocation> {
+        if message_id == 0 || message_id > self.max_message_id() {
+            return None;
+        }
+        let mut commit_file_id = self.first_commit_file_id();
+        loop {
+            let path = create_commit_name(
+                &self.file_storage_directory,
+                &self.file_prefix,
+                &commit_file_id,
+            );
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(false)
+                .open(&path)
+                .ok()?;
+            let buffer = unsafe { open_commit_file(file) }.ok()?;
+            let slots = (buffer.capacity() - FILE_HEADER_SIZE) / COMMIT_SIZE as usize;
+            // The terms fill the file from the start so look for the first one at or past the id.
+            let mut low = 0;
+            let mut high = slots;
+            while low < high {
+                let mid = (low + high) / 2;
+                let pos = mid * COMMIT_SIZE as usize;
+                if buffer.term(pos) == 0 || buffer.max_message_id(pos) >= message_id {
+                    high = mid;
+                } else {
+                    low = mid + 1;
+                }
+            }
+            if low < slots {
+                let pos = low * COMMIT_SIZE as usize;
+                break if buffer.term(pos) > 0 && buffer.committed(pos) > 0 && buffer.verify(pos) {
+                    Some(TermLocation {
+                        term_id: buffer.term(pos),
+                        file_id: buffer.file_id(pos),
+                        position: buffer.file_position_offset(pos),
+                        length: buffer.length_of_commit(pos),
+                        max_message_id: buffer.max_message_id(pos),
+                        committed_at: buffer.start_time(pos),
+                    })
+                } else {
+                    None
+                };
+            }
+            commit_file_id += 1;
+        }
+    }
+
+    /// Gets the committed messages written in a time range.  The frames don't keep the time so the
+    /// range is matched against the times of the terms and the messages of every term in the range
+    /// are returned.
+    /// # Arguments
+    /// `start_ms` - The start of the range in milliseconds since the epoch.
+    /// `end_ms` - The end of the range in milliseconds since the epoch.
+    /// # Returns
+    /// An iterator over the messages or `None` if no terms were committed in the range.
+    pub fn find_by_time(
+        &self,
+        start_ms: u64,
+        end_ms: u64,
+    ) -> crate::Result<Option<MessageIterator>> {
+        self.find_by_time_with_slop(start_ms, end_ms, DEFAULT_TIME_SLOP_MS)
+    }
+
+    /// Gets the committed messages written in a time range allowing for the commit times being
+    /// out of order.
+    /// # Arguments
+    /// `start_ms` - The start of the range in milliseconds since the epoch.
+    /// `end_ms` - The end of the range in milliseconds since the epoch.
+    /// `slop_ms` - How far out of order the times of the terms can be.  The range is widened by
+    /// it and the search only stops at a term past the end by more than it.
+    /// # Returns
+    /// An iterator over the messages or `None` if no terms were committed in the range.
+    pub fn find_by_time_with_slop(
+        &self,
+        start_ms: u64,
+        end_ms: u64,
+        slop_ms: u64,
+    ) -> crate::Result<Option<MessageIterator>> {
+        let (start_id, end_id) = match self.message_range_by_time(start_ms, end_ms, slop_ms) {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+        let mut iterator = MessageIterator::new(
+            u32::MAX,
+            start_id,
+            end_id.min(self.max_message_id()),
+            self.message_files.clone(),
+            false,
+            Arc::new(FileBackend),
+        )?;
+        iterator.set_cross_files(true);
+        Ok(Some(iterator))
+    }
+
+    /// Goes through the committed terms to find the messages in a time range.
+    /// # Arguments
+    /// `start_ms` - The start of the range.
+    /// `end_ms` - The end of the range.
+    /// `slop_ms` - How far out of order the times of the terms can be.
+    /// # Returns
+    /// The id of the first and last message in the terms that are in the range.
+    fn message_range_by_time(
+        &self,
+        start_ms: u64,
+        end_ms: u64,
+        slop_ms: u64,
+    ) -> Option<(u64, u64)> {
+        let mut range: Option<(u64, u64)> = None;
+        let mut previous_max_id = 0;
+        let mut commit_file_id = self.first_commit_file_id();
+        loop {
+            let path = create_commit_name(
+                &self.file_storage_directory,
+                &self.file_prefix,
+                &commit_file_id,
+            );
+            let file = match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(file) => file,
+                Err(_) => break,
+            };
+            let mut buffer = unsafe { open_commit_file(file) }.ok()?;
+            let slots = (buffer.capacity() - FILE_HEADER_SIZE) / COMMIT_SIZE as usize;
+            for slot in 0..slots {
+                let pos = slot * COMMIT_SIZE as usize;
+                if buffer.term(pos) == 0 || buffer.committed(pos) == 0 || !buffer.verify(pos) {
+                    return range;
+                }
+                let started = buffer.start_time(pos);
+                if started > end_ms.saturating_add(slop_ms) {
+                    // Past the end by more than the times can be out of order.
+                    return range;
+                }
+                let max_message_id = buffer.max_message_id(pos);
+                if buffer.committed_timestamp(pos).saturating_add(slop_ms) >= start_ms {
+                    range = match range {
+                        Some((start_id, _)) => Some((start_id, max_message_id)),
+                        None => Some((previous_max_id + 1, max_message_id)),
+                    };
+                }
+                previous_max_id = max_message_id;
+            }
+            commit_file_id += 1;
+        }
+        range
+    }
+
+    /// Queues the message to be written.
+    /// # Arguments
+    /// `msg_type_id` - The type of the message we are writing.
+    /// `bytes` - The bytes to write the buffer.
+    /// `complete` - The future to complete when the message has been committed.
+    fn queue_write(&self, msg_type_id: i32, bytes: &[u8], complete: WriteComplete) {
+        if self.followed_files.is_some() {
+            complete.complete(Err(file::Error::ReadOnly));
+            return;
+        }
+        if self.stop.load(atomic::Ordering::Acquire) > 0
+            || self.closing.load(atomic::Ordering::Acquire)
+        {
+            complete.complete(Err(file::Error::Stopped));
+            return;
+        }
+        let queued_ns = self.latency.now();
+        let reserved = self.backlog.reserve(bytes.len());
         match self.incoming_writer.write(msg_type_id, bytes) {
             Some(p) => {
-                let add_message = AddMessageWriteRs::new(p, sender);
+                let add_message = AddMessageWriteRs::new(p, complete, reserved, queued_ns);
                 if !self.incoming_queue_writer.offer(add_message) {
                     thread::sleep(Duration::from_millis(1));
                 }
-                receiver
             }
             None => {
-                sender.send(Err(file::Error::Full)).unwrap_or_default();
-                receiver
+                complete.complete(Err(file::Error::Full));
+            }
+        }
+    }
+
+    /// Creates a reader that follows the messages as they are committed.  Once it is caught up it
+    /// waits for the commit thread to signal instead of spinning.
+    /// # Arguments
+    /// `message_id` - The id of the first message to read.
+    pub fn tail_from(&self, message_id: u64) -> TailReader<'_> {
+        TailReader::new(
+            self,
+            message_id,
+            self.commit_signal.clone(),
+            self.stop.clone(),
+        )
+    }
+
+    /// Subscribes to the committed messages as a `Stream`.  The stream is pending while it is caught
+    /// up and ends once the store is stopped.
+    /// # Arguments
+    /// `from_message_id` - The id of the first message to return.
+    pub fn subscribe(&self, from_message_id: u64) -> CommittedStream<'_> {
+        CommittedStream::new(self.tail_from(from_message_id))
+    }
+
+    /// Gets the first event file after a file that hasn't been removed.
+    /// # Arguments
+    /// `file_id` - The id of the file to start after.
+    /// # Returns
+    /// The id of the file or `None` if there isn't one.
+    fn next_kept_file(&self, file_id: u32) -> Option<u32> {
+        let files = self
+            .followed_files
+            .as_ref()
+            .unwrap_or(&self.message_files)
+            .lock()
+            .unwrap();
+        files
+            .iter()
+            .find(|f| !f.removed && f.file_id > file_id)
+            .map(|f| f.file_id)
+    }
+
+    /// Gets the reader for an event file.
+    /// # Arguments
+    /// `file_id` - The id of the event file to get.
+    /// # Returns
+    /// The reader if the file exists.
+    fn event_reader(&self, file_id: u32) -> Option<Arc<MessageFileStoreRead>> {
+        let mut readers = self.event_readers.lock().unwrap();
+        match readers.get(&file_id) {
+            Some(reader) => Some(reader.clone()),
+            None => {
+                let path = create_event_name(&self.file_storage_directory, &self.file_prefix, &file_id);
+                if !Path::new(&path).exists() {
+                    return None;
+                }
+                match unsafe { MessageFileStore::open_readonly(&path) } {
+                    Ok(reader) => {
+                        let reader = Arc::new(reader);
+                        readers.insert(file_id, reader.clone());
+                        Some(reader)
+                    }
+                    Err(e) => {
+                        log::error!("{}", e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The message type used for the changes added through the event stream.
+const EVENT_MESSAGE_TYPE: i32 = 1;
+
+impl PersitEventStream for PersistedMessageFile {
+    fn add_change(&self, value: &[u8]) -> CommitFuture<u64> {
+        let (sender, receiver) = oneshot::channel();
+        self.queue_write(EVENT_MESSAGE_TYPE, value, WriteComplete::Change(sender));
+        receiver
+    }
+
+    fn events_from(&self, commit_key: u64) -> Box<dyn Iterator<Item = Event<'_>> + '_> {
+        Box::new(EventIterator {
+            file: self,
+            cursor: CommittedCursor::new(commit_key),
+        })
+    }
+
+    fn committed_key(&self) -> u64 {
+        self.max_message_id()
+    }
+}
+
+impl MessageStore for PersistedMessageFile {
+    fn add_message(&self, msg_type_id: &i32, buffer: &[u8]) -> CommitFuture<crate::Result<u64>> {
+        self.write(*msg_type_id, buffer)
+    }
+}
+
+/// The position of a reader going through the committed messages.  Doesn't hold onto the file so
+/// it can be used by something that owns the file.
+pub(crate) struct CommittedCursor {
+    reader: Option<Arc<MessageFileStoreRead>>,
+    file_id: u32,
+    pos: usize,
+    /// The id of the message to start returning the messages from.
+    from_id: u64,
+    /// Only the messages matching the filter are returned.
+    filter: Option<MessageFilter>,
+    /// Return the messages used by the store.
+    store_messages: bool,
+}
+
+impl CommittedCursor {
+    /// Creates a cursor starting at the first file.
+    /// # Arguments
+    /// `from_id` - The id of the message to start returning the messages from.
+    pub(crate) fn new(from_id: u64) -> Self {
+        CommittedCursor {
+            reader: None,
+            file_id: 1,
+            pos: 0,
+            from_id,
+            filter: None,
+            store_messages: false,
+        }
+    }
+
+    /// Creates a cursor that also returns the messages used by the store like the transaction and
+    /// producer messages.
+    /// # Arguments
+    /// `from_id` - The id of the message to start returning the messages from.
+    pub(crate) fn with_store_messages(from_id: u64) -> Self {
+        CommittedCursor {
+            store_messages: true,
+            ..CommittedCursor::new(from_id)
+        }
+    }
+
+    /// Creates a cursor that only returns the messages matching the filter.
+    /// # Arguments
+    /// `from_id` - The id of the message to start returning the messages from.
+    /// `filter` - The filter to check the message headers against.
+    pub(crate) fn with_filter(from_id: u64, filter: MessageFilter) -> Self {
+        CommittedCursor {
+            filter: Some(filter),
+            ..CommittedCursor::new(from_id)
+        }
+    }
+
+    /// Moves onto the next event file.
+    fn next_file(&mut self, file: &PersistedMessageFile) {
+        // The ids skip the files merged by a compaction.
+        self.file_id = file
+            .next_kept_file(self.file_id)
+            .unwrap_or(self.file_id + 1);
+        self.pos = 0;
+        self.reader = file.event_reader(self.file_id);
+    }
+
+    /// Gets the next committed message.  If we are caught up `None` is returned and the cursor can
+    /// be called again once more messages have been committed.
+    /// # Arguments
+    /// `file` - The file to read the messages from.
+    pub(crate) fn next<'a>(&mut self, file: &'a PersistedMessageFile) -> Option<MessageRead<'a>> {
+        if self.reader.is_none() && self.file_id == 1 && self.pos == 0 && self.from_id > 1 {
+            // Start at the closest message we know the position of instead of the first file.
+            if let Some((_, (file_id, pos))) = file.positions.floor_entry(&self.from_id) {
+                if let Some(reader) = file.event_reader(file_id) {
+                    self.file_id = file_id;
+                    self.pos = pos;
+                    self.reader = Some(reader);
+                }
+            }
+        }
+        if self.reader.is_none() {
+            self.reader = file.event_reader(self.file_id);
+            if self.reader.is_none() {
+                // The file could have been removed by the retention or after a snapshot.
+                if let Some(file_id) = file.next_kept_file(self.file_id) {
+                    self.file_id = file_id;
+                    self.pos = 0;
+                    self.reader = file.event_reader(file_id);
+                }
+            }
+        }
+        loop {
+            let reader = self.reader.clone()?;
+            match reader.read_new(self.pos) {
+                Ok(msg) => {
+                    if msg.msg_type_id() == END_OF_FILE_MESSAGE_TYPE {
+                        self.next_file(file);
+                    } else if msg.message_id() > file.max_message_id.load(atomic::Ordering::Acquire)
+                    {
+                        break None;
+                    } else {
+                        let position = self.pos;
+                        self.pos = msg.next_pos();
+                        // The negative types are used by the store so they aren't part of the stream.
+                        if msg.message_id() >= self.from_id
+                            && (msg.msg_type_id() >= 0 || self.store_messages)
+                        {
+                            match &self.filter {
+                                Some(filter) => {
+                                    if filter.is_past_end(msg.message_id()) {
+                                        // Don't move past the end so we keep returning None.
+                                        self.pos = position;
+                                        break None;
+                                    } else if filter
+                                        .matches(&MessageHeaderView::new(&msg, position))
+                                    {
+                                        break Some(msg);
+                                    }
+                                }
+                                None => break Some(msg),
+                            }
+                        }
+                    }
+                }
+                Err(e) => match e {
+                    file::Error::Full | file::Error::PositionOutOfRange(_) => self.next_file(file),
+                    _ => break None,
+                },
+            }
+        }
+    }
+}
+
+/// Iterates through the committed events in the message files.
+pub struct EventIterator<'a> {
+    file: &'a PersistedMessageFile,
+    cursor: CommittedCursor,
+}
+
+impl<'a> Iterator for EventIterator<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        loop {
+            let msg = self.cursor.next(self.file)?;
+            if msg.msg_type_id() > 0 {
+                break Some(Event {
+                    commit_key: msg.message_id(),
+                    value: msg.bytes(),
+                });
             }
         }
     }
@@ -2196,13 +6377,20 @@ impl PersistedMessageFile {
 #[cfg(test)]
 mod tests {
 
+    use crate::file::backend::InMemoryMessageStore;
     use crate::file::{MessageFileStore, MessageRead};
     use crate::raft::*;
+    use crate::testing::{crash_seed, seeded_rng, Crash, FaultyStore};
+    use rand::Rng;
+    use futures::executor::block_on;
     use futures::future::Future;
     use std::fs::{create_dir_all, remove_dir_all};
+    use std::io;
+    use std::io::Read;
     use std::path::Path;
+    use std::pin::Pin;
     use std::sync::atomic::AtomicU64;
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
     use std::thread;
 
     const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist";
@@ -2224,14 +6412,266 @@ mod tests {
         assert_eq!(files.commit_files.lock().unwrap().len(), 1);
         assert_eq!(files.message_files.lock().unwrap().len(), 1);
 
+        // Only the files with a message or a term in them are loaded.
+        let event_path = create_event_name(TEST_DIR, TEST_PREFIX, &1);
+        let writer = unsafe { MessageFileStore::open_write(&event_path, size) }.unwrap();
+        writer.write(0, 1, 1, &1u64.to_le_bytes()).unwrap();
+        writer.flush().unwrap();
+        let commit_path = create_commit_name(TEST_DIR, TEST_PREFIX, &1);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&commit_path)
+            .unwrap();
+        let mut buffer = unsafe { open_commit_file(file) }.unwrap();
+        buffer.save_term(
+            0,
+            &TermCommit {
+                term_id: 1,
+                version: 1,
+                type_id: 1,
+                server_id: 1,
+                leader_id: 1,
+                committed: 1,
+                timestamp: 1_000,
+                committed_timestamp: 1_000,
+                file_id: 1,
+                file_position_offset: 0,
+                file_max_message_id: 1,
+                length: 64,
+            },
+        );
+        buffer.flush().unwrap();
+
+        // The event files go to the message files and the commit files to the commit files.
         let other_files = load_current_files(TEST_PREFIX, TEST_DIR).unwrap();
+        {
+            let message_files = other_files.message_files.lock().unwrap();
+            assert_eq!(1, message_files.len());
+            assert_eq!(1, message_files[0].file_id);
+            assert_eq!(event_path, message_files[0].path);
+            assert!(message_files[0].path.contains(".events."));
+            let commit_files = other_files.commit_files.lock().unwrap();
+            assert_eq!(1, commit_files.len());
+            assert_eq!(1, commit_files[0].file_id);
+            assert_eq!(commit_path, commit_files[0].path);
+            assert!(commit_files[0].path.contains(".commit."));
+        }
+
+        // A commit file that doesn't hold whole terms isn't read.
+        let bad = create_commit_name(TEST_DIR, TEST_PREFIX, &2);
+        unsafe { create_commit_file(&bad, size) }.unwrap();
+        let file = OpenOptions::new().write(true).open(&bad).unwrap();
+        file.set_len((FILE_HEADER_SIZE + size) as u64 + 100)
+            .unwrap();
+        match load_current_files(TEST_PREFIX, TEST_DIR) {
+            Err(PersistError::CorruptTerm { context, .. }) => {
+                assert_eq!(Some(bad.as_str()), context.path.as_deref())
+            }
+            Err(e) => panic!("Expected a corrupt term: {}", e),
+            Ok(_) => panic!("Expected a corrupt term."),
+        }
+        std::fs::remove_file(&bad).unwrap();
     }
 
     #[test]
     pub fn file_id_test() {
-        let file_test = "test.event.2";
-        assert_eq!(read_file_id(file_test), Some(2));
-    }
+        // The names from before the ids were padded.
+        assert_eq!(Some(2), read_file_id("test", "test.events.2"));
+        assert_eq!(Some(12), read_file_id("test", "test.commit.12"));
+        assert_eq!(Some(2), read_file_id("test", "test.events.0000000002"));
+        assert_eq!(Some(12), read_file_id("test", "test.commit.0000000012"));
+        assert_eq!(
+            Some(3_000_000_000),
+            read_file_id("test", "test.events.3000000000")
+        );
+        assert_eq!(
+            Some(u32::MAX),
+            read_file_id("test", "test.commit.4294967295")
+        );
+        for junk in [
+            "test.events.1.tmp",
+            "test.event.2",
+            "test.events.",
+            "test.events.+1",
+            "test.events.1a",
+            "test.events.4294967296",
+            "test.index.1",
+            "test.meta",
+            "other.events.1",
+            "tests.events.1",
+        ]
+        .iter()
+        {
+            assert_eq!(None, read_file_id("test", junk), "{}", junk);
+        }
+        // Lists in order.
+        let mut names: Vec<String> = [10, 2, 3_000_000_000, 1]
+            .iter()
+            .map(|id| create_commit_name("dir", "test", id))
+            .collect();
+        names.sort();
+        assert_eq!(
+            vec![
+                "dir/test.commit.0000000001",
+                "dir/test.commit.0000000002",
+                "dir/test.commit.0000000010",
+                "dir/test.commit.3000000000"
+            ],
+            names
+        );
+    }
+
+    #[test]
+    pub fn unpadded_file_names_test() {
+        let file_storage_directory = format!("{}_unpadded", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let start = || {
+            builder::PersistedMessageFileBuilder::new(&file_storage_directory, TEST_PREFIX)
+                .max_file_size(0x400)
+                .commit_file_size(COMMIT_SIZE as usize * 4)
+                .build(MessageProcessorInt::new())
+                .unwrap()
+        };
+        let store = start();
+        for i in 1..=300u64 {
+            block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap();
+        }
+        let files = store.message_files.lock().unwrap().len();
+        assert!(files >= 10);
+        store.close().unwrap();
+
+        // Rename the files to how they were named before the ids were padded.
+        let mut renamed = 0;
+        for entry in read_dir(&file_storage_directory).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+            if let Some(file_id) = read_file_id(TEST_PREFIX, &name) {
+                let postfix = if name.contains(EVENT_FILE_POSTFIX) {
+                    EVENT_FILE_POSTFIX
+                } else {
+                    COMMIT_FILE_POSTIX
+                };
+                let old = format!("{}.{}.{}", TEST_PREFIX, postfix, file_id);
+                rename(&path, path.with_file_name(old)).unwrap();
+                renamed += 1;
+            }
+        }
+        assert!(renamed > files);
+        // A junk file is left alone.
+        let junk = format!("{}/{}.events.1.tmp", file_storage_directory, TEST_PREFIX);
+        File::create(&junk).unwrap();
+
+        let store = start();
+        assert!(Path::new(&junk).exists());
+        // The old files keep their names.
+        let old = format!("{}/{}.events.10", file_storage_directory, TEST_PREFIX);
+        assert!(Path::new(&old).exists());
+        assert_eq!(
+            old,
+            create_event_name(&file_storage_directory, TEST_PREFIX, &10)
+        );
+        assert_eq!(files, store.message_files.lock().unwrap().len());
+        let ids: Vec<u64> = store.events_from(1).map(|e| e.commit_key).collect();
+        assert_eq!((1..=300).collect::<Vec<u64>>(), ids);
+        assert_eq!(
+            301,
+            block_on(store.write(1, &301u64.to_le_bytes()))
+                .unwrap()
+                .unwrap()
+        );
+        store.close().unwrap();
+        assert!(Path::new(&old).exists());
+        // A new file gets the padded name.
+        assert_eq!(
+            format!("{}/{}.events.{:010}", file_storage_directory, TEST_PREFIX, 1_000),
+            create_event_name(&file_storage_directory, TEST_PREFIX, &1_000)
+        );
+    }
+
+    #[test]
+    pub fn message_info_test() {
+        let headers = MessageHeaders {
+            tenant: 4,
+            priority: 1,
+            flags: 0,
+            reserved: 0,
+        };
+        let body = [1, 2, 3];
+        let message = MessageInfo::new(5, 1_000, 2, headers, &body);
+        assert_eq!(5, message.message_id());
+        assert_eq!(1_000, message.time_ms());
+        assert_eq!(2, message.message_type());
+        assert_eq!(headers, message.headers());
+        assert_eq!(&body[..], message.body());
+        assert_eq!(3, message.len());
+        assert!(!message.is_empty());
+        assert!(!message.is_padding());
+        let end = MessageInfo::new(
+            u64::MAX,
+            0,
+            END_OF_FILE_MESSAGE_TYPE,
+            MessageHeaders::default(),
+            &[],
+        );
+        assert!(end.is_padding() && end.is_empty());
+        assert!(format!("{:?}", end).contains("MessageInfo"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn serde_test() {
+        let body = [1, 2, 3];
+        let message = MessageInfo {
+            message_id: 5,
+            time_ms: 1_000,
+            message_type: 2,
+            headers: MessageHeaders {
+                tenant: 4,
+                priority: 1,
+                flags: 0,
+                reserved: 0,
+            },
+            message_body: &body,
+        }
+        .to_owned_info();
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(message, serde_json::from_str::<OwnedMessageInfo>(&json).unwrap());
+
+        let term = TermCommit {
+            term_id: 3,
+            version: 1,
+            type_id: 0,
+            server_id: 1,
+            leader_id: 1,
+            committed: 1,
+            timestamp: 1_000,
+            committed_timestamp: 1_001,
+            file_id: 2,
+            file_position_offset: 512,
+            file_max_message_id: 40,
+            length: 96,
+        };
+        let json = serde_json::to_string(&term).unwrap();
+        assert_eq!(term, serde_json::from_str::<TermCommit>(&json).unwrap());
+
+        let location = TermLocation {
+            term_id: 3,
+            file_id: 2,
+            position: 512,
+            length: 96,
+            max_message_id: 40,
+            committed_at: 1_001,
+        };
+        let json = serde_json::to_string(&location).unwrap();
+        assert_eq!(
+            location,
+            serde_json::from_str::<TermLocation>(&json).unwrap()
+        );
+    }
 
     #[test]
     pub fn find_end_of_buffer_test() {
@@ -2285,27 +6725,2588 @@ mod tests {
         assert_eq!(false, r);
     }
 
-    #[tokio::test]
-    pub async fn create_single_node_processor() {
-        let file_storage_directory = format!("{}_single_node", TEST_DIR);
+    #[test]
+    pub fn write_rollover_test() {
+        let file_storage_directory = format!("{}_write_rollover", TEST_DIR);
         let path = Path::new(&file_storage_directory);
         if path.exists() && path.is_dir() {
             remove_dir_all(&file_storage_directory).unwrap();
         }
-        let processor = MessageProcessorInt::new();
-        let mut single_node = startup_single_node(
+        let file_size = 0x400;
+        let mut collection = load_current_files(TEST_PREFIX, &file_storage_directory).unwrap();
+        process_files(
+            &mut collection,
+            TEST_PREFIX,
+            &file_storage_directory,
+            &file_size,
+            &0x1000,
+        )
+        .unwrap();
+        let mut writer = PersistedMessageWriteStream::new(
+            1,
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            file_size,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap()
+        .with_message_files(collection.message_files.clone());
+        let mut last = (0, 0);
+        for message_id in 1..=100u64 {
+            let (pos, file_id) = writer
+                .add_message(1, message_id, &message_id.to_le_bytes())
+                .unwrap();
+            // The positions only go back to the start when we move onto the next file.
+            assert!(file_id > last.1 || (file_id == last.1 && pos > last.0));
+            last = (pos, file_id);
+        }
+        writer.flush().unwrap();
+        let file_ids: Vec<u32> = collection
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.file_id)
+            .collect();
+        assert!(file_ids.len() >= 3);
+        assert_eq!((1..=last.1).collect::<Vec<u32>>(), file_ids);
+
+        let mut ids = Vec::new();
+        let mut start = 1;
+        loop {
+            let mut iterator = MessageIterator::new(
+                200,
+                start,
+                100,
+                collection.message_files.clone(),
+                true,
+                Arc::new(FileBackend),
+            )
+            .unwrap();
+            let next_file = loop {
+                match iterator.next().unwrap() {
+                    NextResult::Some(msg) => {
+                        let mut value = [0; 8];
+                        value.copy_from_slice(&msg.bytes()[0..8]);
+                        assert_eq!(msg.message_id(), u64::from_le_bytes(value));
+                        ids.push(msg.message_id());
+                    }
+                    NextResult::NextFile { .. } => break true,
+                    NextResult::End(_) => break false,
+                    NextResult::More | NextResult::Gap { .. } => panic!("Should be in sequence"),
+                }
+            };
+            if !next_file {
+                break;
+            }
+            start = ids.last().unwrap() + 1;
+        }
+        assert_eq!((1..=100).collect::<Vec<u64>>(), ids);
+
+        // A single iterator reads every file when it crosses them.
+        let mut iterator = MessageIterator::new(
+            200,
+            1,
+            100,
+            collection.message_files.clone(),
+            true,
+            Arc::new(FileBackend),
+        )
+        .unwrap();
+        iterator.set_cross_files(true);
+        let mut crossed = Vec::new();
+        loop {
+            match iterator.next().unwrap() {
+                NextResult::Some(msg) => crossed.push(msg.message_id()),
+                NextResult::End(_) => break,
+                _ => panic!("Should have crossed into the next file"),
+            }
+        }
+        assert_eq!(ids, crossed);
+    }
+
+    /// Writes messages 1 to 3 to an event file in memory.
+    /// # Arguments
+    /// `file_storage_directory` - The directory to put the file in.
+    /// `backend` - Where the file is kept.
+    fn write_delivery_file(file_storage_directory: &str, backend: &Arc<dyn MessageStoreBackend>) {
+        let mut writer = PersistedMessageWriteStream::new_with_backend(
+            1,
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            2048,
+            Arc::new(AtomicU64::new(0)),
+            backend.clone(),
+        )
+        .unwrap();
+        for i in 1..=3u64 {
+            writer.add_message(1, i, &i.to_le_bytes()).unwrap();
+        }
+    }
+
+    /// Sends the owned messages to another thread.
+    struct SendingProcessor {
+        sender: std::sync::mpsc::Sender<OwnedMessage>,
+    }
+
+    impl InfallibleProcessor for SendingProcessor {
+        fn handle<'a>(&mut self, _read: &MessageRead<'a>) {
+            panic!("Only expected owned messages.");
+        }
+
+        fn handle_owned(&mut self, msg: OwnedMessage) {
+            self.sender.send(msg).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn delivery_owned_test() {
+        let file_storage_directory = "/memory/delivery_owned";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        write_delivery_file(file_storage_directory, &backend);
+        let pool = BufferPool::new(&[64], 4);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut reader = PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            Arc::new(AtomicU64::new(3)),
+            SendingProcessor { sender },
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            backend,
+        )
+        .unwrap()
+        .with_delivery(DeliveryMode::Owned(pool.clone()));
+        let handle = thread::spawn(move || {
+            receiver
+                .iter()
+                .map(|msg: OwnedMessage| {
+                    assert!(msg.body.is_pooled());
+                    (msg.message_id, msg.body.to_vec())
+                })
+                .collect::<Vec<_>>()
+        });
+        while reader.process_next().unwrap() {}
+        // Closes the channel.
+        drop(reader);
+        let received = handle.join().unwrap();
+        assert_eq!(
+            (1..=3u64)
+                .map(|i| (i, i.to_le_bytes().to_vec()))
+                .collect::<Vec<_>>(),
+            received
+        );
+        assert_eq!(3, pool.stats().returned);
+    }
+
+    /// Records where the bodies of the messages are.
+    struct PointerProcessor {
+        pointers: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl InfallibleProcessor for PointerProcessor {
+        fn handle<'a>(&mut self, read: &MessageRead<'a>) {
+            self.pointers
+                .lock()
+                .unwrap()
+                .push(read.bytes().as_ptr() as usize);
+        }
+    }
+
+    #[test]
+    pub fn delivery_borrowed_test() {
+        let file_storage_directory = "/memory/delivery_borrowed";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        write_delivery_file(file_storage_directory, &backend);
+        let pointers = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            Arc::new(AtomicU64::new(3)),
+            PointerProcessor {
+                pointers: pointers.clone(),
+            },
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            backend.clone(),
+        )
+        .unwrap();
+        while reader.process_next().unwrap() {}
+
+        // The bodies handed out are the ones in the file so nothing was copied.
+        let file = backend
+            .open_readonly(Path::new(&create_event_name(
+                file_storage_directory,
+                TEST_PREFIX,
+                &1,
+            )))
+            .unwrap();
+        let mut expected = Vec::new();
+        let mut pos = 0;
+        for _ in 0..3 {
+            let msg = file.read_new(pos).unwrap();
+            expected.push(msg.bytes().as_ptr() as usize);
+            pos = msg.next_pos();
+        }
+        assert_eq!(expected, *pointers.lock().unwrap());
+    }
+
+    /// Fails on a message a number of times before handling it.
+    struct FailingProcessor {
+        fail_on: u64,
+        failures: u32,
+        handled: Vec<u64>,
+        attempts: u32,
+    }
+
+    impl MessageProcessor for FailingProcessor {
+        fn handle(&mut self, read: MessageRead<'_>) -> Result<(), ProcessError> {
+            if read.message_id() == self.fail_on {
+                self.attempts += 1;
+                if self.attempts <= self.failures {
+                    return Err(ProcessError::new(format!("attempt {}", self.attempts)));
+                }
+            }
+            self.handled.push(read.message_id());
+            Ok(())
+        }
+    }
+
+    fn failing_reader(
+        name: &str,
+        failures: u32,
+        error_policy: ErrorPolicy,
+    ) -> PersistedMessageReadStream<FailingProcessor> {
+        let file_storage_directory = format!("/memory/{}", name);
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        write_delivery_file(&file_storage_directory, &backend);
+        PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            Arc::new(AtomicU64::new(3)),
+            FailingProcessor {
+                fail_on: 2,
+                failures,
+                handled: Vec::new(),
+                attempts: 0,
+            },
             file_storage_directory,
             TEST_PREFIX.to_owned(),
-            5000,
-            5000,
-            processor,
-            0x40,
-            0x40,
+            backend,
+        )
+        .unwrap()
+        .with_error_policy(error_policy)
+    }
+
+    /// Records the ids in each batch.
+    struct BatchProcessor {
+        batches: Vec<Vec<u64>>,
+        fail_on: u64,
+    }
+
+    impl MessageProcessor for BatchProcessor {
+        fn handle(&mut self, read: MessageRead<'_>) -> Result<(), ProcessError> {
+            self.batches.push(vec![read.message_id()]);
+            Ok(())
+        }
+
+        fn handle_batch(
+            &mut self,
+            batch: &mut dyn Iterator<Item = MessageRead<'_>>,
+        ) -> Result<(), ProcessError> {
+            let mut ids = Vec::new();
+            let mut result = Ok(());
+            for read in batch {
+                if read.message_id() == self.fail_on {
+                    self.fail_on = 0;
+                    result = Err(ProcessError::new("failed"));
+                    break;
+                }
+                ids.push(read.message_id());
+            }
+            self.batches.push(ids);
+            result
+        }
+    }
+
+    #[test]
+    pub fn batch_test() {
+        let file_storage_directory = "/memory/batch";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        let mut writer = PersistedMessageWriteStream::new_with_backend(
+            1,
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x100,
+            Arc::new(AtomicU64::new(0)),
+            backend.clone(),
+        )
+        .unwrap();
+        // The file each message was written to.
+        let mut file_ids = HashMap::new();
+        for i in 1..=30u64 {
+            writer.add_message(1, i, &i.to_le_bytes()).unwrap();
+            file_ids.insert(i, writer.position().0);
+        }
+        assert!(writer.position().0 > 2);
+        let max_message_id = Arc::new(AtomicU64::new(20));
+        let mut reader = PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            max_message_id.clone(),
+            BatchProcessor {
+                batches: Vec::new(),
+                fail_on: 0,
+            },
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            backend,
+        )
+        .unwrap()
+        .with_batch_size(4);
+        while reader.process_next().unwrap() {}
+        max_message_id.store(30, atomic::Ordering::Relaxed);
+        while reader.process_next().unwrap() {}
+
+        let batches = reader.message_processor.batches;
+        assert_eq!(
+            (1..=30u64).collect::<Vec<u64>>(),
+            batches.iter().flatten().cloned().collect::<Vec<u64>>()
         );
-        let bytes: Vec<u8> = vec![10, 11, 12, 13, 14, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32];
-        let r = single_node.write(1, &bytes[0..8]).await;
-        r.unwrap().unwrap();
-        single_node.stop();
+        assert!(batches.iter().any(|batch| batch.len() > 1));
+        for batch in &batches {
+            assert!(!batch.is_empty() && batch.len() <= 4);
+            // Never past the committed message or the end of a file.
+            assert!(batch.contains(&20) == (*batch.last().unwrap() == 20));
+            assert!(batch.iter().all(|id| file_ids[id] == file_ids[&batch[0]]));
+        }
+    }
+
+    /// Records the ids of the messages it is handed.
+    struct IdProcessor {
+        ids: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl InfallibleProcessor for IdProcessor {
+        fn handle<'a>(&mut self, read: &MessageRead<'a>) {
+            self.ids.lock().unwrap().push(read.message_id());
+        }
+    }
+
+    #[test]
+    pub fn poll_test() {
+        let file_storage_directory = "/memory/poll";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        let mut writer = PersistedMessageWriteStream::new_with_backend(
+            1,
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x100,
+            Arc::new(AtomicU64::new(0)),
+            backend.clone(),
+        )
+        .unwrap();
+        for i in 1..=30u64 {
+            writer.add_message(1, i, &i.to_le_bytes()).unwrap();
+        }
+        assert!(writer.position().0 > 2);
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let max_message_id = Arc::new(AtomicU64::new(0));
+        let mut reader = PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            max_message_id.clone(),
+            IdProcessor { ids: ids.clone() },
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            backend,
+        )
+        .unwrap();
+        assert_eq!(0, reader.poll().unwrap());
+        let mut committed = 0;
+        for next in [3u64, 7, 8, 19, 30] {
+            max_message_id.store(next, atomic::Ordering::Release);
+            assert_eq!((next - committed) as usize, reader.poll().unwrap());
+            assert_eq!((1..=next).collect::<Vec<u64>>(), *ids.lock().unwrap());
+            committed = next;
+        }
+        assert_eq!(0, reader.poll().unwrap());
+    }
+
+    #[test]
+    pub fn run_until_test() {
+        let file_storage_directory = "/memory/run_until";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        let mut writer = PersistedMessageWriteStream::new_with_backend(
+            1,
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x100,
+            Arc::new(AtomicU64::new(0)),
+            backend.clone(),
+        )
+        .unwrap();
+        for i in 1..=20u64 {
+            writer.add_message(1, i, &i.to_le_bytes()).unwrap();
+        }
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let max_message_id = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let ids = ids.clone();
+            let max_message_id = max_message_id.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut reader = PersistedMessageReadStream::new_with_backend(
+                    1,
+                    1,
+                    max_message_id,
+                    IdProcessor { ids },
+                    file_storage_directory.to_owned(),
+                    TEST_PREFIX.to_owned(),
+                    backend,
+                )
+                .unwrap();
+                reader.run_until(&stop, &SleepBackoff(Duration::from_millis(1)))
+            })
+        };
+        for next in [5u64, 12, 20] {
+            max_message_id.store(next, atomic::Ordering::Release);
+            let start = Instant::now();
+            while ids.lock().unwrap().len() < next as usize {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!((1..=next).collect::<Vec<u64>>(), *ids.lock().unwrap());
+        }
+        stop.store(true, atomic::Ordering::Release);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    pub fn send_sync_test() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<PersistedCommitStream>();
+        assert_send::<PersistedCommitStreamLeader>();
+        assert_send::<PersistedCommitStreamFollower>();
+        assert_send::<PersistedCommitSingleNode>();
+        assert_send::<PersistedMessageWriteStream>();
+        assert_send::<PersistedMessageReadStream<IdProcessor>>();
+        assert_send::<PersistedMessageFile>();
+        assert_sync::<PersistedMessageFile>();
+    }
+
+    #[test]
+    pub fn commit_and_read_threads_test() {
+        let file_storage_directory = "/memory/commit_and_read_threads";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        let written = Arc::new(AtomicU64::new(0));
+        let committed = Arc::new(AtomicU64::new(0));
+        let mut writer = PersistedMessageWriteStream::new_with_backend(
+            1,
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x100,
+            written.clone(),
+            backend.clone(),
+        )
+        .unwrap();
+        for i in 1..=40u64 {
+            writer.add_message(1, i, &i.to_le_bytes()).unwrap();
+        }
+        let mut commit = PersistedCommitStream::create(
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            COMMIT_SIZE as usize * 8,
+            backend.clone(),
+            committed.clone(),
+            written,
+            system_clock(),
+        )
+        .unwrap();
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            committed.clone(),
+            IdProcessor { ids: ids.clone() },
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            backend,
+        )
+        .unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader_handle = {
+            let stop = stop.clone();
+            thread::spawn(move || reader.run_until(&stop, &SleepBackoff(Duration::from_millis(1))))
+        };
+        let commit_handle = thread::spawn(move || {
+            while committed.load(atomic::Ordering::Acquire) < 40 {
+                commit.step().unwrap();
+            }
+            commit.current_term()
+        });
+        assert!(commit_handle.join().unwrap() > 0);
+        let start = Instant::now();
+        while ids.lock().unwrap().len() < 40 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        stop.store(true, atomic::Ordering::Release);
+        reader_handle.join().unwrap().unwrap();
+        assert_eq!((1..=40u64).collect::<Vec<u64>>(), *ids.lock().unwrap());
+    }
+
+    /// Writes and commits 30 messages, truncates after the one picked from the files the messages
+    /// are in and writes new messages in their place.
+    /// # Arguments
+    /// `file_storage_directory` - The directory to write the files to.
+    /// `pick` - Picks the id to keep from the id of the file each message is in.
+    fn truncate_and_append(file_storage_directory: &str, pick: fn(&[u32]) -> u64) {
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        let written = Arc::new(AtomicU64::new(0));
+        let committed = Arc::new(AtomicU64::new(0));
+        let mut writer = PersistedMessageWriteStream::new_with_backend(
+            1,
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x100,
+            written.clone(),
+            backend.clone(),
+        )
+        .unwrap();
+        let mut files = vec![0];
+        for i in 1..=30u64 {
+            files.push(writer.add_message(1, i, &i.to_le_bytes()).unwrap().1);
+        }
+        let keep = pick(&files);
+        let kept_file = files[keep as usize];
+        assert!(kept_file < files[30]);
+        let mut commit = PersistedCommitStream::create(
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            COMMIT_SIZE as usize * 64,
+            backend.clone(),
+            committed.clone(),
+            written.clone(),
+            system_clock(),
+        )
+        .unwrap();
+        while committed.load(atomic::Ordering::Acquire) < 30 {
+            commit.step().unwrap();
+        }
+        let terms = commit.current_term();
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            committed.clone(),
+            IdProcessor { ids: ids.clone() },
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            backend.clone(),
+        )
+        .unwrap();
+
+        commit.truncate_after(keep).unwrap();
+        writer.truncate_after(keep).unwrap();
+        assert_eq!(keep, written.load(atomic::Ordering::Acquire));
+        assert_eq!(kept_file, writer.position().0);
+        for removed in kept_file + 1..=files[30] {
+            let path = create_event_name(file_storage_directory, TEST_PREFIX, &removed);
+            assert!(!backend.exists(Path::new(&path)));
+        }
+        // The term the id is in is removed and committed again.
+        assert!(committed.load(atomic::Ordering::Acquire) <= keep);
+        assert!(commit.current_term() < terms);
+        while committed.load(atomic::Ordering::Acquire) < keep {
+            commit.step().unwrap();
+        }
+        assert_eq!(CommitStep::Idle, commit.step().unwrap());
+        // The reader stops at the end of the messages kept.
+        assert_eq!(keep as usize, reader.poll().unwrap());
+        assert_eq!(0, reader.poll().unwrap());
+
+        // The new messages get the ids after the ones kept.
+        for i in keep + 1..=keep + 20 {
+            writer.add_message(2, i, &(i * 100).to_le_bytes()).unwrap();
+        }
+        while committed.load(atomic::Ordering::Acquire) < keep + 20 {
+            commit.step().unwrap();
+        }
+        assert_eq!(20, reader.poll().unwrap());
+        assert_eq!((1..=keep + 20).collect::<Vec<u64>>(), *ids.lock().unwrap());
+    }
+
+    #[test]
+    pub fn truncate_mid_file_test() {
+        truncate_and_append("/memory/truncate_mid_file", |files| {
+            (2..30u64)
+                .find(|id| {
+                    let id = *id as usize;
+                    files[id - 1] == files[id] && files[id] == files[id + 1]
+                })
+                .unwrap()
+        });
+    }
+
+    #[test]
+    pub fn truncate_file_boundary_test() {
+        truncate_and_append("/memory/truncate_file_boundary", |files| {
+            (1..30u64)
+                .find(|id| files[*id as usize] < files[*id as usize + 1])
+                .unwrap()
+        });
+    }
+
+    #[test]
+    pub fn batch_error_test() {
+        let file_storage_directory = "/memory/batch_error";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        write_delivery_file(file_storage_directory, &backend);
+        let mut reader = PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            Arc::new(AtomicU64::new(3)),
+            BatchProcessor {
+                batches: Vec::new(),
+                fail_on: 2,
+            },
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            backend,
+        )
+        .unwrap()
+        .with_batch_size(8)
+        .with_error_policy(ErrorPolicy::Retry(1));
+        while reader.process_next().unwrap() {}
+        // The failed message is retried on its own and the rest of the batch is handed out again.
+        assert_eq!(
+            vec![vec![1], vec![2], vec![3]],
+            reader.message_processor.batches
+        );
+    }
+
+    #[test]
+    pub fn error_policy_stop_test() {
+        let mut reader = failing_reader("error_stop", 1, ErrorPolicy::Stop);
+        assert!(reader.process_next().unwrap());
+        match reader.process_next() {
+            Err(file::Error::ProcessFailed(2)) => {}
+            other => panic!("Expected message 2 to fail but got {:?}", other),
+        }
+        assert_eq!(
+            Some((2, &ProcessError::new("attempt 1"))),
+            reader.last_error()
+        );
+        assert_eq!(0, reader.skipped());
+        // Stays on the failed message so it is handed out again.
+        while reader.process_next().unwrap() {}
+        assert_eq!(vec![1, 2, 3], reader.message_processor.handled);
+        assert_eq!(2, reader.message_processor.attempts);
+    }
+
+    #[test]
+    pub fn error_policy_skip_test() {
+        let mut reader = failing_reader("error_skip", u32::MAX, ErrorPolicy::Skip);
+        while reader.process_next().unwrap() {}
+        assert_eq!(vec![1, 3], reader.message_processor.handled);
+        assert_eq!(1, reader.message_processor.attempts);
+        assert_eq!(1, reader.skipped());
+        assert_eq!(2, reader.last_error().unwrap().0);
+    }
+
+    #[test]
+    pub fn error_policy_retry_test() {
+        let mut reader = failing_reader("error_retry", 2, ErrorPolicy::Retry(2));
+        while reader.process_next().unwrap() {}
+        assert_eq!(vec![1, 2, 3], reader.message_processor.handled);
+        assert_eq!(3, reader.message_processor.attempts);
+        assert_eq!(0, reader.skipped());
+
+        // Stops once it runs out of retries.
+        let mut reader = failing_reader("error_retry_out", 3, ErrorPolicy::Retry(2));
+        assert!(reader.process_next().unwrap());
+        assert!(matches!(
+            reader.process_next(),
+            Err(file::Error::ProcessFailed(2))
+        ));
+        assert_eq!(3, reader.message_processor.attempts);
+        assert_eq!(
+            Some((2, &ProcessError::new("attempt 3"))),
+            reader.last_error()
+        );
+    }
+
+    /// Creates a message file with message 3 missing.
+    /// # Arguments
+    /// `file_storage_directory` - The directory to put the file in.
+    /// `backend` - Where the file is kept.
+    fn create_gap_file(
+        file_storage_directory: &str,
+        backend: &dyn MessageStoreBackend,
+    ) -> Arc<Mutex<Vec<MessageFileInfo>>> {
+        let path = Path::new(file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(file_storage_directory).unwrap();
+        }
+        create_dir_all(file_storage_directory).unwrap();
+        let file_path = create_event_name(file_storage_directory, TEST_PREFIX, &1);
+        let (_, writer) = backend.create(Path::new(&file_path), 2048).unwrap();
+        let bytes: Vec<u8> = vec![10, 11, 12, 13, 14, 14, 16, 18];
+        let mut pos = 0;
+        for message_id in &[1, 2, 4, 5] {
+            pos = writer.write(pos, 1, *message_id, &bytes).unwrap();
+        }
+        writer.flush().unwrap();
+        Arc::new(Mutex::new(vec![MessageFileInfo::new(file_path, 1, 1)]))
+    }
+
+    /// Checks the gap is returned when reading the gap file.
+    fn check_message_iterator_gap(
+        file_storage_directory: &str,
+        backend: Arc<dyn MessageStoreBackend>,
+    ) {
+        let message_files = create_gap_file(file_storage_directory, backend.as_ref());
+        let mut iterator = MessageIterator::new(10, 1, 5, message_files, false, backend).unwrap();
+        let mut ids = Vec::new();
+        let mut gaps = Vec::new();
+        loop {
+            match iterator.next().unwrap() {
+                NextResult::Some(msg) => ids.push(msg.message_id()),
+                NextResult::Gap {
+                    expected,
+                    found,
+                    position,
+                } => gaps.push((expected, found, position)),
+                NextResult::End(_) => break,
+                _ => panic!("Unexpected result"),
+            }
+        }
+        assert_eq!(vec![1, 2, 4, 5], ids);
+        assert_eq!(vec![(3, 4, 64)], gaps);
+    }
+
+    #[test]
+    pub fn message_iterator_gap_test() {
+        check_message_iterator_gap(&format!("{}_gap", TEST_DIR), Arc::new(FileBackend));
+    }
+
+    #[test]
+    pub fn message_iterator_gap_in_memory_test() {
+        check_message_iterator_gap(
+            &format!("{}_gap_in_memory", TEST_DIR),
+            Arc::new(InMemoryMessageStore::new()),
+        );
+    }
+
+    #[test]
+    pub fn owned_iterator_test() {
+        let file_storage_directory = format!("{}_owned_iterator", TEST_DIR);
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(FileBackend);
+        let message_files = create_gap_file(&file_storage_directory, backend.as_ref());
+        let file_path = create_event_name(&file_storage_directory, TEST_PREFIX, &2);
+        {
+            let (_, writer) = backend.create(Path::new(&file_path), 2048).unwrap();
+            let mut pos = 0;
+            for message_id in 6..9u64 {
+                pos = writer
+                    .write(pos, 2, message_id, &message_id.to_le_bytes())
+                    .unwrap();
+            }
+            writer.flush().unwrap();
+        }
+        message_files
+            .lock()
+            .unwrap()
+            .push(MessageFileInfo::new(file_path, 2, 6));
+
+        // Goes across the end of the first file and skips the gap.
+        let messages =
+            MessageIterator::new(10, 1, 8, message_files.clone(), false, backend.clone())
+                .unwrap()
+                .into_owned()
+                .collect::<file::Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(
+            vec![1, 2, 4, 5, 6, 7, 8],
+            messages.iter().map(|m| m.message_id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![10, 11, 12, 13, 14, 14, 16, 18],
+            messages[0].message_body
+        );
+        assert_eq!(8u64.to_le_bytes().to_vec(), messages[6].message_body);
+        assert_eq!(2, messages[6].message_type);
+
+        // Stops at the number of messages and the committed id.
+        let mut iterator =
+            MessageIterator::new(3, 2, 8, message_files.clone(), false, backend.clone())
+                .unwrap()
+                .into_owned();
+        let first = iterator.next().unwrap().unwrap();
+        let second = iterator.next().unwrap().unwrap();
+        assert_eq!((2, 4), (first.message_id, second.message_id));
+        assert_eq!(5, iterator.next().unwrap().unwrap().message_id);
+        assert!(iterator.next().is_none());
+        let ids: Vec<u64> = MessageIterator::new(10, 5, 6, message_files, false, backend)
+            .unwrap()
+            .into_owned()
+            .map(|m| m.unwrap().message_id)
+            .collect();
+        assert_eq!(vec![5, 6], ids);
+    }
+
+    /// The file the message was found in before the files were searched with a binary search.
+    fn linear_starting_file(files: &[MessageFileInfo], start_message_id: u64) -> usize {
+        let mut last_file = 0;
+        for (x, file) in files.iter().enumerate() {
+            if file.removed {
+                continue;
+            }
+            if file.message_id_start > start_message_id {
+                break;
+            }
+            last_file = x;
+        }
+        last_file
+    }
+
+    #[test]
+    pub fn starting_file_index_test() {
+        let mut files: Vec<MessageFileInfo> = (0..1000u32)
+            .map(|i| {
+                MessageFileInfo::new(format!("test.events.{}", i + 1), i + 1, i as u64 * 10 + 5)
+            })
+            .collect();
+        // The files removed by the retention are at the front with a few removed in the middle.
+        for i in (0..100).chain(500..503) {
+            files[i].removed = true;
+        }
+        let last_id = files.last().unwrap().message_id_start;
+        for start in (0..last_id + 100).chain(vec![u64::MAX]) {
+            assert_eq!(
+                linear_starting_file(&files, start),
+                starting_file_index(&files, start),
+                "start {}",
+                start
+            );
+        }
+        // Before the files kept so the first file is returned and the iterator reports it removed.
+        assert_eq!(0, starting_file_index(&files, 0));
+        assert_eq!(0, starting_file_index(&files, 1004));
+        assert_eq!(100, starting_file_index(&files, 1005));
+        assert_eq!(499, starting_file_index(&files, 5024));
+        assert_eq!(999, starting_file_index(&files, u64::MAX));
+
+        // Nothing removed and the id is before the first file.
+        for file in files.iter_mut() {
+            file.removed = false;
+        }
+        assert_eq!(0, starting_file_index(&files, 0));
+        assert_eq!(0, starting_file_index(&files, 4));
+        assert_eq!(1, starting_file_index(&files, 15));
+    }
+
+    #[test]
+    pub fn seek_to_test() {
+        let file_storage_directory = format!("{}_seek_to", TEST_DIR);
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(FileBackend);
+        let message_files = create_gap_file(&file_storage_directory, backend.as_ref());
+        let file_path = create_event_name(&file_storage_directory, TEST_PREFIX, &2);
+        {
+            let (_, writer) = backend.create(Path::new(&file_path), 2048).unwrap();
+            let mut pos = 0;
+            for message_id in 6..9 {
+                pos = writer.write(pos, 1, message_id, &[1, 2, 3]).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+        message_files
+            .lock()
+            .unwrap()
+            .push(MessageFileInfo::new(file_path, 2, 6));
+        let mut iterator =
+            MessageIterator::new(10, 1, 8, message_files.clone(), false, backend).unwrap();
+        let next_id = |iterator: &mut MessageIterator| match iterator.next().unwrap() {
+            NextResult::Some(msg) => msg.message_id(),
+            _ => panic!("Expected a message"),
+        };
+        assert_eq!(1, next_id(&mut iterator));
+        // Forward and backward in the same file.
+        iterator.seek_to(5).unwrap();
+        assert_eq!(5, next_id(&mut iterator));
+        iterator.seek_to(2).unwrap();
+        assert_eq!(2, next_id(&mut iterator));
+        // Forward and backward across the files.
+        iterator.seek_to(7).unwrap();
+        assert_eq!(7, next_id(&mut iterator));
+        assert_eq!(8, next_id(&mut iterator));
+        iterator.seek_to(1).unwrap();
+        assert_eq!(1, next_id(&mut iterator));
+        assert_eq!(2, next_id(&mut iterator));
+
+        // The first file is gone so the messages in it can't be read.
+        message_files.lock().unwrap()[0].removed = true;
+        match iterator.seek_to(4) {
+            Err(file::Error::NotRetained {
+                message_id,
+                earliest,
+            }) => assert_eq!((4, 6), (message_id, earliest)),
+            _ => panic!("The removed messages should be reported"),
+        }
+        iterator.seek_to(6).unwrap();
+        assert_eq!(6, next_id(&mut iterator));
+    }
+
+    /// Checks the collection picks up the files added and removed by something else.
+    fn check_refresh(file_storage_directory: &str, backend: Arc<dyn MessageStoreBackend>) {
+        let message_files = create_gap_file(file_storage_directory, backend.as_ref());
+        let mut files = FileCollection::with_backend(
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            backend.clone(),
+        );
+        files.message_files = message_files;
+        assert!(files.refresh().unwrap().is_empty());
+
+        // Another process adds the next file.
+        let file_path = create_event_name(file_storage_directory, TEST_PREFIX, &2);
+        {
+            let (_, writer) = backend.create(Path::new(&file_path), 2048).unwrap();
+            let mut pos = 0;
+            for message_id in 6..9 {
+                pos = writer.write(pos, 1, message_id, &[1, 2, 3]).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+        let delta = files.refresh().unwrap();
+        assert_eq!(vec![2], delta.added_message_files);
+        assert!(delta.removed_message_files.is_empty());
+
+        let mut iterator = MessageIterator::new(
+            10,
+            1,
+            8,
+            files.message_files.clone(),
+            false,
+            backend.clone(),
+        )
+        .unwrap();
+        let mut ids = Vec::new();
+        loop {
+            match iterator.next().unwrap() {
+                NextResult::Some(msg) => ids.push(msg.message_id()),
+                NextResult::Gap { .. } => {}
+                NextResult::NextFile { file_id, .. } => {
+                    assert_eq!(2, file_id);
+                    break;
+                }
+                NextResult::End(_) | NextResult::More => panic!("Should have found the next file"),
+            }
+        }
+        let mut next = MessageIterator::new(
+            10,
+            6,
+            8,
+            files.message_files.clone(),
+            false,
+            backend.clone(),
+        )
+        .unwrap();
+        while let NextResult::Some(msg) = next.next().unwrap() {
+            ids.push(msg.message_id());
+        }
+        assert_eq!(vec![1, 2, 4, 5, 6, 7, 8], ids);
+
+        backend.remove(Path::new(&file_path)).unwrap();
+        let delta = files.refresh().unwrap();
+        assert_eq!(vec![2], delta.removed_message_files);
+        assert!(delta.added_message_files.is_empty());
+        assert!(files.refresh().unwrap().is_empty());
+        match iterator.next() {
+            Err(file::Error::FileRemoved(2)) => {}
+            _ => panic!("The removed file should be reported"),
+        }
+    }
+
+    #[test]
+    pub fn refresh_test() {
+        check_refresh(&format!("{}_refresh", TEST_DIR), Arc::new(FileBackend));
+    }
+
+    #[test]
+    pub fn refresh_in_memory_test() {
+        let stores = Arc::new(InMemoryMessageStore::new());
+        check_refresh(&format!("{}_refresh_in_memory", TEST_DIR), stores.clone());
+        // Nothing was written to the directory.
+        assert_eq!(1, stores.len());
+        assert!(read_dir(format!("{}_refresh_in_memory", TEST_DIR))
+            .unwrap()
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    pub fn message_iterator_strict_gap_test() {
+        let message_files = create_gap_file(&format!("{}_strict_gap", TEST_DIR), &FileBackend);
+        let mut iterator =
+            MessageIterator::new(10, 1, 5, message_files, true, Arc::new(FileBackend)).unwrap();
+        for id in 1..3 {
+            match iterator.next().unwrap() {
+                NextResult::Some(msg) => assert_eq!(id, msg.message_id()),
+                _ => panic!("Expected a message"),
+            }
+        }
+        match iterator.next() {
+            Err(file::Error::SequenceGap {
+                expected,
+                found,
+                position,
+            }) => {
+                assert_eq!(3, expected);
+                assert_eq!(4, found);
+                assert_eq!(64, position);
+            }
+            _ => panic!("Expected a sequence gap"),
+        }
+    }
+
+    #[test]
+    pub fn message_iterator_filter_test() {
+        let file_storage_directory = format!("{}_filter", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        create_dir_all(&file_storage_directory).unwrap();
+        let file_path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+        let (_, writer) = unsafe { MessageFileStore::new(&file_path, 2048).unwrap() };
+        let mut pos = 0;
+        for message_id in 1..=12u64 {
+            let msg_type = (message_id % 3) as i32 + 1;
+            pos = writer
+                .write(pos, msg_type, message_id, &message_id.to_le_bytes())
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        let message_files = Arc::new(Mutex::new(vec![MessageFileInfo::new(file_path, 1, 1)]));
+
+        let headers = Arc::new(Mutex::new(Vec::new()));
+        let seen = headers.clone();
+        let mut iterator =
+            MessageIterator::new(3, 1, 12, message_files, false, Arc::new(FileBackend)).unwrap();
+        iterator.set_filter(MessageFilter::of_types(&[1, 3]).with_predicate(move |h| {
+            seen.lock().unwrap().push(*h);
+            true
+        }));
+        let mut ids = Vec::new();
+        loop {
+            match iterator.next().unwrap() {
+                NextResult::Some(msg) => ids.push(msg.message_id()),
+                NextResult::More | NextResult::End(_) => break,
+                _ => panic!("Unexpected result"),
+            }
+        }
+        // Type 2 is every third message starting at 1 and they don't count against the number.
+        assert_eq!(vec![2, 3, 5], ids);
+        let headers = headers.lock().unwrap();
+        assert_eq!(3, headers.len());
+        for header in headers.iter() {
+            assert_eq!((header.message_id % 3) as i32 + 1, header.msg_type_id);
+            assert_eq!(8, header.size);
+            assert_eq!((header.message_id as usize - 1) * 32, header.position);
+        }
+    }
+
+    #[test]
+    pub fn recover_transaction_test() {
+        let file_storage_directory = format!("{}_recover_txn", TEST_DIR);
+        let crashed_directory = format!("{}_recover_txn_crashed", TEST_DIR);
+        for dir in [&file_storage_directory, &crashed_directory].iter() {
+            let path = Path::new(dir);
+            if path.exists() && path.is_dir() {
+                remove_dir_all(dir).unwrap();
+            }
+        }
+        create_dir_all(&file_storage_directory).unwrap();
+        let file_path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+        let (_, writer) = unsafe { MessageFileStore::new(&file_path, 2048).unwrap() };
+        let mut store = FaultyStore::wrap(&file_path, writer).unwrap();
+        let mut rng = seeded_rng(0);
+        let mut pos = store.write(0, 1, 1, &[1]).unwrap();
+        pos = store
+            .write(pos, TRANSACTION_MESSAGE_TYPE, 2, &[0, 0, 0, 2])
+            .unwrap();
+        pos = store.write(pos, 1, 3, &[3]).unwrap();
+        pos = store.write(pos, 1, 4, &[4]).unwrap();
+        store.flush().unwrap();
+        pos = store
+            .write(pos, TRANSACTION_MESSAGE_TYPE, 5, &[0, 0, 0, 2])
+            .unwrap();
+        pos = store.write(pos, 1, 6, &[6]).unwrap();
+        store.write(pos, 1, 7, &[7]).unwrap();
+
+        // Only the complete transaction was flushed.
+        let crashed = store
+            .materialize(&crashed_directory, Crash::LoseUnflushed, &mut rng)
+            .unwrap();
+        assert_eq!(4, recover_transaction(crashed.to_str().unwrap()).unwrap());
+
+        // Crashed before the last message in the transaction was written.
+        let crashed = store
+            .materialize(&crashed_directory, Crash::KeepWrites(2), &mut rng)
+            .unwrap();
+        let crashed = crashed.to_str().unwrap();
+        assert_eq!(4, recover_transaction(crashed).unwrap());
+        let reader = unsafe { MessageFileStore::open_readonly(&crashed).unwrap() };
+        match find_end_of_buffer(&reader).unwrap() {
+            FindEmptySlotResult::Pos(end, last_msg_id) => {
+                assert_eq!(128, end);
+                assert_eq!(4, last_msg_id);
+            }
+            _ => panic!("Did not find the end of the file."),
+        }
+
+        // The last message was torn so the transaction isn't complete.
+        let crashed = store
+            .materialize(&crashed_directory, Crash::TornLastWrite, &mut rng)
+            .unwrap();
+        assert_eq!(4, recover_transaction(crashed.to_str().unwrap()).unwrap());
+
+        let crashed = store
+            .materialize(&crashed_directory, Crash::KeepWrites(3), &mut rng)
+            .unwrap();
+        assert_eq!(7, recover_transaction(crashed.to_str().unwrap()).unwrap());
+    }
+
+    /// Fails after reading a number of bytes like a write that was stopped by a crash.
+    struct FailingReader<'a> {
+        inner: &'a [u8],
+        remaining: usize,
+    }
+
+    impl<'a> Read for FailingReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::other("Crashed"));
+            }
+            let length = buf.len().min(self.remaining);
+            let read = self.inner.read(&mut buf[..length])?;
+            self.remaining -= read;
+            Ok(read)
+        }
+    }
+
+    fn random_blob(length: usize) -> Vec<u8> {
+        let mut rng = seeded_rng(1);
+        (0..length).map(|_| rng.gen()).collect()
+    }
+
+    /// Opens the event files after a file.
+    fn next_files(
+        directory: String,
+        mut file_id: u32,
+    ) -> impl FnMut() -> Option<Arc<MessageFileStoreRead>> {
+        move || {
+            file_id += 1;
+            let path = create_event_name(&directory, TEST_PREFIX, &file_id);
+            unsafe { MessageFileStore::open_readonly(&path) }
+                .ok()
+                .map(Arc::new)
+        }
+    }
+
+    fn chunked_write_stream(directory: &str) -> PersistedMessageWriteStream {
+        let path = Path::new(directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(directory).unwrap();
+        }
+        create_dir_all(directory).unwrap();
+        PersistedMessageWriteStream::new(
+            1,
+            directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x10000,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    pub fn append_large_test() {
+        let directory = format!("{}_chunked", TEST_DIR);
+        let mut stream = chunked_write_stream(&directory);
+        stream.add_message(1, 1, &[1, 2, 3]).unwrap();
+        // 8 chunks with 3 in each of the first 2 files.
+        let blob = random_blob(120_000);
+        let (_, file_id) = stream
+            .append_large(2, 2, &mut &blob[..], blob.len() as u64)
+            .unwrap();
+        assert_eq!(3, file_id);
+        assert_eq!(2, stream.max_message_id.load(atomic::Ordering::Acquire));
+        stream.add_message(1, 3, &[4]).unwrap();
+
+        let path = create_event_name(&directory, TEST_PREFIX, &1);
+        let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+        let first = reader.read_new(0).unwrap();
+        assert_eq!(None, first.chunk());
+        let mut body = Vec::new();
+        first.body_reader().read_to_end(&mut body).unwrap();
+        assert_eq!(vec![1, 2, 3], body);
+
+        let msg = reader.read_new(first.next_pos()).unwrap();
+        assert_eq!(2, msg.message_id());
+        let chunk = msg.chunk().unwrap();
+        assert_eq!(2, chunk.msg_type_id);
+        assert_eq!(0, chunk.index);
+        assert_eq!(8, chunk.count);
+        // The reader can't go past the end of the file without being able to open the next one.
+        let mut body = Vec::new();
+        assert_eq!(
+            io::ErrorKind::UnexpectedEof,
+            msg.body_reader().read_to_end(&mut body).unwrap_err().kind()
+        );
+        let mut body_reader = msg
+            .body_reader()
+            .with_next_file(next_files(directory.clone(), 1));
+        assert_eq!(blob.len() as u64, body_reader.total_len());
+        let mut body = Vec::new();
+        body_reader.read_to_end(&mut body).unwrap();
+        assert_eq!(blob.len(), body.len());
+        assert_eq!(crc32fast::hash(&blob), crc32fast::hash(&body));
+
+        // The message after the chunks is in the last file.
+        let path = create_event_name(&directory, TEST_PREFIX, &3);
+        let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+        match find_end_of_buffer(&reader).unwrap() {
+            FindEmptySlotResult::Pos(_, last_msg_id) => assert_eq!(3, last_msg_id),
+            _ => panic!("Did not find the end of the file."),
+        }
+        assert_eq!(
+            3,
+            recover_chunked_message(&directory, TEST_PREFIX, &[1, 2, 3]).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn append_large_crash_test() {
+        let directory = format!("{}_chunked_crash", TEST_DIR);
+        let blob = random_blob(100_000);
+        let ids = [1, 2];
+        // Crashes while writing the chunks in the second file and then in the first file.
+        for (crash_at, last_file) in [(70_000, 2), (20_000, 1)].iter() {
+            let mut stream = chunked_write_stream(&directory);
+            stream.add_message(1, 1, &[1]).unwrap();
+            let mut reader = FailingReader {
+                inner: &blob[..],
+                remaining: *crash_at,
+            };
+            assert!(stream
+                .append_large(2, 2, &mut reader, blob.len() as u64)
+                .is_err());
+            assert_eq!(1, stream.max_message_id.load(atomic::Ordering::Acquire));
+            drop(stream);
+            let last_path = create_event_name(&directory, TEST_PREFIX, last_file);
+            assert!(Path::new(&last_path).exists());
+
+            // The whole message is removed and not just the last chunk.
+            assert_eq!(
+                1,
+                recover_chunked_message(&directory, TEST_PREFIX, &ids[..*last_file as usize])
+                    .unwrap()
+            );
+            assert!(!Path::new(&create_event_name(&directory, TEST_PREFIX, &2)).exists());
+            let path = create_event_name(&directory, TEST_PREFIX, &1);
+            assert_eq!(1, recover_transaction(&path).unwrap());
+            let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+            match find_end_of_buffer(&reader).unwrap() {
+                FindEmptySlotResult::Pos(end, last_msg_id) => {
+                    assert_eq!(32, end);
+                    assert_eq!(1, last_msg_id);
+                }
+                _ => panic!("Did not find the end of the file."),
+            }
+        }
+    }
+
+    #[test]
+    pub fn append_large_crash_reopen_test() {
+        let directory = format!("{}_chunked_crash_reopen", TEST_DIR);
+        let blob = random_blob(100_000);
+        let mut stream = chunked_write_stream(&directory);
+        stream.add_message(1, 1, &[1]).unwrap();
+        let mut reader = FailingReader {
+            inner: &blob[..],
+            remaining: 70_000,
+        };
+        assert!(stream
+            .append_large(2, 2, &mut reader, blob.len() as u64)
+            .is_err());
+        drop(stream);
+        // A compaction leaves a gap in the ids so the file before the last one isn't id - 1.
+        let gap = create_event_name(&directory, TEST_PREFIX, &5);
+        rename(create_event_name(&directory, TEST_PREFIX, &2), &gap).unwrap();
+
+        let file = PersistedMessageFile::new(
+            &directory,
+            TEST_PREFIX,
+            0x10000,
+            0x10000,
+            MessageProcessorInt::new(),
+        )
+        .unwrap();
+        assert!(!Path::new(&gap).exists());
+        assert_eq!(2, block_on(file.write(1, &[2])).unwrap().unwrap());
+        file.close().unwrap();
+
+        // A file the run points back to that can't be read is an error and not a panic.
+        assert!(recover_chunked_message(&directory, TEST_PREFIX, &[1, 3]).is_err());
+    }
+
+    /// Reads the messages in a file up to the first empty slot.
+    fn read_messages(path: &str) -> Vec<(i32, u64, Vec<u8>)> {
+        let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+        let mut messages = Vec::new();
+        let mut pos = 0;
+        loop {
+            match reader.read_new(pos) {
+                Ok(msg) if msg.message_id() == 0 => break messages,
+                Ok(msg) => {
+                    messages.push((msg.msg_type_id(), msg.message_id(), msg.bytes().to_vec()));
+                    pos = msg.next_pos();
+                }
+                Err(file::Error::NoMessage) => break messages,
+                Err(e) => panic!("Unable to read the message at {}: {:?}", pos, e),
+            }
+        }
+    }
+
+    #[test]
+    pub fn crash_recovery_test() {
+        let file_storage_directory = format!("{}_crash_recovery", TEST_DIR);
+        let crashed_directory = format!("{}_crash_recovery_crashed", TEST_DIR);
+        let seed = crash_seed();
+        let mut rng = seeded_rng(seed);
+        for _ in 0..100 {
+            for dir in [&file_storage_directory, &crashed_directory].iter() {
+                let path = Path::new(dir);
+                if path.exists() && path.is_dir() {
+                    remove_dir_all(dir).unwrap();
+                }
+            }
+            create_dir_all(&file_storage_directory).unwrap();
+            let file_path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+            let (_, writer) = unsafe { MessageFileStore::new(&file_path, 0x10000).unwrap() };
+            let mut store = FaultyStore::wrap(&file_path, writer).unwrap();
+            let mut written = Vec::new();
+            // The ids a message or transaction ends on.
+            let mut boundaries = vec![0];
+            let mut flushed_id = 0;
+            let mut pos = 0;
+            let mut message_id = 0;
+            for _ in 0..rng.gen_range(1, 20) {
+                if rng.gen_bool(0.3) {
+                    let count: u32 = rng.gen_range(1, 4);
+                    message_id += 1;
+                    let mut body = vec![0; 4];
+                    BigEndian::write_u32(&mut body, count);
+                    pos = store
+                        .write(pos, TRANSACTION_MESSAGE_TYPE, message_id, &body)
+                        .unwrap();
+                    written.push((TRANSACTION_MESSAGE_TYPE, message_id, body));
+                    for _ in 0..count {
+                        message_id += 1;
+                        let body: Vec<u8> = (0..rng.gen_range(1, 200)).map(|_| rng.gen()).collect();
+                        pos = store.write(pos, 1, message_id, &body).unwrap();
+                        written.push((1, message_id, body));
+                    }
+                } else {
+                    message_id += 1;
+                    let body: Vec<u8> = (0..rng.gen_range(1, 200)).map(|_| rng.gen()).collect();
+                    pos = store.write(pos, 1, message_id, &body).unwrap();
+                    written.push((1, message_id, body));
+                }
+                boundaries.push(message_id);
+                if rng.gen_bool(0.3) {
+                    store.flush().unwrap();
+                    flushed_id = message_id;
+                }
+            }
+            let crash = match rng.gen_range(0, 3) {
+                0 => Crash::LoseUnflushed,
+                1 => Crash::KeepWrites(rng.gen_range(0, store.unflushed_writes() + 1)),
+                _ => Crash::TornLastWrite,
+            };
+            let crashed = store
+                .materialize(&crashed_directory, crash, &mut rng)
+                .unwrap();
+            let crashed = crashed.to_str().unwrap();
+            let recovered_id = recover_transaction(crashed).unwrap();
+            let recovered = read_messages(crashed);
+
+            // The messages that survived are the ones written up to a message or transaction.
+            assert!(
+                recovered_id >= flushed_id,
+                "{:?} lost flushed messages with seed {}.",
+                crash,
+                seed
+            );
+            assert!(
+                boundaries.contains(&recovered_id),
+                "{:?} left part of a transaction with seed {}.",
+                crash,
+                seed
+            );
+            assert_eq!(
+                &written[..recovered_id as usize],
+                &recovered[..],
+                "Seed {}.",
+                seed
+            );
+
+            // Can keep writing after the messages that survived.
+            let reader = unsafe { MessageFileStore::open_readonly(&crashed).unwrap() };
+            let end = match find_end_of_buffer(&reader).unwrap() {
+                FindEmptySlotResult::Pos(end, last_msg_id) => {
+                    assert_eq!(recovered_id, last_msg_id);
+                    end
+                }
+                _ => panic!("Did not find the end of the file."),
+            };
+            let (_, writer) = unsafe { MessageFileStore::open(&crashed).unwrap() };
+            writer.write(end, 1, recovered_id + 1, &[1]).unwrap();
+            let mut expected = written[..recovered_id as usize].to_vec();
+            expected.push((1, recovered_id + 1, vec![1]));
+            assert_eq!(expected, read_messages(crashed), "Seed {}.", seed);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn create_single_node_processor() {
+        let file_storage_directory = format!("{}_single_node", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let processor = MessageProcessorInt::new();
+        let mut single_node = startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            5000,
+            5000,
+            processor,
+            0x40,
+            0x40,
+        );
+        let bytes: Vec<u8> = vec![10, 11, 12, 13, 14, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32];
+        let r = single_node.write(1, &bytes[0..8]).await;
+        r.unwrap().unwrap();
+        single_node.stop();
+    }
+
+    #[tokio::test]
+    pub async fn event_stream_test() {
+        let file_storage_directory = format!("{}_event_stream", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let mut single_node = startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            5000,
+            5000,
+            MessageProcessorInt::new(),
+            0x400,
+            0x40,
+        );
+        let values: Vec<Vec<u8>> = vec![vec![1, 2, 3], vec![4, 5], vec![6, 7, 8, 9]];
+        let mut keys = Vec::new();
+        for value in values.iter() {
+            keys.push(single_node.add_change(value).await.unwrap());
+        }
+        assert_eq!(vec![1, 2, 3], keys);
+        let events: Vec<Event> = single_node.events_from(keys[0]).collect();
+        assert_eq!(3, events.len());
+        for (event, (key, value)) in events.iter().zip(keys.iter().zip(values.iter())) {
+            assert_eq!(*key, event.commit_key);
+            assert_eq!(&value[..], event.value);
+        }
+        let events: Vec<u64> = single_node.events_from(2).map(|e| e.commit_key).collect();
+        assert_eq!(vec![2, 3], events);
+        single_node.stop();
+    }
+
+    #[tokio::test]
+    pub async fn backpressure_test() {
+        let file_storage_directory = format!("{}_backpressure", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let file = Arc::new(startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x10000,
+            0x10000,
+            MessageProcessorInt::new(),
+            0x4000,
+            0x400,
+        ));
+        file.set_watermarks(Some(Watermarks { high: 256, low: 64 }));
+        // Simulates a commit that can't keep up.
+        let slow_commit = file.backlog.reserve(512);
+        match file.try_write(1, &[1]) {
+            Err(WouldBlock { lag }) => assert_eq!(512, lag.pending_bytes),
+            Ok(_) => panic!("The write should have been rejected."),
+        }
+        let writers: Vec<_> = (0..4u8)
+            .map(|writer| {
+                let file = file.clone();
+                tokio::spawn(async move {
+                    let mut ids = Vec::new();
+                    for i in 0..25u8 {
+                        let mut body = vec![0; 100];
+                        body[0] = writer;
+                        body[1] = i;
+                        file.capacity().await;
+                        ids.push(file.write(1, &body).await.unwrap().unwrap());
+                    }
+                    ids
+                })
+            })
+            .collect();
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        // Everyone is waiting on the commit.
+        assert_eq!(0, file.written_message_id());
+        assert_eq!(1, file.backlog().pending);
+        drop(slow_commit);
+
+        let mut count = 0;
+        for w in writers {
+            let ids = w.await.unwrap();
+            assert!(ids.windows(2).all(|w| w[0] < w[1]));
+            count += ids.len();
+        }
+        assert_eq!(100, count);
+        assert_eq!(BacklogStatus::default(), file.backlog());
+        let mut next = [0u8; 4];
+        let mut cursor = CommittedCursor::new(1);
+        while let Some(msg) = cursor.next(&file) {
+            let writer = msg.bytes()[0] as usize;
+            assert_eq!(next[writer], msg.bytes()[1]);
+            next[writer] += 1;
+        }
+        assert_eq!([25; 4], next);
+        match Arc::try_unwrap(file) {
+            Ok(mut file) => file.stop(),
+            Err(_) => panic!("The writers should be done with the file."),
+        }
+    }
+
+    #[test]
+    pub fn event_stream_concurrent_test() {
+        let file_storage_directory = format!("{}_event_stream_concurrent", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let single_node = Arc::new(startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        ));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let node = single_node.clone();
+                thread::spawn(move || {
+                    let receivers: Vec<_> = (0..2500u64)
+                        .map(|i| node.add_change(&i.to_le_bytes()))
+                        .collect();
+                    receivers
+                        .into_iter()
+                        .map(|r| block_on(r).unwrap())
+                        .collect::<Vec<u64>>()
+                })
+            })
+            .collect();
+        let mut all_keys = Vec::new();
+        for t in threads {
+            let keys = t.join().unwrap();
+            assert!(keys.windows(2).all(|w| w[0] < w[1]));
+            all_keys.extend(keys);
+        }
+        all_keys.sort();
+        assert_eq!((1..=10_000).collect::<Vec<u64>>(), all_keys);
+        match Arc::try_unwrap(single_node) {
+            Ok(mut single_node) => single_node.stop(),
+            Err(_) => panic!("The node is still being used."),
+        }
+    }
+
+    #[test]
+    pub fn event_stream_stop_test() {
+        let file_storage_directory = format!("{}_event_stream_stop", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let mut single_node = startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        );
+        let bytes: Vec<u8> = vec![1, 2, 3, 4];
+        let receivers: Vec<_> = (0..1000).map(|_| single_node.add_change(&bytes)).collect();
+        single_node.stop();
+        let after_stop = single_node.add_change(&bytes);
+        let mut last_key = 0;
+        for r in receivers {
+            if let Ok(key) = block_on(r) {
+                assert!(key > last_key);
+                last_key = key;
+            }
+        }
+        assert!(block_on(after_stop).is_err());
+        match block_on(single_node.write(1, &bytes)) {
+            Ok(Err(crate::PersistError::Closed { .. })) => {}
+            _ => panic!("Expected the write to fail."),
+        }
+    }
+
+    /// Adds another change when it is woken up by the commit thread.
+    struct AddOnWake {
+        node: std::sync::Weak<PersistedMessageFile>,
+        added: Mutex<Vec<CommitFuture<u64>>>,
+    }
+
+    impl futures::task::ArcWake for AddOnWake {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            if let Some(node) = arc_self.node.upgrade() {
+                let next = node.add_change(&[5, 6, 7, 8]);
+                arc_self.added.lock().unwrap().push(next);
+            }
+        }
+    }
+
+    #[test]
+    pub fn commit_waker_reentrant_test() {
+        let file_storage_directory = format!("{}_commit_waker", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let single_node = Arc::new(startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        ));
+        let wake = Arc::new(AddOnWake {
+            node: Arc::downgrade(&single_node),
+            added: Mutex::new(Vec::new()),
+        });
+        let waker = futures::task::waker(wake.clone());
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut first = single_node.add_change(&[1, 2, 3, 4]);
+        // Tried again if the change was committed before the waker was set.
+        while Pin::new(&mut first).poll(&mut cx).is_ready() {
+            first = single_node.add_change(&[1, 2, 3, 4]);
+        }
+        // The waker runs on the commit thread and adds a change while the futures are completed.
+        let started = Instant::now();
+        while wake.added.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let first = block_on(first).unwrap();
+        let added = wake.added.lock().unwrap().pop().unwrap();
+        assert!(block_on(added).unwrap() > first);
+        assert_eq!(0, single_node.pending_commits());
+        drop(waker);
+        match Arc::try_unwrap(single_node) {
+            Ok(mut single_node) => single_node.stop(),
+            Err(_) => panic!("The node is still being used."),
+        }
+    }
+
+    #[test]
+    pub fn complete_pending_many_test() {
+        const FUTURES: u64 = 1_000_000;
+        let backlog = Arc::new(Backlog::new());
+        let queue = Arc::new(CommitQueue::new(0x10000));
+        let mut pending =
+            PendingCommits::new(queue.clone(), Arc::new(StoreLatency::new(system_clock())));
+        let mut receivers = Vec::with_capacity(FUTURES as usize);
+        for message_id in 1..=FUTURES {
+            let (sender, receiver) = oneshot::channel();
+            receivers.push(receiver);
+            let mut commit = AddMessageCommit::new(
+                message_id,
+                WriteComplete::Change(sender),
+                backlog.reserve(8),
+                0,
+            );
+            // Acts as the commit thread taking the futures when the queue fills up.
+            while let Err(back) = queue.offer(commit) {
+                pending.drain();
+                commit = back;
+            }
+        }
+        pending.drain();
+        assert_eq!(FUTURES, queue.pending());
+        // Nothing is completed until the messages are committed.
+        assert!(receivers[0].try_recv().unwrap().is_none());
+
+        assert_eq!(FUTURES as usize, pending.complete_to(FUTURES));
+        assert_eq!(0, queue.pending());
+        assert_eq!(0, backlog.status().pending);
+        for (i, mut receiver) in receivers.into_iter().enumerate() {
+            assert_eq!(Some(i as u64 + 1), receiver.try_recv().unwrap());
+        }
+    }
+
+    /// Starts a node in the directory and writes the messages without waiting for them.
+    fn start_and_write(
+        file_storage_directory: &str,
+        messages: u64,
+    ) -> (PersistedMessageFile, Vec<CommitFuture<u64>>) {
+        let single_node = startup_single_node(
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        );
+        let receivers = (0..messages)
+            .map(|i| single_node.add_change(&i.to_le_bytes()))
+            .collect();
+        (single_node, receivers)
+    }
+
+    /// Opens the store again and reads in all of the committed values.
+    fn reopen_values(file_storage_directory: &str) -> Vec<u64> {
+        let mut single_node = startup_single_node(
+            file_storage_directory.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        );
+        // The messages that were written but not committed are committed after we start.
+        let started = Instant::now();
+        while single_node.max_message_id() < single_node.written_message_id() {
+            assert!(started.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let values = single_node
+            .events_from(1)
+            .map(|event| {
+                let mut value = [0; 8];
+                value.copy_from_slice(&event.value[..8]);
+                u64::from_le_bytes(value)
+            })
+            .collect();
+        single_node.stop();
+        values
+    }
+
+    #[test]
+    pub fn find_by_time_test() {
+        let file_storage_directory = format!("{}_by_time", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let clock = Arc::new(a19_core::clock::ManualClock::new(1_000));
+        let store = builder::PersistedMessageFileBuilder::new(&file_storage_directory, TEST_PREFIX)
+            .max_file_size(0x400)
+            .clock(clock.clone())
+            .build(MessageProcessorInt::new())
+            .unwrap();
+        // The last batch was committed with a clock that is behind.
+        for (batch, time) in [1_000, 2_000, 3_000, 4_000, 3_800].iter().enumerate() {
+            clock.set_ms(*time);
+            for i in 0..10u64 {
+                let id = block_on(store.write(1, &(batch as u64 * 10 + i).to_le_bytes()))
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(batch as u64 * 10 + i + 1, id);
+            }
+        }
+        let ids = |start_ms: u64, end_ms: u64, slop_ms: u64| -> Option<Vec<u64>> {
+            store
+                .find_by_time_with_slop(start_ms, end_ms, slop_ms)
+                .unwrap()
+                .map(|iterator| {
+                    iterator
+                        .into_owned()
+                        .map(|msg| msg.unwrap().message_id)
+                        .collect()
+                })
+        };
+        // Inside the first file.
+        assert_eq!(Some((11..=20).collect()), ids(1_500, 2_500, 0));
+        // Spans the files.
+        assert!(store.find_term(50).unwrap().file_id > store.find_term(21).unwrap().file_id);
+        assert_eq!(Some((21..=50).collect()), ids(2_500, 4_500, 0));
+        // The batch that is behind is only picked up with enough slop.
+        assert_eq!(Some((31..=40).collect()), ids(3_900, 4_100, 0));
+        assert_eq!(Some((31..=50).collect()), ids(3_900, 4_100, 200));
+        // Before and after all of the messages.
+        assert_eq!(None, ids(0, 500, 0));
+        assert_eq!(None, ids(10_000, 20_000, 0));
+        assert_eq!(Some((1..=10).collect()), ids(0, 500, 500));
+        store.close().unwrap();
+    }
+
+    /// Writes 100 messages 10 milliseconds apart and applies the retention policy.
+    fn apply_retention_to(
+        name: &str,
+        policy: RetentionPolicy,
+    ) -> (PersistedMessageFile, RefreshDelta) {
+        let file_storage_directory = format!("{}_retention/{}", TEST_DIR, name);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let clock = Arc::new(a19_core::clock::ManualClock::new(1_000));
+        let store = builder::PersistedMessageFileBuilder::new(&file_storage_directory, TEST_PREFIX)
+            .max_file_size(0x400)
+            .commit_file_size(COMMIT_SIZE as usize * 4)
+            .clock(clock.clone())
+            .build(MessageProcessorInt::new())
+            .unwrap();
+        for i in 1..=100u64 {
+            clock.set_ms(1_000 + (i - 1) * 10);
+            assert_eq!(
+                i,
+                block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap()
+            );
+        }
+        let delta = store.apply_retention(policy).unwrap();
+        (store, delta)
+    }
+
+    /// The id of the first message kept and checks the rest of the messages can still be read.
+    fn first_kept(store: &PersistedMessageFile) -> u64 {
+        let first = store
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|f| !f.removed)
+            .unwrap()
+            .message_id_start
+            // The first file is created before it has any messages.
+            .max(1);
+        let ids: Vec<u64> = store.events_from(first).map(|e| e.commit_key).collect();
+        assert_eq!((first..=100).collect::<Vec<u64>>(), ids);
+        first
+    }
+
+    fn live_files(store: &PersistedMessageFile) -> usize {
+        store
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|f| !f.removed)
+            .count()
+    }
+
+    #[test]
+    pub fn retention_test() {
+        let (store, delta) = apply_retention_to("keep_all", RetentionPolicy::KeepAll);
+        assert!(delta.is_empty());
+        assert_eq!(1, first_kept(&store));
+        store.close().unwrap();
+
+        let (store, delta) = apply_retention_to("keep_files", RetentionPolicy::KeepFiles(2));
+        assert!(!delta.removed_message_files.is_empty());
+        assert!(!delta.removed_commit_files.is_empty());
+        assert_eq!(2, live_files(&store));
+        for file_id in delta.removed_message_files.iter() {
+            let path = create_event_name(&store.file_storage_directory, TEST_PREFIX, file_id);
+            assert!(!Path::new(&path).exists());
+        }
+        for file_id in delta.removed_commit_files.iter() {
+            let path = create_commit_name(&store.file_storage_directory, TEST_PREFIX, file_id);
+            assert!(!Path::new(&path).exists());
+        }
+        let first = first_kept(&store);
+        assert!(first > 1);
+        // The terms of the messages kept can still be found.
+        assert_eq!(Some(100), store.find_term(100).map(|t| t.max_message_id));
+        assert!(store.find_term(first).is_some());
+        assert!(store
+            .apply_retention(RetentionPolicy::KeepFiles(2))
+            .unwrap()
+            .is_empty());
+        store.close().unwrap();
+
+        let size = (0x400 + FILE_HEADER_SIZE) as u64;
+        let (store, _) = apply_retention_to("keep_bytes", RetentionPolicy::KeepBytes(size * 3));
+        assert_eq!(3, live_files(&store));
+        first_kept(&store);
+        store.close().unwrap();
+
+        let (store, delta) = apply_retention_to("keep_above", RetentionPolicy::KeepAboveId(50));
+        assert!(!delta.removed_message_files.is_empty());
+        let first = first_kept(&store);
+        assert!(first > 1 && first <= 51);
+        store.close().unwrap();
+
+        // Written from 1,000 to 1,990 so the messages before 1,690 are removed.
+        let policy = RetentionPolicy::KeepNewerThan(Duration::from_millis(300));
+        let (store, delta) = apply_retention_to("keep_newer", policy);
+        assert!(!delta.removed_message_files.is_empty());
+        let first = first_kept(&store);
+        assert!(first > 1 && first <= 70);
+        let ids: Vec<u64> = store
+            .find_by_time_with_slop(1_700, 2_000, 0)
+            .unwrap()
+            .unwrap()
+            .into_owned()
+            .map(|msg| msg.unwrap().message_id)
+            .collect();
+        assert_eq!((71..=100).collect::<Vec<u64>>(), ids);
+        store.close().unwrap();
+
+        // Picks up after the removed files when it is opened again.
+        let file_storage_directory = format!("{}_retention/keep_newer", TEST_DIR);
+        let mut store = startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x400,
+            COMMIT_SIZE as usize * 4,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        );
+        assert_eq!(first, first_kept(&store));
+        assert_eq!(
+            101,
+            block_on(store.write(1, &101u64.to_le_bytes()))
+                .unwrap()
+                .unwrap()
+        );
+        store.stop();
+    }
+
+    #[test]
+    pub fn message_store_test() {
+        let file_storage_directory = format!("{}_message_store", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let mut single_node = startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        );
+        let store: &dyn MessageStore = &single_node;
+        let first = store.add_message(&1, &[1, 2, 3]);
+        let second = store.add_message(&1, &[4, 5, 6]);
+        let ids = block_on(async { (first.await, second.await) });
+        assert_eq!((1, 2), (ids.0.unwrap().unwrap(), ids.1.unwrap().unwrap()));
+        // Only completed once the messages have been committed.
+        assert!(single_node.max_message_id() >= 2);
+
+        single_node.stop();
+        // Isn't left waiting once the store has stopped.
+        match block_on(single_node.add_message(&1, &[7])) {
+            Ok(Err(PersistError::Closed { .. })) => {}
+            other => panic!("Expected the store to be closed but got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn group_commit_test() {
+        let file_storage_directory = format!("{}_group_commit", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let start = |prefix: &str, group_commit: GroupCommit| {
+            builder::PersistedMessageFileBuilder::new(file_storage_directory.clone(), prefix)
+                .group_commit(Some(group_commit))
+                .build(MessageProcessorInt::new())
+                .unwrap()
+        };
+
+        // Each message is committed and flushed before the next is written.
+        let mut single = start(
+            "single",
+            GroupCommit {
+                max_messages: 1,
+                ..Default::default()
+            },
+        );
+        for i in 0..50u64 {
+            block_on(single.write(1, &i.to_le_bytes()))
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(50, single.flush_count());
+        single.stop();
+
+        // The count commits the batches so the 1000 messages need at most 10 flushes.  A batch
+        // can pick up more than 100 messages, so the last one might be short and wait for the
+        // timer.
+        let mut batched = start(
+            "batched",
+            GroupCommit {
+                max_messages: 100,
+                max_latency: Duration::from_secs(1),
+                ..Default::default()
+            },
+        );
+        let pending: Vec<_> = (0..1000u64)
+            .map(|i| batched.write(1, &i.to_le_bytes()))
+            .collect();
+        for (i, commit) in pending.into_iter().enumerate() {
+            assert_eq!(i as u64 + 1, block_on(commit).unwrap().unwrap());
+        }
+        assert_eq!(1000, batched.max_message_id());
+        assert!(batched.flush_count() <= 10);
+        batched.stop();
+
+        // The timer commits the batch that never fills up.
+        let mut timed = start(
+            "timed",
+            GroupCommit {
+                max_bytes: usize::MAX,
+                max_messages: u64::MAX,
+                max_latency: Duration::from_millis(1),
+            },
+        );
+        for i in 0..20u64 {
+            block_on(timed.write(1, &i.to_le_bytes())).unwrap().unwrap();
+        }
+        assert_eq!(20, timed.flush_count());
+        timed.stop();
+    }
+
+    #[test]
+    pub fn flush_policy_test() {
+        let file_storage_directory = format!("{}_flush_policy", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let write = |prefix: &str, flush_policy: FlushPolicy| {
+            let store =
+                builder::PersistedMessageFileBuilder::new(file_storage_directory.clone(), prefix)
+                    .flush_policy(Some(flush_policy))
+                    .build(MessageProcessorInt::new())
+                    .unwrap();
+            // Waits for each message so every term has one message in it.
+            for i in 0..20u64 {
+                block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap();
+            }
+            store
+        };
+
+        let mut every_commit = write("every_commit", FlushPolicy::EveryCommit);
+        assert_eq!(20, every_commit.flush_count());
+        every_commit.stop();
+
+        let mut every_n = write("every_n", FlushPolicy::EveryNMessages(5));
+        assert_eq!(4, every_n.flush_count());
+        every_n.stop();
+
+        let mut interval = write("interval", FlushPolicy::Interval(Duration::from_millis(50)));
+        let wait_for_flush = |store: &PersistedMessageFile, flushed: u64| {
+            let started = Instant::now();
+            while store.flush_count() == flushed && started.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(5));
+            }
+            store.flush_count()
+        };
+        assert!(wait_for_flush(&interval, 0) > 0);
+        // Gives the interval time to flush what is left of the messages.
+        thread::sleep(Duration::from_millis(150));
+        let flushed = interval.flush_count();
+        assert!(flushed < 20);
+        // The last message is flushed once the interval passes without anything written.
+        block_on(interval.write(1, &[1])).unwrap().unwrap();
+        assert_eq!(flushed + 1, wait_for_flush(&interval, flushed));
+        interval.stop();
+
+        let mut os = write("os", FlushPolicy::Os);
+        assert_eq!(0, os.flush_count());
+        // Flushes even though the policy never does.
+        assert_eq!(20, os.force_flush().unwrap());
+        assert_eq!(1, os.flush_count());
+        os.stop();
+    }
+
+    #[test]
+    pub fn close_test() {
+        let file_storage_directory = format!("{}_close", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let (single_node, receivers) = start_and_write(&file_storage_directory, 1000);
+        let report = single_node.close().unwrap();
+        assert_eq!(
+            CloseReport {
+                flushed_message_id: 1000,
+                written_message_id: 1000,
+                abandoned_writes: 0,
+                timed_out: false,
+            },
+            report
+        );
+        let keys: Vec<u64> = receivers.into_iter().map(|r| block_on(r).unwrap()).collect();
+        assert_eq!((1..=1000).collect::<Vec<u64>>(), keys);
+        assert_eq!((0..1000).collect::<Vec<u64>>(), reopen_values(&file_storage_directory));
+    }
+
+    #[test]
+    pub fn torn_write_recovery_test() {
+        use crate::file::{CHECKSUM_FLAG, CHECKSUM_SIZE, HEADER_SIZE};
+
+        let file_storage_directory = format!("{}_torn_write", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let file = PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            0x10000,
+            MessageProcessorInt::new(),
+        )
+        .unwrap();
+        for i in 1..=3u64 {
+            assert_eq!(i, block_on(file.write(1, &i.to_le_bytes())).unwrap().unwrap());
+        }
+        assert_eq!(
+            RecoveredPosition {
+                file_id: 1,
+                position: 0,
+                message_id: 0,
+            },
+            file.recovered_position()
+        );
+        file.close().unwrap();
+
+        // The size and the id of the 4th message made it to the disk but the body didn't.
+        let event_path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+        let mut bytes = std::fs::read(&event_path).unwrap();
+        let pos = FILE_HEADER_SIZE + 96;
+        let size = (HEADER_SIZE + CHECKSUM_SIZE + 8) as u32 | CHECKSUM_FLAG;
+        bytes[pos..pos + 4].copy_from_slice(&size.to_be_bytes());
+        bytes[pos + 4..pos + 8].copy_from_slice(&1i32.to_be_bytes());
+        bytes[pos + 8..pos + 16].copy_from_slice(&4u64.to_be_bytes());
+        std::fs::write(&event_path, &bytes).unwrap();
+
+        let file = PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            0x10000,
+            MessageProcessorInt::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            RecoveredPosition {
+                file_id: 1,
+                position: 96,
+                message_id: 3,
+            },
+            file.recovered_position()
+        );
+        assert_eq!(3, file.max_message_id());
+        assert_eq!(3, file.written_message_id());
+        assert_eq!(4, block_on(file.write(1, &4u64.to_le_bytes())).unwrap().unwrap());
+        assert_eq!(96, file.find_term(4).unwrap().position);
+        file.close().unwrap();
+
+        let reader = unsafe { MessageFileStore::open_readonly(&event_path).unwrap() };
+        let mut pos = 0;
+        for i in 1..=4u64 {
+            let msg = reader.read_new(pos).unwrap();
+            assert_eq!((i, &i.to_le_bytes()[..]), (msg.message_id(), msg.bytes()));
+            pos = msg.next_pos();
+        }
+        assert!(matches!(reader.read_new(pos), Err(file::Error::NoMessage)));
+    }
+
+    #[test]
+    pub fn new_reopen_test() {
+        let file_storage_directory = format!("{}_new_reopen", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let file = PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            0x10000,
+            MessageProcessorInt::new(),
+        )
+        .unwrap();
+        assert_eq!(1, block_on(file.write(1, &[1, 2, 3, 4])).unwrap().unwrap());
+        let first = file.find_term(1).unwrap();
+        drop(file);
+
+        let file = PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            0x10000,
+            MessageProcessorInt::new(),
+        )
+        .unwrap();
+        // The last commit is loaded before anything is written.
+        assert_eq!(1, file.max_message_id());
+        assert_eq!(first, file.find_term(1).unwrap());
+        assert_eq!(2, block_on(file.write(1, &[5, 6, 7, 8])).unwrap().unwrap());
+        let second = file.find_term(2).unwrap();
+        assert_eq!(first.file_id, second.file_id);
+        assert_eq!(first.position + first.length as u64, second.position);
+        file.close().unwrap();
+    }
+
+    #[test]
+    pub fn new_short_file_test() {
+        let file_storage_directory = format!("{}_new_short_file", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        create_dir_all(&file_storage_directory).unwrap();
+        let event_path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+        std::fs::write(&event_path, [0u8; 8]).unwrap();
+        match PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            0x10000,
+            MessageProcessorInt::new(),
+        ) {
+            Err(PersistError::Io { source, .. }) => {
+                assert_eq!(ErrorKind::InvalidData, source.kind())
+            }
+            Err(e) => panic!("Expected an io error but got {}", e),
+            Ok(_) => panic!("Expected the short file to be refused."),
+        }
+    }
+
+    #[test]
+    pub fn commit_rollover_test() {
+        let file_storage_directory = format!("{}_commit_rollover", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        // Each commit file only holds 2 terms.
+        let commit_file_size = COMMIT_SIZE as usize * 2;
+        let file = PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            commit_file_size,
+            MessageProcessorInt::new(),
+        )
+        .unwrap();
+        // Waiting on each write gives every message its own term.
+        for i in 1..=9u64 {
+            assert_eq!(i, block_on(file.write(1, &i.to_le_bytes())).unwrap().unwrap());
+        }
+        file.close().unwrap();
+
+        let collection = load_current_files(TEST_PREFIX, &file_storage_directory).unwrap();
+        let commit_files = collection.commit_files.lock().unwrap().clone();
+        assert_eq!(
+            vec![(1, 1, 1), (2, 3, 3), (3, 5, 5), (4, 7, 7), (5, 9, 9)],
+            commit_files
+                .iter()
+                .map(|f| (f.file_id, f.term_start, f.message_id))
+                .collect::<Vec<_>>()
+        );
+        match find_last_commit_pos(&collection.commit_files) {
+            LastCommitPos::LastCommit {
+                term_id,
+                file_id,
+                max_message_id,
+                ..
+            } => assert_eq!((9, 5, 9), (term_id, file_id, max_message_id)),
+            LastCommitPos::NoCommits => panic!("The terms should have been found."),
+        }
+
+        let file = PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            commit_file_size,
+            MessageProcessorInt::new(),
+        )
+        .unwrap();
+        assert_eq!(9, file.max_message_id());
+        assert_eq!(10, block_on(file.write(1, &10u64.to_le_bytes())).unwrap().unwrap());
+        assert_eq!(10, file.find_term(10).unwrap().max_message_id);
+        file.close().unwrap();
+    }
+
+    #[test]
+    pub fn term_checksum_test() {
+        let file_storage_directory = format!("{}_term_checksum", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let file = PersistedMessageFile::new(
+            &file_storage_directory,
+            TEST_PREFIX,
+            0x10000,
+            0x10000,
+            MessageProcessorInt::new(),
+        )
+        .unwrap();
+        for i in 1..=5u64 {
+            assert_eq!(i, block_on(file.write(1, &i.to_le_bytes())).unwrap().unwrap());
+        }
+        file.close().unwrap();
+
+        // Damage the last term like it was only partly written.
+        let commit_path = create_commit_name(&file_storage_directory, TEST_PREFIX, &1);
+        let mut bytes = std::fs::read(&commit_path).unwrap();
+        bytes[FILE_HEADER_SIZE + 4 * COMMIT_SIZE as usize + MAX_MESSAGE_ID + 7] ^= 0x20;
+        std::fs::write(&commit_path, &bytes).unwrap();
+        let buffer = unsafe {
+            open_commit_file(OpenOptions::new().read(true).write(true).open(&commit_path).unwrap())
+                .unwrap()
+        };
+        assert!(buffer.verify(3 * COMMIT_SIZE as usize));
+        assert!(!buffer.verify(4 * COMMIT_SIZE as usize));
+
+        let collection = load_current_files(TEST_PREFIX, &file_storage_directory).unwrap();
+        match find_last_commit_pos(&collection.commit_files) {
+            LastCommitPos::LastCommit {
+                term_id,
+                max_message_id,
+                ..
+            } => assert_eq!((4, 4), (term_id, max_message_id)),
+            LastCommitPos::NoCommits => panic!("The good terms should have been found."),
+        }
+
+        // A first term that is damaged means the file isn't used.
+        bytes[FILE_HEADER_SIZE + TERM_ID_OFFSET] ^= 0x01;
+        std::fs::write(&commit_path, &bytes).unwrap();
+        let collection = load_current_files(TEST_PREFIX, &file_storage_directory).unwrap();
+        assert!(collection.commit_files.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn latency_test() {
+        let file_storage_directory = format!("{}_latency", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let single_node = startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        );
+        let receivers: Vec<_> = (0..200u64)
+            .map(|i| single_node.write(1, &i.to_le_bytes()))
+            .collect();
+        for receiver in receivers {
+            block_on(receiver).unwrap().unwrap();
+        }
+        // The futures are recorded before they are completed.
+        let latency = single_node.latency();
+        assert_eq!(200, latency.commit.count());
+        assert!(latency.commit.percentile(50.0) <= latency.commit.percentile(99.0));
+        assert!(latency.commit.percentile(99.0) <= latency.commit.max());
+        let started = Instant::now();
+        while single_node.latency().handled.count() < 200 {
+            if started.elapsed() > Duration::from_secs(5) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The reader can get to a message before the writer leaves its time.
+        let handled = single_node.latency_and_reset().handled;
+        assert!(handled.count() > 0 && handled.count() <= 200);
+        assert_eq!(0, single_node.latency().commit.count());
+        single_node.close().unwrap();
+    }
+
+    #[test]
+    pub fn event_bus_test() {
+        use a19_concurrent::event_bus::{EventBus, OverflowPolicy};
+
+        let file_storage_directory = format!("{}_event_bus", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let single_node = startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x10000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        );
+        let bus = EventBus::new();
+        let all = bus.subscribe_with(0x4000, OverflowPolicy::DropNewest);
+        let rolled = bus.subscribe_where(16, OverflowPolicy::DropNewest, |e| {
+            matches!(e, StoreEvent::FileRolled { .. })
+        });
+        single_node.set_event_bus(bus);
+        let body = [7u8; 2000];
+        let transaction = || {
+            let mut batch = TransactionBatch::new();
+            batch.add(1, &body);
+            batch.add(1, &body);
+            single_node.write_transaction(&batch)
+        };
+        // Stays in the first file.
+        for _ in 0..10 {
+            block_on(transaction()).unwrap().unwrap();
+        }
+        let mut last_term = 0;
+        while let Some(event) = all.poll() {
+            match event {
+                StoreEvent::TermCommitted { term_id } => {
+                    assert_eq!(last_term + 1, term_id);
+                    last_term = term_id;
+                }
+                e => panic!("Unexpected event {:?}", e),
+            }
+        }
+        assert!(last_term > 0);
+        assert!(rolled.poll().is_none());
+
+        // Only waits for the writer to roll the file.
+        let pending: Vec<_> = (0..10).map(|_| transaction()).collect();
+        let started = Instant::now();
+        let file_id = loop {
+            if let Some(event) = rolled.poll() {
+                break event;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "The file wasn't rolled.");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(StoreEvent::FileRolled { file_id: 2 }, file_id);
+        assert_eq!(0, all.dropped());
+        single_node.close_with(ClosePolicy::Abandon).unwrap();
+        drop(pending);
+    }
+
+    #[test]
+    pub fn position_index_test() {
+        let file_storage_directory = format!("{}_position_index", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let single_node = startup_single_node(
+            file_storage_directory,
+            TEST_PREFIX.to_owned(),
+            0x100000,
+            0x200000,
+            MessageProcessorInt::new(),
+            0x40000,
+            0x4000,
+        );
+        let receivers: Vec<_> = (0..1000u64)
+            .map(|i| single_node.write(1, &i.to_le_bytes()))
+            .collect();
+        for receiver in receivers {
+            block_on(receiver).unwrap().unwrap();
+        }
+        let indexed: Vec<u64> = single_node.positions.iter().map(|(id, _)| id).collect();
+        assert_eq!(vec![1, 257, 513, 769], indexed);
+        let (floor_id, (file_id, pos)) = single_node.positions.floor_entry(&700).unwrap();
+        assert_eq!((513, 1), (floor_id, file_id));
+        assert!(pos > 0);
+
+        // Starts at the indexed position and still finds the exact message.
+        let mut cursor = CommittedCursor::new(700);
+        let msg = cursor.next(&single_node).unwrap();
+        assert_eq!(700, msg.message_id());
+        assert_eq!(699u64.to_le_bytes(), msg.bytes()[..8]);
+        let events: Vec<u64> = single_node.events_from(990).map(|e| e.commit_key).collect();
+        assert_eq!((990..=1000).collect::<Vec<u64>>(), events);
+        single_node.close().unwrap();
+    }
+
+    #[test]
+    pub fn close_abandon_test() {
+        let file_storage_directory = format!("{}_close_abandon", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let (single_node, receivers) = start_and_write(&file_storage_directory, 1000);
+        let report = single_node.close_with(ClosePolicy::Abandon).unwrap();
+        assert!(!report.timed_out);
+        assert!(report.flushed_message_id <= report.written_message_id);
+        let committed = receivers
+            .into_iter()
+            .filter_map(|r| block_on(r).ok())
+            .count();
+        // Every write was either committed or reported as abandoned.
+        assert!(committed as u64 >= report.flushed_message_id);
+        assert_eq!(1000, committed + report.abandoned_writes);
+        // The messages that made it to the event file are committed when opened again.
+        let values = reopen_values(&file_storage_directory);
+        assert_eq!(report.written_message_id, values.len() as u64);
+        assert_eq!((0..values.len() as u64).collect::<Vec<u64>>(), values);
+    }
+
+    #[test]
+    pub fn drop_test() {
+        let file_storage_directory = format!("{}_drop", TEST_DIR);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let (single_node, receivers) = start_and_write(&file_storage_directory, 1000);
+        drop(single_node);
+        // The drop waits for the writes to be committed.
+        for r in receivers {
+            block_on(r).unwrap();
+        }
+        assert_eq!((0..1000).collect::<Vec<u64>>(), reopen_values(&file_storage_directory));
     }
 
     struct MessageProcessorInt {
@@ -2324,7 +9325,7 @@ mod tests {
 
     unsafe impl Send for MessageProcessorInt {}
 
-    impl MessageProcessor for MessageProcessorInt {
+    impl InfallibleProcessor for MessageProcessorInt {
         fn handle<'a>(&mut self, read: &MessageRead<'a>) {
             self.ran = true;
             self.last_message_id = read.message_id();
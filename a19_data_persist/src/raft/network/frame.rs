@@ -0,0 +1,374 @@
+//! Decodes the frames received over the network.  The input can't be trusted so every read is
+//! checked against the length of the frame and nothing is allocated while decoding.  A frame is
+//! rejected before it is dispatched if the size is over the max frame length, the checksum doesn't
+//! match or it was written with a different version of the protocol.
+use crate::raft::network::*;
+use crate::raft::state_machine::RaftEvent;
+use crc32fast::Hasher;
+
+/// The size of the checksum at the end of every frame.
+pub(crate) const CRC_SIZE: usize = 4;
+/// The default max length of a frame.
+pub(crate) const DEFAULT_MAX_FRAME_LENGTH: usize = MAX_DATA_FRAME_SIZE + CRC_SIZE;
+
+/// The errors when decoding a frame.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum FrameError {
+    /// Need more bytes before the frame can be decoded.
+    Incomplete { needed: usize },
+    /// The frame is over the max frame length.  The connection should be closed.
+    TooLarge { size: usize, max: usize },
+    /// The frame size is too small to contain the header and checksum.
+    InvalidFrameSize { size: usize },
+    /// The checksum on the frame doesn't match.
+    InvalidChecksum { expected: u32, actual: u32 },
+    /// The frame was written with a version we don't support.
+    UnsupportedVersion { version: u8 },
+    /// The type of the frame isn't known.
+    UnknownType { type_id: i16 },
+    /// The frame ended before a field could be read.
+    Truncated { position: usize },
+}
+
+/// A frame that has been decoded.
+#[derive(Debug)]
+pub(crate) enum Frame<'a> {
+    /// A raft event for the state machine.
+    Event(RaftEvent),
+    /// A server connecting to us.
+    Hello { server_id: u32, version: u8 },
+    /// The server we connected to doesn't support our version.
+    VersionMismatch { server_id: u32, supported_version: u8 },
+    /// The messages for a term.
+    Data {
+        server_id: u32,
+        term_id: u64,
+        file_id: u32,
+        file_offset: u64,
+        data: &'a [u8],
+    },
+}
+
+/// Calculates the checksum for a frame.
+/// # Arguments
+/// `bytes` - The bytes of the frame without the checksum.
+pub(crate) fn frame_checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+/// Writes the checksum into the last 4 bytes of the frame.
+/// # Arguments
+/// `frame` - The frame to write the checksum to.
+pub(crate) fn write_checksum(frame: &mut [u8]) {
+    let crc_pos = frame.len() - CRC_SIZE;
+    let crc = frame_checksum(&frame[..crc_pos]);
+    BigEndian::write_u32(&mut frame[crc_pos..], crc);
+}
+
+#[inline]
+fn read_u8(buffer: &[u8], position: usize) -> Result<u8, FrameError> {
+    buffer
+        .get(position)
+        .copied()
+        .ok_or(FrameError::Truncated { position })
+}
+
+#[inline]
+fn read_i16(buffer: &[u8], position: usize) -> Result<i16, FrameError> {
+    buffer
+        .get(position..position + 2)
+        .map(BigEndian::read_i16)
+        .ok_or(FrameError::Truncated { position })
+}
+
+#[inline]
+fn read_u32(buffer: &[u8], position: usize) -> Result<u32, FrameError> {
+    buffer
+        .get(position..position + 4)
+        .map(BigEndian::read_u32)
+        .ok_or(FrameError::Truncated { position })
+}
+
+#[inline]
+fn read_u64(buffer: &[u8], position: usize) -> Result<u64, FrameError> {
+    buffer
+        .get(position..position + 8)
+        .map(BigEndian::read_u64)
+        .ok_or(FrameError::Truncated { position })
+}
+
+/// Decodes the frames from a stream of bytes.
+pub(crate) struct FrameDecoder {
+    /// The max size of a frame we will accept.
+    max_frame_length: usize,
+    /// The version of the protocol we support.
+    version: u8,
+}
+
+impl FrameDecoder {
+    /// Creates a decoder for the current version of the protocol.
+    /// # Arguments
+    /// `max_frame_length` - The max size of a frame to accept.
+    pub fn new(max_frame_length: usize) -> Self {
+        FrameDecoder::with_version(max_frame_length, CURRENT_VERSION)
+    }
+
+    /// Creates a decoder for a specific version of the protocol.
+    /// # Arguments
+    /// `max_frame_length` - The max size of a frame to accept.
+    /// `version` - The version of the protocol to accept.
+    pub fn with_version(max_frame_length: usize, version: u8) -> Self {
+        FrameDecoder {
+            max_frame_length,
+            version,
+        }
+    }
+
+    /// Decodes the next frame in the buffer.
+    /// # Arguments
+    /// `buffer` - The bytes received.
+    /// # returns
+    /// The frame and the number of bytes it used.
+    pub fn decode<'a>(&self, buffer: &'a [u8]) -> Result<(Frame<'a>, usize), FrameError> {
+        if buffer.len() < STOP_FRAME {
+            return Err(FrameError::Incomplete {
+                needed: STOP_FRAME - buffer.len(),
+            });
+        }
+        let size = read_u32(buffer, POS_FRAME)? as usize;
+        if size > self.max_frame_length {
+            return Err(FrameError::TooLarge {
+                size,
+                max: self.max_frame_length,
+            });
+        }
+        if size < HEADER_SIZE {
+            return Err(FrameError::InvalidFrameSize { size });
+        }
+        if buffer.len() < size {
+            return Err(FrameError::Incomplete {
+                needed: size - buffer.len(),
+            });
+        }
+        let frame = &buffer[..size];
+        let crc_pos = size - CRC_SIZE;
+        let expected = read_u32(frame, crc_pos)?;
+        let actual = frame_checksum(&frame[..crc_pos]);
+        if expected != actual {
+            return Err(FrameError::InvalidChecksum { expected, actual });
+        }
+        let version = read_u8(frame, POS_VERSION)?;
+        let type_id = read_i16(frame, POS_TYPE)?;
+        let server_id = read_u32(frame, POS_SERVER)?;
+        // Always decode the version mismatch so the other side can tell why we closed.
+        if type_id == VERSION_MISMATCH {
+            let supported_version = read_u8(frame, STOP_SERVER)?;
+            return Ok((
+                Frame::VersionMismatch {
+                    server_id,
+                    supported_version,
+                },
+                size,
+            ));
+        }
+        if version != self.version {
+            return Err(FrameError::UnsupportedVersion { version });
+        }
+        let decoded = match type_id {
+            COMMITED => Frame::Event(RaftEvent::Commited {
+                server_id,
+                term_id: read_u64(frame, STOP_SERVER)?,
+            }),
+            ELECTED_LEADER => Frame::Event(RaftEvent::ElectedLeader { server_id }),
+            FOLLOWER_INDEX => Frame::Event(RaftEvent::FollowerIndex {
+                server_id,
+                term_id: read_u64(frame, STOP_SERVER)?,
+            }),
+            PING => Frame::Event(RaftEvent::Ping {
+                server_id,
+                max_commited_term: read_u64(frame, STOP_SERVER)?,
+                round: read_u64(frame, STOP_SERVER + 8)?,
+            }),
+            PONG => Frame::Event(RaftEvent::Pong {
+                server_id,
+                max_term_id: read_u64(frame, STOP_SERVER)?,
+                round: read_u64(frame, STOP_SERVER + 8)?,
+            }),
+            VOTE_FOR_CANDIATE => Frame::Event(RaftEvent::VoteForCandiate { server_id }),
+            VOTE_FOR_ME => Frame::Event(RaftEvent::VoteForMe {
+                server_id,
+                max_term_id: read_u64(frame, STOP_SERVER)?,
+            }),
+            SETUP_FRAME_FOR_SERVER => Frame::Hello { server_id, version },
+            READ_INDEX_REQUEST => Frame::Event(RaftEvent::ReadIndexRequest {
+                server_id,
+                request_id: read_u64(frame, STOP_SERVER)?,
+            }),
+            READ_INDEX_RESPONSE => Frame::Event(RaftEvent::ReadIndexResponse {
+                server_id,
+                request_id: read_u64(frame, STOP_SERVER)?,
+                commit_term: read_u64(frame, STOP_SERVER + 8)?,
+            }),
+            TIMEOUT_NOW => Frame::Event(RaftEvent::TimeoutNow { server_id }),
+            DATA_FRAME => {
+                if crc_pos < HEADER_SIZE {
+                    return Err(FrameError::InvalidFrameSize { size });
+                }
+                Frame::Data {
+                    server_id,
+                    term_id: read_u64(frame, STOP_SERVER)?,
+                    file_id: read_u32(frame, STOP_SERVER + 8)?,
+                    file_offset: read_u64(frame, STOP_SERVER + 12)?,
+                    data: &frame[HEADER_SIZE..crc_pos],
+                }
+            }
+            _ => {
+                return Err(FrameError::UnknownType { type_id });
+            }
+        };
+        Ok((decoded, size))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::raft::network::frame::*;
+    use rand::{thread_rng, Rng, RngCore};
+
+    #[test]
+    fn decode_event_test() {
+        let mut encoder = RaftEventEncoder::new(3);
+        assert!(encoder.write(RaftEvent::ReadIndexResponse {
+            server_id: 3,
+            request_id: 7,
+            commit_term: 9,
+        }));
+        let decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_LENGTH);
+        match decoder.decode(encoder.buffer()).unwrap() {
+            (
+                Frame::Event(RaftEvent::ReadIndexResponse {
+                    server_id,
+                    request_id,
+                    commit_term,
+                }),
+                size,
+            ) => {
+                assert_eq!(3, server_id);
+                assert_eq!(7, request_id);
+                assert_eq!(9, commit_term);
+                assert_eq!(HEADER_SIZE, size);
+            }
+            _ => {
+                panic!("Expected the read index response!");
+            }
+        }
+    }
+
+    #[test]
+    fn decode_invalid_frame_test() {
+        let mut encoder = RaftEventEncoder::new(3);
+        encoder.write(RaftEvent::Ping {
+            server_id: 3,
+            max_commited_term: 1,
+            round: 1,
+        });
+        let decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_LENGTH);
+        let mut bytes = encoder.buffer().to_vec();
+        assert_eq!(
+            FrameError::Incomplete { needed: 1 },
+            decoder.decode(&bytes[..31]).unwrap_err()
+        );
+        bytes[STOP_SERVER] = 1;
+        match decoder.decode(&bytes).unwrap_err() {
+            FrameError::InvalidChecksum { .. } => {}
+            e => {
+                panic!("Expected an invalid checksum, got {:?}", e);
+            }
+        }
+        let small = FrameDecoder::new(16);
+        assert_eq!(
+            FrameError::TooLarge { size: 32, max: 16 },
+            small.decode(encoder.buffer()).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn reject_newer_version_test() {
+        // A v2 node says hello to a v1 node.
+        let mut v2 = RaftEventEncoder::new(2);
+        v2.msg_buffer[POS_VERSION] = 2;
+        v2.write_setup();
+        let v1 = FrameDecoder::new(DEFAULT_MAX_FRAME_LENGTH);
+        assert_eq!(
+            FrameError::UnsupportedVersion { version: 2 },
+            v1.decode(v2.buffer()).unwrap_err()
+        );
+
+        // The v1 node sends back the version it supports.
+        let mut encoder = RaftEventEncoder::new(1);
+        encoder.write_version_mismatch();
+        let v2_decoder = FrameDecoder::with_version(DEFAULT_MAX_FRAME_LENGTH, 2);
+        match v2_decoder.decode(encoder.buffer()).unwrap() {
+            (
+                Frame::VersionMismatch {
+                    server_id,
+                    supported_version,
+                },
+                _,
+            ) => {
+                assert_eq!(1, server_id);
+                assert_eq!(CURRENT_VERSION, supported_version);
+            }
+            _ => {
+                panic!("Expected a version mismatch!");
+            }
+        }
+    }
+
+    /// Decodes all of the frames in the buffer.  Returns false if a frame couldn't be decoded.
+    fn decode_all(decoder: &FrameDecoder, buffer: &[u8]) -> bool {
+        let mut pos = 0;
+        while pos < buffer.len() {
+            match decoder.decode(&buffer[pos..]) {
+                Ok((_, size)) => {
+                    assert!(size <= 128);
+                    pos += size;
+                }
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn fuzz_decode_test() {
+        let decoder = FrameDecoder::new(128);
+        let mut rng = thread_rng();
+        let mut buffer = [0u8; 256];
+        for _ in 0..10_000 {
+            let length = rng.gen_range(0, buffer.len());
+            rng.fill_bytes(&mut buffer[..length]);
+            decode_all(&decoder, &buffer[..length]);
+        }
+
+        let mut encoder = RaftEventEncoder::new(2);
+        encoder.write(RaftEvent::VoteForMe {
+            server_id: 2,
+            max_term_id: 10,
+        });
+        let valid = encoder.buffer().to_vec();
+        assert!(decode_all(&decoder, &valid));
+        for _ in 0..10_000 {
+            let mut mutated = valid.clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let pos = rng.gen_range(0, mutated.len());
+                mutated[pos] = rng.gen();
+            }
+            let length = rng.gen_range(0, mutated.len() + 1);
+            decode_all(&decoder, &mutated[..length]);
+        }
+    }
+}
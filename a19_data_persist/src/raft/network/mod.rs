@@ -24,6 +24,37 @@
 //! | Server Id                                                     |
 //! +---------------------------------------------------------------+
 //! ```
+//!
+//! The last 4 bytes of every frame are a CRC32 of the rest of the frame.  For the 32 byte frames
+//! the checksum is in the last row marked as not used.  For data frames the checksum comes after
+//! the data.  A frame with a bad checksum, an unknown version or a size over the max frame length
+//! is rejected before it is dispatched.
+//!
+//! # Version Mismatch
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Frame size                                                    |
+//! +---------------+---------------+-------------------------------+ 32
+//! | Version       | Flags         | Type                          |
+//! +---------------+---------------+-------------------------------+ 64
+//! | Server Id                                                     |
+//! +---------------+-----------------------------------------------+ 96
+//! | Supported     |                                               |
+//! +---------------+                                               | 128
+//! |                                                               |
+//! |                                                               | 160
+//! |                                                               |
+//! |                                                               | 192
+//! |                                                               |
+//! +---------------------------------------------------------------+ 224
+//! | CRC                                                           |
+//! +---------------------------------------------------------------+ 256
+//! ```
+//! Sent back when a setup frame has a version we don't support before the connection is closed.
+//! * Supported - The version of the protocol we support.
 //! # Setup Frame
 //!
 //! A setup frame is used to setup the connection to a node.  Since we are using raft we need to
@@ -246,17 +277,18 @@
 //! +---------------+---------------+-------------------------------+ 64
 //! | Server Id                                                     |
 //! +---------------------------------------------------------------+ 96
-//! | Max Committed Term Id                                         |
+//! | Max Term Id                                                   |
 //! |                                                               | 128
 //! |                                                               |
 //! +---------------------------------------------------------------+ 160
-//! |                                                               |
+//! | Heartbeat Round                                               |
 //! |                                                               | 192
 //! |                                                               |
-//! |                                                               | 224
+//! +---------------------------------------------------------------+ 224
 //! | Not Used                                                      |
 //! +---------------------------------------------------------------+ 256
 //! ```
+//! The pong carries the round of the ping it is responding to.
 //! # Ping
 //!
 //! ```text
@@ -273,13 +305,14 @@
 //! |                                                               | 128
 //! |                                                               |
 //! +---------------------------------------------------------------+ 160
-//! |                                                               |
+//! | Heartbeat Round                                               |
 //! |                                                               | 192
 //! |                                                               |
-//! |                                                               | 224
+//! +---------------------------------------------------------------+ 224
 //! | Not Used                                                      |
 //! +---------------------------------------------------------------+ 256
 //! ```
+//! The round goes up with every ping so the leader knows which pings a pong is for.
 //! # Request Term
 //!
 //! ```text
@@ -304,10 +337,97 @@
 //! +---------------------------------------------------------------+ 256
 //! ```
 //! Request a missing term(s) from the server.
+//!
+//! # Read Index Request
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Frame size                                                    |
+//! +---------------+---------------+-------------------------------+ 32
+//! | Version       | Flags         | Type                          |
+//! +---------------+---------------+-------------------------------+ 64
+//! | Server Id                                                     |
+//! +---------------------------------------------------------------+ 96
+//! | Request Id                                                    |
+//! |                                                               | 128
+//! |                                                               |
+//! +---------------------------------------------------------------+ 160
+//! |                                                               |
+//! |                                                               | 192
+//! |                                                               |
+//! |                                                               | 224
+//! | Not Used                                                      |
+//! +---------------------------------------------------------------+ 256
+//! ```
+//! Request the read index from the leader so a follower can serve a linearizable read.
+//!
+//! # Read Index Response
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Frame size                                                    |
+//! +---------------+---------------+-------------------------------+ 32
+//! | Version       | Flags         | Type                          |
+//! +---------------+---------------+-------------------------------+ 64
+//! | Server Id                                                     |
+//! +---------------------------------------------------------------+ 96
+//! | Request Id                                                    |
+//! |                                                               | 128
+//! |                                                               |
+//! +---------------------------------------------------------------+ 160
+//! | Commit Term Id                                                |
+//! |                                                               | 192
+//! |                                                               |
+//! +---------------------------------------------------------------+ 224
+//! | Not Used                                                      |
+//! +---------------------------------------------------------------+ 256
+//! ```
+//! The committed term after the leader confirmed it is still the leader.  The follower serves the
+//! read after it has committed up to the term.
+//!
+//! # Timeout Now
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Frame size                                                    |
+//! +---------------+---------------+-------------------------------+ 32
+//! | Version       | Flags         | Type                          |
+//! +---------------+---------------+-------------------------------+ 64
+//! | Server Id                                                     |
+//! +---------------------------------------------------------------+ 96
+//! |                                                               |
+//! |                                                               | 128
+//! |                                                               |
+//! |                                                               | 160
+//! |                                                               |
+//! |                                                               | 192
+//! |                                                               |
+//! |                                                               | 224
+//! | Not Used                                                      |
+//! +---------------------------------------------------------------+ 256
+//! ```
+//! Sent by the leader to the server it is transferring the leadership to.  The server starts an
+//! election right away.
+#[allow(dead_code)]
+pub(crate) mod frame;
+#[allow(dead_code)]
+pub(crate) mod peer;
+#[allow(dead_code)]
+pub(crate) mod udp;
+
 use crate::file::MessageFileStoreRead;
+use crate::raft::network::frame::{write_checksum, CRC_SIZE};
+use crate::raft::network::peer::PeerConnection;
 use crate::raft::state_machine::{RaftEvent, RaftStateMachineClient};
 use a19_concurrent::queue::spsc_queue::SpscQueueReceiveWrap;
 use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
 use std::sync::Arc;
 use zmq::Socket;
 
@@ -343,6 +463,14 @@ const SETUP_FRAME_FOR_SERVER: i16 = 8;
 #[allow(dead_code)]
 const SETUP_FRAME_RESPONSE: i16 = 9;
 #[allow(dead_code)]
+const READ_INDEX_REQUEST: i16 = 10;
+#[allow(dead_code)]
+const READ_INDEX_RESPONSE: i16 = 11;
+#[allow(dead_code)]
+const TIMEOUT_NOW: i16 = 12;
+#[allow(dead_code)]
+const VERSION_MISMATCH: i16 = 13;
+#[allow(dead_code)]
 const DATA_FRAME: i16 = 20;
 
 #[allow(dead_code)]
@@ -350,21 +478,21 @@ const POS_FRAME: usize = 0;
 #[allow(dead_code)]
 const STOP_FRAME: usize = 4;
 #[allow(dead_code)]
-const POS_VERSION: usize = 5;
+const POS_VERSION: usize = 4;
 #[allow(dead_code)]
-const STOP_VERSION: usize = 6;
+const STOP_VERSION: usize = 5;
 #[allow(dead_code)]
-const POS_FLAGS: usize = 6;
+const POS_FLAGS: usize = 5;
 #[allow(dead_code)]
-const STOP_FLAGS: usize = 7;
+const STOP_FLAGS: usize = 6;
 #[allow(dead_code)]
-const POS_TYPE: usize = 7;
+const POS_TYPE: usize = 6;
 #[allow(dead_code)]
-const STOP_TYPE: usize = 9;
+const STOP_TYPE: usize = 8;
 #[allow(dead_code)]
-const POS_SERVER: usize = 9;
+const POS_SERVER: usize = 8;
 #[allow(dead_code)]
-const STOP_SERVER: usize = 13;
+const STOP_SERVER: usize = 12;
 
 #[derive(Debug)]
 pub(crate) struct EventLogMsg {
@@ -396,6 +524,20 @@ pub(crate) enum NetworkSendType {
     Single { server_id: u32, msg: NetworkSend },
 }
 
+/// A way to send the raft events to the other servers.
+pub(crate) trait RaftTransport {
+    /// Sends an event to a server.
+    /// # Arguments
+    /// `server_id` - The server to send the event to.
+    /// `event` - The event to send.
+    /// # returns
+    /// true if the event was sent.
+    fn send(&mut self, server_id: u32, event: RaftEvent) -> bool;
+
+    /// Gets the next event received from another server.
+    fn poll(&mut self) -> Option<RaftEvent>;
+}
+
 /// A mechanism for sending a single message to the server.
 #[derive(Debug)]
 pub(crate) enum NetworkSingleMessage {}
@@ -411,6 +553,8 @@ struct SendSocket {
     raft_event_encoder: RaftEventEncoder,
     file_store_location: String,
     file_prefix: String,
+    /// The connections to the other servers.
+    peers: HashMap<u32, PeerConnection<NetworkSendType>>,
 }
 
 /// Used to manage the send sockets.
@@ -459,12 +603,38 @@ impl RaftEventEncoder {
         BigEndian::write_i16(&mut self.msg_buffer[POS_TYPE..STOP_TYPE], type_id);
     }
 
+    /// Writes the setup frame to the buffer so we can connect to a server.
+    #[allow(dead_code)]
+    fn write_setup(&mut self) {
+        self.zero_body();
+        self.write_type(SETUP_FRAME_FOR_SERVER);
+        write_checksum(&mut self.msg_buffer);
+    }
+
+    /// Writes the frame to send back when a server connects with a version we don't support.
+    #[allow(dead_code)]
+    fn write_version_mismatch(&mut self) {
+        self.zero_body();
+        self.msg_buffer[STOP_SERVER] = CURRENT_VERSION;
+        self.write_type(VERSION_MISMATCH);
+        write_checksum(&mut self.msg_buffer);
+    }
+
     /// Writes the raft event to the buffer.  If the event isn't suppose to be sent over the wire false is returned.
     /// # Arguments
     /// `raft_event` - The raft event to serialize.
     /// # returns
     /// true if the raft event should be sent over the wire.
     fn write(&mut self, raft_event: RaftEvent) -> bool {
+        if self.write_event(raft_event) {
+            write_checksum(&mut self.msg_buffer);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn write_event(&mut self, raft_event: RaftEvent) -> bool {
         match raft_event {
             RaftEvent::ClientMessageReceived => false,
             RaftEvent::Commited { term_id, server_id } => {
@@ -497,6 +667,7 @@ impl RaftEventEncoder {
             RaftEvent::NoMessagesTimeout => false,
             RaftEvent::Ping {
                 max_commited_term,
+                round,
                 server_id: _,
             } => {
                 self.zero_body();
@@ -504,11 +675,16 @@ impl RaftEventEncoder {
                     &mut self.msg_buffer[STOP_SERVER..(STOP_SERVER + 8)],
                     max_commited_term,
                 );
+                BigEndian::write_u64(
+                    &mut self.msg_buffer[(STOP_SERVER + 8)..(STOP_SERVER + 16)],
+                    round,
+                );
                 self.write_type(PING);
                 true
             }
             RaftEvent::Pong {
                 max_term_id,
+                round,
                 server_id: _,
             } => {
                 self.zero_body();
@@ -516,6 +692,10 @@ impl RaftEventEncoder {
                     &mut self.msg_buffer[STOP_SERVER..(STOP_SERVER + 8)],
                     max_term_id,
                 );
+                BigEndian::write_u64(
+                    &mut self.msg_buffer[(STOP_SERVER + 8)..(STOP_SERVER + 16)],
+                    round,
+                );
                 self.write_type(PONG);
                 true
             }
@@ -543,6 +723,42 @@ impl RaftEventEncoder {
                 true
             }
             RaftEvent::VoteTimeout => false,
+            RaftEvent::ReadIndexRequest {
+                server_id: _,
+                request_id,
+            } => {
+                self.zero_body();
+                BigEndian::write_u64(
+                    &mut self.msg_buffer[STOP_SERVER..(STOP_SERVER + 8)],
+                    request_id,
+                );
+                self.write_type(READ_INDEX_REQUEST);
+                true
+            }
+            RaftEvent::ReadIndexResponse {
+                server_id: _,
+                request_id,
+                commit_term,
+            } => {
+                self.zero_body();
+                BigEndian::write_u64(
+                    &mut self.msg_buffer[STOP_SERVER..(STOP_SERVER + 8)],
+                    request_id,
+                );
+                BigEndian::write_u64(
+                    &mut self.msg_buffer[(STOP_SERVER + 8)..(STOP_SERVER + 16)],
+                    commit_term,
+                );
+                self.write_type(READ_INDEX_RESPONSE);
+                true
+            }
+            RaftEvent::TransferLeadership { server_id: _ } => false,
+            RaftEvent::PeerConnectionChanged { .. } => false,
+            RaftEvent::TimeoutNow { server_id: _ } => {
+                self.zero_body();
+                self.write_type(TIMEOUT_NOW);
+                true
+            }
         }
     }
 }
@@ -559,12 +775,12 @@ impl RaftDataFrame {
     fn new(server_id: u32, max_data_frame_size: usize) -> Self {
         let mut s = Self {
             server_id,
-            msg_buffer: Vec::with_capacity(max_data_frame_size + HEADER_SIZE),
+            msg_buffer: vec![0; max_data_frame_size + HEADER_SIZE + CRC_SIZE],
             max_data_frame_size,
             current_length: 0,
         };
         BigEndian::write_u32(&mut s.msg_buffer[POS_SERVER..STOP_SERVER], server_id);
-        s.msg_buffer[4] = CURRENT_VERSION;
+        s.msg_buffer[POS_VERSION] = CURRENT_VERSION;
         BigEndian::write_i16(&mut s.msg_buffer[POS_TYPE..STOP_TYPE], DATA_FRAME);
         s
     }
@@ -583,16 +799,21 @@ impl RaftDataFrame {
     /// true if we should write it out over the wire.
     fn write(&mut self, msg: EventLogMsg, message_file_store: MessageFileStoreRead) -> bool {
         // Assume the value is already aligned.  We are reading in the raw messages.
-        let frame_size = (msg.length + HEADER_SIZE) as u32;
-        BigEndian::write_u32(&mut self.msg_buffer[0..4], frame_size);
+        if msg.length > self.max_data_frame_size {
+            return false;
+        }
+        let frame_size = msg.length + HEADER_SIZE + CRC_SIZE;
+        BigEndian::write_u32(&mut self.msg_buffer[0..4], frame_size as u32);
         BigEndian::write_u64(&mut self.msg_buffer[12..20], msg.term_id);
         BigEndian::write_u32(&mut self.msg_buffer[20..24], msg.file_id);
         BigEndian::write_u64(&mut self.msg_buffer[24..32], msg.file_offset as u64);
         let copy_from = message_file_store
             .read_section(msg.file_offset, msg.length)
             .unwrap(); // Potential data corruption bug crash!
-        let copy_to = &mut self.msg_buffer[32..msg.length];
+        let copy_to = &mut self.msg_buffer[HEADER_SIZE..(HEADER_SIZE + msg.length)];
         copy_to.clone_from_slice(copy_from);
+        write_checksum(&mut self.msg_buffer[..frame_size]);
+        self.current_length = frame_size;
         true
     }
 }
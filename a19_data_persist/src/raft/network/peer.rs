@@ -0,0 +1,414 @@
+//! Supervises the connection to a single peer.  The socket code owns a `PeerConnection` for each
+//! server and asks it when to reconnect, when to ping and what to send.  Messages are queued while
+//! the peer is disconnected so the heartbeats and entries go out as soon as the connection is back.
+//! The connections can share a latch that is counted down the first time each peer connects so the
+//! node can wait for its peers before it starts.
+use crate::raft::state_machine::RaftEvent;
+use a19_concurrent::latch::Latch;
+use a19_concurrent::queue::spsc_queue::{SpscQueueReceiveWrap, SpscQueueSendWrap};
+use a19_core::pow2::PowOf2;
+use rand::{thread_rng, Rng};
+
+/// The state of the connection to the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    /// We are connected and can send messages.
+    Connected,
+    /// The connection is down and we are waiting to reconnect.
+    Disconnected {
+        /// The time in milliseconds to try to reconnect.
+        reconnect_at_ms: u64,
+    },
+}
+
+/// A change in the connection that needs to be passed onto the raft state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectionChange {
+    Connected { server_id: u32 },
+    Disconnected { server_id: u32 },
+}
+
+impl From<ConnectionChange> for RaftEvent {
+    fn from(change: ConnectionChange) -> Self {
+        match change {
+            ConnectionChange::Connected { server_id } => RaftEvent::PeerConnectionChanged {
+                server_id,
+                connected: true,
+            },
+            ConnectionChange::Disconnected { server_id } => RaftEvent::PeerConnectionChanged {
+                server_id,
+                connected: false,
+            },
+        }
+    }
+}
+
+/// What to do after checking the idle time of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IdleAction {
+    /// Nothing needs to be done.
+    None,
+    /// We haven't sent anything in a while so send a ping frame.
+    SendPing,
+    /// We haven't heard from the peer so the connection is half open.  Close it and reconnect.
+    Disconnect,
+}
+
+/// The settings for reconnecting to a peer.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReconnectConfig {
+    /// The delay for the first reconnect attempt.
+    pub initial_backoff_ms: u64,
+    /// The maximum delay between reconnect attempts.
+    pub max_backoff_ms: u64,
+    /// The maximum random amount of time to add to the delay so the peers don't reconnect at the
+    /// same time.
+    pub jitter_ms: u64,
+    /// How long to wait before sending a ping when nothing has been sent.
+    pub ping_interval_ms: u64,
+    /// How long to wait for a message from the peer before the connection is considered dead.
+    pub idle_timeout_ms: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_backoff_ms: 50,
+            max_backoff_ms: 5_000,
+            jitter_ms: 50,
+            ping_interval_ms: 100,
+            idle_timeout_ms: 1_000,
+        }
+    }
+}
+
+/// Supervises the connection to a peer.  Only meant to be used by the thread sending to the socket
+/// since it owns both ends of the outbound queue.
+pub(crate) struct PeerConnection<T> {
+    server_id: u32,
+    state: ConnectionState,
+    config: ReconnectConfig,
+    /// The number of reconnect attempts since the last time we were connected.
+    attempts: u32,
+    outbound_writer: SpscQueueSendWrap<T>,
+    outbound_reader: SpscQueueReceiveWrap<T>,
+    /// The number of messages in the outbound queue.
+    queued: usize,
+    /// The max number of messages the outbound queue can hold.
+    capacity: usize,
+    last_received_ms: u64,
+    last_sent_ms: u64,
+    /// The number of messages dropped since the queue was full.
+    dropped: u64,
+    /// The number of times we have reconnected to the peer.
+    reconnects: u64,
+    /// Used to tell if the connection has been up before.
+    connected_before: bool,
+    /// Counted down the first time we connect.
+    ready: Option<Latch>,
+}
+
+impl<T> PeerConnection<T> {
+    /// Creates the connection for a peer.  The peer starts out disconnected and can be connected
+    /// right away.
+    /// # Arguments
+    /// `server_id` - The id of the peer.
+    /// `queue_size` - The number of messages to queue while disconnected.
+    /// `config` - The reconnect settings.
+    pub fn new(server_id: u32, queue_size: usize, config: ReconnectConfig) -> Self {
+        let (outbound_writer, outbound_reader) = SpscQueueSendWrap::new(queue_size);
+        PeerConnection {
+            server_id,
+            state: ConnectionState::Disconnected { reconnect_at_ms: 0 },
+            config,
+            attempts: 0,
+            outbound_writer,
+            outbound_reader,
+            queued: 0,
+            capacity: queue_size.round_to_power_of_two(),
+            last_received_ms: 0,
+            last_sent_ms: 0,
+            dropped: 0,
+            reconnects: 0,
+            connected_before: false,
+            ready: None,
+        }
+    }
+
+    /// Counts down a latch the first time the peer connects.  The latch is dropped once counted
+    /// down so the waiters know if a peer was dropped without connecting.
+    /// # Arguments
+    /// `ready` - The latch shared by the connections to the peers.
+    pub fn with_ready_gate(mut self, ready: Latch) -> Self {
+        self.ready = Some(ready);
+        self
+    }
+
+    /// Gets the id of the peer.
+    pub fn server_id(&self) -> u32 {
+        self.server_id
+    }
+
+    /// Gets the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// The number of messages dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The number of times we have reconnected to the peer.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Queues a message to send to the peer.  If the queue is full the oldest message is dropped.
+    /// # Arguments
+    /// `msg` - The message to send.
+    pub fn offer(&mut self, msg: T) {
+        if self.queued >= self.capacity && self.outbound_reader.poll().is_some() {
+            self.queued -= 1;
+            self.dropped += 1;
+        }
+        if self.outbound_writer.offer(msg) {
+            self.queued += 1;
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    /// Gets the next message to send.  Nothing is returned while disconnected so the messages stay
+    /// queued.
+    /// # Arguments
+    /// `now_ms` - The current time in milliseconds.
+    pub fn poll(&mut self, now_ms: u64) -> Option<T> {
+        match self.state {
+            ConnectionState::Connected => {
+                let msg = self.outbound_reader.poll();
+                if msg.is_some() {
+                    self.queued -= 1;
+                    self.last_sent_ms = now_ms;
+                }
+                msg
+            }
+            ConnectionState::Disconnected { .. } => None,
+        }
+    }
+
+    /// Checks to see if we should try to reconnect.
+    /// # Arguments
+    /// `now_ms` - The current time in milliseconds.
+    pub fn should_reconnect(&self, now_ms: u64) -> bool {
+        match self.state {
+            ConnectionState::Connected => false,
+            ConnectionState::Disconnected { reconnect_at_ms } => reconnect_at_ms <= now_ms,
+        }
+    }
+
+    /// Marks the connection as connected.
+    /// # Arguments
+    /// `now_ms` - The current time in milliseconds.
+    /// # returns
+    /// The change to send to the state machine if the state changed.
+    pub fn connected(&mut self, now_ms: u64) -> Option<ConnectionChange> {
+        if self.state == ConnectionState::Connected {
+            return None;
+        }
+        if self.connected_before {
+            self.reconnects += 1;
+        }
+        self.connected_before = true;
+        if let Some(ready) = self.ready.take() {
+            ready.count_down();
+        }
+        self.attempts = 0;
+        self.state = ConnectionState::Connected;
+        self.last_received_ms = now_ms;
+        self.last_sent_ms = now_ms;
+        Some(ConnectionChange::Connected {
+            server_id: self.server_id,
+        })
+    }
+
+    /// Marks the connection as down and schedules the next reconnect attempt.
+    /// # Arguments
+    /// `now_ms` - The current time in milliseconds.
+    /// # returns
+    /// The change to send to the state machine if the state changed.
+    pub fn disconnected(&mut self, now_ms: u64) -> Option<ConnectionChange> {
+        let was_connected = self.state == ConnectionState::Connected;
+        let delay = self.next_backoff();
+        self.attempts = self.attempts.saturating_add(1);
+        self.state = ConnectionState::Disconnected {
+            reconnect_at_ms: now_ms + delay,
+        };
+        if was_connected {
+            Some(ConnectionChange::Disconnected {
+                server_id: self.server_id,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Records a message has been received from the peer.
+    /// # Arguments
+    /// `now_ms` - The current time in milliseconds.
+    pub fn received(&mut self, now_ms: u64) {
+        self.last_received_ms = now_ms;
+    }
+
+    /// Checks to see if the connection has been idle too long.
+    /// # Arguments
+    /// `now_ms` - The current time in milliseconds.
+    pub fn check_idle(&mut self, now_ms: u64) -> IdleAction {
+        match self.state {
+            ConnectionState::Disconnected { .. } => IdleAction::None,
+            ConnectionState::Connected => {
+                if now_ms.saturating_sub(self.last_received_ms) >= self.config.idle_timeout_ms {
+                    IdleAction::Disconnect
+                } else if now_ms.saturating_sub(self.last_sent_ms) >= self.config.ping_interval_ms
+                {
+                    self.last_sent_ms = now_ms;
+                    IdleAction::SendPing
+                } else {
+                    IdleAction::None
+                }
+            }
+        }
+    }
+
+    /// Calculates the time to wait before the next reconnect attempt.  Doubles on each attempt up
+    /// to the max with a random jitter added.
+    fn next_backoff(&self) -> u64 {
+        let shift = self.attempts.min(32);
+        let backoff = self
+            .config
+            .initial_backoff_ms
+            .checked_shl(shift)
+            .unwrap_or(self.config.max_backoff_ms)
+            .min(self.config.max_backoff_ms);
+        let jitter = if self.config.jitter_ms > 0 {
+            thread_rng().gen_range(0, self.config.jitter_ms)
+        } else {
+            0
+        };
+        backoff + jitter
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::raft::network::peer::*;
+
+    fn config() -> ReconnectConfig {
+        ReconnectConfig {
+            initial_backoff_ms: 10,
+            max_backoff_ms: 40,
+            jitter_ms: 0,
+            ping_interval_ms: 5,
+            idle_timeout_ms: 20,
+        }
+    }
+
+    #[test]
+    fn reconnect_backoff_test() {
+        let mut peer: PeerConnection<u32> = PeerConnection::new(2, 8, config());
+        assert!(peer.should_reconnect(0));
+        assert_eq!(
+            Some(ConnectionChange::Connected { server_id: 2 }),
+            peer.connected(0)
+        );
+        assert_eq!(
+            Some(ConnectionChange::Disconnected { server_id: 2 }),
+            peer.disconnected(100)
+        );
+        assert!(!peer.should_reconnect(105));
+        assert!(peer.should_reconnect(110));
+        assert_eq!(None, peer.disconnected(110));
+        assert_eq!(
+            ConnectionState::Disconnected {
+                reconnect_at_ms: 130
+            },
+            peer.state()
+        );
+        peer.disconnected(130);
+        peer.disconnected(170);
+        // Capped at the max backoff.
+        assert_eq!(
+            ConnectionState::Disconnected {
+                reconnect_at_ms: 210
+            },
+            peer.state()
+        );
+        peer.connected(210);
+        assert_eq!(1, peer.reconnects());
+    }
+
+    #[test]
+    fn queue_while_disconnected_test() {
+        let mut peer = PeerConnection::new(2, 4, config());
+        peer.connected(0);
+        peer.offer(1);
+        assert_eq!(Some(1), peer.poll(1));
+        peer.disconnected(2);
+        for i in 2..8 {
+            peer.offer(i);
+        }
+        assert_eq!(None, peer.poll(3));
+        assert_eq!(2, peer.dropped());
+
+        // Replication resumes with the newest messages once reconnected.
+        peer.connected(20);
+        let mut sent = Vec::new();
+        while let Some(v) = peer.poll(21) {
+            sent.push(v);
+        }
+        assert_eq!(vec![4, 5, 6, 7], sent);
+    }
+
+    #[test]
+    fn idle_test() {
+        let mut peer: PeerConnection<u32> = PeerConnection::new(2, 4, config());
+        assert_eq!(IdleAction::None, peer.check_idle(0));
+        peer.connected(0);
+        assert_eq!(IdleAction::None, peer.check_idle(1));
+        assert_eq!(IdleAction::SendPing, peer.check_idle(5));
+        assert_eq!(IdleAction::None, peer.check_idle(6));
+        peer.received(15);
+        assert_eq!(IdleAction::SendPing, peer.check_idle(15));
+        assert_eq!(IdleAction::Disconnect, peer.check_idle(35));
+    }
+
+    #[test]
+    fn ready_gate_test() {
+        use a19_concurrent::latch::LatchError;
+        use std::time::Duration;
+
+        let ready = Latch::new(2);
+        let mut peers: Vec<PeerConnection<u32>> = (2..=3)
+            .map(|id| PeerConnection::new(id, 4, config()).with_ready_gate(ready.clone()))
+            .collect();
+        peers[0].connected(0);
+        peers[0].disconnected(1);
+        // Reconnecting the same peer doesn't count down again.
+        peers[0].connected(20);
+        assert_eq!(1, ready.count());
+        assert_eq!(
+            Err(LatchError::TimedOut),
+            ready.wait_timeout(Duration::from_millis(1))
+        );
+        peers[1].connected(20);
+        assert_eq!(Ok(()), ready.wait());
+
+        // A peer dropped before it connected is reported.
+        let ready = Latch::new(1);
+        let peer: PeerConnection<u32> =
+            PeerConnection::new(2, 4, config()).with_ready_gate(ready.clone());
+        drop(peer);
+        assert_eq!(Err(LatchError::Abandoned), ready.wait());
+    }
+}
@@ -0,0 +1,404 @@
+//! Sends the control messages over UDP so a heartbeat or vote doesn't get stuck behind a large data
+//! frame on the stream.  Losing a datagram is fine since the raft protocol retries, but the same
+//! datagram can show up more than once so every datagram has a sequence number.
+//!
+//! # Datagram Layout
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Sequence                                                      |
+//! |                                                               |
+//! +---------------------------------------------------------------+
+//! | Frame                                                        ...
+//! ...                                                             |
+//! +---------------------------------------------------------------+
+//! | CRC                                                           |
+//! +---------------------------------------------------------------+
+//! ```
+//! * Sequence - Increases by one for every datagram sent by the server.
+//! * Frame - The 32 byte raft event frame.
+//! * CRC - The CRC32 of the sequence and the frame.
+use crate::raft::network::frame::{frame_checksum, Frame, FrameDecoder, CRC_SIZE};
+use crate::raft::network::*;
+use crate::raft::state_machine::RaftEvent;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+/// The size of the sequence number at the start of the datagram.
+const SEQUENCE_SIZE: usize = 8;
+/// The size of a control datagram.
+pub(crate) const DATAGRAM_SIZE: usize = SEQUENCE_SIZE + HEADER_SIZE + CRC_SIZE;
+
+/// Checks to see if the event is a control message that can be sent over UDP.
+/// # Arguments
+/// `event` - The event to check.
+pub(crate) fn is_control_event(event: &RaftEvent) -> bool {
+    matches!(
+        event,
+        RaftEvent::VoteForMe { .. }
+            | RaftEvent::VoteForCandiate { .. }
+            | RaftEvent::Ping { .. }
+            | RaftEvent::Pong { .. }
+    )
+}
+
+/// Keeps track of the sequence numbers we have seen from a server so duplicates can be dropped.
+#[derive(Debug, Default)]
+struct SequenceWindow {
+    /// The highest sequence number we have seen.
+    max: u64,
+    /// The sequence numbers seen below the max.  Bit 0 is the max.
+    seen: u64,
+}
+
+impl SequenceWindow {
+    /// Records a sequence number.
+    /// # Arguments
+    /// `sequence` - The sequence number of the datagram.
+    /// # returns
+    /// true if this is the first time we have seen the sequence number.
+    fn accept(&mut self, sequence: u64) -> bool {
+        if sequence > self.max {
+            let shift = sequence - self.max;
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.max = sequence;
+            true
+        } else {
+            let offset = self.max - sequence;
+            if offset >= 64 {
+                // Too old to tell so treat it as a duplicate.
+                false
+            } else if self.seen & (1 << offset) != 0 {
+                false
+            } else {
+                self.seen |= 1 << offset;
+                true
+            }
+        }
+    }
+}
+
+/// Sends and receives the raft control messages over UDP.
+pub(crate) struct UdpControlTransport {
+    socket: UdpSocket,
+    /// The addresses of the other servers.
+    peers: HashMap<u32, SocketAddr>,
+    encoder: RaftEventEncoder,
+    decoder: FrameDecoder,
+    next_sequence: u64,
+    windows: HashMap<u32, SequenceWindow>,
+    send_buffer: [u8; DATAGRAM_SIZE],
+    /// A little bigger than a datagram so we can tell if we got a datagram that is too big.
+    receive_buffer: [u8; DATAGRAM_SIZE + 1],
+    /// The number of datagrams dropped since they were invalid or duplicates.
+    dropped: u64,
+}
+
+impl UdpControlTransport {
+    /// Binds the UDP socket for the control messages.
+    /// # Arguments
+    /// `server_id` - The id of this server.
+    /// `addr` - The address to bind to.
+    pub fn bind<A: ToSocketAddrs>(server_id: u32, addr: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpControlTransport {
+            socket,
+            peers: HashMap::with_capacity(5),
+            encoder: RaftEventEncoder::new(server_id),
+            decoder: FrameDecoder::new(HEADER_SIZE),
+            next_sequence: 1,
+            windows: HashMap::with_capacity(5),
+            send_buffer: [0; DATAGRAM_SIZE],
+            receive_buffer: [0; DATAGRAM_SIZE + 1],
+            dropped: 0,
+        })
+    }
+
+    /// Gets the address the socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Adds the address of another server.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    /// `addr` - The UDP address of the server.
+    pub fn add_peer(&mut self, server_id: u32, addr: SocketAddr) {
+        self.peers.insert(server_id, addr);
+    }
+
+    /// The number of datagrams that have been dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Checks the datagram and decodes the event in it.
+    /// # Arguments
+    /// `length` - The number of bytes received.
+    fn decode_datagram(&mut self, length: usize) -> Option<RaftEvent> {
+        if length != DATAGRAM_SIZE {
+            return None;
+        }
+        let datagram = &self.receive_buffer[..DATAGRAM_SIZE];
+        let crc_pos = DATAGRAM_SIZE - CRC_SIZE;
+        if BigEndian::read_u32(&datagram[crc_pos..]) != frame_checksum(&datagram[..crc_pos]) {
+            return None;
+        }
+        let sequence = BigEndian::read_u64(&datagram[..SEQUENCE_SIZE]);
+        match self.decoder.decode(&datagram[SEQUENCE_SIZE..crc_pos]) {
+            Ok((Frame::Event(event), _)) => {
+                if !is_control_event(&event) {
+                    return None;
+                }
+                let server_id = BigEndian::read_u32(
+                    &datagram[(SEQUENCE_SIZE + POS_SERVER)..(SEQUENCE_SIZE + STOP_SERVER)],
+                );
+                let window = self.windows.entry(server_id).or_default();
+                if window.accept(sequence) {
+                    Some(event)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl RaftTransport for UdpControlTransport {
+    fn send(&mut self, server_id: u32, event: RaftEvent) -> bool {
+        if !is_control_event(&event) {
+            return false;
+        }
+        let addr = match self.peers.get(&server_id) {
+            Some(addr) => *addr,
+            None => return false,
+        };
+        if !self.encoder.write(event) {
+            return false;
+        }
+        let frame = self.encoder.buffer();
+        if frame.len() + SEQUENCE_SIZE + CRC_SIZE > DATAGRAM_SIZE {
+            // Silently drop anything that doesn't fit in a datagram.
+            self.dropped += 1;
+            return false;
+        }
+        BigEndian::write_u64(&mut self.send_buffer[..SEQUENCE_SIZE], self.next_sequence);
+        self.send_buffer[SEQUENCE_SIZE..(SEQUENCE_SIZE + HEADER_SIZE)].copy_from_slice(frame);
+        let crc_pos = DATAGRAM_SIZE - CRC_SIZE;
+        let crc = frame_checksum(&self.send_buffer[..crc_pos]);
+        BigEndian::write_u32(&mut self.send_buffer[crc_pos..], crc);
+        self.next_sequence += 1;
+        self.socket.send_to(&self.send_buffer, addr).is_ok()
+    }
+
+    fn poll(&mut self) -> Option<RaftEvent> {
+        loop {
+            let length = match self.socket.recv_from(&mut self.receive_buffer) {
+                Ok((length, _)) => length,
+                Err(_) => return None,
+            };
+            match self.decode_datagram(length) {
+                Some(event) => return Some(event),
+                None => {
+                    self.dropped += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Routes the control messages to one transport and everything else to another.  Normally UDP for
+/// the control messages and a stream for the data.
+pub(crate) struct HybridTransport<C: RaftTransport, D: RaftTransport> {
+    control: C,
+    data: D,
+    /// Used to decide if an event goes over the control transport.
+    policy: fn(&RaftEvent) -> bool,
+}
+
+impl<C: RaftTransport, D: RaftTransport> HybridTransport<C, D> {
+    /// Creates a transport that sends the control events over the control transport.
+    /// # Arguments
+    /// `control` - The transport for the control messages.
+    /// `data` - The transport for everything else.
+    pub fn new(control: C, data: D) -> Self {
+        HybridTransport::with_policy(control, data, is_control_event)
+    }
+
+    /// Creates a transport with a custom routing policy.
+    /// # Arguments
+    /// `control` - The transport for the control messages.
+    /// `data` - The transport for everything else.
+    /// `policy` - Returns true if the event should go over the control transport.
+    pub fn with_policy(control: C, data: D, policy: fn(&RaftEvent) -> bool) -> Self {
+        HybridTransport {
+            control,
+            data,
+            policy,
+        }
+    }
+}
+
+impl<C: RaftTransport, D: RaftTransport> RaftTransport for HybridTransport<C, D> {
+    fn send(&mut self, server_id: u32, event: RaftEvent) -> bool {
+        if (self.policy)(&event) {
+            self.control.send(server_id, event)
+        } else {
+            self.data.send(server_id, event)
+        }
+    }
+
+    fn poll(&mut self) -> Option<RaftEvent> {
+        match self.control.poll() {
+            Some(event) => Some(event),
+            None => self.data.poll(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::raft::network::udp::*;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Polls until an event is received or we give up.
+    fn poll_wait<T: RaftTransport>(transport: &mut T) -> Option<RaftEvent> {
+        for _ in 0..100 {
+            if let Some(event) = transport.poll() {
+                return Some(event);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    fn create_pair() -> (UdpControlTransport, UdpControlTransport) {
+        let mut one = UdpControlTransport::bind(1, "127.0.0.1:0").unwrap();
+        let mut two = UdpControlTransport::bind(2, "127.0.0.1:0").unwrap();
+        one.add_peer(2, two.local_addr().unwrap());
+        two.add_peer(1, one.local_addr().unwrap());
+        (one, two)
+    }
+
+    #[test]
+    fn sequence_window_test() {
+        let mut window = SequenceWindow::default();
+        assert!(window.accept(1));
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        assert!(window.accept(2));
+        assert!(!window.accept(1));
+        assert!(window.accept(100));
+        assert!(!window.accept(3));
+    }
+
+    #[test]
+    fn udp_send_test() {
+        let (mut one, mut two) = create_pair();
+        assert!(one.send(
+            2,
+            RaftEvent::Ping {
+                server_id: 1,
+                max_commited_term: 5,
+                round: 7,
+            }
+        ));
+        match poll_wait(&mut two) {
+            Some(RaftEvent::Ping {
+                server_id,
+                max_commited_term,
+                round,
+            }) => {
+                assert_eq!(1, server_id);
+                assert_eq!(5, max_commited_term);
+                assert_eq!(7, round);
+            }
+            _ => {
+                panic!("Expected the ping!");
+            }
+        }
+        // Data isn't sent over UDP.
+        assert!(!one.send(
+            2,
+            RaftEvent::Commited {
+                server_id: 1,
+                term_id: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn udp_duplicate_test() {
+        let (mut one, mut two) = create_pair();
+        one.send(
+            2,
+            RaftEvent::Pong {
+                server_id: 1,
+                max_term_id: 2,
+                round: 1,
+            },
+        );
+        // Send the same datagram again along with a corrupted copy.
+        let duplicate = one.send_buffer;
+        let mut corrupted = one.send_buffer;
+        corrupted[SEQUENCE_SIZE + STOP_SERVER] ^= 0xFF;
+        let addr = two.local_addr().unwrap();
+        one.socket.send_to(&duplicate, addr).unwrap();
+        one.socket.send_to(&corrupted, addr).unwrap();
+        assert!(poll_wait(&mut two).is_some());
+        thread::sleep(Duration::from_millis(10));
+        assert!(two.poll().is_none());
+        assert_eq!(2, two.dropped());
+    }
+
+    /// A transport that keeps the events in memory.
+    #[derive(Default)]
+    struct MemoryTransport {
+        sent: Vec<(u32, RaftEvent)>,
+    }
+
+    impl RaftTransport for MemoryTransport {
+        fn send(&mut self, server_id: u32, event: RaftEvent) -> bool {
+            self.sent.push((server_id, event));
+            true
+        }
+
+        fn poll(&mut self) -> Option<RaftEvent> {
+            self.sent.pop().map(|(_, e)| e)
+        }
+    }
+
+    #[test]
+    fn hybrid_route_test() {
+        let mut hybrid = HybridTransport::new(MemoryTransport::default(), MemoryTransport::default());
+        hybrid.send(
+            2,
+            RaftEvent::VoteForMe {
+                server_id: 1,
+                max_term_id: 1,
+            },
+        );
+        hybrid.send(
+            2,
+            RaftEvent::Commited {
+                server_id: 1,
+                term_id: 1,
+            },
+        );
+        assert_eq!(1, hybrid.control.sent.len());
+        assert_eq!(1, hybrid.data.sent.len());
+        match hybrid.control.sent[0].1 {
+            RaftEvent::VoteForMe { .. } => {}
+            _ => {
+                panic!("Votes go over the control transport!");
+            }
+        }
+    }
+}
@@ -0,0 +1,730 @@
+//! The roles a raft node moves between for the elections.  `RaftState` keeps the term and who we
+//! voted for and turns each event into the actions for the caller to run, so the transitions can
+//! be tested without a network.  `RaftStateMachine` uses the same `ElectionTimer` and `RaftMeta`
+//! but still keeps its own role and term while it handles the election messages, so this is only
+//! the model of the transitions for now.
+use crate::raft::election::ElectionTimer;
+use crate::raft::meta::RaftMeta;
+use std::collections::HashSet;
+
+/// The role of the raft node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RaftNodeState {
+    /// The node is following the leader and copying its messages.
+    Follower,
+    /// The node is asking the other servers to vote for it.
+    Candidate {
+        /// The servers that have voted for us in the current term.
+        votes: HashSet<u32>,
+    },
+    /// The node is the leader and is writing and sending messages.
+    Leader,
+    /// In single node mode.  There isn't anyone else so the events are ignored.
+    SingleNode,
+}
+
+/// The events for the raft protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RaftNodeEvent {
+    /// We didn't hear from a leader before the election timer ran out.
+    ElectionTimeout,
+    /// A server voted for us.
+    VoteGranted {
+        /// The term the vote is for.
+        term: u64,
+        /// The id of the server that voted.
+        server_id: u32,
+    },
+    /// A candidate asked for our vote.
+    VoteRequested {
+        /// The term the candidate is running in.
+        term: u64,
+        /// The id of the candidate.
+        candidate_id: u32,
+        /// True if the candidate has all of the terms we have.  Worked out by the caller since it
+        /// needs the commit files.
+        up_to_date: bool,
+    },
+    /// A leader sent us a term or a heartbeat.
+    NewTerm {
+        /// The term of the leader.
+        term: u64,
+        /// The id of the leader.
+        leader_id: u32,
+    },
+    /// A follower missed too many heartbeats.  The leader keeps sending them in case it comes
+    /// back.
+    HeartbeatFailed {
+        /// The id of the follower.
+        server_id: u32,
+    },
+    /// The leader couldn't reach a quorum with its heartbeats.
+    QuorumLost,
+    /// Time for the leader to send the next heartbeat.
+    HeartbeatTimeout,
+    /// A server replied with a term greater than ours.
+    HigherTerm {
+        /// The term of the server.
+        term: u64,
+    },
+}
+
+/// What the network layer needs to do after an event is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftAction {
+    /// Save the term and who we voted for before anything else is sent.
+    SaveTerm { term: u64, voted_for: Option<u32> },
+    /// Start the election timer again.
+    ResetElectionTimer,
+    /// Ask the other servers to vote for us.
+    RequestVotes { term: u64 },
+    /// Vote for a candidate.
+    SendVote { term: u64, candidate_id: u32 },
+    /// Send a heartbeat to the followers.
+    SendHeartbeat { term: u64 },
+}
+
+/// The state of a raft node.  Handling an event doesn't do any IO, it returns the actions for the
+/// network layer to run instead.
+///
+/// ```text
+///                  ElectionTimeout         quorum of VoteGranted
+///  +----------+ -------------------> +-----------+ ------------------> +--------+
+///  | Follower |                      | Candidate |                     | Leader |
+///  +----------+ <------------------- +-----------+                     +--------+
+///       ^       NewTerm / HigherTerm     |   ^ ElectionTimeout              |
+///       |                                +---+ (next term)                  |
+///       +-------------------------------------------------------------------+
+///                         HigherTerm / NewTerm (higher) / QuorumLost
+/// ```
+/// Events for a term before the current one are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftState {
+    /// The id of this server.
+    server_id: u32,
+    /// The number of servers that vote including us.
+    voters: usize,
+    /// The current term.
+    current_term: u64,
+    /// Who we voted for in the current term.
+    voted_for: Option<u32>,
+    /// The id of the leader of the current term if we know it.
+    leader_id: Option<u32>,
+    /// The role of the node.
+    node_state: RaftNodeState,
+}
+
+impl RaftState {
+    /// Creates the state of a node that starts as a follower.
+    /// # Arguments
+    /// `server_id` - The id of this server.
+    /// `voters` - The number of servers that vote including us.
+    /// `current_term` - The last term we saved.
+    /// `voted_for` - Who we voted for in the last term.
+    pub fn new(server_id: u32, voters: usize, current_term: u64, voted_for: Option<u32>) -> Self {
+        RaftState {
+            server_id,
+            voters: voters.max(1),
+            current_term,
+            voted_for,
+            leader_id: None,
+            node_state: RaftNodeState::Follower,
+        }
+    }
+
+    /// Creates the state of a node that starts as a follower from the saved term and vote.
+    /// # Arguments
+    /// `voters` - The number of servers that vote including us.
+    /// `meta` - The saved term and vote.
+    pub fn from_meta(voters: usize, meta: &RaftMeta) -> Self {
+        RaftState::new(
+            meta.server_id(),
+            voters,
+            meta.current_term(),
+            meta.voted_for(),
+        )
+    }
+
+    /// Creates the state of a node that runs on its own.
+    /// # Arguments
+    /// `server_id` - The id of this server.
+    pub fn single_node(server_id: u32) -> Self {
+        RaftState {
+            server_id,
+            voters: 1,
+            current_term: 0,
+            voted_for: None,
+            leader_id: Some(server_id),
+            node_state: RaftNodeState::SingleNode,
+        }
+    }
+
+    /// The current term.
+    pub fn current_term(&self) -> u64 {
+        self.current_term
+    }
+
+    /// Who we voted for in the current term.
+    pub fn voted_for(&self) -> Option<u32> {
+        self.voted_for
+    }
+
+    /// The id of the leader of the current term if we know it.
+    pub fn leader_id(&self) -> Option<u32> {
+        self.leader_id
+    }
+
+    /// The role of the node.
+    pub fn node_state(&self) -> &RaftNodeState {
+        &self.node_state
+    }
+
+    /// The number of votes needed to win an election.
+    pub fn quorum(&self) -> usize {
+        self.voters / 2 + 1
+    }
+
+    /// Moves the node to its next state.
+    /// # Arguments
+    /// `event` - What happened.
+    /// # Returns
+    /// The actions to run in order.  Empty if the event was ignored.
+    pub fn handle_event(&mut self, event: RaftNodeEvent) -> Vec<RaftAction> {
+        let mut actions = Vec::new();
+        if self.node_state == RaftNodeState::SingleNode {
+            return actions;
+        }
+        match event {
+            RaftNodeEvent::ElectionTimeout => {
+                if self.node_state != RaftNodeState::Leader {
+                    self.start_election(&mut actions);
+                }
+            }
+            RaftNodeEvent::VoteGranted { term, server_id } => {
+                if term == self.current_term {
+                    if let RaftNodeState::Candidate { votes } = &mut self.node_state {
+                        votes.insert(server_id);
+                        if votes.len() >= self.quorum() {
+                            self.become_leader(&mut actions);
+                        }
+                    }
+                }
+            }
+            RaftNodeEvent::VoteRequested {
+                term,
+                candidate_id,
+                up_to_date,
+            } => {
+                // The term and the vote are saved together when stepping down to vote.
+                let newer = term > self.current_term && self.follow(term, None);
+                let grant = term == self.current_term
+                    && up_to_date
+                    && self.node_state == RaftNodeState::Follower
+                    && self.voted_for.unwrap_or(candidate_id) == candidate_id;
+                let voted = grant && self.voted_for.is_none();
+                if voted {
+                    self.voted_for = Some(candidate_id);
+                }
+                if newer || voted {
+                    self.save_term(&mut actions);
+                }
+                if grant {
+                    actions.push(RaftAction::SendVote { term, candidate_id });
+                }
+                if newer || grant {
+                    actions.push(RaftAction::ResetElectionTimer);
+                }
+            }
+            RaftNodeEvent::NewTerm { term, leader_id } => {
+                if term > self.current_term
+                    || (term == self.current_term && self.node_state != RaftNodeState::Leader)
+                {
+                    self.step_down(term, Some(leader_id), &mut actions);
+                }
+            }
+            RaftNodeEvent::HeartbeatFailed { .. } => {}
+            RaftNodeEvent::QuorumLost => {
+                if self.node_state == RaftNodeState::Leader {
+                    self.step_down(self.current_term, None, &mut actions);
+                }
+            }
+            RaftNodeEvent::HeartbeatTimeout => {
+                if self.node_state == RaftNodeState::Leader {
+                    actions.push(RaftAction::SendHeartbeat {
+                        term: self.current_term,
+                    });
+                }
+            }
+            RaftNodeEvent::HigherTerm { term } => {
+                if term > self.current_term {
+                    self.step_down(term, None, &mut actions);
+                }
+            }
+        }
+        actions
+    }
+
+    /// Starts an election when the timer runs out.  The leader ignores the timer.
+    /// # Arguments
+    /// `timer` - The election timer.  Reset if the actions ask for it.
+    /// # Returns
+    /// The actions to run in order.  Empty if the timer hasn't run out.
+    pub fn tick(&mut self, timer: &mut ElectionTimer) -> Vec<RaftAction> {
+        if timer.is_expired() {
+            let actions = self.handle_event(RaftNodeEvent::ElectionTimeout);
+            timer.apply(&actions);
+            actions
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Starts an election in the next term and votes for ourselves.
+    fn start_election(&mut self, actions: &mut Vec<RaftAction>) {
+        self.current_term += 1;
+        self.voted_for = Some(self.server_id);
+        self.leader_id = None;
+        let mut votes = HashSet::new();
+        votes.insert(self.server_id);
+        self.node_state = RaftNodeState::Candidate { votes };
+        self.save_term(actions);
+        actions.push(RaftAction::ResetElectionTimer);
+        if self.quorum() == 1 {
+            self.become_leader(actions);
+        } else {
+            actions.push(RaftAction::RequestVotes {
+                term: self.current_term,
+            });
+        }
+    }
+
+    /// Takes over as the leader of the current term.
+    fn become_leader(&mut self, actions: &mut Vec<RaftAction>) {
+        self.node_state = RaftNodeState::Leader;
+        self.leader_id = Some(self.server_id);
+        actions.push(RaftAction::SendHeartbeat {
+            term: self.current_term,
+        });
+    }
+
+    /// Goes back to being a follower.  The vote is cleared when the term moves forward.
+    /// # Arguments
+    /// `term` - The term we are following.
+    /// `leader_id` - The leader of the term if we know it.
+    /// `actions` - Where to add the actions.
+    fn step_down(&mut self, term: u64, leader_id: Option<u32>, actions: &mut Vec<RaftAction>) {
+        if self.follow(term, leader_id) {
+            self.save_term(actions);
+        }
+        actions.push(RaftAction::ResetElectionTimer);
+    }
+
+    /// Becomes a follower of a term without adding any actions.
+    /// # Arguments
+    /// `term` - The term we are following.
+    /// `leader_id` - The leader of the term if we know it.
+    /// # Returns
+    /// true if the term moved forward so it needs to be saved.
+    fn follow(&mut self, term: u64, leader_id: Option<u32>) -> bool {
+        let newer = term > self.current_term;
+        if newer {
+            self.current_term = term;
+            self.voted_for = None;
+        }
+        self.leader_id = leader_id;
+        self.node_state = RaftNodeState::Follower;
+        newer
+    }
+
+    fn save_term(&self, actions: &mut Vec<RaftAction>) {
+        actions.push(RaftAction::SaveTerm {
+            term: self.current_term,
+            voted_for: self.voted_for,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::raft::node_state::*;
+
+    /// A node in a cluster of 3 in a state.
+    fn raft_state(
+        term: u64,
+        voted_for: Option<u32>,
+        leader_id: Option<u32>,
+        node_state: RaftNodeState,
+    ) -> RaftState {
+        let mut state = RaftState::new(1, 3, term, voted_for);
+        state.leader_id = leader_id;
+        state.node_state = node_state;
+        state
+    }
+
+    fn candidate(votes: &[u32]) -> RaftNodeState {
+        RaftNodeState::Candidate {
+            votes: votes.iter().cloned().collect(),
+        }
+    }
+
+    #[test]
+    pub fn raft_state_transitions_test() {
+        use RaftAction::*;
+        use RaftNodeEvent::*;
+        let follower = raft_state(5, None, Some(2), RaftNodeState::Follower);
+        let voted = raft_state(5, Some(3), None, RaftNodeState::Follower);
+        let running = raft_state(5, Some(1), None, candidate(&[1]));
+        let leader = raft_state(5, Some(1), Some(1), RaftNodeState::Leader);
+        let table = vec![
+            (
+                "follower times out and runs for the next term",
+                follower.clone(),
+                ElectionTimeout,
+                raft_state(6, Some(1), None, candidate(&[1])),
+                vec![
+                    SaveTerm {
+                        term: 6,
+                        voted_for: Some(1),
+                    },
+                    ResetElectionTimer,
+                    RequestVotes { term: 6 },
+                ],
+            ),
+            (
+                "candidate times out and runs again",
+                running.clone(),
+                ElectionTimeout,
+                raft_state(6, Some(1), None, candidate(&[1])),
+                vec![
+                    SaveTerm {
+                        term: 6,
+                        voted_for: Some(1),
+                    },
+                    ResetElectionTimer,
+                    RequestVotes { term: 6 },
+                ],
+            ),
+            (
+                "leader ignores the election timer",
+                leader.clone(),
+                ElectionTimeout,
+                leader.clone(),
+                vec![],
+            ),
+            (
+                "candidate wins with a quorum",
+                running.clone(),
+                VoteGranted {
+                    term: 5,
+                    server_id: 2,
+                },
+                raft_state(5, Some(1), Some(1), RaftNodeState::Leader),
+                vec![SendHeartbeat { term: 5 }],
+            ),
+            (
+                "candidate ignores a vote from an old term",
+                running.clone(),
+                VoteGranted {
+                    term: 4,
+                    server_id: 2,
+                },
+                running.clone(),
+                vec![],
+            ),
+            (
+                "follower ignores a vote",
+                follower.clone(),
+                VoteGranted {
+                    term: 5,
+                    server_id: 2,
+                },
+                follower.clone(),
+                vec![],
+            ),
+            (
+                "follower votes for a candidate",
+                follower.clone(),
+                VoteRequested {
+                    term: 5,
+                    candidate_id: 3,
+                    up_to_date: true,
+                },
+                raft_state(5, Some(3), Some(2), RaftNodeState::Follower),
+                vec![
+                    SaveTerm {
+                        term: 5,
+                        voted_for: Some(3),
+                    },
+                    SendVote {
+                        term: 5,
+                        candidate_id: 3,
+                    },
+                    ResetElectionTimer,
+                ],
+            ),
+            (
+                "follower votes again for the same candidate",
+                voted.clone(),
+                VoteRequested {
+                    term: 5,
+                    candidate_id: 3,
+                    up_to_date: true,
+                },
+                voted.clone(),
+                vec![
+                    SendVote {
+                        term: 5,
+                        candidate_id: 3,
+                    },
+                    ResetElectionTimer,
+                ],
+            ),
+            (
+                "follower only votes once in a term",
+                voted.clone(),
+                VoteRequested {
+                    term: 5,
+                    candidate_id: 2,
+                    up_to_date: true,
+                },
+                voted.clone(),
+                vec![],
+            ),
+            (
+                "follower doesn't vote for a candidate that is behind",
+                follower.clone(),
+                VoteRequested {
+                    term: 5,
+                    candidate_id: 3,
+                    up_to_date: false,
+                },
+                follower.clone(),
+                vec![],
+            ),
+            (
+                "follower ignores a vote request from an old term",
+                follower.clone(),
+                VoteRequested {
+                    term: 4,
+                    candidate_id: 3,
+                    up_to_date: true,
+                },
+                follower.clone(),
+                vec![],
+            ),
+            (
+                "leader steps down and votes in a higher term",
+                leader.clone(),
+                VoteRequested {
+                    term: 7,
+                    candidate_id: 3,
+                    up_to_date: true,
+                },
+                raft_state(7, Some(3), None, RaftNodeState::Follower),
+                vec![
+                    SaveTerm {
+                        term: 7,
+                        voted_for: Some(3),
+                    },
+                    SendVote {
+                        term: 7,
+                        candidate_id: 3,
+                    },
+                    ResetElectionTimer,
+                ],
+            ),
+            (
+                "candidate doesn't vote for another candidate in its term",
+                running.clone(),
+                VoteRequested {
+                    term: 5,
+                    candidate_id: 3,
+                    up_to_date: true,
+                },
+                running.clone(),
+                vec![],
+            ),
+            (
+                "follower hears from its leader",
+                follower.clone(),
+                NewTerm {
+                    term: 5,
+                    leader_id: 2,
+                },
+                follower.clone(),
+                vec![ResetElectionTimer],
+            ),
+            (
+                "candidate follows the leader of its term",
+                running.clone(),
+                NewTerm {
+                    term: 5,
+                    leader_id: 3,
+                },
+                raft_state(5, Some(1), Some(3), RaftNodeState::Follower),
+                vec![ResetElectionTimer],
+            ),
+            (
+                "leader follows the leader of a higher term",
+                leader.clone(),
+                NewTerm {
+                    term: 6,
+                    leader_id: 3,
+                },
+                raft_state(6, None, Some(3), RaftNodeState::Follower),
+                vec![
+                    SaveTerm {
+                        term: 6,
+                        voted_for: None,
+                    },
+                    ResetElectionTimer,
+                ],
+            ),
+            (
+                "leader ignores a term from another server in its term",
+                leader.clone(),
+                NewTerm {
+                    term: 5,
+                    leader_id: 3,
+                },
+                leader.clone(),
+                vec![],
+            ),
+            (
+                "follower ignores a leader from an old term",
+                follower.clone(),
+                NewTerm {
+                    term: 4,
+                    leader_id: 3,
+                },
+                follower.clone(),
+                vec![],
+            ),
+            (
+                "leader keeps leading when a follower misses its heartbeats",
+                leader.clone(),
+                HeartbeatFailed { server_id: 2 },
+                leader.clone(),
+                vec![],
+            ),
+            (
+                "leader steps down when it loses the quorum",
+                leader.clone(),
+                QuorumLost,
+                raft_state(5, Some(1), None, RaftNodeState::Follower),
+                vec![ResetElectionTimer],
+            ),
+            (
+                "follower ignores a lost quorum",
+                follower.clone(),
+                QuorumLost,
+                follower.clone(),
+                vec![],
+            ),
+            (
+                "leader sends a heartbeat",
+                leader.clone(),
+                HeartbeatTimeout,
+                leader.clone(),
+                vec![SendHeartbeat { term: 5 }],
+            ),
+            (
+                "candidate ignores the heartbeat timer",
+                running.clone(),
+                HeartbeatTimeout,
+                running.clone(),
+                vec![],
+            ),
+            (
+                "candidate steps down on a higher term",
+                running.clone(),
+                HigherTerm { term: 8 },
+                raft_state(8, None, None, RaftNodeState::Follower),
+                vec![
+                    SaveTerm {
+                        term: 8,
+                        voted_for: None,
+                    },
+                    ResetElectionTimer,
+                ],
+            ),
+            (
+                "leader steps down on a higher term",
+                leader.clone(),
+                HigherTerm { term: 6 },
+                raft_state(6, None, None, RaftNodeState::Follower),
+                vec![
+                    SaveTerm {
+                        term: 6,
+                        voted_for: None,
+                    },
+                    ResetElectionTimer,
+                ],
+            ),
+            (
+                "leader ignores the same term",
+                leader.clone(),
+                HigherTerm { term: 5 },
+                leader.clone(),
+                vec![],
+            ),
+            (
+                "follower ignores an old term",
+                follower.clone(),
+                HigherTerm { term: 3 },
+                follower.clone(),
+                vec![],
+            ),
+            (
+                "single node ignores everything",
+                RaftState::single_node(1),
+                HigherTerm { term: 3 },
+                RaftState::single_node(1),
+                vec![],
+            ),
+        ];
+        for (name, mut state, event, expected, actions) in table {
+            assert_eq!(actions, state.handle_event(event), "{}", name);
+            assert_eq!(expected, state, "{}", name);
+        }
+    }
+
+    #[test]
+    pub fn raft_state_election_test() {
+        // A cluster of one doesn't need anyone else's vote.
+        let mut state = RaftState::new(1, 1, 0, None);
+        assert_eq!(
+            vec![
+                RaftAction::SaveTerm {
+                    term: 1,
+                    voted_for: Some(1)
+                },
+                RaftAction::ResetElectionTimer,
+                RaftAction::SendHeartbeat { term: 1 },
+            ],
+            state.handle_event(RaftNodeEvent::ElectionTimeout)
+        );
+        assert_eq!(&RaftNodeState::Leader, state.node_state());
+
+        // A cluster of 5 needs 3 votes and a vote counts once.
+        let mut state = RaftState::new(1, 5, 0, None);
+        assert_eq!(3, state.quorum());
+        state.handle_event(RaftNodeEvent::ElectionTimeout);
+        for server_id in [2, 2, 1] {
+            assert!(state
+                .handle_event(RaftNodeEvent::VoteGranted { term: 1, server_id })
+                .is_empty());
+        }
+        assert_eq!(
+            vec![RaftAction::SendHeartbeat { term: 1 }],
+            state.handle_event(RaftNodeEvent::VoteGranted {
+                term: 1,
+                server_id: 4
+            })
+        );
+        assert_eq!(Some(1), state.leader_id());
+        assert_eq!(Some(1), state.voted_for());
+        assert_eq!(1, state.current_term());
+    }
+}
@@ -0,0 +1,286 @@
+//! Works out how far the messages have been replicated to a majority of the servers so the leader
+//! knows which terms it can mark as committed.  Each server reports the highest message it has,
+//! the commit index is the highest message a quorum of the servers has.
+//!
+//! ```text
+//!  match:  leader 30 | follower 2 25 | follower 3 12
+//!  sorted: 30 25 12 -> quorum of 2 -> commit index 25
+//! ```
+//! Per the raft paper a leader only counts the replicas for the messages written in its own term.
+//! A message from an earlier term is only committed when a message after it from the current term
+//! is, otherwise a new leader could commit something that a later leader overwrites.
+
+use crate::raft::{CommitFile, TermFile, TermPosResult};
+use a19_core::clock::Clock;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The highest message a server has.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchIndex {
+    /// The id of the message.
+    pub message_id: u64,
+    /// The id of the event file the message is in.
+    pub file_id: u32,
+    /// The position of the message in the event file.
+    pub position: usize,
+}
+
+/// A term saved to the commit file that hasn't been committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PendingTerm {
+    /// The id of the term in the commit file.
+    term_id: u64,
+    /// The id of the last message in the term.
+    max_message_id: u64,
+}
+
+/// Tracks how far each server has replicated the messages.
+pub struct QuorumTracker {
+    /// The highest message for each voting server including the leader.
+    matches: BTreeMap<u32, MatchIndex>,
+    /// The raft term we are the leader of.
+    current_term: u64,
+    /// The id of the first message written in the current term.
+    term_start: u64,
+    /// The terms waiting for a quorum in the order they were saved.
+    pending: VecDeque<PendingTerm>,
+    /// The id of the last message committed.
+    max_message_id: Arc<AtomicU64>,
+    /// Where the committed times come from.
+    clock: Arc<dyn Clock>,
+}
+
+impl QuorumTracker {
+    /// Creates a tracker where none of the servers have any messages.
+    /// # Arguments
+    /// `voters` - The ids of the voting servers including the leader.
+    /// `max_message_id` - The id of the last message committed.  Moved forward as the terms are
+    /// committed.
+    /// `clock` - Where the committed times come from.
+    pub fn new(voters: &[u32], max_message_id: Arc<AtomicU64>, clock: Arc<dyn Clock>) -> Self {
+        QuorumTracker {
+            matches: voters
+                .iter()
+                .map(|id| (*id, MatchIndex::default()))
+                .collect(),
+            current_term: 0,
+            term_start: 0,
+            pending: VecDeque::new(),
+            max_message_id,
+            clock,
+        }
+    }
+
+    /// Starts counting for a new term after we are elected.
+    /// # Arguments
+    /// `term` - The raft term we are the leader of.
+    /// `first_message_id` - The id of the first message we write in the term.
+    pub fn start_term(&mut self, term: u64, first_message_id: u64) {
+        self.current_term = term;
+        self.term_start = first_message_id;
+    }
+
+    /// The raft term we are the leader of.
+    pub fn current_term(&self) -> u64 {
+        self.current_term
+    }
+
+    /// Records the highest message a server has.  An older ack that arrives late doesn't move it
+    /// back.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    /// `index` - The highest message the server has.
+    pub fn update(&mut self, server_id: u32, index: MatchIndex) {
+        if let Some(current) = self.matches.get_mut(&server_id) {
+            if index.message_id > current.message_id {
+                *current = index;
+            }
+        }
+    }
+
+    /// The highest message a server has.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    pub fn match_index(&self, server_id: u32) -> Option<MatchIndex> {
+        self.matches.get(&server_id).cloned()
+    }
+
+    /// Records a term saved to the commit file so it can be committed once a quorum has it.
+    /// # Arguments
+    /// `term_id` - The id of the term in the commit file.
+    /// `max_message_id` - The id of the last message in the term.
+    pub fn add_term(&mut self, term_id: u64, max_message_id: u64) {
+        self.pending.push_back(PendingTerm {
+            term_id,
+            max_message_id,
+        });
+    }
+
+    /// The number of servers needed for a quorum.
+    pub fn quorum(&self) -> usize {
+        self.matches.len() / 2 + 1
+    }
+
+    /// The highest message a quorum of the servers has.  0 if it is from an earlier term since
+    /// we can't count the replicas for those.
+    pub fn commit_index(&self) -> u64 {
+        let mut ids: Vec<u64> = self.matches.values().map(|m| m.message_id).collect();
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        match ids.get(self.quorum() - 1) {
+            Some(id) if *id >= self.term_start && self.term_start > 0 => *id,
+            _ => 0,
+        }
+    }
+
+    /// Commits the terms a quorum has.  The terms are committed in order and stop at the first
+    /// one that isn't in the commit file.
+    /// # Arguments
+    /// `commit_file` - The commit file the pending terms are in.
+    /// # Returns
+    /// The id of the last message committed if it moved forward.
+    pub fn advance(&mut self, commit_file: &mut TermFile) -> Option<u64> {
+        let commit_index = self.commit_index();
+        let mut committed = None;
+        while let Some(term) = self.pending.front().cloned() {
+            if term.max_message_id > commit_index {
+                break;
+            }
+            match commit_file.calculate_pos(&term.term_id) {
+                TermPosResult::Pos(pos) => {
+                    commit_file
+                        .buffer
+                        .set_committed(pos)
+                        .set_committed_timestamp(pos, self.clock.now_ms());
+                    committed = Some(term.max_message_id);
+                    self.pending.pop_front();
+                }
+                _ => break,
+            }
+        }
+        if let Some(max_message_id) = committed {
+            self.max_message_id
+                .fetch_max(max_message_id, Ordering::AcqRel);
+        }
+        committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::backend::{InMemoryMessageStore, MessageStoreBackend};
+    use crate::raft::quorum::*;
+    use crate::raft::COMMIT_SIZE;
+    use a19_core::clock::ManualClock;
+    use std::path::Path;
+
+    fn term_file(name: &str) -> TermFile {
+        let backend = InMemoryMessageStore::new();
+        let buffer = backend
+            .create_commit(Path::new(name), COMMIT_SIZE as usize * 16)
+            .unwrap();
+        TermFile::with_buffer(buffer, 1, 1)
+    }
+
+    fn at(message_id: u64) -> MatchIndex {
+        MatchIndex {
+            message_id,
+            file_id: 1,
+            position: message_id as usize * 32,
+        }
+    }
+
+    fn committed(file: &TermFile, term_id: u64) -> bool {
+        match file.calculate_pos(&term_id) {
+            TermPosResult::Pos(pos) => file.buffer.committed(pos) == 1,
+            _ => false,
+        }
+    }
+
+    #[test]
+    pub fn three_node_test() {
+        let max_message_id = Arc::new(AtomicU64::new(0));
+        let clock = Arc::new(ManualClock::new(5_000));
+        let mut file = term_file("/memory/quorum/three.commit.1");
+        let mut tracker = QuorumTracker::new(&[1, 2, 3], max_message_id.clone(), clock);
+        assert_eq!(2, tracker.quorum());
+        tracker.start_term(1, 1);
+        tracker.add_term(1, 10);
+        tracker.add_term(2, 20);
+        tracker.add_term(3, 30);
+        tracker.update(1, at(30));
+        // Only the leader has them.
+        assert_eq!(None, tracker.advance(&mut file));
+
+        tracker.update(3, at(12));
+        assert_eq!(12, tracker.commit_index());
+        assert_eq!(Some(10), tracker.advance(&mut file));
+        assert_eq!(10, max_message_id.load(Ordering::Acquire));
+        assert!(committed(&file, 1) && !committed(&file, 2));
+        assert_eq!(5_000, file.buffer.committed_timestamp(0));
+
+        // A late ack doesn't move it back.
+        tracker.update(2, at(25));
+        tracker.update(2, at(5));
+        assert_eq!(Some(at(25)), tracker.match_index(2));
+        assert_eq!(25, tracker.commit_index());
+        assert_eq!(Some(20), tracker.advance(&mut file));
+        assert_eq!(None, tracker.advance(&mut file));
+        assert!(committed(&file, 2) && !committed(&file, 3));
+    }
+
+    #[test]
+    pub fn five_node_test() {
+        let max_message_id = Arc::new(AtomicU64::new(0));
+        let mut file = term_file("/memory/quorum/five.commit.1");
+        let mut tracker = QuorumTracker::new(
+            &[1, 2, 3, 4, 5],
+            max_message_id.clone(),
+            Arc::new(ManualClock::new(0)),
+        );
+        assert_eq!(3, tracker.quorum());
+        tracker.start_term(2, 1);
+        tracker.add_term(1, 10);
+        tracker.add_term(2, 20);
+        tracker.update(1, at(20));
+        tracker.update(2, at(20));
+        tracker.update(3, at(5));
+        assert_eq!(5, tracker.commit_index());
+        assert_eq!(None, tracker.advance(&mut file));
+        tracker.update(4, at(15));
+        assert_eq!(15, tracker.commit_index());
+        assert_eq!(Some(10), tracker.advance(&mut file));
+        tracker.update(5, at(20));
+        assert_eq!(Some(20), tracker.advance(&mut file));
+        assert_eq!(20, max_message_id.load(Ordering::Acquire));
+    }
+
+    #[test]
+    pub fn stale_term_test() {
+        let max_message_id = Arc::new(AtomicU64::new(0));
+        let mut file = term_file("/memory/quorum/stale.commit.1");
+        let mut tracker = QuorumTracker::new(
+            &[1, 2, 3],
+            max_message_id.clone(),
+            Arc::new(ManualClock::new(0)),
+        );
+        // Messages 1 to 10 are from the term before we were elected.
+        tracker.start_term(3, 11);
+        tracker.add_term(1, 10);
+        tracker.add_term(2, 14);
+        tracker.update(1, at(14));
+        tracker.update(2, at(10));
+        // A quorum has the old messages but they can't be counted.
+        assert_eq!(0, tracker.commit_index());
+        assert_eq!(None, tracker.advance(&mut file));
+        assert!(!committed(&file, 1));
+
+        // Once a message from our term has a quorum the old ones are committed with it.
+        tracker.update(2, at(12));
+        assert_eq!(12, tracker.commit_index());
+        assert_eq!(Some(10), tracker.advance(&mut file));
+        assert!(committed(&file, 1) && !committed(&file, 2));
+    }
+}
@@ -0,0 +1,214 @@
+//! Reads the committed messages from the newest to the oldest.  The frames only link forward so
+//! the positions of the messages in a file are found with a forward scan when the iterator gets to
+//! the file.  Only the positions are kept, the messages are read in from the file as they are
+//! returned.
+//!
+//! ```text
+//!  file_prefix.events.1          file_prefix.events.2
+//! +----+----+----+----+---+     +----+----+----+-----------+
+//! | 1  | 2  | 3  | 4  |EOF|     | 5  | 6  | 7  | empty     |
+//! +----+----+----+----+---+     +----+----+----+-----------+
+//!                      <-----------------------  ^ starts at the last committed message
+//! ```
+use crate::file::backend::MessageStoreBackend;
+use crate::file::{Error, MessageFileStoreRead, MessageRead, Result};
+use crate::raft::{MessageFileInfo, MessageIterator, OwnedMessageInfo, END_OF_FILE_MESSAGE_TYPE};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Goes through the committed messages backwards.  The messages are the exact reverse of what
+/// `MessageIterator` returns going forward.
+pub struct ReverseMessageIterator {
+    message_files: Arc<Mutex<Vec<MessageFileInfo>>>,
+    /// Where the files are kept.
+    backend: Arc<dyn MessageStoreBackend>,
+    /// The file being read or `None` once there aren't any more files.
+    reader: Option<MessageFileStoreRead>,
+    /// The id of the file being read.
+    file_id: u32,
+    /// The positions of the messages in the file that haven't been returned yet.
+    positions: Vec<usize>,
+    /// The id of the newest message to return.
+    max_message_id: u64,
+}
+
+impl ReverseMessageIterator {
+    /// Creates an iterator starting at the newest message.
+    /// # Arguments
+    /// `start_message_id` - The id of the first message to return.  The messages after it are
+    /// skipped.
+    /// `max_commit_id` - The maximum id that has been commited.
+    /// `message_files` - The files containing the messages.
+    /// `backend` - Where the files are kept.
+    #[allow(dead_code)]
+    pub(crate) fn new(
+        start_message_id: u64,
+        max_commit_id: u64,
+        message_files: Arc<Mutex<Vec<MessageFileInfo>>>,
+        backend: Arc<dyn MessageStoreBackend>,
+    ) -> Result<Self> {
+        let max_message_id = start_message_id.min(max_commit_id);
+        let file = MessageIterator::find_starting_file(message_files.clone(), max_message_id);
+        let mut iterator = ReverseMessageIterator {
+            message_files,
+            backend,
+            reader: None,
+            file_id: file.file_id,
+            positions: Vec::new(),
+            max_message_id,
+        };
+        iterator.open(&file)?;
+        Ok(iterator)
+    }
+
+    /// Gets the next message going backwards.
+    /// # Returns
+    /// `None` once the start of the first file has been reached.
+    pub fn next_message<'a>(&'a mut self) -> Result<Option<MessageRead<'a>>> {
+        loop {
+            let reader = match &self.reader {
+                Some(reader) => reader,
+                None => return Ok(None),
+            };
+            match self.positions.pop() {
+                Some(pos) => return reader.read_new(pos).map(Some),
+                None => self.previous_file()?,
+            }
+        }
+    }
+
+    /// Moves onto the file before the current one.
+    fn previous_file(&mut self) -> Result<()> {
+        if self.file_id <= 1 {
+            self.reader = None;
+            return Ok(());
+        }
+        let previous_id = self.file_id - 1;
+        let file = self
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|f| f.file_id == previous_id)
+            .cloned();
+        match file {
+            Some(file) if file.removed => Err(Error::FileRemoved(previous_id)),
+            Some(file) => self.open(&file),
+            // The older files have been cleaned up.
+            None => {
+                self.reader = None;
+                Ok(())
+            }
+        }
+    }
+
+    /// Opens a file and finds the positions of the messages in it.
+    /// # Arguments
+    /// `file` - The file to read next.
+    fn open(&mut self, file: &MessageFileInfo) -> Result<()> {
+        let reader = self.backend.open_readonly(Path::new(&file.path))?;
+        self.positions.clear();
+        let mut pos = 0;
+        loop {
+            match reader.read_new(pos) {
+                Ok(msg) => {
+                    if msg.msg_type_id() == END_OF_FILE_MESSAGE_TYPE
+                        || msg.message_id() > self.max_message_id
+                    {
+                        break;
+                    }
+                    self.positions.push(pos);
+                    pos = msg.next_pos();
+                }
+                Err(Error::NoMessage) | Err(Error::Full) | Err(Error::PositionOutOfRange(_)) => {
+                    break
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.reader = Some(reader);
+        self.file_id = file.file_id;
+        Ok(())
+    }
+}
+
+impl Iterator for ReverseMessageIterator {
+    type Item = Result<OwnedMessageInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_message() {
+            Ok(Some(msg)) => Some(Ok(OwnedMessageInfo::copy_of(&msg))),
+            Ok(None) => None,
+            Err(e) => {
+                // Stops after the error.
+                self.reader = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::backend::FileBackend;
+    use crate::raft::reverse::*;
+    use crate::raft::{load_current_files, process_files, PersistedMessageWriteStream};
+    use std::fs::remove_dir_all;
+    use std::sync::atomic::AtomicU64;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_reverse";
+    const TEST_PREFIX: &str = "reverse";
+
+    #[test]
+    pub fn reverse_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let file_size = 0x400;
+        let mut collection = load_current_files(TEST_PREFIX, TEST_DIR).unwrap();
+        process_files(&mut collection, TEST_PREFIX, TEST_DIR, &file_size, &0x1000).unwrap();
+        let mut writer = PersistedMessageWriteStream::new(
+            1,
+            TEST_DIR.to_owned(),
+            TEST_PREFIX.to_owned(),
+            file_size,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap()
+        .with_message_files(collection.message_files.clone());
+        for message_id in 1..=100u64 {
+            writer
+                .add_message(1, message_id, &message_id.to_le_bytes())
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        let message_files = collection.message_files.clone();
+        assert!(message_files.lock().unwrap().len() >= 3);
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(FileBackend);
+
+        // Only goes up to the last committed message.
+        let forward =
+            MessageIterator::new(100, 1, 90, message_files.clone(), true, backend.clone())
+                .unwrap()
+                .into_owned()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(90, forward.len());
+        let mut reverse =
+            ReverseMessageIterator::new(u64::MAX, 90, message_files.clone(), backend.clone())
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+        reverse.reverse();
+        assert_eq!(forward, reverse);
+
+        let last: Vec<u64> = ReverseMessageIterator::new(30, 90, message_files, backend)
+            .unwrap()
+            .take(5)
+            .map(|m| m.unwrap().message_id)
+            .collect();
+        assert_eq!(vec![30, 29, 28, 27, 26], last);
+    }
+}
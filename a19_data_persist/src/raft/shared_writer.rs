@@ -0,0 +1,737 @@
+//! Lets several threads write to the event files at the same time.  A writer claims the space for
+//! its message by adding the aligned size to the position of the file and copies in the body
+//! while the other writers are copying theirs.  The ids and the rest of the headers are written in
+//! the order of the claims with the size last so a reader never sees part of a message and the
+//! ids go up with the position.
+//!
+//! ```text
+//!  claims:   | msg 1 (published) | msg 2 (copying body) | msg 3 (waiting for msg 2) | next claim
+//!  rollover: | ... | end of file marker | claims past the end are retried in the next file
+//!  failure:  | ... | end of file marker over the claim | the claims after it retry in the next file
+//! ```
+//! The claim that crosses the end of the file ends it with the end of file marker, or fills it
+//! with the `u32::MAX` padding when the marker doesn't fit, and opens the next file.  The claims
+//! after it are abandoned without writing anything and retried in the next file.  A writer that
+//! fails after claiming its space ends the file the same way once it is its turn so the writers
+//! behind it aren't left waiting on a message that is never published.
+//!
+//! The writer whose turn it is also adds the message to the position index, the sparse index and
+//! the dirty ranges and hands the id to the commit thread, so a `PersistedMessageWriteStream`
+//! turned into a shared writer keeps everything it was set up with up to date.
+use crate::file;
+use crate::file::backend::MessageStoreBackend;
+use crate::file::{aligned_message_size, MessageFileStoreWrite, MessageHeaders, USER_META_SIZE};
+use crate::raft::dirty_range::DirtyRanges;
+use crate::raft::events::{StoreEvent, StoreEvents};
+use crate::raft::sparse_index::SparseIndexWriter;
+use crate::raft::{
+    create_event_name, find_end_of_buffer, FindEmptySlotResult, MessageFileInfo,
+    PersistedMessageWriteStream, PositionIndex, END_OF_FILE_MESSAGE_TYPE,
+    POSITION_INDEX_INTERVAL,
+};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+/// The published position of a file once it has been ended.  The writers with claims after the
+/// end retry in the next file.
+const ENDED: usize = usize::MAX;
+
+/// The event file the writers are claiming space in.
+struct ClaimedFile {
+    /// The id of the event file.
+    file_id: u32,
+    /// Writes the messages.
+    buffer: MessageFileStoreWrite,
+    /// The position of the next claim.
+    current_pos: AtomicUsize,
+    /// Everything before the position has been published.  `ENDED` once the file is ended.
+    published_pos: AtomicUsize,
+    /// Set if the next file couldn't be opened so the writers waiting for it give up.
+    failed: AtomicBool,
+}
+
+impl ClaimedFile {
+    fn new(file_id: u32, buffer: MessageFileStoreWrite, pos: usize) -> Self {
+        ClaimedFile {
+            file_id,
+            buffer,
+            current_pos: AtomicUsize::new(pos),
+            published_pos: AtomicUsize::new(pos),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Waits for the messages claimed in front of a position to be published.
+    /// # Arguments
+    /// `pos` - The position of the claim.
+    /// # Returns
+    /// False if the file was ended in front of the claim.
+    fn wait_for_turn(&self, pos: usize) -> bool {
+        loop {
+            match self.published_pos.load(Ordering::Acquire) {
+                ENDED => break false,
+                published if published == pos => break true,
+                _ => thread::yield_now(),
+            }
+        }
+    }
+}
+
+struct Shared {
+    /// The file being written to.
+    file: RwLock<Arc<ClaimedFile>>,
+    /// The id of the last message published.  Only changed by the writer whose turn it is.
+    published_id: AtomicU64,
+    /// Set to the id of the last message published so the commit thread can commit it.
+    written_message_id: Arc<AtomicU64>,
+    /// The directory the files are stored in.
+    file_storage_directory: String,
+    /// The file prefix.
+    file_prefix: String,
+    /// The size of the files to create.
+    file_size: usize,
+    /// Where the event files are kept.
+    backend: Arc<dyn MessageStoreBackend>,
+    /// Where the messages written are.
+    positions: Option<Arc<PositionIndex>>,
+    /// The id of the last message added to the position index.
+    last_indexed_id: AtomicU64,
+    /// Adds the positions to the index file next to the event file.
+    sparse_index: Option<Mutex<SparseIndexWriter>>,
+    /// Where to publish the files being rolled.
+    events: Option<Arc<StoreEvents>>,
+    /// The files the readers know about.  The files rolled onto are added to it.
+    message_files: Option<Arc<Mutex<Vec<MessageFileInfo>>>>,
+    /// Where the bytes written are recorded so only they are flushed.
+    dirty: Option<Arc<DirtyRanges>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let file = self.file.read().unwrap();
+        if let Err(e) = file.buffer.flush() {
+            log::warn!("Unable to flush event file {}: {}", file.file_id, e);
+        }
+    }
+}
+
+/// A writer for the event files that can be used by several threads at the same time.  Cloning
+/// it gives another handle to the same files.
+#[derive(Clone)]
+pub struct SharedWriteStream {
+    shared: Arc<Shared>,
+}
+
+impl PersistedMessageWriteStream {
+    /// Turns the stream into a writer several threads can add messages with.  The position index,
+    /// sparse index, dirty ranges, files and events the stream was set up with are kept up to date
+    /// by the shared writer and the commit thread is handed the ids through the same counter.
+    /// # Returns
+    /// The shared writer starting at the position of the stream.
+    pub fn into_shared(mut self) -> file::Result<SharedWriteStream> {
+        let path = create_event_name(&self.file_storage_directory, &self.file_prefix, &self.file_id);
+        let buffer = self
+            .backend
+            .open_write(Path::new(&path), self.file_size)?
+            .1;
+        check_shared(&buffer)?;
+        let published_id = self
+            .loaded_message_id
+            .max(self.max_message_id.load(Ordering::Acquire));
+        Ok(SharedWriteStream {
+            shared: Arc::new(Shared {
+                file: RwLock::new(Arc::new(ClaimedFile::new(
+                    self.file_id,
+                    buffer,
+                    self.current_pos,
+                ))),
+                published_id: AtomicU64::new(published_id),
+                written_message_id: self.max_message_id.clone(),
+                file_storage_directory: self.file_storage_directory.clone(),
+                file_prefix: self.file_prefix.clone(),
+                file_size: self.file_size,
+                backend: self.backend.clone(),
+                positions: self.positions.take(),
+                last_indexed_id: AtomicU64::new(self.last_indexed_id),
+                sparse_index: self.sparse_index.take().map(Mutex::new),
+                events: self.events.take(),
+                message_files: self.message_files.take(),
+                dirty: self.dirty.take(),
+            }),
+        })
+    }
+}
+
+/// Checks the writers can share a file.
+/// # Arguments
+/// `buffer` - The writer for the file.
+/// # Returns
+/// An `Unsupported` error if the file is written with direct I/O.
+fn check_shared(buffer: &MessageFileStoreWrite) -> file::Result<()> {
+    if buffer.is_direct() {
+        Err(file::Error::FileError(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The writers can't share a file written with direct I/O",
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+impl SharedWriteStream {
+    /// Opens the writer at the end of the last event file.  Only the event files are written, use
+    /// `PersistedMessageWriteStream::into_shared` to keep the indexes up to date.
+    /// # Arguments
+    /// `backend` - Where the event files are kept.
+    /// `file_storage_directory` - The directory the files are stored in.
+    /// `file_prefix` - The file prefix.
+    /// `start_file_id` - The id of the last event file.
+    /// `file_size` - The size of the files to create.
+    /// `written_message_id` - The id of the last message written.  Set to the id of each message
+    /// published so the commit thread can commit it.
+    /// # Returns
+    /// An `Unsupported` error if the files are written with direct I/O.
+    pub fn open(
+        backend: Arc<dyn MessageStoreBackend>,
+        file_storage_directory: &str,
+        file_prefix: &str,
+        start_file_id: u32,
+        file_size: usize,
+        written_message_id: Arc<AtomicU64>,
+    ) -> file::Result<Self> {
+        let mut file_id = start_file_id;
+        let path = create_event_name(file_storage_directory, file_prefix, &file_id);
+        let (reader, mut buffer) = backend.open_write(Path::new(&path), file_size)?;
+        let (pos, last_message_id) = match find_end_of_buffer(&reader)? {
+            FindEmptySlotResult::Pos(pos, last_message_id) => (pos, last_message_id),
+            FindEmptySlotResult::Full(last_message_id) => {
+                file_id += 1;
+                let path = create_event_name(file_storage_directory, file_prefix, &file_id);
+                buffer = backend.open_write(Path::new(&path), file_size)?.1;
+                (0, last_message_id)
+            }
+        };
+        check_shared(&buffer)?;
+        let published_id = written_message_id
+            .fetch_max(last_message_id, Ordering::AcqRel)
+            .max(last_message_id);
+        Ok(SharedWriteStream {
+            shared: Arc::new(Shared {
+                file: RwLock::new(Arc::new(ClaimedFile::new(file_id, buffer, pos))),
+                published_id: AtomicU64::new(published_id),
+                written_message_id,
+                file_storage_directory: file_storage_directory.to_owned(),
+                file_prefix: file_prefix.to_owned(),
+                file_size,
+                backend,
+                positions: None,
+                last_indexed_id: AtomicU64::new(0),
+                sparse_index: None,
+                events: None,
+                message_files: None,
+                dirty: None,
+            }),
+        })
+    }
+
+    /// Adds a message.  Can be called from several threads at the same time.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `headers` - The user metadata of the message.
+    /// `body` - The body of the message.
+    /// # Returns
+    /// The id of the message, the position after it and the id of the file it is in.
+    pub fn add_message(
+        &self,
+        msg_type: i32,
+        headers: &MessageHeaders,
+        body: &[u8],
+    ) -> file::Result<(u64, usize, u32)> {
+        let meta_size = if headers.is_empty() {
+            0
+        } else {
+            USER_META_SIZE
+        };
+        let length = aligned_message_size(meta_size + body.len());
+        // Leave room for the end of file message.
+        let capacity = self.current().buffer.capacity();
+        let end_size = aligned_message_size(2);
+        if length + end_size > capacity {
+            return Err(file::Error::NotEnoughSpace {
+                message_size: length as u32,
+                position: 0,
+                capacity,
+                remaining: capacity - end_size,
+            });
+        }
+        loop {
+            let file = self.current();
+            let pos = file.current_pos.fetch_add(length, Ordering::AcqRel);
+            if pos + length <= capacity {
+                let checksum = file.buffer.write_unpublished(pos, headers, body);
+                if !file.wait_for_turn(pos) {
+                    // Ended in front of the claim so it is tried again in the next file.
+                    self.wait_for_next_file(&file)?;
+                    continue;
+                }
+                let message_id = self.shared.published_id.load(Ordering::Acquire) + 1;
+                let published = checksum.and_then(|checksum| {
+                    file.buffer.publish(
+                        pos,
+                        msg_type,
+                        message_id,
+                        headers,
+                        body.len(),
+                        &checksum,
+                    )
+                });
+                match published {
+                    Ok(end) => {
+                        self.published(&file, message_id, pos, end);
+                        break Ok((message_id, end, file.file_id));
+                    }
+                    Err(e) => {
+                        // The claim is never going to be published so the file is ended over it.
+                        if let Err(end_error) = self.end_file(&file, pos) {
+                            log::error!(
+                                "Unable to end event file {} after a failed write: {}",
+                                file.file_id,
+                                end_error
+                            );
+                        }
+                        break Err(e);
+                    }
+                }
+            } else if pos <= capacity {
+                // The claim that crosses the end rolls over to the next file.
+                if file.wait_for_turn(pos) {
+                    self.end_file(&file, pos)?;
+                } else {
+                    self.wait_for_next_file(&file)?;
+                }
+            } else {
+                self.wait_for_next_file(&file)?;
+            }
+        }
+    }
+
+    /// The id of the last message published.
+    pub fn max_message_id(&self) -> u64 {
+        self.shared.published_id.load(Ordering::Acquire)
+    }
+
+    /// The id of the file and the position of the next claim.
+    pub fn position(&self) -> (u32, usize) {
+        let file = self.current();
+        (file.file_id, file.current_pos.load(Ordering::Acquire))
+    }
+
+    /// Flushes the file being written to.
+    pub fn flush(&self) -> file::Result<()> {
+        self.current().buffer.flush()
+    }
+
+    fn current(&self) -> Arc<ClaimedFile> {
+        self.shared.file.read().unwrap().clone()
+    }
+
+    /// Records a message that was published.  Only called by the writer whose turn it is so the
+    /// indexes get the messages in order.
+    /// # Arguments
+    /// `file` - The file the message is in.
+    /// `message_id` - The id of the message.
+    /// `pos` - The position of the message.
+    /// `end` - The position after the message.
+    fn published(&self, file: &ClaimedFile, message_id: u64, pos: usize, end: usize) {
+        let shared = &self.shared;
+        shared.published_id.store(message_id, Ordering::Release);
+        if let Some(dirty) = &shared.dirty {
+            dirty.record(file.file_id, pos, end);
+        }
+        if let Some(positions) = &shared.positions {
+            let last_indexed_id = shared.last_indexed_id.load(Ordering::Relaxed);
+            if pos == 0 || message_id >= last_indexed_id + POSITION_INDEX_INTERVAL {
+                positions.insert(message_id, (file.file_id, pos));
+                shared.last_indexed_id.store(message_id, Ordering::Relaxed);
+            }
+        }
+        if let Some(index) = &shared.sparse_index {
+            index.lock().unwrap().add(file.file_id, message_id, pos);
+        }
+        shared
+            .written_message_id
+            .store(message_id, Ordering::Release);
+        file.published_pos.store(end, Ordering::Release);
+    }
+
+    /// Ends a file at the claim of the writer whose turn it is and starts the next one.  The
+    /// writers waiting on the file are let go either way.
+    /// # Arguments
+    /// `file` - The file to end.
+    /// `pos` - The position of the claim.
+    fn end_file(&self, file: &ClaimedFile, pos: usize) -> file::Result<()> {
+        let result = self.next_file(file, pos);
+        if result.is_err() {
+            file.failed.store(true, Ordering::Release);
+        }
+        file.published_pos.store(ENDED, Ordering::Release);
+        result
+    }
+
+    /// Writes the end of file marker and opens the next file.
+    /// # Arguments
+    /// `file` - The file to end.
+    /// `pos` - The position to write the marker at.
+    fn next_file(&self, file: &ClaimedFile, pos: usize) -> file::Result<()> {
+        let shared = &self.shared;
+        let end = match file
+            .buffer
+            .write_shared(pos, END_OF_FILE_MESSAGE_TYPE, u64::MAX, &[0, 0])
+        {
+            Ok(end) => end,
+            // The rest of the file is padded when the end message doesn't fit.
+            Err(file::Error::Full) => file.buffer.capacity(),
+            Err(e) => return Err(e),
+        };
+        if let Some(dirty) = &shared.dirty {
+            dirty.record(file.file_id, pos, end);
+        }
+        let file_id = file.file_id + 1;
+        let path = create_event_name(&shared.file_storage_directory, &shared.file_prefix, &file_id);
+        let buffer = shared
+            .backend
+            .open_write(Path::new(&path), shared.file_size)?
+            .1;
+        if let Some(message_files) = &shared.message_files {
+            let next_message_id = shared.published_id.load(Ordering::Acquire) + 1;
+            let mut message_files = message_files.lock().unwrap();
+            if !message_files.iter().any(|f| f.file_id == file_id) {
+                message_files.push(MessageFileInfo::new(path, file_id, next_message_id));
+                message_files.sort();
+            }
+        }
+        *shared.file.write().unwrap() = Arc::new(ClaimedFile::new(file_id, buffer, 0));
+        if let Some(events) = &shared.events {
+            events.emit(StoreEvent::FileRolled { file_id });
+        }
+        Ok(())
+    }
+
+    /// Waits for the writer that ended a file to open the next one.
+    /// # Arguments
+    /// `file` - The file that was ended.
+    fn wait_for_next_file(&self, file: &ClaimedFile) -> file::Result<()> {
+        loop {
+            if file.failed.load(Ordering::Acquire) {
+                break Err(file::Error::Full);
+            } else if self.current().file_id != file.file_id {
+                break Ok(());
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::backend::{FileBackend, InMemoryMessageStore, MessageStoreBackend};
+    use crate::file::{Error, MessageFileStore, MessageHeaders};
+    use crate::raft::dirty_range::Dirty;
+    use crate::raft::shared_writer::*;
+    use crate::raft::sparse_index::read_index;
+    use crate::raft::{
+        create_index_name, load_current_files, process_files, PersistedCommitStream, COMMIT_SIZE,
+    };
+    use a19_core::clock::system_clock;
+    use std::collections::HashSet;
+    use std::convert::TryInto;
+    use std::fs::remove_dir_all;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use std::thread;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_shared_writer";
+    const TEST_PREFIX: &str = "shared";
+
+    const WRITERS: u64 = 4;
+    const MESSAGES: u64 = 2000;
+
+    /// The body of a message with the writer and sequence at the start and a pattern after.
+    fn body(writer: u64, seq: u64) -> Vec<u8> {
+        let mut body = vec![(writer * 31 + seq) as u8; 8 + (seq % 5) as usize * 8];
+        body[..8].copy_from_slice(&(writer << 32 | seq).to_le_bytes());
+        body
+    }
+
+    #[test]
+    pub fn multiple_writers_test() {
+        let dir = "/memory/shared_writer";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        let max_message_id = Arc::new(AtomicU64::new(0));
+        let writer =
+            SharedWriteStream::open(backend.clone(), dir, "shared", 1, 0x1000, max_message_id)
+                .unwrap();
+        let handles: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let writer = writer.clone();
+                thread::spawn(move || {
+                    let mut ids = Vec::new();
+                    for seq in 0..MESSAGES {
+                        let headers = MessageHeaders {
+                            tenant: w as u32,
+                            ..MessageHeaders::default()
+                        };
+                        let (id, _, _) = writer.add_message(1, &headers, &body(w, seq)).unwrap();
+                        ids.push(id);
+                    }
+                    ids
+                })
+            })
+            .collect();
+
+        // Follows the files while they are written.
+        let total = WRITERS * MESSAGES;
+        let mut seen = HashSet::new();
+        let mut last_id = 0;
+        let mut file_id = 1;
+        let mut pos = 0;
+        let mut reader = None;
+        while last_id < total {
+            let path = create_event_name(dir, "shared", &file_id);
+            if reader.is_none() {
+                if !backend.exists(Path::new(&path)) {
+                    thread::yield_now();
+                    continue;
+                }
+                reader = Some(backend.open_readonly(Path::new(&path)).unwrap());
+            }
+            match reader.as_ref().unwrap().read_new(pos) {
+                Ok(msg) if msg.msg_type_id() == END_OF_FILE_MESSAGE_TYPE => {
+                    file_id += 1;
+                    pos = 0;
+                    reader = None;
+                }
+                Ok(msg) => {
+                    // The checksum was checked so the message isn't torn.
+                    assert_eq!(last_id + 1, msg.message_id());
+                    last_id = msg.message_id();
+                    let key = u64::from_le_bytes(msg.bytes()[..8].try_into().unwrap());
+                    let (w, seq) = (key >> 32, key & 0xFFFF_FFFF);
+                    assert_eq!(&body(w, seq)[..], msg.bytes());
+                    assert_eq!(w as u32, msg.headers().tenant);
+                    assert!(seen.insert(key));
+                    pos = msg.next_pos();
+                }
+                Err(Error::NoMessage) => thread::yield_now(),
+                Err(Error::Full) | Err(Error::PositionOutOfRange(_)) => {
+                    file_id += 1;
+                    pos = 0;
+                    reader = None;
+                }
+                Err(e) => panic!("Unable to read message {}: {}", last_id + 1, e),
+            }
+        }
+        let mut ids: Vec<u64> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+        ids.sort_unstable();
+        assert_eq!((1..=total).collect::<Vec<u64>>(), ids);
+        assert_eq!(total as usize, seen.len());
+        assert_eq!(total, writer.max_message_id());
+        assert!(file_id > 10);
+    }
+
+    #[test]
+    pub fn reopen_test() {
+        let dir = "/memory/shared_writer_reopen";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        let writer = SharedWriteStream::open(
+            backend.clone(),
+            dir,
+            "shared",
+            1,
+            0x400,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap();
+        for seq in 0..10 {
+            writer
+                .add_message(1, &MessageHeaders::default(), &body(0, seq))
+                .unwrap();
+        }
+        assert!(matches!(
+            writer.add_message(1, &MessageHeaders::default(), &[0; 0x400]),
+            Err(Error::NotEnoughSpace { .. })
+        ));
+        let (file_id, pos) = writer.position();
+        drop(writer);
+
+        // Carries on after the last message.
+        let max_message_id = Arc::new(AtomicU64::new(0));
+        let writer = SharedWriteStream::open(
+            backend,
+            dir,
+            "shared",
+            file_id,
+            0x400,
+            max_message_id.clone(),
+        )
+        .unwrap();
+        assert_eq!(10, max_message_id.load(Ordering::Acquire));
+        assert_eq!((file_id, pos), writer.position());
+        let (id, _, _) = writer
+            .add_message(1, &MessageHeaders::default(), &body(0, 10))
+            .unwrap();
+        assert_eq!(11, id);
+    }
+
+    #[test]
+    pub fn abandoned_claim_test() {
+        let dir = "/memory/shared_writer_abandoned";
+        let backend: Arc<dyn MessageStoreBackend> = Arc::new(InMemoryMessageStore::new());
+        let written = Arc::new(AtomicU64::new(0));
+        let writer =
+            SharedWriteStream::open(backend.clone(), dir, "shared", 1, 0x1000, written.clone())
+                .unwrap();
+        for seq in 0..3 {
+            writer
+                .add_message(1, &MessageHeaders::default(), &body(0, seq))
+                .unwrap();
+        }
+        // A claim whose writer fails before publishing it.
+        let file = writer.current();
+        let pos = file
+            .current_pos
+            .fetch_add(aligned_message_size(8), Ordering::AcqRel);
+        let behind = {
+            let writer = writer.clone();
+            thread::spawn(move || {
+                writer
+                    .add_message(1, &MessageHeaders::default(), &body(1, 0))
+                    .unwrap()
+            })
+        };
+        while file.current_pos.load(Ordering::Acquire) == pos + aligned_message_size(8) {
+            thread::yield_now();
+        }
+        assert!(file.wait_for_turn(pos));
+        writer.end_file(&file, pos).unwrap();
+
+        // The writer behind the claim moves on to the next file.
+        let (id, _, file_id) = behind.join().unwrap();
+        assert_eq!((4, 2), (id, file_id));
+        assert_eq!(4, written.load(Ordering::Acquire));
+        let reader = backend
+            .open_readonly(Path::new(&create_event_name(dir, "shared", &1)))
+            .unwrap();
+        let end = reader.read_new(pos).unwrap();
+        assert_eq!(END_OF_FILE_MESSAGE_TYPE, end.msg_type_id());
+        let reader = backend
+            .open_readonly(Path::new(&create_event_name(dir, "shared", &2)))
+            .unwrap();
+        assert_eq!(4, reader.read_new(0).unwrap().message_id());
+    }
+
+    #[test]
+    pub fn shared_stream_test() {
+        let dir = format!("{}/stream", TEST_DIR);
+        if Path::new(&dir).exists() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let file_size = 0x1000;
+        let mut collection = load_current_files(TEST_PREFIX, &dir).unwrap();
+        process_files(&mut collection, TEST_PREFIX, &dir, &file_size, &0x1000).unwrap();
+        let written = Arc::new(AtomicU64::new(0));
+        let positions = Arc::new(PositionIndex::new());
+        let dirty = Arc::new(DirtyRanges::default());
+        let mut stream = PersistedMessageWriteStream::new(
+            1,
+            dir.clone(),
+            TEST_PREFIX.to_owned(),
+            file_size,
+            written.clone(),
+        )
+        .unwrap()
+        .with_positions(positions.clone())
+        .with_message_files(collection.message_files.clone())
+        .with_dirty_ranges(dirty.clone())
+        .with_sparse_index(16);
+        for i in 1..=10u64 {
+            stream.add_message(1, i, &i.to_le_bytes()).unwrap();
+        }
+        let writer = stream.into_shared().unwrap();
+        let handles: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let writer = writer.clone();
+                thread::spawn(move || {
+                    for seq in 0..500 {
+                        writer
+                            .add_message(1, &MessageHeaders::default(), &body(w, seq))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let total = 10 + WRITERS * 500;
+        assert_eq!(total, written.load(Ordering::Acquire));
+
+        // The commit thread commits what the writers wrote.
+        let committed = Arc::new(AtomicU64::new(0));
+        let mut commit = PersistedCommitStream::create(
+            dir.clone(),
+            TEST_PREFIX.to_owned(),
+            COMMIT_SIZE as usize * 64,
+            Arc::new(FileBackend),
+            committed.clone(),
+            written.clone(),
+            system_clock(),
+        )
+        .unwrap();
+        while committed.load(Ordering::Acquire) < total {
+            commit.step().unwrap();
+        }
+
+        // The files the writers rolled onto start with the id they were added with.
+        let files: Vec<(u32, u64, String)> = collection
+            .message_files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| (f.file_id, f.message_id_start, f.path.clone()))
+            .collect();
+        assert!(files.len() > 10);
+        for (i, (file_id, start, path)) in files.iter().enumerate() {
+            assert_eq!(i as u32 + 1, *file_id);
+            let reader = unsafe { MessageFileStore::open_readonly(path).unwrap() };
+            if i > 0 {
+                assert_eq!(*start, reader.read_new(0).unwrap().message_id());
+            }
+            // Every entry of the index file is at its message.
+            let index = read_index(&create_index_name(&dir, TEST_PREFIX, file_id)).unwrap();
+            assert!(!index.is_empty());
+            for entry in index {
+                let msg = reader.read_new(entry.position).unwrap();
+                assert_eq!(entry.message_id, msg.message_id());
+            }
+        }
+        // Every entry of the position index is at its message.
+        let mut indexed = 0;
+        for (message_id, (file_id, pos)) in positions.range(0..) {
+            let path = create_event_name(&dir, TEST_PREFIX, &file_id);
+            let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+            assert_eq!(message_id, reader.read_new(pos).unwrap().message_id());
+            indexed += 1;
+        }
+        assert!(indexed >= files.len());
+        let (last_file, _) = writer.position();
+        assert!(matches!(dirty.take(last_file), Dirty::Range(_)));
+    }
+}
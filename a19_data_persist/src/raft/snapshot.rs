@@ -0,0 +1,712 @@
+//! Snapshots of the state built from the messages so the event files in front of them can be
+//! removed.  The state is encoded by a `SnapshotSource`, usually the processor that handled the
+//! messages, and a marker with the last message in the snapshot is written in front of it.
+//!
+//! ```text
+//!  events:    1 2 3 ... 499 500 | 501 502 ...
+//!  snapshot:  state after 500   |
+//!             remove_files_before(500) -> the event files with only 1..=500 in them
+//! ```
+//! The layout of a snapshot.
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Magic                                                         |
+//! +---------------------------------------------------------------+ 32
+//! | Version                                                       |
+//! +---------------------------------------------------------------+ 64
+//! | Id of the last message in the snapshot                        |
+//! |                                                               |
+//! +---------------------------------------------------------------+ 128
+//! | Id of the term the message was committed in                   |
+//! |                                                               |
+//! +---------------------------------------------------------------+ 192
+//! | When the snapshot was taken in milliseconds since the epoch   |
+//! |                                                               |
+//! +---------------------------------------------------------------+ 256
+//! | Length of the state                                           |
+//! |                                                               |
+//! +---------------------------------------------------------------+ 320
+//! |                   State                                       ...
+//! ...                                                             |
+//! +---------------------------------------------------------------+
+//! | CRC32 of the bytes before it                                  |
+//! +---------------------------------------------------------------+
+//! ```
+//! A snapshot from another server is installed into a directory the store isn't running in.  The
+//! new files are built in a directory next to them and a marker file is written before the old
+//! files are replaced, a crash after that finishes the install the next time the store starts.
+//! ```text
+//!  file_prefix.install/   events.1 = [snapshot marker (id 500)]  commit.1 = [term up to 500]
+//!  file_prefix.installing  -> remove the old event, commit and index files -> move the new ones in
+//! ```
+use crate::error::{Context, PersistError, ResultExt};
+use crate::file;
+use crate::file::header::create_commit_file;
+use crate::file::MessageFileStore;
+use crate::raft::{
+    create_commit_name, create_event_name, create_index_name, CommitFile, PersistedMessageFile,
+    ProcessError, TermCommit, COMMIT_FILE_POSTIX, EVENT_FILE_POSTFIX, INDEX_FILE_POSTFIX,
+};
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, MAIN_SEPARATOR};
+use std::sync::atomic;
+
+/// Marks the start of a snapshot.  `A19S` in ascii.
+pub const SNAPSHOT_MAGIC: u32 = 0x4131_3953;
+/// The version of the snapshot layout.
+pub const SNAPSHOT_VERSION: u32 = 1;
+/// The size of the header in front of the state.
+pub const SNAPSHOT_HEADER_SIZE: usize = 40;
+const MAGIC: usize = 0;
+const VERSION: usize = 4;
+const LAST_INCLUDED_ID: usize = 8;
+const TERM_ID: usize = 16;
+const TIMESTAMP: usize = 24;
+const STATE_LENGTH: usize = 32;
+/// The message type of the marker at the start of an installed snapshot.  The body is the id of
+/// the last message in the snapshot, the id of its term and when the snapshot was taken.
+pub const SNAPSHOT_MESSAGE_TYPE: i32 = -5;
+/// The size of the body of the marker message.
+const MARKER_SIZE: usize = 24;
+
+/// Where a snapshot is in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SnapshotMarker {
+    /// The id of the last message in the snapshot.
+    pub last_included_id: u64,
+    /// The id of the term the message was committed in.
+    pub term_id: u64,
+    /// When the snapshot was taken in milliseconds since the epoch.
+    pub timestamp: u64,
+}
+
+/// Encodes the state built from the messages for a snapshot.
+pub trait SnapshotSource {
+    /// Encodes the state as it was after the message was handled onto the end of the bytes.
+    /// # Arguments
+    /// `last_included_id` - The id of the last message in the snapshot.
+    /// `bytes` - The bytes to add the state to.
+    /// # Returns
+    /// An error if the state can't be encoded at the message.
+    fn snapshot(&mut self, last_included_id: u64, bytes: &mut Vec<u8>) -> Result<(), ProcessError>;
+}
+
+/// Restores the state from a snapshot.
+pub trait SnapshotSink {
+    /// Replaces the state with the one in the snapshot.
+    /// # Arguments
+    /// `marker` - Where the snapshot is in the log.
+    /// `state` - The state encoded by the source.
+    /// # Returns
+    /// An error if the state can't be decoded.
+    fn install(&mut self, marker: &SnapshotMarker, state: &[u8]) -> Result<(), ProcessError>;
+}
+
+/// Writes a snapshot.
+/// # Arguments
+/// `marker` - Where the snapshot is in the log.
+/// `state` - The state encoded by the source.
+/// `writer` - Where to write the snapshot to.
+pub fn write_snapshot<W: Write>(
+    marker: &SnapshotMarker,
+    state: &[u8],
+    writer: &mut W,
+) -> std::io::Result<()> {
+    let mut header = [0; SNAPSHOT_HEADER_SIZE];
+    BigEndian::write_u32(&mut header[MAGIC..], SNAPSHOT_MAGIC);
+    BigEndian::write_u32(&mut header[VERSION..], SNAPSHOT_VERSION);
+    BigEndian::write_u64(&mut header[LAST_INCLUDED_ID..], marker.last_included_id);
+    BigEndian::write_u64(&mut header[TERM_ID..], marker.term_id);
+    BigEndian::write_u64(&mut header[TIMESTAMP..], marker.timestamp);
+    BigEndian::write_u64(&mut header[STATE_LENGTH..], state.len() as u64);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hasher.update(state);
+    let mut crc = [0; 4];
+    BigEndian::write_u32(&mut crc, hasher.finalize());
+    writer.write_all(&header)?;
+    writer.write_all(state)?;
+    writer.write_all(&crc)?;
+    writer.flush()
+}
+
+/// Reads a snapshot and checks it.
+/// # Arguments
+/// `reader` - Where to read the snapshot from.
+/// # Returns
+/// Where the snapshot is in the log and the state or `CorruptMessage` if it isn't a valid
+/// snapshot.
+pub fn read_snapshot<R: Read>(mut reader: R) -> crate::Result<(SnapshotMarker, Vec<u8>)> {
+    let corrupt = |reason: &str| PersistError::CorruptMessage {
+        context: Context::default(),
+        reason: reason.to_owned(),
+    };
+    let mut header = [0; SNAPSHOT_HEADER_SIZE];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| corrupt("The snapshot is too short."))?;
+    if BigEndian::read_u32(&header[MAGIC..]) != SNAPSHOT_MAGIC {
+        return Err(corrupt("The snapshot doesn't start with the magic number."));
+    }
+    let version = BigEndian::read_u32(&header[VERSION..]);
+    if version != SNAPSHOT_VERSION {
+        return Err(corrupt(&format!(
+            "The snapshot version {} isn't supported.",
+            version
+        )));
+    }
+    let marker = SnapshotMarker {
+        last_included_id: BigEndian::read_u64(&header[LAST_INCLUDED_ID..]),
+        term_id: BigEndian::read_u64(&header[TERM_ID..]),
+        timestamp: BigEndian::read_u64(&header[TIMESTAMP..]),
+    };
+    if marker.last_included_id == 0 {
+        return Err(corrupt("The snapshot doesn't have any messages in it."));
+    }
+    let length = BigEndian::read_u64(&header[STATE_LENGTH..]);
+    // Read through take so a bad length can't allocate more than is there.
+    let mut state = Vec::new();
+    reader
+        .by_ref()
+        .take(length)
+        .read_to_end(&mut state)
+        .map_err(|e| PersistError::from(e).with_id(marker.last_included_id))?;
+    let mut crc = [0; 4];
+    if state.len() as u64 != length || reader.read_exact(&mut crc).is_err() {
+        return Err(corrupt("The snapshot is too short."));
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hasher.update(&state);
+    if hasher.finalize() != BigEndian::read_u32(&crc) {
+        return Err(corrupt("The checksum of the snapshot doesn't match."));
+    }
+    Ok((marker, state))
+}
+
+/// Installs a snapshot from another server into a directory.  The store can't be running in the
+/// directory.  The state is handed to the sink and the event, commit and index files are replaced
+/// with a pair that has the snapshot committed in it so the next message written is the one after
+/// it.  The meta file with the raft term is kept.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// `max_file_size` - The size of an event file.
+/// `commit_file_size` - The size of a commit file.
+/// `reader` - Where to read the snapshot from.
+/// `sink` - Restores the state.
+/// # Returns
+/// Where the snapshot is in the log.  Nothing is changed in the directory if the snapshot isn't
+/// valid or the sink fails.
+pub fn install_snapshot<R: Read, S: SnapshotSink>(
+    file_storage_directory: &str,
+    file_prefix: &str,
+    max_file_size: usize,
+    commit_file_size: usize,
+    reader: R,
+    sink: &mut S,
+) -> crate::Result<SnapshotMarker> {
+    let (marker, state) = read_snapshot(reader)?;
+    sink.install(&marker, &state)
+        .map_err(|_| PersistError::from(file::Error::ProcessFailed(marker.last_included_id)))?;
+    stage_install(
+        file_storage_directory,
+        file_prefix,
+        max_file_size,
+        commit_file_size,
+        &marker,
+    )?;
+    let path = create_install_marker_name(file_storage_directory, file_prefix);
+    File::create(&path)
+        .and_then(|f| f.sync_all())
+        .with_path(path.as_str())?;
+    finish_install(file_storage_directory, file_prefix).with_path(file_storage_directory)?;
+    Ok(marker)
+}
+
+/// Builds the files for an installed snapshot in the install directory.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// `max_file_size` - The size of an event file.
+/// `commit_file_size` - The size of a commit file.
+/// `marker` - Where the snapshot is in the log.
+fn stage_install(
+    file_storage_directory: &str,
+    file_prefix: &str,
+    max_file_size: usize,
+    commit_file_size: usize,
+    marker: &SnapshotMarker,
+) -> crate::Result<()> {
+    let staging = create_install_name(file_storage_directory, file_prefix);
+    if Path::new(&staging).exists() {
+        remove_dir_all(&staging).with_path(staging.as_str())?;
+    }
+    create_dir_all(&staging).with_path(staging.as_str())?;
+    let file_id = 1;
+    let path = create_event_name(&staging, file_prefix, &file_id);
+    let (_read, write) =
+        unsafe { MessageFileStore::new(&path, max_file_size) }.with_path(path.as_str())?;
+    let mut body = [0; MARKER_SIZE];
+    BigEndian::write_u64(&mut body[0..], marker.last_included_id);
+    BigEndian::write_u64(&mut body[8..], marker.term_id);
+    BigEndian::write_u64(&mut body[16..], marker.timestamp);
+    let length = write
+        .write(0, SNAPSHOT_MESSAGE_TYPE, marker.last_included_id, &body)
+        .with_path(path.as_str())?;
+    write.flush().with_path(path.as_str())?;
+    let path = create_commit_name(&staging, file_prefix, &file_id);
+    let mut buffer =
+        unsafe { create_commit_file(&path, commit_file_size) }.with_path(path.as_str())?;
+    let term = TermCommit {
+        // The term keeps its id so the terms after it carry on from it.
+        term_id: marker.term_id.max(1),
+        version: 1,
+        type_id: 1,
+        server_id: 1,
+        leader_id: 1,
+        committed: 1,
+        timestamp: marker.timestamp,
+        committed_timestamp: marker.timestamp,
+        file_id,
+        file_position_offset: 0,
+        file_max_message_id: marker.last_included_id,
+        length: length as u32,
+    };
+    buffer.save_term(0, &term);
+    buffer.flush().with_path(path.as_str())?;
+    Ok(())
+}
+
+/// Finishes an install that was interrupted.  The old files are removed and the ones in the install
+/// directory are moved in.  An install directory without the marker file wasn't finished being
+/// built so it is removed.  Is safe to run again if it is interrupted.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// # Returns
+/// True if an install was finished.
+pub(crate) fn finish_install(file_storage_directory: &str, file_prefix: &str) -> io::Result<bool> {
+    let staging = create_install_name(file_storage_directory, file_prefix);
+    let marker = create_install_marker_name(file_storage_directory, file_prefix);
+    if !Path::new(&marker).exists() {
+        if Path::new(&staging).exists() {
+            remove_dir_all(&staging)?;
+        }
+        return Ok(false);
+    }
+    if Path::new(&staging).exists() {
+        let prefixes: Vec<String> = [EVENT_FILE_POSTFIX, COMMIT_FILE_POSTIX, INDEX_FILE_POSTFIX]
+            .iter()
+            .map(|postfix| format!("{}.{}.", file_prefix, postfix))
+            .collect();
+        // The installed files replace the old ones with the same name when they are moved in.
+        let installed = [
+            create_event_name(file_storage_directory, file_prefix, &1),
+            create_commit_name(file_storage_directory, file_prefix, &1),
+        ];
+        for entry in read_dir(file_storage_directory)? {
+            let path = entry?.path();
+            let is_store_file = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| prefixes.iter().any(|p| n.starts_with(p)))
+                .unwrap_or(false);
+            if is_store_file && path.is_file() && !installed.iter().any(|i| Path::new(i) == path) {
+                remove_file(&path)?;
+            }
+        }
+        let mut staged = Vec::new();
+        for entry in read_dir(&staging)? {
+            staged.push(entry?.file_name());
+        }
+        for name in staged {
+            rename(
+                Path::new(&staging).join(&name),
+                Path::new(file_storage_directory).join(&name),
+            )?;
+        }
+        File::open(file_storage_directory).and_then(|f| f.sync_all())?;
+        remove_dir_all(&staging)?;
+    }
+    remove_file(&marker)?;
+    Ok(true)
+}
+
+/// Creates the name of the directory an installed snapshot is built in.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+fn create_install_name(file_storage_directory: &str, file_prefix: &str) -> String {
+    format!(
+        "{}{}{}.install",
+        file_storage_directory, MAIN_SEPARATOR, file_prefix
+    )
+}
+
+/// Creates the name of the file marking the install directory as ready to be moved in.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+fn create_install_marker_name(file_storage_directory: &str, file_prefix: &str) -> String {
+    format!(
+        "{}{}{}.installing",
+        file_storage_directory, MAIN_SEPARATOR, file_prefix
+    )
+}
+
+impl PersistedMessageFile {
+    /// Takes a snapshot of the state at a committed message.  The source needs to have handled
+    /// the messages up to the id and none after it.
+    /// # Arguments
+    /// `up_to_message_id` - The id of the last message in the snapshot.
+    /// `source` - Encodes the state.
+    /// `writer` - Where to write the snapshot to.
+    /// # Returns
+    /// Where the snapshot is in the log.  `Retained` if the term of the message is no longer in
+    /// the commit files.
+    pub fn create_snapshot<S: SnapshotSource, W: Write>(
+        &self,
+        up_to_message_id: u64,
+        source: &mut S,
+        mut writer: W,
+    ) -> crate::Result<SnapshotMarker> {
+        if up_to_message_id == 0 || up_to_message_id > self.max_message_id() {
+            return Err(PersistError::from(file::Error::NoMessage).with_id(up_to_message_id));
+        }
+        let term = self.find_term(up_to_message_id).ok_or_else(|| {
+            PersistError::from(file::Error::NotRetained {
+                message_id: up_to_message_id,
+                earliest: 0,
+            })
+        })?;
+        let mut state = Vec::new();
+        source
+            .snapshot(up_to_message_id, &mut state)
+            .map_err(|_| PersistError::from(file::Error::ProcessFailed(up_to_message_id)))?;
+        let marker = SnapshotMarker {
+            last_included_id: up_to_message_id,
+            term_id: term.term_id,
+            timestamp: self.clock.now_ms(),
+        };
+        write_snapshot(&marker, &state, &mut writer)?;
+        Ok(marker)
+    }
+
+    /// Removes the event files that only have the messages up to an id in them.  Is called after a
+    /// snapshot at the id has been saved.  The file being written to is always kept and the
+    /// commit files are left for the retention.  The iterators reading a removed file get
+    /// `FileRemoved`.
+    /// # Arguments
+    /// `message_id` - The id of the last message in the snapshot.
+    /// # Returns
+    /// The ids of the files removed.
+    pub fn remove_files_before(&self, message_id: u64) -> crate::Result<Vec<u32>> {
+        if self.followed_files.is_some() {
+            return Err(PersistError::from(file::Error::ReadOnly));
+        }
+        let message_id = message_id.min(self.max_message_id.load(atomic::Ordering::Acquire));
+        let mut removed = Vec::new();
+        {
+            let mut message_files = self.message_files.lock().unwrap();
+            let starts: Vec<u64> = message_files.iter().map(|f| f.message_id_start).collect();
+            for (file, next_start) in message_files.iter_mut().zip(starts.into_iter().skip(1)) {
+                // The next file could start with the rest of a message split between them so it
+                // has to start at or before the id.
+                if next_start > message_id {
+                    break;
+                }
+                if file.removed {
+                    continue;
+                }
+                remove_file(&file.path)?;
+                let index = create_index_name(
+                    &self.file_storage_directory,
+                    &self.file_prefix,
+                    &file.file_id,
+                );
+                if Path::new(&index).exists() {
+                    remove_file(&index)?;
+                }
+                file.removed = true;
+                removed.push(file.file_id);
+            }
+        }
+        {
+            let mut readers = self.event_readers.lock().unwrap();
+            for file_id in removed.iter() {
+                readers.remove(file_id);
+            }
+        }
+        let stale: Vec<u64> = self
+            .positions
+            .iter()
+            .filter(|(_, (file_id, _))| removed.contains(file_id))
+            .map(|(id, _)| id)
+            .collect();
+        for id in stale {
+            self.positions.remove(&id);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::raft::snapshot::*;
+    use crate::raft::{startup_single_node, MessageProcessor, MessageRead};
+    use crate::PersitEventStream;
+    use futures::executor::block_on;
+    use std::fs::remove_dir_all;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_snapshot";
+    const TEST_PREFIX: &str = "snapshot";
+
+    struct NoOp;
+
+    impl MessageProcessor for NoOp {
+        fn handle(&mut self, _read: MessageRead<'_>) -> Result<(), ProcessError> {
+            Ok(())
+        }
+    }
+
+    /// Sums the values of the messages.
+    #[derive(Default)]
+    struct Sum {
+        last_id: u64,
+        total: u64,
+    }
+
+    impl SnapshotSource for Sum {
+        fn snapshot(
+            &mut self,
+            last_included_id: u64,
+            bytes: &mut Vec<u8>,
+        ) -> Result<(), ProcessError> {
+            if last_included_id != self.last_id {
+                return Err(ProcessError::new("The state is at a different message."));
+            }
+            bytes.extend_from_slice(&self.total.to_be_bytes());
+            Ok(())
+        }
+    }
+
+    impl SnapshotSink for Sum {
+        fn install(&mut self, marker: &SnapshotMarker, state: &[u8]) -> Result<(), ProcessError> {
+            if state.len() != 8 {
+                return Err(ProcessError::new("The state isn't a sum."));
+            }
+            self.last_id = marker.last_included_id;
+            self.total = BigEndian::read_u64(state);
+            Ok(())
+        }
+    }
+
+    fn value(bytes: &[u8]) -> u64 {
+        let mut value = [0; 8];
+        value.copy_from_slice(&bytes[..8]);
+        u64::from_le_bytes(value)
+    }
+
+    fn start(dir: &str) -> PersistedMessageFile {
+        startup_single_node(
+            dir.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x1000,
+            0x10000,
+            NoOp,
+            0x10000,
+            0x40,
+        )
+    }
+
+    /// Writes the messages with their id as the value and takes a snapshot at an id.
+    fn snapshot_at(dir: &str, count: u64, at: u64) -> Vec<u8> {
+        clean(dir);
+        let mut store = start(dir);
+        for i in 1..=count {
+            block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap();
+        }
+        let mut sum = Sum::default();
+        for event in store.events_from(1).take(at as usize) {
+            sum.last_id = event.commit_key;
+            sum.total += value(event.value);
+        }
+        let mut bytes = Vec::new();
+        store.create_snapshot(at, &mut sum, &mut bytes).unwrap();
+        store.stop();
+        bytes
+    }
+
+    fn clean(dir: &str) {
+        let path = Path::new(dir);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn snapshot_test() {
+        let dir = format!("{}/mid_log", TEST_DIR);
+        clean(&dir);
+        let mut store = startup_single_node(
+            dir.clone(),
+            TEST_PREFIX.to_owned(),
+            0x1000,
+            0x10000,
+            NoOp,
+            0x10000,
+            0x40,
+        );
+        for i in 1..=500u64 {
+            assert_eq!(
+                i,
+                block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap()
+            );
+        }
+        let mut sum = Sum::default();
+        for event in store.events_from(1).take(300) {
+            sum.last_id = event.commit_key;
+            sum.total += value(event.value);
+        }
+        assert!(store.create_snapshot(299, &mut sum, Vec::new()).is_err());
+        assert!(store.create_snapshot(501, &mut sum, Vec::new()).is_err());
+
+        let mut bytes = Vec::new();
+        let marker = store.create_snapshot(300, &mut sum, &mut bytes).unwrap();
+        assert_eq!(300, marker.last_included_id);
+        assert!(marker.term_id > 0);
+        assert_eq!(SNAPSHOT_HEADER_SIZE + 8 + 4, bytes.len());
+        assert_eq!(SNAPSHOT_MAGIC, BigEndian::read_u32(&bytes[MAGIC..]));
+        assert_eq!(SNAPSHOT_VERSION, BigEndian::read_u32(&bytes[VERSION..]));
+        assert_eq!(300, BigEndian::read_u64(&bytes[LAST_INCLUDED_ID..]));
+        assert_eq!(marker.term_id, BigEndian::read_u64(&bytes[TERM_ID..]));
+        assert_eq!(
+            (1..=300u64).sum::<u64>(),
+            BigEndian::read_u64(&bytes[SNAPSHOT_HEADER_SIZE..])
+        );
+        let crc = bytes.len() - 4;
+        assert_eq!(
+            crc32fast::hash(&bytes[..crc]),
+            BigEndian::read_u32(&bytes[crc..])
+        );
+
+        let removed = store.remove_files_before(marker.last_included_id).unwrap();
+        assert!(!removed.is_empty());
+        assert_eq!(1, removed[0]);
+        assert!(store
+            .remove_files_before(marker.last_included_id)
+            .unwrap()
+            .is_empty());
+        // The messages after the snapshot can still be read.
+        let ids: Vec<u64> = store.events_from(301).map(|e| e.commit_key).collect();
+        assert_eq!((301..=500).collect::<Vec<u64>>(), ids);
+        let total: u64 = store.events_from(301).map(|e| value(e.value)).sum();
+        assert_eq!((1..=500u64).sum::<u64>(), sum.total + total);
+        assert_eq!(
+            501,
+            block_on(store.write(1, &501u64.to_le_bytes()))
+                .unwrap()
+                .unwrap()
+        );
+        assert_eq!(
+            Some(501),
+            store.events_from(501).map(|e| e.commit_key).next()
+        );
+        store.stop();
+    }
+
+    #[test]
+    pub fn install_test() {
+        let bytes = snapshot_at(&format!("{}/install_a", TEST_DIR), 500, 300);
+        let dir = format!("{}/install_b", TEST_DIR);
+        clean(&dir);
+        let mut sum = Sum::default();
+        // A bad snapshot is caught before anything is changed.
+        let mut bad = bytes.clone();
+        bad[SNAPSHOT_HEADER_SIZE] ^= 1;
+        assert!(install_snapshot(&dir, TEST_PREFIX, 0x1000, 0x10000, &bad[..], &mut sum).is_err());
+        assert!(
+            install_snapshot(&dir, TEST_PREFIX, 0x1000, 0x10000, &bytes[..10], &mut sum).is_err()
+        );
+        assert_eq!(0, sum.total);
+
+        let marker =
+            install_snapshot(&dir, TEST_PREFIX, 0x1000, 0x10000, &bytes[..], &mut sum).unwrap();
+        assert_eq!(300, marker.last_included_id);
+        assert_eq!((300, (1..=300u64).sum::<u64>()), (sum.last_id, sum.total));
+        assert!(!Path::new(&create_install_name(&dir, TEST_PREFIX)).exists());
+
+        let mut store = start(&dir);
+        assert_eq!(300, store.max_message_id());
+        assert_eq!(
+            Some(marker.term_id),
+            store.find_term(300).map(|t| t.term_id)
+        );
+        for i in 301..=350u64 {
+            assert_eq!(
+                i,
+                block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap()
+            );
+        }
+        let ids: Vec<u64> = store.events_from(1).map(|e| e.commit_key).collect();
+        assert_eq!((301..=350).collect::<Vec<u64>>(), ids);
+        store.stop();
+        // Picks up after the new messages when it is opened again.
+        let mut store = start(&dir);
+        assert_eq!(
+            351,
+            block_on(store.write(1, &351u64.to_le_bytes()))
+                .unwrap()
+                .unwrap()
+        );
+        store.stop();
+    }
+
+    #[test]
+    pub fn install_crash_test() {
+        let bytes = snapshot_at(&format!("{}/crash_a", TEST_DIR), 200, 150);
+        let dir = format!("{}/crash_b", TEST_DIR);
+        clean(&dir);
+        let mut store = start(&dir);
+        for i in 1..=400u64 {
+            block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap();
+        }
+        store.stop();
+        let (marker, _) = read_snapshot(&bytes[..]).unwrap();
+
+        // Crashed before the marker file was written so the old files are kept.
+        stage_install(&dir, TEST_PREFIX, 0x1000, 0x10000, &marker).unwrap();
+        let mut store = start(&dir);
+        assert_eq!(400, store.max_message_id());
+        store.stop();
+        assert!(!Path::new(&create_install_name(&dir, TEST_PREFIX)).exists());
+
+        // Crashed after the marker file was written so the install is finished on startup.
+        stage_install(&dir, TEST_PREFIX, 0x1000, 0x10000, &marker).unwrap();
+        File::create(create_install_marker_name(&dir, TEST_PREFIX)).unwrap();
+        let mut store = start(&dir);
+        assert_eq!(150, store.max_message_id());
+        assert_eq!(
+            151,
+            block_on(store.write(1, &151u64.to_le_bytes()))
+                .unwrap()
+                .unwrap()
+        );
+        assert_eq!(
+            vec![151],
+            store
+                .events_from(1)
+                .map(|e| e.commit_key)
+                .collect::<Vec<u64>>()
+        );
+        store.stop();
+        assert!(!Path::new(&create_install_marker_name(&dir, TEST_PREFIX)).exists());
+    }
+}
@@ -0,0 +1,414 @@
+//! A sparse index kept next to each event file so a reader can start close to a message instead of
+//! scanning the file from the start.  The writer adds an entry for the first message in the file
+//! and then every `interval` messages.  The index is only a hint, every entry is checked against
+//! the event file before it is used and a missing or bad index falls back to the scan.
+//!
+//! ```text
+//!  file_prefix.index.N
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | Message Id                                                    |
+//! |                                                               | 64
+//! +---------------------------------------------------------------+
+//! | Position in the event file                                    |
+//! |                                                               | 128
+//! +---------------------------------------------------------------+
+//! | Next entry                                                   ...
+//! ```
+use crate::file::{Error, MessageFileStoreRead};
+use crate::raft::{create_event_name, create_index_name, INDEX_FILE_POSTFIX};
+use std::fs::{read, rename, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The default number of messages between the entries.
+pub const DEFAULT_INDEX_INTERVAL: u64 = 1024;
+/// The size of an entry.
+const ENTRY_SIZE: usize = 16;
+
+/// The id of a message and where it is in the event file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub message_id: u64,
+    pub position: usize,
+}
+
+impl IndexEntry {
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0; ENTRY_SIZE];
+        bytes[..8].copy_from_slice(&self.message_id.to_be_bytes());
+        bytes[8..].copy_from_slice(&(self.position as u64).to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut message_id = [0; 8];
+        let mut position = [0; 8];
+        message_id.copy_from_slice(&bytes[..8]);
+        position.copy_from_slice(&bytes[8..ENTRY_SIZE]);
+        IndexEntry {
+            message_id: u64::from_be_bytes(message_id),
+            position: u64::from_be_bytes(position) as usize,
+        }
+    }
+}
+
+/// Gets the path of the index for an event file.
+/// # Arguments
+/// `event_path` - The path of the event file.
+/// # Returns
+/// The path or `None` if the name isn't for an event file.
+pub(crate) fn index_path_for(event_path: &str) -> Option<PathBuf> {
+    let path = Path::new(event_path);
+    let name = path.file_name()?.to_str()?;
+    let mut parts = name.rsplitn(3, '.');
+    let file_id = parts.next()?;
+    parts.next()?;
+    let prefix = parts.next()?;
+    Some(path.with_file_name(format!("{}.{}.{}", prefix, INDEX_FILE_POSTFIX, file_id)))
+}
+
+/// Reads in the entries of an index.  A partly written entry at the end is dropped and the entries
+/// stop at the first one that is out of order.
+/// # Arguments
+/// `path` - The path of the index.
+/// # Returns
+/// The entries or an error if the file can't be read.
+pub fn read_index<P: AsRef<Path>>(path: &P) -> io::Result<Vec<IndexEntry>> {
+    let bytes = read(path)?;
+    let mut entries: Vec<IndexEntry> = Vec::with_capacity(bytes.len() / ENTRY_SIZE);
+    for chunk in bytes.chunks_exact(ENTRY_SIZE) {
+        let entry = IndexEntry::from_bytes(chunk);
+        match entries.last() {
+            Some(last)
+                if last.message_id >= entry.message_id || last.position >= entry.position =>
+            {
+                break
+            }
+            _ => entries.push(entry),
+        }
+    }
+    Ok(entries)
+}
+
+/// Goes through the headers of the messages in an event file to create the entries for it.
+/// # Arguments
+/// `reader` - The event file.
+/// `interval` - The number of messages between the entries.
+fn build_entries(reader: &MessageFileStoreRead, interval: u64) -> Vec<IndexEntry> {
+    let mut entries: Vec<IndexEntry> = Vec::new();
+    let mut pos = 0;
+    while let Ok((message_id, next_pos)) = reader.read_link(pos) {
+        if message_id == u64::MAX {
+            // The end of the file.
+            break;
+        }
+        let due = match entries.last() {
+            Some(last) => message_id >= last.message_id + interval,
+            None => true,
+        };
+        if due {
+            entries.push(IndexEntry {
+                message_id,
+                position: pos,
+            });
+        }
+        pos = next_pos;
+    }
+    entries
+}
+
+/// Writes the index for an event file from the messages in it.  The index is written to a
+/// temporary file first so a reader never sees it half written.
+/// # Arguments
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// `file_id` - The id of the event file.
+/// `interval` - The number of messages between the entries.
+/// # Returns
+/// The entries written.
+pub fn rebuild_index(
+    file_storage_directory: &str,
+    file_prefix: &str,
+    file_id: u32,
+    interval: u64,
+) -> crate::file::Result<Vec<IndexEntry>> {
+    let event_path = create_event_name(file_storage_directory, file_prefix, &file_id);
+    let reader = unsafe { crate::file::MessageFileStore::open_readonly(&event_path)? };
+    let entries = build_entries(&reader, interval.max(1));
+    let path = create_index_name(file_storage_directory, file_prefix, &file_id);
+    let temp = format!("{}.tmp", path);
+    let mut file = File::create(&temp)?;
+    for entry in entries.iter() {
+        file.write_all(&entry.to_bytes())?;
+    }
+    file.sync_all()?;
+    rename(&temp, &path)?;
+    Ok(entries)
+}
+
+/// Finds where to start looking for a message in an event file.
+/// # Arguments
+/// `reader` - The event file.
+/// `event_path` - The path of the event file.
+/// `message_id` - The id of the message to find.
+/// # Returns
+/// The position of the closest message at or before the id or 0 if the index can't be used.
+pub(crate) fn start_position(
+    reader: &MessageFileStoreRead,
+    event_path: &str,
+    message_id: u64,
+) -> usize {
+    let entries = match index_path_for(event_path).map(|path| read_index(&path)) {
+        Some(Ok(entries)) => entries,
+        _ => return 0,
+    };
+    let after = entries.partition_point(|e| e.message_id <= message_id);
+    if after == 0 {
+        return 0;
+    }
+    let entry = entries[after - 1];
+    match reader.read_link(entry.position) {
+        Ok((id, _)) if id == entry.message_id => entry.position,
+        Ok(_) | Err(Error::NoMessage) => {
+            log::warn!(
+                "The index for {} doesn't match the messages, scanning the file instead.",
+                event_path
+            );
+            0
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Adds the entries to the index of the file being written to.
+pub(crate) struct SparseIndexWriter {
+    file_storage_directory: String,
+    file_prefix: String,
+    interval: u64,
+    /// The id of the event file the index is for.
+    file_id: u32,
+    /// The index file.  `None` once a write fails so the index stops being updated.
+    file: Option<File>,
+    /// The id of the message in the last entry.
+    last_indexed_id: Option<u64>,
+}
+
+impl SparseIndexWriter {
+    /// Opens the index of the event file being written to.  The index is rebuilt from the messages
+    /// already in the file so an index that is missing or has the entries of messages that were
+    /// cleared when the store was opened is fixed.
+    /// # Arguments
+    /// `file_storage_directory` - The directory the files are in.
+    /// `file_prefix` - The prefix of the files.
+    /// `file_id` - The id of the event file being written to.
+    /// `interval` - The number of messages between the entries.
+    pub(crate) fn open(
+        file_storage_directory: &str,
+        file_prefix: &str,
+        file_id: u32,
+        interval: u64,
+    ) -> Self {
+        let mut writer = SparseIndexWriter {
+            file_storage_directory: file_storage_directory.to_owned(),
+            file_prefix: file_prefix.to_owned(),
+            interval: interval.max(1),
+            file_id,
+            file: None,
+            last_indexed_id: None,
+        };
+        match rebuild_index(file_storage_directory, file_prefix, file_id, interval) {
+            Ok(entries) => {
+                writer.last_indexed_id = entries.last().map(|e| e.message_id);
+                writer.file = writer.open_file(false);
+            }
+            Err(e) => log::error!("Unable to rebuild the index for file {}: {}", file_id, e),
+        }
+        writer
+    }
+
+    /// The number of messages between the entries.
+    pub(crate) fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Opens the index file for appending.
+    /// # Arguments
+    /// `truncate` - True to start the index over.
+    fn open_file(&self, truncate: bool) -> Option<File> {
+        let path = create_index_name(
+            &self.file_storage_directory,
+            &self.file_prefix,
+            &self.file_id,
+        );
+        let mut options = OpenOptions::new();
+        options.create(true);
+        if truncate {
+            options.write(true).truncate(true);
+        } else {
+            options.append(true);
+        }
+        match options.open(&path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log::error!("Unable to open the index {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Adds an entry if the message is due for one.
+    /// # Arguments
+    /// `file_id` - The id of the event file the message was written to.
+    /// `message_id` - The id of the message.
+    /// `position` - Where the message was written.
+    pub(crate) fn add(&mut self, file_id: u32, message_id: u64, position: usize) {
+        if file_id != self.file_id {
+            // Rolled onto the next file.
+            self.file_id = file_id;
+            self.last_indexed_id = None;
+            self.file = self.open_file(true);
+        }
+        let due = match self.last_indexed_id {
+            Some(last) => position == 0 || message_id >= last + self.interval,
+            None => true,
+        };
+        if !due {
+            return;
+        }
+        if let Some(file) = &mut self.file {
+            let entry = IndexEntry {
+                message_id,
+                position,
+            };
+            match file.write_all(&entry.to_bytes()) {
+                Ok(_) => self.last_indexed_id = Some(message_id),
+                Err(e) => {
+                    // Readers fall back to scanning the file.
+                    log::error!("Unable to write to the index for file {}: {}", file_id, e);
+                    self.file = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::backend::FileBackend;
+    use crate::raft::sparse_index::*;
+    use crate::raft::{load_current_files, process_files, MessageIterator};
+    use crate::raft::{NextResult, PersistedMessageWriteStream};
+    use std::fs::{remove_dir_all, remove_file, write};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_sparse_index";
+    const TEST_PREFIX: &str = "sparse";
+
+    fn first_id(iterator: &mut MessageIterator) -> u64 {
+        match iterator.next().unwrap() {
+            NextResult::Some(msg) => msg.message_id(),
+            _ => panic!("Expected a message."),
+        }
+    }
+
+    #[test]
+    pub fn sparse_index_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let file_size = 0x10000;
+        let mut collection = load_current_files(TEST_PREFIX, TEST_DIR).unwrap();
+        process_files(&mut collection, TEST_PREFIX, TEST_DIR, &file_size, &0x1000).unwrap();
+        let mut writer = PersistedMessageWriteStream::new(
+            1,
+            TEST_DIR.to_owned(),
+            TEST_PREFIX.to_owned(),
+            file_size,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap()
+        .with_message_files(collection.message_files.clone())
+        .with_sparse_index(10);
+        for message_id in 1..=1000u64 {
+            writer
+                .add_message(1, message_id, &message_id.to_le_bytes())
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        let index_path = create_index_name(TEST_DIR, TEST_PREFIX, &1);
+        let entries = read_index(&index_path).unwrap();
+        assert_eq!(100, entries.len());
+        assert_eq!(
+            IndexEntry {
+                message_id: 1,
+                position: 0
+            },
+            entries[0]
+        );
+        assert_eq!(991, entries[99].message_id);
+        // The same entries are created from the messages.
+        assert_eq!(
+            entries,
+            rebuild_index(TEST_DIR, TEST_PREFIX, 1, 10).unwrap()
+        );
+
+        let event_path = create_event_name(TEST_DIR, TEST_PREFIX, &1);
+        let reader = unsafe { crate::file::MessageFileStore::open_readonly(&event_path).unwrap() };
+        // Each message takes up 32 bytes.
+        assert_eq!(0, start_position(&reader, &event_path, 5));
+        assert_eq!(500 * 32, start_position(&reader, &event_path, 505));
+        assert_eq!(990 * 32, start_position(&reader, &event_path, 5000));
+
+        let message_files = collection.message_files.clone();
+        let backend: Arc<dyn crate::file::backend::MessageStoreBackend> = Arc::new(FileBackend);
+        let mut iterator =
+            MessageIterator::new(10, 505, 1000, message_files.clone(), true, backend.clone())
+                .unwrap();
+        assert_eq!(505, first_id(&mut iterator));
+        iterator.seek_to(777).unwrap();
+        assert_eq!(777, first_id(&mut iterator));
+
+        // An index that doesn't match the messages is ignored.
+        let bad = IndexEntry {
+            message_id: 500,
+            position: 64,
+        };
+        write(&index_path, bad.to_bytes()).unwrap();
+        assert_eq!(0, start_position(&reader, &event_path, 505));
+        let mut iterator =
+            MessageIterator::new(10, 505, 1000, message_files.clone(), true, backend.clone())
+                .unwrap();
+        assert_eq!(505, first_id(&mut iterator));
+
+        // A partly written entry is dropped.
+        let mut bytes = entries[0].to_bytes().to_vec();
+        bytes.extend_from_slice(&entries[1].to_bytes()[..7]);
+        write(&index_path, bytes).unwrap();
+        assert_eq!(entries[..1].to_vec(), read_index(&index_path).unwrap());
+
+        // Missing falls back to the scan.
+        remove_file(&index_path).unwrap();
+        assert_eq!(0, start_position(&reader, &event_path, 505));
+        let mut iterator =
+            MessageIterator::new(10, 505, 1000, message_files, true, backend).unwrap();
+        assert_eq!(505, first_id(&mut iterator));
+
+        // The writer rebuilds the index of the file it opens.
+        let _writer = PersistedMessageWriteStream::new(
+            1,
+            TEST_DIR.to_owned(),
+            TEST_PREFIX.to_owned(),
+            file_size,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap()
+        .with_sparse_index(10);
+        assert_eq!(entries, read_index(&index_path).unwrap());
+    }
+}
@@ -1,18 +1,25 @@
+use crate::error::{Context, PersistError};
+use crate::file::header::{create_commit_file, open_commit_file};
+use crate::raft::election::{ElectionTimer, DEFAULT_ELECTION_BASE, DEFAULT_ELECTION_JITTER};
+use crate::raft::events::{StoreEvent, FOLLOWER_LAG_TERMS};
+use crate::raft::meta::RaftMeta;
 use crate::raft::network::{NetworkSend, NetworkSendType};
 use crate::raft::*;
 use crate::raft::{CommitFile, TermFile};
-use a19_concurrent::buffer::mmap_buffer::MemoryMappedInt;
 use a19_concurrent::buffer::ring_buffer::ManyToOneBufferWriter;
 use a19_concurrent::buffer::next_pos;
+use a19_concurrent::event_bus::EventBus;
 use a19_concurrent::queue::mpsc_queue::MpscQueueWrap;
 use a19_concurrent::queue::skip_queue::SkipQueueReader;
 use a19_concurrent::queue::spsc_queue::SpscQueueSendWrap;
-use a19_core::current_time_ms;
+use a19_core::clock::{system_clock, Clock};
+use futures::channel::oneshot;
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fs::*;
-use std::sync::atomic::AtomicU64;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 
 /// The internal message id.
@@ -21,13 +28,18 @@ pub const INTERNAL_MESSAGE_ID: i32 = 1000;
 pub const CREATE_SNAPSHOT: i32 = 1001;
 
 /// Internal messages that are committed as terms.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub(crate) enum InternalMessage {
-    AddServer { server_id: u32 },
+    /// Adds a server to the cluster.  A server that isn't voting is a learner and only receives
+    /// the replicated messages.
+    AddServer { server_id: u32, voting: bool },
     RemoveServer { server_id: u32 },
 }
 
-enum RaftState {
+/// The role of the running state machine with what it keeps track of in it.
+enum RoleState {
     Candidate {
         round_number: u32,
         votes: HashSet<u32>,
@@ -41,7 +53,9 @@ enum RaftState {
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub(crate) enum RaftEvent {
     VoteTimeout,
     LeaderTimeout,
@@ -76,24 +90,242 @@ pub(crate) enum RaftEvent {
     Pong {
         server_id: u32,
         max_term_id: u64,
+        /// The heartbeat round of the ping being responded to.
+        round: u64,
     },
     /// Sent to followers. {Leader} -> {Follower, Candidate}
     Ping {
         server_id: u32,
         max_commited_term: u64,
+        /// The heartbeat round so the pongs can be matched to it.
+        round: u64,
     },
-    /// The leader hasn't received a message. `Leader`
+    /// Sent on an interval when there aren't any other messages.  The leader sends a heartbeat and
+    /// the followers and candidates check the election timer.
     NoMessagesTimeout,
     /// The internal message to process after it has been committed.  This is internal messages received over the queue.
     ProcessInternalMessage {
         msg: InternalMessage,
     },
+    /// A request for the current read index. {Client, Follower} -> {Leader}
+    ReadIndexRequest {
+        server_id: u32,
+        request_id: u64,
+    },
+    /// The read index after the leader has confirmed it is still the leader. {Leader} -> {Follower}
+    ReadIndexResponse {
+        server_id: u32,
+        request_id: u64,
+        commit_term: u64,
+    },
+    /// Transfer the leadership to another server. {Client} -> {Leader}
+    TransferLeadership {
+        server_id: u32,
+    },
+    /// Start an election right away since the leader is transferring the leadership to us. {Leader} -> {Follower}
+    TimeoutNow {
+        server_id: u32,
+    },
+    /// The network connection to a server has gone up or down.
+    PeerConnectionChanged {
+        server_id: u32,
+        connected: bool,
+    },
+}
+
+/// A read index request on the leader waiting for a quorum to confirm the leadership.
+struct PendingReadIndex {
+    /// The server requesting the read index.
+    server_id: u32,
+    /// The id of the request so the server can match the response.
+    request_id: u64,
+    /// The committed term when the request was received.
+    commit_term: u64,
+    /// The heartbeat round sent for the request.  Only the pongs from this round or later count.
+    round: u64,
+    /// The servers that have responded to a heartbeat since the request was received.
+    acks: HashSet<u32>,
+}
+
+/// A local read waiting for the committed term to reach its read index.
+#[derive(Debug, Clone)]
+pub(crate) struct ReadIndexReady {
+    /// The id of the read request.
+    pub request_id: u64,
+    /// The term that has to be applied before the read is served.
+    pub read_index: u64,
+}
+
+/// The local reads waiting on their read index.  Shared by the client and the state machine so
+/// the state machine can wake a read once it can be served.  A read that is dropped has to be
+/// retried by the client since the leader that would have confirmed it is gone.
+pub(crate) struct ReadWaiters {
+    waiting: Mutex<HashMap<u64, oneshot::Sender<u64>>>,
+}
+
+#[allow(dead_code)]
+impl ReadWaiters {
+    pub(crate) fn new() -> Self {
+        ReadWaiters {
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a read to wait on.
+    /// # Arguments
+    /// `request_id` - The id of the read request.
+    /// # Returns
+    /// The future that completes with the id of the last message in the read index term once
+    /// the term has been committed.  It is cancelled if the read has to be retried.
+    fn add(&self, request_id: u64) -> oneshot::Receiver<u64> {
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(request_id, sender);
+        receiver
+    }
+
+    /// Lets a read know its read index has been committed.
+    /// # Arguments
+    /// `request_id` - The id of the read request.
+    /// `message_id` - The id of the last message in the read index term.  The read is served once
+    /// it has been applied.
+    fn ready(&self, request_id: u64, message_id: u64) {
+        if let Some(sender) = self.waiting.lock().unwrap().remove(&request_id) {
+            let _ = sender.send(message_id);
+        }
+    }
+
+    /// Drops a read so the client retries it.
+    /// # Arguments
+    /// `request_id` - The id of the read request.
+    fn cancel(&self, request_id: u64) {
+        self.waiting.lock().unwrap().remove(&request_id);
+    }
+}
+
+/// The role of the node in the cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NodeRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// The replication status of a peer as seen by this node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PeerStatus {
+    /// The id of the peer.
+    pub server_id: u32,
+    /// The highest term the peer has acknowledged.  Only tracked while we are the leader.
+    pub match_term: u64,
+    /// The next term to send to the peer.  Only tracked while we are the leader.
+    pub next_term: u64,
+    /// The last time in milliseconds we received a message from the peer.  0 if we haven't heard
+    /// from it.
+    pub last_contact_ms: u64,
+    /// false if the peer is a learner.
+    pub voting: bool,
+    /// false if the network connection to the peer is down.
+    pub reachable: bool,
+}
+
+/// A snapshot of the status of the node.  The state machine publishes a new snapshot after each
+/// event so it can be read from any thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NodeStatus {
+    /// The id of the server.
+    pub server_id: u32,
+    /// The current role of the node.
+    pub role: NodeRole,
+    /// The current term we have written.
+    pub current_term: u64,
+    /// The current leader if we know who it is.
+    pub leader: Option<u32>,
+    /// The max committed term.
+    pub commit_term: u64,
+    /// The max message id that has been applied.
+    pub applied_message_id: u64,
+    /// The replication status of the other servers.
+    pub peers: Vec<PeerStatus>,
+    /// The number of elections this node has started.
+    pub election_count: u64,
+    /// The number of messages sent to the network.
+    pub messages_sent: u64,
+    /// The number of messages received from other servers.
+    pub messages_received: u64,
+    /// The number of messages dropped since the network queue was full.
+    pub messages_dropped: u64,
+    /// The number of times a connection to a peer has been restored.
+    pub reconnects: u64,
+    /// The client messages held back until they can be accepted.
+    pub held_messages: u64,
+}
+
+impl NodeStatus {
+    /// Creates the status for a node that hasn't processed any events.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    pub fn new(server_id: u32) -> Self {
+        NodeStatus {
+            server_id,
+            role: NodeRole::Follower,
+            current_term: 0,
+            leader: None,
+            commit_term: 0,
+            applied_message_id: 0,
+            peers: Vec::new(),
+            election_count: 0,
+            messages_sent: 0,
+            messages_received: 0,
+            messages_dropped: 0,
+            reconnects: 0,
+            held_messages: 0,
+        }
+    }
+}
+
+/// A server the cluster is started with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClusterConfig {
+    /// The id of the server.
+    pub server_id: u32,
+    /// false if the server is a learner.  A learner gets the log but doesn't vote or count
+    /// towards the quorum.
+    pub voting: bool,
+}
+
+impl ClusterConfig {
+    /// Creates the configuration for a server that votes.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    pub fn voter(server_id: u32) -> Self {
+        ClusterConfig {
+            server_id,
+            voting: true,
+        }
+    }
+
+    /// Creates the configuration for a learner.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    pub fn learner(server_id: u32) -> Self {
+        ClusterConfig {
+            server_id,
+            voting: false,
+        }
+    }
 }
 
 /// Represents a connected server in the clusters.  Servers can be added and removed will the cluster is running.
 struct ConnectedServer {
     /// The id of the server that is connected.  The other connection information is handled by the communication library.
     server_id: u32,
+    /// A server that isn't voting is a learner.  Learners are excluded from the quorum and elections.
+    voting: bool,
+    /// false if the network connection to the server is down.
+    reachable: bool,
 }
 
 
@@ -101,7 +333,7 @@ struct ConnectedServer {
 /// to work with the network library.  Will need to figure out how to get this to work.
 pub(crate) struct RaftStateMachine {
     server_id: u32,
-    current_state: RaftState,
+    current_state: RoleState,
     /// The current term we have written
     current_term_id: u64,
     last_appended_term_id: u64,
@@ -121,6 +353,44 @@ pub(crate) struct RaftStateMachine {
     votes_required: u32,
     file_storage_directory: String,
     file_prefix: String,
+    /// The read index requests waiting on the leadership to be confirmed.
+    pending_read_index: Vec<PendingReadIndex>,
+    /// The id of the last heartbeat round sent.
+    heartbeat_round: u64,
+    /// The first term written since we became the leader.  A read index isn't served until it
+    /// has been committed since we don't know what the previous leader committed before that.
+    leader_start_term: u64,
+    /// The local reads waiting for the committed term to reach the read index.
+    waiting_reads: Vec<ReadIndexReady>,
+    /// The local reads forwarded to the leader we haven't gotten the read index back for.
+    forwarded_reads: HashSet<u64>,
+    /// Used to notify the reads they can be served.
+    read_waiters: Arc<ReadWaiters>,
+    /// The last time we received a message from a server.
+    last_contact: HashMap<u32, u64>,
+    election_count: u64,
+    messages_sent: u64,
+    messages_received: u64,
+    messages_dropped: u64,
+    reconnects: u64,
+    /// The status published for other threads to read.
+    status: Arc<Mutex<NodeStatus>>,
+    /// The server we are transferring the leadership to.  We don't accept client messages while
+    /// transferring.
+    transfer_target: Option<u32>,
+    /// The client messages held back in the skip queue while there isn't a leader or the
+    /// leadership is being transferred.
+    held_messages: u64,
+    /// The clock used for the contact and commit times.
+    clock: Arc<dyn Clock>,
+    /// Where to publish the leaders elected, the terms committed and the followers lagging.
+    events: Option<EventBus<StoreEvent>>,
+    /// The followers reported as lagging.  Reported again once they catch up and fall behind.
+    lagging: HashSet<u32>,
+    /// The term and vote saved so we don't vote twice after a restart.
+    meta: RaftMeta,
+    /// Starts an election when we haven't heard from the leader.
+    election_timer: ElectionTimer,
 }
 
 /// The state machine client use to communicate with the raft state machine.
@@ -132,6 +402,11 @@ pub struct RaftStateMachineClient {
     client_message_writer: ManyToOneBufferWriter,
     state_message_queue: Arc<MpscQueueWrap<RaftEvent>>,
     max_message_id: Arc<AtomicU64>,
+    status: Arc<Mutex<NodeStatus>>,
+    /// The reads waiting for the state machine to confirm their read index.
+    read_waiters: Arc<ReadWaiters>,
+    /// The id of the next read request.
+    next_read_id: AtomicU64,
 }
 
 impl RaftStateMachineClient {
@@ -140,6 +415,11 @@ impl RaftStateMachineClient {
         &self.server_id
     }
 
+    /// Gets a snapshot of the current status of the node.
+    pub fn status(&self) -> NodeStatus {
+        self.status.lock().unwrap().clone()
+    }
+
     /// Used to get the max message id.
     pub(crate) fn max_message_id(&self) -> Arc<AtomicU64> {
         self.max_message_id.clone()
@@ -152,6 +432,60 @@ impl RaftStateMachineClient {
         self.state_message_queue.offer(event)
     }
 
+    /// Runs a read against the local state once it is linearizable.  The leader confirms it is
+    /// still the leader with a quorum and returns its committed term as the read index.  The read
+    /// is run once we have applied the messages up to the end of that term so it sees every write
+    /// committed before the call.  If the leader changes before the read index comes back the request is sent again to
+    /// the new leader.
+    /// # Arguments
+    /// `read` - The read to run.
+    /// # Returns
+    /// The result of the read or `NoLeader` if there isn't a leader to confirm the read index.
+    pub async fn linearizable_read<F, R>(&self, read: F) -> crate::Result<R>
+    where
+        F: FnOnce() -> R,
+    {
+        loop {
+            let request_id = self.next_read_id.fetch_add(1, Ordering::Relaxed);
+            let ready = self.read_waiters.add(request_id);
+            if !self.send_event(RaftEvent::ReadIndexRequest {
+                server_id: self.server_id,
+                request_id,
+            }) {
+                // The queue is full so give the state machine a chance to catch up.
+                self.read_waiters.cancel(request_id);
+                let _ = tokio::task::yield_now().await;
+                continue;
+            }
+            if let Ok(message_id) = ready.await {
+                // The term is committed but the messages in it might not have been applied yet.
+                while self.max_message_id.load(Ordering::Acquire) < message_id {
+                    let _ = tokio::task::yield_now().await;
+                }
+                return Ok(read());
+            }
+            // Only try again if there is a new leader to send the request to.
+            if self.status().leader.is_none() {
+                return Err(PersistError::NoLeader {
+                    context: Context::default(),
+                });
+            }
+        }
+    }
+
+    /// Transfers the leadership to another server.  Only does something if this server is the
+    /// leader.  The client messages are held until the target is elected or the transfer times
+    /// out.
+    /// # Arguments
+    /// `target_server_id` - The server to transfer the leadership to.
+    /// # Returns
+    /// false if the request couldn't be queued.
+    pub fn transfer_leadership(&self, target_server_id: u32) -> bool {
+        self.send_event(RaftEvent::TransferLeadership {
+            server_id: target_server_id,
+        })
+    }
+
     /// Stops the state machine.
     pub(crate) fn stop(&mut self) {
         if let Some(join) = self.state_machine_thread.take() {
@@ -167,13 +501,13 @@ impl RaftStateMachineClient {
 /// `file_storage_directory` - The location of the files we are storing.
 /// `file_prefix` - The prefix for the files we are storing.
 /// `commit_file_size` - The file size for the commit file.
-/// `start_servers` - The ids of the starting servers.
+/// `cluster` - The servers the cluster starts with.  Learners have `voting` set to false.
 fn create_state_machine(
     server_id: u32,
     file_storage_directory: String,
     file_prefix: String,
     commit_file_size: usize,
-    starting_servers: HashSet<u32>,
+    cluster: Vec<ClusterConfig>,
     file_collection: Arc<FileCollection>,
 ) {
     let max_message = AtomicU64::new(0);
@@ -188,7 +522,7 @@ fn create_state_machine(
                 .create_new(true)
                 .open(&file_name).unwrap();
             let map = unsafe {
-                MemoryMappedInt::new(&file_name, next_pos(commit_file_size, COMMIT_SIZE as usize))
+                create_commit_file(&file_name, next_pos(commit_file_size, COMMIT_SIZE as usize))
                     .unwrap()
             };
             let term = TermFile::new(map, 1, file_id);
@@ -207,7 +541,7 @@ fn create_state_machine(
                 .create(false)
                 .open(&path)
                 .unwrap();
-            let map = unsafe { MemoryMappedInt::open(file).unwrap() };
+            let map = unsafe { open_commit_file(file).unwrap() };
             max_message.store(max_message_id, atomic::Ordering::Relaxed);
             // Get the current term we need to find.
             let term = TermFile::new(map, start_term_id, file_id);
@@ -260,26 +594,331 @@ fn run_state_machine(state_machine: RaftStateMachine) -> JoinHandle<u32> {
 
 #[allow(dead_code)]
 impl RaftStateMachine {
-    fn send_leader_request(&mut self) {
-        self.state_message_queue_writer
-            .offer(NetworkSendType::Broadcast {
-                msg: NetworkSend::RaftEvent(RaftEvent::VoteForMe {
-                    server_id: self.server_id,
-                    max_term_id: self.current_commited_term,
-                }),
+    /// Creates the state machine as a candidate.  The term and vote saved before a restart are
+    /// loaded from the meta file.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    /// `message_queue` - The queue of the events to process.
+    /// `state_message_queue_writer` - Where to send the messages to the network.
+    /// `commit_term_file` - The commit file we are currently on.
+    /// `commit_file_size` - The size of a commit file.
+    /// `file_storage_directory` - The location of the files we are storing.
+    /// `file_prefix` - The prefix for the files we are storing.
+    /// `cluster` - The servers the cluster starts with.  Learners have `voting` set to false.
+    /// # Returns
+    /// The state machine or an error if the meta file can't be opened.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        server_id: u32,
+        message_queue: SkipQueueReader<RaftEvent>,
+        state_message_queue_writer: SpscQueueSendWrap<NetworkSendType>,
+        commit_term_file: TermFile,
+        commit_file_size: usize,
+        file_storage_directory: &str,
+        file_prefix: &str,
+        cluster: &[ClusterConfig],
+    ) -> crate::Result<Self> {
+        let mut meta = RaftMeta::load(file_storage_directory, file_prefix)?;
+        let clock = system_clock();
+        if meta.server_id() != server_id {
+            meta.set_server_id(server_id);
+            meta.save()?;
+        }
+        let mut state_machine = RaftStateMachine {
+            server_id,
+            current_state: RoleState::Candidate {
+                round_number: 0,
+                votes: HashSet::new(),
+            },
+            current_term_id: meta.current_term(),
+            last_appended_term_id: 0,
+            voted_for: meta.voted_for(),
+            connected_server: HashMap::with_capacity(3),
+            leader_votes: HashSet::new(),
+            message_queue,
+            commit_term_file,
+            commit_file_size,
+            server_count: 0,
+            leader: 0,
+            state_message_queue_writer,
+            max_message_id: Arc::new(AtomicU64::new(0)),
+            current_commited_term: 0,
+            votes_required: 1,
+            file_storage_directory: file_storage_directory.to_owned(),
+            file_prefix: file_prefix.to_owned(),
+            pending_read_index: Vec::new(),
+            heartbeat_round: 0,
+            leader_start_term: 0,
+            waiting_reads: Vec::new(),
+            forwarded_reads: HashSet::new(),
+            read_waiters: Arc::new(ReadWaiters::new()),
+            last_contact: HashMap::with_capacity(3),
+            election_count: 0,
+            messages_sent: 0,
+            messages_received: 0,
+            messages_dropped: 0,
+            reconnects: 0,
+            status: Arc::new(Mutex::new(NodeStatus::new(server_id))),
+            transfer_target: None,
+            held_messages: 0,
+            election_timer: ElectionTimer::new(
+                clock.clone(),
+                DEFAULT_ELECTION_BASE,
+                DEFAULT_ELECTION_JITTER,
+            ),
+            clock,
+            events: None,
+            lagging: HashSet::new(),
+            meta,
+        };
+        for server in cluster {
+            state_machine.handle_internal_message(InternalMessage::AddServer {
+                server_id: server.server_id,
+                voting: server.voting,
+            });
+        }
+        Ok(state_machine)
+    }
+
+    /// Saves the current term and who we voted for.  Needs to be done before the vote is sent.
+    fn save_meta(&mut self) -> crate::Result<()> {
+        self.meta.set_term(self.current_term_id, self.voted_for);
+        self.meta.save()?;
+        Ok(())
+    }
+
+    /// Sends a message to the network and keeps track of the number of messages sent.
+    /// # Arguments
+    /// `msg` - The message to send.
+    fn send(&mut self, msg: NetworkSendType) {
+        if self.state_message_queue_writer.offer(msg) {
+            self.messages_sent += 1;
+        } else {
+            self.messages_dropped += 1;
+        }
+    }
+
+    /// Records a message received from another server.
+    /// # Arguments
+    /// `event` - The event that was received.
+    fn record_received(&mut self, event: &RaftEvent) {
+        let server_id = match event {
+            RaftEvent::VoteForCandiate { server_id }
+            | RaftEvent::VoteForMe { server_id, .. }
+            | RaftEvent::ElectedLeader { server_id }
+            | RaftEvent::Commited { server_id, .. }
+            | RaftEvent::FollowerIndex { server_id, .. }
+            | RaftEvent::Pong { server_id, .. }
+            | RaftEvent::Ping { server_id, .. }
+            | RaftEvent::ReadIndexRequest { server_id, .. }
+            | RaftEvent::ReadIndexResponse { server_id, .. }
+            | RaftEvent::TimeoutNow { server_id } => *server_id,
+            _ => return,
+        };
+        if server_id != self.server_id {
+            self.messages_received += 1;
+            self.last_contact.insert(server_id, self.clock.now_ms());
+        }
+    }
+
+    /// Publishes an event if there is a bus.
+    /// # Arguments
+    /// `event` - The event to publish.
+    fn emit(&self, event: StoreEvent) {
+        if let Some(events) = &self.events {
+            events.publish(event);
+        }
+    }
+
+    /// The leader we know about.
+    fn current_leader(&self) -> Option<u32> {
+        match &self.current_state {
+            RoleState::Candidate { .. } => None,
+            RoleState::Follower { leader } => Some(*leader),
+            RoleState::Leader { .. } => Some(self.server_id),
+        }
+    }
+
+    /// Publishes the current status so it can be read by other threads.
+    fn publish_status(&mut self) {
+        let (role, leader) = match &self.current_state {
+            RoleState::Candidate { .. } => (NodeRole::Candidate, None),
+            RoleState::Follower { leader } => (NodeRole::Follower, Some(*leader)),
+            RoleState::Leader { .. } => (NodeRole::Leader, Some(self.server_id)),
+        };
+        let mut peers = Vec::with_capacity(self.connected_server.len());
+        for (server_id, server) in self.connected_server.iter() {
+            if *server_id == self.server_id {
+                continue;
+            }
+            let (match_term, next_term) = match &self.current_state {
+                RoleState::Leader {
+                    next_index,
+                    match_index,
+                } => (
+                    *match_index.get(server_id).unwrap_or(&0),
+                    *next_index.get(server_id).unwrap_or(&0),
+                ),
+                _ => (0, 0),
+            };
+            peers.push(PeerStatus {
+                server_id: *server_id,
+                match_term,
+                next_term,
+                last_contact_ms: *self.last_contact.get(server_id).unwrap_or(&0),
+                voting: server.voting,
+                reachable: server.reachable,
             });
+        }
+        peers.sort_by_key(|p| p.server_id);
+        let status = NodeStatus {
+            server_id: self.server_id,
+            role,
+            current_term: self.current_term_id,
+            leader,
+            commit_term: self.current_commited_term,
+            applied_message_id: self.max_message_id.load(atomic::Ordering::Relaxed),
+            peers,
+            election_count: self.election_count,
+            messages_sent: self.messages_sent,
+            messages_received: self.messages_received,
+            messages_dropped: self.messages_dropped,
+            reconnects: self.reconnects,
+            held_messages: self.held_messages,
+        };
+        *self.status.lock().unwrap() = status;
+    }
+
+    fn send_leader_request(&mut self) {
+        self.send(NetworkSendType::Broadcast {
+            msg: NetworkSend::RaftEvent(RaftEvent::VoteForMe {
+                server_id: self.server_id,
+                max_term_id: self.current_commited_term,
+            }),
+        });
     }
 
     fn handle_internal_message(&mut self, msg: InternalMessage) {
         match msg {
-            InternalMessage::AddServer { server_id } => {
-                let connected_server = ConnectedServer { server_id };
+            InternalMessage::AddServer { server_id, voting } => {
+                let connected_server = ConnectedServer {
+                    server_id,
+                    voting,
+                    reachable: true,
+                };
                 self.connected_server.insert(server_id, connected_server);
             }
             InternalMessage::RemoveServer { server_id } => {
                 self.connected_server.remove(&server_id);
             }
         }
+        self.update_votes_required();
+    }
+
+    /// Marks a server as reachable or unreachable when the network connection changes.
+    /// # Arguments
+    /// `server_id` - The server the connection is for.
+    /// `connected` - true if the connection is up.
+    fn handle_peer_connection_changed(&mut self, server_id: u32, connected: bool) {
+        if let Some(server) = self.connected_server.get_mut(&server_id) {
+            if connected && !server.reachable {
+                self.reconnects += 1;
+            }
+            server.reachable = connected;
+        }
+    }
+
+    /// Checks to see if a server is a voting member of the cluster.
+    /// # Arguments
+    /// `server_id` - The id of the server to check.
+    fn is_voting(&self, server_id: u32) -> bool {
+        self.connected_server
+            .get(&server_id)
+            .map(|s| s.voting)
+            .unwrap_or(false)
+    }
+
+    /// Updates the number of votes required for a quorum.  Learners aren't included.
+    fn update_votes_required(&mut self) {
+        let voting = self.connected_server.values().filter(|s| s.voting).count() as u32;
+        self.server_count = voting;
+        self.votes_required = voting / 2 + 1;
+    }
+
+    /// Starts an election if we are a voting member.
+    fn start_election(&mut self) {
+        if self.is_voting(self.server_id) {
+            self.cancel_forwarded_reads();
+            let mut votes = HashSet::with_capacity(self.server_count as usize);
+            votes.insert(self.server_id);
+            self.current_state = RoleState::Candidate {
+                votes,
+                round_number: 1,
+            };
+            self.election_count += 1;
+            self.election_timer.reset();
+            self.send_leader_request();
+        }
+    }
+
+    /// Starts an election or a new round of it once the election timer has run out.
+    fn check_election_timer(&mut self) {
+        if self.election_timer.is_expired() {
+            match self.current_state {
+                RoleState::Follower { .. } => self.start_election(),
+                RoleState::Candidate { .. } => self.handle_vote_timeout(),
+                RoleState::Leader { .. } => {}
+            }
+            // A learner doesn't start an election so it waits for another timeout.
+            self.election_timer.reset();
+        }
+    }
+
+    /// Starts transferring the leadership to another server.  Client messages aren't accepted until
+    /// the transfer is done.
+    /// # Arguments
+    /// `server_id` - The server to transfer the leadership to.
+    fn handle_transfer_leadership(&mut self, server_id: u32) {
+        if server_id != self.server_id && self.is_voting(server_id) {
+            self.transfer_target = Some(server_id);
+            self.check_transfer_leadership();
+        }
+    }
+
+    /// Sends the timeout now message to the transfer target once it has all of the terms.
+    fn check_transfer_leadership(&mut self) {
+        if let Some(target) = self.transfer_target {
+            let up_to_date = if let RoleState::Leader { match_index, .. } = &self.current_state {
+                match match_index.get(&target) {
+                    Some(max_term_id) => *max_term_id >= self.current_term_id,
+                    None => self.current_term_id == 0,
+                }
+            } else {
+                false
+            };
+            if up_to_date {
+                self.send(NetworkSendType::Single {
+                    msg: NetworkSend::RaftEvent(RaftEvent::TimeoutNow {
+                        server_id: self.server_id,
+                    }),
+                    server_id: target,
+                });
+            }
+        }
+    }
+
+    /// Holds a client message in the skip queue until it can be accepted.
+    /// # Arguments
+    /// `event` - The client message event.
+    fn hold_client_message(&mut self, event: RaftEvent) {
+        self.held_messages += 1;
+        self.message_queue.skip(event);
+    }
+
+    /// Replays the held client messages before the rest of the queue.
+    fn release_held_messages(&mut self) {
+        self.held_messages = 0;
+        self.message_queue.switch_to_skip();
     }
 
     /// Handles the request for vote for me.
@@ -288,26 +927,33 @@ impl RaftStateMachine {
     /// `max_term_id` - The max term of the id.
     fn handle_vote_for_me(&mut self, server_id: u32, max_term_id: u64) {
         if self.voted_for.is_none() && self.current_term_id <= max_term_id {
-            self.state_message_queue_writer
-                .offer(NetworkSendType::Single {
-                    msg: NetworkSend::RaftEvent(RaftEvent::VoteForCandiate {
-                        server_id: self.server_id,
-                    }),
-                    server_id,
-                });
-            self.voted_for = Some(self.server_id);
+            self.voted_for = Some(server_id);
+            if let Err(e) = self.save_meta() {
+                // Can't send the vote since we wouldn't remember it after a restart.
+                log::error!("Unable to save the vote for {}: {}", server_id, e);
+                self.voted_for = None;
+                return;
+            }
+            self.send(NetworkSendType::Single {
+                msg: NetworkSend::RaftEvent(RaftEvent::VoteForCandiate {
+                    server_id: self.server_id,
+                }),
+                server_id,
+            });
+            self.election_timer.reset();
         }
     }
 
     /// Sends a ping to other servers.
     fn send_ping(&mut self) {
-        self.state_message_queue_writer
-            .offer(NetworkSendType::Broadcast {
-                msg: NetworkSend::RaftEvent(RaftEvent::Ping {
-                    server_id: self.server_id,
-                    max_commited_term: self.current_commited_term,
-                }),
-            });
+        self.heartbeat_round += 1;
+        self.send(NetworkSendType::Broadcast {
+            msg: NetworkSend::RaftEvent(RaftEvent::Ping {
+                server_id: self.server_id,
+                max_commited_term: self.current_commited_term,
+                round: self.heartbeat_round,
+            }),
+        });
     }
 
     /// Handles a commit from a leader.
@@ -327,7 +973,7 @@ impl RaftStateMachine {
                                     self.commit_term_file.buffer.set_committed(pos);
                                     self.commit_term_file
                                         .buffer
-                                        .set_committed_timestamp(pos, current_time_ms());
+                                        .set_committed_timestamp(pos, self.clock.now_ms());
                                     current_term += 1;
                                     break;
                                 }
@@ -359,46 +1005,176 @@ impl RaftStateMachine {
             match self.commit_term_file.calculate_pos(&term_id) {
                 TermPosResult::Pos(pos) => {
                     let id = self.commit_term_file.buffer.max_message_id(pos);
-                    self.max_message_id.store(id, atomic::Ordering::Relaxed);
+                    self.max_message_id.store(id, atomic::Ordering::Release);
                 }
                 _ => {
                     panic!("The file should exist since we just used it!");
                 }
             }
+            self.release_waiting_reads();
+            self.release_read_index();
+            self.emit(StoreEvent::TermCommitted { term_id });
+        }
+    }
+
+    /// Handles a read index request on the leader.  The current committed term is recorded and a
+    /// heartbeat is sent to confirm we are still the leader.
+    /// # Arguments
+    /// `server_id` - The server requesting the read index.
+    /// `request_id` - The id of the read request.
+    fn handle_read_index_request(&mut self, server_id: u32, request_id: u64) {
+        if self.votes_required > 1 {
+            self.send_ping();
+        }
+        let mut acks = HashSet::with_capacity(self.server_count as usize);
+        acks.insert(self.server_id);
+        self.pending_read_index.push(PendingReadIndex {
+            server_id,
+            request_id,
+            commit_term: self.current_commited_term,
+            round: self.heartbeat_round,
+            acks,
+        });
+        self.release_read_index();
+    }
+
+    /// Records a heartbeat response for the pending read index requests.  Only the requests the
+    /// heartbeat round was sent for count the response.
+    /// # Arguments
+    /// `server_id` - The server that responded to the heartbeat.
+    /// `round` - The heartbeat round the server responded to.
+    fn confirm_read_index(&mut self, server_id: u32, round: u64) {
+        if !self.is_voting(server_id) {
+            return;
+        }
+        for pending in self.pending_read_index.iter_mut() {
+            if pending.round <= round {
+                pending.acks.insert(server_id);
+            }
+        }
+        self.release_read_index();
+    }
+
+    /// Sends back the read index for the requests a quorum has confirmed.  Nothing is released
+    /// until we have committed a term of our own since becoming the leader.
+    fn release_read_index(&mut self) {
+        if self.current_commited_term < self.leader_start_term {
+            return;
+        }
+        let votes_required = self.votes_required as usize;
+        let mut confirmed = Vec::new();
+        let mut i = 0;
+        while i < self.pending_read_index.len() {
+            if self.pending_read_index[i].acks.len() >= votes_required {
+                confirmed.push(self.pending_read_index.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        for pending in confirmed {
+            let read_index = pending.commit_term.max(self.leader_start_term);
+            if pending.server_id == self.server_id {
+                self.queue_local_read(pending.request_id, read_index);
+            } else {
+                self.send(NetworkSendType::Single {
+                    msg: NetworkSend::RaftEvent(RaftEvent::ReadIndexResponse {
+                        server_id: self.server_id,
+                        request_id: pending.request_id,
+                        commit_term: read_index,
+                    }),
+                    server_id: pending.server_id,
+                });
+            }
+        }
+    }
+
+    /// Queues a local read until the committed term has reached the read index.
+    /// # Arguments
+    /// `request_id` - The id of the read request.
+    /// `read_index` - The term that has to be committed before the read can be served.
+    fn queue_local_read(&mut self, request_id: u64, read_index: u64) {
+        if read_index <= self.current_commited_term {
+            let message_id = self.term_max_message_id(read_index);
+            self.read_waiters.ready(request_id, message_id);
+        } else {
+            self.waiting_reads.push(ReadIndexReady {
+                request_id,
+                read_index,
+            });
+        }
+    }
+
+    /// Gets the id of the last message in a committed term.  A term in a commit file we have
+    /// moved past uses the last message committed since it can only be later.
+    /// # Arguments
+    /// `term_id` - The term to get the last message for.
+    fn term_max_message_id(&self, term_id: u64) -> u64 {
+        match self.commit_term_file.calculate_pos(&term_id) {
+            TermPosResult::Pos(pos) => self.commit_term_file.buffer.max_message_id(pos),
+            _ => self.max_message_id.load(atomic::Ordering::Acquire),
+        }
+    }
+
+    /// Drops the reads forwarded to the leader since it can't confirm them anymore.  The clients
+    /// send them again once there is a new leader.
+    fn cancel_forwarded_reads(&mut self) {
+        let waiters = &self.read_waiters;
+        for request_id in self.forwarded_reads.drain() {
+            waiters.cancel(request_id);
+        }
+    }
+
+    /// Releases the reads that have reached the read index.
+    fn release_waiting_reads(&mut self) {
+        let commited = self.current_commited_term;
+        let (ready, waiting): (Vec<_>, Vec<_>) = self
+            .waiting_reads
+            .drain(..)
+            .partition(|ready| ready.read_index <= commited);
+        self.waiting_reads = waiting;
+        for ready in ready {
+            let message_id = self.term_max_message_id(ready.read_index);
+            self.read_waiters.ready(ready.request_id, message_id);
         }
     }
 
     fn handle_candidate(&mut self, server_id: u32) {
-        if let RaftState::Candidate { votes, .. } = &mut self.current_state {
+        if !self.is_voting(server_id) {
+            return;
+        }
+        if let RoleState::Candidate { votes, .. } = &mut self.current_state {
             votes.insert(server_id);
             if votes.len() >= self.votes_required as usize {
-                self.current_state = RaftState::Leader {
+                self.leader_start_term = self.current_term_id + 1;
+                self.current_state = RoleState::Leader {
                     next_index: HashMap::with_capacity(10),
                     match_index: HashMap::with_capacity(10),
                 };
                 // We won let the others know.
-                self.state_message_queue_writer
-                    .offer(NetworkSendType::Broadcast {
-                        msg: NetworkSend::RaftEvent(RaftEvent::ElectedLeader {
-                            server_id: self.server_id,
-                        }),
-                    });
+                self.send(NetworkSendType::Broadcast {
+                    msg: NetworkSend::RaftEvent(RaftEvent::ElectedLeader {
+                        server_id: self.server_id,
+                    }),
+                });
+                self.release_held_messages();
             }
             // Check to see if we have enough votes.
         }
     }
 
     fn handle_vote_timeout(&mut self) {
-        if let RaftState::Candidate { votes, .. } = &mut self.current_state {
+        if let RoleState::Candidate { votes, .. } = &mut self.current_state {
             self.voted_for = None;
             votes.clear();
             votes.insert(self.server_id);
+            self.election_count += 1;
+            self.election_timer.reset();
             self.send_leader_request();
         }
     }
 
     fn handle_follower_index(&mut self, server_id: u32, term_id: u64) {
-        if let RaftState::Leader {
+        if let RoleState::Leader {
             next_index,
             ..
         } = &mut self.current_state
@@ -407,8 +1183,43 @@ impl RaftStateMachine {
         }
     }
 
+    /// Lets the leader know we are still following it so it can confirm its leadership.
+    /// # Arguments
+    /// `server_id` - The server that sent the ping.
+    /// `round` - The heartbeat round of the ping.
+    fn handle_follower_ping(&mut self, server_id: u32, round: u64) {
+        if self.leader == server_id {
+            self.election_timer.reset();
+            self.send(NetworkSendType::Single {
+                msg: NetworkSend::RaftEvent(RaftEvent::Pong {
+                    server_id: self.server_id,
+                    max_term_id: self.current_term_id,
+                    round,
+                }),
+                server_id,
+            });
+        }
+    }
+
+    /// Records the last term a follower has and commits the terms a quorum has.  Nothing is
+    /// committed until a quorum has a term written since we became the leader so a term from an
+    /// earlier leader is only committed along with one of ours.
+    /// # Arguments
+    /// `server_id` - The follower that sent the pong.
+    /// `max_term_id` - The last term the follower has.
     fn handle_leader_pong(&mut self, server_id: u32, max_term_id: u64) {
-        let (start, end) = if let RaftState::Leader {
+        let behind = self.current_term_id.saturating_sub(max_term_id);
+        if behind > FOLLOWER_LAG_TERMS {
+            if self.lagging.insert(server_id) {
+                self.emit(StoreEvent::FollowerLagging {
+                    peer: server_id,
+                    behind,
+                });
+            }
+        } else {
+            self.lagging.remove(&server_id);
+        }
+        let (start, end) = if let RoleState::Leader {
             match_index,
             ..
         } = &mut self.current_state
@@ -426,17 +1237,25 @@ impl RaftStateMachine {
                 let mut next_commit_term = self.current_commited_term + 1;
                 loop {
                     let mut votes = 1; // Need to have us as 1 vote :);
-                    for c in match_index.values() {
-                        if *c >= next_commit_term {
+                    for (id, c) in match_index.iter() {
+                        // Learners don't count towards the quorum.
+                        let voting = self
+                            .connected_server
+                            .get(id)
+                            .map(|s| s.voting)
+                            .unwrap_or(false);
+                        if voting && *c >= next_commit_term {
                             votes += 1;
                         }
                     }
                     if self.votes_required <= votes {
                         // Send a message to the listeners :)
                         next_commit_term += 1;
+                    } else if next_commit_term > self.leader_start_term {
+                        break (start_term, next_commit_term - 1);
                     } else {
                         // Nothing to commit :)
-                        break (start_term, next_commit_term - 1);
+                        break (start_term, start_term - 1);
                     }
                 }
             } else {
@@ -450,13 +1269,12 @@ impl RaftStateMachine {
             self.handle_commit_term(i);
         }
         for i in start..=end {
-            self.state_message_queue_writer
-                .offer(NetworkSendType::Broadcast {
-                    msg: NetworkSend::RaftEvent(RaftEvent::Commited {
-                        server_id: self.server_id,
-                        term_id: i,
-                    }),
-                });
+            self.send(NetworkSendType::Broadcast {
+                msg: NetworkSend::RaftEvent(RaftEvent::Commited {
+                    server_id: self.server_id,
+                    term_id: i,
+                }),
+            });
         }
     }
 
@@ -466,8 +1284,20 @@ impl RaftStateMachine {
     fn process_message_queue(&self) {}
 
     fn process_event(&mut self, event: RaftEvent) {
+        self.record_received(&event);
+        let leader = self.current_leader();
+        self.handle_event(event);
+        if let Some(server_id) = self.current_leader() {
+            if leader != Some(server_id) {
+                self.emit(StoreEvent::LeaderElected { server_id });
+            }
+        }
+        self.publish_status();
+    }
+
+    fn handle_event(&mut self, event: RaftEvent) {
         match &self.current_state {
-            RaftState::Candidate { .. } => {
+            RoleState::Candidate { .. } => {
                 match event {
                     RaftEvent::VoteForCandiate { server_id } => {
                         self.handle_candidate(server_id);
@@ -482,13 +1312,15 @@ impl RaftStateMachine {
                         }
                     }
                     RaftEvent::ClientMessageReceived => {
-                        self.message_queue.skip(event);
+                        self.hold_client_message(event);
                     }
                     RaftEvent::ElectedLeader { server_id } => {
                         self.leader = server_id;
-                        self.current_state = RaftState::Follower {
+                        self.current_state = RoleState::Follower {
                             leader: self.leader,
                         };
+                        self.election_timer.reset();
+                        self.release_held_messages();
                     }
                     RaftEvent::VoteTimeout => {
                         self.handle_vote_timeout();
@@ -497,12 +1329,28 @@ impl RaftStateMachine {
                         // This should never happen since we can't commit while a candidate!
                         panic!("Processing an internal message when candidate!")
                     }
+                    RaftEvent::NoMessagesTimeout => {
+                        self.check_election_timer();
+                    }
+                    RaftEvent::ReadIndexRequest {
+                        server_id,
+                        request_id,
+                    } if server_id == self.server_id => {
+                        // There isn't a leader to confirm the read so the client has to retry.
+                        self.read_waiters.cancel(request_id);
+                    }
+                    RaftEvent::PeerConnectionChanged {
+                        server_id,
+                        connected,
+                    } => {
+                        self.handle_peer_connection_changed(server_id, connected);
+                    }
                     _ => {
                         // Ignore the rest of the events.
                     }
                 }
             }
-            RaftState::Follower { leader } => {
+            RoleState::Follower { leader } => {
                 match event {
                     RaftEvent::ClientMessageReceived => {
                         // Forward to the server.
@@ -512,19 +1360,22 @@ impl RaftStateMachine {
                     }
                     RaftEvent::Commited { term_id, server_id } => {
                         if *leader == server_id {
+                            self.election_timer.reset();
                             self.handle_commit_term(term_id);
                         }
                     }
                     RaftEvent::ElectedLeader { server_id } => {
                         self.voted_for = None;
                         self.leader = server_id;
+                        self.election_timer.reset();
+                        if *leader != server_id {
+                            self.cancel_forwarded_reads();
+                            self.current_state = RoleState::Follower { leader: server_id };
+                        }
                     }
                     RaftEvent::LeaderTimeout => {
                         // try to become leader.
-                        let mut votes = HashSet::with_capacity(self.server_count as usize);
-                        votes.insert(self.server_id);
-                        self.current_state = RaftState::Candidate { votes, round_number: 1 };
-                        self.send_leader_request();
+                        self.start_election();
                     }
                     RaftEvent::Stop => {}
                     RaftEvent::VoteForCandiate { server_id: _ } => {
@@ -539,18 +1390,16 @@ impl RaftStateMachine {
                             self.handle_vote_for_me(server_id, max_term_id);
                         }
                     }
-                    RaftEvent::Pong {
-                        server_id: _,
-                        max_term_id: _,
-                    } => {
+                    RaftEvent::Pong { .. } => {
                         // We aren't processing these since we aren't the leader.
                     }
                     RaftEvent::Ping {
-                        max_commited_term: _,
-                        server_id: _,
-                    } => {}
+                        server_id, round, ..
+                    } => {
+                        self.handle_follower_ping(server_id, round);
+                    }
                     RaftEvent::NoMessagesTimeout => {
-                        // Do nothing since this should be a leader timeout.
+                        self.check_election_timer();
                     }
                     RaftEvent::VoteTimeout => {
                         // Clear out who we voted for.
@@ -559,13 +1408,59 @@ impl RaftStateMachine {
                     RaftEvent::ProcessInternalMessage { msg } => {
                         self.handle_internal_message(msg);
                     }
-                }
-            }
-            RaftState::Leader {
-                ..
-            } => {
+                    RaftEvent::ReadIndexRequest {
+                        server_id,
+                        request_id,
+                    } => {
+                        if server_id == self.server_id {
+                            // Forward to the leader so it can confirm the read index.
+                            self.forwarded_reads.insert(request_id);
+                            let leader = *leader;
+                            self.send(NetworkSendType::Single {
+                                msg: NetworkSend::RaftEvent(RaftEvent::ReadIndexRequest {
+                                    server_id,
+                                    request_id,
+                                }),
+                                server_id: leader,
+                            });
+                        }
+                    }
+                    RaftEvent::ReadIndexResponse {
+                        server_id,
+                        request_id,
+                        commit_term,
+                    } => {
+                        if *leader == server_id && self.forwarded_reads.remove(&request_id) {
+                            self.queue_local_read(request_id, commit_term);
+                        }
+                    }
+                    RaftEvent::TimeoutNow { server_id } => {
+                        if *leader == server_id {
+                            // The leader is handing over the leadership.
+                            self.voted_for = None;
+                            self.start_election();
+                        }
+                    }
+                    RaftEvent::TransferLeadership { .. } => {
+                        // Only the leader can transfer the leadership.
+                    }
+                    RaftEvent::PeerConnectionChanged {
+                        server_id,
+                        connected,
+                    } => {
+                        self.handle_peer_connection_changed(server_id, connected);
+                    }
+                }
+            }
+            RoleState::Leader {
+                ..
+            } => {
                 match event {
                     RaftEvent::ClientMessageReceived => {
+                        if self.transfer_target.is_some() {
+                            // Hold onto the message until the transfer is done.
+                            self.hold_client_message(event);
+                        }
                         // Got a new possible term.
                     }
                     RaftEvent::FollowerIndex { server_id, term_id } => {
@@ -576,7 +1471,19 @@ impl RaftStateMachine {
                     }
                     RaftEvent::ElectedLeader { server_id } => {
                         if self.server_id != server_id {
-                            self.current_state = RaftState::Follower { leader: server_id };
+                            // We can't confirm the reads anymore so the clients have to retry.
+                            let waiters = &self.read_waiters;
+                            for pending in self.pending_read_index.drain(..) {
+                                if pending.server_id == self.server_id {
+                                    waiters.cancel(pending.request_id);
+                                }
+                            }
+                            self.transfer_target = None;
+                            self.leader = server_id;
+                            self.current_state = RoleState::Follower { leader: server_id };
+                            self.election_timer.reset();
+                            // Forward the messages we held onto the new leader.
+                            self.release_held_messages();
                         }
                     }
                     RaftEvent::LeaderTimeout => {
@@ -595,13 +1502,13 @@ impl RaftStateMachine {
                     RaftEvent::Pong {
                         server_id,
                         max_term_id,
+                        round,
                     } => {
                         self.handle_leader_pong(server_id, max_term_id);
+                        self.confirm_read_index(server_id, round);
+                        self.check_transfer_leadership();
                     }
-                    RaftEvent::Ping {
-                        max_commited_term: _,
-                        server_id: _,
-                    } => {
+                    RaftEvent::Ping { .. } => {
                         // Ignore we are the leader and shouldn't be getting a pong.
                     }
                     RaftEvent::NoMessagesTimeout => {
@@ -609,12 +1516,36 @@ impl RaftStateMachine {
                         self.send_ping();
                     }
                     RaftEvent::VoteTimeout => {
-                        // Ignore we are the leader so this shouldn't happen.
+                        // The transfer didn't finish in time so start accepting messages again.
+                        if self.transfer_target.take().is_some() {
+                            self.release_held_messages();
+                        }
                     }
                     RaftEvent::ProcessInternalMessage { msg } => {
                         // Process the internal message.
                         self.handle_internal_message(msg);
                     }
+                    RaftEvent::ReadIndexRequest {
+                        server_id,
+                        request_id,
+                    } => {
+                        self.handle_read_index_request(server_id, request_id);
+                    }
+                    RaftEvent::ReadIndexResponse { .. } => {
+                        // Ignore we are the leader and are the one sending these.
+                    }
+                    RaftEvent::TransferLeadership { server_id } => {
+                        self.handle_transfer_leadership(server_id);
+                    }
+                    RaftEvent::TimeoutNow { .. } => {
+                        // Ignore we are already the leader.
+                    }
+                    RaftEvent::PeerConnectionChanged {
+                        server_id,
+                        connected,
+                    } => {
+                        self.handle_peer_connection_changed(server_id, connected);
+                    }
                 }
             }
         }
@@ -624,14 +1555,20 @@ impl RaftStateMachine {
 #[cfg(test)]
 mod test {
 
+    use crate::file::header::FILE_HEADER_SIZE;
     use crate::raft::network::*;
     use crate::raft::state_machine::*;
+    use a19_concurrent::buffer::ring_buffer::create_many_to_one;
     use a19_concurrent::buffer::DirectByteBuffer;
     use a19_concurrent::queue::skip_queue::create_skip_queue;
+    use a19_concurrent::queue::spsc_queue::SpscQueueReceiveWrap;
+    use a19_core::clock::ManualClock;
+    use futures::executor::block_on;
     use serial_test::serial;
-    use std::collections::{HashMap, HashSet};
+    use std::collections::HashMap;
     use std::sync::atomic::AtomicU64;
-    use std::sync::Arc;
+    use std::sync::{mpsc, Arc};
+    use std::time::{Duration, Instant};
 
     const FILE_STORAGE_DIRECTORY: &str = "/home/mrh0057/Raft_State_Machine_Test";
     const FILE_PREFIX: &str = "state_machine_test";
@@ -641,48 +1578,56 @@ mod test {
 
     /// Used to crate the state machine.
     fn create_state_machine() -> (RaftStateMachine, NetworkInfo) {
+        create_server(1, FILE_PREFIX)
+    }
+
+    /// Creates the state machine for a server in a cluster of 3.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    /// `file_prefix` - The prefix of the commit files so each server has its own.
+    fn create_server(server_id: u32, file_prefix: &str) -> (RaftStateMachine, NetworkInfo) {
+        let cluster: Vec<ClusterConfig> = (1..=3).map(ClusterConfig::voter).collect();
+        create_cluster_server(server_id, file_prefix, &cluster)
+    }
+
+    /// Creates the state machine for a server.
+    /// # Arguments
+    /// `server_id` - The id of the server.
+    /// `file_prefix` - The prefix of the commit files so each server has its own.
+    /// `cluster` - The servers in the cluster.
+    fn create_cluster_server(
+        server_id: u32,
+        file_prefix: &str,
+        cluster: &[ClusterConfig],
+    ) -> (RaftStateMachine, NetworkInfo) {
         let (event_writer, event_reader) = create_skip_queue(1024);
         let (net_writer, net_reader) = SpscQueueSendWrap::new(1024);
-        let mut raft_state_machine = RaftStateMachine {
-            server_id: 1,
-            current_state: RaftState::Candidate {
-                round_number: 0,
-                votes: HashSet::new(),
-            },
-            current_term_id: 0,
-            last_appended_term_id: 0,
-            voted_for: None,
-            connected_server: HashMap::with_capacity(1),
-            leader_votes: HashSet::new(),
-            message_queue: event_reader,
-            commit_term_file: create_term_file(
+        // Every test starts without a saved vote.
+        let _ = remove_file(create_meta_name(FILE_STORAGE_DIRECTORY, file_prefix));
+        let raft_state_machine = RaftStateMachine::new(
+            server_id,
+            event_reader,
+            net_writer,
+            create_term_file(
                 FILE_STORAGE_DIRECTORY,
-                FILE_PREFIX,
+                file_prefix,
                 1,
                 1,
                 COMMIT_FILE_SIZE as usize,
             ),
-            commit_file_size: COMMIT_FILE_SIZE,
-            server_count: 3,
-            leader: 0,
-            state_message_queue_writer: net_writer,
-            max_message_id: Arc::new(AtomicU64::new(0)),
-            current_commited_term: 0,
-            votes_required: 2,
-            file_storage_directory: FILE_STORAGE_DIRECTORY.to_owned(),
-            file_prefix: FILE_PREFIX.to_owned(),
+            COMMIT_FILE_SIZE,
+            FILE_STORAGE_DIRECTORY,
+            file_prefix,
+            cluster,
+        )
+        .unwrap();
+        let read_waiters = raft_state_machine.read_waiters.clone();
+
+        let net = NetworkInfo {
+            net_reader,
+            event_writer: Arc::new(event_writer),
+            read_waiters,
         };
-        raft_state_machine
-            .connected_server
-            .insert(1, ConnectedServer { server_id: 1 });
-        raft_state_machine
-            .connected_server
-            .insert(2, ConnectedServer { server_id: 2 });
-        raft_state_machine
-            .connected_server
-            .insert(3, ConnectedServer { server_id: 3 });
-
-        let net = NetworkInfo { net_reader };
         (raft_state_machine, net)
     }
 
@@ -691,7 +1636,7 @@ mod test {
         let (mut state_machine, _) = create_state_machine();
         state_machine.process_event(RaftEvent::ElectedLeader { server_id: 2 });
         match state_machine.current_state {
-            RaftState::Follower { leader } => {
+            RoleState::Follower { leader } => {
                 assert_eq!(2, leader);
             }
             _ => {
@@ -733,7 +1678,8 @@ mod test {
         }
 
         match &state_machine.current_state {
-            RaftState::Candidate { votes, .. } => {}
+            // We vote for ourselves.
+            RoleState::Candidate { votes, .. } => assert!(votes.len() == 1 && votes.contains(&1)),
             _ => {
                 assert!(false);
             }
@@ -764,7 +1710,7 @@ mod test {
         }
 
         match &state_machine.current_state {
-            RaftState::Leader {
+            RoleState::Leader {
                 match_index,
                 next_index,
             } => {}
@@ -790,8 +1736,8 @@ mod test {
         state_machine
             .commit_term_file
             .buffer
-            .write_bytes(0, &zeros);
-        state_machine.current_state = RaftState::Follower { leader: 2 };
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
+        state_machine.current_state = RoleState::Follower { leader: 2 };
         state_machine.process_event(RaftEvent::Commited {
             server_id: 2,
             term_id,
@@ -822,7 +1768,7 @@ mod test {
         state_machine
             .commit_term_file
             .buffer
-            .write_bytes(0, &zeros);
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
         let mut next_term_file = create_term_file(
             FILE_STORAGE_DIRECTORY,
             FILE_PREFIX,
@@ -832,9 +1778,9 @@ mod test {
         );
         let mut first_term_file =
             create_term_file(FILE_STORAGE_DIRECTORY, FILE_PREFIX, 1, 1, COMMIT_FILE_SIZE);
-        next_term_file.buffer.write_bytes(0, &zeros);
+        next_term_file.buffer.write_bytes(FILE_HEADER_SIZE, &zeros);
 
-        state_machine.current_state = RaftState::Follower { leader: 2 };
+        state_machine.current_state = RoleState::Follower { leader: 2 };
         state_machine.process_event(RaftEvent::Commited {
             server_id: 2,
             term_id,
@@ -861,7 +1807,7 @@ mod test {
         let (mut state_machine, net) = create_state_machine();
         state_machine.current_term_id = 1;
         state_machine.current_commited_term = 0;
-        state_machine.current_state = RaftState::Leader {
+        state_machine.current_state = RoleState::Leader {
             next_index: HashMap::with_capacity(3),
             match_index: HashMap::with_capacity(3),
         };
@@ -870,11 +1816,12 @@ mod test {
         state_machine
             .commit_term_file
             .buffer
-            .write_bytes(0, &zeros);
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
 
         state_machine.process_event(RaftEvent::Pong {
             server_id: 2,
             max_term_id: 1,
+            round: 0,
         });
         let net_result = net.net_reader.poll().unwrap();
         match net_result {
@@ -907,7 +1854,870 @@ mod test {
         assert!(timestamp > 0);
     }
 
+    #[test]
+    #[serial]
+    pub fn events_test() {
+        let (mut state_machine, _net) = create_state_machine();
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+        state_machine.events = Some(bus);
+        state_machine.process_event(RaftEvent::ElectedLeader { server_id: 2 });
+        assert_eq!(
+            Some(StoreEvent::LeaderElected { server_id: 2 }),
+            events.poll()
+        );
+
+        state_machine.current_state = RoleState::Leader {
+            next_index: HashMap::with_capacity(3),
+            match_index: HashMap::with_capacity(3),
+        };
+        state_machine.current_term_id = 1;
+        state_machine.current_commited_term = 0;
+        let zeros = get_commit_zeros();
+        state_machine
+            .commit_term_file
+            .buffer
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 2,
+            max_term_id: 1,
+            round: 0,
+        });
+        assert_eq!(Some(StoreEvent::TermCommitted { term_id: 1 }), events.poll());
+
+        // Only reported once while it stays behind.
+        state_machine.current_term_id = 100;
+        for _ in 0..2 {
+            state_machine.process_event(RaftEvent::Pong {
+                server_id: 3,
+                max_term_id: 1,
+                round: 0,
+            });
+        }
+        assert_eq!(
+            Some(StoreEvent::FollowerLagging {
+                peer: 3,
+                behind: 99
+            }),
+            events.poll()
+        );
+        assert_eq!(None, events.poll());
+    }
+
+    #[test]
+    #[serial]
+    pub fn read_index_leader_test() {
+        let (mut state_machine, net) = create_state_machine();
+        state_machine.current_term_id = 1;
+        state_machine.current_commited_term = 1;
+        state_machine.current_state = RoleState::Leader {
+            next_index: HashMap::with_capacity(3),
+            match_index: HashMap::with_capacity(3),
+        };
+        state_machine.process_event(RaftEvent::NoMessagesTimeout);
+        assert!(net.net_reader.poll().is_some());
+        state_machine.process_event(RaftEvent::ReadIndexRequest {
+            server_id: 2,
+            request_id: 10,
+        });
+        // Leadership has to be confirmed before the read index is returned.
+        match net.net_reader.poll().unwrap() {
+            NetworkSendType::Broadcast {
+                msg: NetworkSend::RaftEvent(RaftEvent::Ping { round, .. }),
+            } => {
+                assert_eq!(2, round);
+            }
+            _ => {
+                panic!("Expected a ping to confirm the leadership!");
+            }
+        }
+        assert!(net.net_reader.poll().is_none());
+
+        // The pong for the ping sent before the request doesn't confirm it.
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 3,
+            max_term_id: 1,
+            round: 1,
+        });
+        assert!(net.net_reader.poll().is_none());
+
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 3,
+            max_term_id: 1,
+            round: 2,
+        });
+        match net.net_reader.poll().unwrap() {
+            NetworkSendType::Single {
+                server_id,
+                msg:
+                    NetworkSend::RaftEvent(RaftEvent::ReadIndexResponse {
+                        request_id,
+                        commit_term,
+                        ..
+                    }),
+            } => {
+                assert_eq!(2, server_id);
+                assert_eq!(10, request_id);
+                assert_eq!(1, commit_term);
+            }
+            _ => {
+                panic!("Expected the read index response!");
+            }
+        }
+        assert!(state_machine.pending_read_index.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    pub fn read_index_own_term_test() {
+        let (mut state_machine, net) = create_state_machine();
+        let zeros = get_commit_zeros();
+        state_machine
+            .commit_term_file
+            .buffer
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
+        state_machine.current_term_id = 2;
+        state_machine.current_commited_term = 1;
+        state_machine.leader_start_term = 2;
+        state_machine.current_state = RoleState::Leader {
+            next_index: HashMap::with_capacity(3),
+            match_index: HashMap::with_capacity(3),
+        };
+        state_machine.process_event(RaftEvent::ReadIndexRequest {
+            server_id: 2,
+            request_id: 10,
+        });
+        assert!(net.net_reader.poll().is_some());
+
+        // The leadership is confirmed but we haven't committed a term of our own yet.
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 3,
+            max_term_id: 1,
+            round: 1,
+        });
+        assert!(net.net_reader.poll().is_none());
+        assert_eq!(1, state_machine.pending_read_index.len());
+
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 3,
+            max_term_id: 2,
+            round: 1,
+        });
+        match net.net_reader.poll().unwrap() {
+            NetworkSendType::Single {
+                server_id,
+                msg:
+                    NetworkSend::RaftEvent(RaftEvent::ReadIndexResponse {
+                        request_id,
+                        commit_term,
+                        ..
+                    }),
+            } => {
+                assert_eq!(2, server_id);
+                assert_eq!(10, request_id);
+                assert_eq!(2, commit_term);
+            }
+            _ => {
+                panic!("Expected the read index response!");
+            }
+        }
+        match net.net_reader.poll().unwrap() {
+            NetworkSendType::Broadcast {
+                msg: NetworkSend::RaftEvent(RaftEvent::Commited { term_id, .. }),
+            } => {
+                assert_eq!(2, term_id);
+            }
+            _ => {
+                panic!("Expected the commit!");
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    pub fn read_index_follower_test() {
+        let (mut state_machine, net) = create_state_machine();
+        let zeros = get_commit_zeros();
+        state_machine
+            .commit_term_file
+            .buffer
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
+        state_machine
+            .commit_term_file
+            .buffer
+            .set_term(0, 1)
+            .set_max_message_id(0, 4);
+        state_machine.current_state = RoleState::Follower { leader: 2 };
+        let mut ready = net.read_waiters.add(5);
+        state_machine.process_event(RaftEvent::ReadIndexRequest {
+            server_id: 1,
+            request_id: 5,
+        });
+        match net.net_reader.poll().unwrap() {
+            NetworkSendType::Single {
+                server_id,
+                msg: NetworkSend::RaftEvent(RaftEvent::ReadIndexRequest { request_id, .. }),
+            } => {
+                assert_eq!(2, server_id);
+                assert_eq!(5, request_id);
+            }
+            _ => {
+                panic!("Expected the request to be forwarded to the leader!");
+            }
+        }
+
+        // The leader has committed a term we haven't seen yet.
+        state_machine.process_event(RaftEvent::ReadIndexResponse {
+            server_id: 2,
+            request_id: 5,
+            commit_term: 1,
+        });
+        assert_eq!(None, ready.try_recv().unwrap());
+
+        state_machine.process_event(RaftEvent::Commited {
+            server_id: 2,
+            term_id: 1,
+        });
+        // Completes with the last message in the term so the client waits for it to be applied.
+        assert_eq!(Some(4), ready.try_recv().unwrap());
+    }
+
+    #[test]
+    #[serial]
+    pub fn read_index_leader_change_test() {
+        let (mut state_machine, net) = create_state_machine();
+        state_machine.current_state = RoleState::Follower { leader: 2 };
+        let mut ready = net.read_waiters.add(7);
+        state_machine.process_event(RaftEvent::ReadIndexRequest {
+            server_id: 1,
+            request_id: 7,
+        });
+        assert!(net.net_reader.poll().is_some());
+
+        // The leader changed before it confirmed the read so the client has to send it again.
+        state_machine.process_event(RaftEvent::ElectedLeader { server_id: 3 });
+        assert!(ready.try_recv().is_err());
+        state_machine.process_event(RaftEvent::ReadIndexResponse {
+            server_id: 3,
+            request_id: 7,
+            commit_term: 0,
+        });
+        assert!(state_machine.waiting_reads.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    pub fn status_test() {
+        let (mut state_machine, net) = create_state_machine();
+        let clock = Arc::new(ManualClock::new(1_000));
+        state_machine.clock = clock.clone();
+        let zeros = get_commit_zeros();
+        state_machine
+            .commit_term_file
+            .buffer
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
+        state_machine.process_event(RaftEvent::ElectedLeader { server_id: 2 });
+        {
+            let status = state_machine.status.lock().unwrap();
+            assert_eq!(NodeRole::Follower, status.role);
+            assert_eq!(Some(2), status.leader);
+            assert_eq!(1, status.messages_received);
+        }
+
+        state_machine.process_event(RaftEvent::LeaderTimeout);
+        {
+            let status = state_machine.status.lock().unwrap();
+            assert_eq!(NodeRole::Candidate, status.role);
+            assert_eq!(None, status.leader);
+            assert_eq!(1, status.election_count);
+            assert_eq!(1, status.messages_sent);
+        }
+
+        clock.advance(Duration::from_millis(250));
+        state_machine.process_event(RaftEvent::VoteForCandiate { server_id: 3 });
+        state_machine.current_term_id = 1;
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 3,
+            max_term_id: 1,
+            round: 0,
+        });
+        let status = state_machine.status.lock().unwrap();
+        assert_eq!(NodeRole::Leader, status.role);
+        assert_eq!(Some(1), status.leader);
+        assert_eq!(1, status.commit_term);
+        assert_eq!(3, status.messages_received);
+        assert_eq!(2, status.peers.len());
+        let peer = &status.peers[1];
+        assert_eq!(3, peer.server_id);
+        assert_eq!(1, peer.match_term);
+        assert_eq!(1_250, peer.last_contact_ms);
+        assert_eq!(2, status.peers[0].server_id);
+        assert_eq!(1_000, status.peers[0].last_contact_ms);
+        while net.net_reader.poll().is_some() {}
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn status_serde_test() {
+        let mut status = NodeStatus::new(1);
+        status.role = NodeRole::Leader;
+        status.leader = Some(1);
+        status.peers.push(PeerStatus {
+            server_id: 2,
+            match_term: 4,
+            next_term: 5,
+            last_contact_ms: 1_250,
+            voting: true,
+            reachable: false,
+        });
+        let json = serde_json::to_string(&status).unwrap();
+        assert!(json.contains("\"role\":\"Leader\""));
+        assert_eq!(status, serde_json::from_str::<NodeStatus>(&json).unwrap());
+    }
+
+    #[test]
+    #[serial]
+    pub fn learner_quorum_test() {
+        let cluster = [
+            ClusterConfig::voter(1),
+            ClusterConfig::voter(2),
+            ClusterConfig::voter(3),
+            ClusterConfig::learner(4),
+        ];
+        let (mut state_machine, net) = create_cluster_server(1, FILE_PREFIX, &cluster);
+        assert_eq!(3, state_machine.server_count);
+        assert_eq!(2, state_machine.votes_required);
+        let zeros = get_commit_zeros();
+        state_machine
+            .commit_term_file
+            .buffer
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
+        state_machine.current_term_id = 1;
+        state_machine.current_state = RoleState::Leader {
+            next_index: HashMap::with_capacity(3),
+            match_index: HashMap::with_capacity(3),
+        };
+
+        // The learner doesn't count towards the quorum.
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 4,
+            max_term_id: 1,
+            round: 0,
+        });
+        assert_eq!(0, state_machine.current_commited_term);
+        assert!(net.net_reader.poll().is_none());
+
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 2,
+            max_term_id: 1,
+            round: 0,
+        });
+        assert_eq!(1, state_machine.current_commited_term);
+    }
+
+    #[test]
+    #[serial]
+    pub fn leader_commits_own_term_test() {
+        let (mut state_machine, net) = create_state_machine();
+        let zeros = get_commit_zeros();
+        state_machine
+            .commit_term_file
+            .buffer
+            .write_bytes(FILE_HEADER_SIZE, &zeros);
+        state_machine.current_term_id = 3;
+        state_machine.leader_start_term = 3;
+        state_machine.current_state = RoleState::Leader {
+            next_index: HashMap::with_capacity(3),
+            match_index: HashMap::with_capacity(3),
+        };
+
+        // A quorum has the terms from the last leader but none of ours.
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 2,
+            max_term_id: 2,
+            round: 0,
+        });
+        assert_eq!(0, state_machine.current_commited_term);
+        assert!(net.net_reader.poll().is_none());
+
+        // The earlier terms are committed with ours.
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 2,
+            max_term_id: 3,
+            round: 0,
+        });
+        assert_eq!(3, state_machine.current_commited_term);
+        for term_id in 1..=3 {
+            match net.net_reader.poll().unwrap() {
+                NetworkSendType::Broadcast {
+                    msg: NetworkSend::RaftEvent(RaftEvent::Commited { term_id: commited, .. }),
+                } => assert_eq!(term_id, commited),
+                _ => panic!("Expected the term to be committed!"),
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn vote_saved_test() {
+        let (mut state_machine, _net) = create_state_machine();
+        state_machine.current_state = RoleState::Follower { leader: 2 };
+        state_machine.process_event(RaftEvent::VoteForMe {
+            server_id: 3,
+            max_term_id: 0,
+        });
+        assert_eq!(Some(3), state_machine.voted_for);
+        drop(state_machine);
+
+        // Restarted so the vote has to come from the meta file.
+        let (_, event_reader) = create_skip_queue(16);
+        let (net_writer, net_reader) = SpscQueueSendWrap::new(16);
+        let mut state_machine = RaftStateMachine::new(
+            1,
+            event_reader,
+            net_writer,
+            create_term_file(
+                FILE_STORAGE_DIRECTORY,
+                FILE_PREFIX,
+                1,
+                1,
+                COMMIT_FILE_SIZE,
+            ),
+            COMMIT_FILE_SIZE,
+            FILE_STORAGE_DIRECTORY,
+            FILE_PREFIX,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(Some(3), state_machine.voted_for);
+        state_machine.current_state = RoleState::Follower { leader: 2 };
+        state_machine.process_event(RaftEvent::VoteForMe {
+            server_id: 2,
+            max_term_id: 0,
+        });
+        assert_eq!(Some(3), state_machine.voted_for);
+        assert!(net_reader.poll().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn learner_no_election_test() {
+        let cluster = [
+            ClusterConfig::learner(1),
+            ClusterConfig::voter(2),
+            ClusterConfig::voter(3),
+        ];
+        let (mut state_machine, net) = create_cluster_server(1, FILE_PREFIX, &cluster);
+        state_machine.current_state = RoleState::Follower { leader: 2 };
+        state_machine.process_event(RaftEvent::LeaderTimeout);
+        match state_machine.current_state {
+            RoleState::Follower { leader } => {
+                assert_eq!(2, leader);
+            }
+            _ => {
+                panic!("A learner shouldn't start an election!");
+            }
+        }
+        assert!(net.net_reader.poll().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn transfer_leadership_test() {
+        let (mut state_machine, net) = create_state_machine();
+        state_machine.current_term_id = 2;
+        state_machine.current_commited_term = 2;
+        let mut match_index = HashMap::with_capacity(3);
+        match_index.insert(2, 1);
+        state_machine.current_state = RoleState::Leader {
+            next_index: HashMap::with_capacity(3),
+            match_index,
+        };
+        state_machine.process_event(RaftEvent::TransferLeadership { server_id: 2 });
+        assert_eq!(Some(2), state_machine.transfer_target);
+        // The target is behind so we have to wait for it to catch up.
+        assert!(net.net_reader.poll().is_none());
+
+        state_machine.process_event(RaftEvent::Pong {
+            server_id: 2,
+            max_term_id: 2,
+            round: 0,
+        });
+        match net.net_reader.poll().unwrap() {
+            NetworkSendType::Single {
+                server_id,
+                msg: NetworkSend::RaftEvent(RaftEvent::TimeoutNow { server_id: leader }),
+            } => {
+                assert_eq!(2, server_id);
+                assert_eq!(1, leader);
+            }
+            _ => {
+                panic!("Expected timeout now to be sent to the target!");
+            }
+        }
+
+        state_machine.process_event(RaftEvent::ElectedLeader { server_id: 2 });
+        assert_eq!(None, state_machine.transfer_target);
+        match state_machine.current_state {
+            RoleState::Follower { leader } => {
+                assert_eq!(2, leader);
+            }
+            _ => {
+                panic!("Should be following the new leader!");
+            }
+        }
+        assert_eq!(2, state_machine.current_commited_term);
+    }
+
+    #[test]
+    #[serial]
+    fn transfer_hold_messages_test() {
+        let (mut state_machine, net) = create_state_machine();
+        state_machine.current_term_id = 2;
+        let mut match_index = HashMap::with_capacity(3);
+        match_index.insert(2, 1);
+        state_machine.current_state = RoleState::Leader {
+            next_index: HashMap::with_capacity(3),
+            match_index,
+        };
+        state_machine.process_event(RaftEvent::TransferLeadership { server_id: 2 });
+        net.event_writer.offer(RaftEvent::ClientMessageReceived);
+        let event = state_machine.message_queue.poll().unwrap();
+        state_machine.process_event(event);
+        // The message is held until the transfer is done.
+        assert_eq!(1, state_machine.status.lock().unwrap().held_messages);
+        assert!(state_machine.message_queue.poll().is_none());
+
+        // The transfer timed out so we are still the leader and accept it.
+        state_machine.process_event(RaftEvent::VoteTimeout);
+        assert_eq!(None, state_machine.transfer_target);
+        match state_machine.message_queue.poll() {
+            Some(RaftEvent::ClientMessageReceived) => {}
+            _ => {
+                panic!("Expected the held message to be released!");
+            }
+        }
+        state_machine.process_event(RaftEvent::ClientMessageReceived);
+        assert_eq!(0, state_machine.status.lock().unwrap().held_messages);
+        assert!(state_machine.message_queue.poll().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn election_timer_test() {
+        let (mut state_machine, net) = create_state_machine();
+        let clock = Arc::new(ManualClock::new(0));
+        state_machine.election_timer = ElectionTimer::with_seed(
+            clock.clone(),
+            Duration::from_millis(150),
+            Duration::from_millis(0),
+            1,
+        );
+        state_machine.leader = 2;
+        state_machine.current_state = RoleState::Follower { leader: 2 };
+
+        // Hearing from the leader starts the timer again.
+        clock.advance(Duration::from_millis(100));
+        state_machine.process_event(RaftEvent::Ping {
+            server_id: 2,
+            max_commited_term: 0,
+            round: 1,
+        });
+        assert!(net.net_reader.poll().is_some());
+        clock.advance(Duration::from_millis(100));
+        state_machine.process_event(RaftEvent::NoMessagesTimeout);
+        assert!(net.net_reader.poll().is_none());
+
+        clock.advance(Duration::from_millis(50));
+        state_machine.process_event(RaftEvent::NoMessagesTimeout);
+        match net.net_reader.poll().unwrap() {
+            NetworkSendType::Broadcast {
+                msg: NetworkSend::RaftEvent(RaftEvent::VoteForMe { server_id, .. }),
+            } => {
+                assert_eq!(1, server_id);
+            }
+            _ => {
+                panic!("Expected the vote request!");
+            }
+        }
+        assert_eq!(1, state_machine.election_count);
+
+        // A candidate that doesn't win in time starts another round.
+        clock.advance(Duration::from_millis(150));
+        state_machine.process_event(RaftEvent::NoMessagesTimeout);
+        assert!(net.net_reader.poll().is_some());
+        assert_eq!(2, state_machine.election_count);
+    }
+
+    #[test]
+    #[serial]
+    fn timeout_now_test() {
+        let (mut state_machine, net) = create_state_machine();
+        state_machine.current_state = RoleState::Follower { leader: 2 };
+        state_machine.process_event(RaftEvent::TimeoutNow { server_id: 3 });
+        assert!(net.net_reader.poll().is_none());
+
+        state_machine.process_event(RaftEvent::TimeoutNow { server_id: 2 });
+        match state_machine.current_state {
+            RoleState::Candidate { .. } => {}
+            _ => {
+                panic!("Should have started an election!");
+            }
+        }
+        match net.net_reader.poll().unwrap() {
+            NetworkSendType::Broadcast {
+                msg: NetworkSend::RaftEvent(RaftEvent::VoteForMe { server_id, .. }),
+            } => {
+                assert_eq!(1, server_id);
+            }
+            _ => {
+                panic!("Expected a vote request!");
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn peer_connection_changed_test() {
+        let (mut state_machine, _) = create_state_machine();
+        state_machine.current_state = RoleState::Follower { leader: 2 };
+        state_machine.process_event(RaftEvent::PeerConnectionChanged {
+            server_id: 3,
+            connected: false,
+        });
+        {
+            let status = state_machine.status.lock().unwrap();
+            assert!(status.peers[0].reachable);
+            assert!(!status.peers[1].reachable);
+            assert_eq!(0, status.reconnects);
+        }
+        state_machine.process_event(RaftEvent::PeerConnectionChanged {
+            server_id: 3,
+            connected: true,
+        });
+        let status = state_machine.status.lock().unwrap();
+        assert!(status.peers[1].reachable);
+        assert_eq!(1, status.reconnects);
+    }
+
+    /// A cluster of 3 servers running in memory.  The messages the servers send are handed to
+    /// each other by the test so they can be held back.
+    struct TestCluster {
+        servers: Vec<(RaftStateMachine, NetworkInfo)>,
+    }
+
+    impl TestCluster {
+        /// Creates the cluster with server 1 as the leader.
+        /// # Arguments
+        /// `file_prefix` - The prefix of the commit files, the id of the server is added to it.
+        fn new(file_prefix: &str) -> Self {
+            let zeros = get_commit_zeros();
+            let servers = (1..=3)
+                .map(|server_id| {
+                    let (mut state_machine, net) =
+                        create_server(server_id, &format!("{}_{}", file_prefix, server_id));
+                    state_machine
+                        .commit_term_file
+                        .buffer
+                        .write_bytes(FILE_HEADER_SIZE, &zeros);
+                    state_machine.leader = 1;
+                    state_machine.current_state = if server_id == 1 {
+                        RoleState::Leader {
+                            next_index: HashMap::with_capacity(3),
+                            match_index: HashMap::with_capacity(3),
+                        }
+                    } else {
+                        RoleState::Follower { leader: 1 }
+                    };
+                    (state_machine, net)
+                })
+                .collect();
+            TestCluster { servers }
+        }
+
+        /// Gets the state machine for a server.
+        fn server(&mut self, server_id: u32) -> &mut RaftStateMachine {
+            &mut self.servers[server_id as usize - 1].0
+        }
+
+        /// Creates a client for a server.
+        fn client(&self, server_id: u32) -> RaftStateMachineClient {
+            let (state_machine, net) = &self.servers[server_id as usize - 1];
+            let (_, buffer_reader) = create_many_to_one(0x1000);
+            let (_, client_message_writer) = create_many_to_one(0x1000);
+            RaftStateMachineClient {
+                server_id,
+                internal_message_id: AtomicU64::new(0),
+                buffer_reader,
+                state_machine_thread: None,
+                client_message_writer,
+                state_message_queue: net.event_writer.clone(),
+                max_message_id: state_machine.max_message_id.clone(),
+                status: state_machine.status.clone(),
+                read_waiters: net.read_waiters.clone(),
+                next_read_id: AtomicU64::new(1),
+            }
+        }
+
+        /// Adds a term with the messages up to `max_message_id` to every server like it has been
+        /// replicated.
+        fn write_term(&mut self, term_id: u64, max_message_id: u64) {
+            for (state_machine, _) in self.servers.iter_mut() {
+                let pos = match state_machine.commit_term_file.calculate_pos(&term_id) {
+                    TermPosResult::Pos(pos) => pos,
+                    _ => panic!("Should be in range!"),
+                };
+                state_machine
+                    .commit_term_file
+                    .buffer
+                    .set_term(pos, term_id)
+                    .set_max_message_id(pos, max_message_id);
+                state_machine.current_term_id = term_id;
+            }
+        }
+
+        /// Sends an event to a server.
+        fn deliver(&self, server_id: u32, event: RaftEvent) {
+            assert!(self.servers[server_id as usize - 1]
+                .1
+                .event_writer
+                .offer(event));
+        }
+
+        /// Runs the servers until none of them have anything left to do.
+        /// # Arguments
+        /// `hold` - Returns true for a message to a server that should be held back.
+        /// # Returns
+        /// The messages held back with the server they were sent to.
+        fn run<F: Fn(u32, &RaftEvent) -> bool>(&mut self, hold: F) -> Vec<(u32, RaftEvent)> {
+            let mut held = Vec::new();
+            loop {
+                let mut sent = Vec::new();
+                for (state_machine, net) in self.servers.iter_mut() {
+                    while let Some(event) = state_machine.message_queue.poll() {
+                        state_machine.process_event(event);
+                    }
+                    while let Some(msg) = net.net_reader.poll() {
+                        match msg {
+                            NetworkSendType::Broadcast {
+                                msg: NetworkSend::RaftEvent(event),
+                            } => {
+                                for server_id in (1..=3).filter(|id| *id != state_machine.server_id) {
+                                    sent.push((server_id, event.clone()));
+                                }
+                            }
+                            NetworkSendType::Single {
+                                server_id,
+                                msg: NetworkSend::RaftEvent(event),
+                            } => {
+                                sent.push((server_id, event));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                if sent.is_empty() {
+                    break held;
+                }
+                for (server_id, event) in sent {
+                    if hold(server_id, &event) {
+                        held.push((server_id, event));
+                    } else {
+                        self.deliver(server_id, event);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    pub fn linearizable_read_cluster_test() {
+        let mut cluster = TestCluster::new("linearizable_read");
+        cluster.write_term(1, 5);
+
+        // Server 3 doesn't find out the term was committed.
+        let hold_commit = |server_id: u32, event: &RaftEvent| {
+            server_id == 3 && matches!(event, RaftEvent::Commited { .. })
+        };
+        cluster.deliver(1, RaftEvent::NoMessagesTimeout);
+        let held = cluster.run(hold_commit);
+        assert_eq!(1, held.len());
+        assert_eq!(1, cluster.server(1).current_commited_term);
+        assert_eq!(1, cluster.server(2).current_commited_term);
+        assert_eq!(0, cluster.server(3).current_commited_term);
+
+        let client = Arc::new(cluster.client(3));
+        let (done, read) = mpsc::channel();
+        let reader = {
+            let client = client.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                // Reads what server 3 has applied as soon as the read is let through.
+                let applied = client.max_message_id();
+                let id = block_on(client.linearizable_read(|| applied.load(Ordering::Acquire)));
+                done.send(id).unwrap();
+            })
+        };
+
+        // The leader confirms the read index but the read has to wait for the commit.
+        let start = Instant::now();
+        while cluster.server(3).waiting_reads.is_empty() {
+            assert!(cluster.run(hold_commit).is_empty());
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::yield_now();
+        }
+        assert_eq!(1, cluster.server(3).waiting_reads[0].read_index);
+        assert!(read.recv_timeout(Duration::from_millis(50)).is_err());
+
+        for (server_id, event) in held {
+            cluster.deliver(server_id, event);
+        }
+        cluster.run(|_, _| false);
+        assert_eq!(5, read.recv_timeout(Duration::from_secs(5)).unwrap().unwrap());
+        reader.join().unwrap();
+
+        // The term is committed but the messages haven't all been applied so the read waits.
+        let applied = client.max_message_id();
+        applied.store(4, Ordering::Release);
+        let reader = thread::spawn(move || {
+            let applied = client.max_message_id();
+            let id = block_on(client.linearizable_read(|| applied.load(Ordering::Acquire)));
+            done.send(id).unwrap();
+        });
+        let start = Instant::now();
+        while read.recv_timeout(Duration::from_millis(10)).is_err() {
+            cluster.run(|_, _| false);
+            if start.elapsed() > Duration::from_millis(200) {
+                break;
+            }
+        }
+        assert!(cluster.server(3).waiting_reads.is_empty());
+        assert!(read.recv_timeout(Duration::from_millis(50)).is_err());
+        applied.store(5, Ordering::Release);
+        assert_eq!(5, read.recv_timeout(Duration::from_secs(5)).unwrap().unwrap());
+        reader.join().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    pub fn transfer_leadership_client_test() {
+        let mut cluster = TestCluster::new("transfer_leadership_client");
+        cluster.write_term(1, 5);
+        cluster.deliver(1, RaftEvent::NoMessagesTimeout);
+        assert!(cluster.run(|_, _| false).is_empty());
+
+        let client = cluster.client(1);
+        assert!(client.transfer_leadership(2));
+        cluster.run(|_, _| false);
+        assert_eq!(NodeRole::Leader, cluster.client(2).status().role);
+        assert_eq!(Some(2), client.status().leader);
+        assert_eq!(Some(2), cluster.client(3).status().leader);
+    }
+
     struct NetworkInfo {
         net_reader: SpscQueueReceiveWrap<NetworkSendType>,
+        event_writer: Arc<MpscQueueWrap<RaftEvent>>,
+        read_waiters: Arc<ReadWaiters>,
     }
 }
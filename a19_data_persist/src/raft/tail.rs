@@ -0,0 +1,318 @@
+//! Follows the end of the store as the messages are committed.  The reader goes through the
+//! committed messages and once it is caught up it parks the thread or hands its waker to the
+//! commit thread instead of spinning.  The commit thread signals the waiters every time it moves
+//! the max committed message id forward.
+//!
+//! ```text
+//!  commit thread                          tail reader
+//! +-------------------+                  +---------------------------+
+//! | store max id      |                  | read up to the max id     |
+//! | signal the waiters| ---- wakes ----> | caught up so park/pending |
+//! +-------------------+                  +---------------------------+
+//! ```
+use crate::file;
+use crate::file::MessageRead;
+use crate::raft::{CommittedCursor, OwnedMessageInfo, PersistedMessageFile};
+use futures::Stream;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Wakes the readers waiting for more messages to be committed.
+#[derive(Default)]
+pub(crate) struct CommitSignal {
+    /// The wakers of the futures waiting for a commit.
+    wakers: Mutex<Vec<Waker>>,
+    /// The threads parked waiting for a commit.
+    condvar: Condvar,
+}
+
+impl CommitSignal {
+    /// Wakes everything waiting.  Called after the max committed message id has been stored and
+    /// when the store is stopped.
+    pub(crate) fn notify(&self) {
+        let wakers = {
+            let mut wakers = self.wakers.lock().unwrap();
+            self.condvar.notify_all();
+            mem::take(&mut *wakers)
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Parks the thread until signaled or the timeout.
+    /// # Arguments
+    /// `ready` - Checked while holding the lock so a signal between checking and parking isn't
+    /// missed.
+    /// `timeout` - The longest to wait.
+    fn wait(&self, ready: impl Fn() -> bool, timeout: Duration) {
+        let wakers = self.wakers.lock().unwrap();
+        if !ready() {
+            let _ = self.condvar.wait_timeout(wakers, timeout).unwrap();
+        }
+    }
+
+    /// Adds the waker of a future to wake on the next signal.
+    /// # Arguments
+    /// `waker` - The waker to add.
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+}
+
+/// Reads the committed messages and waits for more once it is caught up.
+pub struct TailReader<'a> {
+    file: &'a PersistedMessageFile,
+    cursor: CommittedCursor,
+    signal: Arc<CommitSignal>,
+    stop: Arc<AtomicU8>,
+}
+
+impl<'a> TailReader<'a> {
+    /// Creates a reader starting at a message.
+    /// # Arguments
+    /// `file` - The store to read from.
+    /// `from_id` - The id of the first message to return.
+    pub(crate) fn new(
+        file: &'a PersistedMessageFile,
+        from_id: u64,
+        signal: Arc<CommitSignal>,
+        stop: Arc<AtomicU8>,
+    ) -> Self {
+        TailReader {
+            file,
+            cursor: CommittedCursor::new(from_id),
+            signal,
+            stop,
+        }
+    }
+
+    /// Gets the next committed message without waiting.
+    /// # Returns
+    /// `None` if we are caught up with the commits.
+    pub fn poll_next(&mut self) -> Option<MessageRead<'a>> {
+        self.cursor.next(self.file)
+    }
+
+    /// Gets the next committed message parking the thread until it is committed.
+    /// # Arguments
+    /// `timeout` - The longest to wait for the message.
+    /// # Returns
+    /// `None` if the message wasn't committed before the timeout or the store was stopped.
+    pub fn wait_next(&mut self, timeout: Duration) -> Option<MessageRead<'a>> {
+        let started = Instant::now();
+        loop {
+            let seen = self.file.max_message_id();
+            if let Some(msg) = self.poll_next() {
+                break Some(msg);
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= timeout || self.stopped() {
+                break None;
+            }
+            let file = self.file;
+            self.signal.wait(
+                || file.max_message_id() > seen || self.stopped(),
+                timeout - elapsed,
+            );
+        }
+    }
+
+    /// Gets a future that completes with the next committed message.
+    /// # Returns
+    /// The future that completes with the message or `None` once the store is stopped.
+    pub fn next_async(&mut self) -> TailNext<'_, 'a> {
+        TailNext { reader: self }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Acquire) > 0
+    }
+}
+
+/// The future waiting for the next message to be committed.
+pub struct TailNext<'r, 'a> {
+    reader: &'r mut TailReader<'a>,
+}
+
+impl<'r, 'a> Future for TailNext<'r, 'a> {
+    type Output = Option<MessageRead<'a>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(msg) = self.reader.poll_next() {
+            return Poll::Ready(Some(msg));
+        }
+        if self.reader.stopped() {
+            return Poll::Ready(None);
+        }
+        self.reader.signal.register(cx.waker());
+        // Check again in case the commit happened before the waker was added.
+        match self.reader.poll_next() {
+            Some(msg) => Poll::Ready(Some(msg)),
+            None if self.reader.stopped() => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The committed messages as a `Stream`.  Each message is copied so it can be kept past the next
+/// poll.  Once it is caught up the waker is handed to the commit thread so the stream is pending
+/// instead of spinning.  Dropping the stream only leaves a stale waker that is cleared on the next
+/// commit so it never holds up the writers.
+pub struct CommittedStream<'a> {
+    reader: TailReader<'a>,
+}
+
+impl<'a> CommittedStream<'a> {
+    /// Creates a stream starting at a message.
+    /// # Arguments
+    /// `reader` - The reader following the commits.
+    pub(crate) fn new(reader: TailReader<'a>) -> Self {
+        CommittedStream { reader }
+    }
+}
+
+impl<'a> Stream for CommittedStream<'a> {
+    type Item = file::Result<OwnedMessageInfo>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut next = self.reader.next_async();
+        Pin::new(&mut next)
+            .poll(cx)
+            .map(|msg| msg.map(|msg| Ok(OwnedMessageInfo::copy_of(&msg))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file::MessageRead;
+    use crate::raft::*;
+    use crate::PersitEventStream;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use std::convert::TryInto;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_tail";
+    const TEST_PREFIX: &str = "tail";
+    const MESSAGES: u64 = 200;
+
+    struct NoProcessor;
+
+    impl InfallibleProcessor for NoProcessor {
+        fn handle<'a>(&mut self, _read: &MessageRead<'a>) {}
+    }
+
+    #[test]
+    pub fn tail_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        let store = Arc::new(startup_single_node(
+            TEST_DIR.to_owned(),
+            TEST_PREFIX.to_owned(),
+            0x10000,
+            0x10000,
+            NoProcessor,
+            0x40000,
+            0x4000,
+        ));
+        let writer = store.clone();
+        let writer_thread = thread::spawn(move || {
+            for i in 0..MESSAGES {
+                block_on(writer.add_change(&i.to_le_bytes())).unwrap();
+                if i % 10 == 0 {
+                    thread::sleep(Duration::from_millis(2));
+                }
+            }
+        });
+
+        let mut reader = store.tail_from(1);
+        let mut read = Vec::new();
+        while (read.len() as u64) < MESSAGES / 2 {
+            let msg = reader.wait_next(Duration::from_secs(10)).unwrap();
+            read.push(u64::from_le_bytes(msg.bytes()[..8].try_into().unwrap()));
+        }
+        while (read.len() as u64) < MESSAGES {
+            let msg = block_on(reader.next_async()).unwrap();
+            read.push(u64::from_le_bytes(msg.bytes()[..8].try_into().unwrap()));
+        }
+        writer_thread.join().unwrap();
+        assert_eq!((0..MESSAGES).collect::<Vec<u64>>(), read);
+        // Caught up so the wait times out.
+        assert!(reader.poll_next().is_none());
+        let started = Instant::now();
+        assert!(reader.wait_next(Duration::from_millis(20)).is_none());
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        drop(reader);
+        Arc::try_unwrap(store).ok().unwrap().stop();
+    }
+
+    #[test]
+    pub fn subscribe_test() {
+        let dir = format!("{}_subscribe", TEST_DIR);
+        let path = Path::new(&dir);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let store = Arc::new(startup_single_node(
+            dir.clone(),
+            TEST_PREFIX.to_owned(),
+            0x10000,
+            0x10000,
+            NoProcessor,
+            0x40000,
+            0x4000,
+        ));
+        let writer = store.clone();
+        let writer_thread = thread::spawn(move || {
+            for i in 0..MESSAGES {
+                block_on(writer.add_change(&i.to_le_bytes())).unwrap();
+                if i % 10 == 0 {
+                    thread::sleep(Duration::from_millis(2));
+                }
+            }
+        });
+
+        // Starts part of the way in.
+        let mut stream = store.subscribe(11);
+        let read: Vec<(u64, u64)> = block_on(async {
+            let mut read = Vec::new();
+            while (read.len() as u64) < MESSAGES - 10 {
+                let msg = stream.next().await.unwrap().unwrap();
+                let body = u64::from_le_bytes(msg.message_body[..8].try_into().unwrap());
+                read.push((msg.message_id, body));
+            }
+            read
+        });
+        writer_thread.join().unwrap();
+        assert_eq!(
+            (11..=MESSAGES)
+                .map(|id| (id, id - 1))
+                .collect::<Vec<(u64, u64)>>(),
+            read
+        );
+        // Dropping a pending stream doesn't hold up the writes.
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+        drop(stream);
+        assert_eq!(
+            MESSAGES + 1,
+            block_on(store.add_change(&MESSAGES.to_le_bytes())).unwrap()
+        );
+        Arc::try_unwrap(store).ok().unwrap().stop();
+    }
+}
@@ -0,0 +1,1152 @@
+//! Checks the newest event and commit files before a store starts taking writes.  The default is
+//! the fast open that trusts the files.  `Tail` walks the frames written after the last committed
+//! position and the last term, `Full` walks the whole newest pair of files.  A frame is checked for
+//! its shape and its checksum: the size has to fit in the file, the ids have to go up and the types
+//! below zero have to be ones the store writes.  The uncommitted frames are always checked when the
+//! store is opened so a message that was only partly written before a crash is cleared.
+//!
+//! ```text
+//!  newest event file
+//! +----------------------------+--------------------+-----------------+
+//! | committed messages         | written messages   | empty (zeros)   |
+//! +----------------------------+--------------------+-----------------+
+//! ^ Full starts here           ^ Tail starts here
+//!                              ^ repair only clears from here on
+//! ```
+//! `validate_files` is the maintenance check.  It walks every file in the directory without
+//! changing anything and reports each problem with the file and byte offset it is at, the terms
+//! also have to point at messages in the event files that are still there.
+use crate::error::{Context, PersistError, ResultExt};
+use crate::file::backend::MessageStoreBackend;
+use crate::file::chunk::CHUNK_MESSAGE_TYPE;
+use crate::file::header::{FileHeader, FileType, FILE_HEADER_SIZE};
+use crate::file::{
+    has_checksum, message_checksum, split_size, MessageFileStore, MessageFileStoreRead,
+    CHECKSUM_SIZE, HEADER_SIZE, MESSAGE_ID, MESSAGE_TYPE, USER_META_SIZE,
+};
+use crate::raft::snapshot::SNAPSHOT_MESSAGE_TYPE;
+use crate::raft::{
+    read_file_id, CommitFile, FlushPolicy, GroupCommit, COMMIT_FILE_POSTIX, COMMIT_SIZE,
+    END_OF_FILE_MESSAGE_TYPE, EVENT_FILE_POSTFIX, PRODUCER_MESSAGE_TYPE, TRANSACTION_MESSAGE_TYPE,
+};
+use a19_concurrent::buffer::align;
+use a19_concurrent::buffer::atomic_buffer::AtomicByteBuffer;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// How often the progress is reported while walking a file.
+const PROGRESS_INTERVAL: usize = 0x10_0000;
+
+/// How much of the newest files to check when the store is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VerifyLevel {
+    /// Trust the files.  The fastest open.
+    #[default]
+    None,
+    /// Check the last term and the frames written after the last committed position.
+    Tail,
+    /// Check every term in the newest commit file and every frame in the newest event file.
+    Full,
+}
+
+/// How far the check of a file has gotten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyProgress {
+    /// The id of the file being checked.
+    pub file_id: u32,
+    /// The position that has been checked up to.
+    pub position: usize,
+    /// The size of the file.
+    pub size: usize,
+}
+
+/// Called with the progress of a check since a full check can take a while.
+pub type ProgressCallback = Arc<dyn Fn(VerifyProgress) + Send + Sync>;
+
+/// The options for opening a store.
+#[derive(Clone, Default)]
+pub struct OpenOptions {
+    /// How much of the newest files to check.
+    pub verify_on_open: VerifyLevel,
+    /// Clears everything after a bad frame or term as long as it is after the committed
+    /// position.  The store still refuses to open when the committed messages are bad.
+    pub repair: bool,
+    /// Called as the check goes through the files.
+    pub progress: Option<ProgressCallback>,
+    /// Keeps an index next to each event file with an entry every this many messages.  `None`
+    /// doesn't write the index.
+    pub index_interval: Option<u64>,
+    /// Batches the written messages into one term.  `None` commits the messages as soon as they
+    /// are written.
+    pub group_commit: Option<GroupCommit>,
+    /// When the event files are flushed as the messages are committed.  `None` flushes on every
+    /// commit with a group commit and otherwise leaves it to the operating system.
+    pub flush_policy: Option<FlushPolicy>,
+}
+
+impl fmt::Debug for OpenOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenOptions")
+            .field("verify_on_open", &self.verify_on_open)
+            .field("repair", &self.repair)
+            .field("progress", &self.progress.is_some())
+            .field("index_interval", &self.index_interval)
+            .field("group_commit", &self.group_commit)
+            .field("flush_policy", &self.flush_policy)
+            .finish()
+    }
+}
+
+impl OpenOptions {
+    /// Sets how much of the files to check.
+    /// # Arguments
+    /// `level` - How much of the newest files to check.
+    pub fn with_verify(mut self, level: VerifyLevel) -> Self {
+        self.verify_on_open = level;
+        self
+    }
+
+    /// Sets if the uncommitted part of the files can be repaired.
+    /// # Arguments
+    /// `repair` - True to clear the bad frames after the committed position.
+    pub fn with_repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Sets the callback for the progress of the check.
+    /// # Arguments
+    /// `progress` - Called as the check goes through a file.
+    pub fn with_progress<F>(mut self, progress: F) -> Self
+    where
+        F: Fn(VerifyProgress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    fn report(&self, file_id: u32, position: usize, size: usize) {
+        if let Some(progress) = &self.progress {
+            progress(VerifyProgress {
+                file_id,
+                position,
+                size,
+            });
+        }
+    }
+}
+
+/// What a check found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of frames that were checked.
+    pub frames: u64,
+    /// The number of terms that were checked.
+    pub terms: u64,
+    /// The position in the event file everything was cleared from when it was repaired.
+    pub repaired_at: Option<usize>,
+}
+
+/// A problem `validate_files` found in a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileIssue {
+    /// Where the problem is in bytes from the start of the file including the header.
+    pub offset: usize,
+    /// What is wrong.
+    pub reason: String,
+}
+
+/// What `validate_files` found in a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileValidation {
+    /// If the file has the messages or the terms.
+    pub kind: FileType,
+    /// The path to the file.
+    pub path: String,
+    /// The id of the file from its name.
+    pub file_id: u32,
+    /// The number of frames or terms that were checked.
+    pub checked: u64,
+    /// The problems found in the file.
+    pub issues: Vec<FileIssue>,
+}
+
+impl FileValidation {
+    fn new(kind: FileType, path: &Path, file_id: u32) -> Self {
+        FileValidation {
+            kind,
+            path: path.to_string_lossy().into_owned(),
+            file_id,
+            checked: 0,
+            issues: Vec::new(),
+        }
+    }
+
+    fn add_issue(&mut self, offset: usize, reason: String) {
+        self.issues.push(FileIssue { offset, reason });
+    }
+}
+
+/// What `validate_files` found in a directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// The event files and then the commit files in the order of their ids.
+    pub files: Vec<FileValidation>,
+}
+
+impl ValidationReport {
+    /// True if none of the files have a problem.
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(|f| f.issues.is_empty())
+    }
+
+    /// The problems that were found with the file each one is in.
+    pub fn issues(&self) -> impl Iterator<Item = (&FileValidation, &FileIssue)> {
+        self.files
+            .iter()
+            .flat_map(|file| file.issues.iter().map(move |issue| (file, issue)))
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            let checked = match file.kind {
+                FileType::Commit => "terms",
+                _ => "frames",
+            };
+            if file.issues.is_empty() {
+                writeln!(f, "{}: ok, {} {} checked", file.path, file.checked, checked)?;
+            } else {
+                writeln!(
+                    f,
+                    "{}: {} issues, {} {} checked",
+                    file.path,
+                    file.issues.len(),
+                    file.checked,
+                    checked
+                )?;
+                for issue in &file.issues {
+                    writeln!(f, "  at byte {}: {}", issue.offset, issue.reason)?;
+                }
+            }
+        }
+        write!(
+            f,
+            "{} files checked, {} issues",
+            self.files.len(),
+            self.issues().count()
+        )
+    }
+}
+
+/// Where the committed messages end.
+struct Committed {
+    /// The id of the event file the last term is in.
+    file_id: u32,
+    /// The position after the last term.
+    position: usize,
+    /// The last committed message.
+    max_message_id: u64,
+}
+
+/// A problem found in a file.
+struct Bad {
+    position: usize,
+    reason: String,
+}
+
+/// Where a term points in the event files.
+#[derive(Debug, Clone, Copy)]
+struct TermRef {
+    /// The id of the term.
+    term_id: u64,
+    /// The id of the last message in the term.
+    max_message_id: u64,
+    /// The id of the event file the term starts in.
+    file_id: u32,
+    /// The position of the first message of the term in the event file.
+    position: u64,
+    /// The number of bytes the messages of the term take up.
+    length: u32,
+}
+
+impl TermRef {
+    /// Reads a term from a commit file.
+    /// # Arguments
+    /// `buffer` - The commit file.
+    /// `pos` - The position of the term.
+    fn read<B: CommitFile + ?Sized>(buffer: &B, pos: usize) -> Self {
+        TermRef {
+            term_id: buffer.term(pos),
+            max_message_id: buffer.max_message_id(pos),
+            file_id: buffer.file_id(pos),
+            position: buffer.file_position_offset(pos),
+            length: buffer.length_of_commit(pos),
+        }
+    }
+}
+
+/// Checks the newest event and commit files of a store.
+/// # Arguments
+/// `backend` - Where the files are kept.
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// `options` - How much to check and if the files can be repaired.
+/// # Returns
+/// What was checked or the first bad position that couldn't be repaired.
+pub fn verify_store(
+    backend: &dyn MessageStoreBackend,
+    file_storage_directory: &str,
+    file_prefix: &str,
+    options: &OpenOptions,
+) -> crate::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    if options.verify_on_open == VerifyLevel::None {
+        return Ok(report);
+    }
+    let files = match backend.list(Path::new(file_storage_directory)) {
+        Ok(files) => files,
+        // A new store doesn't have anything to check.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(report),
+        Err(e) => return Err(e).with_path(file_storage_directory),
+    };
+    let committed = match newest_file(&files, file_prefix, COMMIT_FILE_POSTIX) {
+        Some((file_id, path)) => verify_terms(backend, &path, file_id, options, &mut report)?,
+        None => None,
+    };
+    if let Some((file_id, path)) = newest_file(&files, file_prefix, EVENT_FILE_POSTFIX) {
+        let (start, last_id) = match &committed {
+            Some(c) if c.file_id == file_id => (c.position, c.max_message_id),
+            Some(c) if c.file_id > file_id => {
+                return Err(PersistError::CorruptTerm {
+                    context: Context {
+                        path: Some(path.to_string_lossy().into_owned()),
+                        position: None,
+                        id: Some(c.file_id as u64),
+                    },
+                    reason: "The last term is in an event file that doesn't exist.".to_owned(),
+                });
+            }
+            Some(c) => (0, c.max_message_id),
+            None => (0, 0),
+        };
+        let path_str = path.to_string_lossy().into_owned();
+        let reader = backend.open_readonly(&path).with_path(path_str.as_str())?;
+        let (from, last_id) = match options.verify_on_open {
+            VerifyLevel::Full => (0, 0),
+            _ => (start, last_id),
+        };
+        if let Err(bad) = verify_frames(
+            &reader,
+            file_id,
+            from,
+            usize::MAX,
+            last_id,
+            options,
+            &mut report,
+        ) {
+            if bad.position >= start && options.repair {
+                let (_, writer) = backend.open(&path).with_path(path_str.as_str())?;
+                writer.clear(bad.position, writer.capacity() - bad.position);
+                writer.flush().with_path(path_str.as_str())?;
+                log::warn!(
+                    "Repaired {} by clearing from {}: {}",
+                    path_str,
+                    bad.position,
+                    bad.reason
+                );
+                report.repaired_at = Some(bad.position);
+                options.report(file_id, writer.capacity(), writer.capacity());
+            } else {
+                return Err(PersistError::CorruptMessage {
+                    context: Context {
+                        path: Some(path_str),
+                        position: Some(bad.position),
+                        id: Some(file_id as u64),
+                    },
+                    reason: bad.reason,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Clears the messages that were only partly written when the process died.  The frames after the
+/// committed position are walked and everything from the first bad one is cleared.  None of them
+/// were committed so no one was told they were written.
+/// # Arguments
+/// `path` - The path to the newest event file.
+/// `start` - The position after the last committed message in the file.
+/// `last_id` - The id of the last committed message.
+/// # Returns
+/// The position the file was cleared from if there was a bad frame.
+pub(crate) fn truncate_torn_tail(
+    path: &str,
+    start: usize,
+    last_id: u64,
+) -> crate::file::Result<Option<usize>> {
+    let reader = unsafe { MessageFileStore::open_readonly(&path)? };
+    let mut report = VerifyReport::default();
+    match verify_frames(
+        &reader,
+        0,
+        start,
+        usize::MAX,
+        last_id,
+        &OpenOptions::default(),
+        &mut report,
+    ) {
+        Ok(_) => Ok(None),
+        Err(bad) => {
+            let writer = unsafe { MessageFileStore::open_write(&path, 0)? };
+            writer.clear(bad.position, writer.capacity() - bad.position);
+            writer.flush()?;
+            log::warn!(
+                "Cleared {} from {} after a partial write: {}",
+                path,
+                bad.position,
+                bad.reason
+            );
+            Ok(Some(bad.position))
+        }
+    }
+}
+
+/// An event file being validated.
+struct EventFile {
+    /// What was found in the file.
+    validation: FileValidation,
+    /// The reader for the file.  `None` if it couldn't be opened.
+    reader: Option<MessageFileStoreRead>,
+}
+
+/// Checks every event and commit file in a directory without changing them so it can be run
+/// against a store that is running.  Unlike `verify_store` a problem doesn't stop the check, it
+/// is added to the file it is in and the rest of the files are still checked.  The walk of an
+/// event file stops at its first bad frame since the frames after it can't be found.  Only the
+/// committed terms and the messages in them are checked, the ones after can still be being
+/// written.
+/// # Arguments
+/// `backend` - Where the files are kept.
+/// `file_storage_directory` - The directory the files are in.
+/// `file_prefix` - The prefix of the files.
+/// # Returns
+/// What was found in each file or an error if the directory can't be listed.
+pub fn validate_files(
+    backend: &dyn MessageStoreBackend,
+    file_storage_directory: &str,
+    file_prefix: &str,
+) -> crate::Result<ValidationReport> {
+    let files = backend
+        .list(Path::new(file_storage_directory))
+        .with_path(file_storage_directory)?;
+    let mut events = BTreeMap::new();
+    for (file_id, path) in files_of(&files, file_prefix, EVENT_FILE_POSTFIX) {
+        let mut validation = FileValidation::new(FileType::Event, &path, file_id);
+        let reader = match backend.open_readonly(&path) {
+            Ok(reader) => match header_issue(reader.buffer(), FileType::Event, file_id) {
+                Some(reason) => {
+                    validation.add_issue(0, reason);
+                    None
+                }
+                None => Some(reader),
+            },
+            Err(e) => {
+                validation.add_issue(0, e.to_string());
+                None
+            }
+        };
+        events.insert(file_id, EventFile { validation, reader });
+    }
+
+    let mut commits = Vec::new();
+    let mut previous = None;
+    for (file_id, path) in files_of(&files, file_prefix, COMMIT_FILE_POSTIX) {
+        let mut validation = FileValidation::new(FileType::Commit, &path, file_id);
+        match backend.open_readonly(&path) {
+            Ok(reader) => match header_issue(reader.buffer(), FileType::Commit, file_id) {
+                Some(reason) => validation.add_issue(0, reason),
+                None => validate_terms(reader.buffer(), &events, &mut previous, &mut validation),
+            },
+            Err(e) => validation.add_issue(0, e.to_string()),
+        }
+        commits.push(validation);
+    }
+
+    // The messages after the last committed term can still be being written.
+    let committed = previous.map(|t: TermRef| (t.file_id, (t.position + t.length as u64) as usize));
+    let mut last_id = 0;
+    for (file_id, event) in events.iter_mut() {
+        let reader = match &event.reader {
+            Some(reader) => reader,
+            None => continue,
+        };
+        let end = match committed {
+            Some((committed_file, end)) if *file_id == committed_file => end,
+            Some((committed_file, _)) if *file_id < committed_file => usize::MAX,
+            _ => 0,
+        };
+        // A file can start with the rest of a message split into chunks at the end of the file
+        // before it so it has the same id.
+        let start_id = match reader.read_new(0) {
+            Ok(msg)
+                if msg.msg_type_id() == CHUNK_MESSAGE_TYPE
+                    && last_id > 0
+                    && msg.message_id() == last_id =>
+            {
+                last_id - 1
+            }
+            _ => last_id,
+        };
+        let mut report = VerifyReport::default();
+        match verify_frames(
+            reader,
+            *file_id,
+            0,
+            end,
+            start_id,
+            &OpenOptions::default(),
+            &mut report,
+        ) {
+            Ok(message_id) => last_id = message_id,
+            Err(bad) => event
+                .validation
+                .add_issue(FILE_HEADER_SIZE + bad.position, bad.reason),
+        }
+        event.validation.checked = report.frames;
+    }
+    Ok(ValidationReport {
+        files: events
+            .into_values()
+            .map(|event| event.validation)
+            .chain(commits)
+            .collect(),
+    })
+}
+
+/// Checks the header of a file is for the kind of file and the id in its name.
+/// # Arguments
+/// `buffer` - The buffer of the file.
+/// `kind` - What should be kept in the file.
+/// `file_id` - The id from the name of the file.
+/// # Returns
+/// What is wrong with the header.
+fn header_issue(buffer: &dyn AtomicByteBuffer, kind: FileType, file_id: u32) -> Option<String> {
+    match FileHeader::read(buffer).and_then(|header| header.expect(kind)) {
+        Ok(header) if header.file_id == file_id => None,
+        Ok(header) => Some(format!(
+            "The header is for the file {} instead of {}.",
+            header.file_id, file_id
+        )),
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+/// Checks the committed terms in a commit file and that they point at messages in the event
+/// files.
+/// # Arguments
+/// `buffer` - The commit file.
+/// `events` - The event files in the directory.
+/// `previous` - The last term in the commit files before it.  Moved to the last term checked.
+/// `validation` - Where to add the problems.
+fn validate_terms(
+    buffer: &dyn AtomicByteBuffer,
+    events: &BTreeMap<u32, EventFile>,
+    previous: &mut Option<TermRef>,
+    validation: &mut FileValidation,
+) {
+    let size = buffer.capacity() - FILE_HEADER_SIZE;
+    let term_size = COMMIT_SIZE as usize;
+    if !size.is_multiple_of(term_size) {
+        validation.add_issue(
+            FILE_HEADER_SIZE + size / term_size * term_size,
+            format!("The file ends {} bytes into a term.", size % term_size),
+        );
+    }
+    let mut pos = 0;
+    // A term that isn't committed yet can still be being written.
+    while pos + term_size <= size && buffer.term(pos) != 0 && buffer.committed(pos) != 0 {
+        let offset = FILE_HEADER_SIZE + pos;
+        let term = TermRef::read(buffer, pos);
+        let valid = buffer.verify(pos);
+        pos += term_size;
+        if !valid {
+            validation.add_issue(offset, "The term doesn't match its checksum.".to_owned());
+            continue;
+        }
+        if let Some(before) = previous {
+            if !follows(before, &term) {
+                validation.add_issue(
+                    offset,
+                    format!(
+                        "The term {} doesn't follow the term {}.",
+                        term.term_id, before.term_id
+                    ),
+                );
+            }
+        }
+        if let Some(reason) = dangling(&term, events) {
+            validation.add_issue(offset, reason);
+        }
+        *previous = Some(term);
+        validation.checked += 1;
+    }
+}
+
+/// Checks a term points at the messages in an event file.  A term in an event file before the
+/// oldest one was removed by the retention.
+/// # Arguments
+/// `term` - The term to check.
+/// `events` - The event files in the directory.
+/// # Returns
+/// What is wrong with where the term points.
+fn dangling(term: &TermRef, events: &BTreeMap<u32, EventFile>) -> Option<String> {
+    let reader = match events.get(&term.file_id) {
+        Some(EventFile {
+            reader: Some(reader),
+            ..
+        }) => reader,
+        Some(_) => {
+            return Some(format!(
+                "The event file {} the term {} is in can't be read.",
+                term.file_id, term.term_id
+            ))
+        }
+        None => {
+            return match events.keys().next() {
+                Some(oldest) if term.file_id > 0 && term.file_id < *oldest => None,
+                _ => Some(format!(
+                    "The term {} is in the event file {} which doesn't exist.",
+                    term.term_id, term.file_id
+                )),
+            }
+        }
+    };
+    let end = term.position as usize + term.length as usize;
+    if end > reader.capacity() {
+        return Some(format!(
+            "The term {} ends at {} which is past the end of the event file {}.",
+            term.term_id, end, term.file_id
+        ));
+    }
+    match reader.read_link(term.position as usize) {
+        Ok((message_id, _)) if message_id > 0 && message_id <= term.max_message_id => None,
+        _ => Some(format!(
+            "The term {} doesn't start on one of its messages at {} in the event file {}.",
+            term.term_id, term.position, term.file_id
+        )),
+    }
+}
+
+/// Finds the file with the largest id.
+/// # Arguments
+/// `files` - The files in the directory.
+/// `file_prefix` - The prefix of the files.
+/// `postfix` - The kind of file to look for.
+fn newest_file(files: &[PathBuf], file_prefix: &str, postfix: &str) -> Option<(u32, PathBuf)> {
+    files_of(files, file_prefix, postfix).pop()
+}
+
+/// Finds the files of a kind.
+/// # Arguments
+/// `files` - The files in the directory.
+/// `file_prefix` - The prefix of the files.
+/// `postfix` - The kind of file to look for.
+/// # Returns
+/// The ids and paths of the files in the order of their ids.
+fn files_of(files: &[PathBuf], file_prefix: &str, postfix: &str) -> Vec<(u32, PathBuf)> {
+    let starts_with = format!("{}.{}.", file_prefix, postfix);
+    let mut found: Vec<(u32, PathBuf)> = files
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            if name.starts_with(&starts_with) {
+                read_file_id(file_prefix, name).map(|id| (id, path.clone()))
+            } else {
+                None
+            }
+        })
+        .collect();
+    found.sort_by_key(|(id, _)| *id);
+    found
+}
+
+/// Checks the terms in a commit file.  A bad last term is cleared when repairing since it hasn't
+/// been used yet, a bad term before it means the committed messages can't be trusted.
+/// # Arguments
+/// `backend` - Where the files are kept.
+/// `path` - The path of the commit file.
+/// `file_id` - The id of the commit file.
+/// `options` - How much to check.
+/// `report` - Where to count the terms.
+/// # Returns
+/// Where the committed messages end or `None` if there aren't any terms.
+fn verify_terms(
+    backend: &dyn MessageStoreBackend,
+    path: &Path,
+    file_id: u32,
+    options: &OpenOptions,
+    report: &mut VerifyReport,
+) -> crate::Result<Option<Committed>> {
+    let path_str = path.to_string_lossy().into_owned();
+    let reader = backend.open_readonly(path).with_path(path_str.as_str())?;
+    let buffer = reader.buffer();
+    let size = buffer.capacity() - FILE_HEADER_SIZE;
+    let term_size = COMMIT_SIZE as usize;
+    let mut count = 0;
+    while (count + 1) * term_size <= size && buffer.term(count * term_size) != 0 {
+        count += 1;
+    }
+    if count == 0 {
+        return Ok(None);
+    }
+    let first = match options.verify_on_open {
+        VerifyLevel::Full => 0,
+        _ => count - 1,
+    };
+    let mut checked = first;
+    for index in first..count {
+        let pos = index * term_size;
+        let bad = if !buffer.verify(pos) {
+            Some("The term doesn't match its checksum.")
+        } else if buffer.committed(pos) == 0 {
+            Some("The term isn't committed.")
+        } else if index > 0
+            && !follows(
+                &TermRef::read(buffer, pos - term_size),
+                &TermRef::read(buffer, pos),
+            )
+        {
+            Some("The term doesn't follow the term before it.")
+        } else {
+            None
+        };
+        if let Some(reason) = bad {
+            if index == count - 1 && options.repair {
+                let (_, writer) = backend.open(path).with_path(path_str.as_str())?;
+                writer.clear(pos, term_size);
+                writer.flush().with_path(path_str.as_str())?;
+                log::warn!("Cleared the last term in {}: {}", path_str, reason);
+                break;
+            }
+            return Err(PersistError::CorruptTerm {
+                context: Context {
+                    path: Some(path_str),
+                    position: Some(pos),
+                    id: Some(buffer.term(pos)),
+                },
+                reason: reason.to_owned(),
+            });
+        }
+        report.terms += 1;
+        checked = index + 1;
+    }
+    options.report(file_id, count * term_size, size);
+    if checked == 0 {
+        Ok(None)
+    } else {
+        let pos = (checked - 1) * term_size;
+        Ok(Some(Committed {
+            file_id: buffer.file_id(pos),
+            position: (buffer.file_position_offset(pos) + buffer.length_of_commit(pos) as u64)
+                as usize,
+            max_message_id: buffer.max_message_id(pos),
+        }))
+    }
+}
+
+/// Checks to see if a term carries on from the term before it.
+/// # Arguments
+/// `previous` - The term before.
+/// `term` - The term to check.
+fn follows(previous: &TermRef, term: &TermRef) -> bool {
+    term.term_id == previous.term_id + 1
+        && term.max_message_id >= previous.max_message_id
+        && (term.file_id, term.position) >= (previous.file_id, previous.position)
+}
+
+/// Walks the frames in an event file.
+/// # Arguments
+/// `reader` - The event file.
+/// `file_id` - The id of the event file.
+/// `start` - The position of the first frame to check.
+/// `end` - The position to stop at.  The frames starting after it aren't checked.
+/// `last_id` - The id of the message before the first frame.
+/// `options` - Where to report the progress.
+/// `report` - Where to count the frames.
+/// # Returns
+/// The id of the last message checked or the first bad position in the file.
+fn verify_frames(
+    reader: &MessageFileStoreRead,
+    file_id: u32,
+    start: usize,
+    end: usize,
+    last_id: u64,
+    options: &OpenOptions,
+    report: &mut VerifyReport,
+) -> Result<u64, Bad> {
+    // The buffer includes the file header so the positions read from it are moved past it.
+    let buffer = reader.buffer();
+    let size = buffer.capacity() - FILE_HEADER_SIZE;
+    let mut pos = start;
+    let mut last_id = last_id;
+    let mut last_type = 0;
+    let mut next_report = start + PROGRESS_INTERVAL;
+    options.report(file_id, start, size);
+    while pos < end && !reader.is_end(pos) {
+        let raw = buffer.get_u32(FILE_HEADER_SIZE + pos);
+        if raw == 0 {
+            // Nothing has been written past here so the rest should be empty.
+            return match buffer
+                .get_bytes(FILE_HEADER_SIZE + pos, size - pos)
+                .iter()
+                .position(|b| *b != 0)
+            {
+                Some(offset) => Err(Bad {
+                    position: (pos + offset) / HEADER_SIZE * HEADER_SIZE,
+                    reason: "There are bytes after the last message.".to_owned(),
+                }),
+                None => {
+                    options.report(file_id, size, size);
+                    Ok(last_id)
+                }
+            };
+        }
+        let (length, has_meta) = split_size(raw);
+        let aligned = align(length, HEADER_SIZE);
+        if length < HEADER_SIZE || aligned > size - pos {
+            return Err(Bad {
+                position: pos,
+                reason: format!("The size {} doesn't fit in the file.", length),
+            });
+        }
+        let msg_type = buffer.get_i32(FILE_HEADER_SIZE + pos + MESSAGE_TYPE);
+        let message_id = buffer.get_u64(FILE_HEADER_SIZE + pos + MESSAGE_ID);
+        if msg_type == END_OF_FILE_MESSAGE_TYPE {
+            break;
+        }
+        if msg_type < 0
+            && msg_type != TRANSACTION_MESSAGE_TYPE
+            && msg_type != PRODUCER_MESSAGE_TYPE
+            && msg_type != CHUNK_MESSAGE_TYPE
+            && msg_type != SNAPSHOT_MESSAGE_TYPE
+        {
+            return Err(Bad {
+                position: pos,
+                reason: format!("The message type {} isn't known.", msg_type),
+            });
+        }
+        if has_checksum(raw) {
+            let checksum_pos = FILE_HEADER_SIZE + pos + HEADER_SIZE;
+            let meta_size = if has_meta { USER_META_SIZE } else { 0 };
+            let body = checksum_pos + CHECKSUM_SIZE + meta_size;
+            let end = FILE_HEADER_SIZE + pos + length;
+            if body > end {
+                return Err(Bad {
+                    position: pos,
+                    reason: format!("The size {} is too small for the checksum.", length),
+                });
+            }
+            let meta = if has_meta {
+                Some(buffer.get_bytes(checksum_pos + CHECKSUM_SIZE, USER_META_SIZE))
+            } else {
+                None
+            };
+            let checksum = message_checksum(
+                msg_type,
+                message_id,
+                meta,
+                buffer.get_bytes(body, end - body),
+            );
+            if buffer.get_u32(checksum_pos) != checksum {
+                return Err(Bad {
+                    position: pos,
+                    reason: format!("Message {} doesn't match its checksum.", message_id),
+                });
+            }
+        }
+        let same_chunk = msg_type == CHUNK_MESSAGE_TYPE
+            && last_type == CHUNK_MESSAGE_TYPE
+            && message_id == last_id;
+        if !same_chunk && message_id <= last_id {
+            return Err(Bad {
+                position: pos,
+                reason: format!("The message id {} isn't after {}.", message_id, last_id),
+            });
+        }
+        last_id = message_id;
+        last_type = msg_type;
+        report.frames += 1;
+        pos += aligned;
+        if pos >= next_report {
+            options.report(file_id, pos, size);
+            next_report = pos + PROGRESS_INTERVAL;
+        }
+    }
+    options.report(file_id, size, size);
+    Ok(last_id)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::error::PersistError;
+    use crate::file::backend::FileBackend;
+    use crate::file::header::open_commit_file;
+    use crate::file::MessageFileStore;
+    use crate::file::{CHECKSUM_SIZE, HEADER_SIZE};
+    use crate::raft::builder::PersistedMessageFileBuilder;
+    use crate::raft::verify::*;
+    use crate::raft::{
+        create_event_name, create_term_file, MessageProcessor, MessageRead, ProcessError,
+        TermCommit,
+    };
+    use futures::executor::block_on;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::sync::Mutex;
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_verify";
+    const TEST_PREFIX: &str = "verify";
+
+    /// Writes an event file with the ids and commits the first 3 messages.
+    fn create_store(name: &str, ids: &[u64]) -> String {
+        let file_storage_directory = format!("{}_{}", TEST_DIR, name);
+        let path = Path::new(&file_storage_directory);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        create_dir_all(&file_storage_directory).unwrap();
+        let file_path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+        let (_, writer) = unsafe { MessageFileStore::new(&file_path, 1024).unwrap() };
+        let mut pos = 0;
+        for message_id in ids {
+            pos = writer
+                .write(pos, 1, *message_id, &message_id.to_le_bytes())
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        let mut term_file = create_term_file(&file_storage_directory, TEST_PREFIX, 1, 1, 1024);
+        term_file.buffer.save_term(
+            0,
+            &TermCommit {
+                term_id: 1,
+                version: 1,
+                type_id: 1,
+                server_id: 1,
+                leader_id: 1,
+                committed: 1,
+                timestamp: 1_000,
+                committed_timestamp: 1_000,
+                file_id: 1,
+                file_position_offset: 0,
+                file_max_message_id: 3,
+                length: 96,
+            },
+        );
+        term_file.buffer.flush().unwrap();
+        file_storage_directory
+    }
+
+    #[test]
+    pub fn tail_repair_test() {
+        // The 5th frame has an id that goes backwards after the committed messages.
+        let file_storage_directory = create_store("tail", &[1, 2, 3, 4, 2, 6]);
+        let options = OpenOptions::default().with_verify(VerifyLevel::Tail);
+        match verify_store(&FileBackend, &file_storage_directory, TEST_PREFIX, &options) {
+            Err(PersistError::CorruptMessage { context, .. }) => {
+                assert_eq!(Some(128), context.position);
+            }
+            _ => panic!("The bad frame wasn't found"),
+        }
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let seen = progress.clone();
+        let options = options
+            .with_repair(true)
+            .with_progress(move |p| seen.lock().unwrap().push(p));
+        let report =
+            verify_store(&FileBackend, &file_storage_directory, TEST_PREFIX, &options).unwrap();
+        assert_eq!(Some(128), report.repaired_at);
+        assert_eq!(1, report.terms);
+        assert_eq!(1, report.frames);
+        assert_eq!(1024, progress.lock().unwrap().last().unwrap().position);
+
+        let path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+        let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+        assert_eq!(4, reader.read_new(96).unwrap().message_id());
+        assert!(reader.read_new(128).is_err());
+        let report =
+            verify_store(&FileBackend, &file_storage_directory, TEST_PREFIX, &options).unwrap();
+        assert_eq!(None, report.repaired_at);
+    }
+
+    #[test]
+    pub fn full_refuses_committed_test() {
+        // The 2nd frame has the wrong id so the 3rd is out of order and both are committed.
+        let file_storage_directory = create_store("full", &[1, 9, 3, 4]);
+        let options = OpenOptions::default()
+            .with_verify(VerifyLevel::Full)
+            .with_repair(true);
+        match verify_store(&FileBackend, &file_storage_directory, TEST_PREFIX, &options) {
+            Err(PersistError::CorruptMessage { context, .. }) => {
+                assert_eq!(Some(64), context.position);
+                assert_eq!(Some(1), context.id);
+            }
+            _ => panic!("The bad frame wasn't found"),
+        }
+        let path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+        let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+        assert_eq!(4, reader.read_new(96).unwrap().message_id());
+
+        // The tail check doesn't look at the committed messages.
+        let options = options.with_verify(VerifyLevel::Tail);
+        let report =
+            verify_store(&FileBackend, &file_storage_directory, TEST_PREFIX, &options).unwrap();
+        assert_eq!(1, report.frames);
+    }
+
+    #[test]
+    pub fn checksum_repair_test() {
+        let file_storage_directory = create_store("checksum", &[1, 2, 3, 4, 5]);
+        // Flip a byte in the body of the 5th frame.
+        let path = create_event_name(&file_storage_directory, TEST_PREFIX, &1);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[FILE_HEADER_SIZE + 128 + HEADER_SIZE + CHECKSUM_SIZE] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let options = OpenOptions::default().with_verify(VerifyLevel::Tail);
+        match verify_store(&FileBackend, &file_storage_directory, TEST_PREFIX, &options) {
+            Err(PersistError::CorruptMessage { context, reason }) => {
+                assert_eq!(Some(128), context.position);
+                assert_eq!("Message 5 doesn't match its checksum.", reason);
+            }
+            _ => panic!("The bad checksum wasn't found"),
+        }
+        let report = verify_store(
+            &FileBackend,
+            &file_storage_directory,
+            TEST_PREFIX,
+            &options.with_repair(true),
+        )
+        .unwrap();
+        assert_eq!(Some(128), report.repaired_at);
+        assert_eq!(1, report.frames);
+    }
+
+    struct NoOp;
+
+    impl MessageProcessor for NoOp {
+        fn handle(&mut self, _read: MessageRead<'_>) -> Result<(), ProcessError> {
+            Ok(())
+        }
+    }
+
+    /// Counts the frames and terms that were checked.
+    fn checked(report: &ValidationReport, kind: FileType) -> u64 {
+        report
+            .files
+            .iter()
+            .filter(|f| f.kind == kind)
+            .map(|f| f.checked)
+            .sum()
+    }
+
+    #[test]
+    pub fn validate_files_test() {
+        let file_storage_directory = format!("{}_validate", TEST_DIR);
+        if Path::new(&file_storage_directory).exists() {
+            remove_dir_all(&file_storage_directory).unwrap();
+        }
+        let store = PersistedMessageFileBuilder::new(&file_storage_directory, TEST_PREFIX)
+            .max_file_size(0x400)
+            .commit_file_size(COMMIT_SIZE as usize * 4)
+            .build(NoOp)
+            .unwrap();
+        for i in 1..=100u64 {
+            block_on(store.write(1, &i.to_le_bytes())).unwrap().unwrap();
+        }
+        store.close().unwrap();
+
+        let report = validate_files(&FileBackend, &file_storage_directory, TEST_PREFIX).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert!(
+            report
+                .files
+                .iter()
+                .filter(|f| f.kind == FileType::Commit)
+                .count()
+                > 1
+        );
+        assert_eq!(100, checked(&report, FileType::Event));
+        assert!(checked(&report, FileType::Commit) > 0);
+        assert!(report.to_string().ends_with(" 0 issues"));
+
+        // Flip a byte in the body of the 2nd message in the 2nd event file.
+        let path = create_event_name(&file_storage_directory, TEST_PREFIX, &2);
+        let (position, message_id) = {
+            let reader = unsafe { MessageFileStore::open_readonly(&path).unwrap() };
+            let next = reader.read_new(0).unwrap().next_pos();
+            (next, reader.read_new(next).unwrap().message_id())
+        };
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[FILE_HEADER_SIZE + position + HEADER_SIZE + CHECKSUM_SIZE] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+
+        // Point the last term at an event file that doesn't exist.
+        let commit = report
+            .files
+            .iter()
+            .rfind(|f| f.kind == FileType::Commit)
+            .unwrap();
+        let term_pos = (commit.checked as usize - 1) * COMMIT_SIZE as usize;
+        let term_id = {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&commit.path)
+                .unwrap();
+            let mut buffer = unsafe { open_commit_file(file).unwrap() };
+            buffer.set_file_id(term_pos, 999).set_checksum(term_pos);
+            buffer.flush().unwrap();
+            buffer.term(term_pos)
+        };
+
+        let report = validate_files(&FileBackend, &file_storage_directory, TEST_PREFIX).unwrap();
+        assert!(!report.is_ok());
+        let issues: Vec<(String, FileIssue)> = report
+            .issues()
+            .map(|(file, issue)| (file.path.clone(), issue.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    path,
+                    FileIssue {
+                        offset: FILE_HEADER_SIZE + position,
+                        reason: format!("Message {} doesn't match its checksum.", message_id),
+                    }
+                ),
+                (
+                    commit.path.clone(),
+                    FileIssue {
+                        offset: FILE_HEADER_SIZE + term_pos,
+                        reason: format!(
+                            "The term {} is in the event file 999 which doesn't exist.",
+                            term_id
+                        ),
+                    }
+                ),
+            ],
+            issues
+        );
+        let text = report.to_string();
+        assert!(text.contains(&format!("  at byte {}: ", FILE_HEADER_SIZE + position)));
+        assert!(text.ends_with(" 2 issues"));
+    }
+}
@@ -74,10 +74,12 @@ impl MessageWriteCollection {
                     Some(p) => match p.to_str() {
                         Some(p) => {
                             if p.starts_with(&starts_with_commit) {
-                                match read_file_id(p) {
+                                match read_file_id(file_prefix, p) {
                                     Some(id) => {
-                                        let message_file =
-                                            get_message_file_info(path.to_str().unwrap())?;
+                                        let message_file = get_message_file_info(
+                                            file_prefix,
+                                            path.to_str().unwrap(),
+                                        )?;
                                         collection.insert(id, Rc::new(message_file));
                                     }
                                     _ => {}
@@ -280,6 +282,7 @@ fn new_message_file(
             path: path.to_string(),
             file_id,
             message_id_start: message_id,
+            removed: false,
         },
         writer,
     ))
@@ -287,9 +290,14 @@ fn new_message_file(
 
 /// Gets the information about the file.
 /// # Arguments
+/// `file_prefix` - The prefix of the files.
 /// `path` - The path of the file to get the information from.
-fn get_message_file_info(path: &str) -> crate::file::Result<MessageFileInfo> {
-    match read_file_id(&path) {
+fn get_message_file_info(file_prefix: &str, path: &str) -> crate::file::Result<MessageFileInfo> {
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    match read_file_id(file_prefix, name) {
         Some(id) => {
             let (read, _) = unsafe { MessageFileStore::open(&path)? };
             let mut msg_id: u64 = 0;
@@ -302,6 +310,7 @@ fn get_message_file_info(path: &str) -> crate::file::Result<MessageFileInfo> {
                 path: path.to_owned(),
                 file_id: id,
                 message_id_start: msg_id,
+                removed: false,
             })
         }
         _ => Err(crate::file::Error::InvalidFile),
@@ -375,7 +384,7 @@ mod test {
             r.flush().unwrap();
         }
         let path = create_commit_name(FILE_STORAGE_DIRECTORY, FILE_PREFIX, &1);
-        let file_info = get_message_file_info(&path).unwrap();
+        let file_info = get_message_file_info(FILE_PREFIX, &path).unwrap();
         assert_eq!(file_info.file_id, 1);
         assert_eq!(file_info.message_id_start, 1);
         assert_eq!(path, file_info.path);
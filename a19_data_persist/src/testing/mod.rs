@@ -0,0 +1,433 @@
+//! Helpers for testing how the stores recover from a crash.  The writes to a file go through a
+//! `FaultyStore` that records them along with the flushes.  A crash is simulated by working out
+//! which of the bytes made it to disk and writing them out to a directory so the recovery can be
+//! run against them.  Enable the `testing` feature to use them from another crate.
+//!```text
+//! flushed bytes   - Always survive the crash.
+//! write 1         \
+//! write 2          > Written since the last flush, survive depending on the crash.
+//! write 3         /
+//!```
+//! A write is made up of the stores in the order the buffer makes them.  For a message the header
+//! and body are stored before the size so a torn write never has a size without its body.  The
+//! crashes are picked with a seeded rng so a failure can be repeated with the same seed.
+//!
+//! `pipeline` steps the writer, the commit and the reader of a store on a single thread so the
+//! orders they can run in can be checked one at a time.
+pub mod pipeline;
+
+use crate::file;
+use crate::file::header::FILE_HEADER_SIZE;
+use crate::file::{
+    message_checksum, MessageFileStoreWrite, CHECKSUM_FLAG, CHECKSUM_SIZE, HEADER_SIZE, MESSAGE_ID,
+    MESSAGE_SIZE, MESSAGE_TYPE,
+};
+use crate::raft::flusher::Flusher;
+use a19_concurrent::buffer::mmap_buffer::MemoryMappedInt;
+use a19_concurrent::buffer::DirectByteBuffer;
+use byteorder::{BigEndian, ByteOrder};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The size of a page that is lost by `Crash::ZeroPage`.
+pub const PAGE_SIZE: usize = 4096;
+/// The largest store that is never torn.  A store of a word or less is done by a single
+/// instruction.
+const ATOMIC_STORE_SIZE: usize = 8;
+/// The environment variable to set to repeat the crashes for a seed.
+const SEED_VAR: &str = "A19_CRASH_SEED";
+/// The size of the block used to mark a file as full.
+const FULL_BLOCK_SIZE: usize = 1000;
+
+/// How the process died.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crash {
+    /// Nothing written since the last flush made it to disk.
+    LoseUnflushed,
+    /// Only the first writes since the last flush made it to disk.
+    KeepWrites(usize),
+    /// The writes since the last flush made it to disk except the last one which is cut off at a
+    /// random byte.
+    TornLastWrite,
+    /// Everything made it to disk except the page which is zeroed.
+    ZeroPage(usize),
+}
+
+/// A single store into the file.
+#[derive(Debug, Clone)]
+struct Store {
+    position: usize,
+    bytes: Vec<u8>,
+}
+
+impl Store {
+    fn new(position: usize, bytes: Vec<u8>) -> Self {
+        Store { position, bytes }
+    }
+
+    /// Copies the store into the bytes of a file.
+    /// # Arguments
+    /// `image` - The bytes of the file.
+    /// `length` - The number of bytes of the store to copy.
+    fn apply(&self, image: &mut [u8], length: usize) {
+        let end = (self.position + length).min(image.len());
+        if self.position < end {
+            image[self.position..end].copy_from_slice(&self.bytes[..end - self.position]);
+        }
+    }
+}
+
+/// Wraps the buffer of a file and records the writes and flushes to it.
+pub struct FaultyStore<T> {
+    inner: T,
+    path: PathBuf,
+    /// The bytes on disk as of the last flush.
+    flushed: Vec<u8>,
+    /// The writes since the last flush.  Each write is the stores in the order they were made.
+    unflushed: Vec<Vec<Store>>,
+    writes: usize,
+    flushes: usize,
+}
+
+impl<T> FaultyStore<T> {
+    /// Wraps the buffer for a file.  What is in the file now is taken as being on disk.
+    /// # Arguments
+    /// `path` - The path of the file the buffer is for.
+    /// `inner` - The buffer to wrap.
+    pub fn wrap<P: AsRef<Path>>(path: &P, inner: T) -> io::Result<Self> {
+        let flushed = fs::read(path)?;
+        Ok(FaultyStore {
+            inner,
+            path: path.as_ref().to_path_buf(),
+            flushed,
+            unflushed: Vec::new(),
+            writes: 0,
+            flushes: 0,
+        })
+    }
+
+    /// The wrapped buffer.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// The number of writes made to the file.
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// The number of times the file has been flushed.
+    pub fn flushes(&self) -> usize {
+        self.flushes
+    }
+
+    /// The number of writes since the last flush.
+    pub fn unflushed_writes(&self) -> usize {
+        self.unflushed.len()
+    }
+
+    fn record(&mut self, stores: Vec<Store>) {
+        self.writes += 1;
+        self.unflushed.push(stores);
+    }
+
+    fn record_flush(&mut self) {
+        self.flushes += 1;
+        for stores in self.unflushed.drain(..) {
+            for store in stores.iter() {
+                store.apply(&mut self.flushed, store.bytes.len());
+            }
+        }
+    }
+
+    /// Works out the bytes that are on disk after a crash.
+    /// # Arguments
+    /// `crash` - How the process died.
+    /// `rng` - Used to pick where a write is torn.
+    /// # Returns
+    /// The bytes of the file.
+    pub fn surviving_bytes<R: Rng>(&self, crash: Crash, rng: &mut R) -> Vec<u8> {
+        let mut image = self.flushed.clone();
+        let kept = match crash {
+            Crash::LoseUnflushed => 0,
+            Crash::KeepWrites(writes) => writes.min(self.unflushed.len()),
+            Crash::TornLastWrite => self.unflushed.len().saturating_sub(1),
+            Crash::ZeroPage(_) => self.unflushed.len(),
+        };
+        for stores in self.unflushed[..kept].iter() {
+            for store in stores.iter() {
+                store.apply(&mut image, store.bytes.len());
+            }
+        }
+        match crash {
+            Crash::TornLastWrite => {
+                if let Some(stores) = self.unflushed.last() {
+                    let total: usize = stores.iter().map(|s| s.bytes.len()).sum();
+                    if total > 0 {
+                        let mut remaining = rng.gen_range(0, total);
+                        for store in stores.iter() {
+                            let length = store.bytes.len();
+                            if remaining >= length {
+                                store.apply(&mut image, length);
+                                remaining -= length;
+                            } else {
+                                if length > ATOMIC_STORE_SIZE {
+                                    store.apply(&mut image, remaining);
+                                }
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            Crash::ZeroPage(page) => {
+                let start = (page * PAGE_SIZE).min(image.len());
+                let end = (start + PAGE_SIZE).min(image.len());
+                for b in image[start..end].iter_mut() {
+                    *b = 0;
+                }
+            }
+            _ => {}
+        }
+        image
+    }
+
+    /// Writes out the bytes that are on disk after a crash so the recovery can be run on them.
+    /// # Arguments
+    /// `directory` - The directory to write the file to.  The file has the same name as the one
+    /// being wrapped.
+    /// `crash` - How the process died.
+    /// `rng` - Used to pick where a write is torn.
+    /// # Returns
+    /// The path to the file that was written.
+    pub fn materialize<P: AsRef<Path>, R: Rng>(
+        &self,
+        directory: &P,
+        crash: Crash,
+        rng: &mut R,
+    ) -> io::Result<PathBuf> {
+        fs::create_dir_all(directory)?;
+        let name = self
+            .path
+            .file_name()
+            .ok_or_else(|| io::Error::other("The path doesn't have a file name."))?;
+        let path = directory.as_ref().join(name);
+        fs::write(&path, self.surviving_bytes(crash, rng))?;
+        Ok(path)
+    }
+}
+
+impl FaultyStore<MessageFileStoreWrite> {
+    /// Writes a message to the file.
+    /// # Arguments
+    /// `position` - The position to write the message to.
+    /// `msg_type_id` - The type of the message.
+    /// `message_id` - The id of the message.
+    /// `buffer` - The body of the message.
+    /// # Returns
+    /// The position of the next message.
+    pub fn write(
+        &mut self,
+        position: usize,
+        msg_type_id: i32,
+        message_id: u64,
+        buffer: &[u8],
+    ) -> file::Result<usize> {
+        let capacity = self.inner.capacity();
+        match self.inner.write(position, msg_type_id, message_id, buffer) {
+            Ok(next) => {
+                // The messages are after the header of the file.
+                let position = FILE_HEADER_SIZE + position;
+                let mut header = vec![0; HEADER_SIZE - MESSAGE_TYPE];
+                BigEndian::write_i32(&mut header[..MESSAGE_ID - MESSAGE_TYPE], msg_type_id);
+                BigEndian::write_u64(&mut header[MESSAGE_ID - MESSAGE_TYPE..], message_id);
+                let mut body = vec![0; CHECKSUM_SIZE];
+                BigEndian::write_u32(
+                    &mut body,
+                    message_checksum(msg_type_id, message_id, None, buffer),
+                );
+                body.extend_from_slice(buffer);
+                let mut size = vec![0; MESSAGE_TYPE - MESSAGE_SIZE];
+                BigEndian::write_u32(
+                    &mut size,
+                    (HEADER_SIZE + CHECKSUM_SIZE + buffer.len()) as u32 | CHECKSUM_FLAG,
+                );
+                self.record(vec![
+                    Store::new(position + MESSAGE_TYPE, header),
+                    Store::new(position + HEADER_SIZE, body),
+                    Store::new(position + MESSAGE_SIZE, size),
+                ]);
+                Ok(next)
+            }
+            Err(file::Error::Full) => {
+                // The rest of the file is filled in to mark it as full.
+                let stores = (position..capacity)
+                    .step_by(FULL_BLOCK_SIZE)
+                    .map(|p| {
+                        Store::new(
+                            FILE_HEADER_SIZE + p,
+                            vec![255; FULL_BLOCK_SIZE.min(capacity - p)],
+                        )
+                    })
+                    .collect();
+                self.record(stores);
+                Err(file::Error::Full)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Zeros out a section of the file.
+    /// # Arguments
+    /// `position` - The position to start clearing from.
+    /// `length` - The number of bytes to clear.
+    pub fn clear(&mut self, position: usize, length: usize) {
+        self.inner.clear(position, length);
+        self.record(vec![Store::new(FILE_HEADER_SIZE + position, vec![0; length])]);
+    }
+
+    /// Flushes the file to disk.
+    pub fn flush(&mut self) -> file::Result<()> {
+        self.inner.flush()?;
+        self.record_flush();
+        Ok(())
+    }
+
+    /// Flushes the file to disk the same way the store does.
+    /// # Arguments
+    /// `flusher` - Flushes the file.
+    #[allow(dead_code)]
+    pub(crate) fn flush_with(&mut self, flusher: &Flusher) -> file::Result<()> {
+        flusher.flush(&self.inner)?;
+        self.record_flush();
+        Ok(())
+    }
+}
+
+impl FaultyStore<MemoryMappedInt> {
+    /// Writes bytes to the file.
+    /// # Arguments
+    /// `position` - The position to write the bytes to.
+    /// `bytes` - The bytes to write.
+    pub fn write_bytes(&mut self, position: usize, bytes: &[u8]) {
+        self.inner.write_bytes(position, bytes);
+        self.record(vec![Store::new(position, bytes.to_vec())]);
+    }
+
+    /// Puts an unsigned long into the file.
+    /// # Arguments
+    /// `position` - The position to put the value.
+    /// `value` - The value to put.
+    pub fn put_u64(&mut self, position: usize, value: u64) {
+        self.inner.put_u64(position, value);
+        let mut bytes = vec![0; 8];
+        BigEndian::write_u64(&mut bytes, value);
+        self.record(vec![Store::new(position, bytes)]);
+    }
+
+    /// Puts an unsigned int into the file.
+    /// # Arguments
+    /// `position` - The position to put the value.
+    /// `value` - The value to put.
+    pub fn put_u32(&mut self, position: usize, value: u32) {
+        self.inner.put_u32(position, value);
+        let mut bytes = vec![0; 4];
+        BigEndian::write_u32(&mut bytes, value);
+        self.record(vec![Store::new(position, bytes)]);
+    }
+
+    /// Flushes the file to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.record_flush();
+        Ok(())
+    }
+}
+
+/// The seed to use for the crashes.  Taken from `A19_CRASH_SEED` if it is set otherwise a random
+/// one is picked.  The seed is logged so a failed run can be repeated.
+pub fn crash_seed() -> u64 {
+    let seed = match std::env::var(SEED_VAR) {
+        Ok(value) => value.parse().expect("The crash seed isn't a number."),
+        Err(_) => rand::thread_rng().gen(),
+    };
+    log::info!("{}={}", SEED_VAR, seed);
+    seed
+}
+
+/// Creates the rng to pick the crashes with.
+/// # Arguments
+/// `seed` - The seed for the rng.  The same seed always picks the same crashes.
+pub fn seeded_rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::testing::*;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    const TEST_DIR: &str = "/home/mrh0057/cargo/tests/a19_data_persist_testing";
+
+    #[test]
+    pub fn crash_test() {
+        let path = Path::new(TEST_DIR);
+        if path.exists() && path.is_dir() {
+            remove_dir_all(TEST_DIR).unwrap();
+        }
+        create_dir_all(TEST_DIR).unwrap();
+        let file_path = path.join("crash.1");
+        let buffer = unsafe { MemoryMappedInt::new(&file_path, PAGE_SIZE * 2).unwrap() };
+        let mut store = FaultyStore::wrap(&file_path, buffer).unwrap();
+        let mut rng = seeded_rng(7);
+        store.put_u64(0, 1);
+        store.flush().unwrap();
+        store.put_u32(8, 2);
+        store.write_bytes(PAGE_SIZE, &[3; 64]);
+        assert_eq!(3, store.writes());
+        assert_eq!(1, store.flushes());
+        assert_eq!(2, store.unflushed_writes());
+
+        let bytes = store.surviving_bytes(Crash::LoseUnflushed, &mut rng);
+        assert_eq!(1, BigEndian::read_u64(&bytes[0..8]));
+        assert_eq!(0, BigEndian::read_u32(&bytes[8..12]));
+        assert_eq!(0, bytes[PAGE_SIZE]);
+
+        let bytes = store.surviving_bytes(Crash::KeepWrites(1), &mut rng);
+        assert_eq!(2, BigEndian::read_u32(&bytes[8..12]));
+        assert_eq!(0, bytes[PAGE_SIZE]);
+
+        // Only a prefix of the last write is kept.
+        for _ in 0..20 {
+            let bytes = store.surviving_bytes(Crash::TornLastWrite, &mut rng);
+            assert_eq!(2, BigEndian::read_u32(&bytes[8..12]));
+            let written = bytes[PAGE_SIZE..PAGE_SIZE + 64]
+                .iter()
+                .take_while(|b| **b == 3)
+                .count();
+            assert!(written < 64);
+            assert!(bytes[PAGE_SIZE + written..].iter().all(|b| *b == 0));
+        }
+
+        let bytes = store.surviving_bytes(Crash::ZeroPage(0), &mut rng);
+        assert!(bytes[..PAGE_SIZE].iter().all(|b| *b == 0));
+        assert_eq!(3, bytes[PAGE_SIZE]);
+
+        // The same seed tears the write in the same place.
+        let first = store.surviving_bytes(Crash::TornLastWrite, &mut seeded_rng(11));
+        let second = store.surviving_bytes(Crash::TornLastWrite, &mut seeded_rng(11));
+        assert_eq!(first, second);
+
+        let crashed = store
+            .materialize(&format!("{}_crashed", TEST_DIR), Crash::KeepWrites(1), &mut rng)
+            .unwrap();
+        assert_eq!("crash.1", crashed.file_name().unwrap());
+        let bytes = fs::read(&crashed).unwrap();
+        assert_eq!(PAGE_SIZE * 2, bytes.len());
+        assert_eq!(2, BigEndian::read_u32(&bytes[8..12]));
+    }
+}
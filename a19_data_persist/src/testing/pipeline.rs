@@ -0,0 +1,487 @@
+//! Runs the writer, the commit and the reader of a single node store on one thread.  Each part is
+//! moved forward a step at a time so a test picks the order they run in, either as a script or
+//! from a seeded rng, and the same order always gives the same result.
+//!```text
+//! append -> queue -> step_writer -> event files -> step_commit -> commit files
+//!                                        |                            |
+//!                                        +------> step_reader <-------+ committed id
+//!```
+//! The files are kept in an `InMemoryMessageStore` and the terms get their time from a
+//! `ManualClock`.  After every step the invariants are checked and a break panics with the step
+//! that broke it.
+//! - The reader never sees a message that hasn't been committed.
+//! - The commit never gets past the messages that have been written.
+//! - The positions of the writer, the commit and the reader never go backwards.
+use crate::file;
+use crate::file::backend::InMemoryMessageStore;
+use crate::file::MessageRead;
+use crate::raft::{
+    CommitStep, InfallibleProcessor, PersistedCommitStream, PersistedMessageReadStream,
+    PersistedMessageWriteStream, TransactionBatch,
+};
+use crate::testing::seeded_rng;
+use a19_core::clock::ManualClock;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The directory the files are kept in.
+const DIRECTORY: &str = "/simulated";
+/// The prefix of the files.
+const PREFIX: &str = "pipeline";
+
+/// A part of the pipeline that can be stepped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Writer,
+    Commit,
+    Reader,
+}
+
+/// Something waiting to be written.
+enum Append {
+    Message { msg_type: i32, body: Vec<u8> },
+    Transaction(TransactionBatch),
+}
+
+/// Records the ids of the messages the reader hands it.
+struct RecordingProcessor {
+    seen: Arc<Mutex<Vec<u64>>>,
+}
+
+impl InfallibleProcessor for RecordingProcessor {
+    fn handle<'a>(&mut self, read: &MessageRead<'a>) {
+        self.seen.lock().unwrap().push(read.message_id());
+    }
+}
+
+/// The writer, commit and reader of a store stepped on a single thread.
+pub struct SimulatedPipeline {
+    writer: PersistedMessageWriteStream,
+    commit: PersistedCommitStream,
+    reader: PersistedMessageReadStream<RecordingProcessor>,
+    /// Where the files are kept.
+    backend: Arc<InMemoryMessageStore>,
+    clock: Arc<ManualClock>,
+    /// The messages waiting for the writer.
+    pending: VecDeque<Append>,
+    /// The id of the last message written.
+    written: Arc<AtomicU64>,
+    /// The id of the last message committed.
+    committed: Arc<AtomicU64>,
+    /// The ids of the messages the reader has handled.
+    seen: Arc<Mutex<Vec<u64>>>,
+    /// The id to give the next message.
+    next_id: u64,
+    /// The values after the last step so we can tell if they went backwards.
+    last_committed: u64,
+    last_writer: (u32, usize),
+    last_commit: (u32, usize),
+    last_reader: (u32, usize),
+    /// The steps that have been run.
+    steps: Vec<Step>,
+}
+
+impl SimulatedPipeline {
+    /// Creates a pipeline with no messages.
+    /// # Arguments
+    /// `file_size` - The size of the event files.
+    /// `commit_file_size` - The size of the commit files.
+    pub fn new(file_size: usize, commit_file_size: usize) -> file::Result<Self> {
+        let backend = Arc::new(InMemoryMessageStore::new());
+        let clock = Arc::new(ManualClock::new(1_000));
+        let written = Arc::new(AtomicU64::new(0));
+        let committed = Arc::new(AtomicU64::new(0));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let writer = PersistedMessageWriteStream::new_with_backend(
+            1,
+            DIRECTORY.to_owned(),
+            PREFIX.to_owned(),
+            file_size,
+            written.clone(),
+            backend.clone(),
+        )?;
+        let commit = PersistedCommitStream::create(
+            DIRECTORY.to_owned(),
+            PREFIX.to_owned(),
+            commit_file_size,
+            backend.clone(),
+            committed.clone(),
+            written.clone(),
+            clock.clone(),
+        )?;
+        let reader = PersistedMessageReadStream::new_with_backend(
+            1,
+            1,
+            committed.clone(),
+            RecordingProcessor { seen: seen.clone() },
+            DIRECTORY.to_owned(),
+            PREFIX.to_owned(),
+            backend.clone(),
+        )?;
+        Ok(SimulatedPipeline {
+            last_writer: writer.position(),
+            last_commit: commit.position(),
+            last_reader: reader.position(),
+            writer,
+            commit,
+            reader,
+            backend,
+            clock,
+            pending: VecDeque::new(),
+            written,
+            committed,
+            seen,
+            next_id: 1,
+            last_committed: 0,
+            steps: Vec::new(),
+        })
+    }
+
+    /// Queues a message for the writer.
+    /// # Arguments
+    /// `msg_type` - The type of the message.
+    /// `body` - The body of the message.
+    pub fn append(&mut self, msg_type: i32, body: &[u8]) {
+        self.pending.push_back(Append::Message {
+            msg_type,
+            body: body.to_vec(),
+        });
+    }
+
+    /// Queues a transaction for the writer.
+    /// # Arguments
+    /// `batch` - The messages in the transaction.
+    pub fn append_transaction(&mut self, batch: TransactionBatch) {
+        self.pending.push_back(Append::Transaction(batch));
+    }
+
+    /// Writes the next queued message.
+    /// # Returns
+    /// False if there wasn't anything to write.
+    pub fn step_writer(&mut self) -> file::Result<bool> {
+        let written = match self.pending.pop_front() {
+            Some(Append::Message { msg_type, body }) => {
+                self.writer.add_message(msg_type, self.next_id, &body)?;
+                self.next_id += 1;
+                true
+            }
+            Some(Append::Transaction(batch)) => {
+                let last_id = self.writer.add_transaction(self.next_id, batch.bytes())?;
+                self.next_id = last_id + 1;
+                true
+            }
+            None => false,
+        };
+        self.check_invariants(Step::Writer);
+        Ok(written)
+    }
+
+    /// Commits the next block of written messages.  The clock is moved forward a millisecond
+    /// first so each term gets its own time.
+    pub fn step_commit(&mut self) -> file::Result<CommitStep> {
+        self.clock.advance(Duration::from_millis(1));
+        let step = self.commit.step()?;
+        self.check_invariants(Step::Commit);
+        Ok(step)
+    }
+
+    /// Reads the next committed message.
+    /// # Returns
+    /// False if the reader didn't move forward.
+    pub fn step_reader(&mut self) -> file::Result<bool> {
+        let moved = self.reader.process_next()?;
+        self.check_invariants(Step::Reader);
+        Ok(moved)
+    }
+
+    /// Runs a step.
+    /// # Arguments
+    /// `step` - The part of the pipeline to step.
+    /// # Returns
+    /// True if the step did something.
+    pub fn step(&mut self, step: Step) -> file::Result<bool> {
+        match step {
+            Step::Writer => self.step_writer(),
+            Step::Commit => Ok(self.step_commit()? != CommitStep::Idle),
+            Step::Reader => self.step_reader(),
+        }
+    }
+
+    /// Runs the steps in order.
+    /// # Arguments
+    /// `steps` - The steps to run.
+    pub fn run_script(&mut self, steps: &[Step]) -> file::Result<()> {
+        for step in steps {
+            self.step(*step)?;
+        }
+        Ok(())
+    }
+
+    /// Runs steps picked with a seeded rng.  The same seed always runs the same steps.
+    /// # Arguments
+    /// `seed` - The seed for the rng.
+    /// `steps` - The number of steps to run.
+    pub fn run_random(&mut self, seed: u64, steps: usize) -> file::Result<()> {
+        let mut rng = seeded_rng(seed);
+        for _ in 0..steps {
+            let step = match rng.gen_range(0, 3) {
+                0 => Step::Writer,
+                1 => Step::Commit,
+                _ => Step::Reader,
+            };
+            self.step(step)?;
+        }
+        Ok(())
+    }
+
+    /// Steps everything until all of the queued messages have been written, committed and read.
+    pub fn drain(&mut self) -> file::Result<()> {
+        loop {
+            let wrote = self.step_writer()?;
+            let committed = self.step_commit()? != CommitStep::Idle;
+            let read = self.step_reader()?;
+            if !wrote && !committed && !read {
+                break Ok(());
+            }
+        }
+    }
+
+    /// Flushes the event file being written to.
+    pub fn flush_writer(&self) -> file::Result<()> {
+        self.writer.flush()
+    }
+
+    /// The ids of the messages the reader has handled.
+    pub fn seen(&self) -> Vec<u64> {
+        self.seen.lock().unwrap().clone()
+    }
+
+    /// The id of the last message written.
+    pub fn written_id(&self) -> u64 {
+        self.written.load(Ordering::Acquire)
+    }
+
+    /// The id of the last message committed.
+    pub fn committed_id(&self) -> u64 {
+        self.committed.load(Ordering::Acquire)
+    }
+
+    /// The id of the last term committed.
+    pub fn current_term(&self) -> u64 {
+        self.commit.current_term()
+    }
+
+    /// The id of the event file and the position of the writer, the commit and the reader.
+    pub fn positions(&self) -> [(u32, usize); 3] {
+        [
+            self.writer.position(),
+            self.commit.position(),
+            self.reader.position(),
+        ]
+    }
+
+    /// The number of files in the backend.
+    pub fn file_count(&self) -> usize {
+        self.backend.len()
+    }
+
+    /// Checks the invariants after a step.
+    /// # Arguments
+    /// `step` - The step that was run.
+    fn check_invariants(&mut self, step: Step) {
+        self.steps.push(step);
+        let written = self.written_id();
+        let committed = self.committed_id();
+        let [writer, commit, reader] = self.positions();
+        let mut broken = Vec::new();
+        if committed > written {
+            broken.push(format!(
+                "committed id {} is past the written id {}",
+                committed, written
+            ));
+        }
+        if committed < self.last_committed {
+            broken.push(format!(
+                "committed id went back from {} to {}",
+                self.last_committed, committed
+            ));
+        }
+        {
+            let seen = self.seen.lock().unwrap();
+            if let Some(id) = seen.iter().find(|id| **id > committed) {
+                broken.push(format!(
+                    "reader saw {} but only {} is committed",
+                    id, committed
+                ));
+            }
+            if seen.windows(2).any(|ids| ids[0] >= ids[1]) {
+                broken.push(format!("reader saw the ids out of order {:?}", seen));
+            }
+        }
+        for (name, last, now) in [
+            ("writer", self.last_writer, writer),
+            ("commit", self.last_commit, commit),
+            ("reader", self.last_reader, reader),
+        ]
+        .iter()
+        {
+            if now < last {
+                broken.push(format!("{} went back from {:?} to {:?}", name, last, now));
+            }
+        }
+        if commit > writer {
+            broken.push(format!(
+                "commit {:?} is past the writer {:?}",
+                commit, writer
+            ));
+        }
+        if !broken.is_empty() {
+            panic!(
+                "{:?} at step {} broke the invariants: {}\nsteps: {:?}",
+                step,
+                self.steps.len(),
+                broken.join(", "),
+                self.steps
+            );
+        }
+        self.last_committed = committed;
+        self.last_writer = writer;
+        self.last_commit = commit;
+        self.last_reader = reader;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::testing::pipeline::*;
+    use crate::testing::seeded_rng;
+
+    const FILE_SIZE: usize = 1024;
+    const COMMIT_FILE_SIZE: usize = 256;
+
+    #[test]
+    pub fn commit_before_flush_test() {
+        let mut pipeline = SimulatedPipeline::new(FILE_SIZE, COMMIT_FILE_SIZE).unwrap();
+        // Nothing is written so there isn't anything to commit.
+        assert_eq!(CommitStep::Idle, pipeline.step_commit().unwrap());
+        assert!(!pipeline.step_reader().unwrap());
+
+        pipeline.append(1, &[1; 20]);
+        pipeline.append(1, &[2; 20]);
+        pipeline.step_writer().unwrap();
+        // The reader can't get ahead of the commit.
+        assert!(!pipeline.step_reader().unwrap());
+        assert_eq!(
+            CommitStep::Committed {
+                term_id: 1,
+                max_message_id: 1
+            },
+            pipeline.step_commit().unwrap()
+        );
+        pipeline.step_writer().unwrap();
+        assert_eq!(2, pipeline.written_id());
+        // Committed before the writer was flushed.
+        assert_eq!(
+            CommitStep::Committed {
+                term_id: 2,
+                max_message_id: 2
+            },
+            pipeline.step_commit().unwrap()
+        );
+        pipeline.flush_writer().unwrap();
+        assert_eq!(CommitStep::Idle, pipeline.step_commit().unwrap());
+        pipeline.drain().unwrap();
+        assert_eq!(vec![1, 2], pipeline.seen());
+    }
+
+    #[test]
+    pub fn rollover_mid_term_test() {
+        let mut pipeline = SimulatedPipeline::new(FILE_SIZE, COMMIT_FILE_SIZE).unwrap();
+        // Each message takes up 128 bytes so a file holds 8.
+        for i in 0..20u8 {
+            pipeline.append(1, &[i; 100]);
+        }
+        pipeline
+            .run_script(&[Step::Writer, Step::Writer, Step::Commit, Step::Reader])
+            .unwrap();
+        assert_eq!(vec![1], pipeline.seen());
+        // The writer rolls onto the next files while the term is open.
+        for _ in 0..12 {
+            pipeline.step_writer().unwrap();
+        }
+        assert_eq!(2, pipeline.positions()[0].0);
+        assert_eq!(
+            CommitStep::Committed {
+                term_id: 2,
+                max_message_id: 8
+            },
+            pipeline.step_commit().unwrap()
+        );
+        assert_eq!(
+            CommitStep::NextEventFile(2),
+            pipeline.step_commit().unwrap()
+        );
+        // The commit file only holds 2 terms.
+        assert_eq!(
+            CommitStep::NextCommitFile(2),
+            pipeline.step_commit().unwrap()
+        );
+        assert_eq!(
+            CommitStep::Committed {
+                term_id: 3,
+                max_message_id: 14
+            },
+            pipeline.step_commit().unwrap()
+        );
+        pipeline.drain().unwrap();
+        assert_eq!((1..=20).collect::<Vec<u64>>(), pipeline.seen());
+        assert_eq!(3, pipeline.positions()[2].0);
+        assert_eq!(3, pipeline.file_count());
+    }
+
+    #[test]
+    pub fn transaction_test() {
+        let mut pipeline = SimulatedPipeline::new(FILE_SIZE, COMMIT_FILE_SIZE).unwrap();
+        pipeline.append(1, &[1; 20]);
+        let mut batch = TransactionBatch::new();
+        batch.add(2, &[2; 20]);
+        batch.add(2, &[3; 20]);
+        pipeline.append_transaction(batch);
+        pipeline.append(1, &[4; 20]);
+        pipeline
+            .run_script(&[
+                Step::Writer,
+                Step::Writer,
+                Step::Commit,
+                Step::Reader,
+                Step::Reader,
+                Step::Reader,
+                Step::Reader,
+            ])
+            .unwrap();
+        // The transaction message has the id 2 and isn't handed to the reader.
+        assert_eq!(vec![1, 3, 4], pipeline.seen());
+        pipeline.drain().unwrap();
+        assert_eq!(vec![1, 3, 4, 5], pipeline.seen());
+    }
+
+    #[test]
+    pub fn random_test() {
+        for seed in 0..20 {
+            let mut pipeline = SimulatedPipeline::new(FILE_SIZE, COMMIT_FILE_SIZE).unwrap();
+            let mut rng = seeded_rng(seed);
+            for i in 0..60u8 {
+                let length = rng.gen_range(1, 200);
+                pipeline.append(1, &vec![i; length]);
+            }
+            pipeline.run_random(seed, 300).unwrap();
+            pipeline.drain().unwrap();
+            assert_eq!((1..=60).collect::<Vec<u64>>(), pipeline.seen());
+            assert_eq!(60, pipeline.committed_id());
+        }
+    }
+}